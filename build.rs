@@ -0,0 +1,69 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Embed the SQL files of migrations/ into the binary. They must be named
+// NNNN_description.sql, and each number used once. The optional
+// NNNN_description.down.sql next to one undoes it. Table names are written
+// between braces, {users}, and replaced as src/tables.rs says.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+
+    let dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("migrations");
+    let mut migrations = Vec::new();
+    let mut downs = Vec::new();
+    for entry in fs::read_dir(&dir).expect("can't read migrations/") {
+        let path = entry.unwrap().path();
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let stem = match file_name.strip_suffix(".sql") {
+            Some(stem) => stem,
+            None => {
+                continue;
+            }
+        };
+
+        let (stem, is_down) = match stem.strip_suffix(".down") {
+            Some(stem) => (stem, true),
+            None => (stem, false),
+        };
+
+        let (number, name) = stem
+            .split_once('_')
+            .filter(|(number, name)| {
+                number.len() == 4 && number.bytes().all(|byte| byte.is_ascii_digit()) && !name.is_empty()
+            })
+            .unwrap_or_else(|| panic!("migrations/{} must be named NNNN_description.sql", file_name));
+        let version: i64 = number.parse().unwrap();
+        if is_down {
+            downs.push((stem.to_owned(), path.display().to_string()));
+        } else {
+            migrations.push((version, stem.to_owned(), name.replace('_', " "), path.display().to_string()));
+        }
+    }
+
+    for (stem, _) in &downs {
+        if !migrations.iter().any(|migration| &migration.1 == stem) {
+            panic!("migrations/{}.down.sql has no migrations/{}.sql to undo", stem, stem);
+        }
+    }
+
+    migrations.sort();
+    for pair in migrations.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            panic!("migration {:04} is defined by two files in migrations/", pair[0].0);
+        }
+    }
+
+    let mut generated = String::from("pub const SQL_MIGRATIONS: &[(i64, &str, &str, Option<&str>)] = &[\n");
+    for (version, stem, description, path) in &migrations {
+        let down = match downs.iter().find(|(down_stem, _)| down_stem == stem) {
+            Some((_, down_path)) => format!("Some(include_str!({:?}))", down_path),
+            None => "None".to_owned(),
+        };
+        generated.push_str(&format!("    ({}, {:?}, include_str!({:?}), {}),\n", version, description, path, down));
+    }
+    generated.push_str("];\n");
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("sql_migrations.rs");
+    fs::write(out, generated).unwrap();
+}
@@ -0,0 +1,6 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_postgresql_tutorial::fuzzing;
+
+fuzz_target!(|bytes: &[u8]| fuzzing::chunked(bytes));
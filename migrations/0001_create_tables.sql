@@ -0,0 +1,26 @@
+-- The schema the server used to create on every start, so this is a no-op on
+-- databases that predate migrations
+
+CREATE TABLE IF NOT EXISTS {users} (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    email VARCHAR UNIQUE NOT NULL
+);
+
+ALTER TABLE {users} ADD COLUMN IF NOT EXISTS anonymized_at TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS {events_outbox} (
+    id BIGSERIAL PRIMARY KEY,
+    event_type VARCHAR NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    delivered_at TIMESTAMPTZ
+);
+
+CREATE TABLE IF NOT EXISTS {idempotency_keys} (
+    key VARCHAR PRIMARY KEY,
+    request_hash VARCHAR NOT NULL,
+    status_line VARCHAR,
+    response_body TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
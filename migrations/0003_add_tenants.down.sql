@@ -0,0 +1,11 @@
+-- Fails when tenants share emails or idempotency keys, those rows have to go first
+
+ALTER TABLE {idempotency_keys} DROP CONSTRAINT {idempotency_keys_pkey}, ADD PRIMARY KEY (key);
+
+DROP INDEX {users_tenant_email_key};
+CREATE UNIQUE INDEX {users_email_lower_key} ON {users} (lower(email));
+ALTER TABLE {users} ADD CONSTRAINT {users_email_key} UNIQUE (email);
+
+ALTER TABLE {users} DROP COLUMN tenant_id;
+ALTER TABLE {events_outbox} DROP COLUMN tenant_id;
+ALTER TABLE {idempotency_keys} DROP COLUMN tenant_id;
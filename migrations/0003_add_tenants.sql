@@ -0,0 +1,14 @@
+-- Every row belongs to a tenant, the existing ones to the default tenant, which
+-- is the only one without TENANCY=column
+
+ALTER TABLE {users} ADD COLUMN tenant_id VARCHAR NOT NULL DEFAULT 'default';
+ALTER TABLE {events_outbox} ADD COLUMN tenant_id VARCHAR NOT NULL DEFAULT 'default';
+ALTER TABLE {idempotency_keys} ADD COLUMN tenant_id VARCHAR NOT NULL DEFAULT 'default';
+
+-- Emails are unique within a tenant, regardless of case
+ALTER TABLE {users} DROP CONSTRAINT {users_email_key};
+DROP INDEX {users_email_lower_key};
+CREATE UNIQUE INDEX {users_tenant_email_key} ON {users} (tenant_id, lower(email));
+
+-- So are the idempotency keys
+ALTER TABLE {idempotency_keys} DROP CONSTRAINT {idempotency_keys_pkey}, ADD PRIMARY KEY (tenant_id, key);
@@ -0,0 +1,4 @@
+-- Leaves the encrypted emails unreadable, run rotate-encryption-key first
+
+DROP INDEX {users_tenant_email_hash_key};
+ALTER TABLE {users} DROP COLUMN email_hash;
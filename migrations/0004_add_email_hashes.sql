@@ -0,0 +1,5 @@
+-- With ENCRYPTION_KEY the email is stored encrypted, and found by this keyed
+-- hash of it; NULL for the emails stored as they are
+
+ALTER TABLE {users} ADD COLUMN email_hash VARCHAR;
+CREATE UNIQUE INDEX {users_tenant_email_hash_key} ON {users} (tenant_id, email_hash);
@@ -0,0 +1,3 @@
+-- migrate: no-transaction
+
+DROP INDEX CONCURRENTLY IF EXISTS {events_outbox_tenant_id_idx};
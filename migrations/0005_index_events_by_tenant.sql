@@ -0,0 +1,6 @@
+-- migrate: no-transaction
+-- GET /events and the replays of the event streams, from an id on for a tenant.
+-- Concurrently, so that writes go on while it is built; if it fails, drop the
+-- INVALID index it leaves before migrating again.
+
+CREATE INDEX CONCURRENTLY {events_outbox_tenant_id_idx} ON {events_outbox} (tenant_id, id);
@@ -0,0 +1,5 @@
+-- migrate: no-transaction
+-- The dispatcher only looks for the events not delivered yet, a few among all of
+-- them. Concurrently, see 0005.
+
+CREATE INDEX CONCURRENTLY {events_outbox_undelivered_idx} ON {events_outbox} (id) WHERE delivered_at IS NULL;
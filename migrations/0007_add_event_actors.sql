@@ -0,0 +1,4 @@
+-- The name of the API key whose request caused the event, none without API_KEYS
+-- or for the events that predate it
+
+ALTER TABLE {events_outbox} ADD COLUMN actor VARCHAR;
@@ -0,0 +1,13 @@
+-- The API keys minted with POST /admin/api-keys or `api-keys create`, only their
+-- SHA-256 in hex, which is what the presented keys are looked up by
+
+CREATE TABLE {api_keys} (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    key_hash VARCHAR NOT NULL,
+    scopes TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ,
+    CONSTRAINT {api_keys_key_hash_key} UNIQUE (key_hash)
+);
@@ -0,0 +1,4 @@
+-- What POST /login checks the password against, argon2id in the PHC string
+-- format; NULL for the users without a password, who can't log in
+
+ALTER TABLE {users} ADD COLUMN password_hash VARCHAR;
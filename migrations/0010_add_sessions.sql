@@ -0,0 +1,14 @@
+-- The sessions of POST /session, by the SHA-256 in hex of the id in their
+-- cookie. Each request moves expires_at up, as far as the absolute lifetime; the
+-- sessions of a user end with them.
+
+CREATE TABLE {sessions} (
+    id VARCHAR PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES {users} (id) ON DELETE CASCADE,
+    tenant_id VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    last_seen TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX {sessions_user_id_idx} ON {sessions} (user_id);
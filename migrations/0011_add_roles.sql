@@ -0,0 +1,9 @@
+-- What the users and the stored API keys may do: read, write, or everything as
+-- admins. The keys minted before there were roles could do everything, and keep
+-- that; those minted since say which role they have.
+
+ALTER TABLE {users} ADD COLUMN role VARCHAR NOT NULL DEFAULT 'writer'
+    CHECK (role IN ('reader', 'writer', 'admin'));
+ALTER TABLE {api_keys} ADD COLUMN role VARCHAR NOT NULL DEFAULT 'admin'
+    CHECK (role IN ('reader', 'writer', 'admin'));
+ALTER TABLE {api_keys} ALTER COLUMN role SET DEFAULT 'writer';
@@ -0,0 +1,16 @@
+-- When the users proved their email is theirs, and the tokens they do it with,
+-- by the SHA-256 in hex of the token sent to them. A token is kept once used, so
+-- that using it again is told apart from a token that never was.
+
+ALTER TABLE {users} ADD COLUMN verified_at TIMESTAMPTZ;
+
+CREATE TABLE {verification_tokens} (
+    token_hash VARCHAR PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES {users} (id) ON DELETE CASCADE,
+    tenant_id VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX {verification_tokens_user_id_idx} ON {verification_tokens} (user_id);
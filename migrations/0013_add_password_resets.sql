@@ -0,0 +1,13 @@
+-- The tokens users choose a new password with, by the SHA-256 in hex of the token
+-- mailed to them, kept once used like those of the verifications
+
+CREATE TABLE {password_reset_tokens} (
+    token_hash VARCHAR PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES {users} (id) ON DELETE CASCADE,
+    tenant_id VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX {password_reset_tokens_user_id_idx} ON {password_reset_tokens} (user_id);
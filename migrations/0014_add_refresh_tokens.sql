@@ -0,0 +1,17 @@
+-- The refresh tokens of POST /login, by the SHA-256 in hex of the token. Each
+-- refresh replaces the token with the next of its chain, the chain of the login
+-- it started with, and the replaced token is kept to tell when it is used again.
+
+CREATE TABLE {refresh_tokens} (
+    token_hash VARCHAR PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES {users} (id) ON DELETE CASCADE,
+    tenant_id VARCHAR NOT NULL,
+    chain_id VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    rotated_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX {refresh_tokens_user_id_idx} ON {refresh_tokens} (user_id);
+CREATE INDEX {refresh_tokens_chain_id_idx} ON {refresh_tokens} (chain_id);
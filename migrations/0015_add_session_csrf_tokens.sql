@@ -0,0 +1,6 @@
+-- The CSRF tokens of the sessions, which the requests that change anything with
+-- the cookie send back in X-CSRF-Token. Without the cookie a token is of no use,
+-- so it is kept as is, for GET /session/csrf to hand it out again; the sessions
+-- begun before there were tokens get one there.
+
+ALTER TABLE {sessions} ADD COLUMN csrf_token VARCHAR;
@@ -0,0 +1,12 @@
+-- The nonces of the signed requests, with SIGNATURE_NONCE_STORE=postgres, by the
+-- name of the key that signed them. Each is kept until its timestamp is too old
+-- to take, then may be used again.
+
+CREATE TABLE {signature_nonces} (
+    key_name VARCHAR NOT NULL,
+    nonce VARCHAR NOT NULL,
+    forgotten_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (key_name, nonce)
+);
+
+CREATE INDEX {signature_nonces_forgotten_at_idx} ON {signature_nonces} (forgotten_at);
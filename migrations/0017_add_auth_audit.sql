@@ -0,0 +1,17 @@
+-- The audit trail of authentication and authorization, with AUTH_AUDIT=true and
+-- AUTH_AUDIT_STORE=postgres: the successes kept by the sampling, every failure
+-- and every denial. The principal is the identity attempted when nobody got in,
+-- its emails masked.
+
+CREATE TABLE {auth_audit} (
+    id BIGSERIAL PRIMARY KEY,
+    occurred_at TIMESTAMPTZ NOT NULL,
+    kind VARCHAR NOT NULL,
+    outcome VARCHAR NOT NULL,
+    principal VARCHAR,
+    client_ip VARCHAR,
+    route VARCHAR,
+    tenant_id VARCHAR NOT NULL
+);
+
+CREATE INDEX {auth_audit_occurred_at_idx} ON {auth_audit} (occurred_at);
@@ -0,0 +1,4 @@
+-- Leaves the encrypted responses unreadable, they are replayed as they are stored
+
+ALTER TABLE {idempotency_keys} DROP COLUMN response_encrypted;
+ALTER TABLE {idempotency_keys} DROP COLUMN user_id;
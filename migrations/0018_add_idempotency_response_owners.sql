@@ -0,0 +1,6 @@
+-- The user a stored response is of, so that it goes when the user is anonymized,
+-- and whether it is encrypted, which it is with ENCRYPTION_KEY since it carries
+-- their email
+
+ALTER TABLE {idempotency_keys} ADD COLUMN user_id INTEGER;
+ALTER TABLE {idempotency_keys} ADD COLUMN response_encrypted BOOLEAN NOT NULL DEFAULT false;
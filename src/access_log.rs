@@ -0,0 +1,376 @@
+use log::kv::Value;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Mutex;
+use std::thread;
+use std::time::{ Duration, Instant };
+use tracing::Span;
+
+use crate::route_metrics::{ self, Route };
+use crate::http::{ get_header, get_segments };
+use crate::{ alerts, log_sampling, redact, request_id, slow };
+
+// What RUST_LOG sets the level of the lines by, access=off for none of them
+const TARGET: &str = "access";
+
+thread_local! {
+    // The request being answered on this thread, set by start
+    static CURRENT: RefCell<Option<Entry>> = const { RefCell::new(None) };
+}
+
+// The requests started and not logged yet, on whichever thread, by the id of
+// their entry
+static IN_FLIGHT: Mutex<BTreeMap<u64, InFlight>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// A request being answered, the streams for as long as they are open
+#[derive(Clone, Debug)]
+pub struct InFlight {
+    pub route: String,
+    pub request_id: Option<String>,
+    pub started: Instant,
+}
+
+// A line of the access log, at info, once the response was written: the method,
+// the path with its query, the route it matched, the status, how many bytes were
+// written, how many milliseconds it took, how many repository operations it made
+// and the milliseconds they took, waiting for a connection of the pool included,
+// that are on its request span too, the address of the client and its
+// User-Agent. A request never answered has the status panic when its handler
+// panicked, and dropped when the connection was gone, or closed before sending
+// one. The emails and the secrets of the paths are masked, see redact::text.
+// The request is counted then in the metrics of its route too, and in
+// alerts::counts, even when its line is sampled out, see log_sampling.
+pub struct Entry {
+    id: u64,
+    request_id: Option<String>,
+    method: String,
+    target: String,
+    route: String,
+    // None for a connection closed without a request
+    counted: Option<Route>,
+    client: Option<IpAddr>,
+    user_agent: Option<String>,
+    started: Instant,
+    status: Option<u16>,
+    bytes: usize,
+    // Whether writing the response failed, or the connection closed before sending
+    // a request, whatever was written to it
+    dropped: bool,
+    // Whether the database failed it
+    db_error: bool,
+    db_calls: u64,
+    db_time: Duration,
+    pool_wait: Duration,
+    // Its request span, that has the numbers of the database too
+    span: Span,
+}
+
+// Log the request on this thread, read at started, when the returned guard is
+// dropped
+pub fn start(request: &str, route: String, client: Option<IpAddr>, started: Instant, span: Span) -> Logging {
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target) = (request_line.next().unwrap_or("-"), request_line.next().unwrap_or("-"));
+    let segments = get_segments(request);
+    let entry = Entry {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        request_id: request_id::current(),
+        method: method.to_owned(),
+        target: redact::text(target),
+        route,
+        counted: (!request.is_empty()).then(|| route_metrics::route(method, &segments)),
+        client,
+        user_agent: get_header(request, "User-Agent").map(str::to_owned),
+        started,
+        status: None,
+        bytes: 0,
+        dropped: request.is_empty(),
+        db_error: false,
+        db_calls: 0,
+        db_time: Duration::ZERO,
+        pool_wait: Duration::ZERO,
+        span,
+    };
+    if entry.counted.is_some() {
+        let in_flight = InFlight { route: entry.route.clone(), request_id: entry.request_id.clone(), started };
+        IN_FLIGHT.lock().unwrap().insert(entry.id, in_flight);
+    }
+    resume(Some(entry))
+}
+
+pub struct Logging;
+
+impl Drop for Logging {
+    fn drop(&mut self) {
+        if let Some(entry) = CURRENT.take() {
+            log(&entry, thread::panicking());
+        }
+    }
+}
+
+// The route of the request being answered on this thread, None for a connection
+// closed without one
+pub fn route() -> Option<String> {
+    CURRENT.with_borrow(|entry| entry.as_ref().filter(|entry| entry.counted.is_some()).map(|entry| entry.route.clone()))
+}
+
+// The requests being answered, the oldest first
+pub fn in_flight() -> Vec<InFlight> {
+    IN_FLIGHT.lock().unwrap().values().cloned().collect()
+}
+
+// The request of this thread, for the thread answering it from then on to resume
+pub fn hand_off() -> Option<Entry> {
+    CURRENT.take()
+}
+
+pub fn resume(entry: Option<Entry>) -> Logging {
+    CURRENT.set(entry);
+    Logging
+}
+
+// The head and as many bytes of the response, the status out of the first head
+// written, were sent
+pub fn responded(head: &str, bytes: usize) {
+    CURRENT.with_borrow_mut(|entry| {
+        if let Some(entry) = entry {
+            let status = head.split_whitespace().nth(1).and_then(|status| status.parse().ok());
+            entry.status = entry.status.or(status);
+            entry.bytes += bytes;
+        }
+    });
+}
+
+// Writing the response failed, the client is gone
+pub fn dropped() {
+    CURRENT.with_borrow_mut(|entry| {
+        if let Some(entry) = entry {
+            entry.dropped = true;
+        }
+    });
+}
+
+// The database failed the request, see repository_error_response
+pub fn db_failed() {
+    CURRENT.with_borrow_mut(|entry| {
+        if let Some(entry) = entry {
+            entry.db_error = true;
+        }
+    });
+}
+
+// The request made a repository operation, that took duration
+pub fn db_called(duration: Duration) {
+    CURRENT.with_borrow_mut(|entry| {
+        if let Some(entry) = entry {
+            entry.db_calls += 1;
+            entry.db_time += duration;
+        }
+    });
+}
+
+// The request waited for a connection of the pool for duration
+pub fn pool_waited(duration: Duration) {
+    CURRENT.with_borrow_mut(|entry| {
+        if let Some(entry) = entry {
+            entry.pool_wait += duration;
+        }
+    });
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+// Its status, or what stands for it when it was never answered
+fn status(entry: &Entry, panicked: bool) -> Value<'static> {
+    match entry.status.filter(|_| !entry.dropped) {
+        Some(status) => Value::from(status),
+        None if panicked && !entry.dropped => Value::from("panic"),
+        None => Value::from("dropped"),
+    }
+}
+
+fn log(entry: &Entry, panicked: bool) {
+    IN_FLIGHT.lock().unwrap().remove(&entry.id);
+    let status = status(entry, panicked);
+    let elapsed = entry.started.elapsed();
+    if let Some(route) = entry.counted {
+        let answered = entry.status.filter(|_| !entry.dropped);
+        route_metrics::observe(route, answered, elapsed);
+        alerts::count(answered, entry.db_error, panicked);
+    }
+    let (db_ms, pool_wait_ms) = (milliseconds(entry.db_time), milliseconds(entry.pool_wait));
+    entry.span.record("db_calls", entry.db_calls);
+    entry.span.record("db_ms", db_ms);
+    entry.span.record("pool_wait_ms", pool_wait_ms);
+    let succeeded = matches!(entry.status, Some(200..=399)) && !entry.dropped;
+    if succeeded && !slow::is_slow_request(elapsed) && !log_sampling::keep(entry.request_id.as_deref()) {
+        return;
+    }
+    let duration_ms = milliseconds(elapsed);
+    let client_ip = entry.client.map_or_else(|| "-".to_owned(), |client| client.to_string());
+    log::info!(
+        target: TARGET,
+        method = entry.method.as_str(),
+        path = entry.target.as_str(),
+        route = entry.route.as_str(),
+        status = status,
+        bytes = entry.bytes,
+        duration_ms = duration_ms,
+        db_calls = entry.db_calls,
+        db_ms = db_ms,
+        pool_wait_ms = pool_wait_ms,
+        client_ip = client_ip.as_str(),
+        user_agent = entry.user_agent.as_deref().unwrap_or("-");
+        "{} {} {}",
+        entry.method,
+        entry.target,
+        status
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log_sampling::LogSamplingConfig;
+    use log::kv::{ self, Key, VisitSource };
+    use log::{ Log, Metadata, Record };
+    use std::panic;
+    use std::sync::Once;
+
+    thread_local! {
+        // The fields of the lines logged on this thread, as text
+        static LOGGED: RefCell<Vec<Vec<(String, String)>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    struct Capturing;
+
+    struct Fields(Vec<(String, String)>);
+
+    impl<'kvs> VisitSource<'kvs> for Fields {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+            self.0.push((key.to_string(), value.to_string()));
+            Ok(())
+        }
+    }
+
+    impl Log for Capturing {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let mut fields = Fields(vec![("message".to_owned(), record.args().to_string())]);
+            record.key_values().visit(&mut fields).unwrap();
+            LOGGED.with_borrow_mut(|logged| logged.push(fields.0));
+        }
+
+        fn flush(&self) {}
+    }
+
+    // The fields of the lines logged on this thread since the last time
+    fn logged() -> Vec<Vec<(String, String)>> {
+        static CAPTURING: Once = Once::new();
+        CAPTURING.call_once(|| {
+            log::set_logger(&Capturing).unwrap();
+            log::set_max_level(log::LevelFilter::Info);
+        });
+        LOGGED.take()
+    }
+
+    fn field<'a>(fields: &'a [(String, String)], name: &str) -> &'a str {
+        fields.iter().find(|(field, _)| field == name).map_or("", |(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn lines_have_every_field() {
+        logged();
+        let request = "GET /users?name_contains=ada HTTP/1.1\r\nUser-Agent: curl/8.5.0\r\n\r\n";
+        let logging = start(request, "GET /users".to_owned(), "10.0.0.1".parse().ok(), Instant::now(), Span::none());
+        responded("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n", 80);
+        responded("", 20);
+        drop(logging);
+
+        let logged = logged();
+        let names: Vec<&str> = logged[0].iter().map(|(name, _)| name.as_str()).collect();
+        let expected = [
+            "message",
+            "method",
+            "path",
+            "route",
+            "status",
+            "bytes",
+            "duration_ms",
+            "db_calls",
+            "db_ms",
+            "pool_wait_ms",
+            "client_ip",
+            "user_agent",
+        ];
+        assert_eq!(names, expected);
+        let values: Vec<&str> = logged[0].iter().map(|(_, value)| value.as_str()).collect();
+        let message = "GET /users?name_contains=ada 200";
+        assert_eq!(values[..6], [message, "GET", "/users?name_contains=ada", "GET /users", "200", "100"]);
+        assert!(values[6].parse::<f64>().is_ok(), "{:?}", values);
+        // Without a database
+        assert_eq!(values[7..10], ["0", "0", "0"]);
+        assert_eq!(values[10..], ["10.0.0.1", "curl/8.5.0"]);
+    }
+
+    #[test]
+    fn requests_never_answered_are_marked() {
+        logged();
+        // Closed before sending one, whatever was written to it
+        let logging = start("", "-".to_owned(), None, Instant::now(), Span::none());
+        responded("HTTP/1.1 404 NOT FOUND\r\n\r\n", 26);
+        drop(logging);
+        // The client left before the response
+        let request = "GET /users/1 HTTP/1.1\r\n\r\n";
+        let logging = start(request, "GET /users/{id}".to_owned(), None, Instant::now(), Span::none());
+        dropped();
+        drop(logging);
+        // The handler panicked
+        panic::catch_unwind(|| {
+            let _logging = start(request, "GET /users/{id}".to_owned(), None, Instant::now(), Span::none());
+            panic!("the handler failed");
+        })
+        .unwrap_err();
+
+        let logged = logged();
+        let statuses: Vec<&str> = logged.iter().map(|fields| field(fields, "status")).collect();
+        assert_eq!(statuses, ["dropped", "dropped", "panic"]);
+        assert_eq!((field(&logged[0], "method"), field(&logged[0], "bytes")), ("-", "26"));
+        assert_eq!((field(&logged[0], "client_ip"), field(&logged[0], "user_agent")), ("-", "-"));
+        assert_eq!(field(&logged[2], "message"), "GET /users/1 panic");
+    }
+
+    #[test]
+    fn only_the_successful_requests_are_sampled() {
+        logged();
+        log_sampling::init(LogSamplingConfig { rate: 0.1, file: None });
+        let request = "GET /users/1 HTTP/1.1\r\n\r\n";
+        // The paths of the lines logged
+        let answer = |ids: &[String], status: &str| -> Vec<String> {
+            for id in ids {
+                let _request_id = request_id::enter(id.clone());
+                let logging = start(request, "GET /users/{id}".to_owned(), None, Instant::now(), Span::none());
+                responded(&format!("HTTP/1.1 {}\r\n\r\n", status), 26);
+                drop(logging);
+            }
+            logged().iter().map(|fields| field(fields, "path").to_owned()).collect()
+        };
+        let ids: Vec<String> = (0..4000).map(|index| format!("request-{}", index)).collect();
+
+        let kept = answer(&ids, "200 OK").len();
+        assert!((320..=480).contains(&kept), "{} of 4000 kept", kept);
+        // The same ones every time
+        assert_eq!(answer(&ids, "304 NOT MODIFIED").len(), kept);
+        for status in ["404 NOT FOUND", "401 UNAUTHORIZED", "500 INTERNAL SERVER ERROR"] {
+            assert_eq!(answer(&ids[..1000], status).len(), 1000, "{}", status);
+        }
+        log_sampling::init(LogSamplingConfig { rate: 1.0, file: None });
+    }
+}
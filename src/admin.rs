@@ -0,0 +1,154 @@
+use std::sync::OnceLock;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use crate::cache;
+use crate::config::{ self, flag_from_env };
+use crate::profile::Profile;
+use crate::repository::{ RepositoryError, UserRepository };
+use crate::errors::repository_error_response;
+use crate::http::{ decode_query_value, get_body, get_query_param, BAD_REQUEST, INTERNAL_SERVER_ERROR, OK_RESPONSE };
+use crate::models::User;
+
+const DEFAULT_SEED_COUNT: u32 = 10;
+const MAX_SEED_COUNT: u32 = 10_000;
+
+const FIRST_NAMES: [&str; 8] = ["Ada", "Alan", "Grace", "Linus", "Margaret", "Dennis", "Barbara", "Ken"];
+const LAST_NAMES: [&str; 8] = ["Lovelace", "Turing", "Hopper", "Torvalds", "Hamilton", "Ritchie", "Liskov", "Thompson"];
+
+#[derive(Deserialize, Debug)]
+struct SeedRequest {
+    count: Option<u32>,
+    seed: Option<u64>,
+}
+
+static ENABLED: OnceLock<bool> = OnceLock::new();
+
+// The admin routes are compiled in but only answer with ADMIN_ENDPOINTS=true, the
+// default when APP_ENV is set to a dev or test environment; otherwise they are a
+// plain 404. Without APP_ENV they are off, as in prod.
+pub fn enabled_from_env() -> Result<bool, String> {
+    let profile = config::var("APP_ENV").ok().map(|app_env| Profile::of(&app_env));
+    flag_from_env("ADMIN_ENDPOINTS", profile.is_some_and(|profile| profile != Profile::Prod))
+}
+
+pub fn init(enabled: bool) {
+    ENABLED.set(enabled).ok();
+}
+
+pub fn endpoints_enabled() -> bool {
+    ENABLED.get() == Some(&true)
+}
+
+// Empty every table and restart the id sequences
+pub fn handle_reset_request(repository: &dyn UserRepository, _request: &str) -> (String, String) {
+    match repository.reset() {
+        Ok(()) => {
+            if let Some(cache) = cache::cache() {
+                cache.clear();
+            }
+            let summary = serde_json::json!({
+                "truncated": ["users", "events_outbox", "idempotency_keys"],
+                "sequences_restarted": true,
+            });
+            (OK_RESPONSE.to_owned(), summary.to_string())
+        }
+        Err(e) => repository_error_response(e, "Error resetting database"),
+    }
+}
+
+// Insert generated users. The same seed always generates the same users.
+pub fn handle_seed_request(repository: &dyn UserRepository, request: &str) -> (String, String) {
+    let body = get_body(request);
+    let seed_request = if body.trim().is_empty() {
+        Ok(SeedRequest { count: None, seed: None })
+    } else {
+        serde_json::from_str::<SeedRequest>(body)
+    };
+
+    let seed_request = match seed_request {
+        Ok(seed_request) => seed_request,
+        Err(_) => {
+            return (BAD_REQUEST.to_owned(), "Invalid request body".to_owned());
+        }
+    };
+
+    let count = seed_request.count.unwrap_or(DEFAULT_SEED_COUNT);
+    if count > MAX_SEED_COUNT {
+        return (BAD_REQUEST.to_owned(), format!("count must be at most {}", MAX_SEED_COUNT));
+    }
+    let seed = seed_request.seed.unwrap_or_else(random_seed);
+
+    match repository.seed(generated_users(count, seed)) {
+        Ok(ids) => {
+            let summary = serde_json::json!({
+                "requested": count,
+                "inserted": ids.len(),
+                "seed": seed,
+                "ids": ids,
+            });
+            (OK_RESPONSE.to_owned(), summary.to_string())
+        }
+        Err(e) => repository_error_response(e, "Error seeding database"),
+    }
+}
+
+// Run a query that takes ?seconds=, as a runaway one would, for testing the
+// statement timeout and the cancellation of abandoned requests
+pub fn handle_sleep_request(repository: &dyn UserRepository, request: &str) -> (String, String) {
+    let seconds = get_query_param(request, "seconds").unwrap_or("1");
+    let duration = match seconds.parse::<f64>().ok().and_then(|seconds| Duration::try_from_secs_f64(seconds).ok()) {
+        Some(duration) => duration,
+        None => {
+            return (BAD_REQUEST.to_owned(), format!("Invalid seconds: {}", seconds));
+        }
+    };
+
+    match repository.sleep(duration) {
+        Ok(()) => (OK_RESPONSE.to_owned(), serde_json::json!({ "slept": duration.as_secs_f64() }).to_string()),
+        Err(e) => repository_error_response(e, "Error sleeping"),
+    }
+}
+
+// Fail like a handler may, by panicking with ?with=panic, as if the database had
+// with ?with=db, or else answering a 500, for testing what is done about it
+pub fn handle_fail_request(request: &str) -> (String, String) {
+    let reason = get_query_param(request, "reason").map_or_else(|| "on purpose".to_owned(), decode_query_value);
+    match get_query_param(request, "with") {
+        Some("panic") => panic!("Failing {}", reason),
+        Some("db") => repository_error_response(RepositoryError::Db(reason.into()), "Failing"),
+        _ => (INTERNAL_SERVER_ERROR.to_owned(), format!("Failing {}", reason)),
+    }
+}
+
+// The users POST /admin/seed inserts, and `seed --count`
+pub fn generated_users(count: u32, seed: u64) -> Vec<User> {
+    let mut rng = SplitMix64(seed);
+    (0..count).map(|index| generate_user(&mut rng, index)).collect()
+}
+
+pub fn random_seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+fn generate_user(rng: &mut SplitMix64, index: u32) -> User {
+    let first_name = FIRST_NAMES[(rng.next() % FIRST_NAMES.len() as u64) as usize];
+    let last_name = LAST_NAMES[(rng.next() % LAST_NAMES.len() as u64) as usize];
+    let tag = rng.next() % 10_000;
+
+    let name = format!("{} {}", first_name, last_name);
+    let email = format!("{}.{}.{}{}@example.com", first_name.to_lowercase(), last_name.to_lowercase(), index, tag);
+    User::new(None, name, email, false)
+}
+
+// Small deterministic generator, stable across versions unlike the std hashers
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+// Since startup
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+static CLIENT_ERRORS: AtomicU64 = AtomicU64::new(0);
+static SERVER_ERRORS: AtomicU64 = AtomicU64::new(0);
+static DB_ERRORS: AtomicU64 = AtomicU64::new(0);
+static AUTH_FAILURES: AtomicU64 = AtomicU64::new(0);
+static PANICS: AtomicU64 = AtomicU64::new(0);
+
+// The counts to alert on, without labels for the expressions to stay simple, the
+// detail being in http_requests_total. Each request is counted once, by the
+// access log, whether a handler answered it or not: the 4xx, the 5xx, the 401 and
+// 403 as auth failures, those the database failed, and the panics, that are 5xx
+// too.
+pub struct Counts {
+    pub requests: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+    pub db_errors: u64,
+    pub auth_failures: u64,
+    pub panics: u64,
+}
+
+// A request was answered with status, None if it never was, after the database
+// failed it if db_error, its handler panicking if panicked
+pub fn count(status: Option<u16>, db_error: bool, panicked: bool) {
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+    let add = |counter: &AtomicU64, counted: bool| {
+        if counted {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    };
+    add(&CLIENT_ERRORS, matches!(status, Some(400..=499)));
+    // A panic before the response stands for a 500
+    add(&SERVER_ERRORS, matches!(status, Some(500..=599)) || (status.is_none() && panicked));
+    add(&AUTH_FAILURES, matches!(status, Some(401 | 403)));
+    add(&DB_ERRORS, db_error);
+    add(&PANICS, panicked);
+}
+
+pub fn counts() -> Counts {
+    Counts {
+        requests: REQUESTS.load(Ordering::Relaxed),
+        client_errors: CLIENT_ERRORS.load(Ordering::Relaxed),
+        server_errors: SERVER_ERRORS.load(Ordering::Relaxed),
+        db_errors: DB_ERRORS.load(Ordering::Relaxed),
+        auth_failures: AUTH_FAILURES.load(Ordering::Relaxed),
+        panics: PANICS.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,223 @@
+use chrono::{ DateTime, Utc };
+use postgres::{ GenericClient, Row };
+use sha2::{ Digest, Sha256 };
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+use crate::auth::{ Role, Scope };
+use crate::repository::{ stored_role, RepositoryError };
+use crate::db::{ pool, POOL };
+use crate::errors::{ repository_error_response, with_causes };
+use crate::http::{ get_body, BAD_REQUEST, INTERNAL_SERVER_ERROR, NOT_FOUND, NOT_IMPLEMENTED, OK_RESPONSE };
+use crate::{ clock, read_only, tables };
+
+// Random bytes in a key, written in hex
+const KEY_LENGTH: usize = 32;
+
+// How often last_used_at is written for a key, by each instance
+const TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+
+// When this instance last wrote last_used_at, by key id
+static TOUCHED: Mutex<Option<HashMap<i32, Instant>>> = Mutex::new(None);
+
+// A key of the table that a request presented
+#[derive(Debug)]
+pub struct StoredKey {
+    pub id: i32,
+    pub name: String,
+    pub role: Role,
+    // None for a key minted without any, which takes every route of its role
+    pub scopes: Option<Vec<Scope>>,
+    pub revoked: bool,
+}
+
+// What is stored of a key, and what it is looked up by, as for the sessions
+pub fn hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+pub fn generate() -> String {
+    let mut bytes = [0; KEY_LENGTH];
+    File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut bytes))
+        .expect("the system has random bytes");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// The key with this hash, revoked or not. Only the hashes reach the database, so
+// how long finding one takes says nothing of the keys.
+pub fn find(key: &str) -> Result<Option<StoredKey>, RepositoryError> {
+    let key_hash = hash(key);
+    let query =
+        tables::sql("SELECT id, name, role, revoked_at IS NOT NULL, scopes FROM {api_keys} WHERE key_hash = $1");
+    let row = pool().read(|client| client.query_opt(query, &[&key_hash]))?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let (role, scopes) = (stored_role(row.get(2)), stored_scopes(row.get(4)));
+    let found = StoredKey { id: row.get(0), name: row.get(1), role, scopes, revoked: row.get(3) };
+    if !found.revoked {
+        touch(found.id);
+    }
+    Ok(Some(found))
+}
+
+// The scopes of a row, those that aren't known left out: a key minted with only
+// such scopes takes no route of any
+fn stored_scopes(scopes: Vec<String>) -> Option<Vec<Scope>> {
+    (!scopes.is_empty()).then(|| scopes.iter().filter_map(|scope| scope.parse().ok()).collect())
+}
+
+// Write when the key was used, at most once every TOUCH_INTERVAL. Nothing is
+// written while read-only, and a failure only costs the time of the write.
+fn touch(id: i32) {
+    if read_only::enabled() {
+        return;
+    }
+    {
+        let mut touched = TOUCHED.lock().unwrap();
+        let touched = touched.get_or_insert_with(HashMap::new);
+        if touched.get(&id).is_some_and(|at| clock::elapsed(*at) < TOUCH_INTERVAL) {
+            return;
+        }
+        touched.insert(id, clock::instant());
+    }
+    let query = tables::sql("UPDATE {api_keys} SET last_used_at = now() WHERE id = $1");
+    let touched = pool().get().map_err(RepositoryError::from).and_then(|mut client| {
+        client.execute(query, &[&id]).map_err(RepositoryError::from)
+    });
+    if let Err(e) = touched {
+        log::error!("Error writing when API key {} was used: {}", id, with_causes(&e));
+    }
+}
+
+// Store a new key, with the key itself in the row this once
+pub fn mint(
+    client: &mut impl GenericClient,
+    name: &str,
+    role: Role,
+    scopes: &[Scope]
+) -> Result<serde_json::Value, postgres::Error> {
+    let key = generate();
+    let scopes: Vec<&str> = scopes.iter().map(|scope| scope.as_str()).collect();
+    let query = tables::sql(
+        "INSERT INTO {api_keys} (name, key_hash, role, scopes) VALUES ($1, $2, $3, $4)
+        RETURNING id, name, scopes, created_at, last_used_at, revoked_at, role"
+    );
+    let row = client.query_one(query, &[&name, &hash(&key), &role.as_str(), &scopes])?;
+    let mut minted = to_json(&row);
+    minted["key"] = key.into();
+    Ok(minted)
+}
+
+// A row of id, name, scopes, created_at, last_used_at, revoked_at and role, the
+// hash left out
+fn to_json(row: &Row) -> serde_json::Value {
+    let (id, name, scopes): (i32, String, Vec<String>) = (row.get(0), row.get(1), row.get(2));
+    let created_at: DateTime<Utc> = row.get(3);
+    let (last_used_at, revoked_at): (Option<DateTime<Utc>>, Option<DateTime<Utc>>) = (row.get(4), row.get(5));
+    serde_json::json!({
+        "id": id,
+        "name": name,
+        "role": stored_role(row.get(6)),
+        "scopes": scopes,
+        "created_at": created_at,
+        "last_used_at": last_used_at,
+        "revoked_at": revoked_at,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewApiKey {
+    name: String,
+    #[serde(default = "writer")]
+    role: Role,
+    // Of the known ones, none for every route of the role
+    #[serde(default)]
+    scopes: Vec<Scope>,
+}
+
+fn only_with_postgres() -> Option<(String, String)> {
+    let message = "Only available when DATABASE_URL is a Postgres database";
+    POOL.get().is_none().then(|| (NOT_IMPLEMENTED.to_owned(), message.to_owned()))
+}
+
+fn writer() -> Role {
+    Role::Writer
+}
+
+// POST /admin/api-keys with {"name": ..., "role": ..., "scopes": [...]}, for a
+// writer unless said otherwise, of every scope unless given some. The only
+// response with the key in it
+pub fn handle_create_api_key_request(request: &str) -> (String, String) {
+    if let Some(response) = only_with_postgres() {
+        return response;
+    }
+    let new = match serde_json::from_str::<NewApiKey>(get_body(request)) {
+        Ok(new) if !new.name.trim().is_empty() => new,
+        Ok(_) => {
+            return (BAD_REQUEST.to_owned(), "name must not be empty".to_owned());
+        }
+        Err(e) => {
+            return (BAD_REQUEST.to_owned(), format!("Invalid request body: {}", e));
+        }
+    };
+
+    let minted = pool().get().map_err(RepositoryError::from).and_then(|mut client| {
+        mint(&mut *client, new.name.trim(), new.role, &new.scopes).map_err(RepositoryError::from)
+    });
+    match minted {
+        Ok(minted) => {
+            log::info!("Minted API key {} named {}", minted["id"], minted["name"]);
+            (OK_RESPONSE.to_owned(), minted.to_string())
+        }
+        Err(e) => {
+            log::error!("Minting an API key failed: {}", with_causes(&e));
+            (INTERNAL_SERVER_ERROR.to_owned(), "Minting the API key failed".to_owned())
+        }
+    }
+}
+
+// GET /admin/api-keys, revoked ones included
+pub fn handle_list_api_keys_request() -> (String, String) {
+    if let Some(response) = only_with_postgres() {
+        return response;
+    }
+    let query =
+        tables::sql("SELECT id, name, scopes, created_at, last_used_at, revoked_at, role FROM {api_keys} ORDER BY id");
+    match pool().read(|client| client.query(query, &[])) {
+        Ok(rows) => {
+            let keys: Vec<serde_json::Value> = rows.iter().map(to_json).collect();
+            (OK_RESPONSE.to_owned(), serde_json::Value::from(keys).to_string())
+        }
+        Err(e) => repository_error_response(e.into(), "Error listing the API keys"),
+    }
+}
+
+// DELETE /admin/api-keys/{id}: the key is kept, revoked at the time of the first
+// of these
+pub fn handle_revoke_api_key_request(id: i32) -> (String, String) {
+    if let Some(response) = only_with_postgres() {
+        return response;
+    }
+    let query = tables::sql(
+        "UPDATE {api_keys} SET revoked_at = COALESCE(revoked_at, now()) WHERE id = $1
+        RETURNING id, name, scopes, created_at, last_used_at, revoked_at, role"
+    );
+    // A write, never run twice as the reads are
+    let revoked = pool().get().map_err(RepositoryError::from).and_then(|mut client| {
+        client.query_opt(query, &[&id]).map_err(RepositoryError::from)
+    });
+    match revoked {
+        Ok(Some(row)) => {
+            log::info!("Revoked API key {}", id);
+            (OK_RESPONSE.to_owned(), to_json(&row).to_string())
+        }
+        Ok(None) => (NOT_FOUND.to_owned(), format!("API key {} not found", id)),
+        Err(e) => repository_error_response(e, "Error revoking the API key"),
+    }
+}
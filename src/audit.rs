@@ -0,0 +1,218 @@
+use chrono::{ DateTime, Utc };
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::OnceLock;
+
+use crate::config::{ self, number_from_env };
+use crate::repository::RepositoryError;
+use crate::db::pool;
+use crate::errors::{ repository_error_response, with_causes };
+use crate::http::{ decode_query_value, get_query_param, BAD_REQUEST, NOT_IMPLEMENTED, OK_RESPONSE };
+use crate::{ clock, pagination, tables, tenant };
+
+// What the SIEM rules match the lines of the trail by
+const EVENT_TYPE: &str = "auth_audit";
+
+static CONFIG: OnceLock<Option<AuditConfig>> = OnceLock::new();
+static SUCCESSES: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // Where the request being handled comes from and goes, set by enter
+    static CURRENT: RefCell<Option<Request>> = const { RefCell::new(None) };
+}
+
+// With AUTH_AUDIT=true, every outcome of authenticating and every denial of a
+// route is logged under the audit target, with the fields event_type auth_audit,
+// the principal or the identity attempted, the emails masked, the address of the
+// client, the route and the outcome code: a line of JSON with LOG_FORMAT=json. AUTH_AUDIT_SUCCESS_SAMPLE=n keeps one
+// in n of the successes, 0 none of them; the failures are all kept. With
+// AUTH_AUDIT_STORE=postgres they are also stored in the auth_audit table, which GET
+// /admin/auth-events?since=... lists.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditConfig {
+    pub success_sample: u64,
+    pub stored: bool,
+}
+
+impl AuditConfig {
+    // None without the trail
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match config::var("AUTH_AUDIT").as_deref() {
+            Ok("false") | Err(_) => Ok(None),
+            Ok("true") => {
+                let success_sample = number_from_env("AUTH_AUDIT_SUCCESS_SAMPLE", 1)?;
+                let stored = match config::var("AUTH_AUDIT_STORE").as_deref() {
+                    Ok("log") | Err(_) => false,
+                    Ok("postgres") => true,
+                    Ok(value) => return Err(format!("AUTH_AUDIT_STORE must be log or postgres, got {:?}", value)),
+                };
+                Ok(Some(AuditConfig { success_sample, stored }))
+            }
+            Ok(value) => Err(format!("AUTH_AUDIT must be true or false, got {:?}", value)),
+        }
+    }
+}
+
+pub fn init(config: Option<AuditConfig>) {
+    CONFIG.set(config).ok();
+}
+
+fn config() -> Option<&'static AuditConfig> {
+    CONFIG.get_or_init(|| None).as_ref()
+}
+
+struct Request {
+    client: Option<IpAddr>,
+    route: String,
+    // Who the credentials said they were, before they were checked
+    attempted: Option<String>,
+}
+
+// Record what happens to the request on this thread as coming from the client and
+// for the route, until the returned guard is dropped
+pub fn enter(client: Option<IpAddr>, route: String) -> Entered {
+    CURRENT.set(Some(Request { client, route, attempted: None }));
+    Entered
+}
+
+pub struct Entered;
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.set(None);
+    }
+}
+
+// Who the credentials of the request being handled claim to be, masked already
+pub fn attempted(identity: &str) {
+    CURRENT.with_borrow_mut(|request| {
+        if let Some(request) = request {
+            request.attempted = Some(identity.to_owned());
+        }
+    });
+}
+
+// Nothing is recorded outside of a request entered
+fn record(kind: &str, outcome: &str, principal: Option<&str>) {
+    let Some(config) = config() else {
+        return;
+    };
+    let current = CURRENT.with_borrow(|request| {
+        request.as_ref().map(|request| (request.client, request.route.clone(), request.attempted.clone()))
+    });
+    let Some((client, route, attempted)) = current else {
+        return;
+    };
+    let client_ip = client.map(|client| client.to_string());
+    let principal = principal.map(str::to_owned).or(attempted);
+    let (occurred_at, tenant) = (clock::utc(), tenant::current());
+    log::info!(
+        target: "audit",
+        event_type = EVENT_TYPE,
+        kind,
+        outcome,
+        principal = principal.as_deref(),
+        client_ip = client_ip.as_deref(),
+        route = route.as_str(),
+        tenant = tenant.as_str();
+        "{} {}", kind, outcome
+    );
+    if config.stored {
+        let query = tables::sql(
+            "INSERT INTO {auth_audit} (occurred_at, kind, outcome, principal, client_ip, route, tenant_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)"
+        );
+        // Once, an insert run again after a connection failure may store it twice
+        let stored = pool().get().map_err(RepositoryError::from).and_then(|mut client| {
+            client
+                .execute(query, &[&occurred_at, &kind, &outcome, &principal, &client_ip, &route, &tenant])
+                .map_err(RepositoryError::from)
+        });
+        if let Err(e) = stored {
+            log::error!("Error storing the auth audit event: {}", with_causes(&e));
+        }
+    }
+}
+
+// The principal was let in, kept one time in AUTH_AUDIT_SUCCESS_SAMPLE
+pub fn authenticated(principal: &str) {
+    let Some(config) = config() else {
+        return;
+    };
+    let sampled = config.success_sample > 0
+        && SUCCESSES.fetch_add(1, Ordering::Relaxed).is_multiple_of(config.success_sample);
+    if sampled {
+        record("authentication", "authenticated", Some(principal));
+    }
+}
+
+// The credentials of the request were turned away, for the reason of the code
+pub fn refused(code: &str) {
+    record("authentication", code, None);
+}
+
+// The principal may not take the route, for the reason of the code
+pub fn denied(principal: &str, code: &str) {
+    record("authorization", code, Some(principal));
+}
+
+// The failed login locked the identity attempted, or the address of the client
+pub fn locked() {
+    record("lockout", "lockout_triggered", None);
+}
+
+// Only the stored events can be listed
+fn only_when_stored() -> Option<(String, String)> {
+    let message = "Only available with AUTH_AUDIT=true and AUTH_AUDIT_STORE=postgres";
+    (!config().is_some_and(|config| config.stored)).then(|| (NOT_IMPLEMENTED.to_owned(), message.to_owned()))
+}
+
+// GET /admin/auth-events?since=...&limit=..., the stored events since the RFC 3339
+// time, the oldest first, PAGE_SIZE_DEFAULT of them unless the limit says otherwise
+pub fn handle_list_events_request(request: &str) -> (String, String) {
+    if let Some(response) = only_when_stored() {
+        return response;
+    }
+    let since = match get_query_param(request, "since").map(decode_query_value) {
+        Some(since) => match DateTime::parse_from_rfc3339(&since) {
+            Ok(since) => since.with_timezone(&Utc),
+            Err(_) => {
+                return (BAD_REQUEST.to_owned(), format!("since must be an RFC 3339 time, got {:?}", since));
+            }
+        },
+        None => DateTime::<Utc>::UNIX_EPOCH,
+    };
+    let limit = match pagination::limit(request) {
+        Ok(limit) => limit,
+        Err(e) => return e.response(),
+    };
+
+    let query = tables::sql(
+        "SELECT id, occurred_at, kind, outcome, principal, client_ip, route, tenant_id FROM {auth_audit}
+        WHERE occurred_at >= $1 ORDER BY occurred_at, id LIMIT $2"
+    );
+    let rows = limit.value as i64;
+    match pool().read(|client| client.query(query, &[&since, &rows])) {
+        Ok(rows) => {
+            let events: Vec<serde_json::Value> = rows
+                .iter()
+                .map(|row| {
+                    let occurred_at: DateTime<Utc> = row.get(1);
+                    serde_json::json!({
+                        "id": row.get::<_, i64>(0),
+                        "timestamp": occurred_at,
+                        "kind": row.get::<_, String>(2),
+                        "outcome": row.get::<_, String>(3),
+                        "principal": row.get::<_, Option<String>>(4),
+                        "client_ip": row.get::<_, Option<String>>(5),
+                        "route": row.get::<_, Option<String>>(6),
+                        "tenant": row.get::<_, String>(7),
+                    })
+                })
+                .collect();
+            (pagination::linked(OK_RESPONSE, request, limit, None), serde_json::Value::from(events).to_string())
+        }
+        Err(e) => repository_error_response(e.into(), "Error listing the auth events"),
+    }
+}
@@ -0,0 +1,703 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha2::{ Digest, Sha256 };
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{ Mutex, OnceLock };
+
+use crate::jwt::{ self, Claims, JwtConfig };
+use crate::secret::Secret;
+use crate::sessions::{ self, SessionConfig };
+use crate::signing::{ self, SigningConfig };
+use crate::errors::repository_error_response;
+use crate::http::{ get_header, with_header };
+use crate::{ api_keys, audit, config, https, locale, tenant };
+
+const DEFAULT_EXEMPT: &str = "/health,/livez,/readyz,/version,/metrics";
+
+// Where the tokens and the sessions are had, refreshed and ended, without either,
+// from the OIDC provider too, and the emails verified and the passwords reset with
+// the tokens mailed
+const LOGIN_PATHS: [&str; 9] = [
+    "/login",
+    "/auth/login",
+    "/auth/callback",
+    "/token/refresh",
+    "/logout",
+    "/session",
+    "/users/verify",
+    "/password-reset/request",
+    "/password-reset/confirm",
+];
+
+const MALFORMED_BASIC: &str = "Authorization: Basic must hold user:password in base64";
+
+const FORBIDDEN_PROBLEM: &str = "HTTP/1.1 403 FORBIDDEN\r\nContent-Type: application/problem+json\r\n\r\n";
+
+static CONFIG: OnceLock<Option<AuthConfig>> = OnceLock::new();
+
+// The SHA-256 of the password each user was last let in with, so that bcrypt runs
+// once per password rather than on every request
+static VERIFIED: Mutex<Option<HashMap<String, [u8; 32]>>> = Mutex::new(None);
+
+thread_local! {
+    // Who the request being handled was authenticated as
+    static CURRENT: RefCell<Option<Principal>> = const { RefCell::new(None) };
+}
+
+// What a principal may do, each role what the one before it may and more: read,
+// then write, then everything, the admin routes and the history included
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Reader,
+    Writer,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Reader => "reader",
+            Role::Writer => "writer",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "reader" => Ok(Role::Reader),
+            "writer" => Ok(Role::Writer),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("a role is reader, writer or admin, got {:?}", role)),
+        }
+    }
+}
+
+// What a key of the database may do on top of its role, when it was minted with
+// any of them: read the users, create and change them, delete them, or the admin
+// routes, which gives every other scope too
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "users:read")]
+    UsersRead,
+    #[serde(rename = "users:write")]
+    UsersWrite,
+    #[serde(rename = "users:delete")]
+    UsersDelete,
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::UsersRead => "users:read",
+            Scope::UsersWrite => "users:write",
+            Scope::UsersDelete => "users:delete",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        match scope {
+            "users:read" => Ok(Scope::UsersRead),
+            "users:write" => Ok(Scope::UsersWrite),
+            "users:delete" => Ok(Scope::UsersDelete),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!("a scope is users:read, users:write, users:delete or admin, got {:?}", scope)),
+        }
+    }
+}
+
+// With API_KEYS set, as "name:key,…", every request needs one of the keys in
+// X-Api-Key or Authorization: Bearer, except those for the paths of AUTH_EXEMPT,
+// the probes, the version and the metrics by default. Its name stands for the client in the
+// logs and as the actor of the events it causes. With API_KEY_STORE=database the
+// keys of POST /admin/api-keys are accepted too, and those of API_KEYS are the root
+// keys that mint the first of them, if `api-keys create` doesn't. With
+// BASIC_AUTH_USERS, as "name:bcrypt hash,…", Authorization: Basic with one of the
+// users and its password does as well as a key, and its name is the actor. With
+// JWT_SECRET or a key pair, see JwtConfig, the tokens of POST /login do too, in
+// Authorization: Bearer, and their user is the actor. With SESSIONS=true, see
+// SessionConfig, so does the cookie of POST /session, if nothing else was sent.
+// The keys and the users of the environment are admins, unless listed as
+// name:key:role or name:hash:role; those of the database and the accounts have
+// the role they were given. The accounts that aren't admins only have their own
+// record, see owner_check and own_list. With REQUEST_SIGNING=true, see
+// SigningConfig, the keys of API_KEYS may sign the requests instead. The keys of
+// the database minted with scopes only take the routes of those, see authorize.
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+    keys: Vec<ApiKey>,
+    users: Vec<BasicUser>,
+    jwt: Option<JwtConfig>,
+    sessions: Option<SessionConfig>,
+    signing: Option<SigningConfig>,
+    user_lists: UserLists,
+    pub exempt: Vec<String>,
+    // Whether the keys of the api_keys table are accepted, with Postgres only
+    pub stored: bool,
+}
+
+// What GET /users is for the accounts that aren't admins, with USER_LISTS:
+// forbidden by default, or own for a list of just themselves
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UserLists {
+    Forbidden,
+    Own,
+}
+
+#[derive(Clone)]
+struct ApiKey {
+    name: String,
+    // What is compared, so that every comparison takes as long
+    digest: [u8; 32],
+    // The secret of the signed requests
+    key: Secret<String>,
+    role: Role,
+}
+
+// Never shows the key
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ApiKey").field("name", &self.name).field("role", &self.role).finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct BasicUser {
+    name: String,
+    // $2b$…, from htpasswd -B or any bcrypt
+    hash: String,
+    role: Role,
+}
+
+impl AuthConfig {
+    // None when the API is open
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let stored = match config::var("API_KEY_STORE").as_deref() {
+            Ok("env") | Err(_) => false,
+            Ok("database") => true,
+            Ok(value) => return Err(format!("API_KEY_STORE must be env or database, got {:?}", value)),
+        };
+        let keys = config::var("API_KEYS").ok();
+        let users = config::var("BASIC_AUTH_USERS").ok();
+        let jwt = JwtConfig::from_env()?;
+        let sessions = SessionConfig::from_env()?;
+        if keys.is_none() && users.is_none() && jwt.is_none() && sessions.is_none() && !stored {
+            return Ok(None);
+        }
+
+        let listed_keys = keys.is_some();
+        let keys = pairs(keys.as_deref().unwrap_or_default())
+            .map(|(name, key)| match key.map(with_role) {
+                Some(Ok((key, role))) => {
+                    Ok(ApiKey { name: name.to_owned(), digest: digest(key), key: Secret::new(key.to_owned()), role })
+                }
+                Some(Err(e)) => Err(format!("API_KEYS: the key named {:?} has {}", name, e)),
+                // Only the name, the key may be what was mistyped
+                None => Err(format!("API_KEYS must list keys as name:key, got one named {:?}", name)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if listed_keys && keys.is_empty() && !stored {
+            return Err("API_KEYS must list at least one key".to_owned());
+        }
+        let signing = SigningConfig::from_env()?;
+        if signing.is_some() && keys.is_empty() {
+            return Err("REQUEST_SIGNING=true needs the keys of API_KEYS to sign with".to_owned());
+        }
+        let users = match users {
+            Some(users) => parse_users(&users)?,
+            None => Vec::new(),
+        };
+        let user_lists = match config::var("USER_LISTS").as_deref() {
+            Ok("forbidden") | Err(_) => UserLists::Forbidden,
+            Ok("own") => UserLists::Own,
+            Ok(value) => return Err(format!("USER_LISTS must be forbidden or own, got {:?}", value)),
+        };
+        let exempt = config::var("AUTH_EXEMPT").unwrap_or_else(|_| DEFAULT_EXEMPT.to_owned());
+        let exempt = exempt.split(',').map(str::trim).filter(|path| !path.is_empty()).map(str::to_owned).collect();
+        Ok(Some(AuthConfig { keys, users, jwt, sessions, signing, user_lists, exempt, stored }))
+    }
+
+    // Whether the nonces of the signed requests are kept in the database, with
+    // Postgres only
+    pub fn nonces_in_database(&self) -> bool {
+        self.signing.as_ref().is_some_and(|signing| signing.nonce_store == signing::NonceStore::Postgres)
+    }
+
+    // Whether JWTs are issued and accepted
+    pub fn jwts(&self) -> bool {
+        self.jwt.is_some()
+    }
+
+    // Whether sessions are stored, with Postgres only
+    pub fn sessions(&self) -> bool {
+        self.sessions.is_some()
+    }
+
+    // Whether refresh tokens are stored, with Postgres only too
+    pub fn refresh_tokens(&self) -> bool {
+        self.jwt.as_ref().is_some_and(|jwt| jwt.refresh_lifetime.is_some())
+    }
+
+    fn takes_keys(&self) -> bool {
+        !self.keys.is_empty() || self.stored
+    }
+
+    // What a request without them is told it needs
+    fn needed(&self) -> String {
+        let ways = [
+            (self.takes_keys(), "an API key"),
+            (self.jwt.is_some(), "a token"),
+            (!self.users.is_empty(), "a user"),
+            (self.sessions.is_some(), "a session"),
+            (self.signing.is_some(), "a signature"),
+        ];
+        let ways: Vec<&str> = ways.iter().filter(|(on, _)| *on).map(|(_, way)| *way).collect();
+        let headers = [
+            (self.takes_keys(), "X-Api-Key"),
+            (self.takes_keys() || self.jwt.is_some(), "Authorization: Bearer"),
+            (!self.users.is_empty(), "Authorization: Basic"),
+            (self.sessions.is_some(), "the session cookie"),
+            (self.signing.is_some(), "X-Signature"),
+        ];
+        let headers: Vec<&str> = headers.iter().filter(|(on, _)| *on).map(|(_, header)| *header).collect();
+        let needed = format!("{} is needed, in {}", ways.join(" or "), headers.join(" or "));
+        needed[..1].to_uppercase() + &needed[1..]
+    }
+}
+
+// The name:secret of a list, with None for the secret when it is missing
+fn pairs(list: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
+    list.split(',').map(str::trim).filter(|pair| !pair.is_empty()).map(|pair| match pair.split_once(':') {
+        Some((name, secret)) if !name.is_empty() && !secret.is_empty() => (name, Some(secret)),
+        _ => (pair.split(':').next().unwrap_or_default(), None),
+    })
+}
+
+// The secret of a name:secret:role, and the role, admin when there is none
+fn with_role(secret: &str) -> Result<(&str, Role), String> {
+    match secret.split_once(':') {
+        Some((secret, role)) => Ok((secret, role.parse()?)),
+        None => Ok((secret, Role::Admin)),
+    }
+}
+
+fn parse_users(users: &str) -> Result<Vec<BasicUser>, String> {
+    let users = pairs(users)
+        .map(|(name, hash)| match hash.map(with_role) {
+            Some(Ok((hash, role))) if hash.starts_with("$2") => {
+                Ok(BasicUser { name: name.to_owned(), hash: hash.to_owned(), role })
+            }
+            Some(Err(e)) => Err(format!("BASIC_AUTH_USERS: the user named {:?} has {}", name, e)),
+            _ => Err(format!("BASIC_AUTH_USERS must list users as name:bcrypt hash, got one named {:?}", name)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if users.is_empty() {
+        return Err("BASIC_AUTH_USERS must list at least one user".to_owned());
+    }
+    Ok(users)
+}
+
+pub fn init(config: Option<AuthConfig>) {
+    CONFIG.set(config).ok();
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+// The key among keys, each compared in full
+fn find_key<'a>(keys: &'a [ApiKey], key: &str) -> Option<&'a ApiKey> {
+    let digest = digest(key);
+    keys.iter().fold(None, |found, candidate| {
+        let difference = candidate.digest.iter().zip(&digest).fold(0, |difference, (a, b)| difference | (a ^ b));
+        if difference == 0 { Some(candidate) } else { found }
+    })
+}
+
+// Who a request was authenticated as
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal {
+    // Of the key or the user, the actor of what the request does
+    pub name: String,
+    pub role: Role,
+    // Those of its key, when it was minted with some; None for every scope
+    pub scopes: Option<Vec<Scope>>,
+    // Those of its token or its session, if it came with one
+    pub claims: Option<Claims>,
+}
+
+impl Principal {
+    fn named(name: impl Into<String>, role: Role) -> Self {
+        Principal { name: name.into(), role, scopes: None, claims: None }
+    }
+
+    fn with_claims(claims: Claims) -> Self {
+        Principal { name: claims.name(), role: claims.role, scopes: None, claims: Some(claims) }
+    }
+
+    // Whether it may take the routes of the scope, as it may with the admin one
+    fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.as_ref().is_none_or(|scopes| scopes.contains(&scope) || scopes.contains(&Scope::Admin))
+    }
+
+    // The id of the account it is, unless it is an admin: the keys and the Basic
+    // users aren't any account, and have every record their role allows
+    fn owner(&self) -> Option<&str> {
+        self.claims.as_ref().filter(|_| self.role < Role::Admin).map(|claims| claims.sub.as_str())
+    }
+}
+
+pub fn jwt() -> Option<&'static JwtConfig> {
+    CONFIG.get_or_init(|| None).as_ref().and_then(|config| config.jwt.as_ref())
+}
+
+pub fn sessions() -> Option<&'static SessionConfig> {
+    CONFIG.get_or_init(|| None).as_ref().and_then(|config| config.sessions.as_ref())
+}
+
+// Who the request is from, or the 401 answering it, unless the keys of the
+// database couldn't be read. None when the API is open, for the exempt paths,
+// LOGIN_PATHS, and for the preflights of CORS, which have no credentials, unless
+// the client authenticated with a certificate over TLS.
+pub fn authenticate(request: &str, path: &str) -> Result<Option<Principal>, (String, String)> {
+    let authenticated = principal(request, path);
+    if let Ok(Some(principal)) = &authenticated {
+        audit::authenticated(&principal.name);
+    }
+    authenticated
+}
+
+fn principal(request: &str, path: &str) -> Result<Option<Principal>, (String, String)> {
+    // Verified in the handshake already, whatever else the request has
+    if let Some((name, role)) = https::client_certificate() {
+        return Ok(Some(Principal::named(name, role)));
+    }
+    let Some(config) = CONFIG.get_or_init(|| None) else {
+        return Ok(None);
+    };
+    let method = request.split_whitespace().next().unwrap_or_default();
+    let exempt =
+        LOGIN_PATHS.contains(&path) || config.exempt.iter().any(|exempt| exempt.trim_end_matches('/') == path);
+    if method == "OPTIONS" || exempt {
+        return Ok(None);
+    }
+    let signed = config.signing.as_ref().and_then(|signing| {
+        Some(signing).zip(signing::signer(signing, request, &config.keys, |key| key.key.expose()))
+    });
+    if let Some((signing, signed)) = signed {
+        let key = signed.map_err(|rejected| unauthorized(config, rejected.code, rejected.detail))?;
+        return match signing::first_use(signing, &key.name, request) {
+            Ok(true) => Ok(Some(Principal::named(&key.name, key.role))),
+            Ok(false) => Err(unauthorized(
+                config,
+                "nonce_replayed",
+                "X-Nonce was sent before with this key, sign the request again with a new one"
+            )),
+            Err(e) => Err(repository_error_response(e, "Error checking the nonce")),
+        };
+    }
+    let authorization = get_header(request, "Authorization");
+    if let Some(credentials) = authorization.and_then(|authorization| authorization.strip_prefix("Basic ")) {
+        if !config.users.is_empty() {
+            return basic_user(&config.users, credentials)
+                .map(|user| Some(Principal::named(&user.name, user.role)))
+                .map_err(|detail| unauthorized(config, "invalid_credentials", detail));
+        }
+    }
+    let bearer = authorization.and_then(|authorization| authorization.strip_prefix("Bearer ")).map(str::trim);
+    // The keys have no dots, the tokens have two
+    if let Some((jwt, token)) = config.jwt.as_ref().zip(bearer.filter(|token| token.matches('.').count() == 2)) {
+        let claims = jwt::decode(jwt, token).map_err(|rejected| {
+            unauthorized(config, rejected.code, rejected.detail)
+        })?;
+        if tenant::from_request(request).ok().as_ref() != Some(&claims.tenant) {
+            return Err(unauthorized(config, "token_wrong_tenant", "The token is for another tenant"));
+        }
+        return Ok(Some(Principal::with_claims(claims)));
+    }
+
+    let key = get_header(request, "X-Api-Key").map(str::trim).or(bearer);
+    let session = config.sessions.as_ref().zip(sessions::cookie(request, sessions::COOKIE));
+    if let Some((sessions, id)) = session.filter(|_| authorization.is_none() && key.is_none()) {
+        return session_principal(config, sessions, request, id).map(Some);
+    }
+
+    let key = key.filter(|_| config.takes_keys());
+    let Some(key) = key else {
+        return Err(unauthorized(config, "missing_credentials", &config.needed()));
+    };
+    if let Some(found) = find_key(&config.keys, key) {
+        return Ok(Some(Principal::named(&found.name, found.role)));
+    }
+    if !config.stored {
+        return Err(unauthorized(config, "invalid_api_key", "The API key isn't valid"));
+    }
+    match api_keys::find(key) {
+        Ok(Some(stored)) if !stored.revoked => {
+            Ok(Some(Principal { scopes: stored.scopes, ..Principal::named(stored.name, stored.role) }))
+        }
+        Ok(Some(_)) => Err(unauthorized(config, "api_key_revoked", "The API key was revoked")),
+        Ok(None) => Err(unauthorized(config, "invalid_api_key", "The API key isn't valid")),
+        Err(e) => Err(repository_error_response(e, "Error checking the API key")),
+    }
+}
+
+// Who the session of the cookie is for, or the 401 clearing a cookie whose session
+// ended, or never was
+fn session_principal(
+    config: &AuthConfig,
+    sessions: &SessionConfig,
+    request: &str,
+    id: &str
+) -> Result<Principal, (String, String)> {
+    let ended = || {
+        let (status_line, body) = unauthorized(config, "invalid_session", "The session ended or never was");
+        (with_header(&status_line, &sessions::clear_cookie()), body)
+    };
+    let Ok(tenant) = tenant::from_request(request) else {
+        return Err(ended());
+    };
+    let method = request.split_whitespace().next().unwrap_or_default();
+    match sessions::find(sessions, id, &tenant) {
+        // Another site may have made the browser send the cookie, not the token
+        Ok(Some(session)) if matches!(method, "POST" | "PUT" | "PATCH" | "DELETE") && !session.csrf_sent(request) => {
+            Err(csrf_refused(&session.claims.name()))
+        }
+        Ok(Some(session)) => Ok(Principal::with_claims(session.claims)),
+        Ok(None) => Err(ended()),
+        Err(e) => Err(repository_error_response(e, "Error checking the session")),
+    }
+}
+
+// The user whose password the base64 user:password of credentials is
+fn basic_user<'a>(users: &'a [BasicUser], credentials: &str) -> Result<&'a BasicUser, &'static str> {
+    let decoded = BASE64.decode(credentials.trim()).map_err(|_| MALFORMED_BASIC)?;
+    let decoded = String::from_utf8(decoded).map_err(|_| MALFORMED_BASIC)?;
+    let (name, password) = decoded.split_once(':').ok_or(MALFORMED_BASIC)?;
+    audit::attempted(name);
+    let Some(user) = users.iter().find(|user| user.name == name) else {
+        // As long as for a user that exists, so that the names can't be guessed
+        bcrypt::verify(password, &users[0].hash).ok();
+        return Err("The user or the password isn't valid");
+    };
+
+    let digest = digest(password);
+    let mut verified = VERIFIED.lock().unwrap();
+    if verified.get_or_insert_with(HashMap::new).get(name) == Some(&digest) {
+        return Ok(user);
+    }
+    drop(verified);
+    if !bcrypt::verify(password, &user.hash).unwrap_or(false) {
+        return Err("The user or the password isn't valid");
+    }
+    VERIFIED.lock().unwrap().get_or_insert_with(HashMap::new).insert(user.name.clone(), digest);
+    Ok(user)
+}
+
+// With a challenge for each of the ways in, and the code of what was wrong with
+// the credentials, for the audit trail too
+fn unauthorized(config: &AuthConfig, code: &str, detail: &str) -> (String, String) {
+    audit::refused(code);
+    let mut status_line = "HTTP/1.1 401 UNAUTHORIZED\r\nContent-Type: application/problem+json\r\n".to_owned();
+    if config.takes_keys() || config.jwt.is_some() {
+        status_line.push_str("WWW-Authenticate: Bearer realm=\"rust-api\"\r\n");
+    }
+    if !config.users.is_empty() {
+        status_line.push_str("WWW-Authenticate: Basic realm=\"rust-api\"\r\n");
+    }
+    status_line.push_str("\r\n");
+    let body = serde_json::json!({
+        "type": "about:blank",
+        "title": locale::title(401, "Unauthorized"),
+        "status": 401,
+        "detail": detail,
+        "code": code,
+    });
+    (status_line, body.to_string())
+}
+
+// The 401 of a login that failed
+pub fn unauthorized_problem(code: &str, detail: &str) -> (String, String) {
+    let config = CONFIG.get().and_then(Option::as_ref).expect("logging in needs the auth config");
+    unauthorized(config, code, detail)
+}
+
+// The 403 of a principal without the role the route needs, or whose key lacks the
+// scope of the route, if it has one, unless it has both. The API is open without
+// one.
+pub fn authorize(
+    principal: Option<&Principal>,
+    required: Role,
+    scope: Option<Scope>,
+    route: &str
+) -> Result<(), (String, String)> {
+    let Some(principal) = principal else {
+        return Ok(());
+    };
+    if principal.role < required {
+        audit::denied(&principal.name, "missing_role");
+        let body = serde_json::json!({
+            "type": "about:blank",
+            "title": locale::title(403, "Forbidden"),
+            "status": 403,
+            "detail": format!("{} needs the {} role, {} is a {}", route, required, principal.name, principal.role),
+            "code": "missing_role",
+            "required_role": required,
+        });
+        return Err((FORBIDDEN_PROBLEM.to_owned(), body.to_string()));
+    }
+    let Some(scope) = scope.filter(|scope| !principal.has_scope(*scope)) else {
+        return Ok(());
+    };
+    audit::denied(&principal.name, "missing_scope");
+    let body = serde_json::json!({
+        "type": "about:blank",
+        "title": locale::title(403, "Forbidden"),
+        "status": 403,
+        "detail": format!("{} needs the {} scope, the key of {} doesn't have it", route, scope, principal.name),
+        "code": "missing_scope",
+        "required_scope": scope,
+    });
+    Err((FORBIDDEN_PROBLEM.to_owned(), body.to_string()))
+}
+
+// authorize for the principal entered, for what needs more than its route does,
+// as each field of a GraphQL request
+pub fn authorize_entered(required: Role, scope: Option<Scope>, route: &str) -> Result<(), (String, String)> {
+    CURRENT.with_borrow(|principal| authorize(principal.as_ref(), required, scope, route))
+}
+
+fn csrf_refused(principal: &str) -> (String, String) {
+    audit::denied(principal, "invalid_csrf_token");
+    let body = serde_json::json!({
+        "type": "about:blank",
+        "title": locale::title(403, "Forbidden"),
+        "status": 403,
+        "detail": format!("A change with the session cookie must send the CSRF token in {}", sessions::CSRF_HEADER),
+        "code": "invalid_csrf_token",
+    });
+    (FORBIDDEN_PROBLEM.to_owned(), body.to_string())
+}
+
+fn not_owner(principal: &Principal, detail: String) -> (String, String) {
+    audit::denied(&principal.name, "not_owner");
+    let body = serde_json::json!({
+        "type": "about:blank",
+        "title": locale::title(403, "Forbidden"),
+        "status": 403,
+        "detail": detail,
+        "code": "not_owner",
+    });
+    (FORBIDDEN_PROBLEM.to_owned(), body.to_string())
+}
+
+// The 403 of an account at the route for the record of another, unless it is
+// its own or the principal entered may have any. It comes before the record is
+// looked for, so that it is the same whether or not there is one: the ids of the
+// others aren't told, not even by a 404.
+pub fn owner_check(id: i32, route: &str) -> Result<(), (String, String)> {
+    CURRENT.with_borrow(|principal| match principal {
+        Some(principal) if principal.owner().is_some_and(|owner| owner != id.to_string()) => {
+            Err(not_owner(principal, format!("{} is only for the record of {}", route, principal.name)))
+        }
+        _ => Ok(()),
+    })
+}
+
+// The only user GET /users lists for the principal entered, None for all of them,
+// or its 403 with USER_LISTS=forbidden
+pub fn own_list() -> Result<Option<i32>, (String, String)> {
+    let lists = CONFIG.get_or_init(|| None).as_ref().map_or(UserLists::Forbidden, |config| config.user_lists);
+    CURRENT.with_borrow(|principal| {
+        let Some((principal, owner)) = principal.as_ref().and_then(|principal| Some((principal, principal.owner()?)))
+        else {
+            return Ok(None);
+        };
+        match lists {
+            UserLists::Own => Ok(Some(owner.parse().unwrap_or_default())),
+            UserLists::Forbidden => {
+                let detail = "GET /users is only for admins, the others have GET /users/{id}";
+                Err(not_owner(principal, detail.to_owned()))
+            }
+        }
+    })
+}
+
+// Act as the principal on this thread until the returned guard is dropped
+pub fn enter(principal: Option<Principal>) -> Entered {
+    CURRENT.set(principal);
+    Entered
+}
+
+pub struct Entered;
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.set(None);
+    }
+}
+
+// The name of the principal entered on this thread, None outside of requests or
+// when the API is open
+pub fn current() -> Option<String> {
+    CURRENT.with_borrow(|principal| principal.as_ref().map(|principal| principal.name.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_found_by_comparing_them_all() {
+        let key = |name: &str, key: &str, role| {
+            ApiKey { name: name.to_owned(), digest: digest(key), key: Secret::new(key.to_owned()), role }
+        };
+        let keys = [key("deploy", "s3cret", Role::Admin), key("ci", "an0ther", Role::Reader)];
+        let name = |key| find_key(&keys, key).map(|found| found.name.as_str());
+        assert_eq!(name("an0ther"), Some("ci"));
+        assert_eq!(name("s3cret"), Some("deploy"));
+        assert_eq!(name("s3cre"), None);
+        assert_eq!(name(""), None);
+    }
+
+    #[test]
+    fn basic_credentials_that_dont_decode_are_turned_away() {
+        // admin:s3cret, at the lowest cost
+        let hash = "$2b$04$O.vIcdqRYXi5KqXHrK/0.uleDGk9vG3omjysBgyJMwYQCqzQP3oju";
+        let users = [BasicUser { name: "admin".to_owned(), hash: hash.to_owned(), role: Role::Admin }];
+        assert_eq!(basic_user(&users, "YWRtaW46czNjcmV0").unwrap().name, "admin");
+        assert_eq!(basic_user(&users, "YWRtaW46czNjcmV0").unwrap().name, "admin");
+        assert_eq!(basic_user(&users, "YWRtaW46d3Jvbmc="), Err("The user or the password isn't valid"));
+        assert_eq!(basic_user(&users, "bm9ib2R5OnMzY3JldA=="), Err("The user or the password isn't valid"));
+        // admins3cret, without the colon
+        assert_eq!(basic_user(&users, "YWRtaW5zM2NyZXQ="), Err(MALFORMED_BASIC));
+        assert_eq!(basic_user(&users, "not base64!"), Err(MALFORMED_BASIC));
+        assert_eq!(basic_user(&users, "//8="), Err(MALFORMED_BASIC));
+    }
+}
@@ -0,0 +1,291 @@
+use chrono::Utc;
+use postgres::{ Client, IsolationLevel, Transaction };
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::fs::{ self, File };
+use std::io::{ BufRead, BufReader, BufWriter, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::OnceLock;
+
+use crate::db::CONNECTOR;
+use crate::errors::with_causes;
+use crate::http::{ INTERNAL_SERVER_ERROR, NOT_IMPLEMENTED, OK_RESPONSE };
+use crate::{ config, migrations, tables };
+
+// Backups go to BACKUP_DIR, relative to the working directory unless absolute
+const DEFAULT_DIR: &str = "backups";
+
+static DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+// First line of every backup, with the format version and the schema version
+const FORMAT: &str = "rust-api-backup";
+const FORMAT_VERSION: u64 = 1;
+
+// Rows inserted per statement when restoring
+const BATCH_SIZE: usize = 500;
+
+// Emptied before restoring, along with the sequences
+const TRUNCATE_QUERY: &str =
+    "TRUNCATE {users}, {sessions}, {refresh_tokens}, {verification_tokens}, {password_reset_tokens}, {events_outbox},
+    {idempotency_keys} RESTART IDENTITY";
+
+// A table, by the name it has in backups whatever the prefix, with the SQL that
+// copies it out as lines of {"table": ..., "row": ...} and the SQL that loads
+// a JSON array of its rows back in
+struct Table {
+    name: &'static str,
+    export: &'static str,
+    import: &'static str,
+    // Moves the sequence of the id past the restored rows
+    sequence: Option<&'static str>,
+}
+
+const TABLES: [Table; 3] = [
+    Table {
+        name: "users",
+        export: "COPY (
+                SELECT json_build_object('table', 'users', 'row', row_to_json(t)) FROM {users} t ORDER BY id
+            ) TO STDOUT",
+        import: "INSERT INTO {users} SELECT * FROM json_populate_recordset(NULL::{users}, $1)",
+        sequence: Some(
+            "SELECT setval(pg_get_serial_sequence('{users}', 'id'), max(id)) FROM {users} HAVING count(*) > 0"
+        ),
+    },
+    Table {
+        name: "events_outbox",
+        export: "COPY (
+                SELECT json_build_object('table', 'events_outbox', 'row', row_to_json(t)) FROM {events_outbox} t
+                ORDER BY id
+            ) TO STDOUT",
+        import: "INSERT INTO {events_outbox} SELECT * FROM json_populate_recordset(NULL::{events_outbox}, $1)",
+        sequence: Some(
+            "SELECT setval(pg_get_serial_sequence('{events_outbox}', 'id'), max(id)) FROM {events_outbox}
+            HAVING count(*) > 0"
+        ),
+    },
+    Table {
+        name: "idempotency_keys",
+        export: "COPY (
+                SELECT json_build_object('table', 'idempotency_keys', 'row', row_to_json(t)) FROM {idempotency_keys} t
+                ORDER BY key
+            ) TO STDOUT",
+        import: "INSERT INTO {idempotency_keys} SELECT * FROM json_populate_recordset(NULL::{idempotency_keys}, $1)",
+        sequence: None,
+    },
+];
+
+#[derive(Deserialize)]
+struct BackupRow {
+    table: String,
+    row: Value,
+}
+
+// What a backup or a restore went through
+pub struct Summary {
+    pub path: PathBuf,
+    pub schema_version: i64,
+    // In the order of TABLES
+    pub rows: Vec<(&'static str, u64)>,
+}
+
+impl Summary {
+    pub fn to_json(&self) -> Value {
+        let rows: serde_json::Map<String, Value> = self.rows
+            .iter()
+            .map(|&(table, count)| (table.to_owned(), count.into()))
+            .collect();
+        serde_json::json!({
+            "path": self.path.display().to_string(),
+            "schema_version": self.schema_version,
+            "rows": rows,
+        })
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows: Vec<String> = self.rows.iter().map(|(table, count)| format!("{} {}", count, table)).collect();
+        write!(f, "{} ({}, schema version {})", self.path.display(), rows.join(", "), self.schema_version)
+    }
+}
+
+// None without BACKUP_DIR
+pub fn dir_from_env() -> Result<Option<PathBuf>, String> {
+    match config::var("BACKUP_DIR") {
+        Ok(dir) if dir.trim().is_empty() => Err("BACKUP_DIR must not be empty".to_owned()),
+        Ok(dir) => Ok(Some(PathBuf::from(dir))),
+        Err(_) => Ok(None),
+    }
+}
+
+pub fn init(dir: Option<PathBuf>) {
+    DIR.set(dir).ok();
+}
+
+// That of BACKUP_DIR, when it is set
+pub fn configured_dir() -> Option<&'static Path> {
+    DIR.get().and_then(Option::as_deref)
+}
+
+pub fn backup_dir() -> PathBuf {
+    configured_dir().map_or_else(|| PathBuf::from(DEFAULT_DIR), Path::to_path_buf)
+}
+
+// Write every table to a new file of dir, named after the time, from a single
+// snapshot. The rows are streamed from COPY, so the backup is never all in
+// memory; the file only gets its name once complete.
+pub fn backup(client: &mut Client, dir: &Path) -> Result<Summary, Box<dyn Error>> {
+    fs::create_dir_all(dir).map_err(|e| format!("can't create {}: {}", dir.display(), e))?;
+    let path = dir.join(format!("backup-{}.ndjson", Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+    let partial = path.with_extension("ndjson.partial");
+
+    match write_backup(client, &partial) {
+        Ok(summary) => {
+            fs::rename(&partial, &path)?;
+            Ok(Summary { path, ..summary })
+        }
+        Err(e) => {
+            fs::remove_file(&partial).ok();
+            Err(e)
+        }
+    }
+}
+
+fn write_backup(client: &mut Client, path: &Path) -> Result<Summary, Box<dyn Error>> {
+    let file = File::create(path).map_err(|e| format!("can't create {}: {}", path.display(), e))?;
+    let mut out = BufWriter::new(file);
+    let mut transaction = client
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .read_only(true)
+        .start()?;
+
+    let schema_version = migrations::schema_version(&mut transaction)?;
+    let header = serde_json::json!({
+        "format": FORMAT,
+        "version": FORMAT_VERSION,
+        "schema_version": schema_version,
+        "created_at": Utc::now(),
+    });
+    writeln!(out, "{}", header)?;
+
+    let mut rows = Vec::new();
+    for table in &TABLES {
+        let mut count = 0;
+        for line in BufReader::new(transaction.copy_out(tables::sql(table.export))?).lines() {
+            // The text format of COPY doubles backslashes, and JSON has none of
+            // the other characters it escapes
+            writeln!(out, "{}", line?.replace("\\\\", "\\"))?;
+            count += 1;
+        }
+        rows.push((table.name, count));
+    }
+    transaction.commit()?;
+
+    out.into_inner()?.sync_all()?;
+    Ok(Summary { path: path.to_owned(), schema_version, rows })
+}
+
+// Load a backup, in one transaction, after applying the pending migrations. The
+// schema must be the one the backup was taken with, and the tables empty unless
+// force, which empties them first.
+pub fn restore(client: &mut Client, path: &Path, force: bool) -> Result<Summary, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| format!("can't open {}: {}", path.display(), e))?;
+    let mut lines = BufReader::new(file).lines();
+    let header: Value = match lines.next() {
+        Some(line) => serde_json::from_str(&line?).unwrap_or_default(),
+        None => Value::Null,
+    };
+    if header["format"] != FORMAT || header["version"] != FORMAT_VERSION {
+        return Err(format!("{} is not a backup of this API", path.display()).into());
+    }
+    let backup_schema_version = header["schema_version"].as_i64().unwrap_or_default();
+
+    migrations::apply(client)?;
+    let mut transaction = client.transaction()?;
+    let schema_version = migrations::schema_version(&mut transaction)?;
+    if schema_version != backup_schema_version {
+        return Err(
+            format!(
+                "the backup is of schema version {} and the database is at {}, restore it with the version that took it",
+                backup_schema_version,
+                schema_version
+            ).into()
+        );
+    }
+
+    let mut existing = 0;
+    for table in &TABLES {
+        let count: i64 = transaction.query_one(&format!("SELECT count(*) FROM {}", table_name(table)), &[])?.get(0);
+        existing += count;
+    }
+    if existing > 0 && !force {
+        return Err(format!("the database already has {} rows, restore with --force to replace them", existing).into());
+    }
+    transaction.batch_execute(tables::sql(TRUNCATE_QUERY))?;
+
+    let mut rows: Vec<(&'static str, u64)> = TABLES.iter().map(|table| (table.name, 0)).collect();
+    let mut batch: Vec<Value> = Vec::new();
+    let mut batch_table = 0;
+    for (index, line) in lines.enumerate() {
+        let row: BackupRow = serde_json::from_str(&line?).map_err(|e| format!("line {} of the backup: {}", index + 2, e))?;
+        let table = TABLES
+            .iter()
+            .position(|table| table.name == row.table)
+            .ok_or_else(|| format!("line {} of the backup: unknown table {}", index + 2, row.table))?;
+        if !batch.is_empty() && (table != batch_table || batch.len() == BATCH_SIZE) {
+            insert(&mut transaction, batch_table, &mut batch, &mut rows)?;
+        }
+        batch_table = table;
+        batch.push(row.row);
+    }
+    if !batch.is_empty() {
+        insert(&mut transaction, batch_table, &mut batch, &mut rows)?;
+    }
+
+    for table in &TABLES {
+        if let Some(sequence) = table.sequence {
+            transaction.batch_execute(tables::sql(sequence))?;
+        }
+    }
+    transaction.commit()?;
+
+    Ok(Summary { path: path.to_owned(), schema_version, rows })
+}
+
+fn insert(
+    transaction: &mut Transaction,
+    table: usize,
+    batch: &mut Vec<Value>,
+    rows: &mut [(&'static str, u64)]
+) -> Result<(), postgres::Error> {
+    let inserted = transaction.execute(tables::sql(TABLES[table].import), &[&Value::Array(std::mem::take(batch))])?;
+    rows[table].1 += inserted;
+    Ok(())
+}
+
+// The name of the table in SQL, prefixed
+fn table_name(table: &Table) -> String {
+    tables::identifier(&tables::prefixed(table.name))
+}
+
+// POST /admin/backup, to BACKUP_DIR on the server. It runs on a connection of its
+// own, without the statement timeout of the pool.
+pub fn handle_backup_request() -> (String, String) {
+    let Some(connector) = CONNECTOR.get() else {
+        return (NOT_IMPLEMENTED.to_owned(), "Only available when DATABASE_URL is a Postgres database".to_owned());
+    };
+
+    let result = connector.connect().map_err(Into::into).and_then(|mut client| backup(&mut client, &backup_dir()));
+    match result {
+        Ok(summary) => {
+            log::info!("Backed up to {}", summary);
+            (OK_RESPONSE.to_owned(), summary.to_json().to_string())
+        }
+        Err(e) => {
+            log::error!("Backup failed: {}", with_causes(&*e));
+            (INTERNAL_SERVER_ERROR.to_owned(), "Backup failed".to_owned())
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use serde_json::Value;
+use std::sync::OnceLock;
+
+use crate::config::{ self, number_from_env };
+use crate::http::{ get_body, get_header };
+use crate::{ access_log, redact };
+
+const DEFAULT_MAX_BYTES: u64 = 4096;
+
+// What RUST_LOG sets the level of the lines by, off unless body=debug
+const TARGET: &str = "body";
+
+static CONFIG: OnceLock<BodyLogConfig> = OnceLock::new();
+
+// With RUST_LOG=body=debug, the bodies of the POST, PUT and PATCH requests and of
+// the responses answering 400 or more are logged, under the request_id of the
+// request, for reproducing what a client saw. They are redacted like the logs, see
+// redact::json, and cut to DEBUG_BODY_MAX_BYTES, the binary ones left out.
+// DEBUG_BODY_ROUTES, separated by commas, limits them to the routes under those
+// paths, /users being every route of the users and /users/{id} one of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BodyLogConfig {
+    pub routes: Vec<String>,
+    pub max_bytes: usize,
+}
+
+impl BodyLogConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let routes = config::var("DEBUG_BODY_ROUTES").unwrap_or_default();
+        let routes = routes
+            .split(',')
+            .map(|route| route.trim().trim_end_matches('/'))
+            .filter(|route| !route.is_empty())
+            .map(str::to_owned)
+            .collect();
+        let max_bytes = number_from_env("DEBUG_BODY_MAX_BYTES", DEFAULT_MAX_BYTES)? as usize;
+        Ok(BodyLogConfig { routes, max_bytes })
+    }
+}
+
+pub fn init(config: BodyLogConfig) {
+    CONFIG.set(config).ok();
+}
+
+fn config() -> &'static BodyLogConfig {
+    CONFIG.get_or_init(|| BodyLogConfig { routes: Vec::new(), max_bytes: DEFAULT_MAX_BYTES as usize })
+}
+
+// The route of the request on this thread, if its bodies are logged
+fn logged_route() -> Option<String> {
+    if !log::log_enabled!(target: TARGET, log::Level::Debug) {
+        return None;
+    }
+    let route = access_log::route()?;
+    let path = route.split_once(' ').map_or(route.as_str(), |(_, path)| path);
+    let under = |prefix: &String| path == prefix || path.starts_with(&format!("{}/", prefix));
+    let routes = &config().routes;
+    (routes.is_empty() || routes.iter().any(under)).then_some(route)
+}
+
+// The request being answered on this thread, unless it has no body to log
+pub fn request(request: &str) {
+    let method = request.split_whitespace().next().unwrap_or_default();
+    if !matches!(method, "POST" | "PUT" | "PATCH") {
+        return;
+    }
+    if let Some(route) = logged_route() {
+        log_body("request", &route, get_header(request, "Content-Type"), get_body(request).as_bytes());
+    }
+}
+
+// The response of head and content to the request on this thread, if it is an
+// error
+pub fn response(head: &str, content: &[u8]) {
+    let status = head.split_whitespace().nth(1).and_then(|status| status.parse::<u16>().ok());
+    if status.is_none_or(|status| status < 400) {
+        return;
+    }
+    if let Some(route) = logged_route() {
+        let content_type = head.split("\r\n").find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim().eq_ignore_ascii_case("Content-Type").then_some(value.trim())
+        });
+        log_body("response", &route, content_type, content);
+    }
+}
+
+fn log_body(kind: &'static str, route: &str, content_type: Option<&str>, body: &[u8]) {
+    if body.is_empty() {
+        return;
+    }
+    let Some((logged, truncated)) = loggable(content_type, body, config().max_bytes) else {
+        log::debug!(target: TARGET, kind = kind, route = route, bytes = body.len(); "Binary {} body left out", kind);
+        return;
+    };
+    log::debug!(
+        target: TARGET,
+        kind = kind,
+        route = route,
+        bytes = body.len(),
+        truncated = truncated,
+        body = logged.as_str();
+        "{} body of {}",
+        if kind == "request" { "Request" } else { "Response" },
+        route
+    );
+}
+
+// The body as it is logged, redacted then cut to max_bytes, and whether it was
+// cut, None for a binary one
+fn loggable(content_type: Option<&str>, body: &[u8], max_bytes: usize) -> Option<(String, bool)> {
+    let content_type = content_type.unwrap_or("text/plain").to_ascii_lowercase();
+    let textual = ["text/", "json", "xml", "application/x-www-form-urlencoded"];
+    if !textual.iter().any(|textual| content_type.contains(textual)) {
+        return None;
+    }
+    let text = std::str::from_utf8(body).ok().filter(|text| !text.contains('\u{fffd}'))?;
+    let redacted = match serde_json::from_str::<Value>(text) {
+        Ok(json) => redact::json(&json).to_string(),
+        Err(_) => redact::text(text),
+    };
+    if redacted.len() <= max_bytes {
+        return Some((redacted, false));
+    }
+    let end = (0..=max_bytes).rev().find(|end| redacted.is_char_boundary(*end)).unwrap_or_default();
+    Some((redacted[..end].to_owned(), true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_are_redacted_then_cut() {
+        let body = br#"{"name":"Ada Lovelace","email":"ada@example.com","password":"correct horse"}"#;
+        let (logged, truncated) = loggable(Some("application/json"), body, 4096).unwrap();
+        assert!(!truncated);
+        assert!(!logged.contains("correct horse") && logged.contains(r#""password":"[redacted]""#), "{}", logged);
+        assert!(!logged.contains("ada@example.com") && !logged.contains("Lovelace"), "{}", logged);
+
+        let (logged, truncated) = loggable(Some("application/json"), body, 10).unwrap();
+        assert!(truncated);
+        assert_eq!(logged.len(), 10);
+        let (logged, truncated) = loggable(Some("text/plain; charset=utf-8"), "héhé".as_bytes(), 2).unwrap();
+        assert_eq!((logged.as_str(), truncated), ("h", true));
+
+        assert_eq!(loggable(Some("application/octet-stream"), b"\x00\x01", 4096), None);
+        assert_eq!(loggable(Some("image/png"), b"\x89PNG", 4096), None);
+        assert_eq!(loggable(None, "\u{fffd}\u{fffd}".as_bytes(), 4096), None);
+    }
+}
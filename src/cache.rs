@@ -0,0 +1,333 @@
+use serde_json::Value;
+use sha2::{ Digest, Sha256 };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Mutex, OnceLock };
+use std::time::{ Duration, Instant };
+
+use crate::config::{ self, number_from_env };
+use crate::{ clock, tenant };
+
+mod memory;
+mod redis;
+
+use memory::MemoryStore;
+use redis::RedisStore;
+pub use redis::RedisConfig;
+
+const DEFAULT_MAX_ENTRIES: u64 = 10_000;
+
+static CACHE: OnceLock<Option<UserCache>> = OnceLock::new();
+
+// The responses of GET /users/{id} kept for USER_CACHE_TTL_MS, off unless it is
+// set. They are kept in memory, or in Redis when CACHE_URL is set, so that the
+// instances sharing it see each other's invalidations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheConfig {
+    pub ttl: Duration,
+    pub store: StoreConfig,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StoreConfig {
+    // At most USER_CACHE_MAX_ENTRIES, the least recently used go first. The writes
+    // of the other instances sharing a Postgres database invalidate them too, as
+    // their notifications are heard, see changed.
+    Memory { max_entries: usize },
+    Redis(RedisConfig),
+}
+
+impl CacheConfig {
+    // None when the cache is off
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let ttl = number_from_env("USER_CACHE_TTL_MS", 0)?;
+        let url = config::var("CACHE_URL").ok();
+        if ttl == 0 {
+            return match url {
+                Some(_) => Err("CACHE_URL needs USER_CACHE_TTL_MS".to_owned()),
+                None => Ok(None),
+            };
+        }
+        let store = match url {
+            Some(url) => StoreConfig::Redis(RedisConfig::from_env(&url)?),
+            None => {
+                let max_entries = number_from_env("USER_CACHE_MAX_ENTRIES", DEFAULT_MAX_ENTRIES)? as usize;
+                if max_entries == 0 {
+                    return Err("USER_CACHE_MAX_ENTRIES must be at least 1".to_owned());
+                }
+                StoreConfig::Memory { max_entries }
+            }
+        };
+        Ok(Some(CacheConfig { ttl: Duration::from_millis(ttl), store }))
+    }
+}
+
+pub fn init(config: Option<CacheConfig>) {
+    CACHE.set(config.map(UserCache::new)).ok();
+}
+
+pub fn cache() -> Option<&'static UserCache> {
+    CACHE.get_or_init(|| None).as_ref()
+}
+
+// A user was written, by this instance or another, as the notifications of the
+// outbox tell, the one without an id being any of them. Only the entries kept in
+// memory are dropped, those of Redis were by the instance that wrote.
+pub fn changed(tenant: &str, payload: &Value) {
+    if let Some(cache) = cache().filter(|cache| !cache.store.shared()) {
+        cache.changed(tenant, payload);
+    }
+}
+
+// Listening again, after the notifications sent meanwhile went unheard
+pub fn resubscribed() {
+    if let Some(cache) = cache().filter(|cache| !cache.store.shared()) {
+        cache.clear();
+    }
+}
+
+// What the ETag of a response is made of
+pub fn etag(body: &str) -> String {
+    format!("\"{:x}\"", Sha256::digest(body.as_bytes()))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cached {
+    pub body: String,
+    pub etag: String,
+}
+
+// Users by tenant and id
+type Key = (String, i32);
+
+// Where the entries are kept. A store that can't be reached behaves as an empty
+// one, the users are read from the repository meanwhile.
+trait Store: Send + Sync {
+    fn get(&self, key: &Key, now: Instant) -> Option<Cached>;
+
+    fn put(&self, key: Key, cached: Cached, ttl: Duration, now: Instant);
+
+    fn remove(&self, key: &Key);
+
+    fn clear(&self);
+
+    // None when only the store itself knows
+    fn size(&self) -> Option<usize>;
+
+    fn evictions(&self) -> Option<u64>;
+
+    // Whether the other instances see its entries, and their invalidations
+    fn shared(&self) -> bool {
+        false
+    }
+
+    // Whether it can be reached right now, for /health/details
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub struct UserCache {
+    ttl: Duration,
+    store: Box<dyn Store>,
+    // Bumped by every invalidation, so that a user read before one isn't stored
+    // after it. Not held while the store is written, which may be Redis: a user
+    // stored while it was bumped is removed again.
+    generation: Mutex<u64>,
+    // Since startup
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+}
+
+impl UserCache {
+    fn new(config: CacheConfig) -> Self {
+        let store: Box<dyn Store> = match config.store {
+            StoreConfig::Memory { max_entries } => Box::new(MemoryStore::new(max_entries)),
+            StoreConfig::Redis(redis) => Box::new(RedisStore::new(redis)),
+        };
+        UserCache::with_store(config.ttl, store)
+    }
+
+    fn with_store(ttl: Duration, store: Box<dyn Store>) -> Self {
+        UserCache { ttl, store, generation: Mutex::new(0), hits: AtomicU64::new(0), misses: AtomicU64::new(0) }
+    }
+
+    // The entries, when kept in memory
+    pub fn size(&self) -> Option<usize> {
+        self.store.size()
+    }
+
+    // The entries dropped to make room for others, when kept in memory
+    pub fn evictions(&self) -> Option<u64> {
+        self.store.evictions()
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        self.store.check()
+    }
+
+    // The share of the users answered from the cache, None before any was asked for
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let (hits, misses) = (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed));
+        (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64)
+    }
+
+    // The user of the tenant of the request, counted as a hit or a miss
+    pub fn get(&self, id: i32) -> Option<Cached> {
+        self.get_at((tenant::current(), id), clock::instant())
+    }
+
+    // To pass to put, read before the repository is asked
+    pub fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    pub fn put(&self, id: i32, cached: Cached, generation: u64) {
+        self.put_at((tenant::current(), id), cached, generation, clock::instant());
+    }
+
+    // After a write of the user succeeded
+    pub fn invalidate(&self, id: i32) {
+        self.invalidate_key(&(tenant::current(), id));
+    }
+
+    // After writes to every user
+    pub fn clear(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.store.clear();
+    }
+
+    fn changed(&self, tenant: &str, payload: &Value) {
+        match payload.get("id").and_then(Value::as_i64).and_then(|id| i32::try_from(id).ok()) {
+            Some(id) => self.invalidate_key(&(tenant.to_owned(), id)),
+            None => self.clear(),
+        }
+    }
+
+    fn invalidate_key(&self, key: &Key) {
+        *self.generation.lock().unwrap() += 1;
+        self.store.remove(key);
+    }
+
+    fn get_at(&self, key: Key, now: Instant) -> Option<Cached> {
+        let cached = self.store.get(&key, now);
+        let counter = if cached.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        cached
+    }
+
+    fn put_at(&self, key: Key, cached: Cached, generation: u64, now: Instant) {
+        if self.generation() != generation {
+            return;
+        }
+        self.store.put(key.clone(), cached, self.ttl, now);
+        // Invalidated while storing, maybe removed before it was stored
+        if self.generation() != generation {
+            self.store.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{ mpsc, Arc };
+    use std::thread;
+
+    fn key(id: i32) -> Key {
+        ("default".to_owned(), id)
+    }
+
+    fn cached(body: &str) -> Cached {
+        Cached { body: body.to_owned(), etag: etag(body) }
+    }
+
+    #[test]
+    fn users_read_before_an_invalidation_are_not_stored() {
+        let cache = UserCache::with_store(Duration::from_secs(10), Box::new(MemoryStore::new(10)));
+        let now = Instant::now();
+        let generation = cache.generation();
+        cache.invalidate(1);
+        cache.put_at(key(1), cached("stale"), generation, now);
+        assert_eq!(cache.get_at(key(1), now), None);
+
+        cache.put_at(key(1), cached("ada"), cache.generation(), now);
+        assert_eq!(cache.get_at(key(1), now), Some(cached("ada")));
+        cache.clear();
+        assert_eq!(cache.get_at(key(1), now), None);
+        assert_eq!((cache.hits.load(Ordering::Relaxed), cache.misses.load(Ordering::Relaxed)), (1, 2));
+    }
+
+    #[test]
+    fn the_users_of_the_notifications_are_dropped() {
+        let cache = UserCache::with_store(Duration::from_secs(10), Box::new(MemoryStore::new(10)));
+        let now = Instant::now();
+        for id in [1, 2] {
+            cache.put_at(key(id), cached("ada"), cache.generation(), now);
+        }
+        let generation = cache.generation();
+        cache.changed("default", &serde_json::json!({ "id": 1, "name": "Ada King" }));
+        assert_eq!(cache.get_at(key(1), now), None);
+        assert_eq!(cache.get_at(key(2), now), Some(cached("ada")));
+        // As an invalidation of this instance would
+        cache.put_at(key(1), cached("stale"), generation, now);
+        assert_eq!(cache.get_at(key(1), now), None);
+
+        cache.changed("other", &serde_json::json!({ "id": 2 }));
+        assert_eq!(cache.get_at(key(2), now), Some(cached("ada")));
+        cache.changed("default", &serde_json::json!({}));
+        assert_eq!(cache.get_at(key(2), now), None);
+    }
+
+    // Kept in memory once the test lets the put through, as a slow Redis would
+    struct Slow {
+        memory: MemoryStore,
+        putting: Mutex<mpsc::Sender<()>>,
+        stored: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl Store for Slow {
+        fn get(&self, key: &Key, now: Instant) -> Option<Cached> {
+            self.memory.get(key, now)
+        }
+
+        fn put(&self, key: Key, cached: Cached, ttl: Duration, now: Instant) {
+            self.putting.lock().unwrap().send(()).unwrap();
+            self.stored.lock().unwrap().recv().unwrap();
+            self.memory.put(key, cached, ttl, now);
+        }
+
+        fn remove(&self, key: &Key) {
+            self.memory.remove(key);
+        }
+
+        fn clear(&self) {
+            self.memory.clear();
+        }
+
+        fn size(&self) -> Option<usize> {
+            self.memory.size()
+        }
+
+        fn evictions(&self) -> Option<u64> {
+            self.memory.evictions()
+        }
+    }
+
+    #[test]
+    fn an_invalidation_while_storing_waits_for_nothing_and_wins() {
+        let ((putting, put), (store, stored)) = (mpsc::channel(), mpsc::channel());
+        let slow = Slow { memory: MemoryStore::new(10), putting: Mutex::new(putting), stored: Mutex::new(stored) };
+        let cache = Arc::new(UserCache::with_store(Duration::from_secs(10), Box::new(slow)));
+        let now = Instant::now();
+        let storing = {
+            let (cache, generation) = (cache.clone(), cache.generation());
+            thread::spawn(move || cache.put_at(key(1), cached("stale"), generation, now))
+        };
+
+        put.recv().unwrap();
+        cache.invalidate(1);
+        store.send(()).unwrap();
+        storing.join().unwrap();
+        assert_eq!(cache.get_at(key(1), now), None);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+use super::{ Cached, Key, Store };
+
+struct Entry {
+    cached: Cached,
+    expires: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<Key, Entry>,
+    // The keys by when they were last used, the least recently used first
+    by_use: BTreeMap<u64, Key>,
+    uses: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &Key) -> Option<Entry> {
+        let entry = self.by_key.remove(key)?;
+        self.by_use.remove(&entry.last_used);
+        Some(entry)
+    }
+}
+
+// The entries of this instance only, at most max_entries
+pub struct MemoryStore {
+    max_entries: usize,
+    entries: Mutex<Entries>,
+    evictions: AtomicU64,
+}
+
+impl MemoryStore {
+    pub fn new(max_entries: usize) -> Self {
+        MemoryStore { max_entries, entries: Mutex::new(Entries::default()), evictions: AtomicU64::new(0) }
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(&self, key: &Key, now: Instant) -> Option<Cached> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.by_key.get(key).map(|entry| entry.expires <= now) {
+            Some(false) => {
+                entries.uses += 1;
+                let uses = entries.uses;
+                let entry = entries.by_key.get_mut(key).unwrap();
+                let last_used = std::mem::replace(&mut entry.last_used, uses);
+                let cached = entry.cached.clone();
+                entries.by_use.remove(&last_used);
+                entries.by_use.insert(uses, key.clone());
+                Some(cached)
+            }
+            Some(true) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put(&self, key: Key, cached: Cached, ttl: Duration, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+        while entries.by_key.len() >= self.max_entries {
+            let Some((_, oldest)) = entries.by_use.pop_first() else {
+                break;
+            };
+            entries.by_key.remove(&oldest);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        entries.uses += 1;
+        let last_used = entries.uses;
+        entries.by_use.insert(last_used, key.clone());
+        entries.by_key.insert(key, Entry { cached, expires: now + ttl, last_used });
+    }
+
+    fn remove(&self, key: &Key) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    fn clear(&self) {
+        *self.entries.lock().unwrap() = Entries::default();
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.entries.lock().unwrap().by_key.len())
+    }
+
+    fn evictions(&self) -> Option<u64> {
+        Some(self.evictions.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::etag;
+
+    const TTL: Duration = Duration::from_secs(10);
+
+    fn key(id: i32) -> Key {
+        ("default".to_owned(), id)
+    }
+
+    fn cached(body: &str) -> Cached {
+        Cached { body: body.to_owned(), etag: etag(body) }
+    }
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let (store, now) = (MemoryStore::new(10), Instant::now());
+        assert_eq!(store.get(&key(1), now), None);
+        store.put(key(1), cached("ada"), TTL, now);
+        assert_eq!(store.get(&key(1), now + Duration::from_secs(9)), Some(cached("ada")));
+        assert_eq!(store.get(&key(1), now + TTL), None);
+        assert_eq!(store.size(), Some(0));
+    }
+
+    #[test]
+    fn the_least_recently_used_are_evicted() {
+        let (store, now) = (MemoryStore::new(2), Instant::now());
+        store.put(key(1), cached("ada"), TTL, now);
+        store.put(key(2), cached("alan"), TTL, now);
+        store.get(&key(1), now);
+        store.put(key(3), cached("grace"), TTL, now);
+
+        assert_eq!(store.get(&key(2), now), None);
+        assert!(store.get(&key(1), now).is_some());
+        assert!(store.get(&key(3), now).is_some());
+        assert_eq!(store.evictions(), Some(1));
+    }
+}
@@ -0,0 +1,444 @@
+use std::io::{ self, BufRead, BufReader, Write };
+use std::net::{ TcpStream, ToSocketAddrs };
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use super::{ etag, Cached, Key, Store };
+use crate::config::number_from_env;
+use crate::secret::Secret;
+
+const DEFAULT_PORT: u16 = 6379;
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(50);
+const DEFAULT_POOL_SIZE: u64 = 8;
+// How long the cache is skipped once Redis couldn't be reached
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+// The entries of every instance sharing the Redis, followed by tenant:id
+const KEY_PREFIX: &str = "rust-api:user:";
+// The deletes kept for later past which every entry is deleted instead
+const MAX_DEFERRED_KEYS: usize = 10_000;
+
+// CACHE_URL, redis://[[username]:password@]host[:port][/database]. Each command
+// waits CACHE_TIMEOUT_MS at most, and up to CACHE_POOL_SIZE connections are kept
+// open between them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RedisConfig {
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    pub database: u64,
+    pub timeout: Duration,
+    pub pool_size: usize,
+}
+
+impl RedisConfig {
+    pub fn from_env(url: &str) -> Result<Self, String> {
+        let mut config = RedisConfig::from_url(url)?;
+        let timeout = number_from_env("CACHE_TIMEOUT_MS", DEFAULT_TIMEOUT.as_millis() as u64)?;
+        config.timeout = Duration::from_millis(timeout);
+        config.pool_size = number_from_env("CACHE_POOL_SIZE", DEFAULT_POOL_SIZE)? as usize;
+        if config.timeout.is_zero() {
+            return Err("CACHE_TIMEOUT_MS must be at least 1".to_owned());
+        }
+        Ok(config)
+    }
+
+    fn from_url(url: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!("CACHE_URL must be redis://[[username]:password@]host[:port][/database], got {:?}", url)
+        };
+        let rest = url.strip_prefix("redis://").ok_or_else(invalid)?;
+        let (credentials, rest) = match rest.rsplit_once('@') {
+            Some((credentials, rest)) => (Some(credentials), rest),
+            None => (None, rest),
+        };
+        let (host, database) = match rest.split_once('/') {
+            Some((host, "")) => (host, 0),
+            Some((host, database)) => (host, database.parse().map_err(|_| invalid())?),
+            None => (rest, 0),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let address = match host.rsplit_once(':') {
+            Some((_, port)) if !host.ends_with(']') => {
+                port.parse::<u16>().map_err(|_| invalid())?;
+                host.to_owned()
+            }
+            _ => format!("{}:{}", host, DEFAULT_PORT),
+        };
+        let (username, password) = match credentials.map(|credentials| credentials.split_once(':')) {
+            None => (None, None),
+            Some(None) => (None, credentials.map(|password| Secret::new(password.to_owned()))),
+            Some(Some((username, password))) => {
+                let username = Some(username).filter(|name| !name.is_empty()).map(str::to_owned);
+                (username, Some(Secret::new(password.to_owned())))
+            }
+        };
+        Ok(RedisConfig {
+            address,
+            username,
+            password,
+            database,
+            timeout: DEFAULT_TIMEOUT,
+            pool_size: DEFAULT_POOL_SIZE as usize,
+        })
+    }
+}
+
+// The entries shared by the instances using the same Redis, which expires them.
+// The bodies are stored, the ETags are made again from them.
+pub struct RedisStore(Arc<Redis>);
+
+struct Redis {
+    config: RedisConfig,
+    idle: Mutex<Vec<Connection>>,
+    // Set when Redis couldn't be reached, the cache is skipped until then
+    down_until: Mutex<Option<Instant>>,
+    // The deletes Redis couldn't be sent, sent before any other command. The cache
+    // is skipped until they were, its entries being maybe stale.
+    deferred: Mutex<Deferred>,
+    // Held by the one sending them
+    flushing: Mutex<()>,
+}
+
+#[derive(Default)]
+struct Deferred {
+    keys: Vec<String>,
+    // Every entry, after a clear that failed
+    all: bool,
+    // Bumped by each delete deferred, for those deferred while sending to stay
+    version: u64,
+}
+
+impl Deferred {
+    fn is_empty(&self) -> bool {
+        self.keys.is_empty() && !self.all
+    }
+}
+
+impl RedisStore {
+    pub fn new(config: RedisConfig) -> Self {
+        RedisStore(Arc::new(Redis {
+            config,
+            idle: Mutex::new(Vec::new()),
+            down_until: Mutex::new(None),
+            deferred: Mutex::new(Deferred::default()),
+            flushing: Mutex::new(()),
+        }))
+    }
+
+    // Kept for when Redis is back, which a thread of its own waits for, so that
+    // the other instances don't read the entry even when this one is idle
+    fn defer(&self, add: impl FnOnce(&mut Deferred)) {
+        let mut deferred = self.0.deferred.lock().unwrap();
+        let retry = deferred.is_empty();
+        add(&mut deferred);
+        deferred.version += 1;
+        if deferred.keys.len() > MAX_DEFERRED_KEYS {
+            deferred.keys.clear();
+            deferred.all = true;
+        }
+        if retry {
+            let redis = self.0.clone();
+            thread::spawn(move || {
+                while !redis.flush() {
+                    thread::sleep(RETRY_INTERVAL);
+                }
+            });
+        }
+    }
+}
+
+impl Redis {
+    // None when Redis is down, failed to answer, or the deletes deferred are still
+    // to be sent
+    fn command(&self, args: &[&[u8]]) -> Option<Reply> {
+        match self.flush() {
+            true => self.send(args),
+            false => None,
+        }
+    }
+
+    fn send(&self, args: &[&[u8]]) -> Option<Reply> {
+        {
+            let mut down_until = self.down_until.lock().unwrap();
+            match *down_until {
+                Some(until) if Instant::now() < until => return None,
+                _ => *down_until = None,
+            }
+        }
+
+        let send = |mut connection: Connection| connection.command(args).map(|reply| (connection, reply));
+        let idle = self.idle.lock().unwrap().pop();
+        let result = match idle.map(send) {
+            Some(Ok(answered)) => Ok(answered),
+            // Redis may have closed the idle one meanwhile, a new one is tried before giving up
+            _ => Connection::open(&self.config).and_then(send),
+        };
+
+        match result {
+            Ok((connection, reply)) => {
+                let mut idle = self.idle.lock().unwrap();
+                if idle.len() < self.config.pool_size {
+                    idle.push(connection);
+                }
+                Some(reply)
+            }
+            Err(e) => {
+                log::warn!(
+                    "Redis cache unavailable, serving without it for the next {}s: {}",
+                    RETRY_INTERVAL.as_secs(),
+                    e
+                );
+                *self.down_until.lock().unwrap() = Some(Instant::now() + RETRY_INTERVAL);
+                self.idle.lock().unwrap().clear();
+                None
+            }
+        }
+    }
+
+    // Whether no delete is deferred anymore, sending them when some are. They stay
+    // deferred while being sent, for the entries not to be read meanwhile.
+    fn flush(&self) -> bool {
+        let (keys, all, version) = {
+            let deferred = self.deferred.lock().unwrap();
+            if deferred.is_empty() {
+                return true;
+            }
+            (deferred.keys.clone(), deferred.all, deferred.version)
+        };
+        let Ok(_flushing) = self.flushing.try_lock() else {
+            return false;
+        };
+        let sent = match all {
+            true => self.delete_all(),
+            false => {
+                let mut del: Vec<&[u8]> = vec![b"DEL"];
+                del.extend(keys.iter().map(String::as_bytes));
+                self.send(&del).is_some()
+            }
+        };
+        if !sent {
+            return false;
+        }
+        let mut deferred = self.deferred.lock().unwrap();
+        if deferred.version == version {
+            *deferred = Deferred { version, ..Deferred::default() };
+        } else if !all {
+            let sent = keys.len().min(deferred.keys.len());
+            deferred.keys.drain(..sent);
+        }
+        deferred.is_empty()
+    }
+
+    // Whether every entry was deleted
+    fn delete_all(&self) -> bool {
+        let pattern = format!("{}*", KEY_PREFIX);
+        let mut cursor = b"0".to_vec();
+        loop {
+            let reply = self.send(&[b"SCAN", &cursor, b"MATCH", pattern.as_bytes(), b"COUNT", b"1000"]);
+            let Some(Reply::Array(mut reply)) = reply else {
+                return false;
+            };
+            let (Some(Reply::Array(keys)), Some(Reply::Bulk(Some(next)))) = (reply.pop(), reply.pop()) else {
+                return false;
+            };
+            let mut del: Vec<&[u8]> = vec![b"DEL"];
+            del.extend(keys.iter().filter_map(|key| match key {
+                Reply::Bulk(Some(key)) => Some(key.as_slice()),
+                _ => None,
+            }));
+            if del.len() > 1 && self.send(&del).is_none() {
+                return false;
+            }
+            if next == b"0" {
+                return true;
+            }
+            cursor = next;
+        }
+    }
+}
+
+fn redis_key((tenant, id): &Key) -> String {
+    format!("{}{}:{}", KEY_PREFIX, tenant, id)
+}
+
+impl Store for RedisStore {
+    fn get(&self, key: &Key, _now: Instant) -> Option<Cached> {
+        let Reply::Bulk(Some(body)) = self.0.command(&[b"GET", redis_key(key).as_bytes()])? else {
+            return None;
+        };
+        let body = String::from_utf8(body).ok()?;
+        Some(Cached { etag: etag(&body), body })
+    }
+
+    fn put(&self, key: Key, cached: Cached, ttl: Duration, _now: Instant) {
+        let ttl = ttl.as_millis().to_string();
+        self.0.command(&[b"SET", redis_key(&key).as_bytes(), cached.body.as_bytes(), b"PX", ttl.as_bytes()]);
+    }
+
+    // Those that can't be sent now are sent once Redis is back
+    fn remove(&self, key: &Key) {
+        let key = redis_key(key);
+        if self.0.command(&[b"DEL", key.as_bytes()]).is_none() {
+            self.defer(|deferred| deferred.keys.push(key));
+        }
+    }
+
+    fn clear(&self) {
+        if !(self.0.flush() && self.0.delete_all()) {
+            self.defer(|deferred| deferred.all = true);
+        }
+    }
+
+    fn size(&self) -> Option<usize> {
+        None
+    }
+
+    fn evictions(&self) -> Option<u64> {
+        None
+    }
+
+    fn shared(&self) -> bool {
+        true
+    }
+
+    // On a connection of its own, the idle ones may be gone by now
+    fn check(&self) -> Result<(), String> {
+        let config = &self.0.config;
+        let reply = Connection::open(config).and_then(|mut connection| connection.command(&[b"PING"]));
+        match reply {
+            Ok(Reply::Simple(pong)) if pong == "PONG" => Ok(()),
+            Ok(reply) => Err(format!("{} answered {:?} to PING", config.address, reply)),
+            Err(e) => Err(format!("can't reach {}: {}", config.address, e)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+// A connection speaking the protocol of Redis, RESP, as far as the commands
+// above need
+struct Connection {
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn open(config: &RedisConfig) -> io::Result<Self> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", config.address));
+        for address in config.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, config.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(config.timeout))?;
+                    stream.set_write_timeout(Some(config.timeout))?;
+                    stream.set_nodelay(true)?;
+                    let mut connection = Connection { reader: BufReader::new(stream) };
+                    connection.set_up(config)?;
+                    return Ok(connection);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn set_up(&mut self, config: &RedisConfig) -> io::Result<()> {
+        match (&config.username, &config.password) {
+            (Some(username), Some(password)) => {
+                self.command(&[b"AUTH", username.as_bytes(), password.expose().as_bytes()])?;
+            }
+            (None, Some(password)) => {
+                self.command(&[b"AUTH", password.expose().as_bytes()])?;
+            }
+            _ => {}
+        }
+        if config.database != 0 {
+            self.command(&[b"SELECT", config.database.to_string().as_bytes()])?;
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, args: &[&[u8]]) -> io::Result<Reply> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg);
+            request.extend_from_slice(b"\r\n");
+        }
+        self.reader.get_mut().write_all(&request)?;
+        read_reply(&mut self.reader)
+    }
+}
+
+fn read_reply(reader: &mut impl BufRead) -> io::Result<Reply> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Redis closed the connection"));
+    }
+    let line = line.strip_suffix("\r\n").ok_or_else(|| invalid(format!("Unterminated reply {:?}", line)))?;
+    let (kind, value) = line.split_at_checked(1).ok_or_else(|| invalid("Empty reply".to_owned()))?;
+    let number = || value.parse::<i64>().map_err(|_| invalid(format!("Invalid reply {:?}", line)));
+    match kind {
+        "+" => Ok(Reply::Simple(value.to_owned())),
+        "-" => Err(io::Error::other(format!("Redis answered {}", value))),
+        ":" => Ok(Reply::Integer(number()?)),
+        "$" => match number()? {
+            length if length < 0 => Ok(Reply::Bulk(None)),
+            length => {
+                let mut data = vec![0; length as usize + 2];
+                reader.read_exact(&mut data)?;
+                data.truncate(length as usize);
+                Ok(Reply::Bulk(Some(data)))
+            }
+        },
+        "*" => match number()? {
+            length if length < 0 => Ok(Reply::Array(Vec::new())),
+            length => (0..length).map(|_| read_reply(reader)).collect::<io::Result<_>>().map(Reply::Array),
+        },
+        _ => Err(invalid(format!("Invalid reply {:?}", line))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_parsed() {
+        let config = RedisConfig::from_url("redis://cache.internal").unwrap();
+        assert_eq!((config.address.as_str(), config.database), ("cache.internal:6379", 0));
+        assert_eq!((config.username, config.password), (None, None));
+
+        let config = RedisConfig::from_url("redis://app:s3cr:et@10.0.0.5:6380/2").unwrap();
+        assert_eq!((config.address.as_str(), config.database), ("10.0.0.5:6380", 2));
+        let password = config.password.as_ref().map(|password| password.expose().as_str());
+        assert_eq!((config.username.as_deref(), password), (Some("app"), Some("s3cr:et")));
+        let config = RedisConfig::from_url("redis://:secret@[::1]/").unwrap();
+        assert_eq!((config.address.as_str(), config.username), ("[::1]:6379", None));
+        assert_eq!(config.password.as_ref().map(|password| password.expose().as_str()), Some("secret"));
+
+        assert!(RedisConfig::from_url("http://cache.internal").is_err());
+        assert!(RedisConfig::from_url("redis://cache.internal:port").is_err());
+        assert!(RedisConfig::from_url("redis:///1").is_err());
+    }
+
+    #[test]
+    fn replies_are_read() {
+        let mut replies = "+OK\r\n:3\r\n$5\r\nhe\r\no\r\n$-1\r\n*2\r\n$1\r\n0\r\n*0\r\n-ERR wrong\r\n".as_bytes();
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Simple("OK".to_owned()));
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Integer(3));
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Bulk(Some(b"he\r\no".to_vec())));
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Bulk(None));
+        let scan = Reply::Array(vec![Reply::Bulk(Some(b"0".to_vec())), Reply::Array(Vec::new())]);
+        assert_eq!(read_reply(&mut replies).unwrap(), scan);
+        assert!(read_reply(&mut replies).unwrap_err().to_string().contains("ERR wrong"));
+    }
+}
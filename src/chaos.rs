@@ -0,0 +1,325 @@
+use std::io::{ self, IoSlice, Write };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Mutex, RwLock };
+use std::thread;
+use std::time::Duration;
+
+use crate::admin::{ random_seed, SplitMix64 };
+use crate::config::{ self, number_from_env };
+use crate::http::{ get_body, BAD_REQUEST, OK_RESPONSE };
+use crate::locale;
+use crate::profile::Profile;
+use crate::repository::RepositoryError;
+
+const DEFAULT_DELAY_MS: u64 = 1000;
+const DEFAULT_ERROR_STATUS: u16 = 503;
+
+const ERROR_PROBLEM: &str =
+    "HTTP/1.1 500 INTERNAL SERVER ERROR\r\nContent-Type: application/problem+json\r\nX-Chaos-Fault: error\r\n\r\n";
+const UNAVAILABLE_PROBLEM: &str = "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Type: application/problem+json\r\n\
+    Retry-After: 1\r\nX-Chaos-Fault: error\r\n\r\n";
+
+// What RUST_LOG sets the level of the lines by
+const TARGET: &str = "chaos";
+
+const FAULTS: [Fault; 4] = [Fault::Delay, Fault::Error, Fault::DbError, Fault::Disconnect];
+
+static STATE: RwLock<State> = RwLock::new(State { available: false, chaos: Chaos::OFF });
+static RNG: Mutex<SplitMix64> = Mutex::new(SplitMix64(0));
+// Since startup, by fault
+static INJECTED: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+// The faults injected for the clients to test their retries against, each in a
+// share of the requests, from 0 for none, the default, to 1 for all of them: a
+// delay before the handler runs, CHAOS_DELAY_RATE of CHAOS_DELAY_MS, a 500 or a
+// 503 instead of it, CHAOS_ERROR_RATE of CHAOS_ERROR_STATUS, the repository
+// failing as if the database couldn't be reached, CHAOS_DB_ERROR_RATE of its
+// operations, and a response cut off halfway with the connection closed,
+// CHAOS_DISCONNECT_RATE. The probes, the metrics and the switch aren't touched.
+// POST /admin/chaos changes them at runtime, and CHAOS_SEED makes the same
+// requests get the same faults.
+// Only with the dev and test profiles: the settings are refused in prod, and the
+// switch answers 404 there. The faults are logged under the chaos target and
+// counted in chaos_faults_injected_total, and the repository's are injected in
+// front of the circuit breaker, which doesn't count them, nor the permits.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Chaos {
+    pub delay_rate: f64,
+    pub delay_ms: u64,
+    pub error_rate: f64,
+    pub error_status: u16,
+    pub db_error_rate: f64,
+    pub disconnect_rate: f64,
+}
+
+impl Chaos {
+    const OFF: Chaos = Chaos {
+        delay_rate: 0.0,
+        delay_ms: DEFAULT_DELAY_MS,
+        error_rate: 0.0,
+        error_status: DEFAULT_ERROR_STATUS,
+        db_error_rate: 0.0,
+        disconnect_rate: 0.0,
+    };
+
+    fn enabled(&self) -> bool {
+        [self.delay_rate, self.error_rate, self.db_error_rate, self.disconnect_rate].iter().any(|rate| *rate > 0.0)
+    }
+
+    fn check(self) -> Result<Self, String> {
+        let rates = [
+            ("delay_rate", self.delay_rate),
+            ("error_rate", self.error_rate),
+            ("db_error_rate", self.db_error_rate),
+            ("disconnect_rate", self.disconnect_rate),
+        ];
+        if let Some((name, rate)) = rates.iter().find(|(_, rate)| !(0.0..=1.0).contains(rate)) {
+            return Err(format!("{} must be a number between 0 and 1, got {}", name, rate));
+        }
+        if !matches!(self.error_status, 500 | 503) {
+            return Err(format!("error_status must be 500 or 503, got {}", self.error_status));
+        }
+        Ok(self)
+    }
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Chaos::OFF
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChaosConfig {
+    pub initial: Chaos,
+    // Whether the profile lets it be turned on
+    pub available: bool,
+    pub seed: Option<u64>,
+}
+
+impl ChaosConfig {
+    pub fn from_env(profile: Profile) -> Result<Self, String> {
+        let initial = Chaos {
+            delay_rate: rate_from_env("CHAOS_DELAY_RATE")?,
+            delay_ms: number_from_env("CHAOS_DELAY_MS", DEFAULT_DELAY_MS)?,
+            error_rate: rate_from_env("CHAOS_ERROR_RATE")?,
+            error_status: match number_from_env("CHAOS_ERROR_STATUS", DEFAULT_ERROR_STATUS as u64)? {
+                status @ (500 | 503) => status as u16,
+                status => return Err(format!("CHAOS_ERROR_STATUS must be 500 or 503, got {}", status)),
+            },
+            db_error_rate: rate_from_env("CHAOS_DB_ERROR_RATE")?,
+            disconnect_rate: rate_from_env("CHAOS_DISCONNECT_RATE")?,
+        };
+        let available = profile != Profile::Prod;
+        if initial.enabled() && !available {
+            return Err(format!("faults are only injected with the dev and test profiles, not {}", profile.name()));
+        }
+        let seed = match config::var("CHAOS_SEED") {
+            Ok(_) => Some(number_from_env("CHAOS_SEED", 0)?),
+            Err(_) => None,
+        };
+        Ok(ChaosConfig { initial, available, seed })
+    }
+}
+
+fn rate_from_env(name: &str) -> Result<f64, String> {
+    match config::var(name) {
+        Ok(rate) => match rate.trim().parse::<f64>() {
+            Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+            _ => Err(format!("{} must be a number between 0 and 1, got {:?}", name, rate.trim())),
+        },
+        Err(_) => Ok(0.0),
+    }
+}
+
+struct State {
+    available: bool,
+    chaos: Chaos,
+}
+
+pub fn init(config: ChaosConfig) {
+    *STATE.write().unwrap() = State { available: config.available, chaos: config.initial };
+    *RNG.lock().unwrap() = SplitMix64(config.seed.unwrap_or_else(random_seed));
+    if config.initial.enabled() {
+        log::warn!(target: TARGET, "Injecting faults: {:?}", config.initial);
+    }
+}
+
+// Whether POST /admin/chaos answers, with the dev and test profiles
+pub fn available() -> bool {
+    STATE.read().unwrap().available
+}
+
+fn current() -> Chaos {
+    STATE.read().unwrap().chaos
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Fault {
+    Delay,
+    Error,
+    DbError,
+    Disconnect,
+}
+
+impl Fault {
+    fn name(self) -> &'static str {
+        match self {
+            Fault::Delay => "delay",
+            Fault::Error => "error",
+            Fault::DbError => "db_error",
+            Fault::Disconnect => "disconnect",
+        }
+    }
+}
+
+// Whether to inject the fault, rate being its share, counting it when it is
+fn roll(fault: Fault, rate: f64) -> bool {
+    let injected = match rate {
+        rate if rate <= 0.0 => false,
+        rate if rate >= 1.0 => true,
+        // The 53 bits of a double
+        rate => ((RNG.lock().unwrap().next() >> 11) as f64 / (1u64 << 53) as f64) < rate,
+    };
+    if injected {
+        INJECTED[fault as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    injected
+}
+
+fn exempt(segments: &[&str]) -> bool {
+    matches!(segments, ["health", ..] | ["livez"] | ["readyz"] | ["metrics"] | ["admin", "chaos"])
+}
+
+// Before the handler of a request runs: the delay, if it is one of those
+// delayed, then the response it gets instead, if it is one of those failed
+pub fn before_handler(method: &str, segments: &[&str]) -> Option<(String, String)> {
+    let chaos = current();
+    if !chaos.enabled() || exempt(segments) {
+        return None;
+    }
+    let path = format!("/{}", segments.join("/"));
+    if roll(Fault::Delay, chaos.delay_rate) {
+        log::warn!(target: TARGET, fault = "delay"; "Delaying {} {} by {}ms", method, path, chaos.delay_ms);
+        thread::sleep(Duration::from_millis(chaos.delay_ms));
+    }
+    if !roll(Fault::Error, chaos.error_rate) {
+        return None;
+    }
+    log::warn!(target: TARGET, fault = "error"; "Answering {} {} with a {}", method, path, chaos.error_status);
+    let (status_line, title) = match chaos.error_status {
+        500 => (ERROR_PROBLEM, "Internal Server Error"),
+        _ => (UNAVAILABLE_PROBLEM, "Service Unavailable"),
+    };
+    let body = serde_json::json!({
+        "type": "about:blank",
+        "title": locale::title(chaos.error_status, title),
+        "status": chaos.error_status,
+        "code": "chaos_fault",
+        "detail": "Injected by the chaos mode",
+    });
+    Some((status_line.to_owned(), body.to_string()))
+}
+
+// Before an operation of the repository, the error it fails with when it is one
+// of those failed
+pub fn repository_fault(operation: &str) -> Option<RepositoryError> {
+    let chaos = current();
+    if !roll(Fault::DbError, chaos.db_error_rate) {
+        return None;
+    }
+    log::warn!(target: TARGET, fault = "db_error"; "Failing the {} of the repository", operation);
+    Some(RepositoryError::Unavailable("Connection refused, injected by the chaos mode".into()))
+}
+
+// Whether the response to the request is cut off, see CutOff
+pub fn disconnects(method: &str, segments: &[&str]) -> bool {
+    let chaos = current();
+    if exempt(segments) || !roll(Fault::Disconnect, chaos.disconnect_rate) {
+        return false;
+    }
+    log::warn!(target: TARGET, fault = "disconnect"; "Cutting off the response to {} /{}", method, segments.join("/"));
+    true
+}
+
+// A stream that takes the first half of what is written to it at once, the head
+// and body of a response, then fails as a connection reset by the server would,
+// the connection being closed after
+pub struct CutOff<'a, W: Write>(pub &'a mut W);
+
+impl<W: Write> Write for CutOff<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let bytes: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        self.0.write_all(&bytes[..bytes.len() / 2])?;
+        self.0.flush()?;
+        Err(io::Error::new(io::ErrorKind::ConnectionAborted, "cut off by the chaos mode"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// For /metrics: the faults injected since startup, by name
+pub fn injected() -> Vec<(&'static str, u64)> {
+    FAULTS.iter().map(|fault| (fault.name(), INJECTED[*fault as usize].load(Ordering::Relaxed))).collect()
+}
+
+// GET /admin/chaos
+pub fn handle_get_chaos_request() -> (String, String) {
+    (OK_RESPONSE.to_owned(), serde_json::to_string(&current()).unwrap())
+}
+
+// POST /admin/chaos, with a Chaos, its fields left out being off or their default
+pub fn handle_set_chaos_request(request: &str) -> (String, String) {
+    let chaos = match serde_json::from_str::<Chaos>(get_body(request)).map_err(|e| e.to_string()) {
+        Ok(chaos) => chaos.check(),
+        Err(e) => Err(e),
+    };
+    let chaos = match chaos {
+        Ok(chaos) => chaos,
+        Err(e) => return (BAD_REQUEST.to_owned(), format!("Invalid request body: {}", e)),
+    };
+    STATE.write().unwrap().chaos = chaos;
+    log::warn!(target: TARGET, "Chaos mode set to {:?}", chaos);
+    handle_get_chaos_request()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_rates_are_those_of_the_share_of_the_faults() {
+        *RNG.lock().unwrap() = SplitMix64(7);
+        let before = INJECTED[Fault::Delay as usize].load(Ordering::Relaxed);
+        let injected = (0..10_000).filter(|_| roll(Fault::Delay, 0.25)).count();
+        assert!((2300..2700).contains(&injected), "{}", injected);
+        assert!((0..100).all(|_| roll(Fault::Delay, 1.0)) && !(0..100).any(|_| roll(Fault::Delay, 0.0)));
+        assert!(INJECTED[Fault::Delay as usize].load(Ordering::Relaxed) - before >= injected as u64 + 100);
+    }
+
+    #[test]
+    fn a_response_is_cut_off_halfway() {
+        let mut written = Vec::new();
+        let response = b"HTTP/1.1 200 OK\r\n\r\n{\"id\":1}";
+        let error = CutOff(&mut written).write_all(response).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(written, &response[..response.len() / 2]);
+    }
+
+    #[test]
+    fn the_chaos_set_is_checked() {
+        let chaos: Chaos = serde_json::from_str(r#"{"error_rate": 1}"#).unwrap();
+        assert_eq!(chaos, Chaos { error_rate: 1.0, ..Chaos::OFF });
+        assert!(chaos.check().is_ok());
+        assert!(Chaos { delay_rate: 1.5, ..Chaos::OFF }.check().is_err());
+        assert!(Chaos { error_status: 502, ..Chaos::OFF }.check().is_err());
+        assert!(serde_json::from_str::<Chaos>(r#"{"eror_rate": 1}"#).is_err());
+    }
+}
@@ -0,0 +1,875 @@
+use log::LevelFilter;
+use postgres::Client;
+use serde_json::json;
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::auth::{ Role, Scope };
+use crate::client::{ ClientAction, ClientCommand };
+use crate::config::Config;
+use crate::migrations::{ self, RollbackTarget };
+use crate::pool::{ self, Pool, RetryConfig };
+use crate::repository::postgres::PostgresRepository;
+use crate::repository::sqlite::SqliteRepository;
+use crate::repository::UserRepository;
+use crate::schema::{ self, Strictness };
+use crate::tls::{ Connector, TlsMode };
+use crate::errors::with_causes;
+use crate::{ admin, api_keys, backup, client, encryption, fixtures, tables, tenant };
+
+// What the process exits with, for the scripts running it: 0 when it did what it
+// was asked, EXIT_USAGE for arguments it doesn't know, EXIT_CONFIG for settings
+// that are missing or invalid, EXIT_DEPENDENCY when the database, or whatever
+// else it needs, failed it, and EXIT_REFUSED when the API a client command
+// called turned the request down.
+pub const EXIT_USAGE: i32 = 1;
+pub const EXIT_CONFIG: i32 = 2;
+pub const EXIT_DEPENDENCY: i32 = 3;
+pub const EXIT_REFUSED: i32 = 4;
+
+pub const USAGE: &str = "\
+usage: rust_postgresql_tutorial [--config FILE] [--log-level LEVEL] [COMMAND]
+
+  serve [--seed-file FILE] [--seed-on-conflict skip|upsert] [--seed-strict]
+      Serve the API, the command when there is none
+  migrate [--dry-run] [--to VERSION]
+      Apply the pending migrations, those up to VERSION with --to
+  migrate down [--steps N | --to VERSION]
+      Roll back the latest migration, the N latest, or those after VERSION
+  seed [--file FILE] [--count N]
+      Insert the users of a seed file, and N generated ones
+  check [--json] [--write-probe] [--timeout SECS]
+      Check the settings, that the database answers and its schema is the expected one
+  version
+      Print the version
+  backup | restore FILE [--force]
+      Back the database up to BACKUP_DIR, or restore it
+  rotate-encryption-key
+      Encrypt the emails with NEW_ENCRYPTION_KEY
+  api-keys create NAME [--role reader|writer|admin] [users:read|users:write|users:delete|admin...]
+      Mint an API key
+  client users list | get ID | create --name NAME --email EMAIL | delete ID
+         [--base-url URL] [--api-key KEY] [--json]
+      Call a running API, at CLIENT_BASE_URL with CLIENT_API_KEY unless given
+
+--config FILE, or CONFIG_FILE, is a TOML file of settings, under those of the
+environment. --log-level LEVEL, one of off, error, warn, info, debug or trace, is
+the level of the targets that RUST_LOG doesn't set one for.";
+
+const MIGRATE_USAGE: &str = "usage: migrate [--dry-run] [--to VERSION] | migrate down [--steps N | --to VERSION]";
+const SEED_USAGE: &str = "usage: seed [--file FILE] [--count N], with a file, a count or both";
+const SERVE_USAGE: &str = "usage: serve [--seed-file FILE] [--seed-on-conflict skip|upsert] [--seed-strict]";
+const BACKUP_USAGE: &str = "usage: backup | restore FILE [--force]";
+const CHECK_USAGE: &str = "usage: check [--json] [--write-probe] [--timeout SECS]";
+const API_KEYS_USAGE: &str =
+    "usage: api-keys create NAME [--role reader|writer|admin] [users:read|users:write|users:delete|admin...]";
+const CLIENT_USAGE: &str = "usage: client users list | get ID | create --name NAME --email EMAIL | delete ID \
+    [--base-url URL] [--api-key KEY] [--json]";
+
+// The most users `seed --count` generates at once
+const MAX_SEED_COUNT: u32 = 100_000;
+// How long `check` waits for the database, unless --timeout says otherwise
+const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq)]
+pub struct Cli {
+    pub config: Option<String>,
+    pub log_level: Option<LevelFilter>,
+    pub command: Command,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // With the options of the server, which are those of the whole command line
+    // when it has no command, as before there were others
+    Serve(Vec<String>),
+    Migrate { dry_run: bool, to: Option<i64> },
+    RollBack(RollbackTarget),
+    Seed { file: Option<String>, count: Option<u32> },
+    Check(CheckOptions),
+    Version,
+    Help,
+    Backup,
+    Restore { file: String, force: bool },
+    RotateEncryptionKey,
+    ApiKeys { name: String, role: Role, scopes: Vec<Scope> },
+    // Run before the settings are read, it needs none of them
+    Client(ClientCommand),
+}
+
+// With --json the report is one JSON object rather than lines, with --write-probe
+// writes are tried too, and --timeout is how long the database has to answer
+#[derive(Debug, PartialEq)]
+pub struct CheckOptions {
+    pub json: bool,
+    pub write_probe: bool,
+    pub timeout: Duration,
+}
+
+// The arguments after the name of the program. The error is the usage to print.
+pub fn parse(args: &[String]) -> Result<Cli, String> {
+    // --config and --log-level go anywhere, before the command or among its options
+    let (mut config, mut log_level, mut rest) = (None, None, Vec::new());
+    let mut args = args.iter().map(String::as_str);
+    while let Some(arg) = args.next() {
+        match arg {
+            "--config" => config = Some(args.next().ok_or(USAGE)?.to_owned()),
+            "--log-level" => {
+                let level = args.next().ok_or(USAGE)?;
+                let parsed = level.parse::<LevelFilter>().map_err(|_| {
+                    format!("--log-level must be off, error, warn, info, debug or trace, got {:?}", level)
+                })?;
+                log_level = Some(parsed);
+            }
+            _ => rest.push(arg),
+        }
+    }
+
+    let command = match rest.as_slice() {
+        [] => Command::Serve(Vec::new()),
+        ["serve", options @ ..] => Command::Serve(server_options(options)?),
+        [option, ..] if option.starts_with("--") && !["--help", "-h"].contains(option) => {
+            Command::Serve(server_options(&rest)?)
+        }
+        ["migrate", options @ ..] => migrate(options)?,
+        ["seed", options @ ..] => seed(options)?,
+        ["check", options @ ..] => check_options(options)?,
+        ["version"] => Command::Version,
+        ["help"] | ["--help"] | ["-h"] => Command::Help,
+        ["backup"] => Command::Backup,
+        ["restore", file] => Command::Restore { file: file.to_string(), force: false },
+        ["restore", file, "--force"] => Command::Restore { file: file.to_string(), force: true },
+        ["backup" | "restore", ..] => return Err(BACKUP_USAGE.to_owned()),
+        ["rotate-encryption-key"] => Command::RotateEncryptionKey,
+        ["rotate-encryption-key", ..] => {
+            return Err("usage: rotate-encryption-key, with the new key in NEW_ENCRYPTION_KEY".to_owned());
+        }
+        ["api-keys", options @ ..] => api_keys(options)?,
+        ["client", options @ ..] => client(options)?,
+        [command, ..] if ["version", "help"].contains(command) => {
+            return Err(format!("usage: {}, without arguments", command));
+        }
+        [command, ..] => return Err(format!("unknown command {:?}\n\n{}", command, USAGE)),
+    };
+    Ok(Cli { config, log_level, command })
+}
+
+// Those SeedConfig::from_args reads, only checked for being known here
+fn server_options(options: &[&str]) -> Result<Vec<String>, String> {
+    let mut rest = options.iter();
+    while let Some(option) = rest.next() {
+        match *option {
+            "--seed-file" | "--seed-on-conflict" => {
+                rest.next().ok_or(SERVE_USAGE)?;
+            }
+            "--seed-strict" => {}
+            _ => return Err(SERVE_USAGE.to_owned()),
+        }
+    }
+    Ok(options.iter().map(|option| option.to_string()).collect())
+}
+
+fn migrate(options: &[&str]) -> Result<Command, String> {
+    let number = |value: Option<&&str>| value.and_then(|value| value.parse::<u64>().ok()).ok_or(MIGRATE_USAGE);
+    match options {
+        ["down"] => return Ok(Command::RollBack(RollbackTarget::Steps(1))),
+        ["down", "--steps", steps] => {
+            return Ok(Command::RollBack(RollbackTarget::Steps(number(Some(steps))? as usize)));
+        }
+        ["down", "--to", version] => {
+            return Ok(Command::RollBack(RollbackTarget::Version(number(Some(version))? as i64)));
+        }
+        ["down", ..] => return Err(MIGRATE_USAGE.to_owned()),
+        _ => {}
+    }
+    let (mut dry_run, mut to) = (false, None);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--dry-run" => dry_run = true,
+            "--to" => to = Some(number(options.next())? as i64),
+            _ => return Err(MIGRATE_USAGE.to_owned()),
+        }
+    }
+    Ok(Command::Migrate { dry_run, to })
+}
+
+fn check_options(options: &[&str]) -> Result<Command, String> {
+    let mut check = CheckOptions { json: false, write_probe: false, timeout: DEFAULT_CHECK_TIMEOUT };
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--json" => check.json = true,
+            "--write-probe" => check.write_probe = true,
+            "--timeout" => {
+                let seconds = options.next().and_then(|seconds| seconds.parse::<u64>().ok());
+                check.timeout = Duration::from_secs(seconds.filter(|seconds| *seconds > 0).ok_or(CHECK_USAGE)?);
+            }
+            _ => return Err(CHECK_USAGE.to_owned()),
+        }
+    }
+    Ok(Command::Check(check))
+}
+
+fn seed(options: &[&str]) -> Result<Command, String> {
+    let (mut file, mut count) = (None, None);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--file" => file = Some(options.next().ok_or(SEED_USAGE)?.to_string()),
+            "--count" => {
+                let parsed = options.next().and_then(|count| count.parse::<u32>().ok()).ok_or(SEED_USAGE)?;
+                if parsed > MAX_SEED_COUNT {
+                    return Err(format!("--count must be at most {}", MAX_SEED_COUNT));
+                }
+                count = Some(parsed);
+            }
+            _ => return Err(SEED_USAGE.to_owned()),
+        }
+    }
+    if file.is_none() && count.is_none() {
+        return Err(SEED_USAGE.to_owned());
+    }
+    Ok(Command::Seed { file, count })
+}
+
+// A writer's key unless --role says otherwise, --role admin for the first key of
+// an API without root keys
+fn api_keys(options: &[&str]) -> Result<Command, String> {
+    let (name, rest) = match options {
+        ["create", name, rest @ ..] if !name.trim().is_empty() => (name.trim(), rest),
+        _ => return Err(API_KEYS_USAGE.to_owned()),
+    };
+    let (role, scopes) = match rest {
+        ["--role", role, scopes @ ..] => {
+            (role.parse::<Role>().map_err(|e| format!("{}, {}", e, API_KEYS_USAGE))?, scopes)
+        }
+        ["--role"] => return Err(API_KEYS_USAGE.to_owned()),
+        scopes => (Role::Writer, scopes),
+    };
+    let scopes = scopes
+        .iter()
+        .map(|scope| scope.parse::<Scope>().map_err(|e| format!("{}, {}", e, API_KEYS_USAGE)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Command::ApiKeys { name: name.to_owned(), role, scopes })
+}
+
+// The options go anywhere after `client users`
+fn client(options: &[&str]) -> Result<Command, String> {
+    let ["users", options @ ..] = options else {
+        return Err(CLIENT_USAGE.to_owned());
+    };
+    let (mut base_url, mut api_key, mut name, mut email) = (None, None, None, None);
+    let (mut json, mut rest) = (false, Vec::new());
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let mut value = || options.next().map(|value| value.to_string()).ok_or(CLIENT_USAGE);
+        match *option {
+            "--base-url" => base_url = Some(value()?),
+            "--api-key" => api_key = Some(value()?),
+            "--name" => name = Some(value()?),
+            "--email" => email = Some(value()?),
+            "--json" => json = true,
+            _ => rest.push(*option),
+        }
+    }
+    let id = |id: &str| id.parse::<i32>().map_err(|_| format!("the id must be a number, got {:?}", id));
+    let action = match (rest.as_slice(), name, email) {
+        (["list"], None, None) => ClientAction::List,
+        (["get", user], None, None) => ClientAction::Get(id(user)?),
+        (["delete", user], None, None) => ClientAction::Delete(id(user)?),
+        (["create"], Some(name), Some(email)) => ClientAction::Create { name, email },
+        _ => return Err(CLIENT_USAGE.to_owned()),
+    };
+    Ok(Command::Client(ClientCommand { action, base_url, api_key, json }))
+}
+
+pub fn version() -> String {
+    format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+// Run a command other than serve, which main runs itself, and return the code to
+// exit with. What it prints goes to out, what went wrong is logged.
+pub fn run(config: &Config, command: Command, out: &mut dyn Write) -> i32 {
+    let failed = match &command {
+        Command::Serve(_) => unreachable!("main serves the API itself"),
+        Command::Version => {
+            writeln!(out, "{}", version()).ok();
+            return 0;
+        }
+        Command::Help => {
+            writeln!(out, "{}", USAGE).ok();
+            return 0;
+        }
+        Command::Check(options) => return check(config, options, out),
+        Command::Client(command) => return client::run(command, out),
+        Command::Migrate { .. } | Command::RollBack(_) => "Migration failed",
+        Command::Seed { .. } => "Seeding failed",
+        Command::Backup => "The backup failed",
+        Command::Restore { .. } => "The restore failed",
+        Command::RotateEncryptionKey => "The rotation failed",
+        Command::ApiKeys { .. } => "Minting the API key failed",
+    };
+    let config_error = |error: &str| {
+        log::error!("{}: {}", failed, error);
+        EXIT_CONFIG
+    };
+
+    // The other commands are for Postgres
+    if !config.postgres {
+        return match command {
+            Command::Migrate { .. } | Command::RollBack(_) => {
+                config_error("migrations are for Postgres, the other backends create their own schema")
+            }
+            _ => config_error("only available when DATABASE_URL is a Postgres database"),
+        };
+    }
+    let connector = match Connector::from_credentials(config.database.clone()) {
+        Ok(connector) => connector,
+        Err(e) => return config_error(&e.to_string()),
+    };
+    let result = match command {
+        Command::Migrate { dry_run, to } => run_migrate(config, &connector, dry_run, to, out),
+        Command::RollBack(target) => run_roll_back(config, &connector, target, out),
+        Command::Seed { file, count } => {
+            // SEED_ON_CONFLICT and SEED_STRICT apply to the file as to that of serve
+            let args = file.map(|file| ["--seed-file".to_owned(), file]);
+            let seed = match args.map(|args| fixtures::SeedConfig::from_args(&config.seed, &args)) {
+                None => None,
+                Some(Ok(seed)) => seed,
+                Some(Err(e)) => return config_error(&e),
+            };
+            run_seed(config, &connector, seed, count, out)
+        }
+        Command::Backup => run_backup(config, &connector, out),
+        Command::Restore { file, force } => run_restore(config, &connector, &file, force, out),
+        Command::RotateEncryptionKey => {
+            match &config.new_encryption_key {
+                Some(new) => run_rotate(config, &connector, new, out),
+                None => return config_error("NEW_ENCRYPTION_KEY is not set"),
+            }
+        }
+        Command::ApiKeys { name, role, scopes } => run_api_keys(config, &connector, &name, role, &scopes, out),
+        Command::Serve(_) | Command::Version | Command::Help | Command::Check(_) | Command::Client(_) => {
+            unreachable!("answered above")
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            log::error!("{}: {}", failed, with_causes(&*e));
+            EXIT_DEPENDENCY
+        }
+    }
+}
+
+// The settings were read and checked already, by main, then the database must
+// answer within the timeout, over TLS when the settings ask for it, with every
+// migration applied and the expected schema, and with --write-probe take writes,
+// which are tried and rolled back. Nothing is changed and the port isn't bound.
+// Prints the report, and exits with EXIT_CONFIG or EXIT_DEPENDENCY when a step
+// failed.
+fn check(config: &Config, options: &CheckOptions, out: &mut dyn Write) -> i32 {
+    let mut report = Report::default();
+    // Serving would refuse those settings
+    if let Some(e) = config.refused_to_serve() {
+        report.push("config", Status::Failed, Some(e));
+        report.print(EXIT_CONFIG, options.json, out);
+        return EXIT_CONFIG;
+    }
+    report.push("config", Status::Ok, None);
+    let code = check_database(config, options, &mut report);
+    report.print(code, options.json, out);
+    code
+}
+
+// The report of check --json when the settings aren't valid, which main finds
+// before any command runs
+pub fn report_invalid_config(errors: &[String], json: bool, out: &mut dyn Write) {
+    let mut report = Report::default();
+    report.push("config", Status::Failed, Some(errors.join("; ")));
+    report.print(EXIT_CONFIG, json, out);
+}
+
+fn check_database(config: &Config, options: &CheckOptions, report: &mut Report) -> i32 {
+    if !config.postgres {
+        let url = config.database_url.expose();
+        let opened = match SqliteRepository::handles(url) {
+            true => SqliteRepository::open(url).map_err(|e| e.to_string()).and_then(|repository| {
+                repository.ping().map_err(|e| e.to_string())
+            }),
+            false => Ok(()),
+        };
+        if let Err(e) = opened {
+            report.push("database", Status::Failed, Some(e));
+            return EXIT_DEPENDENCY;
+        }
+        report.push("database", Status::Ok, None);
+        if options.write_probe {
+            report.push("write", Status::Skipped, Some("only probed on Postgres".to_owned()));
+        }
+        return 0;
+    }
+
+    // Retried within the timeout, each attempt and query given up after it too
+    let budget = config.retry.budget.min(options.timeout);
+    let retry = RetryConfig { budget, ..config.retry };
+    let connected = Connector::from_credentials(config.database.clone())
+        .map_err(|e| (EXIT_CONFIG, e.to_string()))
+        .and_then(|connector| {
+            let connector = connector.with_connect_timeout(options.timeout);
+            let failed = |e: postgres::Error| (EXIT_DEPENDENCY, with_causes(&e));
+            let mut client = pool::connect_with_retry(&connector, &retry).map_err(failed)?;
+            let timeout = format!("SET statement_timeout = {}", options.timeout.as_millis());
+            client.batch_execute(&timeout).map_err(failed)?;
+            Ok((connector, client))
+        });
+    let (connector, mut client) = match connected {
+        Ok(connected) => connected,
+        Err((code, e)) => {
+            report.push("database", Status::Failed, Some(e));
+            return code;
+        }
+    };
+    report.push("database", Status::Ok, None);
+
+    let tls = connector.tls_settings();
+    let mut settings = format!("sslmode {}", tls.mode.name());
+    if let Some(root_cert) = &tls.root_cert {
+        settings.push_str(&format!(", root certificate {}", root_cert));
+    }
+    match encrypted(&mut client) {
+        Ok(_) if tls.mode == TlsMode::Disable => report.push("tls", Status::Skipped, Some(settings)),
+        Ok(true) => report.push("tls", Status::Ok, Some(format!("encrypted, {}", settings))),
+        // Only with prefer, which takes a server without TLS
+        Ok(false) => report.push("tls", Status::Ok, Some(format!("not encrypted, {}", settings))),
+        Err(e) => {
+            report.push("tls", Status::Failed, Some(with_causes(&e)));
+            return EXIT_DEPENDENCY;
+        }
+    }
+
+    let pending = match migrations::pending(&mut client) {
+        Ok(pending) => pending,
+        Err(e) => {
+            report.push("migrations", Status::Failed, Some(with_causes(&*e)));
+            return EXIT_DEPENDENCY;
+        }
+    };
+    if matches!(config.migrations, migrations::Mode::Ignore) {
+        report.push("migrations", Status::Skipped, Some("MIGRATIONS_MODE is ignore".to_owned()));
+    } else if !pending.is_empty() {
+        let names: Vec<String> = pending.iter().map(|migration| migration.to_string()).collect();
+        // Serving applies them first, the schema is the one they make then
+        if matches!(config.migrations, migrations::Mode::Apply) {
+            report.push("migrations", Status::Ok, Some(format!("applied when serving: {}", names.join(", "))));
+            report.push("schema", Status::Skipped, Some("before the migrations are applied".to_owned()));
+            if options.write_probe {
+                report.push("write", Status::Skipped, Some("before the migrations are applied".to_owned()));
+            }
+            return 0;
+        }
+        report.push("migrations", Status::Failed, Some(format!("waiting to be applied: {}", names.join(", "))));
+        return EXIT_DEPENDENCY;
+    } else {
+        report.push("migrations", Status::Ok, None);
+    }
+
+    // The differences SCHEMA_CHECK=warn would only log fail the check
+    let strictness = match config.schema_check {
+        Strictness::Off => Strictness::Off,
+        _ => Strictness::Strict,
+    };
+    if let Err(e) = schema::check(&mut client, &strictness) {
+        report.push("schema", Status::Failed, Some(with_causes(&*e)));
+        return EXIT_DEPENDENCY;
+    }
+    report.push("schema", Status::Ok, None);
+
+    if options.write_probe {
+        if let Err(e) = probe_writes(&mut client) {
+            report.push("write", Status::Failed, Some(with_causes(&e)));
+            return EXIT_DEPENDENCY;
+        }
+        report.push("write", Status::Ok, Some("rolled back".to_owned()));
+    }
+    0
+}
+
+// Whether the connection of the check went over TLS
+fn encrypted(client: &mut Client) -> Result<bool, postgres::Error> {
+    let row = client.query_opt("SELECT ssl FROM pg_stat_ssl WHERE pid = pg_backend_pid()", &[])?;
+    Ok(row.is_some_and(|row| row.get(0)))
+}
+
+// Statements changing no row still need the rights to write and a server that
+// takes writes, not a standby nor a read-only transaction. Rolled back anyway.
+fn probe_writes(client: &mut Client) -> Result<(), postgres::Error> {
+    let mut transaction = client.transaction()?;
+    transaction.batch_execute(tables::sql(
+        "INSERT INTO {users} SELECT * FROM {users} WHERE false;
+        UPDATE {users} SET id = id WHERE false;
+        DELETE FROM {users} WHERE false"
+    ))?;
+    transaction.rollback()
+}
+
+#[derive(Clone, Copy)]
+enum Status {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+// What check found, step after step, printed as lines of name: status, details,
+// or with --json as an object of the status, the code exited with and the steps
+#[derive(Default)]
+struct Report {
+    steps: Vec<(&'static str, Status, Option<String>)>,
+}
+
+impl Report {
+    fn push(&mut self, name: &'static str, status: Status, detail: Option<String>) {
+        self.steps.push((name, status, detail));
+    }
+
+    fn print(&self, code: i32, json: bool, out: &mut dyn Write) {
+        if json {
+            let steps: Vec<serde_json::Value> = self
+                .steps
+                .iter()
+                .map(|(name, status, detail)| {
+                    let status = match status {
+                        Status::Ok => "ok",
+                        Status::Failed => "failed",
+                        Status::Skipped => "skipped",
+                    };
+                    json!({ "name": name, "status": status, "detail": detail })
+                })
+                .collect();
+            let status = if code == 0 { "ok" } else { "failed" };
+            writeln!(out, "{}", json!({ "status": status, "exit_code": code, "checks": steps })).ok();
+            return;
+        }
+        for (name, status, detail) in &self.steps {
+            let status = match status {
+                Status::Ok => "ok",
+                Status::Failed => "failed",
+                Status::Skipped => "not checked",
+            };
+            match detail {
+                Some(detail) => writeln!(out, "{}: {}, {}", name, status, detail).ok(),
+                None => writeln!(out, "{}: {}", name, status).ok(),
+            };
+        }
+    }
+}
+
+fn run_migrate(
+    config: &Config,
+    connector: &Connector,
+    dry_run: bool,
+    to: Option<i64>,
+    out: &mut dyn Write
+) -> Result<(), Box<dyn Error>> {
+    let mut client = pool::connect_with_retry(connector, &config.retry)?;
+    if let Some(to) = to {
+        let head = migrations::all()?.last().map_or(0, |migration| migration.version);
+        let applied = migrations::schema_version(&mut client)?;
+        if to > head {
+            return Err(format!("migration {:04} is unknown to this version, the latest is {:04}", to, head).into());
+        }
+        if to < applied {
+            let rolling_back = format!("migrate down --to {} rolls back to it", to);
+            return Err(format!("version {} is applied already, {}", applied, rolling_back).into());
+        }
+    }
+    let to = to.unwrap_or(i64::MAX);
+
+    if dry_run {
+        let mut pending = migrations::pending(&mut client)?;
+        pending.retain(|migration| migration.version <= to);
+        if pending.is_empty() {
+            writeln!(out, "No pending migrations")?;
+        }
+        for migration in &pending {
+            writeln!(out, "Would apply migration {}", migration)?;
+        }
+        return Ok(());
+    }
+
+    if migrations::apply_up_to(&mut client, to)?.is_empty() {
+        writeln!(out, "The database schema is up to date")?;
+    }
+    tenant::migrate_all_up_to(&mut client, to)
+}
+
+fn run_roll_back(
+    config: &Config,
+    connector: &Connector,
+    target: RollbackTarget,
+    out: &mut dyn Write
+) -> Result<(), Box<dyn Error>> {
+    let mut client = pool::connect_with_retry(connector, &config.retry)?;
+    if migrations::roll_back(&mut client, target)?.is_empty() {
+        writeln!(out, "Nothing to roll back")?;
+    }
+    Ok(())
+}
+
+// The file first, then the generated users, through the same repository as POST
+// /admin/seed and with a random seed like it
+fn run_seed(
+    config: &Config,
+    connector: &Connector,
+    seed: Option<fixtures::SeedConfig>,
+    count: Option<u32>,
+    out: &mut dyn Write
+) -> Result<(), Box<dyn Error>> {
+    if let Some(seed) = seed {
+        let mut client = pool::connect_with_retry(connector, &config.retry)?;
+        let summary = fixtures::seed(&mut client, &seed)?;
+        writeln!(out, "Seeded the database from {}: {}", seed.path, summary)?;
+    }
+    if let Some(count) = count {
+        // The command exits once done, the pool with it
+        let pool: &'static Pool = Box::leak(Box::new(Pool::new(connector.clone(), config.pool.clone())?));
+        let ids = PostgresRepository::new(pool, None).seed(admin::generated_users(count, admin::random_seed()))?;
+        writeln!(out, "Inserted {} generated users of {}", ids.len(), count)?;
+    }
+    Ok(())
+}
+
+fn run_backup(config: &Config, connector: &Connector, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let mut client = pool::connect_with_retry(connector, &config.retry)?;
+    writeln!(out, "Backed up to {}", backup::backup(&mut client, &backup::backup_dir())?)?;
+    Ok(())
+}
+
+fn run_restore(
+    config: &Config,
+    connector: &Connector,
+    file: &str,
+    force: bool,
+    out: &mut dyn Write
+) -> Result<(), Box<dyn Error>> {
+    let mut client = pool::connect_with_retry(connector, &config.retry)?;
+    writeln!(out, "Restored {}", backup::restore(&mut client, Path::new(file), force)?)?;
+    Ok(())
+}
+
+// With the current key in ENCRYPTION_KEY, if the emails are encrypted already
+fn run_rotate(
+    config: &Config,
+    connector: &Connector,
+    new: &encryption::Key,
+    out: &mut dyn Write
+) -> Result<(), Box<dyn Error>> {
+    let mut client = pool::connect_with_retry(connector, &config.retry)?;
+    let rotated = encryption::rotate(&mut client, config.encryption_key.as_ref(), new)?;
+    writeln!(out, "Encrypted {} emails with the new key, start the server with it as ENCRYPTION_KEY", rotated)?;
+    Ok(())
+}
+
+// Printing the key, which isn't stored
+fn run_api_keys(
+    config: &Config,
+    connector: &Connector,
+    name: &str,
+    role: Role,
+    scopes: &[Scope],
+    out: &mut dyn Write
+) -> Result<(), Box<dyn Error>> {
+    let mut client = pool::connect_with_retry(connector, &config.retry)?;
+    let minted = api_keys::mint(&mut client, name, role, scopes)?;
+    writeln!(out, "Minted API key {} named {}, a {}, it is shown only this once:", minted["id"], name, role)?;
+    writeln!(out, "{}", minted["key"].as_str().unwrap())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn parsed(args: &[&str]) -> Result<Cli, String> {
+        parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    fn command(args: &[&str]) -> Command {
+        parsed(args).unwrap().command
+    }
+
+    fn check(json: bool, write_probe: bool, timeout: Duration) -> Command {
+        Command::Check(CheckOptions { json, write_probe, timeout })
+    }
+
+    // What the command printed, and its exit code
+    fn ran(vars: &[(&str, &str)], args: &[&str]) -> (i32, String) {
+        let vars = vars.iter().map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = Config::from_vars(vars).unwrap();
+        let mut out = Vec::new();
+        let code = run(&config, command(args), &mut out);
+        (code, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn the_server_is_the_default_command() {
+        assert_eq!(command(&[]), Command::Serve(Vec::new()));
+        let options = ["--seed-file", "users.json", "--seed-strict"];
+        let expected = Command::Serve(options.iter().map(|option| option.to_string()).collect());
+        assert_eq!(command(&options), expected);
+        assert_eq!(command(&["serve", "--seed-file", "users.json", "--seed-strict"]), expected);
+        assert_eq!(parsed(&["serve", "--seed-file"]), Err(SERVE_USAGE.to_owned()));
+        assert_eq!(parsed(&["--bogus"]), Err(SERVE_USAGE.to_owned()));
+    }
+
+    #[test]
+    fn each_command_is_parsed() {
+        assert_eq!(command(&["migrate"]), Command::Migrate { dry_run: false, to: None });
+        assert_eq!(command(&["migrate", "--to", "3", "--dry-run"]), Command::Migrate { dry_run: true, to: Some(3) });
+        assert_eq!(command(&["migrate", "down"]), Command::RollBack(RollbackTarget::Steps(1)));
+        assert_eq!(command(&["migrate", "down", "--to", "2"]), Command::RollBack(RollbackTarget::Version(2)));
+        assert_eq!(
+            command(&["seed", "--file", "users.json", "--count", "5"]),
+            Command::Seed { file: Some("users.json".to_owned()), count: Some(5) }
+        );
+        assert_eq!(command(&["check"]), check(false, false, DEFAULT_CHECK_TIMEOUT));
+        let options = ["check", "--timeout", "3", "--json", "--write-probe"];
+        assert_eq!(command(&options), check(true, true, Duration::from_secs(3)));
+        assert_eq!(command(&["version"]), Command::Version);
+        assert_eq!(command(&["restore", "backup.json", "--force"]), Command::Restore {
+            file: "backup.json".to_owned(),
+            force: true,
+        });
+        assert_eq!(command(&["api-keys", "create", "ci", "--role", "admin", "admin"]), Command::ApiKeys {
+            name: "ci".to_owned(),
+            role: Role::Admin,
+            scopes: vec![Scope::Admin],
+        });
+        let options = ["client", "users", "create", "--json", "--name", "Ada", "--email", "ada@example.com"];
+        assert_eq!(command(&options), Command::Client(ClientCommand {
+            action: ClientAction::Create { name: "Ada".to_owned(), email: "ada@example.com".to_owned() },
+            base_url: None,
+            api_key: None,
+            json: true,
+        }));
+        let options = ["client", "users", "--base-url", "http://api:8080", "get", "5"];
+        let Command::Client(client) = command(&options) else { panic!("not a client command") };
+        assert_eq!((client.action, client.base_url.as_deref()), (ClientAction::Get(5), Some("http://api:8080")));
+    }
+
+    #[test]
+    fn the_log_level_goes_anywhere() {
+        let cli = parsed(&["migrate", "--log-level", "debug", "--dry-run"]).unwrap();
+        assert_eq!(cli, Cli { config: None, log_level: Some(LevelFilter::Debug), command: Command::Migrate {
+            dry_run: true,
+            to: None,
+        } });
+        let cli = parsed(&["--config", "api.toml", "check"]).unwrap();
+        let expected = (Some("api.toml"), check(false, false, DEFAULT_CHECK_TIMEOUT));
+        assert_eq!((cli.config.as_deref(), cli.command), expected);
+        assert_eq!(parsed(&["--log-level", "warn"]).unwrap().log_level, Some(LevelFilter::Warn));
+        assert!(parsed(&["--log-level", "loud", "check"]).unwrap_err().contains("got \"loud\""));
+        assert_eq!(parsed(&["check", "--log-level"]), Err(USAGE.to_owned()));
+    }
+
+    #[test]
+    fn invalid_arguments_are_refused_with_their_usage() {
+        assert!(parsed(&["frobnicate"]).unwrap_err().starts_with("unknown command \"frobnicate\"\n\nusage:"));
+        assert_eq!(parsed(&["migrate", "--to", "three"]), Err(MIGRATE_USAGE.to_owned()));
+        assert_eq!(parsed(&["migrate", "down", "--steps"]), Err(MIGRATE_USAGE.to_owned()));
+        assert_eq!(parsed(&["seed"]), Err(SEED_USAGE.to_owned()));
+        assert_eq!(parsed(&["seed", "--count", "1000000"]), Err("--count must be at most 100000".to_owned()));
+        assert_eq!(parsed(&["check", "--now"]), Err(CHECK_USAGE.to_owned()));
+        assert_eq!(parsed(&["check", "--timeout", "0"]), Err(CHECK_USAGE.to_owned()));
+        assert_eq!(parsed(&["version", "--now"]), Err("usage: version, without arguments".to_owned()));
+        assert_eq!(parsed(&["restore"]), Err(BACKUP_USAGE.to_owned()));
+        assert!(parsed(&["api-keys", "create", "ci", "--role", "root"]).unwrap_err().ends_with(API_KEYS_USAGE));
+        assert_eq!(parsed(&["client", "users", "create", "--name", "Ada"]), Err(CLIENT_USAGE.to_owned()));
+        let not_a_number = Err("the id must be a number, got \"five\"".to_owned());
+        assert_eq!(parsed(&["client", "users", "get", "five"]), not_a_number);
+        assert_eq!(parsed(&["client", "groups", "list"]), Err(CLIENT_USAGE.to_owned()));
+    }
+
+    #[test]
+    fn version_prints_the_name_and_version() {
+        let (code, out) = ran(&[("DATABASE_URL", "memory://")], &["version"]);
+        assert_eq!((code, out.as_str()), (0, "rust_postgresql_tutorial 0.1.0\n"));
+    }
+
+    #[test]
+    fn check_passes_in_memory_and_fails_on_a_database_it_cant_open() {
+        let (code, out) = ran(&[("DATABASE_URL", "memory://")], &["check"]);
+        assert_eq!((code, out.as_str()), (0, "config: ok\ndatabase: ok\n"));
+
+        let (code, out) = ran(&[("DATABASE_URL", "sqlite:///nonexistent/dir/api.db")], &["check"]);
+        assert_eq!(code, EXIT_DEPENDENCY);
+        assert!(out.starts_with("config: ok\ndatabase: failed, "), "{}", out);
+    }
+
+    #[test]
+    fn check_fails_on_a_database_that_doesnt_answer() {
+        let vars = [("DATABASE_URL", "postgres://postgres@127.0.0.1:1/api"), ("DB_CONNECT_MAX_ATTEMPTS", "1")];
+        let (code, out) = ran(&vars, &["check"]);
+        assert_eq!(code, EXIT_DEPENDENCY);
+        assert!(out.starts_with("config: ok\ndatabase: failed, "), "{}", out);
+        assert!(!out.contains("migrations"), "{}", out);
+
+        let (code, out) = ran(&vars, &["check", "--json"]);
+        let report: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!((code, &report["status"], &report["exit_code"]), (EXIT_DEPENDENCY, &json!("failed"), &json!(3)));
+        assert_eq!(report["checks"][0], json!({ "name": "config", "status": "ok", "detail": null }));
+        assert_eq!(report["checks"][1]["status"], "failed");
+        assert_eq!(report["checks"].as_array().unwrap().len(), 2, "{}", report);
+    }
+
+    #[test]
+    fn an_invalid_config_is_reported_too() {
+        let (mut text, mut json) = (Vec::new(), Vec::new());
+        report_invalid_config(&["DATABASE_URL is not set".to_owned()], false, &mut text);
+        report_invalid_config(&["DATABASE_URL is not set".to_owned()], true, &mut json);
+        assert_eq!(String::from_utf8(text).unwrap(), "config: failed, DATABASE_URL is not set\n");
+        let report: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(report["exit_code"], EXIT_CONFIG);
+        let failed = json!({ "name": "config", "status": "failed", "detail": "DATABASE_URL is not set" });
+        assert_eq!(report["checks"], json!([failed]));
+    }
+
+    #[test]
+    fn the_database_commands_are_for_postgres() {
+        for args in [&["migrate"][..], &["seed", "--count", "3"], &["backup"], &["rotate-encryption-key"]] {
+            assert_eq!(ran(&[("DATABASE_URL", "memory://")], args), (EXIT_CONFIG, String::new()), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn migrate_check_and_seed_on_postgres() {
+        let Ok(url) = env::var("TEST_DATABASE_URL") else {
+            eprintln!("TEST_DATABASE_URL is not set, skipping");
+            return;
+        };
+        let vars = [("DATABASE_URL", url.as_str())];
+        let (code, out) = ran(&vars, &["migrate"]);
+        assert_eq!(code, 0, "{}", out);
+        let (code, out) = ran(&vars, &["migrate", "--dry-run"]);
+        assert_eq!((code, out.as_str()), (0, "No pending migrations\n"));
+        // Behind the schema, --to doesn't roll back
+        assert_eq!(ran(&vars, &["migrate", "--to", "1"]).0, EXIT_DEPENDENCY);
+
+        let (code, out) = ran(&vars, &["check", "--write-probe"]);
+        assert_eq!(code, 0, "{}", out);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[..2], ["config: ok", "database: ok"]);
+        assert!(lines[2].starts_with("tls: ") && lines[2].ends_with(", sslmode prefer"), "{}", out);
+        assert_eq!(lines[3..], ["migrations: ok", "schema: ok", "write: ok, rolled back"]);
+
+        let (code, out) = ran(&vars, &["seed", "--count", "3"]);
+        assert_eq!(code, 0, "{}", out);
+        assert!(out.starts_with("Inserted ") && out.ends_with(" generated users of 3\n"), "{}", out);
+        let (code, _) = ran(&vars, &["seed", "--file", "/nonexistent/users.json"]);
+        assert_eq!(code, EXIT_DEPENDENCY);
+    }
+}
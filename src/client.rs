@@ -0,0 +1,376 @@
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fmt;
+use std::io::Write;
+
+use crate::cli::{ EXIT_CONFIG, EXIT_DEPENDENCY, EXIT_REFUSED };
+use crate::secret::Secret;
+use crate::validation::NewUser;
+use crate::models::User;
+use crate::{ config, json_case, oidc };
+
+const DEFAULT_BASE_URL: &str = "http://localhost:8080";
+
+// What `client users` does, against the API at --base-url, or else at
+// CLIENT_BASE_URL, with the key of --api-key, or else of CLIENT_API_KEY
+#[derive(Debug, PartialEq)]
+pub struct ClientCommand {
+    pub action: ClientAction,
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    // The users as the API answers them, rather than a table
+    pub json: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ClientAction {
+    List,
+    Get(i32),
+    Create { name: String, email: String },
+    Delete(i32),
+}
+
+// Why a call failed: the API couldn't be reached or answered what isn't a
+// response of it, or it refused the request with what it said. The user isn't
+// there, is in the way of another or isn't valid, or else the API refused it for
+// another reason, its status says which.
+#[derive(Debug)]
+pub enum ClientError {
+    Unreachable(String),
+    NotFound(Problem),
+    Conflict(Problem),
+    Invalid(Problem),
+    Refused(Problem),
+}
+
+// What the API answered a request it refused: its status, the message of its
+// body, and the body itself, the problem or the errors of the fields, Null when it
+// isn't JSON
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub status: u16,
+    pub message: String,
+    pub body: Value,
+}
+
+impl Problem {
+    // The fields the API found invalid, or in the way of another user
+    pub fn fields(&self) -> Vec<&str> {
+        let errors = self.body["errors"].as_array().into_iter().flatten();
+        errors.filter_map(|error| error["field"].as_str()).collect()
+    }
+}
+
+impl ClientError {
+    fn of(status: u16, body: &str) -> Self {
+        let problem = Problem {
+            status,
+            message: message(body),
+            body: serde_json::from_str(body).unwrap_or(Value::Null),
+        };
+        match status {
+            404 => ClientError::NotFound(problem),
+            409 => ClientError::Conflict(problem),
+            422 => ClientError::Invalid(problem),
+            _ => ClientError::Refused(problem),
+        }
+    }
+
+    // What the API answered, None when it couldn't be reached
+    pub fn problem(&self) -> Option<&Problem> {
+        match self {
+            ClientError::Unreachable(_) => None,
+            ClientError::NotFound(problem)
+            | ClientError::Conflict(problem)
+            | ClientError::Invalid(problem)
+            | ClientError::Refused(problem) => Some(problem),
+        }
+    }
+
+    // EXIT_REFUSED for the requests the API turned down, as a 409 or a 422,
+    // EXIT_DEPENDENCY when the API failed
+    pub fn exit_code(&self) -> i32 {
+        match self.problem() {
+            Some(Problem { status: 400..=499, .. }) => EXIT_REFUSED,
+            _ => EXIT_DEPENDENCY,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Unreachable(e) => write!(f, "{}", e),
+            ClientError::NotFound(problem)
+            | ClientError::Conflict(problem)
+            | ClientError::Invalid(problem)
+            | ClientError::Refused(problem) => write!(f, "The API answered {}: {}", problem.status, problem.message),
+        }
+    }
+}
+
+// How the client authenticates: with an API key, in X-Api-Key, with a token of
+// POST /login, as a bearer, or not at all
+#[derive(Clone, Debug, PartialEq)]
+pub enum Auth {
+    None,
+    ApiKey(String),
+    Bearer(String),
+}
+
+// What GET /users lists: at most limit users, or else as many as the API's
+// PAGE_SIZE_DEFAULT, after the user of after_id, and only those of the email or
+// whose name has name_contains
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ListParams {
+    pub limit: Option<usize>,
+    pub after_id: Option<i32>,
+    pub email: Option<String>,
+    pub name_contains: Option<String>,
+}
+
+impl ListParams {
+    fn path(&self) -> String {
+        let (limit, after_id) = (self.limit.map(|limit| limit.to_string()), self.after_id.map(|id| id.to_string()));
+        let params = [
+            ("limit", limit.as_deref()),
+            ("after_id", after_id.as_deref()),
+            ("email", self.email.as_deref()),
+            ("name_contains", self.name_contains.as_deref()),
+        ];
+        let params: Vec<(&str, &str)> = params.into_iter().filter_map(|(name, value)| Some((name, value?))).collect();
+        match params.is_empty() {
+            true => "/users".to_owned(),
+            false => format!("/users?{}", oidc::form(&params)),
+        }
+    }
+}
+
+// The users of a running API, over the HTTP client of the crate: an http or an
+// https base URL, the API under its path if it has one
+pub struct Client {
+    base_url: String,
+    // The header of the auth, with the credentials in it
+    authorization: Option<Secret<String>>,
+}
+
+impl Client {
+    pub fn new(base_url: &str, auth: Auth) -> Result<Self, String> {
+        oidc::parse_url(base_url).map_err(|e| format!("The base URL {}", e))?;
+        let authorization = match auth {
+            Auth::None => None,
+            Auth::ApiKey(key) => Some(format!("X-Api-Key: {}\r\n", key)),
+            Auth::Bearer(token) => Some(format!("Authorization: Bearer {}\r\n", token)),
+        };
+        if authorization.as_ref().is_some_and(|header| header.trim_end().contains(['\r', '\n'])) {
+            return Err("The credentials must be on a single line".to_owned());
+        }
+        let base_url = base_url.trim_end_matches('/').to_owned();
+        Ok(Client { base_url, authorization: authorization.map(Secret::new) })
+    }
+
+    // Those of the command, or else of the environment
+    pub fn of(command: &ClientCommand) -> Result<Self, String> {
+        let base_url = command.base_url.clone().or_else(|| config::var("CLIENT_BASE_URL").ok());
+        let api_key = command.api_key.clone().or_else(|| config::var("CLIENT_API_KEY").ok());
+        let auth = api_key.filter(|key| !key.is_empty()).map_or(Auth::None, Auth::ApiKey);
+        Client::new(base_url.as_deref().unwrap_or(DEFAULT_BASE_URL), auth)
+    }
+
+    // All of them, a page at a time
+    pub fn list(&self) -> Result<Vec<User>, ClientError> {
+        let pages = self.pages(ListParams::default()).collect::<Result<Vec<_>, _>>()?;
+        Ok(pages.into_iter().flatten().collect())
+    }
+
+    // One page of them
+    pub fn list_page(&self, params: &ListParams) -> Result<Vec<User>, ClientError> {
+        self.call("GET", &params.path(), "")
+    }
+
+    // The pages of those of params, each after the last user of the one before,
+    // until one has none of those
+    pub fn pages(&self, params: ListParams) -> Pages<'_> {
+        Pages { client: self, params: Some(params) }
+    }
+
+    pub fn get(&self, id: i32) -> Result<User, ClientError> {
+        self.call("GET", &format!("/users/{}", id), "")
+    }
+
+    pub fn create(&self, user: &NewUser) -> Result<User, ClientError> {
+        self.call("POST", "/users", &serde_json::to_string(user).unwrap())
+    }
+
+    pub fn update(&self, id: i32, user: &NewUser) -> Result<User, ClientError> {
+        self.call("PUT", &format!("/users/{}", id), &serde_json::to_string(user).unwrap())
+    }
+
+    pub fn delete(&self, id: i32) -> Result<(), ClientError> {
+        self.call::<Value>("DELETE", &format!("/users/{}", id), "").map(|_| ())
+    }
+
+    fn call<T: DeserializeOwned>(&self, method: &str, path: &str, body: &str) -> Result<T, ClientError> {
+        let mut headers = String::new();
+        if let Some(authorization) = &self.authorization {
+            headers.push_str(authorization.expose());
+        }
+        if !body.is_empty() {
+            headers.push_str("Content-Type: application/json\r\n");
+        }
+        let url = format!("{}{}", self.base_url, path);
+        let (status, body) = oidc::fetch(method, &url, &headers, body).map_err(ClientError::Unreachable)?;
+        if !(200..300).contains(&status) {
+            return Err(ClientError::of(status, &body));
+        }
+        let not_json = |e| ClientError::Unreachable(format!("{} {} answered what isn't JSON: {}", method, url, e));
+        // The same whichever JSON_CASE the API has
+        let mut value: Value = serde_json::from_str(&body).map_err(not_json)?;
+        json_case::snake_case_keys(&mut value);
+        serde_json::from_value(value).map_err(not_json)
+    }
+}
+
+// The pages of Client::pages, None once the last one was answered or a call failed
+pub struct Pages<'a> {
+    client: &'a Client,
+    params: Option<ListParams>,
+}
+
+impl Iterator for Pages<'_> {
+    type Item = Result<Vec<User>, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let params = self.params.take()?;
+        let page = match self.client.list_page(&params) {
+            Ok(page) => page,
+            Err(e) => return Some(Err(e)),
+        };
+        // An API that doesn't page would answer the same page again
+        let last = page.last().and_then(|user| user.id);
+        if page.first().is_none_or(|first| first.id <= params.after_id) {
+            return None;
+        }
+        self.params = Some(ListParams { after_id: last, ..params });
+        Some(Ok(page))
+    }
+}
+
+// What the API said of a request it refused, from the errors of the fields, the
+// error of its JSON, the detail of a problem, or else its text
+fn message(body: &str) -> String {
+    let Ok(value) = serde_json::from_str::<Value>(body) else {
+        return match body.trim() {
+            "" => "no details".to_owned(),
+            text => text.to_owned(),
+        };
+    };
+    if let Some(errors) = value["errors"].as_array() {
+        let field = |error: &Value| match (error["field"].as_str(), error["message"].as_str()) {
+            (Some(field), Some(message)) => format!("{}: {}", field, message),
+            _ => error.to_string(),
+        };
+        return errors.iter().map(field).collect::<Vec<_>>().join("; ");
+    }
+    let text = value["error"]["message"].as_str().or(value["detail"].as_str()).or(value["title"].as_str());
+    text.map_or_else(|| value.to_string(), str::to_owned)
+}
+
+// Run the command and return the code to exit with. The users go to out, as a
+// table or as JSON, and what went wrong to stderr.
+pub fn run(command: &ClientCommand, out: &mut dyn Write) -> i32 {
+    let client = match Client::of(command) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_CONFIG;
+        }
+    };
+    let printed = match &command.action {
+        ClientAction::List => client.list().map(|users| print_users(out, &users, command.json, true)),
+        ClientAction::Get(id) => client.get(*id).map(|user| print_users(out, &[user], command.json, false)),
+        ClientAction::Create { name, email } => {
+            let user = NewUser { name: name.clone(), email: email.clone(), password: None, password_hash: None };
+            client.create(&user).map(|user| print_users(out, &[user], command.json, false))
+        }
+        ClientAction::Delete(id) => client.delete(*id).map(|_| match command.json {
+            true => writeln!(out, "{}", serde_json::json!({ "id": id, "deleted": true })).ok(),
+            false => writeln!(out, "Deleted user {}", id).ok(),
+        }),
+    };
+    match printed {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            e.exit_code()
+        }
+    }
+}
+
+// As a table of their id, name and email, or as JSON: an array for a list, the
+// user itself otherwise
+fn print_users(out: &mut dyn Write, users: &[User], json: bool, list: bool) -> Option<()> {
+    if json {
+        let json = if list { serde_json::to_string(users) } else { serde_json::to_string(&users[0]) };
+        return writeln!(out, "{}", json.unwrap()).ok();
+    }
+    let rows: Vec<[String; 3]> = users
+        .iter()
+        .map(|user| [user.id.map_or_else(String::new, |id| id.to_string()), user.name.clone(), user.email.clone()])
+        .collect();
+    let header = ["ID".to_owned(), "NAME".to_owned(), "EMAIL".to_owned()];
+    let mut widths = [0; 3];
+    for row in [&header].into_iter().chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in [&header].into_iter().chain(&rows) {
+        let line = format!("{:<w0$}  {:<w1$}  {}", row[0], row[1], row[2], w0 = widths[0], w1 = widths[1]);
+        writeln!(out, "{}", line.trim_end()).ok()?;
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_message_is_what_the_api_said() {
+        let taken = r#"{"errors":[{"field":"email","code":"taken","message":"is already taken"}]}"#;
+        assert_eq!(message(taken), "email: is already taken");
+        assert_eq!(message(r#"{"error":{"code":"overloaded","message":"Too busy"}}"#), "Too busy");
+        assert_eq!(message(r#"{"title":"Unauthorized","detail":"Missing API key"}"#), "Missing API key");
+        assert_eq!(message("User with ID 5 not found\n"), "User with ID 5 not found");
+        assert_eq!(message(""), "no details");
+    }
+
+    #[test]
+    fn the_params_are_the_query_of_the_list() {
+        assert_eq!(ListParams::default().path(), "/users");
+        let params = ListParams {
+            limit: Some(10),
+            after_id: Some(20),
+            email: None,
+            name_contains: Some("Ada L".to_owned()),
+        };
+        assert_eq!(params.path(), "/users?limit=10&after_id=20&name_contains=Ada%20L");
+    }
+
+    #[test]
+    fn the_users_are_a_table_of_aligned_columns() {
+        let users = [
+            User::new(Some(1), "Ada".to_owned(), "ada@example.com".to_owned(), false),
+            User::new(Some(12), "Grace Hopper".to_owned(), "grace@example.com".to_owned(), false),
+        ];
+        let mut out = Vec::new();
+        print_users(&mut out, &users, false, true);
+        let expected = "\
+ID  NAME          EMAIL
+1   Ada           ada@example.com
+12  Grace Hopper  grace@example.com
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
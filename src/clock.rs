@@ -0,0 +1,113 @@
+use chrono::{ DateTime, Utc };
+use std::sync::{ Arc, Mutex, RwLock };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+// The time as the app reads it: when the tokens expire, the windows of the rate
+// limits and of the lockouts, how long what is cached lives, the times of the
+// audit log. It is that of the system, unless Config::clock is one of the tests,
+// which they set and move on to see what happens an hour later without waiting.
+// How long the requests take, the timeouts and the retries of the backends keep
+// to the system's, the time they measure being the one that really went by, and
+// what the database expires goes by its own, now() in the SQL.
+pub trait Clock: Send + Sync {
+    // The wall time, for the times told and the expiries
+    fn now(&self) -> SystemTime;
+    // Never going back, for the windows and the lifetimes
+    fn instant(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// A clock standing still but when it is moved: set, for the wall time only, as a
+// system clock can be, or advanced, for both
+pub struct TestClock {
+    started: Instant,
+    // The wall time, and how far it was advanced since it started
+    moved: Mutex<(SystemTime, Duration)>,
+}
+
+impl TestClock {
+    pub fn new(now: SystemTime) -> Self {
+        TestClock { started: Instant::now(), moved: Mutex::new((now, Duration::ZERO)) }
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        self.moved.lock().unwrap().0 = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut moved = self.moved.lock().unwrap();
+        *moved = (moved.0 + by, moved.1 + by);
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        self.moved.lock().unwrap().0
+    }
+
+    fn instant(&self) -> Instant {
+        self.started + self.moved.lock().unwrap().1
+    }
+}
+
+// None until init, for the system's
+static CLOCK: RwLock<Option<Arc<dyn Clock>>> = RwLock::new(None);
+
+pub fn init(clock: Arc<dyn Clock>) {
+    *CLOCK.write().unwrap() = Some(clock);
+}
+
+pub fn now() -> SystemTime {
+    CLOCK.read().unwrap().as_ref().map_or_else(SystemTime::now, |clock| clock.now())
+}
+
+pub fn instant() -> Instant {
+    CLOCK.read().unwrap().as_ref().map_or_else(Instant::now, |clock| clock.instant())
+}
+
+// Seconds since the epoch, as the tokens have their times
+pub fn unix_secs() -> u64 {
+    now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+pub fn utc() -> DateTime<Utc> {
+    now().into()
+}
+
+// Since the instant, of the clock too
+pub fn elapsed(since: Instant) -> Duration {
+    instant().saturating_duration_since(since)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_test_clock_moves_only_when_it_is_moved() {
+        let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(1_000));
+        let (now, instant) = (clock.now(), clock.instant());
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!((clock.now(), clock.instant()), (now, instant));
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(1_090));
+        assert_eq!(clock.instant() - instant, Duration::from_secs(90));
+
+        // Set back, the wall time goes with it but not the instants
+        clock.set(UNIX_EPOCH);
+        assert_eq!(clock.now(), UNIX_EPOCH);
+        assert_eq!(clock.instant() - instant, Duration::from_secs(90));
+    }
+}
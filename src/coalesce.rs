@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, Condvar, Mutex, OnceLock };
+use std::time::Duration;
+
+use crate::config::number_from_env;
+
+const DEFAULT_WAIT: Duration = Duration::from_secs(5);
+
+static FLIGHTS: OnceLock<Flights> = OnceLock::new();
+
+// Concurrent GET /users/{id} of the same user that isn't cached, and GET /users of
+// the same query, wait for the first of them to read it, and answer with its
+// response, rather than all asking the database at once. They wait up to
+// COALESCE_WAIT_MS, then read it themselves; 0 turns it off.
+#[derive(Clone, Debug)]
+pub struct CoalesceConfig {
+    pub wait: Duration,
+}
+
+impl CoalesceConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let wait = number_from_env("COALESCE_WAIT_MS", DEFAULT_WAIT.as_millis() as u64)?;
+        Ok(CoalesceConfig { wait: Duration::from_millis(wait) })
+    }
+}
+
+pub fn init(config: CoalesceConfig) {
+    FLIGHTS.set(Flights::new(config)).ok();
+}
+
+pub fn flights() -> &'static Flights {
+    FLIGHTS.get_or_init(|| Flights::new(CoalesceConfig { wait: DEFAULT_WAIT }))
+}
+
+// The status line and the body
+type Response = (String, String);
+
+enum Status {
+    Flying,
+    // None when the leader panicked
+    Landed(Option<Response>),
+}
+
+struct Flight {
+    status: Mutex<Status>,
+    landed: Condvar,
+}
+
+pub struct Flights {
+    wait: Duration,
+    in_flight: Mutex<HashMap<String, Arc<Flight>>>,
+    // Since startup, the requests answered with the response of another
+    pub coalesced: AtomicU64,
+}
+
+impl Flights {
+    pub fn new(config: CoalesceConfig) -> Self {
+        Flights { wait: config.wait, in_flight: Mutex::default(), coalesced: AtomicU64::new(0) }
+    }
+
+    // The response of fetch, or that of the request already fetching key. Only for
+    // the requests that change nothing.
+    pub fn run(&self, key: String, fetch: impl FnOnce() -> Response) -> Response {
+        if self.wait.is_zero() {
+            return fetch();
+        }
+        let (flight, leading) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = Arc::new(Flight { status: Mutex::new(Status::Flying), landed: Condvar::new() });
+                    in_flight.insert(key.clone(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+
+        if !leading {
+            let status = flight.status.lock().unwrap();
+            let (status, _) = flight
+                .landed
+                .wait_timeout_while(status, self.wait, |status| matches!(status, Status::Flying))
+                .unwrap();
+            if let Status::Landed(Some(response)) = &*status {
+                self.coalesced.fetch_add(1, Ordering::Relaxed);
+                return response.clone();
+            }
+            drop(status);
+            return fetch();
+        }
+
+        // Lands on drop, so that the followers of a leader that panics don't wait
+        // for nothing
+        let mut landing = Landing { flights: self, key, flight, response: None };
+        let response = fetch();
+        landing.response = Some(response.clone());
+        response
+    }
+}
+
+struct Landing<'a> {
+    flights: &'a Flights,
+    key: String,
+    flight: Arc<Flight>,
+    response: Option<Response>,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        // Those coming after this fetch again
+        self.flights.in_flight.lock().unwrap().remove(&self.key);
+        *self.flight.status.lock().unwrap() = Status::Landed(self.response.take());
+        self.flight.landed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{ self, AssertUnwindSafe };
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn followers_of_a_leader_that_panics_fetch_themselves() {
+        let flights = Flights::new(CoalesceConfig { wait: Duration::from_secs(5) });
+        let (started, start) = mpsc::channel();
+        let response = thread::scope(|scope| {
+            let leader = scope.spawn(|| {
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                    flights.run("user 1".to_owned(), || {
+                        started.send(()).unwrap();
+                        thread::sleep(Duration::from_millis(100));
+                        panic!("the database went away");
+                    })
+                }))
+            });
+            start.recv().unwrap();
+            let response = flights.run("user 1".to_owned(), || ("200".to_owned(), "ada".to_owned()));
+            assert!(leader.join().unwrap().is_err());
+            response
+        });
+        assert_eq!(response, ("200".to_owned(), "ada".to_owned()));
+        assert_eq!(flights.coalesced.load(Ordering::Relaxed), 0);
+        assert!(flights.in_flight.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,159 @@
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+// Read in that order, the first to set a variable winning
+const FILES: [&str; 2] = [".env.local", ".env"];
+
+// The number of the line and why it isn't valid
+type LineError = (usize, String);
+
+// The .env of the directory, and the .env.local overriding it, for the local
+// runs: their variables are set in the environment unless they are there already,
+// what the process was started with winning over the files. Neither is needed. A
+// line that isn't valid is left out, the warnings about those returned to be
+// logged once the logger is set up.
+pub fn load(dir: &Path) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for name in FILES {
+        let path = dir.join(name);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => {
+                warnings.push(format!("Can't read {}: {}", name, e));
+                continue;
+            }
+        };
+        let (variables, errors) = parse(&text);
+        for (line, e) in errors {
+            warnings.push(format!("{}, line {}: {}, left out", name, line, e));
+        }
+        for (variable, value) in variables {
+            if env::var_os(&variable).is_none() {
+                env::set_var(variable, value);
+            }
+        }
+    }
+    warnings
+}
+
+// The NAME=value lines, with an optional export before, and the lines that
+// aren't valid and why. Blank lines and those starting with # are skipped. A
+// value between double quotes has the escapes \n, \t, \", \\ and \$, one between
+// single quotes is as written, and one without quotes ends at a # after a blank,
+// the blanks around it removed.
+pub fn parse(text: &str) -> (Vec<(String, String)>, Vec<LineError>) {
+    let (mut variables, mut errors) = (Vec::new(), Vec::new());
+    for (line, content) in (1..).zip(text.lines()) {
+        let content = content.trim();
+        if content.is_empty() || content.starts_with('#') {
+            continue;
+        }
+        match variable(content) {
+            Ok(variable) => variables.push(variable),
+            Err(e) => errors.push((line, e)),
+        }
+    }
+    (variables, errors)
+}
+
+fn variable(line: &str) -> Result<(String, String), String> {
+    let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+    let Some((name, value)) = line.split_once('=') else {
+        return Err("expected NAME=value".to_owned());
+    };
+    let name = name.trim();
+    let valid = |(i, c): (usize, char)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit());
+    if name.is_empty() || !name.char_indices().all(valid) {
+        return Err(format!("{:?} isn't the name of a variable", name));
+    }
+    let value = value.trim_start();
+    let (value, rest) = match value.chars().next() {
+        Some('"') => double_quoted(&value[1..])?,
+        Some('\'') => match value[1..].split_once('\'') {
+            Some((value, rest)) => (value.to_owned(), rest),
+            None => return Err("the ' isn't closed".to_owned()),
+        },
+        _ => {
+            let end = value.find(" #").or_else(|| value.find("\t#")).unwrap_or(value.len());
+            (value[..end].trim_end().to_owned(), "")
+        }
+    };
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("expected the end of the line after the value of {}, got {:?}", name, rest));
+    }
+    Ok((name.to_owned(), value))
+}
+
+// The value up to the closing quote, and what follows it
+fn double_quoted(text: &str) -> Result<(String, &str), String> {
+    let mut value = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &text[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c @ ('"' | '\\' | '$')) => value.push(c),
+                Some(c) => return Err(format!("unknown escape \\{}", c)),
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+    Err("the \" isn't closed".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(text: &str) -> Vec<(String, String)> {
+        let (variables, errors) = parse(text);
+        assert_eq!(errors, []);
+        variables
+    }
+
+    fn variable(name: &str, value: &str) -> (String, String) {
+        (name.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn the_values_are_read_with_their_quotes() {
+        let text = r#"
+            # The local database
+            DATABASE_URL=postgres://postgres@localhost/users
+            export APP_ENV = dev   # and its profile
+            MAINTENANCE_MESSAGE="Back in a few minutes, \"promise\""
+            SMTP_FROM='Users <users@example.com> # not a comment'
+            EMPTY=
+            PASSWORD="a#b c"  # the comment
+        "#;
+        let expected = [
+            variable("DATABASE_URL", "postgres://postgres@localhost/users"),
+            variable("APP_ENV", "dev"),
+            variable("MAINTENANCE_MESSAGE", "Back in a few minutes, \"promise\""),
+            variable("SMTP_FROM", "Users <users@example.com> # not a comment"),
+            variable("EMPTY", ""),
+            variable("PASSWORD", "a#b c"),
+        ];
+        assert_eq!(variables(text), expected);
+        assert_eq!(variables("A=\"two\\nlines\\t\\$HOME\""), [variable("A", "two\nlines\t$HOME")]);
+        assert_eq!(variables("URL=http://host/#anchor"), [variable("URL", "http://host/#anchor")]);
+    }
+
+    #[test]
+    fn the_lines_that_are_not_valid_are_left_out_with_their_number() {
+        let text = "A=1\nnot a variable\n1A=2\nB=\"open\nC='open\nD=\"done\" and more\nE=\"\\q\"\nF=3\n";
+        let (variables, errors) = parse(text);
+        assert_eq!(variables, [variable("A", "1"), variable("F", "3")]);
+        let lines: Vec<usize> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [2, 3, 4, 5, 6, 7]);
+        assert_eq!(errors[0].1, "expected NAME=value");
+        assert_eq!((errors[2].1.as_str(), errors[3].1.as_str()), ("the \" isn't closed", "the ' isn't closed"));
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fs;
+
+// The file of --config or CONFIG_FILE, in TOML, sets the same settings as the
+// environment, named like the variables: PORT = 8080, or port = 8080, or under a
+// table naming the start of the variable, as [db_pool] max_size = 10 for
+// DB_POOL_MAX_SIZE. The strings, the numbers and the booleans are the values of
+// the variables as written, the arrays those of the lists, joined by commas. Not
+// the whole of TOML: no inline tables, dates nor strings of several lines, those
+// are refused with their line.
+pub fn read(path: &str) -> Result<Vec<(String, String)>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    parse(&text).map_err(|(line, e)| format!("{}, line {}: {}", path, line, e))
+}
+
+// The settings in the order of the file, or the line that isn't valid and why
+pub fn parse(text: &str) -> Result<Vec<(String, String)>, (usize, String)> {
+    let (mut table, mut settings, mut lines) = (Vec::new(), Vec::new(), HashMap::new());
+    for (line, content) in (1..).zip(text.lines()) {
+        let mut rest = Rest(content);
+        rest.skip_blanks();
+        if rest.at_end() {
+            continue;
+        }
+        if rest.eat("[[") {
+            return Err((line, "arrays of tables aren't supported".to_owned()));
+        }
+        if rest.eat("[") {
+            table = rest.key().map_err(|e| (line, e))?;
+            if !rest.eat("]") {
+                return Err((line, "expected ] after the name of the table".to_owned()));
+            }
+            rest.end().map_err(|e| (line, e))?;
+            continue;
+        }
+
+        let key = rest.key().map_err(|e| (line, e))?;
+        if !rest.eat("=") {
+            return Err((line, format!("expected = after {}", key.join("."))));
+        }
+        rest.skip_blanks();
+        let value = rest.value().map_err(|e| (line, e))?;
+        rest.end().map_err(|e| (line, e))?;
+
+        let name = table.iter().chain(&key).map(|part| part.to_uppercase().replace('-', "_")).collect::<Vec<_>>();
+        let name = name.join("_");
+        if let Some(first) = lines.insert(name.clone(), line) {
+            return Err((line, format!("{} is set already, on line {}", name, first)));
+        }
+        settings.push((name, value));
+    }
+    Ok(settings)
+}
+
+// What is left of a line
+struct Rest<'a>(&'a str);
+
+impl Rest<'_> {
+    fn skip_blanks(&mut self) {
+        self.0 = self.0.trim_start_matches([' ', '\t']);
+    }
+
+    fn at_end(&self) -> bool {
+        self.0.is_empty() || self.0.starts_with('#')
+    }
+
+    fn end(&mut self) -> Result<(), String> {
+        self.skip_blanks();
+        match self.at_end() {
+            true => Ok(()),
+            false => Err(format!("unexpected {:?} at the end of the line", self.0)),
+        }
+    }
+
+    // Past the token when it comes next, the blanks after it too
+    fn eat(&mut self, token: &str) -> bool {
+        match self.0.strip_prefix(token) {
+            Some(rest) => {
+                self.0 = rest;
+                self.skip_blanks();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Its parts, as a.b or "a".b
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut parts = Vec::new();
+        loop {
+            let part = match self.0.chars().next() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let length = self.0.find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '-');
+                    let (part, rest) = self.0.split_at(length.unwrap_or(self.0.len()));
+                    if part.is_empty() {
+                        return Err(format!("expected a key, got {:?}", self.0));
+                    }
+                    self.0 = rest;
+                    part.to_owned()
+                }
+            };
+            if part.is_empty() || part.contains(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '-') {
+                return Err(format!("keys are made of letters, digits, _ and -, got {:?}", part));
+            }
+            parts.push(part);
+            self.skip_blanks();
+            if !self.eat(".") {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<String, String> {
+        if self.0.starts_with("\"\"\"") || self.0.starts_with("'''") {
+            return Err("strings of several lines aren't supported".to_owned());
+        }
+        match self.0.chars().next() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            Some('[') => self.array(),
+            Some('{') => Err("inline tables aren't supported".to_owned()),
+            Some(_) => self.scalar(),
+            None => Err("expected a value after =".to_owned()),
+        }
+    }
+
+    // A string, an array of them, of numbers or of booleans, as a list
+    fn array(&mut self) -> Result<String, String> {
+        self.eat("[");
+        let mut values = Vec::new();
+        while !self.eat("]") {
+            if self.at_end() {
+                return Err("expected ] at the end of the array, arrays are on one line".to_owned());
+            }
+            match self.0.chars().next() {
+                Some('[') => return Err("arrays of arrays aren't supported".to_owned()),
+                _ => values.push(self.value()?),
+            }
+            self.skip_blanks();
+            if !self.eat(",") && !self.0.starts_with(']') {
+                return Err(format!("expected , or ] in the array, got {:?}", self.0));
+            }
+        }
+        Ok(values.join(","))
+    }
+
+    // A number or a boolean, up to the next blank, comma or bracket
+    fn scalar(&mut self) -> Result<String, String> {
+        let length = self.0.find([' ', '\t', ',', ']', '#']).unwrap_or(self.0.len());
+        let (value, rest) = self.0.split_at(length);
+        self.0 = rest;
+        if value == "true" || value == "false" {
+            return Ok(value.to_owned());
+        }
+        let number = value.replace('_', "");
+        let digits = number.strip_prefix(['+', '-']).unwrap_or(&number);
+        let valid = match digits.contains(['.', 'e', 'E']) {
+            true => number.parse::<f64>().is_ok_and(f64::is_finite),
+            false => !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()),
+        };
+        match valid && !value.starts_with('_') && !value.ends_with('_') {
+            true => Ok(number.strip_prefix('+').unwrap_or(&number).to_owned()),
+            false => Err(format!("expected a string, a number, a boolean or an array, got {:?}", value)),
+        }
+    }
+
+    // "..." with its escapes
+    fn basic_string(&mut self) -> Result<String, String> {
+        let mut value = String::new();
+        let mut chars = self.0.char_indices().skip(1);
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.0 = &self.0[index + 1..];
+                    return Ok(value);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some(c @ ('u' | 'U')) => {
+                            let length = if c == 'u' { 4 } else { 8 };
+                            let hex: String = chars.by_ref().take(length).map(|(_, c)| c).collect();
+                            let code = u32::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == length);
+                            code.and_then(char::from_u32).ok_or_else(|| format!("invalid escape \\{}{}", c, hex))?
+                        }
+                        Some(c) => return Err(format!("invalid escape \\{}", c)),
+                        None => break,
+                    };
+                    value.push(escaped);
+                }
+                c => value.push(c),
+            }
+        }
+        Err("expected \" at the end of the string".to_owned())
+    }
+
+    // '...' as it is written
+    fn literal_string(&mut self) -> Result<String, String> {
+        match self.0[1..].split_once('\'') {
+            Some((value, rest)) => {
+                self.0 = rest;
+                Ok(value.to_owned())
+            }
+            None => Err("expected ' at the end of the string".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(text: &str) -> Vec<(String, String)> {
+        parse(text).unwrap()
+    }
+
+    fn setting(name: &str, value: &str) -> (String, String) {
+        (name.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn the_keys_are_the_variables_under_their_table() {
+        let text = "
+            # The server
+            port = 9000
+            APP_ENV = \"production\"  # and its profile
+
+            [db_pool]
+            max_size = 10
+            [db.connect]
+            retry-secs = 5
+            max_attempts = 3
+        ";
+        let expected = [
+            setting("PORT", "9000"),
+            setting("APP_ENV", "production"),
+            setting("DB_POOL_MAX_SIZE", "10"),
+            setting("DB_CONNECT_RETRY_SECS", "5"),
+            setting("DB_CONNECT_MAX_ATTEMPTS", "3"),
+        ];
+        assert_eq!(settings(text), expected);
+    }
+
+    #[test]
+    fn the_values_are_written_as_the_variables() {
+        let text = r#"
+            a = "with \"quotes\", a tab\t and \u00e9"
+            b = 'C:\literal # not a comment'
+            c = 1_000
+            d = -0.5
+            e = true
+            f = ["10.0.0.1", "10.0.0.2", ]
+            g = []
+            h = +3
+        "#;
+        assert_eq!(settings(text), [
+            setting("A", "with \"quotes\", a tab\t and \u{e9}"),
+            setting("B", "C:\\literal # not a comment"),
+            setting("C", "1000"),
+            setting("D", "-0.5"),
+            setting("E", "true"),
+            setting("F", "10.0.0.1,10.0.0.2"),
+            setting("G", ""),
+            setting("H", "3"),
+        ]);
+    }
+
+    #[test]
+    fn an_invalid_line_is_refused_with_its_number() {
+        for (text, line, error) in [
+            ("port = 80\nport 81", 2, "expected = after port"),
+            ("\n\nport = ", 3, "expected a value after ="),
+            ("name = \"unterminated", 1, "expected \" at the end of the string"),
+            ("[db_pool\nmax_size = 1", 1, "expected ] after the name of the table"),
+            ("port = 80 81", 1, "unexpected \"81\" at the end of the line"),
+            ("port = eighty", 1, "expected a string, a number, a boolean or an array, got \"eighty\""),
+            ("port = 80\n[server]\nPORT_X = 1\n[]", 4, "expected a key, got \"]\""),
+            ("list = [1,\n2]", 1, "expected ] at the end of the array, arrays are on one line"),
+            ("auth = { user = 1 }", 1, "inline tables aren't supported"),
+            ("PORT = 1\nport = 2", 2, "PORT is set already, on line 1"),
+            ("at = 1979-05-27", 1, "expected a string, a number, a boolean or an array, got \"1979-05-27\""),
+        ] {
+            assert_eq!(parse(text), Err((line, error.to_owned())), "{:?}", text);
+        }
+    }
+}
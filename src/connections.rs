@@ -0,0 +1,182 @@
+use std::io::Read;
+use std::net::{ Shutdown, TcpStream };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Condvar, Mutex };
+use std::time::{ Duration, Instant };
+
+use crate::config::{ self, number_from_env };
+use crate::shutdown;
+use crate::handlers::handle_livez_request;
+use crate::http::{ get_path, write_response, OVERLOADED };
+
+const DEFAULT_MAX_CONNECTIONS: u64 = 1000;
+const DEFAULT_HEADROOM: u64 = 4;
+// How long a connection in the headroom has to send its request
+const HEADROOM_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+// What the accept loop does at MAX_CONNECTIONS, from MAX_CONNECTIONS_POLICY
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    // Accept and answer 503 right away. MAX_CONNECTIONS_HEADROOM more are read,
+    // so that GET /livez is still answered.
+    Shed,
+    // Stop accepting until a connection closes, the backlog of the kernel holds
+    // the others meanwhile
+    Pause,
+}
+
+// At most MAX_CONNECTIONS connections are open at once, counted from accept to
+// close, the streams included, so that a spike can't run the process out of file
+// descriptors
+#[derive(Clone, Debug)]
+pub struct ConnectionsConfig {
+    pub max_connections: usize,
+    pub headroom: usize,
+    pub policy: Policy,
+}
+
+impl ConnectionsConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let policy = match config::var("MAX_CONNECTIONS_POLICY").as_deref() {
+            Ok("shed") | Err(_) => Policy::Shed,
+            Ok("pause") => Policy::Pause,
+            Ok(value) => return Err(format!("MAX_CONNECTIONS_POLICY must be shed or pause, got {:?}", value)),
+        };
+        let config = ConnectionsConfig {
+            max_connections: number_from_env("MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS)? as usize,
+            headroom: number_from_env("MAX_CONNECTIONS_HEADROOM", DEFAULT_HEADROOM)? as usize,
+            policy,
+        };
+
+        if config.max_connections == 0 {
+            return Err("MAX_CONNECTIONS must be at least 1".to_owned());
+        }
+
+        Ok(config)
+    }
+}
+
+// Whether an accepted connection may be handled
+pub enum Admission<'a> {
+    Admitted(Slot<'a>),
+    // Over the limit, only for GET /livez
+    Headroom(Slot<'a>),
+    Refused,
+}
+
+// The open connections, each holding a slot until it is closed
+pub struct Connections {
+    config: ConnectionsConfig,
+    active: Mutex<usize>,
+    closed: Condvar,
+    // Since startup
+    pub rejected: AtomicU64,
+}
+
+impl Connections {
+    pub fn new(config: ConnectionsConfig) -> Self {
+        Connections { config, active: Mutex::new(0), closed: Condvar::new(), rejected: AtomicU64::new(0) }
+    }
+
+    pub fn config(&self) -> &ConnectionsConfig {
+        &self.config
+    }
+
+    pub fn active(&self) -> usize {
+        *self.active.lock().unwrap()
+    }
+
+    // Before accepting: with the pause policy, waits for a connection to close
+    // while there are MAX_CONNECTIONS, or for the server to shut down
+    pub fn wait_for_room(&self) {
+        if self.config.policy == Policy::Pause {
+            let active = self.active.lock().unwrap();
+            let full = |active: &mut usize| *active >= self.config.max_connections && !shutdown::draining();
+            drop(self.closed.wait_while(active, full).unwrap());
+        }
+    }
+
+    // Stop waiting for room, the server is shutting down
+    pub fn wake(&self) {
+        self.closed.notify_all();
+    }
+
+    // Wait up to timeout for the connections to be closed, returning how many are
+    // still open
+    pub fn wait_until_closed(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut active = self.active.lock().unwrap();
+        while *active > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            active = self.closed.wait_timeout(active, deadline - now).unwrap().0;
+        }
+        *active
+    }
+
+    pub fn admit(&self) -> Admission<'_> {
+        let mut active = self.active.lock().unwrap();
+        let admission = if *active < self.config.max_connections {
+            *active += 1;
+            Admission::Admitted(Slot(self))
+        } else if self.config.policy == Policy::Shed && *active < self.config.max_connections + self.config.headroom {
+            *active += 1;
+            Admission::Headroom(Slot(self))
+        } else {
+            Admission::Refused
+        };
+        drop(active);
+
+        if matches!(admission, Admission::Refused) {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        admission
+    }
+
+    // A connection in the headroom: answered when it is GET /livez, refused
+    // otherwise
+    pub fn serve_headroom(&self, mut stream: TcpStream, slot: Slot) {
+        stream.set_read_timeout(Some(HEADROOM_READ_TIMEOUT)).ok();
+        let mut buffer = [0; 1024];
+        let size = stream.read(&mut buffer).unwrap_or(0);
+        let request = String::from_utf8_lossy(&buffer[..size]);
+        if request.split_whitespace().next() == Some("GET") && get_path(&request) == "/livez" {
+            let (status_line, content) = handle_livez_request();
+            write_response(&mut stream, &status_line, &content).ok();
+        } else {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            refuse(stream, "Too many connections are open");
+        }
+        drop(slot);
+    }
+}
+
+pub struct Slot<'a>(&'a Connections);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.active.lock().unwrap() -= 1;
+        self.0.closed.notify_one();
+    }
+}
+
+// Answer 503 without waiting for the request, and close
+pub fn refuse(mut stream: TcpStream, message: &str) {
+    discard_unread(&mut stream);
+    let body = serde_json::json!({ "error": { "code": "overloaded", "message": message } });
+    write_response(&mut stream, OVERLOADED, body.to_string()).ok();
+    stream.shutdown(Shutdown::Write).ok();
+}
+
+// Read what of the request already arrived, before answering without the rest of
+// it: closing with unread data would reset the connection before the client reads
+// the response
+pub fn discard_unread(stream: &mut TcpStream) {
+    if stream.set_nonblocking(true).is_ok() {
+        let mut buffer = [0; 1024];
+        while matches!(stream.read(&mut buffer), Ok(size) if size > 0) {}
+        stream.set_nonblocking(false).ok();
+    }
+}
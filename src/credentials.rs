@@ -0,0 +1,145 @@
+use std::fs;
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::config;
+use crate::secret::Secret;
+use crate::tls::TlsMode;
+
+// Counts the SIGHUPs received, each asks for the files to be read again
+static RELOAD_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+// Where a connection string comes from: the variable itself, as DATABASE_URL, or
+// the file its _FILE variant names, as secrets managers mount them. The password
+// of PGPASSWORD_FILE is used when the connection string has none, like libpq does
+// with PGPASSWORD. Files are read again when asked to, so that rotated secrets
+// are picked up without a restart; what they hold is never logged.
+#[derive(Clone)]
+pub struct Credentials {
+    var: &'static str,
+    url: Source,
+    password_file: Option<String>,
+    // PGSSLMODE and PGSSLROOTCERT, for a connection string without sslmode and
+    // sslrootcert
+    pub ssl_mode: Option<TlsMode>,
+    pub ssl_root_cert: Option<String>,
+}
+
+#[derive(Clone)]
+enum Source {
+    Value(String),
+    File(String),
+}
+
+// What the credentials held when last read
+#[derive(Clone, PartialEq)]
+pub struct Secrets {
+    pub url: Secret<String>,
+    pub password: Option<Secret<String>>,
+}
+
+impl Credentials {
+    // None when neither the variable nor its _FILE variant is set
+    pub fn from_env(var: &'static str) -> Result<Option<Self>, String> {
+        let file_var = format!("{}_FILE", var);
+        let url = match (config::var(var), config::var(&file_var)) {
+            (Ok(_), Ok(_)) => {
+                return Err(format!("{} and {} are both set, set only one of them", var, file_var));
+            }
+            (Ok(url), Err(_)) => Source::Value(url),
+            (Err(_), Ok(path)) => Source::File(path),
+            (Err(_), Err(_)) => {
+                return Ok(None);
+            }
+        };
+
+        let ssl_mode = match config::var("PGSSLMODE") {
+            Ok(mode) => Some(TlsMode::parse(&mode).map_err(|e| format!("PGSSLMODE: {}", e))?),
+            Err(_) => None,
+        };
+        Ok(Some(Credentials {
+            var,
+            url,
+            password_file: config::var("PGPASSWORD_FILE").ok(),
+            ssl_mode,
+            ssl_root_cert: config::var("PGSSLROOTCERT").ok(),
+        }))
+    }
+
+    pub fn read(&self) -> Result<Secrets, String> {
+        let url = match &self.url {
+            Source::Value(url) => url.clone(),
+            Source::File(path) => read_secret(&format!("{}_FILE", self.var), path)?,
+        };
+        let password = match &self.password_file {
+            Some(path) => Some(read_secret("PGPASSWORD_FILE", path)?),
+            None => None,
+        };
+        Ok(Secrets { url: Secret::new(url), password: password.map(Secret::new) })
+    }
+
+    // Whether reading again can give something new
+    pub fn reads_files(&self) -> bool {
+        matches!(self.url, Source::File(_)) || self.password_file.is_some()
+    }
+}
+
+// The secret in a file, without the newline that usually ends it. Errors name the
+// file, never what is in it.
+fn read_secret(var: &str, path: &str) -> Result<String, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Can't read {} {}: {}", var, path, e))?;
+    let secret = content.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        return Err(format!("{} {} is empty", var, path));
+    }
+    Ok(secret.to_owned())
+}
+
+// How many times the files were asked to be read again, which connectors compare
+// with the count they last read them at
+pub fn reload_requests() -> u64 {
+    RELOAD_REQUESTS.load(Ordering::Relaxed)
+}
+
+// Read the files again after a SIGHUP, before the next connection is opened
+pub fn reload_on_sighup() {
+    extern "C" fn request_reload(_signal: libc::c_int) {
+        RELOAD_REQUESTS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Only touches an atomic, which is safe in a signal handler
+    unsafe {
+        libc::signal(libc::SIGHUP, request_reload as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn secrets_are_read_again_and_never_shown_in_errors() {
+        let path = env::temp_dir().join(format!("credentials-test-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let credentials = Credentials {
+            var: "DATABASE_URL",
+            url: Source::Value("postgres://api@localhost/api".to_owned()),
+            password_file: Some(path.clone()),
+            ssl_mode: None,
+            ssl_root_cert: None,
+        };
+
+        let password = || credentials.read().unwrap().password.map(|password| password.expose().clone());
+        fs::write(&path, "first\n").unwrap();
+        assert_eq!(password().as_deref(), Some("first"));
+        fs::write(&path, "second\r\n").unwrap();
+        assert_eq!(password().as_deref(), Some("second"));
+
+        fs::write(&path, "\n").unwrap();
+        let empty = credentials.read().err().unwrap();
+        assert_eq!(empty, format!("PGPASSWORD_FILE {} is empty", path));
+        fs::remove_file(&path).unwrap();
+        let missing = credentials.read().err().unwrap();
+        assert!(missing.starts_with(&format!("Can't read PGPASSWORD_FILE {}", path)), "{}", missing);
+    }
+}
@@ -0,0 +1,251 @@
+use std::error::Error;
+use std::process;
+use std::sync::{ Arc, OnceLock };
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::credentials::{ self, Credentials };
+use crate::errors::with_causes;
+use crate::pool::{ connect_with_retry, Pool, PoolConfig, RetryConfig };
+use crate::repository::bulkhead::Permits;
+use crate::repository::circuit::Circuit;
+use crate::repository::memory::MemoryRepository;
+use crate::repository::postgres::PostgresRepository;
+use crate::repository::sqlite::SqliteRepository;
+use crate::repository::UserRepository;
+use crate::tls::Connector;
+use crate::{ cli, disconnect, fixtures, migrations, outbox, schema, tenant, ws };
+
+// Connection string from DATABASE_URL, read at startup so that the same build can
+// run against any database
+pub(crate) static CONNECTOR: OnceLock<Connector> = OnceLock::new();
+
+// Connections shared by the request handlers
+pub(crate) static POOL: OnceLock<Pool> = OnceLock::new();
+
+// Connections to the read replica of DATABASE_READ_URL, when there is one
+pub(crate) static READ_POOL: OnceLock<Pool> = OnceLock::new();
+
+// Whether the database is worth asking, for every repository in front of it
+static CIRCUIT: OnceLock<Circuit> = OnceLock::new();
+
+// The operations every repository in front of the database may run at once
+static PERMITS: OnceLock<Permits> = OnceLock::new();
+
+// The connection string of DATABASE_URL, and the circuit and the permits every
+// repository in front of the database shares
+pub(crate) fn init(config: &Config) -> Result<(), String> {
+    if config.postgres {
+        CONNECTOR.set(Connector::from_credentials(config.database.clone()).map_err(|e| e.to_string())?).ok();
+        credentials::reload_on_sighup();
+    }
+    CIRCUIT.set(Circuit::new(config.circuit.clone())).ok();
+    PERMITS.set(Permits::new(config.bulkhead.clone())).ok();
+    Ok(())
+}
+
+// Where the handlers find the users, and where they read those of the GETs sent
+// with X-Read-Primary: true, for callers that need to read their own writes. The
+// second only differs from the first with a replica.
+#[derive(Clone)]
+pub struct Repositories {
+    pub users: Arc<dyn UserRepository>,
+    pub primary_reads: Option<Arc<dyn UserRepository>>,
+}
+
+// Those of DATABASE_URL, the Postgres database brought up to date and seeded as
+// the options of serve say. Exits on failure, with the exit code of cli.
+pub fn open_repositories(config: &Config, options: &[String]) -> Repositories {
+    let seed = match fixtures::SeedConfig::from_args(&config.seed, options) {
+        Ok(seed) => seed,
+        Err(e) => {
+            log::error!("{}", e);
+            process::exit(cli::EXIT_CONFIG);
+        }
+    };
+    if !config.postgres && seed.is_some() {
+        log::error!("Seeding from a file is only supported when DATABASE_URL is a Postgres database");
+        process::exit(cli::EXIT_CONFIG);
+    }
+
+    let url = config.database_url.expose();
+    let users: Arc<dyn UserRepository> = if MemoryRepository::handles(url) {
+        Arc::new(MemoryRepository::default())
+    } else if SqliteRepository::handles(url) {
+        match SqliteRepository::open(url) {
+            Ok(repository) => Arc::new(repository),
+            Err(e) => {
+                log::error!("Error opening the SQLite database: {}", e);
+                process::exit(cli::EXIT_DEPENDENCY);
+            }
+        }
+    } else {
+        start_postgres(config, seed);
+        let primary_reads: Option<Arc<dyn UserRepository>> = match READ_POOL.get() {
+            Some(_) => Some(Arc::new(PostgresRepository::new(pool(), None))),
+            None => None,
+        };
+        let users = Arc::new(PostgresRepository::new(pool(), READ_POOL.get()));
+        return Repositories { users, primary_reads };
+    };
+    Repositories { users, primary_reads: None }
+}
+
+// Bring the database up to date, open the pool and start delivering the events
+// recorded by the mutations. Exits on failure.
+fn start_postgres(config: &Config, seed: Option<fixtures::SeedConfig>) {
+    // Set the database
+    if let Err(e) = set_database(&config.retry, &config.migrations, &config.schema_check, seed.as_ref()) {
+        log::error!("Database setup failed: {}", with_causes(&*e));
+        process::exit(cli::EXIT_DEPENDENCY);
+    }
+
+    match Pool::new(connector().clone(), config.pool.clone()) {
+        Ok(pool) => {
+            POOL.set(pool).ok();
+        }
+        Err(e) => {
+            log::error!("Error opening the connection pool: {}", with_causes(&e));
+            process::exit(cli::EXIT_DEPENDENCY);
+        }
+    }
+
+    if let Some(credentials) = &config.read_database {
+        open_read_pool(credentials.clone(), config.pool.clone());
+    }
+    disconnect::start(POOL.get().into_iter().chain(READ_POOL.get()).collect());
+
+    // Deliver the events recorded by the mutations
+    thread::spawn(outbox::run_dispatcher);
+    thread::spawn(ws::run_broadcaster);
+}
+
+// The replica is optional at runtime: while it can't be reached the reads go to
+// the primary, so it being down at startup is only a warning
+fn open_read_pool(credentials: Credentials, config: PoolConfig) {
+    let connector = match Connector::from_credentials(credentials) {
+        Ok(connector) => connector,
+        Err(e) => {
+            log::error!("Invalid DATABASE_READ_URL: {}", e);
+            process::exit(cli::EXIT_CONFIG);
+        }
+    };
+
+    let pool = match Pool::new(connector.clone(), config.clone()) {
+        Ok(pool) => pool,
+        Err(e) => {
+            log::warn!(
+                "Can't reach the read replica, reading from the primary until it can: {}",
+                with_causes(&e)
+            );
+            let config = PoolConfig { min_size: 0, ..config };
+            Pool::new(connector, config).expect("a pool without connections opens nothing")
+        }
+    };
+    READ_POOL.set(pool).ok();
+}
+
+pub(crate) fn connector() -> &'static Connector {
+    CONNECTOR.get().expect("DATABASE_URL is read at startup")
+}
+
+pub(crate) fn pool() -> &'static Pool {
+    POOL.get().expect("the pool is opened at startup")
+}
+
+pub(crate) fn circuit() -> &'static Circuit {
+    CIRCUIT.get().expect("the circuit is set up at startup")
+}
+
+pub(crate) fn permits() -> &'static Permits {
+    PERMITS.get().expect("the permits are set up at startup")
+}
+
+// Database setup: bring the schema up to date, then make sure it is the one the
+// API expects, whoever manages it
+fn set_database(
+    retry_config: &RetryConfig,
+    migrations_mode: &migrations::Mode,
+    schema_check: &schema::Strictness,
+    seed: Option<&fixtures::SeedConfig>
+) -> Result<(), Box<dyn Error>> {
+    let mut client = connect_with_retry(connector(), retry_config)?; // db connection
+    match migrations_mode {
+        migrations::Mode::Apply => {
+            let applied = migrations::apply(&mut client)?;
+            if applied.is_empty() {
+                log::info!("The database schema is up to date");
+            }
+            tenant::migrate_all(&mut client)?;
+        }
+        migrations::Mode::Check(interval) => {
+            let pending = migrations::pending(&mut client)?;
+            if !pending.is_empty() {
+                let names: Vec<String> = pending.iter().map(|migration| migration.to_string()).collect();
+                log::info!(
+                    "MIGRATIONS_MODE is check: not serving until these migrations are applied: {}",
+                    names.join(", ")
+                );
+                migrations::set_waiting_for(&pending);
+                let (interval, schema_check, seed) = (*interval, *schema_check, seed.cloned());
+                thread::spawn(move || wait_for_migrations(interval, schema_check, seed));
+                // The schema is checked, and the seed loaded, once it caught up
+                return Ok(());
+            }
+            log::info!("MIGRATIONS_MODE is check: the database schema is up to date");
+        }
+        migrations::Mode::Ignore => log::info!("Not applying migrations, MIGRATIONS_MODE is ignore"),
+    }
+    schema::check(&mut client, schema_check)?;
+    tenant::prepare_registry(&mut client)?;
+    seed_database(&mut client, seed)
+}
+
+// Load the seed file, into a schema the API can serve
+fn seed_database(client: &mut postgres::Client, seed: Option<&fixtures::SeedConfig>) -> Result<(), Box<dyn Error>> {
+    if let Some(config) = seed {
+        let summary = fixtures::seed(client, config)?;
+        log::info!("Seeded the database from {}: {}", config.path, summary);
+    }
+    Ok(())
+}
+
+// In check mode, look for the missing migrations again until they are all applied
+fn wait_for_migrations(interval: Duration, schema_check: schema::Strictness, seed: Option<fixtures::SeedConfig>) {
+    loop {
+        thread::sleep(interval);
+        let mut client = match connector().connect() {
+            Ok(client) => client,
+            Err(e) => {
+                log::error!("Error checking for pending migrations: {}", e);
+                continue;
+            }
+        };
+        let pending = match migrations::pending(&mut client) {
+            Ok(pending) => pending,
+            Err(e) => {
+                log::error!("Error checking for pending migrations: {}", with_causes(&*e));
+                continue;
+            }
+        };
+        if !pending.is_empty() {
+            migrations::set_waiting_for(&pending);
+            continue;
+        }
+
+        // Serving a schema that doesn't fit would fail like at startup
+        if let Err(e) = schema::check(&mut client, &schema_check) {
+            log::error!("Database setup failed: {}", e);
+            process::exit(cli::EXIT_DEPENDENCY);
+        }
+        let setup = tenant::prepare_registry(&mut client).map_err(Into::into);
+        if let Err(e) = setup.and_then(|_| seed_database(&mut client, seed.as_ref())) {
+            log::error!("Database setup failed: {}", with_causes(&*e));
+            process::exit(cli::EXIT_DEPENDENCY);
+        }
+        migrations::set_waiting_for(&[]);
+        log::info!("All migrations are applied, serving requests");
+        return;
+    }
+}
@@ -0,0 +1,64 @@
+use serde_json::{ json, Map, Value };
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use crate::http::OK_RESPONSE;
+use crate::{ access_log, cache, config, connections, metrics, rate_limit, reload, secret, workers };
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+// The server started now, for the uptime
+pub fn init() {
+    STARTED.get_or_init(Instant::now);
+}
+
+// GET /debug/stats, what the server is doing right now, as JSON: how long it has
+// been up and which build it is, its connections, workers and pools, the cache and
+// the rate limiter, the requests being answered with how long they have taken so
+// far, and the environment it was configured by, the secrets redacted, with the
+// generation of the settings, which each SIGHUP changing them moves on. Nothing is
+// asked of the database, so it answers when that is down too.
+pub fn handle_stats_request() -> (String, String) {
+    let uptime = STARTED.get().map_or(0.0, |started| started.elapsed().as_secs_f64());
+    let (workers, connections) = (workers(), connections());
+    let cache = cache::cache().map(|cache| json!({ "entries": cache.size(), "hit_ratio": cache.hit_ratio() }));
+    let in_flight: Vec<Value> = access_log::in_flight()
+        .into_iter()
+        .map(|request| {
+            json!({
+                "route": request.route,
+                "request_id": request.request_id,
+                "elapsed_ms": request.started.elapsed().as_millis() as u64,
+            })
+        })
+        .collect();
+    let vars: Map<String, Value> = config::vars()
+        .into_iter()
+        .map(|(name, value)| (name.clone(), Value::from(secret::env_value(&name, &value))))
+        .collect();
+
+    let stats = json!({
+        "uptime_seconds": uptime,
+        "build": {
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "profile": if cfg!(debug_assertions) { "debug" } else { "release" },
+        },
+        "connections": { "active": connections.active(), "max": connections.config().max_connections },
+        "workers": {
+            "threads": workers.config().threads,
+            "busy": workers.busy(),
+            "queue_depth": workers.queue_depth(),
+        },
+        "pools": metrics::pool_status(),
+        "cache": cache,
+        "rate_limiter": { "buckets": rate_limit::tracked() },
+        "in_flight": in_flight,
+        "config": {
+            "profile": config::get().app_env.clone(),
+            "generation": reload::generation(),
+            "env": vars,
+        },
+    });
+    (OK_RESPONSE.to_owned(), stats.to_string())
+}
@@ -0,0 +1,102 @@
+use std::cell::Cell;
+use std::io;
+use std::net::TcpStream;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Mutex, OnceLock };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use crate::pool::Pool;
+
+// How often the clients of the running requests are checked
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The clients of the requests being handled, by request, and when they are out of
+// time. The connections checked out of the pools are marked with the request they
+// are working for.
+static WATCHED: Mutex<Vec<(u64, TcpStream, Option<Instant>)>> = Mutex::new(Vec::new());
+static LAST_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+static POOLS: OnceLock<Vec<&'static Pool>> = OnceLock::new();
+
+thread_local! {
+    // The watched request the thread is handling
+    static CURRENT: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+// Cancel the queries of a request whose client went away, the response would go
+// nowhere, or that ran out of time. Until this is called watch does nothing.
+pub fn start(pools: Vec<&'static Pool>) {
+    POOLS.set(pools).ok();
+    thread::spawn(run);
+}
+
+// Watches the client until the returned guard is dropped, and the time until the
+// deadline
+pub fn watch(stream: &TcpStream, deadline: Option<Instant>) -> Watch {
+    if POOLS.get().is_none() {
+        return Watch(None);
+    }
+    // The request is already read, so the timeout only applies to the peeks
+    let clone = stream.try_clone().and_then(|clone| {
+        clone.set_read_timeout(Some(Duration::from_millis(1)))?;
+        Ok(clone)
+    });
+    match clone {
+        Ok(clone) => {
+            let request = LAST_REQUEST.fetch_add(1, Ordering::Relaxed) + 1;
+            WATCHED.lock().unwrap().push((request, clone, deadline));
+            CURRENT.set(Some(request));
+            Watch(Some(request))
+        }
+        Err(e) => {
+            log::error!("Error watching the client: {}", e);
+            Watch(None)
+        }
+    }
+}
+
+// The request the connections checked out by this thread are for
+pub fn current() -> Option<u64> {
+    CURRENT.get()
+}
+
+pub struct Watch(Option<u64>);
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        if let Some(request) = self.0 {
+            CURRENT.set(None);
+            // Waits for a cancel in progress, so that it can't hit the next request
+            WATCHED.lock().unwrap().retain(|(watched, _, _)| *watched != request);
+        }
+    }
+}
+
+fn run() {
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let mut watched = WATCHED.lock().unwrap();
+        let (mut gone, now) = (Vec::new(), Instant::now());
+        watched.retain(|(request, stream, deadline)| {
+            let abandoned = deadline.is_some_and(|deadline| now >= deadline) || has_disconnected(stream);
+            if abandoned {
+                gone.push(*request);
+            }
+            !abandoned
+        });
+        for request in gone {
+            for pool in POOLS.get().into_iter().flatten() {
+                pool.cancel_checked_out(request);
+            }
+        }
+    }
+}
+
+fn has_disconnected(stream: &TcpStream) -> bool {
+    match stream.peek(&mut [0; 1]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => !matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut),
+    }
+}
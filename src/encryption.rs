@@ -0,0 +1,328 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{ Hmac, Mac };
+use postgres::Client;
+use ring::aead::{ Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN };
+use ring::rand::{ SecureRandom, SystemRandom };
+use serde_json::Value;
+use sha2::Sha256;
+use std::error::Error;
+use std::sync::OnceLock;
+
+use crate::{ config, tables, tenant };
+
+type HmacSha256 = Hmac<Sha256>;
+
+const KEY_LENGTH: usize = 32;
+
+static KEY: OnceLock<Option<Key>> = OnceLock::new();
+
+// The key the emails are encrypted with, from ENCRYPTION_KEY: 32 bytes, base64
+// encoded. Two keys are derived from it, so that none is used for two things:
+// one for AES-256-GCM, one for the lookup hashes.
+//
+// An email is sealed as the base64 of a random nonce and the AES-256-GCM
+// ciphertext with its tag, which is checked before anything is decrypted. The
+// lookup hash is HMAC-SHA256 of the email in lowercase: the same for the same
+// email, so it can be indexed and searched, without the key to tell which email
+// it is.
+#[derive(Clone)]
+pub struct Key {
+    encryption: LessSafeKey,
+    lookup: [u8; KEY_LENGTH],
+}
+
+impl Key {
+    // name is the variable the key came from, for the errors
+    pub fn from_base64(name: &str, value: &str) -> Result<Self, String> {
+        let bytes = BASE64.decode(value.trim()).map_err(|_| format!("{} must be base64", name))?;
+        let master: [u8; KEY_LENGTH] = bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| format!("{} must be {} bytes, got {}", name, KEY_LENGTH, bytes.len()))?;
+        let encryption = UnboundKey::new(&AES_256_GCM, &derive(&master, b"email encryption")).unwrap();
+        Ok(Key {
+            encryption: LessSafeKey::new(encryption),
+            lookup: derive(&master, b"email lookup"),
+        })
+    }
+
+    pub fn seal(&self, plaintext: &str) -> String {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).expect("the system has random bytes");
+
+        let mut ciphertext = plaintext.as_bytes().to_vec();
+        self.encryption
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut ciphertext)
+            .expect("an email is shorter than what AES-GCM can seal");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        BASE64.encode(sealed)
+    }
+
+    // Fails when the email was sealed with another key, or changed since
+    pub fn open(&self, sealed: &str) -> Result<String, String> {
+        let bytes = BASE64.decode(sealed).map_err(|_| "not an encrypted value".to_owned())?;
+        if bytes.len() < NONCE_LEN + AES_256_GCM.tag_len() {
+            return Err("not an encrypted value".to_owned());
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
+        let mut ciphertext = ciphertext.to_vec();
+        let plaintext = self.encryption
+            .open_in_place(nonce, Aad::empty(), &mut ciphertext)
+            .map_err(|_| "encrypted with another key".to_owned())?;
+        String::from_utf8(plaintext.to_vec()).map_err(|_| "not an encrypted email".to_owned())
+    }
+
+    pub fn lookup_hash(&self, email: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.lookup).unwrap();
+        mac.update(email.to_lowercase().as_bytes());
+        BASE64.encode(mac.finalize().into_bytes())
+    }
+}
+
+fn derive(master: &[u8; KEY_LENGTH], purpose: &[u8]) -> [u8; KEY_LENGTH] {
+    let mut mac = HmacSha256::new_from_slice(master).unwrap();
+    mac.update(purpose);
+    mac.finalize().into_bytes().into()
+}
+
+// None without ENCRYPTION_KEY, the emails are then stored as they are
+pub fn from_env() -> Result<Option<Key>, String> {
+    match config::var("ENCRYPTION_KEY") {
+        Ok(value) if !value.is_empty() => Key::from_base64("ENCRYPTION_KEY", &value).map(Some),
+        _ => Ok(None),
+    }
+}
+
+// None without NEW_ENCRYPTION_KEY, which rotate-encryption-key needs
+pub fn new_from_env() -> Result<Option<Key>, String> {
+    match config::var("NEW_ENCRYPTION_KEY") {
+        Ok(value) if !value.is_empty() => Key::from_base64("NEW_ENCRYPTION_KEY", &value).map(Some),
+        _ => Ok(None),
+    }
+}
+
+pub fn init(key: Option<Key>) {
+    KEY.set(key).ok();
+}
+
+fn key() -> Option<&'static Key> {
+    KEY.get_or_init(|| None).as_ref()
+}
+
+// The email as it is stored, and its lookup hash when it is encrypted
+pub fn seal_email(email: &str) -> (String, Option<String>) {
+    match key() {
+        Some(key) => (key.seal(email), Some(key.lookup_hash(email))),
+        None => (email.to_owned(), None),
+    }
+}
+
+// What to find an email by among the encrypted ones, None without a key
+pub fn lookup_hash(email: &str) -> Option<String> {
+    key().map(|key| key.lookup_hash(email))
+}
+
+// The email of a row, decrypted if it is encrypted, which its lookup hash tells.
+// Rows written before the key was set stay readable.
+pub fn open_email(stored: String, encrypted: bool) -> Result<String, String> {
+    open_with(key(), stored, encrypted, "the email")
+}
+
+// The response stored for an idempotency key, which carries the email of the user
+// it created: sealed whole with the key, and whether it is
+pub fn seal_response(body: &str) -> (String, bool) {
+    match key() {
+        Some(key) => (key.seal(body), true),
+        None => (body.to_owned(), false),
+    }
+}
+
+pub fn open_response(stored: String, encrypted: bool) -> Result<String, String> {
+    open_with(key(), stored, encrypted, "the stored response")
+}
+
+// The payload of an event as it is stored, its email sealed under encrypted_email
+// with the key
+pub fn seal_payload(payload: &Value) -> Value {
+    match key() {
+        Some(key) => sealed_payload(key, payload),
+        None => payload.clone(),
+    }
+}
+
+// The payload of a stored event with its email back. One sealed with another key
+// is left sealed, the event is still worth showing without it.
+pub fn open_payload(payload: Value) -> Value {
+    match opened_payload(key(), &payload) {
+        Ok(opened) => opened,
+        Err(_) => payload,
+    }
+}
+
+// what is what was sealed, for the errors
+fn open_with(key: Option<&Key>, stored: String, encrypted: bool, what: &str) -> Result<String, String> {
+    if !encrypted {
+        return Ok(stored);
+    }
+    match key {
+        Some(key) => key.open(&stored),
+        None => Err(format!("{} is encrypted and ENCRYPTION_KEY is not set", what)),
+    }
+}
+
+fn sealed_payload(key: &Key, payload: &Value) -> Value {
+    let mut payload = payload.clone();
+    if let Some(fields) = payload.as_object_mut() {
+        if let Some(Value::String(email)) = fields.remove("email") {
+            fields.insert("encrypted_email".to_owned(), Value::String(key.seal(&email)));
+        }
+    }
+    payload
+}
+
+fn opened_payload(key: Option<&Key>, payload: &Value) -> Result<Value, String> {
+    let mut payload = payload.clone();
+    if let Some(fields) = payload.as_object_mut() {
+        if let Some(Value::String(sealed)) = fields.remove("encrypted_email") {
+            let email = open_with(key, sealed, true, "the email")?;
+            fields.insert("email".to_owned(), Value::String(email));
+        }
+    }
+    Ok(payload)
+}
+
+// Encrypt every email with the new key, in one transaction per schema: those
+// encrypted with the current key, and those stored as they are, in the users, the
+// events and the stored responses. Anonymized users are left alone, their emails
+// are made up. The instances must then be started
+// with the new key, those still on the old one can't read the emails again.
+pub fn rotate(client: &mut Client, current: Option<&Key>, new: &Key) -> Result<u64, Box<dyn Error>> {
+    let mut rotated = rotate_schema(client, current, new)?;
+    for schema in tenant::provisioned_schemas(client)? {
+        tenant::use_schema(client, &schema)?;
+        let result = rotate_schema(client, current, new).map_err(|e| format!("schema {}: {}", schema, e));
+        tables::reset_search_path(client)?;
+        rotated += result?;
+    }
+    Ok(rotated)
+}
+
+fn rotate_schema(client: &mut Client, current: Option<&Key>, new: &Key) -> Result<u64, Box<dyn Error>> {
+    let mut transaction = client.transaction()?;
+    let rows = transaction.query(
+        tables::sql(
+            "SELECT id, email, email_hash IS NOT NULL FROM {users} WHERE anonymized_at IS NULL ORDER BY id FOR UPDATE"
+        ),
+        &[]
+    )?;
+
+    for row in &rows {
+        let (id, stored, encrypted): (i32, String, bool) = (row.get(0), row.get(1), row.get(2));
+        let email = match (encrypted, current) {
+            (false, _) => stored,
+            (true, Some(key)) => key.open(&stored).map_err(|e| format!("the email of user {}: {}", id, e))?,
+            (true, None) => {
+                return Err(format!("the email of user {} is encrypted and ENCRYPTION_KEY is not set", id).into());
+            }
+        };
+        transaction.execute(
+            tables::sql("UPDATE {users} SET email = $2, email_hash = $3 WHERE id = $1"),
+            &[&id, &new.seal(&email), &new.lookup_hash(&email)]
+        )?;
+    }
+
+    let events = transaction.query(
+        tables::sql(
+            "SELECT id, payload FROM {events_outbox} WHERE payload ? 'email' OR payload ? 'encrypted_email' FOR UPDATE"
+        ),
+        &[]
+    )?;
+    for event in &events {
+        let id: i64 = event.get(0);
+        let payload = opened_payload(current, &event.get(1)).map_err(|e| format!("event {}: {}", id, e))?;
+        transaction.execute(
+            tables::sql("UPDATE {events_outbox} SET payload = $2 WHERE id = $1"),
+            &[&id, &sealed_payload(new, &payload)]
+        )?;
+    }
+
+    let responses = transaction.query(
+        tables::sql(
+            "SELECT tenant_id, key, response_body, response_encrypted FROM {idempotency_keys}
+            WHERE response_body IS NOT NULL FOR UPDATE"
+        ),
+        &[]
+    )?;
+    for response in &responses {
+        let (tenant, key): (String, String) = (response.get(0), response.get(1));
+        let body = open_with(current, response.get(2), response.get(3), "the stored response")
+            .map_err(|e| format!("idempotency key {}: {}", key, e))?;
+        transaction.execute(
+            tables::sql(
+                "UPDATE {idempotency_keys} SET response_body = $3, response_encrypted = true
+                WHERE tenant_id = $1 AND key = $2"
+            ),
+            &[&tenant, &key, &new.seal(&body)]
+        )?;
+    }
+    transaction.commit()?;
+    Ok(rows.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key(byte: u8) -> Key {
+        Key::from_base64("ENCRYPTION_KEY", &BASE64.encode([byte; KEY_LENGTH])).unwrap()
+    }
+
+    #[test]
+    fn emails_are_sealed_and_opened() {
+        let key = test_key(1);
+        let email = "ada.lovelace.with.a.long.address@example.com";
+        let sealed = key.seal(email);
+        assert!(!sealed.contains("ada"));
+        assert_ne!(sealed, key.seal(email));
+        assert_eq!(key.open(&sealed).as_deref(), Ok(email));
+
+        assert_eq!(test_key(2).open(&sealed), Err("encrypted with another key".to_owned()));
+        let mut tampered = BASE64.decode(&sealed).unwrap();
+        tampered[NONCE_LEN] ^= 1;
+        assert_eq!(key.open(&BASE64.encode(tampered)), Err("encrypted with another key".to_owned()));
+        assert!(key.open("ada@example.com").is_err());
+    }
+
+    #[test]
+    fn the_emails_of_payloads_are_sealed_apart() {
+        let key = test_key(1);
+        let payload = serde_json::json!({ "id": 7, "name": "Ada", "email": "ada@example.com" });
+        let sealed = sealed_payload(&key, &payload);
+        assert_eq!(sealed["name"], "Ada");
+        assert!(sealed.get("email").is_none());
+        assert!(!sealed["encrypted_email"].as_str().unwrap().contains("ada"));
+        assert_eq!(opened_payload(Some(&key), &sealed), Ok(payload.clone()));
+        assert!(opened_payload(Some(&test_key(2)), &sealed).is_err());
+        assert!(opened_payload(None, &sealed).is_err());
+
+        let deleted = serde_json::json!({ "id": 7 });
+        assert_eq!(sealed_payload(&key, &deleted), deleted);
+    }
+
+    #[test]
+    fn lookup_hashes_ignore_case_and_depend_on_the_key() {
+        let key = test_key(1);
+        assert_eq!(key.lookup_hash("Ada@Example.com"), key.lookup_hash("ada@example.com"));
+        assert_ne!(key.lookup_hash("ada@example.com"), key.lookup_hash("alan@example.com"));
+        assert_ne!(key.lookup_hash("ada@example.com"), test_key(2).lookup_hash("ada@example.com"));
+    }
+
+    #[test]
+    fn keys_are_32_bytes_of_base64() {
+        assert!(Key::from_base64("ENCRYPTION_KEY", "not base64!").is_err());
+        let short = Key::from_base64("ENCRYPTION_KEY", &BASE64.encode([0; 16]));
+        assert_eq!(short.err(), Some("ENCRYPTION_KEY must be 32 bytes, got 16".to_owned()));
+    }
+}
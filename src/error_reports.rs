@@ -0,0 +1,241 @@
+use serde_json::{ json, Value };
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{ Hash, Hasher };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::mpsc::{ self, Receiver, SyncSender, TrySendError };
+use std::sync::{ Mutex, OnceLock };
+use std::thread;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use crate::oidc;
+use crate::config::{ self, number_from_env };
+use crate::{ access_log, redact, request_id, trace_context };
+
+const DEFAULT_MAX_QUEUE_SIZE: u64 = 100;
+const DEFAULT_DEDUP_WINDOW_SECONDS: u64 = 60;
+const DEFAULT_MAX_PER_MINUTE: u64 = 60;
+
+// The fingerprints remembered at most, those out of their window forgotten past it
+const MAX_FINGERPRINTS: usize = 1000;
+
+static REPORTER: OnceLock<Reporter> = OnceLock::new();
+
+// With SENTRY_DSN, or ERROR_WEBHOOK_URL for any other service, the unexpected
+// errors are reported: the panics of the handlers and the 500s they answer, with
+// the request_id and the route of the request, the error, redacted like the logs,
+// and the release and APP_ENV. They are queued, up to ERROR_REPORT_QUEUE_SIZE of
+// them, the others dropped, and sent by a thread of their own. The same error of
+// the same route, its numbers aside, is only reported once every
+// ERROR_REPORT_DEDUP_SECONDS, with how many were left out since, and no more than
+// ERROR_REPORT_MAX_PER_MINUTE are reported in all.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrorReportConfig {
+    pub url: String,
+    // The X-Sentry-Auth header, for a SENTRY_DSN
+    pub auth: Option<String>,
+    pub environment: String,
+    pub max_queue_size: usize,
+    pub dedup_window: Duration,
+    pub max_per_minute: u64,
+}
+
+impl ErrorReportConfig {
+    // None without either
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let (url, auth) = match (config::var("SENTRY_DSN"), config::var("ERROR_WEBHOOK_URL")) {
+            (Ok(_), Ok(_)) => return Err("SENTRY_DSN and ERROR_WEBHOOK_URL can't both be set".to_owned()),
+            (Ok(dsn), Err(_)) => {
+                let (url, auth) = sentry_store(&dsn).ok_or("SENTRY_DSN must be like https://key@host/project")?;
+                (url, Some(auth))
+            }
+            (Err(_), Ok(url)) => (url, None),
+            (Err(_), Err(_)) => return Ok(None),
+        };
+        oidc::parse_url(&url).map_err(|e| format!("the error reports can't be sent: {}", e))?;
+        let environment = config::var("APP_ENV").unwrap_or_else(|_| "development".to_owned());
+        let max_queue_size = number_from_env("ERROR_REPORT_QUEUE_SIZE", DEFAULT_MAX_QUEUE_SIZE)?.max(1) as usize;
+        let dedup_window = number_from_env("ERROR_REPORT_DEDUP_SECONDS", DEFAULT_DEDUP_WINDOW_SECONDS)?;
+        let max_per_minute = number_from_env("ERROR_REPORT_MAX_PER_MINUTE", DEFAULT_MAX_PER_MINUTE)?;
+        Ok(Some(ErrorReportConfig {
+            url,
+            auth,
+            environment,
+            max_queue_size,
+            dedup_window: Duration::from_secs(dedup_window),
+            max_per_minute,
+        }))
+    }
+}
+
+// The store endpoint of the project of the DSN, and the header authenticating to it
+fn sentry_store(dsn: &str) -> Option<(String, String)> {
+    let (scheme, rest) = dsn.split_once("://")?;
+    let (key, rest) = rest.split_once('@')?;
+    let key = key.split(':').next().filter(|key| !key.is_empty())?;
+    let (host, project) = rest.rsplit_once('/')?;
+    if host.is_empty() || project.is_empty() {
+        return None;
+    }
+    let url = format!("{}://{}/api/{}/store/", scheme, host, project);
+    let client = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+    Some((url, format!("X-Sentry-Auth: Sentry sentry_version=7, sentry_client={}, sentry_key={}\r\n", client, key)))
+}
+
+struct Reporter {
+    environment: String,
+    dedup_window: Duration,
+    max_per_minute: u64,
+    // With the traceparent of the span reporting it
+    queue: SyncSender<(Value, String)>,
+    limits: Mutex<Limits>,
+    dropped: AtomicU64,
+}
+
+#[derive(Default)]
+struct Limits {
+    // By fingerprint, when it was last reported and how many were left out since
+    seen: HashMap<u64, (Instant, u64)>,
+    // The reports of the minute so far, and when it started
+    minute: Option<(Instant, u64)>,
+}
+
+pub fn init(config: Option<ErrorReportConfig>) {
+    let Some(config) = config else {
+        return;
+    };
+    let (queue, queued) = mpsc::sync_channel(config.max_queue_size);
+    let reporter = Reporter {
+        environment: config.environment.clone(),
+        dedup_window: config.dedup_window,
+        max_per_minute: config.max_per_minute,
+        queue,
+        limits: Mutex::default(),
+        dropped: AtomicU64::new(0),
+    };
+    if REPORTER.set(reporter).is_ok() {
+        thread::spawn(move || send(&config, queued));
+    }
+}
+
+// Report the error of the request on this thread, panic or error by kind, under
+// the id it was logged and answered with. Never waits for it to be sent.
+pub fn report(kind: &'static str, error_id: &str, error: &str) {
+    let Some(reporter) = REPORTER.get() else {
+        return;
+    };
+    let route = access_log::route();
+    let message = redact::text(error);
+    let fingerprint = fingerprint(kind, route.as_deref(), &message);
+    let Some(suppressed) = reporter.allowed(fingerprint) else {
+        return;
+    };
+    let request_id = request_id::current();
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    let event = json!({
+        "event_id": request_id::generate().replace('-', ""),
+        "timestamp": timestamp,
+        "platform": "other",
+        "level": if kind == "panic" { "fatal" } else { "error" },
+        "logger": kind,
+        "message": message,
+        "release": concat!(env!("CARGO_PKG_NAME"), "@", env!("CARGO_PKG_VERSION")),
+        "environment": reporter.environment,
+        "tags": { "kind": kind, "route": route, "request_id": request_id, "error_id": error_id },
+        "fingerprint": [format!("{:016x}", fingerprint)],
+        "extra": { "suppressed": suppressed },
+    });
+    if let Err(TrySendError::Full(_)) = reporter.queue.try_send((event, trace_context::outbound())) {
+        reporter.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Reporter {
+    // How many of the fingerprint were left out since it was last reported, None
+    // if this one is to be left out too
+    fn allowed(&self, fingerprint: u64) -> Option<u64> {
+        let mut limits = self.limits.lock().unwrap();
+        let now = Instant::now();
+        let within = |since: Instant, window: Duration| now.duration_since(since) < window;
+        if let Some((reported, suppressed)) = limits.seen.get_mut(&fingerprint) {
+            if within(*reported, self.dedup_window) {
+                *suppressed += 1;
+                return None;
+            }
+        }
+        let minute = match limits.minute {
+            Some((started, count)) if within(started, Duration::from_secs(60)) => (started, count),
+            _ => (now, 0),
+        };
+        if minute.1 >= self.max_per_minute {
+            limits.minute = Some(minute);
+            return None;
+        }
+        limits.minute = Some((minute.0, minute.1 + 1));
+        if limits.seen.len() >= MAX_FINGERPRINTS {
+            let window = self.dedup_window;
+            limits.seen.retain(|_, (reported, _)| within(*reported, window));
+        }
+        let suppressed = limits.seen.insert(fingerprint, (now, 0)).map_or(0, |(_, suppressed)| suppressed);
+        Some(suppressed)
+    }
+}
+
+// The same for the errors that only differ by their numbers, the ids and the
+// durations they mention
+fn fingerprint(kind: &str, route: Option<&str>, message: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (kind, route).hash(&mut hasher);
+    let mut after_digit = false;
+    for c in message.chars() {
+        let digit = c.is_ascii_digit();
+        // A run of digits counts as one 0
+        if !(digit && after_digit) {
+            (if digit { '0' } else { c }).hash(&mut hasher);
+        }
+        after_digit = digit;
+    }
+    hasher.finish()
+}
+
+// The reports dropped for a full queue, None unless reporting
+pub fn dropped() -> Option<u64> {
+    REPORTER.get().map(|reporter| reporter.dropped.load(Ordering::Relaxed))
+}
+
+fn send(config: &ErrorReportConfig, queued: Receiver<(Value, String)>) {
+    let headers = format!("Content-Type: application/json\r\n{}", config.auth.as_deref().unwrap_or_default());
+    for (event, traced) in queued {
+        let headers = format!("{}{}", headers, traced);
+        match oidc::fetch("POST", &config.url, &headers, &event.to_string()) {
+            Ok((200..=299, _)) => {}
+            Ok((status, _)) => log::warn!("Can't report an error to {}: answered {}", config.url, status),
+            Err(e) => log::warn!("Can't report an error: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sentry_dsns_name_their_store() {
+        let (url, auth) = sentry_store("https://abc123@o42.ingest.sentry.io/4501").unwrap();
+        assert_eq!(url, "https://o42.ingest.sentry.io/api/4501/store/");
+        assert!(auth.starts_with("X-Sentry-Auth: Sentry sentry_version=7, "), "{}", auth);
+        assert!(auth.ends_with("sentry_key=abc123\r\n"), "{}", auth);
+        assert_eq!(sentry_store("https://o42.ingest.sentry.io/4501"), None);
+        assert_eq!(sentry_store("https://abc123@o42.ingest.sentry.io/"), None);
+    }
+
+    #[test]
+    fn errors_differing_by_their_numbers_are_the_same() {
+        let route = Some("GET /users/{id}");
+        let user = |id| fingerprint("error", route, &format!("Error fetching user {}: timed out", id));
+        assert_eq!(user(7), user(123456));
+        assert_ne!(user(7), fingerprint("error", route, "Error fetching user 7: connection reset"));
+        assert_ne!(user(7), fingerprint("panic", route, "Error fetching user 7: timed out"));
+        assert_ne!(user(7), fingerprint("error", None, "Error fetching user 7: timed out"));
+    }
+}
@@ -0,0 +1,501 @@
+use std::backtrace::{ Backtrace, BacktraceStatus };
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::io::{ self, Write };
+use std::sync::OnceLock;
+
+use crate::http::{
+    body_bytes, BAD_REQUEST_PROBLEM, CONFLICT_PROBLEM, CONFLICT_RETRY_PROBLEM, GATEWAY_TIMEOUT_PROBLEM, HEAD_BYTES,
+    INTERNAL_SERVER_ERROR, NOT_FOUND_PROBLEM, NOT_IMPLEMENTED_PROBLEM, OVERLOADED_PROBLEM, PAYLOAD_TOO_LARGE_PROBLEM,
+    REQUEST_TIMEOUT_PROBLEM, SERVICE_UNAVAILABLE_PROBLEM, UNPROCESSABLE_ENTITY_PROBLEM, URI_TOO_LONG_PROBLEM
+};
+use crate::repository::{ Conflict, RepositoryError };
+use crate::validation::ValidationError;
+use crate::{ access_log, api_keys, error_reports, locale, redact, request_id, security_headers, verification };
+
+// Without ERROR_DETAILS, as in prod, the 500s only answer the id of their error,
+// which is logged along with what went wrong. With it, they also say what.
+static ERROR_IDS: OnceLock<bool> = OnceLock::new();
+
+thread_local! {
+    // The id a panic of this thread was logged under, for the 500 answered once the
+    // handler has unwound
+    static PANIC_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+    // What the 500 about to be answered failed with, beyond what it answers
+    static FAILURE: RefCell<Option<Failure>> = const { RefCell::new(None) };
+}
+
+// Logged with the 500 whose body is answered, for the log only: the causes of the
+// error, its SQLSTATE when Postgres failed and where it was answered from
+struct Failure {
+    answered: String,
+    causes: String,
+    sqlstate: Option<String>,
+    backtrace: Backtrace,
+}
+
+pub(crate) fn init(error_details: bool) {
+    ERROR_IDS.set(!error_details).ok();
+}
+
+// Whether the 500s only answer the id of their error
+pub(crate) fn ids_only() -> bool {
+    ERROR_IDS.get() == Some(&true)
+}
+
+// The postgres errors leave the underlying cause, like the TLS error behind
+// "error performing TLS handshake", out of their message
+pub(crate) fn with_causes(error: &dyn Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        let cause_message = cause.to_string();
+        if !message.contains(&cause_message) {
+            message = format!("{}: {}", message, cause_message);
+        }
+        source = cause.source();
+    }
+    redact::text(&message)
+}
+
+// Kept for the log of the 500 answering the body, which has less to say
+fn failed(error: &(dyn Error + 'static), answered: String) -> String {
+    let mut sqlstate = None;
+    let mut source = Some(error);
+    while let Some(e) = source {
+        if let Some(code) = e.downcast_ref::<postgres::Error>().and_then(postgres::Error::code) {
+            sqlstate = Some(code.code().to_owned());
+            break;
+        }
+        source = e.source();
+    }
+    let causes = with_causes(error);
+    FAILURE.set(Some(Failure { answered: answered.clone(), causes, sqlstate, backtrace: Backtrace::capture() }));
+    answered
+}
+
+// The database can't be reached right now, the client should try again later
+fn unavailable_response(error: impl fmt::Display) -> (String, String) {
+    let detail = format!("Database unavailable: {}", redact::text(&error.to_string()));
+    let problem = problem(503, "Service Unavailable", "database_unavailable", &detail);
+    (SERVICE_UNAVAILABLE_PROBLEM.to_owned(), problem.to_string())
+}
+
+// The 404 of a user that isn't there, or isn't anymore
+pub(crate) fn user_not_found(id: i32) -> (String, String) {
+    let detail = locale::text("user_not_found", &[("id", &id.to_string())]);
+    verification::problem(NOT_FOUND_PROBLEM, 404, "Not Found", "user_not_found", &detail)
+}
+
+// The failures every storage operation can have. Anything unexpected is a 500
+// starting with what was being done.
+pub(crate) fn repository_error_response(error: RepositoryError, failure: &str) -> (String, String) {
+    // Counted for alerting, unlike what the server itself turned away
+    if matches!(error, RepositoryError::Unavailable(_) | RepositoryError::Timeout(_) | RepositoryError::Db(_)) {
+        access_log::db_failed();
+    }
+    match error {
+        RepositoryError::Conflict(Conflict::EmailTaken) => {
+            let taken = ValidationError::email_taken();
+            let mut problem = problem(409, "Conflict", "email_taken", &taken.message);
+            problem["errors"] = serde_json::json!([taken]);
+            (CONFLICT_PROBLEM.to_owned(), problem.to_string())
+        }
+        RepositoryError::Conflict(Conflict::Concurrent) => {
+            let detail = redact::text(&error.to_string());
+            (CONFLICT_RETRY_PROBLEM.to_owned(), problem(409, "Conflict", "transaction_conflict", &detail).to_string())
+        }
+        RepositoryError::Unavailable(e) => unavailable_response(e),
+        RepositoryError::Timeout(e) => {
+            let detail = redact::text(&e.to_string());
+            let problem = problem(504, "Gateway Timeout", "statement_timeout", &detail);
+            (GATEWAY_TIMEOUT_PROBLEM.to_owned(), problem.to_string())
+        }
+        RepositoryError::Overloaded => {
+            let problem = problem(503, "Service Unavailable", "overloaded", &error.to_string());
+            (OVERLOADED_PROBLEM.to_owned(), problem.to_string())
+        }
+        RepositoryError::CircuitOpen(retry_after) => {
+            let status_line = format!(
+                "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Type: application/problem+json\r\nRetry-After: {}\r\n\r\n",
+                retry_after.as_millis().div_ceil(1000).max(1)
+            );
+            (status_line, problem(503, "Service Unavailable", "circuit_open", &error.to_string()).to_string())
+        }
+        RepositoryError::Unsupported(what) => {
+            (NOT_IMPLEMENTED_PROBLEM.to_owned(), problem(501, "Not Implemented", "unsupported", what).to_string())
+        }
+        e => {
+            let answered = format!("{}: {}", failure, redact::text(&e.to_string()));
+            let cause: &(dyn Error + 'static) = match &e {
+                RepositoryError::Db(cause) => cause.as_ref(),
+                e => e,
+            };
+            (INTERNAL_SERVER_ERROR.to_owned(), failed(cause, answered))
+        }
+    }
+}
+
+// What a handler failed with. The router answers them all through response, the
+// one place where they get their status and their body.
+#[derive(Debug)]
+pub(crate) enum ApiError {
+    // The user of the id isn't there, or isn't anymore
+    NotFound(i32),
+    // The record of another resource isn't there: what it is called, and its id
+    Missing(&'static str, String),
+    // The 403 auth answers a principal that may not take the route
+    Forbidden((String, String)),
+    // The fields that are invalid, each with why
+    Validation(Vec<ValidationError>),
+    // A 409 saying why, the user being in a state that doesn't allow it
+    Conflict(String),
+    // A 400 saying why, the request being what it shouldn't be
+    BadRequest(String),
+    // A 422 saying why, the request being well formed but not doable as it is
+    Unprocessable(String),
+    // A body that isn't what the route takes, as validation::described tells
+    InvalidBody(serde_json::Value),
+    // The storage failed, and what was being done
+    Db(RepositoryError, &'static str),
+    // What shouldn't have failed
+    Internal(Box<dyn Error + Send + Sync>),
+    // No route has the path asked for
+    NoRoute(String),
+    // The routes of the path are for other methods, these
+    MethodNotAllowed(Vec<&'static str>),
+    // The request couldn't be routed as it was sent
+    Malformed(Malformed),
+    // The upgrade to a WebSocket was asked for wrong, and why
+    Handshake(&'static str),
+}
+
+// What of a request keeps it from being routed, before any handler runs
+#[derive(Debug)]
+pub(crate) enum Malformed {
+    // Longer than what is read of the request
+    RequestLineTooLong,
+    // Not a method, a target and a version of HTTP
+    RequestLine,
+    // The head doesn't end within what is read of the request
+    HeaderTooLarge,
+    // A Content-Length longer than what is read of a body
+    BodyTooLarge,
+    // The segment of the path where an id goes, and why it isn't one
+    InvalidId(String, &'static str),
+    // Not sent whole before the read timeout
+    TimedOut,
+}
+
+// Answered by the response in Ok, whatever its status, or by the error in Err
+pub(crate) type Handled = Result<(String, String), ApiError>;
+
+impl ApiError {
+    pub(crate) fn response(self) -> (String, String) {
+        match self {
+            ApiError::NotFound(id) => user_not_found(id),
+            ApiError::Missing(name, id) => {
+                let detail = format!("No {} has the ID {}", name, id);
+                verification::problem(NOT_FOUND_PROBLEM, 404, "Not Found", &format!("{}_not_found", name), &detail)
+            }
+            ApiError::Forbidden(response) => response,
+            ApiError::Validation(errors) => {
+                let detail = errors.iter().map(|error| error.message.as_str()).collect::<Vec<_>>().join("; ");
+                let mut problem = problem(422, "Unprocessable Entity", "validation_failed", &detail);
+                problem["errors"] = serde_json::json!(errors);
+                (UNPROCESSABLE_ENTITY_PROBLEM.to_owned(), problem.to_string())
+            }
+            ApiError::Conflict(reason) =>
+                (CONFLICT_PROBLEM.to_owned(), problem(409, "Conflict", "conflict", &reason).to_string()),
+            ApiError::BadRequest(reason) =>
+                (BAD_REQUEST_PROBLEM.to_owned(), problem(400, "Bad Request", "bad_request", &reason).to_string()),
+            ApiError::Unprocessable(reason) => {
+                let problem = problem(422, "Unprocessable Entity", "unprocessable", &reason);
+                (UNPROCESSABLE_ENTITY_PROBLEM.to_owned(), problem.to_string())
+            }
+            ApiError::InvalidBody(described) => {
+                let code = described["code"].as_str().unwrap_or("invalid_json");
+                let mut problem = problem(400, "Bad Request", code, described["message"].as_str().unwrap_or_default());
+                for member in ["field", "line", "column"] {
+                    problem[member] = described[member].clone();
+                }
+                (BAD_REQUEST_PROBLEM.to_owned(), problem.to_string())
+            }
+            ApiError::Db(e, failure) => repository_error_response(e, failure),
+            ApiError::Internal(e) => {
+                let answered = redact::text(&e.to_string());
+                (INTERNAL_SERVER_ERROR.to_owned(), failed(e.as_ref(), answered))
+            }
+            ApiError::NoRoute(path) => {
+                let mut problem = problem(404, "Not Found", "route_not_found", &format!("No route for {}", path));
+                problem["instance"] = path.into();
+                (NOT_FOUND_PROBLEM.to_owned(), problem.to_string())
+            }
+            ApiError::MethodNotAllowed(allowed) => {
+                let allowed = allowed.join(", ");
+                let status_line = format!(
+                    "HTTP/1.1 405 METHOD NOT ALLOWED\r\nContent-Type: application/problem+json\r\nAllow: {}\r\n\r\n",
+                    allowed
+                );
+                let detail = format!("The methods of this path are {}", allowed);
+                (status_line, problem(405, "Method Not Allowed", "method_not_allowed", &detail).to_string())
+            }
+            ApiError::Malformed(Malformed::RequestLineTooLong) => {
+                let problem = problem(414, "URI Too Long", "request_line_too_long", "The request line is too long");
+                (URI_TOO_LONG_PROBLEM.to_owned(), problem.to_string())
+            }
+            ApiError::Malformed(Malformed::RequestLine) => {
+                let detail = "The request line must be a method, a target and the version of HTTP";
+                (BAD_REQUEST_PROBLEM.to_owned(), problem(400, "Bad Request", "bad_request_line", detail).to_string())
+            }
+            ApiError::Malformed(Malformed::HeaderTooLarge) => {
+                let detail = format!("The headers must end within the first {} bytes", HEAD_BYTES);
+                (BAD_REQUEST_PROBLEM.to_owned(), problem(400, "Bad Request", "header_too_large", &detail).to_string())
+            }
+            ApiError::Malformed(Malformed::BodyTooLarge) => {
+                let detail = format!("The body must be at most {} bytes", body_bytes());
+                let problem = problem(413, "Payload Too Large", "body_too_large", &detail);
+                (PAYLOAD_TOO_LARGE_PROBLEM.to_owned(), problem.to_string())
+            }
+            ApiError::Malformed(Malformed::TimedOut) => {
+                let detail = "The request wasn't sent whole in time";
+                let problem = problem(408, "Request Timeout", "request_timeout", detail);
+                (REQUEST_TIMEOUT_PROBLEM.to_owned(), problem.to_string())
+            }
+            ApiError::Malformed(Malformed::InvalidId(value, reason)) => {
+                let mut problem = problem(400, "Bad Request", "invalid_id", reason);
+                problem["value"] = value.into();
+                (BAD_REQUEST_PROBLEM.to_owned(), problem.to_string())
+            }
+            ApiError::Handshake(reason) =>
+                verification::problem(BAD_REQUEST_PROBLEM, 400, "Bad Request", "bad_websocket_handshake", reason),
+        }
+    }
+}
+
+// The RFC 7807 problem every ApiError is answered with, its code saying which
+fn problem(status: u16, title: &str, code: &str, detail: &str) -> serde_json::Value {
+    serde_json::json!({
+        "type": "about:blank",
+        "title": locale::title(status, title),
+        "status": status,
+        "detail": detail,
+        "code": code,
+    })
+}
+
+impl From<RepositoryError> for ApiError {
+    fn from(error: RepositoryError) -> Self {
+        ApiError::Db(error, "Database error")
+    }
+}
+
+impl From<postgres::Error> for ApiError {
+    fn from(error: postgres::Error) -> Self {
+        RepositoryError::from(error).into()
+    }
+}
+
+// Only what is written can fail that way, what is read being answered a 400
+impl From<serde_json::Error> for ApiError {
+    fn from(error: serde_json::Error) -> Self {
+        ApiError::Internal(format!("Error serializing the response: {}", error).into())
+    }
+}
+
+// A 500 as it is answered: what went wrong is logged and reported under the id of
+// the request, or a new one, with its causes, its SQLSTATE and a backtrace when
+// there are, and the response is a problem with the id, also in X-Error-Id. Only
+// with ERROR_DETAILS does it say what went wrong.
+pub(crate) fn logged_error(status_line: &str, content: &[u8]) -> (String, String) {
+    let error_id = request_id::current().unwrap_or_else(|| api_keys::generate()[..16].to_owned());
+    let message = redact::text(&String::from_utf8_lossy(content));
+    error_reports::report("error", &error_id, &message);
+    // Unless it was answered something else after all
+    let failure = FAILURE.take().filter(|failure| failure.answered.as_bytes() == content);
+    let mut logged = message.clone();
+    if let Some(failure) = &failure {
+        if !logged.contains(&failure.causes) {
+            logged.push_str(&format!(", caused by: {}", failure.causes));
+        }
+        if failure.backtrace.status() == BacktraceStatus::Captured {
+            logged.push_str(&format!("\nstack backtrace:\n{}", failure.backtrace));
+        }
+    }
+    match failure.and_then(|failure| failure.sqlstate) {
+        Some(sqlstate) => {
+            log::error!(error_id = error_id.as_str(), sqlstate = sqlstate.as_str(); "Error {}: {}", error_id, logged)
+        }
+        None => log::error!(error_id = error_id.as_str(); "Error {}: {}", error_id, logged),
+    }
+    error_id_response(status_line, &error_id, (!ids_only()).then_some(message.as_str()))
+}
+
+// From the panic hook, while the request is still entered: the id of the request,
+// or a new one, which the panic is logged and answered under
+pub(crate) fn panicked() -> String {
+    let error_id = request_id::current().unwrap_or_else(|| api_keys::generate()[..16].to_owned());
+    PANIC_ID.set(Some(error_id.clone()));
+    error_id
+}
+
+// Once the handler has unwound, the id the panic was logged under, a new one when
+// there was no panic hook to see it
+pub(crate) fn take_panic_id() -> String {
+    PANIC_ID.take().unwrap_or_else(|| api_keys::generate()[..16].to_owned())
+}
+
+// The 500 of a request whose handler panicked, with only the id of its error.
+// Written as it is, for what went wrong was logged and reported by the panic hook.
+pub(crate) fn write_panicked(stream: &mut impl Write, error_id: &str) -> io::Result<()> {
+    let (status_line, body) = error_id_response(INTERNAL_SERVER_ERROR, error_id, None);
+    stream.write_all(format!("{}{}", security_headers::added(&status_line), body).as_bytes())
+}
+
+// The problem of a 500: what went wrong, when it is told, or where it was logged
+fn error_id_response(status_line: &str, error_id: &str, detail: Option<&str>) -> (String, String) {
+    let mut head: Vec<&str> = status_line
+        .trim_end_matches("\r\n")
+        .split("\r\n")
+        .filter(|line| !line.to_ascii_lowercase().starts_with("content-type:"))
+        .collect();
+    head.insert(1, "Content-Type: application/problem+json");
+    let header = format!("X-Error-Id: {}", error_id);
+    head.insert(2, &header);
+    let body = serde_json::json!({
+        "type": "about:blank",
+        "title": locale::title(500, "Internal Server Error"),
+        "status": 500,
+        "detail": detail.map_or_else(|| format!("The error was logged as {}", error_id), str::to_owned),
+        "error_id": error_id,
+    });
+    (format!("{}\r\n\r\n", head.join("\r\n")), body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::with_header;
+    use crate::validation;
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    fn production_errors_only_answer_their_id() {
+        let status_line = "HTTP/1.1 500 INTERNAL SERVER ERROR\r\nContent-Type: application/json\r\nX-Api: 1\r\n\r\n";
+        let (status_line, body) = error_id_response(status_line, "0123456789abcdef", None);
+        assert_eq!(
+            status_line,
+            "HTTP/1.1 500 INTERNAL SERVER ERROR\r\nContent-Type: application/problem+json\r\n\
+                X-Error-Id: 0123456789abcdef\r\nX-Api: 1\r\n\r\n"
+        );
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!((body["status"].as_u64(), body["error_id"].as_str()), (Some(500), Some("0123456789abcdef")));
+        assert_eq!(body["detail"], "The error was logged as 0123456789abcdef");
+        let status_line = error_id_response(INTERNAL_SERVER_ERROR, "0", None).0;
+        let content_type = with_header(INTERNAL_SERVER_ERROR, "Content-Type: application/problem+json");
+        assert_eq!(status_line, with_header(&content_type, "X-Error-Id: 0"));
+
+        // Saying what, with ERROR_DETAILS
+        let body = error_id_response(INTERNAL_SERVER_ERROR, "0", Some("Database error: disk full")).1;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!((&body["detail"], &body["error_id"]), (&"Database error: disk full".into(), &"0".into()));
+    }
+
+    #[test]
+    fn errors_are_logged_under_the_request_id() {
+        let body = |(_, body): (String, String)| serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        let error_id = body(logged_error(INTERNAL_SERVER_ERROR, b"broken"))["error_id"].as_str().unwrap().to_owned();
+        assert_eq!(error_id.len(), 16);
+        let _request_id = request_id::enter("export-1".to_owned());
+        assert_eq!(body(logged_error(INTERNAL_SERVER_ERROR, b"broken"))["error_id"], "export-1");
+    }
+
+    #[test]
+    fn database_errors_are_logged_and_answered_without_the_users() {
+        let detail = "ERROR: duplicate key value violates unique constraint \"users_tenant_email_key\"\n\
+            DETAIL: Key (tenant_id, lower(email))=(default, ada@example.com) already exists.";
+        let error = RepositoryError::Db(io::Error::other(detail).into());
+        let logged = with_causes(&error);
+        let (status_line, body) = repository_error_response(error, "Error creating the user");
+        assert_eq!(status_line, INTERNAL_SERVER_ERROR);
+        for message in [logged, body] {
+            assert!(message.contains("=([redacted]) already exists."), "{}", message);
+            assert!(!message.contains("ada@example.com"), "{}", message);
+        }
+        let error = RepositoryError::Db(io::Error::other("no user ada@example.com").into());
+        assert_eq!(repository_error_response(error, "Error").1, "Error: no user a***@example.com");
+    }
+
+    #[test]
+    fn the_failures_of_the_database_have_a_status_of_their_own() {
+        let status = |error: RepositoryError| repository_error_response(error, "Error").0;
+        assert_eq!(status(RepositoryError::Overloaded), OVERLOADED_PROBLEM);
+        assert_eq!(status(RepositoryError::Timeout("canceling statement".into())), GATEWAY_TIMEOUT_PROBLEM);
+        assert_eq!(status(RepositoryError::Unavailable("connection refused".into())), SERVICE_UNAVAILABLE_PROBLEM);
+        assert_eq!(status(RepositoryError::Unsupported("Only with Postgres")), NOT_IMPLEMENTED_PROBLEM);
+        assert_eq!(status(RepositoryError::Conflict(Conflict::Concurrent)), CONFLICT_RETRY_PROBLEM);
+        // In whole seconds, rounded up
+        let status_line = status(RepositoryError::CircuitOpen(Duration::from_millis(1500)));
+        assert!(status_line.contains("\r\nRetry-After: 2\r\n"), "{}", status_line);
+        assert!(status_line.contains("\r\nContent-Type: application/problem+json\r\n"), "{}", status_line);
+        // The handlers answer those they expect themselves
+        assert_eq!(status(RepositoryError::NotFound), INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn each_error_of_the_handlers_has_its_status_and_body() {
+        let json = |body: &str| serde_json::from_str::<serde_json::Value>(body).unwrap();
+        let (status_line, body) = ApiError::NotFound(7).response();
+        assert_eq!(status_line, NOT_FOUND_PROBLEM);
+        let body = json(&body);
+        assert_eq!((&body["status"], &body["code"]), (&404.into(), &"user_not_found".into()), "{}", body);
+
+        let (status_line, body) = ApiError::Validation(vec![ValidationError::email_taken()]).response();
+        assert_eq!(status_line, UNPROCESSABLE_ENTITY_PROBLEM);
+        let body = json(&body);
+        assert_eq!((&body["errors"][0]["field"], &body["errors"][0]["code"]), (&"email".into(), &"taken".into()));
+        assert_eq!((&body["status"], &body["code"]), (&422.into(), &"validation_failed".into()), "{}", body);
+
+        let problem = |error: ApiError| {
+            let (status_line, body) = error.response();
+            let body = json(&body);
+            (status_line, body["status"].as_u64().unwrap(), body["code"].clone(), body["detail"].clone())
+        };
+        let reason = || "why".to_owned();
+        let conflict = (CONFLICT_PROBLEM.to_owned(), 409, "conflict".into(), "why".into());
+        assert_eq!(problem(ApiError::Conflict(reason())), conflict);
+        let bad_request = (BAD_REQUEST_PROBLEM.to_owned(), 400, "bad_request".into(), "why".into());
+        assert_eq!(problem(ApiError::BadRequest(reason())), bad_request);
+        let unprocessable = (UNPROCESSABLE_ENTITY_PROBLEM.to_owned(), 422, "unprocessable".into(), "why".into());
+        assert_eq!(problem(ApiError::Unprocessable(reason())), unprocessable);
+
+        // Where the body went wrong, as members of its problem
+        let error = serde_json::from_str::<serde_json::Value>("{\"name\": }").unwrap_err();
+        let (status_line, body) = ApiError::InvalidBody(validation::described("{\"name\": }", &error)).response();
+        let body = json(&body);
+        assert_eq!(status_line, BAD_REQUEST_PROBLEM);
+        assert_eq!((&body["status"], &body["code"], &body["line"]), (&400.into(), &"invalid_json".into(), &1.into()));
+        assert!(body["title"].is_string() && body["detail"].is_string() && body.get("error").is_none(), "{}", body);
+
+        // Those of the storage as repository_error_response answers them
+        let unavailable = ApiError::Db(RepositoryError::Unavailable("connection refused".into()), "Error");
+        let detail = "Database unavailable: connection refused";
+        let answered = (SERVICE_UNAVAILABLE_PROBLEM.to_owned(), 503, "database_unavailable".into(), detail.into());
+        assert_eq!(problem(unavailable), answered);
+        let (status_line, body) = ApiError::from(RepositoryError::Conflict(Conflict::EmailTaken)).response();
+        let body = json(&body);
+        assert_eq!((status_line.as_str(), &body["errors"][0]["code"]), (CONFLICT_PROBLEM, &"taken".into()));
+        assert_eq!((&body["status"], &body["code"]), (&409.into(), &"email_taken".into()));
+        let failed = ApiError::Db(RepositoryError::Db("deadlock detected".into()), "Error fetching user").response();
+        assert_eq!(failed, (INTERNAL_SERVER_ERROR.to_owned(), "Error fetching user: deadlock detected".to_owned()));
+
+        let internal = ApiError::Internal("no user ada@example.com".into()).response();
+        assert_eq!(internal, (INTERNAL_SERVER_ERROR.to_owned(), "no user a***@example.com".to_owned()));
+        let unwritable: ApiError = serde_json::from_str::<f64>("NaN").unwrap_err().into();
+        let (status_line, body) = unwritable.response();
+        assert_eq!(status_line, INTERNAL_SERVER_ERROR);
+        assert!(body.starts_with("Error serializing the response: "), "{}", body);
+    }
+}
@@ -0,0 +1,280 @@
+use chrono::{ DateTime, Utc };
+use postgres::{ Client, Transaction };
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+
+use crate::validation::{ self, NewUser };
+use crate::tenant::DEFAULT_TENANT;
+use crate::models::User;
+use crate::config::{ self, flag_from_env };
+use crate::{ encryption, outbox, tables };
+
+// Held while seeding, so that instances starting together don't seed at once
+const SEED_LOCK: i64 = 0x7275_7374_5f73_6564;
+
+const SEQUENCE_QUERY: &str =
+    "SELECT setval(pg_get_serial_sequence('{users}', 'id'), max(id)) FROM {users} HAVING count(*) > 0";
+
+const USAGE: &str = "usage: [--seed-file FILE] [--seed-on-conflict skip|upsert] [--seed-strict]";
+
+// What to do with a user of the file whose email is already taken
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OnConflict {
+    // Leave the user that has it as it is
+    #[default]
+    Skip,
+    // Give it the name of the file
+    Upsert,
+}
+
+impl OnConflict {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "skip" => Ok(OnConflict::Skip),
+            "upsert" => Ok(OnConflict::Upsert),
+            _ => Err(format!("the seed conflict mode must be skip or upsert, got {:?}", value)),
+        }
+    }
+}
+
+// Users to load at startup, after the migrations, for environments that need data
+// from their first boot. The options of the server win over SEED_FILE,
+// SEED_ON_CONFLICT and SEED_STRICT. Strict, an invalid entry aborts the whole seed
+// and the startup; otherwise it is reported and skipped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeedConfig {
+    pub path: String,
+    pub on_conflict: OnConflict,
+    pub strict: bool,
+}
+
+// SEED_FILE, SEED_ON_CONFLICT and SEED_STRICT, read with the rest of the settings
+// for the options to override
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SeedSettings {
+    pub path: Option<String>,
+    pub on_conflict: OnConflict,
+    pub strict: bool,
+}
+
+impl SeedSettings {
+    pub fn from_env() -> Result<Self, String> {
+        let path = config::var("SEED_FILE").ok().filter(|path| !path.is_empty());
+        let on_conflict = match config::var("SEED_ON_CONFLICT") {
+            Ok(value) => OnConflict::parse(&value)?,
+            Err(_) => OnConflict::Skip,
+        };
+        let strict = flag_from_env("SEED_STRICT", false)?;
+        Ok(SeedSettings { path, on_conflict, strict })
+    }
+}
+
+impl SeedConfig {
+    // None without a file to seed from
+    pub fn from_args(settings: &SeedSettings, args: &[String]) -> Result<Option<Self>, String> {
+        let SeedSettings { mut path, mut on_conflict, mut strict } = settings.clone();
+
+        let mut args = args.iter().map(String::as_str);
+        while let Some(arg) = args.next() {
+            match arg {
+                "--seed-file" => {
+                    path = Some(args.next().ok_or(USAGE)?.to_owned());
+                }
+                "--seed-on-conflict" => {
+                    on_conflict = OnConflict::parse(args.next().ok_or(USAGE)?)?;
+                }
+                "--seed-strict" => {
+                    strict = true;
+                }
+                _ => {
+                    return Err(USAGE.to_owned());
+                }
+            }
+        }
+
+        Ok(path.map(|path| SeedConfig { path, on_conflict, strict }))
+    }
+}
+
+// An entry of the file: the body of POST /users, with the id to give the user and
+// the time its user.created event says it was created at
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+    id: Option<i32>,
+    name: String,
+    email: String,
+    created_at: Option<DateTime<Utc>>,
+}
+
+// What a seed did with the entries of the file
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub inserted: u64,
+    pub updated: u64,
+    // Already there, or with the email of a user that is
+    pub unchanged: u64,
+    pub invalid: u64,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} inserted, {} updated, {} unchanged, {} invalid",
+            self.inserted,
+            self.updated,
+            self.unchanged,
+            self.invalid
+        )
+    }
+}
+
+// Load the file in one transaction, into the default tenant. It can be loaded again: a user whose email is
+// taken is skipped or updated, and never inserted twice. The entries that can't be
+// loaded are reported with their index, from 0.
+pub fn seed(client: &mut Client, config: &SeedConfig) -> Result<Summary, Box<dyn Error>> {
+    let content = fs::read_to_string(&config.path).map_err(|e| format!("can't read {}: {}", config.path, e))?;
+    let entries: Vec<Value> = serde_json::from_str(&content)
+        .map_err(|e| format!("{} is not a JSON array: {}", config.path, e))?;
+
+    let mut transaction = client.transaction()?;
+    transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&SEED_LOCK])?;
+
+    let mut summary = Summary::default();
+    let mut explicit_ids = false;
+    for (index, entry) in entries.into_iter().enumerate() {
+        let result = match serde_json::from_value::<Fixture>(entry).map_err(|e| e.to_string()).and_then(validate) {
+            Ok(fixture) => {
+                explicit_ids |= fixture.id.is_some();
+                load(&mut transaction, &fixture, config.on_conflict, &mut summary)?
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            if config.strict {
+                return Err(format!("entry {} of {}: {}, nothing was seeded", index, config.path, e).into());
+            }
+            log::warn!("Skipping entry {} of {}: {}", index, config.path, e);
+            summary.invalid += 1;
+        }
+    }
+
+    if explicit_ids {
+        transaction.batch_execute(tables::sql(SEQUENCE_QUERY))?;
+    }
+    transaction.commit()?;
+    Ok(summary)
+}
+
+fn validate(fixture: Fixture) -> Result<Fixture, String> {
+    let mut user = NewUser { name: fixture.name, email: fixture.email, password: None, password_hash: None };
+    let errors = validation::validate_fields(&mut user);
+    if let Some(error) = errors.first() {
+        return Err(format!("{} is invalid: {}", error.field, error.message));
+    }
+    if fixture.id.is_some_and(|id| id < 1) {
+        return Err("id must be at least 1".to_owned());
+    }
+    Ok(Fixture { name: user.name, email: user.email, ..fixture })
+}
+
+// Errors about the entry are Ok(Err), the transaction goes on without it; those
+// of the database end it
+fn load(
+    transaction: &mut Transaction,
+    fixture: &Fixture,
+    on_conflict: OnConflict,
+    summary: &mut Summary
+) -> Result<Result<(), String>, postgres::Error> {
+    let existing = transaction.query_opt(
+        tables::sql(
+            "SELECT id, name FROM {users}
+            WHERE tenant_id = $2 AND (email_hash = $3 OR (email_hash IS NULL AND lower(email) = lower($1)))"
+        ),
+        &[&fixture.email, &DEFAULT_TENANT, &encryption::lookup_hash(&fixture.email)]
+    )?;
+    if let Some(row) = existing {
+        let (id, name): (i32, String) = (row.get(0), row.get(1));
+        if fixture.id.is_some_and(|wanted| wanted != id) {
+            return Ok(Err(format!("the email belongs to user {}", id)));
+        }
+        if on_conflict == OnConflict::Skip || name == fixture.name {
+            summary.unchanged += 1;
+            return Ok(Ok(()));
+        }
+
+        transaction.execute(tables::sql("UPDATE {users} SET name = $2 WHERE id = $1"), &[&id, &fixture.name])?;
+        let user = User::new(Some(id), fixture.name.clone(), fixture.email.clone(), false);
+        outbox::enqueue(transaction, DEFAULT_TENANT, "user.updated", &serde_json::to_value(&user).unwrap())?;
+        summary.updated += 1;
+        return Ok(Ok(()));
+    }
+
+    let (email, email_hash) = encryption::seal_email(&fixture.email);
+    let id: i32 = match fixture.id {
+        Some(id) => {
+            let taken = transaction.query_opt(tables::sql("SELECT 1 FROM {users} WHERE id = $1"), &[&id])?;
+            if taken.is_some() {
+                return Ok(Err(format!("id {} belongs to another user", id)));
+            }
+            transaction.execute(
+                tables::sql("INSERT INTO {users} (id, name, email, email_hash) VALUES ($1, $2, $3, $4)"),
+                &[&id, &fixture.name, &email, &email_hash]
+            )?;
+            id
+        }
+        None => {
+            let row = transaction.query_one(
+                tables::sql("INSERT INTO {users} (name, email, email_hash) VALUES ($1, $2, $3) RETURNING id"),
+                &[&fixture.name, &email, &email_hash]
+            )?;
+            row.get(0)
+        }
+    };
+    let user = User::new(Some(id), fixture.name.clone(), fixture.email.clone(), false);
+    let event = outbox::enqueue(transaction, DEFAULT_TENANT, "user.created", &serde_json::to_value(&user).unwrap())?;
+    if let Some(created_at) = fixture.created_at {
+        transaction.execute(
+            tables::sql("UPDATE {events_outbox} SET created_at = $2 WHERE id = $1"),
+            &[&event, &created_at]
+        )?;
+    }
+    summary.inserted += 1;
+    Ok(Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn options_are_read_from_the_arguments() {
+        let defaults = SeedSettings::default();
+        let config = SeedConfig::from_args(
+            &defaults,
+            &args(&["--seed-file", "demo.json", "--seed-on-conflict", "upsert", "--seed-strict"])
+        );
+        let expected = SeedConfig { path: "demo.json".to_owned(), on_conflict: OnConflict::Upsert, strict: true };
+        assert_eq!(config, Ok(Some(expected)));
+
+        assert_eq!(SeedConfig::from_args(&defaults, &args(&["--seed-file"])), Err(USAGE.to_owned()));
+        assert_eq!(SeedConfig::from_args(&defaults, &args(&["--seed"])), Err(USAGE.to_owned()));
+        let mode = ["--seed-file", "demo.json", "--seed-on-conflict", "replace"];
+        let mode = SeedConfig::from_args(&defaults, &args(&mode));
+        assert_eq!(mode, Err("the seed conflict mode must be skip or upsert, got \"replace\"".to_owned()));
+        assert_eq!(SeedConfig::from_args(&defaults, &[]), Ok(None));
+
+        // Over the settings
+        let settings = SeedSettings { path: Some("seed.json".to_owned()), on_conflict: OnConflict::Skip, strict: true };
+        let config = SeedConfig::from_args(&settings, &args(&["--seed-on-conflict", "upsert"]));
+        let expected = SeedConfig { path: "seed.json".to_owned(), on_conflict: OnConflict::Upsert, strict: true };
+        assert_eq!(config, Ok(Some(expected)));
+    }
+}
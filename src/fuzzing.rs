@@ -0,0 +1,191 @@
+// What the fuzz targets of fuzz/ run on the bytes they are given: the readers of
+// a request, on as much of it as the server reads, and the decoder of chunked
+// bodies. They must not panic, and what they read must agree with the bytes,
+// which is asserted. The tests replay fuzz/regressions, the inputs that once
+// broke them, and requests made at random, for the builds without cargo-fuzz.
+
+use crate::http::{
+    decode_query_value, get_body, get_header, get_path, get_query, get_query_param, get_segments, malformed, parse_id,
+    request_bytes, HEAD_BYTES
+};
+use crate::{ locale, oidc, route_metrics, route_timeout };
+
+pub fn request(bytes: &[u8]) {
+    let bytes = &bytes[..bytes.len().min(request_bytes())];
+    let request = String::from_utf8_lossy(bytes);
+    let method = request.split_whitespace().next().unwrap_or_default();
+    let target = request.split_whitespace().nth(1).unwrap_or_default();
+
+    // Routed only with a method and a target
+    if malformed(&request, bytes.len()).is_none() {
+        assert!(!method.is_empty() && !target.is_empty(), "{:?}", request);
+    }
+
+    let path = get_path(&request);
+    assert!(target.contains(path), "{:?} isn't in {:?}", path, target);
+    let segments = get_segments(&request);
+    for segment in &segments {
+        assert!(!segment.is_empty() && !segment.contains('/'), "{:?}", segment);
+        // An id has one way of being written
+        if let Ok(id) = parse_id(segment) {
+            assert_eq!(id.to_string(), *segment);
+        }
+    }
+    let (_, template) = route_metrics::route(method, &segments).labels();
+    assert!(template == "unmatched" || template.split('/').filter(|each| !each.is_empty()).count() == segments.len());
+    route_timeout::route(method, &segments);
+
+    let head = request.split_once("\r\n\r\n").map_or(&*request, |(head, _)| head);
+    for name in head.split("\r\n").skip(1).filter_map(|line| line.split_once(':')).map(|(name, _)| name.trim()) {
+        let value = get_header(&request, name).unwrap_or_else(|| panic!("{:?} is a header", name));
+        assert!(value == value.trim() && !value.contains("\r\n"), "{:?}", value);
+    }
+    if let Some(accept_language) = get_header(&request, "Accept-Language") {
+        locale::negotiate(accept_language);
+    }
+
+    let query = get_query(&request);
+    assert!(target.contains(query));
+    for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        let first = get_query_param(&request, name).unwrap_or_else(|| panic!("{:?} is a parameter", name));
+        assert!(query.contains(first));
+        // Each byte decoded at most, or replaced when it isn't UTF-8
+        assert!(decode_query_value(value).len() <= value.len() * 3, "{:?}", value);
+    }
+
+    // All that follows the head, or nothing
+    let body = get_body(&request);
+    match request.find("\r\n\r\n") {
+        Some(end) => assert_eq!(body, &request[end + 4..]),
+        None => assert_eq!(body, ""),
+    }
+}
+
+pub fn chunked(bytes: &[u8]) {
+    let Some(decoded) = oidc::dechunked(bytes) else {
+        return;
+    };
+    // Never more than was sent, and the same once chunked again
+    assert!(decoded.len() <= bytes.len());
+    let mut chunked = Vec::new();
+    for chunk in decoded.chunks(7) {
+        chunked.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        chunked.extend_from_slice(chunk);
+        chunked.extend_from_slice(b"\r\n");
+    }
+    chunked.extend_from_slice(b"0\r\n\r\n");
+    assert_eq!(oidc::dechunked(&chunked), Some(decoded));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::SplitMix64;
+    use std::fs;
+    use std::panic::{ self, AssertUnwindSafe };
+    use std::path::Path;
+
+    // What the corruptions put in, those that mean something to the readers
+    const SPECIAL: [&[u8]; 12] =
+        [b"\r\n", b"\r\n\r\n", b"\r", b"\n", b"/", b"//", b"?", b"&", b"=", b"%", b":", b"\xff\xfe"];
+
+    // Run one of the targets on each input, saying which failed and how to keep it
+    fn run(target: fn(&[u8]), inputs: impl IntoIterator<Item = (String, Vec<u8>)>) {
+        for (name, input) in inputs {
+            if panic::catch_unwind(AssertUnwindSafe(|| target(&input))).is_err() {
+                panic!("{} failed on {:?}, which belongs in fuzz/regressions", name, String::from_utf8_lossy(&input));
+            }
+        }
+    }
+
+    fn regressions(target: &str) -> Vec<(String, Vec<u8>)> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions").join(target);
+        let mut inputs: Vec<(String, Vec<u8>)> = fs::read_dir(&dir)
+            .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+            .map(|entry| entry.unwrap().path())
+            .map(|path| (path.display().to_string(), fs::read(&path).unwrap()))
+            .collect();
+        inputs.sort();
+        inputs
+    }
+
+    // A request as a client could send it, to one of the routes
+    fn valid_request(rng: &mut SplitMix64) -> Vec<u8> {
+        let (method, template) = route_metrics::ROUTES[rng.next() as usize % route_metrics::ROUTES.len()];
+        let path = template.replace("{id}", &(rng.next() % 100_000).to_string());
+        let query = ["", "?limit=5", "?after_id=7&limit=10", "?email=ada%40example.com&name_contains=Ada+L"];
+        let headers = [
+            "Accept-Language: fr-CA, en;q=0.8\r\n",
+            "X-Api-Key: s3cret\r\n",
+            "traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01\r\n",
+            "Content-Type: application/json\r\n",
+        ];
+        let mut request =
+            format!("{} {}{} HTTP/1.1\r\nHost: localhost\r\n", method, path, query[rng.next() as usize % query.len()]);
+        for header in headers.iter().filter(|_| rng.next().is_multiple_of(2)) {
+            request.push_str(header);
+        }
+        request.push_str("\r\n");
+        if method != "GET" {
+            request.push_str(r#"{"name": "Ada", "email": "ada@example.com"}"#);
+        }
+        request.into_bytes()
+    }
+
+    // With a few bytes changed, put in, taken out or repeated
+    fn corrupted(rng: &mut SplitMix64, mut bytes: Vec<u8>) -> Vec<u8> {
+        for _ in 0..1 + rng.next() % 4 {
+            let at = rng.next() as usize % (bytes.len() + 1);
+            match rng.next() % 5 {
+                0 if at < bytes.len() => bytes[at] = rng.next() as u8,
+                1 => {
+                    let special = SPECIAL[rng.next() as usize % SPECIAL.len()];
+                    bytes.splice(at..at, special.iter().copied());
+                }
+                2 => bytes.truncate(at),
+                3 => {
+                    let end = (at + 1 + rng.next() as usize % 64).min(bytes.len());
+                    let repeated = bytes[at..end].to_vec();
+                    bytes.splice(at..at, repeated);
+                }
+                _ => {
+                    let random: Vec<u8> = (0..rng.next() % 8).map(|_| rng.next() as u8).collect();
+                    bytes.splice(at..at, random);
+                }
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn the_regressions_parse_without_panicking() {
+        run(request, regressions("request"));
+        run(chunked, regressions("chunked"));
+    }
+
+    #[test]
+    fn random_requests_parse_without_panicking() {
+        let mut rng = SplitMix64(0x5eed);
+        let valid = (0..500).map(|index| (format!("valid request {}", index), valid_request(&mut rng)));
+        let valid: Vec<(String, Vec<u8>)> = valid.collect();
+        run(request, valid.clone());
+        let corruptions = valid.into_iter().flat_map(|(name, bytes)| {
+            let mut rng = SplitMix64(rng.next());
+            (0..8).map(move |each| (format!("{} corrupted {}", name, each), corrupted(&mut rng, bytes.clone())))
+        });
+        run(request, corruptions);
+        let arbitrary = (0..2000).map(|index| {
+            let length = rng.next() as usize % (2 * HEAD_BYTES);
+            (format!("arbitrary bytes {}", index), (0..length).map(|_| rng.next() as u8).collect())
+        });
+        run(request, arbitrary.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn random_chunked_bodies_decode_without_panicking() {
+        let mut rng = SplitMix64(0xc4a2);
+        let valid = b"4\r\n{\"a\"\r\n3;ext=1\r\n:1}\r\n10\r\n0123456789abcdef\r\n0\r\n\r\n".to_vec();
+        let inputs = (0..2000).map(|index| (format!("chunked body {}", index), corrupted(&mut rng, valid.clone())));
+        run(chunked, inputs.collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,658 @@
+// What can fail in a resolver is answered as an error of the response, none of it unwrapped
+#![cfg_attr(not(test), deny(clippy::unwrap_used, clippy::expect_used))]
+
+use serde::{ Serialize, Serializer };
+use serde_json::{ json, Map, Value as Json };
+use std::sync::RwLock;
+
+use crate::auth::{ self, Role, Scope };
+use crate::config::{ flag_from_env, number_from_env };
+use crate::errors::{ self, ApiError };
+use crate::handlers::{ user_filter, UserResource };
+use crate::http::{ get_body, serialized };
+use crate::models::User;
+use crate::profile::Profile;
+use crate::repository::{ UserFilter, UserRepository };
+use crate::resource::{ self, CrudResource, Stored };
+use crate::validation::{ self, NewUser };
+use crate::pagination;
+
+mod parse;
+
+use parse::{ Field, Kind, Operation, Value };
+
+const DEFAULT_MAX_DEPTH: usize = 6;
+const DEFAULT_MAX_COMPLEXITY: u64 = 5000;
+
+// The media type of GraphQL over HTTP, which JSON_CASE leaves alone
+const OK_GRAPHQL: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/graphql-response+json\r\n\r\n";
+const BAD_REQUEST_GRAPHQL: &str =
+    "HTTP/1.1 400 BAD REQUEST\r\nContent-Type: application/graphql-response+json\r\n\r\n";
+// GraphiQL loads its scripts and styles from unpkg, and only sends its requests here
+const GRAPHIQL_RESPONSE: &str = concat!(
+    "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n",
+    "Content-Security-Policy: default-src 'none'; script-src https://unpkg.com 'unsafe-inline'; ",
+    "style-src https://unpkg.com 'unsafe-inline'; font-src https://unpkg.com data:; img-src data:; ",
+    "connect-src 'self'; frame-ancestors 'none'\r\n\r\n"
+);
+const GRAPHIQL: &str = include_str!("graphql/graphiql.html");
+
+static CONFIG: RwLock<GraphqlConfig> = RwLock::new(GraphqlConfig {
+    max_depth: DEFAULT_MAX_DEPTH,
+    max_complexity: DEFAULT_MAX_COMPLEXITY,
+    graphiql: false,
+});
+
+// POST /graphql answers the schema below, the users as GET /users/{id} and GET
+// /users do and their changes as the other routes of /users make them, each field
+// authorized as its route is. GRAPHQL_MAX_DEPTH is how deeply a document may nest
+// its fields, those of the operation being 1 deep, and GRAPHQL_MAX_COMPLEXITY how
+// many fields it may ask for, those under users counting once for each of its
+// limit. GRAPHIQL serves GraphiQL at GET /graphql, by default with the dev profile.
+//
+//   type Query { user(id: Int!): User, users(limit: Int, offset: Int, filter: UserFilter): [User!] }
+//   type Mutation { createUser(input: UserInput!): User, updateUser(id: Int!, input: UserInput!): User,
+//                   deleteUser(id: Int!): Int }
+//   type User { id: Int, name: String!, email: String!, anonymized: Boolean!, emailDisplay: String,
+//               verifiedAt: String }
+//   input UserFilter { email: String, nameContains: String }
+//   input UserInput { name: String!, email: String!, password: String }
+//
+// The schema isn't introspectable, nor are fragments, directives and subscriptions
+// served.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GraphqlConfig {
+    pub max_depth: usize,
+    pub max_complexity: u64,
+    pub graphiql: bool,
+}
+
+impl GraphqlConfig {
+    pub fn from_env(profile: Profile) -> Result<Self, String> {
+        let max_depth = number_from_env("GRAPHQL_MAX_DEPTH", DEFAULT_MAX_DEPTH as u64)? as usize;
+        let max_complexity = number_from_env("GRAPHQL_MAX_COMPLEXITY", DEFAULT_MAX_COMPLEXITY)?;
+        if max_depth == 0 || max_complexity == 0 {
+            return Err("GRAPHQL_MAX_DEPTH and GRAPHQL_MAX_COMPLEXITY must be at least 1".to_owned());
+        }
+        let graphiql = flag_from_env("GRAPHIQL", profile == Profile::Dev)?;
+        Ok(GraphqlConfig { max_depth, max_complexity, graphiql })
+    }
+}
+
+// At startup
+pub fn init(config: GraphqlConfig) {
+    if let Ok(mut current) = CONFIG.write() {
+        *current = config;
+    }
+}
+
+fn config() -> GraphqlConfig {
+    CONFIG.read().map_or(
+        GraphqlConfig { max_depth: DEFAULT_MAX_DEPTH, max_complexity: DEFAULT_MAX_COMPLEXITY, graphiql: false },
+        |config| *config
+    )
+}
+
+// Whether GET /graphql serves GraphiQL
+pub(crate) fn graphiql() -> bool {
+    config().graphiql
+}
+
+// GET /graphql, with GRAPHIQL=true. It doesn't ask for the schema, which isn't
+// introspectable.
+pub(crate) fn handle_graphiql_request() -> (String, String) {
+    (GRAPHIQL_RESPONSE.to_owned(), GRAPHIQL.to_owned())
+}
+
+// The types of the schema that have fields
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Type {
+    Query,
+    Mutation,
+    User,
+}
+
+// A field of a type: the names of its arguments, those required ending with a !,
+// and the type of what it answers, None for a scalar
+struct Schema {
+    name: &'static str,
+    arguments: &'static [&'static str],
+    of: Option<Type>,
+}
+
+const fn scalar(name: &'static str) -> Schema {
+    Schema { name, arguments: &[], of: None }
+}
+
+const QUERY: &[Schema] = &[
+    Schema { name: "user", arguments: &["id!"], of: Some(Type::User) },
+    Schema { name: "users", arguments: &["limit", "offset", "filter"], of: Some(Type::User) },
+];
+const MUTATION: &[Schema] = &[
+    Schema { name: "createUser", arguments: &["input!"], of: Some(Type::User) },
+    Schema { name: "updateUser", arguments: &["id!", "input!"], of: Some(Type::User) },
+    Schema { name: "deleteUser", arguments: &["id!"], of: None },
+];
+const USER: &[Schema] = &[
+    scalar("id"),
+    scalar("name"),
+    scalar("email"),
+    scalar("anonymized"),
+    scalar("emailDisplay"),
+    scalar("verifiedAt"),
+];
+
+impl Type {
+    fn name(self) -> &'static str {
+        match self {
+            Type::Query => "Query",
+            Type::Mutation => "Mutation",
+            Type::User => "User",
+        }
+    }
+
+    fn fields(self) -> &'static [Schema] {
+        match self {
+            Type::Query => QUERY,
+            Type::Mutation => MUTATION,
+            Type::User => USER,
+        }
+    }
+}
+
+// The body of POST /graphql
+#[derive(Deserialize)]
+struct Request {
+    query: String,
+    #[serde(default)]
+    variables: Option<Map<String, Json>>,
+    #[serde(default, rename = "operationName")]
+    operation_name: Option<String>,
+}
+
+// An error of the response, with where it was in the document and the field it
+// was answered for, and its code in extensions.code
+struct GraphqlError {
+    message: String,
+    code: &'static str,
+    location: Option<parse::Location>,
+    path: Option<String>,
+    extensions: Map<String, Json>,
+}
+
+impl GraphqlError {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        GraphqlError { message: message.into(), code, location: None, path: None, extensions: Map::new() }
+    }
+
+    fn at(mut self, field: &Field) -> Self {
+        self.location = Some(field.location);
+        self
+    }
+
+    fn into_json(self) -> Json {
+        let mut extensions = self.extensions;
+        extensions.insert("code".to_owned(), self.code.into());
+        let mut error = Map::new();
+        error.insert("message".to_owned(), self.message.into());
+        if let Some(location) = self.location {
+            error.insert("locations".to_owned(), json!([{ "line": location.line, "column": location.column }]));
+        }
+        if let Some(path) = self.path {
+            error.insert("path".to_owned(), json!([path]));
+        }
+        error.insert("extensions".to_owned(), extensions.into());
+        error.into()
+    }
+}
+
+fn invalid(message: String) -> GraphqlError {
+    GraphqlError::new("GRAPHQL_VALIDATION_FAILED", message)
+}
+
+fn bad_input(message: String) -> GraphqlError {
+    GraphqlError::new("BAD_USER_INPUT", message)
+}
+
+impl From<ApiError> for GraphqlError {
+    fn from(error: ApiError) -> Self {
+        answered(error.response())
+    }
+}
+
+// The error of what the route would have answered, by its status, a 500 logged
+// under its error id as it would be
+fn answered((status_line, body): (String, String)) -> GraphqlError {
+    let status = status_line.get(9..12).and_then(|status| status.parse().ok()).unwrap_or(500);
+    let body = match status {
+        500 => errors::logged_error(&status_line, body.as_bytes()).1,
+        _ => body,
+    };
+    let code = match status {
+        400 | 422 => "BAD_USER_INPUT",
+        401 => "UNAUTHENTICATED",
+        403 => "FORBIDDEN",
+        404 => "NOT_FOUND",
+        409 => "CONFLICT",
+        502..=504 => "SERVICE_UNAVAILABLE",
+        _ => "INTERNAL_SERVER_ERROR",
+    };
+    // A problem, the errors of a validation, or what a 409 says
+    let problem = serde_json::from_str::<Json>(&body).ok().filter(Json::is_object).unwrap_or_default();
+    let mut error = GraphqlError::new(code, body.clone());
+    if let Some(message) = problem["detail"].as_str().or(problem["error"]["message"].as_str()) {
+        error.message = message.to_owned();
+    }
+    if let Some(errors) = problem["errors"].as_array() {
+        let messages: Vec<&str> = errors.iter().filter_map(|error| error["message"].as_str()).collect();
+        error.message = messages.join("; ");
+        error.extensions.insert("errors".to_owned(), errors.clone().into());
+    }
+    if let Some(error_id) = problem["error_id"].as_str() {
+        error.extensions.insert("errorId".to_owned(), error_id.into());
+    }
+    error
+}
+
+// A value of the data, the fields of its objects in the order they were asked for
+enum Output {
+    Scalar(Json),
+    List(Vec<Output>),
+    Object(Vec<(String, Output)>),
+}
+
+impl Serialize for Output {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Output::Scalar(value) => value.serialize(serializer),
+            Output::List(items) => serializer.collect_seq(items),
+            Output::Object(fields) => serializer.collect_map(fields.iter().map(|(key, value)| (key, value))),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Response {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<Json>,
+    data: Output,
+}
+
+// POST /graphql. A document that can't be executed is answered 400 with why, and
+// one that is 200 with the data of its fields, those that failed being null with
+// their error.
+pub(crate) fn handle_graphql_request(repository: &dyn UserRepository, request: &str) -> (String, String) {
+    match prepared(request) {
+        Ok((operation, variables)) => {
+            let (data, errors) = execute(repository, &operation, &variables);
+            (OK_GRAPHQL.to_owned(), serialized(&Response { errors, data }))
+        }
+        Err(error) => (BAD_REQUEST_GRAPHQL.to_owned(), json!({ "errors": [error.into_json()] }).to_string()),
+    }
+}
+
+// Whether the request is a mutation, to be limited and turned away as the writes
+// are. What doesn't parse isn't, as it is refused before anything is done.
+pub(crate) fn mutates(request: &str) -> bool {
+    let Ok(body) = serde_json::from_str::<Request>(get_body(request)) else {
+        return false;
+    };
+    let Ok(operations) = parse::parse(&body.query) else {
+        return false;
+    };
+    operations
+        .iter()
+        .filter(|operation| body.operation_name.is_none() || operation.name == body.operation_name)
+        .any(|operation| operation.kind == Kind::Mutation)
+}
+
+// The operation of the request and its variables, once the document was checked
+// against the schema and its limits
+fn prepared(request: &str) -> Result<(Operation, Map<String, Json>), GraphqlError> {
+    let body: Request = serde_json::from_str(get_body(request)).map_err(|e| {
+        let message = format!("The body must be a JSON object with the query of the document: {}", e);
+        GraphqlError::new("BAD_REQUEST", message)
+    })?;
+    let operations = parse::parse(&body.query).map_err(|e| GraphqlError {
+        location: Some(e.location),
+        ..GraphqlError::new("GRAPHQL_PARSE_FAILED", e.to_string())
+    })?;
+    let operation = selected(operations, body.operation_name.as_deref())?;
+    let variables = coerced(&operation, body.variables.unwrap_or_default())?;
+
+    let root = match operation.kind {
+        Kind::Query => Type::Query,
+        Kind::Mutation => Type::Mutation,
+    };
+    checked(&operation.selection, root, &variables)?;
+    let config = config();
+    let depth = depth(&operation.selection);
+    if depth > config.max_depth {
+        let message =
+            format!("The document is {} fields deep, over the {} of GRAPHQL_MAX_DEPTH", depth, config.max_depth);
+        return Err(GraphqlError::new("QUERY_TOO_DEEP", message));
+    }
+    let complexity = complexity(&operation.selection, &variables);
+    if complexity > config.max_complexity {
+        let message = format!(
+            "The document has a complexity of {}, over the {} of GRAPHQL_MAX_COMPLEXITY",
+            complexity, config.max_complexity
+        );
+        return Err(GraphqlError::new("QUERY_TOO_COMPLEX", message));
+    }
+    Ok((operation, variables))
+}
+
+// The operation named by operationName, or the only one of the document
+fn selected(mut operations: Vec<Operation>, name: Option<&str>) -> Result<Operation, GraphqlError> {
+    let index = match name {
+        Some(name) => operations
+            .iter()
+            .position(|operation| operation.name.as_deref() == Some(name))
+            .ok_or_else(|| invalid(format!("Unknown operation named \"{}\"", name)))?,
+        None if operations.len() == 1 => 0,
+        None => return Err(invalid("Must provide operationName if the document has several operations".to_owned())),
+    };
+    Ok(operations.swap_remove(index))
+}
+
+// The variables the operation declares, as they were given or else defaulted. The
+// others are left out.
+fn coerced(operation: &Operation, mut given: Map<String, Json>) -> Result<Map<String, Json>, GraphqlError> {
+    let mut variables = Map::new();
+    for variable in &operation.variables {
+        let value = match given.remove(&variable.name) {
+            Some(value) => value,
+            None => variable.default.as_ref().map_or(Json::Null, |default| resolved(default, &Map::new())),
+        };
+        if value.is_null() && variable.required() {
+            return Err(bad_input(format!(
+                "Variable \"${}\" of required type \"{}\" was not provided",
+                variable.name, variable.type_name
+            )));
+        }
+        variables.insert(variable.name.clone(), value);
+    }
+    Ok(variables)
+}
+
+// What of the selection the schema doesn't have, or asks for the same key twice
+// differently
+fn checked(selection: &[Field], of: Type, variables: &Map<String, Json>) -> Result<(), GraphqlError> {
+    for (at, field) in selection.iter().enumerate() {
+        let refused = |message: String| Err(invalid(message).at(field));
+        if selection[..at].iter().any(|other| other.key() == field.key() && !other.same_as(field)) {
+            return refused(format!("Fields \"{}\" conflict as they ask for different things", field.key()));
+        }
+        if field.name == "__typename" {
+            if !field.arguments.is_empty() || !field.selection.is_empty() {
+                return refused("Field \"__typename\" has neither arguments nor a selection".to_owned());
+            }
+            continue;
+        }
+        let Some(schema) = of.fields().iter().find(|schema| schema.name == field.name) else {
+            return refused(format!("Cannot query field \"{}\" on type \"{}\"", field.name, of.name()));
+        };
+        for (at, (name, value)) in field.arguments.iter().enumerate() {
+            if !schema.arguments.iter().any(|argument| argument.trim_end_matches('!') == name) {
+                return refused(format!("Unknown argument \"{}\" on field \"{}.{}\"", name, of.name(), field.name));
+            }
+            if field.arguments[..at].iter().any(|(other, _)| other == name) {
+                return refused(format!("There can be only one argument named \"{}\"", name));
+            }
+            if let Some(variable) = undefined(value, variables) {
+                return refused(format!("Variable \"${}\" is not defined", variable));
+            }
+        }
+        for required in schema.arguments.iter().filter_map(|argument| argument.strip_suffix('!')) {
+            if !field.arguments.iter().any(|(name, _)| name == required) {
+                return refused(format!("Field \"{}\" argument \"{}\" is required", field.name, required));
+            }
+        }
+        match (schema.of, field.selection.is_empty()) {
+            (Some(object), true) => {
+                let message = format!("Field \"{}\" of type \"{}\" must have a selection", field.name, object.name());
+                return refused(message);
+            }
+            (None, false) => return refused(format!("Field \"{}\" is a scalar, without a selection", field.name)),
+            (Some(object), false) => checked(&field.selection, object, variables)?,
+            (None, true) => {}
+        }
+    }
+    Ok(())
+}
+
+// A variable of the value the operation doesn't declare
+fn undefined<'a>(value: &'a Value, variables: &Map<String, Json>) -> Option<&'a str> {
+    match value {
+        Value::Variable(name) => Some(name.as_str()).filter(|name| !variables.contains_key(*name)),
+        Value::List(items) => items.iter().find_map(|item| undefined(item, variables)),
+        Value::Object(fields) => fields.iter().find_map(|(_, value)| undefined(value, variables)),
+        _ => None,
+    }
+}
+
+fn depth(selection: &[Field]) -> usize {
+    selection.iter().map(|field| 1 + depth(&field.selection)).max().unwrap_or(0)
+}
+
+// A point for each field, those under users once for each of its limit
+fn complexity(selection: &[Field], variables: &Map<String, Json>) -> u64 {
+    selection
+        .iter()
+        .map(|field| {
+            let times = match field.name.as_str() {
+                "users" => argument(field, "limit", variables)
+                    .as_i64()
+                    .map_or(pagination::limit_argument(None), |limit| pagination::limit_argument(Some(limit)))
+                    .map_or(1, |limit| limit.value as u64),
+                _ => 1,
+            };
+            times.saturating_mul(complexity(&field.selection, variables)).saturating_add(1)
+        })
+        .fold(0, u64::saturating_add)
+}
+
+// The value of a literal, with the variables it uses
+fn resolved(value: &Value, variables: &Map<String, Json>) -> Json {
+    match value {
+        Value::Variable(name) => variables.get(name).cloned().unwrap_or(Json::Null),
+        Value::Int(value) => (*value).into(),
+        Value::Float(value) => (*value).into(),
+        Value::String(value) | Value::Enum(value) => value.as_str().into(),
+        Value::Boolean(value) => (*value).into(),
+        Value::Null => Json::Null,
+        Value::List(items) => items.iter().map(|item| resolved(item, variables)).collect(),
+        Value::Object(fields) => {
+            fields.iter().map(|(name, value)| (name.clone(), resolved(value, variables))).collect::<Map<_, _>>().into()
+        }
+    }
+}
+
+// The fields of the operation, each with its data or else null and its error
+fn execute(
+    repository: &dyn UserRepository,
+    operation: &Operation,
+    variables: &Map<String, Json>
+) -> (Output, Vec<Json>) {
+    let users = UserResource::new(repository);
+    let (mut data, mut errors) = (Vec::new(), Vec::new());
+    for field in &operation.selection {
+        // Asked for twice, it is answered once, a mutation made once
+        if data.iter().any(|(key, _)| key == field.key()) {
+            continue;
+        }
+        let output = match (operation.kind, field.name.as_str()) {
+            (Kind::Query, "__typename") => Ok(Output::Scalar("Query".into())),
+            (Kind::Mutation, "__typename") => Ok(Output::Scalar("Mutation".into())),
+            (Kind::Query, "user") => user(&users, field, variables),
+            (Kind::Query, "users") => list(&users, field, variables),
+            (Kind::Mutation, "createUser") => create_user(&users, field, variables),
+            (Kind::Mutation, "updateUser") => update_user(&users, field, variables),
+            (Kind::Mutation, "deleteUser") => delete_user(&users, field, variables),
+            // Refused by checked
+            _ => Err(invalid(format!("Cannot query field \"{}\"", field.name))),
+        };
+        let output = output.unwrap_or_else(|error| {
+            errors.push(GraphqlError { path: Some(field.key().to_owned()), ..error.at(field) }.into_json());
+            Output::Scalar(Json::Null)
+        });
+        data.push((field.key().to_owned(), output));
+    }
+    (Output::Object(data), errors)
+}
+
+fn authorized(required: Role, scope: Scope, field: &Field) -> Result<(), GraphqlError> {
+    auth::authorize_entered(required, Some(scope), &field.name).map_err(answered)
+}
+
+fn user(users: &UserResource, field: &Field, variables: &Map<String, Json>) -> Result<Output, GraphqlError> {
+    authorized(Role::Reader, Scope::UsersRead, field)?;
+    let id = id_argument(field, variables)?;
+    auth::owner_check(id, &field.name).map_err(answered)?;
+    Ok(project(&users.find(id)?, &field.selection))
+}
+
+// The users as GET /users lists them, from the offset on
+fn list(users: &UserResource, field: &Field, variables: &Map<String, Json>) -> Result<Output, GraphqlError> {
+    authorized(Role::Reader, Scope::UsersRead, field)?;
+    let own = auth::own_list().map_err(answered)?;
+    let limit = int_argument(field, "limit", variables)?.map(i64::from);
+    let limit = pagination::limit_argument(limit).map_err(bad_input)?.value;
+    let offset = match int_argument(field, "offset", variables)? {
+        Some(offset) => {
+            usize::try_from(offset).map_err(|_| bad_input(format!("offset must be at least 0, got {}", offset)))?
+        }
+        None => 0,
+    };
+    let filter = filter_argument(field, variables)?;
+
+    let (mut listed, mut skipped) = (Vec::new(), 0);
+    let each = &mut |user: User| {
+        if own.is_some_and(|own| user.id != Some(own)) {
+            return true;
+        }
+        match skipped < offset {
+            true => skipped += 1,
+            false => listed.push(project(&user, &field.selection)),
+        }
+        listed.len() < limit
+    };
+    users.repository.list_each(&filter, each).map_err(|e| ApiError::Db(e, "Error fetching users"))?;
+    Ok(Output::List(listed))
+}
+
+fn create_user(users: &UserResource, field: &Field, variables: &Map<String, Json>) -> Result<Output, GraphqlError> {
+    authorized(Role::Writer, Scope::UsersWrite, field)?;
+    let input = input_argument(field, variables)?;
+    // Without the request there is no Idempotency-Key, so the user is always created
+    match resource::created(users, "", input, false)? {
+        Stored::Record(user) => Ok(project(&user, &field.selection)),
+        Stored::Answered(response) => Err(answered(response)),
+    }
+}
+
+fn update_user(users: &UserResource, field: &Field, variables: &Map<String, Json>) -> Result<Output, GraphqlError> {
+    authorized(Role::Writer, Scope::UsersWrite, field)?;
+    let id = id_argument(field, variables)?;
+    auth::owner_check(id, &field.name).map_err(answered)?;
+    let input = input_argument(field, variables)?;
+    Ok(project(&resource::updated(users, id, input, false)?, &field.selection))
+}
+
+// The id of the user deleted
+fn delete_user(users: &UserResource, field: &Field, variables: &Map<String, Json>) -> Result<Output, GraphqlError> {
+    authorized(Role::Writer, Scope::UsersDelete, field)?;
+    let id = id_argument(field, variables)?;
+    auth::owner_check(id, &field.name).map_err(answered)?;
+    resource::deleted(users, id, false)?;
+    Ok(Output::Scalar(id.into()))
+}
+
+// The fields of the user asked for, by the keys they were asked under
+fn project(user: &User, selection: &[Field]) -> Output {
+    let mut fields: Vec<(String, Output)> = Vec::new();
+    for field in selection {
+        if fields.iter().any(|(key, _)| key == field.key()) {
+            continue;
+        }
+        let value = match field.name.as_str() {
+            "id" => json!(user.id),
+            "name" => json!(user.name),
+            "email" => json!(user.email),
+            "anonymized" => json!(user.anonymized),
+            "emailDisplay" => json!(user.email_display),
+            "verifiedAt" => json!(user.verified_at),
+            "__typename" => json!("User"),
+            _ => Json::Null,
+        };
+        fields.push((field.key().to_owned(), Output::Scalar(value)));
+    }
+    Output::Object(fields)
+}
+
+fn argument(field: &Field, name: &str, variables: &Map<String, Json>) -> Json {
+    field.arguments.iter().find(|(each, _)| each == name).map_or(Json::Null, |(_, value)| resolved(value, variables))
+}
+
+fn int_argument(field: &Field, name: &str, variables: &Map<String, Json>) -> Result<Option<i32>, GraphqlError> {
+    match argument(field, name, variables) {
+        Json::Null => Ok(None),
+        value => {
+            let message = format!("Argument \"{}\" of \"{}\" must be an Int, got {}", name, field.name, value);
+            value.as_i64().and_then(|value| i32::try_from(value).ok()).map(Some).ok_or_else(|| bad_input(message))
+        }
+    }
+}
+
+fn id_argument(field: &Field, variables: &Map<String, Json>) -> Result<i32, GraphqlError> {
+    int_argument(field, "id", variables)?
+        .ok_or_else(|| bad_input(format!("Argument \"id\" of \"{}\" must not be null", field.name)))
+}
+
+// A UserInput, read as the body of POST /users is
+fn input_argument(field: &Field, variables: &Map<String, Json>) -> Result<NewUser, GraphqlError> {
+    let input = argument(field, "input", variables);
+    if !input.is_object() {
+        return Err(bad_input(format!("Argument \"input\" of \"{}\" must be a UserInput, got {}", field.name, input)));
+    }
+    let body = input.to_string();
+    serde_json::from_str(&body).map_err(|e| {
+        let described: Json = serde_json::from_str(&validation::body_error(&body, &e)).unwrap_or_default();
+        let mut error = bad_input(described["error"]["message"].as_str().unwrap_or("Invalid UserInput").to_owned());
+        if let Some(code) = described["error"]["code"].as_str() {
+            error.extensions.insert("reason".to_owned(), code.into());
+        }
+        if let Some(field) = described["error"]["field"].as_str() {
+            error.extensions.insert("field".to_owned(), field.into());
+        }
+        error
+    })
+}
+
+// A UserFilter, as the ?email= and ?name_contains= of GET /users
+fn filter_argument(field: &Field, variables: &Map<String, Json>) -> Result<UserFilter, GraphqlError> {
+    let filter = match argument(field, "filter", variables) {
+        Json::Null => Map::new(),
+        Json::Object(filter) => filter,
+        filter => {
+            let message = format!("Argument \"filter\" of \"{}\" must be a UserFilter, got {}", field.name, filter);
+            return Err(bad_input(message));
+        }
+    };
+    let mut strings = [None, None];
+    for (name, value) in &filter {
+        let at = match name.as_str() {
+            "email" => 0,
+            "nameContains" => 1,
+            _ => return Err(bad_input(format!("Field \"{}\" is not defined by type \"UserFilter\"", name))),
+        };
+        strings[at] = match value {
+            Json::Null => None,
+            Json::String(value) => Some(value.as_str()),
+            value => return Err(bad_input(format!("Field \"{}\" of UserFilter must be a String, got {}", name, value))),
+        };
+    }
+    let [email, name_contains] = strings;
+    Ok(user_filter(email, name_contains, None))
+}
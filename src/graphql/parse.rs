@@ -0,0 +1,581 @@
+use std::fmt;
+
+// Deeper than any document the schema takes, far from what the stack can't
+const MAX_NESTING: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Kind {
+    Query,
+    Mutation,
+}
+
+#[derive(Debug)]
+pub(crate) struct Operation {
+    pub(crate) kind: Kind,
+    pub(crate) name: Option<String>,
+    pub(crate) variables: Vec<Variable>,
+    pub(crate) selection: Vec<Field>,
+}
+
+// A variable the operation declares, its type as it was written
+#[derive(Debug)]
+pub(crate) struct Variable {
+    pub(crate) name: String,
+    pub(crate) type_name: String,
+    pub(crate) default: Option<Value>,
+}
+
+impl Variable {
+    pub(crate) fn required(&self) -> bool {
+        self.type_name.ends_with('!')
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Field {
+    pub(crate) alias: Option<String>,
+    pub(crate) name: String,
+    pub(crate) arguments: Vec<(String, Value)>,
+    pub(crate) selection: Vec<Field>,
+    pub(crate) location: Location,
+}
+
+impl Field {
+    // What it is answered under
+    pub(crate) fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+
+    // Whether it asks for the same as other, wherever it was written
+    pub(crate) fn same_as(&self, other: &Field) -> bool {
+        let same = |(field, other): (&Field, &Field)| field.key() == other.key() && field.same_as(other);
+        self.name == other.name
+            && self.arguments == other.arguments
+            && self.selection.len() == other.selection.len()
+            && self.selection.iter().zip(&other.selection).all(same)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Variable(String),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Boolean(bool),
+    Null,
+    Enum(String),
+    List(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Location {
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct SyntaxError {
+    pub(crate) message: String,
+    pub(crate) location: Location,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Syntax Error: {}", self.message)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Punctuator(char),
+    Spread,
+    Name(String),
+    Int(i64),
+    Float(f64),
+    String(String),
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Punctuator(c) => write!(f, "\"{}\"", c),
+            Token::Spread => write!(f, "\"...\""),
+            Token::Name(name) => write!(f, "Name \"{}\"", name),
+            Token::Int(value) => write!(f, "Int \"{}\"", value),
+            Token::Float(value) => write!(f, "Float \"{}\"", value),
+            Token::String(value) => write!(f, "String {:?}", value),
+            Token::End => write!(f, "<EOF>"),
+        }
+    }
+}
+
+// The operations of a GraphQL document: those of fields, with their aliases, their
+// arguments and their selections, and the variables they declare. What the schema
+// has no use for, the fragments, the directives and the subscriptions, is refused
+// where it is met, as is what is nested past MAX_NESTING.
+pub(crate) fn parse(source: &str) -> Result<Vec<Operation>, SyntaxError> {
+    let mut parser = Parser { tokens: tokenize(source)?, at: 0 };
+    let mut operations = vec![parser.operation()?];
+    while parser.peek() != &Token::End {
+        operations.push(parser.operation()?);
+    }
+    Ok(operations)
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, Location)>, SyntaxError> {
+    let chars: Vec<char> = source.chars().collect();
+    let (mut tokens, mut i, mut line, mut line_start) = (Vec::new(), 0, 1, 0);
+    while i < chars.len() {
+        let location = Location { line, column: i - line_start + 1 };
+        let error = |message: String| SyntaxError { message, location };
+        match chars[i] {
+            '\n' => {
+                i += 1;
+                (line, line_start) = (line + 1, i);
+            }
+            ' ' | '\t' | '\r' | ',' | '\u{feff}' => i += 1,
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            c @ ('{' | '}' | '(' | ')' | '[' | ']' | ':' | '!' | '$' | '=' | '@' | '|' | '&') => {
+                tokens.push((Token::Punctuator(c), location));
+                i += 1;
+            }
+            '.' if chars[i..].starts_with(&['.', '.', '.']) => {
+                tokens.push((Token::Spread, location));
+                i += 3;
+            }
+            '"' if chars[i..].starts_with(&['"', '"', '"']) => {
+                let (value, end) = block_string(&chars, i + 3).ok_or_else(|| error("Unterminated string".into()))?;
+                for (at, _) in chars[i..end].iter().enumerate().filter(|(_, c)| **c == '\n') {
+                    (line, line_start) = (line + 1, i + at + 1);
+                }
+                tokens.push((Token::String(value), location));
+                i = end;
+            }
+            '"' => {
+                let (value, end) = string(&chars, i + 1).map_err(error)?;
+                tokens.push((Token::String(value), location));
+                i = end;
+            }
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let start = i;
+                while i < chars.len() && (chars[i] == '_' || chars[i].is_ascii_alphanumeric()) {
+                    i += 1;
+                }
+                tokens.push((Token::Name(chars[start..i].iter().collect()), location));
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let (token, end) = number(&chars, i).map_err(error)?;
+                tokens.push((token, location));
+                i = end;
+            }
+            c => return Err(error(format!("Unexpected character {:?}", c))),
+        }
+    }
+    let location = Location { line, column: chars.len() - line_start + 1 };
+    tokens.push((Token::End, location));
+    Ok(tokens)
+}
+
+// The value of the string starting after its quote, and where it ends
+fn string(chars: &[char], mut i: usize) -> Result<(String, usize), String> {
+    let mut value = String::new();
+    loop {
+        match chars.get(i) {
+            None | Some('\n' | '\r') => return Err("Unterminated string".to_owned()),
+            Some('"') => return Ok((value, i + 1)),
+            Some('\\') => {
+                let escaped = match chars.get(i + 1) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let hex: String = chars.iter().skip(i + 2).take(4).collect();
+                        let code = u32::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == 4);
+                        i += 4;
+                        code.and_then(char::from_u32).ok_or_else(|| format!("Invalid Unicode escape \\u{}", hex))?
+                    }
+                    Some(c) => return Err(format!("Invalid escape \\{}", c)),
+                    None => return Err("Unterminated string".to_owned()),
+                };
+                value.push(escaped);
+                i += 2;
+            }
+            Some(c) => {
+                value.push(*c);
+                i += 1;
+            }
+        }
+    }
+}
+
+// The value of the block string starting after its quotes, without the indent its
+// lines have in common nor the blank lines around them, and where it ends
+fn block_string(chars: &[char], mut i: usize) -> Option<(String, usize)> {
+    let mut raw = String::new();
+    loop {
+        if chars[i..].starts_with(&['"', '"', '"']) {
+            break;
+        }
+        if chars[i..].starts_with(&['\\', '"', '"', '"']) {
+            raw.push_str("\"\"\"");
+            i += 4;
+            continue;
+        }
+        raw.push(*chars.get(i)?);
+        i += 1;
+    }
+    let lines: Vec<&str> = raw.lines().collect();
+    let indent = lines
+        .iter()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches([' ', '\t']).len())
+        .min()
+        .unwrap_or(0);
+    let mut lines: Vec<&str> = lines
+        .iter()
+        .enumerate()
+        .map(|(at, line)| if at == 0 { line } else { line.get(indent..).unwrap_or("") })
+        .collect();
+    while lines.first().is_some_and(|line| line.trim().is_empty()) {
+        lines.remove(0);
+    }
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    Some((lines.join("\n"), i + 3))
+}
+
+// An Int or a Float, and where it ends
+fn number(chars: &[char], start: usize) -> Result<(Token, usize), String> {
+    let digit = |i: usize| chars.get(i).is_some_and(char::is_ascii_digit);
+    let mut i = start + usize::from(chars[start] == '-');
+    let mut float = false;
+    let digits = |mut i: usize| {
+        while digit(i) {
+            i += 1;
+        }
+        i
+    };
+    if !digit(i) {
+        return Err("Invalid number, expected a digit after \"-\"".to_owned());
+    }
+    i = digits(i);
+    if chars.get(i) == Some(&'.') {
+        if !digit(i + 1) {
+            return Err("Invalid number, expected a digit after \".\"".to_owned());
+        }
+        (i, float) = (digits(i + 1), true);
+    }
+    if matches!(chars.get(i), Some('e' | 'E')) {
+        i += 1 + usize::from(matches!(chars.get(i + 1), Some('+' | '-')));
+        if !digit(i) {
+            return Err("Invalid number, expected a digit in the exponent".to_owned());
+        }
+        (i, float) = (digits(i), true);
+    }
+    if chars.get(i).is_some_and(|c| *c == '_' || *c == '.' || c.is_ascii_alphabetic()) {
+        return Err(format!("Invalid number, unexpected {:?}", chars[i]));
+    }
+    let text: String = chars[start..i].iter().collect();
+    let token = match float {
+        true => text.parse().map(Token::Float).map_err(|_| format!("Invalid number {}", text))?,
+        false => text.parse().map(Token::Int).map_err(|_| format!("Int {} is out of range", text))?,
+    };
+    Ok((token, i))
+}
+
+struct Parser {
+    // End last
+    tokens: Vec<(Token, Location)>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.at].0
+    }
+
+    fn location(&self) -> Location {
+        self.tokens[self.at].1
+    }
+
+    // Staying at the end once there
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.at].0.clone();
+        self.at = (self.at + 1).min(self.tokens.len() - 1);
+        token
+    }
+
+    fn error(&self, message: impl Into<String>) -> SyntaxError {
+        SyntaxError { message: message.into(), location: self.location() }
+    }
+
+    fn unexpected(&self, expected: &str) -> SyntaxError {
+        self.error(format!("Expected {}, found {}", expected, self.peek()))
+    }
+
+    // Whether the next token is the punctuator, which is then passed
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == &Token::Punctuator(c);
+        if found {
+            self.next();
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), SyntaxError> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(self.unexpected(&format!("\"{}\"", c))),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, SyntaxError> {
+        match self.peek().clone() {
+            Token::Name(name) => {
+                self.next();
+                Ok(name)
+            }
+            _ => Err(self.unexpected("Name")),
+        }
+    }
+
+    fn operation(&mut self) -> Result<Operation, SyntaxError> {
+        let kind = match self.peek() {
+            Token::Punctuator('{') => {
+                let selection = self.selection(1)?;
+                return Ok(Operation { kind: Kind::Query, name: None, variables: Vec::new(), selection });
+            }
+            Token::Name(name) if name == "query" => Kind::Query,
+            Token::Name(name) if name == "mutation" => Kind::Mutation,
+            Token::Name(name) if name == "subscription" => return Err(self.error("Subscriptions are not supported")),
+            Token::Name(name) if name == "fragment" => return Err(self.error("Fragments are not supported")),
+            _ => return Err(self.unexpected("an operation")),
+        };
+        self.next();
+        let name = match self.peek() {
+            Token::Name(_) => Some(self.name()?),
+            _ => None,
+        };
+        let variables = match self.peek() {
+            Token::Punctuator('(') => self.variables()?,
+            _ => Vec::new(),
+        };
+        self.no_directives()?;
+        Ok(Operation { kind, name, variables, selection: self.selection(1)? })
+    }
+
+    fn variables(&mut self) -> Result<Vec<Variable>, SyntaxError> {
+        self.expect('(')?;
+        let mut variables = Vec::new();
+        loop {
+            self.expect('$')?;
+            let name = self.name()?;
+            self.expect(':')?;
+            let type_name = self.type_name(0)?;
+            let default = match self.eat('=') {
+                true => Some(self.value(true, 0)?),
+                false => None,
+            };
+            variables.push(Variable { name, type_name, default });
+            if self.eat(')') {
+                return Ok(variables);
+            }
+        }
+    }
+
+    fn type_name(&mut self, depth: usize) -> Result<String, SyntaxError> {
+        if depth > MAX_NESTING {
+            return Err(self.error("The type is nested too deeply"));
+        }
+        let mut type_name = match self.eat('[') {
+            true => {
+                let item = self.type_name(depth + 1)?;
+                self.expect(']')?;
+                format!("[{}]", item)
+            }
+            false => self.name()?,
+        };
+        if self.eat('!') {
+            type_name.push('!');
+        }
+        Ok(type_name)
+    }
+
+    fn no_directives(&self) -> Result<(), SyntaxError> {
+        match self.peek() {
+            Token::Punctuator('@') => Err(self.error("Directives are not supported")),
+            _ => Ok(()),
+        }
+    }
+
+    fn selection(&mut self, depth: usize) -> Result<Vec<Field>, SyntaxError> {
+        if depth > MAX_NESTING {
+            return Err(self.error("The selection is nested too deeply"));
+        }
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        loop {
+            if self.peek() == &Token::Spread {
+                return Err(self.error("Fragments are not supported"));
+            }
+            fields.push(self.field(depth)?);
+            if self.eat('}') {
+                return Ok(fields);
+            }
+        }
+    }
+
+    fn field(&mut self, depth: usize) -> Result<Field, SyntaxError> {
+        let location = self.location();
+        let mut name = self.name()?;
+        let alias = match self.eat(':') {
+            true => {
+                let aliased = self.name()?;
+                Some(std::mem::replace(&mut name, aliased))
+            }
+            false => None,
+        };
+        let mut arguments = Vec::new();
+        if self.eat('(') {
+            loop {
+                let name = self.name()?;
+                self.expect(':')?;
+                arguments.push((name, self.value(false, 0)?));
+                if self.eat(')') {
+                    break;
+                }
+            }
+        }
+        self.no_directives()?;
+        let selection = match self.peek() {
+            Token::Punctuator('{') => self.selection(depth + 1)?,
+            _ => Vec::new(),
+        };
+        Ok(Field { alias, name, arguments, selection, location })
+    }
+
+    // Without variables when constant, as the defaults of the variables are
+    fn value(&mut self, constant: bool, depth: usize) -> Result<Value, SyntaxError> {
+        if depth > MAX_NESTING {
+            return Err(self.error("The value is nested too deeply"));
+        }
+        let location = self.location();
+        match self.next() {
+            Token::Punctuator('$') if !constant => Ok(Value::Variable(self.name()?)),
+            Token::Int(value) => Ok(Value::Int(value)),
+            Token::Float(value) => Ok(Value::Float(value)),
+            Token::String(value) => Ok(Value::String(value)),
+            Token::Name(name) => Ok(match name.as_str() {
+                "true" => Value::Boolean(true),
+                "false" => Value::Boolean(false),
+                "null" => Value::Null,
+                _ => Value::Enum(name),
+            }),
+            Token::Punctuator('[') => {
+                let mut items = Vec::new();
+                while !self.eat(']') {
+                    items.push(self.value(constant, depth + 1)?);
+                }
+                Ok(Value::List(items))
+            }
+            Token::Punctuator('{') => {
+                let mut fields = Vec::new();
+                while !self.eat('}') {
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    fields.push((name, self.value(constant, depth + 1)?));
+                }
+                Ok(Value::Object(fields))
+            }
+            token => Err(SyntaxError { message: format!("Unexpected {}", token), location }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operations_are_parsed_with_their_aliases_arguments_and_variables() {
+        let source = r#"
+            # The first page
+            query Page($limit: Int = 10, $filter: UserFilter!) {
+                first: users(limit: $limit, filter: $filter) { id name }
+                user(id: -7) { email }
+            }
+            mutation { createUser(input: {name: "Ada \"L\" é", email: "ada@example.com", tags: [1, 2.5e1]}) { id } }
+            { __typename }
+        "#;
+        let operations = parse(source).unwrap();
+        assert_eq!(operations.len(), 3);
+        let page = &operations[0];
+        assert_eq!((page.kind, page.name.as_deref()), (Kind::Query, Some("Page")));
+        let variables: Vec<_> =
+            page.variables.iter().map(|variable| (variable.name.as_str(), variable.required())).collect();
+        assert_eq!(variables, [("limit", false), ("filter", true)]);
+        assert_eq!(page.variables[0].default, Some(Value::Int(10)));
+
+        let users = &page.selection[0];
+        let first = ("first", "users", Location { line: 4, column: 17 });
+        assert_eq!((users.key(), users.name.as_str(), users.location), first);
+        assert_eq!(users.arguments[0], ("limit".to_owned(), Value::Variable("limit".to_owned())));
+        let selected: Vec<&str> = users.selection.iter().map(Field::key).collect();
+        assert_eq!(selected, ["id", "name"]);
+        assert_eq!(page.selection[1].arguments[0].1, Value::Int(-7));
+
+        let input = &operations[1].selection[0].arguments[0].1;
+        let Value::Object(input) = input else { panic!("{:?}", input) };
+        assert_eq!(input[0].1, Value::String("Ada \"L\" é".to_owned()));
+        assert_eq!(input[2].1, Value::List(vec![Value::Int(1), Value::Float(25.0)]));
+        assert_eq!((operations[2].kind, operations[2].selection[0].name.as_str()), (Kind::Query, "__typename"));
+    }
+
+    #[test]
+    fn block_strings_lose_their_common_indent() {
+        let operations = parse("{ user(note: \"\"\"\n    first\n      second\n    \"\"\") { id }\n  bad }").unwrap();
+        let note = &operations[0].selection[0].arguments[0].1;
+        assert_eq!(note, &Value::String("first\n  second".to_owned()));
+        // Which leaves the lines after counted
+        assert_eq!(operations[0].selection[1].location, Location { line: 5, column: 3 });
+    }
+
+    #[test]
+    fn what_isnt_supported_or_valid_is_refused_where_it_is() {
+        let error = |source| parse(source).unwrap_err();
+        let at = |line, column| Location { line, column };
+        assert_eq!(error("{ user(id: 1) { ...Fields } }"), SyntaxError {
+            message: "Fragments are not supported".into(),
+            location: at(1, 17),
+        });
+        assert_eq!(error("fragment F on User { id }").message, "Fragments are not supported");
+        assert_eq!(error("subscription { users { id } }").message, "Subscriptions are not supported");
+        assert_eq!(error("{ user(id: 1) @include(if: true) { id } }").location, at(1, 15));
+        assert_eq!(error("{ users { id }").message, "Expected Name, found <EOF>");
+        assert_eq!(error("{\n  user(id: 1 { id } }").message, "Expected Name, found \"{\"");
+        assert_eq!(error("{\n  user(id: 1 { id } }").location, at(2, 14));
+        assert_eq!(error("{ user(id: \"1) { id } }").message, "Unterminated string");
+        let too_big = error("{ user(id: 99999999999999999999) { id } }");
+        assert_eq!(too_big.message, "Int 99999999999999999999 is out of range");
+        assert_eq!(error("{ user(id: 1x) { id } }").message, "Invalid number, unexpected 'x'");
+        assert_eq!(error("query ($id: Int = $other) { id }").message, "Unexpected \"$\"");
+        assert_eq!(error("").message, "Expected an operation, found <EOF>");
+
+        let nested = format!("{}{}", "{ a ".repeat(40), "}".repeat(40));
+        assert_eq!(error(&nested).message, "The selection is nested too deeply");
+    }
+}
@@ -0,0 +1,1080 @@
+// What can fail in a handler is answered as an ApiError, none of it unwrapped
+#![cfg_attr(not(test), deny(clippy::unwrap_used, clippy::expect_used))]
+
+use serde::de::DeserializeOwned;
+
+use crate::auth::{ self, Role };
+use crate::cache::{ self, Cached };
+use crate::coalesce::{ self, Flights };
+use crate::errors::{ with_causes, ApiError, Handled };
+use crate::http::{
+    decode_query_value, get_body, get_header, get_query, get_query_param, parse_id, serialized, with_header,
+    OK_RESPONSE, SERVICE_UNAVAILABLE, UNPROCESSABLE_ENTITY
+};
+use crate::models::User;
+use crate::repository::circuit::State;
+use crate::repository::{ Conflict, Created, IdempotencyKey, RepositoryError, UserFilter, UserRepository };
+use crate::resource::{ self, CrudResource, Stored };
+use crate::validation::{ self, NewUser, ValidationError };
+use crate::{ config, idempotency, migrations, pagination, read_only, redact, shutdown, tenant, verification };
+
+// Get one user, from the cache when it is on and use_cache. Otherwise the
+// concurrent requests for the user are answered by the one that reads it.
+pub(crate) fn handle_get_user_request(
+    repository: &dyn UserRepository,
+    id: i32,
+    use_cache: bool,
+    flights: &Flights
+) -> Handled {
+    let cache = cache::cache().filter(|_| use_cache);
+    if let Some(cached) = cache.and_then(|cache| cache.get(id)) {
+        return Ok((with_header(&user_response(&cached.etag), "X-Cache: HIT"), cached.body));
+    }
+    // Those not using the cache read from the primary. Those waiting get the
+    // response of the one reading, so its error is answered here.
+    let key = format!("{} {} {}", tenant::current(), id, use_cache);
+    Ok(flights.run(key, || find_user(repository, id, cache).unwrap_or_else(ApiError::response)))
+}
+
+fn find_user(repository: &dyn UserRepository, id: i32, cache: Option<&cache::UserCache>) -> Handled {
+    let generation = cache.map(|cache| cache.generation());
+    let user = UserResource::new(repository).find(id)?;
+    let body = serialized(&user);
+    let etag = cache::etag(&body);
+    let status_line = user_response(&etag);
+    match cache.zip(generation) {
+        Some((cache, generation)) => {
+            cache.put(id, Cached { body: body.clone(), etag }, generation);
+            Ok((with_header(&status_line, "X-Cache: MISS"), body))
+        }
+        None => Ok((status_line, body)),
+    }
+}
+
+fn user_response(etag: &str) -> String {
+    with_header(OK_RESPONSE, &format!("ETag: {}", etag))
+}
+
+// The cached response of a user is out of date once it was written
+pub(crate) fn invalidate_cached(id: i32) {
+    if let Some(cache) = cache::cache() {
+        cache.invalidate(id);
+    }
+}
+
+// The users, created, listed, updated and deleted by the handlers of resource.
+// With use_cache, a user is read from the cache when it is on. The accounts that
+// aren't admins only have their own.
+#[derive(Clone, Copy)]
+pub(crate) struct UserResource<'a> {
+    pub(crate) repository: &'a dyn UserRepository,
+    pub(crate) use_cache: bool,
+}
+
+impl<'a> UserResource<'a> {
+    pub(crate) fn new(repository: &'a dyn UserRepository) -> Self {
+        UserResource { repository, use_cache: true }
+    }
+}
+
+impl CrudResource for UserResource<'_> {
+    type Id = i32;
+    type Model = User;
+    type CreatePayload = NewUser;
+    type UpdatePayload = NewUser;
+
+    const NAME: &'static str = "user";
+    const PATH: &'static str = "users";
+
+    fn id_of(user: &User) -> Option<i32> {
+        user.id
+    }
+
+    fn parse_id(segment: &str) -> Result<i32, &'static str> {
+        parse_id(segment)
+    }
+
+    // In the language of the request, like the other 404s of the users
+    fn not_found(id: i32) -> ApiError {
+        ApiError::NotFound(id)
+    }
+
+    fn validate_create(&self, user: &mut NewUser) -> Vec<ValidationError> {
+        validation::validate_fields(user)
+    }
+
+    // The password is changed at its own route, given the one it replaces
+    fn validate_update(&self, user: &mut NewUser) -> Vec<ValidationError> {
+        let mut errors = validation::validate_fields(user);
+        if user.password.is_some() {
+            errors.push(ValidationError::password_elsewhere());
+        }
+        errors
+    }
+
+    fn find(&self, id: i32) -> Result<User, ApiError> {
+        self.repository.find(id).map_err(of_user(id, "Error fetching user"))
+    }
+
+    // Filtered with ?email= and ?name_contains=
+    fn list_each(
+        &self,
+        request: &str,
+        after_id: Option<i64>,
+        each: &mut dyn FnMut(User) -> bool
+    ) -> Result<(), ApiError> {
+        let email = get_query_param(request, "email").map(decode_query_value);
+        let name_contains = get_query_param(request, "name_contains").map(decode_query_value);
+        let filter = user_filter(email.as_deref(), name_contains.as_deref(), after_id);
+        // Only the user themselves, for those that aren't admins
+        let own = auth::own_list().ok().flatten();
+        let listed = self.repository.list_each(&filter, &mut |user| {
+            own.is_some_and(|own| user.id != Some(own)) || each(user)
+        });
+        listed.map_err(|e| ApiError::Db(e, "Error fetching users"))
+    }
+
+    fn create(&self, request: &str, mut user: NewUser, dry_run: bool) -> Result<Stored<Self>, ApiError> {
+        // A dry run must not use up the key of the real request
+        let idempotency_key = get_header(request, "Idempotency-Key").filter(|_| !dry_run);
+        // Only what logging in checks the password against is stored
+        user.password_hash = user.password.take().filter(|_| !dry_run).map(|password| password.hash());
+
+        let response = |user: &User| (OK_RESPONSE.to_owned(), serialized(user));
+        let idempotency = idempotency_key.map(|key| IdempotencyKey {
+            key,
+            request_hash: idempotency::hash_body(get_body(request)),
+            response: &response,
+        });
+
+        match self.repository.create(&user, dry_run, idempotency.as_ref()) {
+            Ok(Created::User(user)) => Ok(Stored::Record(user)),
+            Ok(Created::Replay(status_line, body)) => Ok(Stored::Answered((status_line, body))),
+            Ok(Created::KeyMismatch) => {
+                let key = idempotency_key.unwrap_or_default();
+                Err(ApiError::Unprocessable(format!("Idempotency-Key {} was used with a different request body", key)))
+            }
+            Err(RepositoryError::Db(_)) => Err(ApiError::Internal("Failed to insert user into database".into())),
+            Err(e) => Err(ApiError::Db(e, "Failed to insert user into database")),
+        }
+    }
+
+    fn update(&self, id: i32, user: NewUser, dry_run: bool) -> Result<User, ApiError> {
+        self.repository.update(id, &user, dry_run).map_err(|e| match e {
+            RepositoryError::Conflict(Conflict::Anonymized) => {
+                ApiError::Conflict(format!("User with ID {} has been anonymized", id))
+            }
+            e => of_user(id, "Error updating user")(e),
+        })
+    }
+
+    fn delete(&self, id: i32, dry_run: bool) -> Result<(), ApiError> {
+        self.repository.delete(id, dry_run).map_err(of_user(id, "Error deleting user"))
+    }
+
+    // The user is there either way, and can ask for another token
+    fn after_create(&self, user: &User) {
+        if let Some((config, id)) = verification::config().zip(user.id) {
+            if let Err(e) = verification::issue(config, id, &user.email) {
+                log::error!("Error sending the verification of user {}: {}", id, with_causes(&e));
+            }
+        }
+    }
+
+    fn after_update(&self, id: i32, _user: &User) {
+        invalidate_cached(id);
+    }
+
+    fn after_delete(&self, id: i32) {
+        invalidate_cached(id);
+    }
+
+    // Their own record, or the list of it alone, unless USER_LISTS=forbidden
+    fn authorize(&self, id: Option<i32>, route: &str) -> Result<(), ApiError> {
+        let authorized = match id {
+            Some(id) => auth::owner_check(id, route),
+            None => auth::own_list().map(drop),
+        };
+        authorized.map_err(ApiError::Forbidden)
+    }
+
+    fn get(&self, id: i32) -> Handled {
+        handle_get_user_request(self.repository, id, self.use_cache, coalesce::flights())
+    }
+
+    fn get_list(&self, request: &str) -> Handled {
+        handle_list_users_request(self, request, coalesce::flights())
+    }
+}
+
+// A page of the users. The concurrent requests for the same one, by its query and
+// whose list it is, are answered by the one that reads it.
+fn handle_list_users_request(users: &UserResource, request: &str, flights: &Flights) -> Handled {
+    let own = auth::own_list().ok().flatten();
+    let key = format!("{} list {:?} {} {}", tenant::current(), own, users.use_cache, get_query(request));
+    Ok(flights.run(key, || resource::list(users, request).unwrap_or_else(ApiError::response)))
+}
+
+// The filter of a list of the users, its values normalized like the stored ones
+// they are compared with
+pub(crate) fn user_filter(email: Option<&str>, name_contains: Option<&str>, after_id: Option<i64>) -> UserFilter {
+    UserFilter {
+        email: email.map(|email| validation::normalize_email(email.trim())),
+        name_contains: name_contains.map(|name| validation::normalize_name(name.trim())),
+        // No user has an id past those of i32
+        after_id: after_id.map(|id| i32::try_from(id).unwrap_or(i32::MAX)),
+    }
+}
+
+// Run the create validation without creating anything. The 422 of a user that
+// isn't valid is its answer, not an error.
+pub(crate) fn handle_validate_request(repository: &dyn UserRepository, request: &str) -> Handled {
+    let mut new_user = deserialize_body::<NewUser>(request).map_err(|e| invalid_body(request, e))?;
+
+    match validation::validate_new_user(repository, &mut new_user) {
+        Ok(errors) if errors.is_empty() => {
+            Ok((OK_RESPONSE.to_owned(), serde_json::json!({ "valid": true }).to_string()))
+        }
+        Ok(errors) => {
+            let body = serde_json::json!({ "valid": false, "errors": errors });
+            Ok((UNPROCESSABLE_ENTITY.to_owned(), body.to_string()))
+        }
+        Err(e) => Err(ApiError::Db(e, "Error validating user")),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoleChange {
+    role: Role,
+}
+
+// Give a user another role, which their sessions have from the next request on
+// and their tokens from the next login
+pub(crate) fn handle_set_role_request(repository: &dyn UserRepository, request: &str, id: i32) -> Handled {
+    let change = serde_json::from_str::<RoleChange>(get_body(request)).map_err(|e| invalid_body(request, e))?;
+    repository.set_role(id, change.role).map_err(of_user(id, "Error changing the role"))?;
+    Ok((OK_RESPONSE.to_owned(), serde_json::json!({ "id": id, "role": change.role }).to_string()))
+}
+
+// Anonymize a user: the personal data is scrubbed for good, the row and its id stay
+pub(crate) fn handle_anonymize_request(repository: &dyn UserRepository, request: &str, id: i32) -> Handled {
+    let dry_run = is_dry_run(request);
+
+    let user = repository.anonymize(id, dry_run).map_err(of_user(id, "Error anonymizing user"))?;
+    if dry_run {
+        return Ok((OK_RESPONSE.to_owned(), dry_run_body(serde_json::to_value(&user)?)));
+    }
+    invalidate_cached(id);
+    Ok((OK_RESPONSE.to_owned(), serialized(&user)))
+}
+
+// Export everything stored about a user, as one JSON document or as NDJSON lines.
+// Its history is cut into pages like the lists, of ?limit= events after the one of
+// ?after_id=, at most EXPORT_PAGE_SIZE_MAX, each page having the user.
+pub(crate) fn handle_export_request(repository: &dyn UserRepository, request: &str, id: i32) -> Handled {
+    let ndjson = get_query_param(request, "format") == Some("ndjson");
+    let (limit, after_id) = (pagination::export_limit(request)?, pagination::after_id(request)?);
+
+    let (user, mut history) = repository.export(id).map_err(of_user(id, "Error exporting user"))?;
+    history.retain(|event| after_id.is_none_or(|after_id| event.id > after_id));
+    let more = history.len() > limit.value;
+    history.truncate(limit.value);
+    let next = history.last().map(|event| event.id).filter(|_| more);
+    let (content_type, extension, body) = if ndjson {
+        let mut lines = vec![serde_json::json!({ "type": "user", "data": user })];
+        for event in &history {
+            lines.push(serde_json::json!({ "type": "event", "data": event }));
+        }
+        let body: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        ("application/x-ndjson", "ndjson", body.join("\n") + "\n")
+    } else {
+        let export = serde_json::json!({ "user": user, "history": history });
+        ("application/json", "json", export.to_string())
+    };
+
+    let status_line = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename=\"user-{}.{}\"\r\n\r\n",
+        content_type,
+        id,
+        extension
+    );
+    Ok((pagination::linked(&status_line, request, limit, next), body))
+}
+
+// Get the events recorded after since_id
+pub(crate) fn handle_get_events_request(repository: &dyn UserRepository, request: &str) -> Handled {
+    let since_id = get_query_param(request, "since_id").unwrap_or("0");
+    let since_id_int =
+        since_id.parse::<i64>().map_err(|_| ApiError::BadRequest(format!("Invalid since_id: {}", since_id)))?;
+    let events = repository.events_since(since_id_int).map_err(|e| ApiError::Db(e, "Error fetching events"))?;
+    Ok((OK_RESPONSE.to_owned(), serialized(&events)))
+}
+
+// What an operation on the user of the id failed with, a 404 when there is none
+fn of_user(id: i32, failure: &'static str) -> impl FnOnce(RepositoryError) -> ApiError {
+    move |e| match e {
+        RepositoryError::NotFound => UserResource::not_found(id),
+        e => ApiError::Db(e, failure),
+    }
+}
+
+// The 400 of a body that isn't what the route takes, saying where it went wrong
+pub(crate) fn invalid_body(request: &str, error: serde_json::Error) -> ApiError {
+    ApiError::InvalidBody(validation::described(get_body(request), &error))
+}
+
+// Whether the API can currently reach the database, and whether it takes writes
+pub(crate) fn handle_health_request(repository: &dyn UserRepository) -> (String, String) {
+    let read_only = read_only::enabled();
+    match repository.ping() {
+        Ok(()) => {
+            let body = serde_json::json!({ "status": "ok", "database": "ok", "read_only": read_only });
+            (OK_RESPONSE.to_owned(), body.to_string())
+        }
+        Err(e) => {
+            let database = redact::text(&e.to_string());
+            let body = serde_json::json!({ "status": "degraded", "database": database, "read_only": read_only });
+            (SERVICE_UNAVAILABLE.to_owned(), body.to_string())
+        }
+    }
+}
+
+// Whether the process is up, without asking anything else. Still answered when
+// the connections are over MAX_CONNECTIONS, from the headroom.
+pub(crate) fn handle_livez_request() -> (String, String) {
+    (OK_RESPONSE.to_owned(), serde_json::json!({ "status": "alive" }).to_string())
+}
+
+// Which build answers, and the profile APP_ENV picked
+pub(crate) fn handle_version_request() -> (String, String) {
+    let config = config::get();
+    let body = serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "app_env": config.app_env,
+        "profile": config.profile.name(),
+    });
+    (OK_RESPONSE.to_owned(), body.to_string())
+}
+
+// Whether this instance should get traffic: the schema has caught up with the
+// migrations and the database answers
+pub(crate) fn handle_readyz_request(repository: &dyn UserRepository) -> (String, String) {
+    // For the load balancers to stop sending requests
+    if shutdown::draining() {
+        return (SERVICE_UNAVAILABLE.to_owned(), serde_json::json!({ "ready": false, "draining": true }).to_string());
+    }
+
+    let waiting_for = migrations::waiting_for();
+    if !waiting_for.is_empty() {
+        let body = serde_json::json!({ "ready": false, "pending_migrations": waiting_for });
+        return (SERVICE_UNAVAILABLE.to_owned(), body.to_string());
+    }
+
+    match repository.ping() {
+        Ok(()) => (OK_RESPONSE.to_owned(), serde_json::json!({ "ready": true }).to_string()),
+        Err(e @ RepositoryError::CircuitOpen(_)) => {
+            let database = redact::text(&e.to_string());
+            let body = serde_json::json!({ "ready": false, "circuit": State::Open.name(), "database": database });
+            (SERVICE_UNAVAILABLE.to_owned(), body.to_string())
+        }
+        Err(e) => {
+            let body = serde_json::json!({ "ready": false, "database": redact::text(&e.to_string()) });
+            (SERVICE_UNAVAILABLE.to_owned(), body.to_string())
+        }
+    }
+}
+
+// ?dry_run=true runs a mutation in full, constraint checks included, then rolls it back
+pub(crate) fn is_dry_run(request: &str) -> bool {
+    get_query_param(request, "dry_run") == Some("true")
+}
+
+// The response the real request would have produced, marked as a dry run
+pub(crate) fn dry_run_body(mut body: serde_json::Value) -> String {
+    if let Some(object) = body.as_object_mut() {
+        object.insert("dry_run".to_owned(), serde_json::Value::Bool(true));
+    }
+    body.to_string()
+}
+
+pub(crate) fn deserialize_body<T: DeserializeOwned>(request: &str) -> Result<T, serde_json::Error> {
+    let _parsing = tracing::info_span!("parse_body").entered();
+    serde_json::from_str(get_body(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource;
+    use crate::coalesce::CoalesceConfig;
+    use crate::http::{
+        BAD_REQUEST_PROBLEM, CONFLICT_PROBLEM, INTERNAL_SERVER_ERROR, NOT_FOUND_PROBLEM, NOT_IMPLEMENTED_PROBLEM,
+        UNPROCESSABLE_ENTITY_PROBLEM
+    };
+    use crate::repository::circuit::{ Circuit, CircuitBreaker, CircuitConfig };
+    use crate::repository::memory::MemoryRepository;
+    use crate::repository::Account;
+    use std::collections::HashMap;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+    use std::sync::{ Barrier, Mutex };
+    use std::thread;
+    use std::time::Duration;
+
+    // Knows one user, has every email taken, and can't reach its database for
+    // anything else
+    struct FakeRepository;
+
+    impl UserRepository for FakeRepository {
+        fn find(&self, id: i32) -> Result<User, RepositoryError> {
+            match id {
+                1 => Ok(User::new(Some(1), "Ada".to_owned(), "ada@example.com".to_owned(), false)),
+                _ => Err(RepositoryError::NotFound),
+            }
+        }
+
+        fn list(&self, _filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+            Err(RepositoryError::Unavailable("connection refused".into()))
+        }
+
+        fn create(
+            &self,
+            _user: &NewUser,
+            _dry_run: bool,
+            _idempotency: Option<&IdempotencyKey>
+        ) -> Result<Created, RepositoryError> {
+            Err(RepositoryError::Conflict(Conflict::EmailTaken))
+        }
+
+        fn update(&self, _id: i32, _user: &NewUser, _dry_run: bool) -> Result<User, RepositoryError> {
+            Err(RepositoryError::Conflict(Conflict::Anonymized))
+        }
+
+        fn delete(&self, _id: i32, _dry_run: bool) -> Result<(), RepositoryError> {
+            Err(RepositoryError::Db("deadlock detected".into()))
+        }
+
+        fn email_taken(&self, _email: &str) -> Result<bool, RepositoryError> {
+            Ok(true)
+        }
+
+        fn ping(&self) -> Result<(), RepositoryError> {
+            Err(RepositoryError::Unavailable("connection refused".into()))
+        }
+
+        fn credentials(&self, _email: &str) -> Result<Option<Account>, RepositoryError> {
+            Ok(None)
+        }
+
+        fn password_hash(&self, _id: i32) -> Result<Option<String>, RepositoryError> {
+            Err(RepositoryError::NotFound)
+        }
+
+        fn set_password_hash(&self, _id: i32, _hash: &str) -> Result<(), RepositoryError> {
+            Err(RepositoryError::NotFound)
+        }
+
+        fn set_role(&self, _id: i32, _role: Role) -> Result<(), RepositoryError> {
+            Err(RepositoryError::NotFound)
+        }
+    }
+
+    // Lists count users, those after the after_id of the filter, then fails if it
+    // must, and does what FakeRepository does for the rest
+    struct ListingRepository {
+        count: i32,
+        fails: bool,
+    }
+
+    impl UserRepository for ListingRepository {
+        fn find(&self, id: i32) -> Result<User, RepositoryError> {
+            FakeRepository.find(id)
+        }
+
+        fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+            FakeRepository.list(filter)
+        }
+
+        fn list_each(&self, filter: &UserFilter, each: &mut dyn FnMut(User) -> bool) -> Result<(), RepositoryError> {
+            for id in filter.after_id.unwrap_or(0) + 1..=self.count {
+                if !each(User::new(Some(id), format!("User {}", id), format!("user{}@example.com", id), false)) {
+                    return Ok(());
+                }
+            }
+            if self.fails {
+                return Err(RepositoryError::Unavailable("connection reset".into()));
+            }
+            Ok(())
+        }
+
+        fn create(
+            &self,
+            user: &NewUser,
+            dry_run: bool,
+            idempotency: Option<&IdempotencyKey>
+        ) -> Result<Created, RepositoryError> {
+            FakeRepository.create(user, dry_run, idempotency)
+        }
+
+        fn update(&self, id: i32, user: &NewUser, dry_run: bool) -> Result<User, RepositoryError> {
+            FakeRepository.update(id, user, dry_run)
+        }
+
+        fn delete(&self, id: i32, dry_run: bool) -> Result<(), RepositoryError> {
+            FakeRepository.delete(id, dry_run)
+        }
+
+        fn email_taken(&self, email: &str) -> Result<bool, RepositoryError> {
+            FakeRepository.email_taken(email)
+        }
+
+        fn ping(&self) -> Result<(), RepositoryError> {
+            FakeRepository.ping()
+        }
+
+        fn credentials(&self, email: &str) -> Result<Option<Account>, RepositoryError> {
+            FakeRepository.credentials(email)
+        }
+
+        fn password_hash(&self, id: i32) -> Result<Option<String>, RepositoryError> {
+            FakeRepository.password_hash(id)
+        }
+
+        fn set_password_hash(&self, id: i32, hash: &str) -> Result<(), RepositoryError> {
+            FakeRepository.set_password_hash(id, hash)
+        }
+
+        fn set_role(&self, id: i32, role: Role) -> Result<(), RepositoryError> {
+            FakeRepository.set_role(id, role)
+        }
+    }
+
+    // Counts its reads, which are slow, otherwise FakeRepository
+    #[derive(Default)]
+    struct CountingRepository {
+        finds: AtomicUsize,
+        lists: AtomicUsize,
+    }
+
+    impl UserRepository for CountingRepository {
+        fn find(&self, id: i32) -> Result<User, RepositoryError> {
+            self.finds.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(Duration::from_millis(200));
+            FakeRepository.find(id)
+        }
+
+        fn list(&self, _filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+            self.lists.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(Duration::from_millis(200));
+            Ok(Vec::new())
+        }
+
+        fn create(
+            &self,
+            user: &NewUser,
+            dry_run: bool,
+            idempotency: Option<&IdempotencyKey>
+        ) -> Result<Created, RepositoryError> {
+            FakeRepository.create(user, dry_run, idempotency)
+        }
+
+        fn update(&self, id: i32, user: &NewUser, dry_run: bool) -> Result<User, RepositoryError> {
+            FakeRepository.update(id, user, dry_run)
+        }
+
+        fn delete(&self, id: i32, dry_run: bool) -> Result<(), RepositoryError> {
+            FakeRepository.delete(id, dry_run)
+        }
+
+        fn email_taken(&self, email: &str) -> Result<bool, RepositoryError> {
+            FakeRepository.email_taken(email)
+        }
+
+        fn ping(&self) -> Result<(), RepositoryError> {
+            FakeRepository.ping()
+        }
+
+        fn credentials(&self, email: &str) -> Result<Option<Account>, RepositoryError> {
+            FakeRepository.credentials(email)
+        }
+
+        fn password_hash(&self, id: i32) -> Result<Option<String>, RepositoryError> {
+            FakeRepository.password_hash(id)
+        }
+
+        fn set_password_hash(&self, id: i32, hash: &str) -> Result<(), RepositoryError> {
+            FakeRepository.set_password_hash(id, hash)
+        }
+
+        fn set_role(&self, id: i32, role: Role) -> Result<(), RepositoryError> {
+            FakeRepository.set_role(id, role)
+        }
+    }
+
+    // What an operation fails with
+    type Failure = fn() -> RepositoryError;
+
+    // The users of a MemoryRepository, recording the operations asked of it, and
+    // failing those it was told to with the error made for them
+    #[derive(Default)]
+    struct ScriptedRepository {
+        users: MemoryRepository,
+        failing: Mutex<HashMap<&'static str, Failure>>,
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    impl ScriptedRepository {
+        // With the users of these names, ids from 1 on
+        fn with_users(names: &[&str]) -> Self {
+            let repository = ScriptedRepository::default();
+            for name in names {
+                let email = format!("{}@example.com", name.to_lowercase());
+                let user = NewUser { name: name.to_string(), email, password: None, password_hash: None };
+                repository.users.create(&user, false, None).unwrap();
+            }
+            repository
+        }
+
+        fn fail(&self, operation: &'static str, error: Failure) {
+            self.failing.lock().unwrap().insert(operation, error);
+        }
+
+        // The operations asked of it since the last time
+        fn calls(&self) -> Vec<&'static str> {
+            std::mem::take(&mut *self.calls.lock().unwrap())
+        }
+
+        fn call(&self, operation: &'static str) -> Result<(), RepositoryError> {
+            self.calls.lock().unwrap().push(operation);
+            match self.failing.lock().unwrap().get(operation) {
+                Some(error) => Err(error()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl UserRepository for ScriptedRepository {
+        fn find(&self, id: i32) -> Result<User, RepositoryError> {
+            self.call("find")?;
+            self.users.find(id)
+        }
+
+        fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+            self.call("list")?;
+            self.users.list(filter)
+        }
+
+        fn create(
+            &self,
+            user: &NewUser,
+            dry_run: bool,
+            idempotency: Option<&IdempotencyKey>
+        ) -> Result<Created, RepositoryError> {
+            self.call("create")?;
+            self.users.create(user, dry_run, idempotency)
+        }
+
+        fn update(&self, id: i32, user: &NewUser, dry_run: bool) -> Result<User, RepositoryError> {
+            self.call("update")?;
+            self.users.update(id, user, dry_run)
+        }
+
+        fn delete(&self, id: i32, dry_run: bool) -> Result<(), RepositoryError> {
+            self.call("delete")?;
+            self.users.delete(id, dry_run)
+        }
+
+        fn email_taken(&self, email: &str) -> Result<bool, RepositoryError> {
+            self.call("email_taken")?;
+            self.users.email_taken(email)
+        }
+
+        fn ping(&self) -> Result<(), RepositoryError> {
+            self.call("ping")?;
+            self.users.ping()
+        }
+
+        fn credentials(&self, email: &str) -> Result<Option<Account>, RepositoryError> {
+            self.call("credentials")?;
+            self.users.credentials(email)
+        }
+
+        fn password_hash(&self, id: i32) -> Result<Option<String>, RepositoryError> {
+            self.call("password_hash")?;
+            self.users.password_hash(id)
+        }
+
+        fn set_password_hash(&self, id: i32, hash: &str) -> Result<(), RepositoryError> {
+            self.call("set_password_hash")?;
+            self.users.set_password_hash(id, hash)
+        }
+
+        fn set_role(&self, id: i32, role: Role) -> Result<(), RepositoryError> {
+            self.call("set_role")?;
+            self.users.set_role(id, role)
+        }
+    }
+
+    fn unavailable() -> RepositoryError {
+        RepositoryError::Unavailable("connection refused".into())
+    }
+
+    fn failed() -> RepositoryError {
+        RepositoryError::Db("deadlock detected".into())
+    }
+
+    // The status code of a response
+    // The response of the handler, its error answered as the router does
+    fn answered(handled: Handled) -> (String, String) {
+        handled.unwrap_or_else(ApiError::response)
+    }
+
+    fn users(repository: &dyn UserRepository) -> UserResource<'_> {
+        UserResource::new(repository)
+    }
+
+    fn status((status_line, _): &(String, String)) -> u16 {
+        status_line.split_whitespace().nth(1).and_then(|code| code.parse().ok()).unwrap()
+    }
+
+    fn request(method: &str, target: &str, body: &str) -> String {
+        format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n{}", method, target, body)
+    }
+
+    // The response to GET /users/{id}, not coalesced with those of the other tests
+    fn get_user(repository: &dyn UserRepository, id: i32) -> (String, String) {
+        answered(handle_get_user_request(repository, id, true, &Flights::new(CoalesceConfig { wait: Duration::ZERO })))
+    }
+
+    // The response to GET /users, its head then its body
+    fn list(repository: &dyn UserRepository) -> String {
+        let (status_line, content) = answered(resource::list(&users(repository), &request("GET", "/users", "")));
+        status_line + &content
+    }
+
+    // The target of the Link to the next page in the head of a page
+    fn next_page(status_line: &str) -> Option<&str> {
+        let links = status_line.split("\r\n").find_map(|header| header.strip_prefix("Link: "))?;
+        let next = links.split(", ").find_map(|link| link.strip_suffix("; rel=\"next\""))?;
+        next.strip_prefix('<')?.strip_suffix('>')
+    }
+
+    #[test]
+    fn long_lists_are_sent_in_pages() {
+        let repository = ListingRepository { count: 250, fails: false };
+        let first = "/users?name_contains=user&limit=100";
+        let (mut target, mut pages, mut ids) = (Some(first.to_owned()), 0, Vec::new());
+        while let Some(page) = target {
+            let (status_line, body) = answered(resource::list(&users(&repository), &request("GET", &page, "")));
+            let link = format!("\r\nLink: <{}>; rel=\"first\"", first);
+            assert!(status_line.contains(&link), "{}", status_line);
+            let users: serde_json::Value = serde_json::from_str(&body).unwrap();
+            ids.extend(users.as_array().unwrap().iter().map(|user| user["id"].as_i64().unwrap()));
+            (target, pages) = (next_page(&status_line).map(str::to_owned), pages + 1);
+        }
+        assert_eq!((pages, ids), (3, (1..=250).collect::<Vec<i64>>()));
+
+        // Over the maximum, all there is, and said to be less than asked for
+        let listed = resource::list(&users(&repository), &request("GET", "/users?limit=5000", ""));
+        let (status_line, body) = answered(listed);
+        assert!(status_line.contains("\r\nX-Limit-Clamped: true\r\n") && next_page(&status_line).is_none());
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap().as_array().unwrap().len(), 250);
+        for invalid in ["/users?limit=0", "/users?limit=-5", "/users?after_id=first"] {
+            let (status_line, _) = answered(resource::list(&users(&repository), &request("GET", invalid, "")));
+            assert_eq!(status_line, BAD_REQUEST_PROBLEM, "{}", invalid);
+        }
+
+        // A page is read in full before anything is sent, a failure on the way is
+        // answered like any other
+        assert!(list(&ListingRepository { count: 3, fails: true }).starts_with("HTTP/1.1 503"));
+    }
+
+    #[test]
+    fn concurrent_reads_of_a_user_are_coalesced() {
+        let repository = CountingRepository::default();
+        let flights = Flights::new(CoalesceConfig { wait: Duration::from_secs(5) });
+        let barrier = Barrier::new(20);
+        let responses: Vec<_> = thread::scope(|scope| {
+            let readers: Vec<_> = (0..20)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        answered(handle_get_user_request(&repository, 1, true, &flights))
+                    })
+                })
+                .collect();
+            readers.into_iter().map(|reader| reader.join().unwrap()).collect()
+        });
+
+        assert_eq!(repository.finds.load(Ordering::Relaxed), 1);
+        assert_eq!(flights.coalesced.load(Ordering::Relaxed), 19);
+        assert!(responses.iter().all(|response| *response == responses[0]));
+        assert!(responses[0].0.starts_with("HTTP/1.1 200 OK"), "{}", responses[0].0);
+        // Another read once the first landed
+        answered(handle_get_user_request(&repository, 1, true, &flights));
+        assert_eq!(repository.finds.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn concurrent_lists_of_the_same_page_are_coalesced() {
+        let repository = CountingRepository::default();
+        let flights = Flights::new(CoalesceConfig { wait: Duration::from_secs(5) });
+        let barrier = Barrier::new(20);
+        let responses: Vec<_> = thread::scope(|scope| {
+            let readers: Vec<_> = (0..20)
+                .map(|reader| {
+                    let (barrier, flights, repository) = (&barrier, &flights, &repository);
+                    scope.spawn(move || {
+                        let target = format!("/users?limit={}", 5 + reader % 2);
+                        barrier.wait();
+                        answered(handle_list_users_request(&users(repository), &request("GET", &target, ""), flights))
+                    })
+                })
+                .collect();
+            readers.into_iter().map(|reader| reader.join().unwrap()).collect()
+        });
+
+        // One read for each query
+        assert_eq!(repository.lists.load(Ordering::Relaxed), 2);
+        assert_eq!(flights.coalesced.load(Ordering::Relaxed), 18);
+        assert!(responses.iter().all(|(status_line, body)| status_line.starts_with("HTTP/1.1 200 OK") && body == "[]"));
+    }
+
+    #[test]
+    fn handlers_map_repository_results_to_responses() {
+        let repository = FakeRepository;
+        let body = r#"{"name": "Ada", "email": "ada@example.com"}"#;
+
+        let (status_line, body_found) = get_user(&repository, 1);
+        assert_eq!(status_line, with_header(OK_RESPONSE, &format!("ETag: {}", cache::etag(&body_found))));
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body_found).unwrap()["name"], "Ada");
+        assert_eq!(get_user(&repository, 2).0, NOT_FOUND_PROBLEM);
+
+        assert!(list(&repository).starts_with("HTTP/1.1 503"));
+
+        let (status_line, conflict) = answered(resource::create(&users(&repository), &request("POST", "/users", body)));
+        assert_eq!(status_line, CONFLICT_PROBLEM);
+        assert!(conflict.contains("\"taken\""));
+
+        let update = resource::update(&users(&repository), &request("PUT", "/users/1", body), 1);
+        let (status_line, update) = answered(update);
+        let detail = serde_json::from_str::<serde_json::Value>(&update).unwrap()["detail"].clone();
+        assert_eq!((status_line.as_str(), detail), (CONFLICT_PROBLEM, "User with ID 1 has been anonymized".into()));
+
+        let deleted = resource::delete(&users(&repository), &request("DELETE", "/users/1", ""), 1);
+        let (status_line, failure) = answered(deleted);
+        assert_eq!(status_line, INTERNAL_SERVER_ERROR);
+        assert_eq!(failure, "Error deleting user: deadlock detected");
+
+        let validate = answered(handle_validate_request(&repository, &request("POST", "/users/validate", body)));
+        assert_eq!(validate.0, UNPROCESSABLE_ENTITY);
+
+        // The Postgres features the fake doesn't implement
+        let export = answered(handle_export_request(&repository, &request("GET", "/users/1/export", ""), 1));
+        assert_eq!(export.0, NOT_IMPLEMENTED_PROBLEM);
+    }
+
+    // Nothing reaches the repository when the request itself is invalid
+    #[test]
+    fn handlers_validate_before_using_the_repository() {
+        let repository = ScriptedRepository::with_users(&["Ada"]);
+        let (invalid, malformed) = (r#"{"name": "", "email": "invalid"}"#, "{");
+
+        let invalid_post = request("POST", "/users", invalid);
+        assert_eq!(answered(resource::create(&users(&repository), &invalid_post)).0, UNPROCESSABLE_ENTITY_PROBLEM);
+        let malformed_post = request("POST", "/users", malformed);
+        assert_eq!(answered(resource::create(&users(&repository), &malformed_post)).0, BAD_REQUEST_PROBLEM);
+        let update = |body| answered(resource::update(&users(&repository), &request("PUT", "/users/1", body), 1));
+        assert_eq!(update(invalid).0, UNPROCESSABLE_ENTITY_PROBLEM);
+        assert_eq!(update(malformed).0, BAD_REQUEST_PROBLEM);
+        let password = r#"{"name": "Ada", "email": "ada@example.com", "password": "correct horse"}"#;
+        let password_change = request("PUT", "/users/1", password);
+        let changed = answered(resource::update(&users(&repository), &password_change, 1));
+        assert_eq!(changed.0, UNPROCESSABLE_ENTITY_PROBLEM);
+        let no_role = request("PUT", "/users/1/role", r#"{"role": "owner"}"#);
+        assert_eq!(answered(handle_set_role_request(&repository, &no_role, 1)).0, BAD_REQUEST_PROBLEM);
+        let malformed_validate = request("POST", "/users/validate", malformed);
+        assert_eq!(answered(handle_validate_request(&repository, &malformed_validate)).0, BAD_REQUEST_PROBLEM);
+        let invalid_page = request("GET", "/users?limit=0", "");
+        assert_eq!(answered(resource::list(&users(&repository), &invalid_page)).0, BAD_REQUEST_PROBLEM);
+        let invalid_export = request("GET", "/users/1/export?after_id=first", "");
+        assert_eq!(answered(handle_export_request(&repository, &invalid_export, 1)).0, BAD_REQUEST_PROBLEM);
+        let invalid_events = request("GET", "/events?since_id=latest", "");
+        assert_eq!(answered(handle_get_events_request(&repository, &invalid_events)).0, BAD_REQUEST_PROBLEM);
+        assert_eq!(repository.calls(), Vec::<&str>::new());
+        // Whether an email is taken is only asked once it is one
+        let invalid_email = request("POST", "/users/validate", r#"{"name": "Ada", "email": "ada@"}"#);
+        assert_eq!(answered(handle_validate_request(&repository, &invalid_email)).0, UNPROCESSABLE_ENTITY);
+        assert_eq!(repository.calls(), Vec::<&str>::new());
+        let taken = request("POST", "/users/validate", r#"{"name": "", "email": "ada@example.com"}"#);
+        let (status_line, body) = answered(handle_validate_request(&repository, &taken));
+        assert_eq!(status_line, UNPROCESSABLE_ENTITY);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["errors"][1]["code"], "taken");
+        assert_eq!(repository.calls(), ["email_taken"]);
+    }
+
+    #[test]
+    fn a_read_answers_what_the_repository_had_or_why_it_couldnt() {
+        let repository = ScriptedRepository::with_users(&["Ada"]);
+        assert_eq!(status(&get_user(&repository, 1)), 200);
+        assert_eq!(get_user(&repository, 2).0, NOT_FOUND_PROBLEM);
+        assert_eq!(repository.calls(), ["find", "find"]);
+
+        let failures: [(Failure, u16); 5] = [
+            (unavailable, 503),
+            (|| RepositoryError::Timeout("canceling statement due to statement timeout".into()), 504),
+            (|| RepositoryError::Overloaded, 503),
+            (|| RepositoryError::Unsupported("Not with this database"), 501),
+            (failed, 500),
+        ];
+        for (error, expected) in failures {
+            repository.fail("find", error);
+            repository.fail("list", error);
+            let description = error().to_string();
+            assert_eq!(status(&get_user(&repository, 1)), expected, "{}", description);
+            let listed = answered(resource::list(&users(&repository), &request("GET", "/users", "")));
+            assert_eq!(status(&listed), expected, "{}", description);
+        }
+        // What failed is said, without what of it is secret
+        repository.fail("find", || RepositoryError::Db("password=hunter2 rejected".into()));
+        assert_eq!(get_user(&repository, 1).1, "Error fetching user: password=[REDACTED] rejected");
+    }
+
+    #[test]
+    fn creating_a_user_maps_its_conflicts_to_409() {
+        let repository = ScriptedRepository::with_users(&["Ada"]);
+        let grace = request("POST", "/users", r#"{"name": "Grace", "email": "grace@example.com"}"#);
+        let created = answered(resource::create(&users(&repository), &grace));
+        assert_eq!(status(&created), 200, "{}", created.1);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&created.1).unwrap()["id"], 2);
+
+        // The email of Ada, however it is written
+        let taken = request("POST", "/users", r#"{"name": "Ada", "email": " ADA@example.com"}"#);
+        let (status_line, body) = answered(resource::create(&users(&repository), &taken));
+        assert_eq!(status_line, CONFLICT_PROBLEM);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["errors"][0]["code"], "taken");
+        assert_eq!(repository.calls(), ["create", "create"]);
+
+        repository.fail("create", || RepositoryError::Conflict(Conflict::Concurrent));
+        let (status_line, _) = answered(resource::create(&users(&repository), &grace));
+        assert!(status_line.starts_with("HTTP/1.1 409 CONFLICT\r\n") && status_line.contains("\r\nRetry-After: 1\r\n"));
+        repository.fail("create", unavailable);
+        assert_eq!(status(&answered(resource::create(&users(&repository), &grace))), 503);
+        repository.fail("create", failed);
+        let failure = answered(resource::create(&users(&repository), &grace));
+        assert_eq!(failure, (INTERNAL_SERVER_ERROR.to_owned(), "Failed to insert user into database".to_owned()));
+    }
+
+    #[test]
+    fn an_update_of_no_user_is_404() {
+        let repository = ScriptedRepository::with_users(&["Ada", "Grace"]);
+        let body = r#"{"name": "Ada Lovelace", "email": "ada@example.com"}"#;
+        let updated = answered(resource::update(&users(&repository), &request("PUT", "/users/1", body), 1));
+        assert_eq!(status(&updated), 200, "{}", updated.1);
+        assert_eq!(repository.users.find(1).unwrap().name, "Ada Lovelace");
+
+        let missing = answered(resource::update(&users(&repository), &request("PUT", "/users/3", body), 3));
+        assert_eq!(missing.0, NOT_FOUND_PROBLEM);
+        let taken = answered(resource::update(&users(&repository), &request("PUT", "/users/2", body), 2));
+        assert_eq!(taken.0, CONFLICT_PROBLEM);
+        assert_eq!(repository.users.find(2).unwrap().name, "Grace");
+
+        let dry_run = r#"{"name": "Ada Byron", "email": "ada@example.com"}"#;
+        let checked = resource::update(&users(&repository), &request("PUT", "/users/1?dry_run=true", dry_run), 1);
+        let checked = answered(checked);
+        assert_eq!(status(&checked), 200);
+        assert_eq!(repository.users.find(1).unwrap().name, "Ada Lovelace");
+        assert_eq!(repository.calls(), ["update", "update", "update", "update"]);
+
+        repository.fail("update", || RepositoryError::Conflict(Conflict::Anonymized));
+        let anonymized = resource::update(&users(&repository), &request("PUT", "/users/1", body), 1);
+        let (status_line, anonymized) = answered(anonymized);
+        assert_eq!(status_line, CONFLICT_PROBLEM);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&anonymized).unwrap()["code"], "conflict");
+        repository.fail("update", unavailable);
+        assert_eq!(status(&answered(resource::update(&users(&repository), &request("PUT", "/users/1", body), 1))), 503);
+    }
+
+    #[test]
+    fn a_user_is_deleted_once() {
+        let repository = ScriptedRepository::with_users(&["Ada"]);
+        let delete = request("DELETE", "/users/1", "");
+
+        // A dry run leaves them there
+        let dry_run = request("DELETE", "/users/1?dry_run=true", "");
+        let checked = answered(resource::delete(&users(&repository), &dry_run, 1));
+        assert_eq!(status(&checked), 200);
+        assert!(repository.users.find(1).is_ok());
+
+        let deleted = answered(resource::delete(&users(&repository), &delete, 1));
+        assert_eq!(deleted, (OK_RESPONSE.to_owned(), "\"1\"".to_owned()));
+        // Then they are gone, and deleting them again is a 404 like for any other
+        assert_eq!(answered(resource::delete(&users(&repository), &delete, 1)).0, NOT_FOUND_PROBLEM);
+        assert_eq!(get_user(&repository, 1).0, NOT_FOUND_PROBLEM);
+        assert_eq!(repository.calls(), ["delete", "delete", "delete", "find"]);
+
+        repository.fail("delete", unavailable);
+        assert_eq!(status(&answered(resource::delete(&users(&repository), &delete, 1))), 503);
+    }
+
+    #[test]
+    fn the_role_is_set_on_existing_users() {
+        let repository = ScriptedRepository::with_users(&["Ada"]);
+        let admin = r#"{"role": "admin"}"#;
+        let set = answered(handle_set_role_request(&repository, &request("PUT", "/users/1/role", admin), 1));
+        assert_eq!(set, (OK_RESPONSE.to_owned(), r#"{"id":1,"role":"admin"}"#.to_owned()));
+        let missing = answered(handle_set_role_request(&repository, &request("PUT", "/users/2/role", admin), 2));
+        assert_eq!(missing.0, NOT_FOUND_PROBLEM);
+        repository.fail("set_role", failed);
+        let failure = answered(handle_set_role_request(&repository, &request("PUT", "/users/1/role", admin), 1));
+        let expected = (INTERNAL_SERVER_ERROR.to_owned(), "Error changing the role: deadlock detected".to_owned());
+        assert_eq!(failure, expected);
+        assert_eq!(repository.calls(), ["set_role", "set_role", "set_role"]);
+    }
+
+    #[test]
+    fn the_probes_answer_503_without_the_database() {
+        let repository = ScriptedRepository::with_users(&[]);
+        let valid = request("POST", "/users/validate", r#"{"name": "Ada", "email": "ada@example.com"}"#);
+        let validated = answered(handle_validate_request(&repository, &valid));
+        assert_eq!(validated, (OK_RESPONSE.to_owned(), r#"{"valid":true}"#.to_owned()));
+        assert_eq!(status(&handle_health_request(&repository)), 200);
+        assert_eq!(status(&handle_readyz_request(&repository)), 200);
+        assert_eq!(repository.calls(), ["email_taken", "ping", "ping"]);
+
+        repository.fail("email_taken", unavailable);
+        repository.fail("ping", unavailable);
+        assert_eq!(status(&answered(handle_validate_request(&repository, &valid))), 503);
+        let (status_line, body) = handle_health_request(&repository);
+        assert_eq!(status_line, SERVICE_UNAVAILABLE);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["status"], "degraded");
+        assert_eq!(handle_readyz_request(&repository).0, SERVICE_UNAVAILABLE);
+        // What the backend doesn't have, without asking it anything else
+        let events = answered(handle_get_events_request(&repository, &request("GET", "/events", "")));
+        assert_eq!(events.0, NOT_IMPLEMENTED_PROBLEM);
+    }
+
+    #[test]
+    fn an_open_circuit_answers_503_with_retry_after() {
+        let config = CircuitConfig {
+            failure_threshold: 1,
+            failure_window: Duration::from_secs(10),
+            cool_down: Duration::from_secs(30),
+            half_open_probes: 1,
+        };
+        let circuit = Circuit::new(config);
+        let repository = CircuitBreaker::new(&circuit, &FakeRepository);
+
+        // The first connection failure opens it
+        assert!(list(&repository).starts_with("HTTP/1.1 503"));
+        let (status_line, body) = get_user(&repository, 1);
+        assert!(status_line.contains("Retry-After: 30\r\n"), "{}", status_line);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["code"], "circuit_open");
+
+        let (status_line, body) = handle_readyz_request(&repository);
+        assert_eq!(status_line, SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!((&body["ready"], &body["circuit"]), (&serde_json::json!(false), &serde_json::json!("open")));
+    }
+}
@@ -0,0 +1,230 @@
+use serde_json::{ json, Map, Value };
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{ mpsc, OnceLock };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use crate::cache::{ self, UserCache };
+use crate::config::number_from_env;
+use crate::pool::Pool;
+use crate::repository::circuit::State;
+use crate::repository::UserRepository;
+use crate::db::{ circuit, POOL };
+use crate::http::{ OK_RESPONSE, SERVICE_UNAVAILABLE };
+use crate::{ backup, migrations, redact };
+
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// The share of the pool in use from which the database is degraded
+const SATURATED: f64 = 0.9;
+
+// The share of the disk left under which the backups are degraded
+const LOW_DISK: f64 = 0.1;
+
+static CONFIG: OnceLock<HealthConfig> = OnceLock::new();
+
+// GET /health/details, unlike /health, checks every dependency: the database,
+// how long SELECT 1 takes on a connection of the pool and how much of it is in
+// use, the cache when there is one, the migrations applied against those of this
+// version, the disk of BACKUP_DIR when it is set, and the circuit breaker. Each
+// is ok, degraded or down, and the whole is the worst of them. They are checked
+// at once, those that didn't answer within HEALTH_PROBE_TIMEOUT_MS being down.
+#[derive(Clone, Debug)]
+pub struct HealthConfig {
+    pub probe_timeout: Duration,
+}
+
+impl HealthConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let probe_timeout = number_from_env("HEALTH_PROBE_TIMEOUT_MS", DEFAULT_PROBE_TIMEOUT.as_millis() as u64)?;
+        if probe_timeout == 0 {
+            return Err("HEALTH_PROBE_TIMEOUT_MS must be at least 1".to_owned());
+        }
+        Ok(HealthConfig { probe_timeout: Duration::from_millis(probe_timeout) })
+    }
+}
+
+pub fn init(config: HealthConfig) {
+    CONFIG.set(config).ok();
+}
+
+fn config() -> &'static HealthConfig {
+    CONFIG.get_or_init(|| HealthConfig { probe_timeout: DEFAULT_PROBE_TIMEOUT })
+}
+
+// Worst last
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    Ok,
+    Degraded,
+    Down,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Degraded => "degraded",
+            Status::Down => "down",
+        }
+    }
+}
+
+struct Component {
+    status: Status,
+    detail: String,
+}
+
+impl Component {
+    fn new(status: Status, detail: impl Into<String>) -> Self {
+        Component { status, detail: redact::text(&detail.into()) }
+    }
+}
+
+type Probe = Box<dyn FnOnce() -> Component + Send>;
+
+pub fn handle_details_request(repository: &dyn UserRepository) -> (String, String) {
+    let mut probes: Vec<(&str, Probe)> = Vec::new();
+    if let Some(pool) = POOL.get() {
+        probes.push(("database", Box::new(move || database(pool))));
+    }
+    if let Some(cache) = cache::cache() {
+        probes.push(("cache", Box::new(move || cache_store(cache))));
+    }
+    if let Some(pool) = POOL.get() {
+        probes.push(("migrations", Box::new(move || applied_migrations(pool))));
+    }
+    if let Some(dir) = backup::configured_dir() {
+        probes.push(("backup_disk", Box::new(move || backup_disk(dir))));
+    }
+    // On threads of their own, one that hangs is left behind
+    let deadline = Instant::now() + config().probe_timeout;
+    let answers: Vec<(&str, mpsc::Receiver<(Component, Duration)>)> = probes
+        .into_iter()
+        .map(|(name, probe)| {
+            let (answer, answered) = mpsc::channel();
+            thread::spawn(move || {
+                let started = Instant::now();
+                let component = probe();
+                answer.send((component, started.elapsed())).ok();
+            });
+            (name, answered)
+        })
+        .collect();
+
+    let mut components = Vec::new();
+    // Without a pool, the database is in the process and answers at once
+    if POOL.get().is_none() {
+        let started = Instant::now();
+        let component = match repository.ping() {
+            Ok(()) => Component::new(Status::Ok, "Answering"),
+            Err(e) => Component::new(Status::Down, e.to_string()),
+        };
+        components.push(("database", component, started.elapsed()));
+    }
+    for (name, answered) in answers {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let (component, latency) = answered.recv_timeout(left).unwrap_or_else(|_| {
+            let timeout = config().probe_timeout;
+            (Component::new(Status::Down, format!("No answer within {:?}", timeout)), timeout)
+        });
+        components.push((name, component, latency));
+    }
+    let started = Instant::now();
+    components.push(("circuit_breaker", circuit_breaker(), started.elapsed()));
+
+    let status = components.iter().map(|(_, component, _)| component.status).max().unwrap_or(Status::Ok);
+    let components: Map<String, Value> = components
+        .into_iter()
+        .map(|(name, component, latency)| {
+            let component = json!({
+                "status": component.status.name(),
+                "latency_ms": (latency.as_secs_f64() * 1_000_000.0).round() / 1000.0,
+                "detail": component.detail,
+            });
+            (name.to_owned(), component)
+        })
+        .collect();
+    let body = json!({ "status": status.name(), "components": components }).to_string();
+    match status {
+        Status::Down => (SERVICE_UNAVAILABLE.to_owned(), body),
+        _ => (OK_RESPONSE.to_owned(), body),
+    }
+}
+
+fn database(pool: &Pool) -> Component {
+    let (in_use, max_size) = (pool.stats().in_use.load(Ordering::Relaxed), pool.config().max_size);
+    let saturation = in_use as f64 / max_size.max(1) as f64;
+    let answered = pool
+        .get()
+        .map_err(|e| e.to_string())
+        .and_then(|mut client| client.simple_query("SELECT 1").map_err(|e| e.to_string()));
+    let in_use = format!("{} of {} connections in use", in_use, max_size);
+    match answered {
+        Err(e) => Component::new(Status::Down, e),
+        Ok(_) if saturation >= SATURATED => Component::new(Status::Degraded, format!("Pool saturated, {}", in_use)),
+        Ok(_) => Component::new(Status::Ok, format!("Answering, {}", in_use)),
+    }
+}
+
+fn cache_store(cache: &UserCache) -> Component {
+    match cache.check() {
+        Ok(()) => Component::new(Status::Ok, "Answering"),
+        Err(e) => Component::new(Status::Down, e),
+    }
+}
+
+fn applied_migrations(pool: &Pool) -> Component {
+    let head = match migrations::all() {
+        Ok(all) => all.last().map_or(0, |migration| migration.version),
+        Err(e) => return Component::new(Status::Down, e),
+    };
+    let applied = pool
+        .get()
+        .map_err(|e| e.to_string())
+        .and_then(|mut client| migrations::schema_version(&mut *client).map_err(|e| e.to_string()));
+    match applied {
+        Err(e) => Component::new(Status::Down, e),
+        Ok(applied) if applied < head => {
+            Component::new(Status::Degraded, format!("Version {} applied, {} waiting to be", applied, head))
+        }
+        Ok(applied) if applied > head => {
+            Component::new(Status::Degraded, format!("Version {} applied, newer than this version's {}", applied, head))
+        }
+        Ok(applied) => Component::new(Status::Ok, format!("Version {} applied", applied)),
+    }
+}
+
+fn backup_disk(dir: &Path) -> Component {
+    let free = CString::new(dir.as_os_str().as_bytes()).map_err(io::Error::other).and_then(|path| {
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        match unsafe { libc::statvfs(path.as_ptr(), &mut stats) } {
+            0 => Ok((stats.f_bavail * stats.f_frsize, stats.f_blocks * stats.f_frsize)),
+            _ => Err(io::Error::last_os_error()),
+        }
+    });
+    match free {
+        Err(e) => Component::new(Status::Down, format!("Can't check {}: {}", dir.display(), e)),
+        Ok((free, total)) => {
+            let detail = format!("{} MiB free of {} MiB", free >> 20, total >> 20);
+            match (free as f64) < total as f64 * LOW_DISK {
+                true => Component::new(Status::Degraded, format!("Low on space, {}", detail)),
+                false => Component::new(Status::Ok, detail),
+            }
+        }
+    }
+}
+
+fn circuit_breaker() -> Component {
+    let state = circuit().state();
+    let status = match state {
+        State::Closed => Status::Ok,
+        State::HalfOpen => Status::Degraded,
+        State::Open => Status::Down,
+    };
+    Component::new(status, state.name())
+}
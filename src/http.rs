@@ -0,0 +1,560 @@
+use std::io::{ self, IoSlice, Read, Write };
+use std::sync::OnceLock;
+
+use crate::config::number_from_env;
+use crate::errors::{ logged_error, Malformed };
+use crate::{ access_log, body_log, json_case, locale, request_id, security_headers, trace_context };
+
+// As much of the head of a request as is read, its request line included
+pub(crate) const HEAD_BYTES: usize = 8 * 1024;
+
+// As much of its body, unless MAX_BODY_BYTES says otherwise, a longer one being
+// answered with a 413 unread
+pub(crate) const DEFAULT_BODY_BYTES: usize = 64 * 1024;
+
+static BODY_BYTES: OnceLock<usize> = OnceLock::new();
+
+pub(crate) const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n";
+
+pub(crate) const BAD_REQUEST: &str = "HTTP/1.1 400 BAD REQUEST\r\n\r\n";
+
+pub(crate) const BAD_REQUEST_PROBLEM: &str =
+    "HTTP/1.1 400 BAD REQUEST\r\nContent-Type: application/problem+json\r\n\r\n";
+
+pub(crate) const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\n\r\n";
+
+pub(crate) const NOT_FOUND_PROBLEM: &str = "HTTP/1.1 404 NOT FOUND\r\nContent-Type: application/problem+json\r\n\r\n";
+
+pub(crate) const CONFLICT: &str = "HTTP/1.1 409 CONFLICT\r\n\r\n";
+
+pub(crate) const CONFLICT_PROBLEM: &str = "HTTP/1.1 409 CONFLICT\r\nContent-Type: application/problem+json\r\n\r\n";
+
+pub(crate) const CONFLICT_RETRY_PROBLEM: &str =
+    "HTTP/1.1 409 CONFLICT\r\nContent-Type: application/problem+json\r\nRetry-After: 1\r\n\r\n";
+
+pub(crate) const REQUEST_TIMEOUT_PROBLEM: &str =
+    "HTTP/1.1 408 REQUEST TIMEOUT\r\nContent-Type: application/problem+json\r\nConnection: close\r\n\r\n";
+
+pub(crate) const PAYLOAD_TOO_LARGE_PROBLEM: &str =
+    "HTTP/1.1 413 PAYLOAD TOO LARGE\r\nContent-Type: application/problem+json\r\nConnection: close\r\n\r\n";
+
+pub(crate) const URI_TOO_LONG_PROBLEM: &str =
+    "HTTP/1.1 414 URI TOO LONG\r\nContent-Type: application/problem+json\r\n\r\n";
+
+pub(crate) const UNPROCESSABLE_ENTITY: &str = "HTTP/1.1 422 UNPROCESSABLE ENTITY\r\n\r\n";
+
+pub(crate) const UNPROCESSABLE_ENTITY_PROBLEM: &str =
+    "HTTP/1.1 422 UNPROCESSABLE ENTITY\r\nContent-Type: application/problem+json\r\n\r\n";
+
+pub(crate) const INTERNAL_SERVER_ERROR: &str = "HTTP/1.1 500 INTERNAL SERVER ERROR\r\n\r\n";
+
+pub(crate) const NOT_IMPLEMENTED: &str = "HTTP/1.1 501 NOT IMPLEMENTED\r\n\r\n";
+
+pub(crate) const NOT_IMPLEMENTED_PROBLEM: &str =
+    "HTTP/1.1 501 NOT IMPLEMENTED\r\nContent-Type: application/problem+json\r\n\r\n";
+
+pub(crate) const OVERLOADED: &str =
+    "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Type: application/json\r\nRetry-After: 1\r\n\r\n";
+
+pub(crate) const OVERLOADED_PROBLEM: &str =
+    "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Type: application/problem+json\r\nRetry-After: 1\r\n\r\n";
+
+pub(crate) const SERVICE_UNAVAILABLE: &str = "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nRetry-After: 5\r\n\r\n";
+
+pub(crate) const SERVICE_UNAVAILABLE_PROBLEM: &str =
+    "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Type: application/problem+json\r\nRetry-After: 5\r\n\r\n";
+
+pub(crate) const GATEWAY_TIMEOUT_PROBLEM: &str =
+    "HTTP/1.1 504 GATEWAY TIMEOUT\r\nContent-Type: application/problem+json\r\n\r\n";
+
+pub fn body_bytes_from_env() -> Result<usize, String> {
+    match number_from_env("MAX_BODY_BYTES", DEFAULT_BODY_BYTES as u64)? {
+        0 => Err("MAX_BODY_BYTES must be at least 1".to_owned()),
+        bytes => Ok(bytes as usize),
+    }
+}
+
+pub fn init(body_bytes: usize) {
+    BODY_BYTES.set(body_bytes).ok();
+}
+
+pub(crate) fn body_bytes() -> usize {
+    BODY_BYTES.get().copied().unwrap_or(DEFAULT_BODY_BYTES)
+}
+
+// As much of a request as is read
+pub(crate) fn request_bytes() -> usize {
+    HEAD_BYTES + body_bytes()
+}
+
+// The status line and headers with one more header
+pub(crate) fn with_header(status_line: &str, header: &str) -> String {
+    let headers = status_line.strip_suffix("\r\n\r\n").unwrap_or(status_line);
+    format!("{}\r\n{}\r\n\r\n", headers, header)
+}
+
+// The status line and headers, the length of the body and the security headers
+// added, then the body, written without copying them into one buffer first
+pub(crate) fn write_response(stream: &mut impl Write, status_line: &str, content: impl AsRef<[u8]>) -> io::Result<()> {
+    let logged = status_line.starts_with("HTTP/1.1 500").then(|| logged_error(status_line, content.as_ref()));
+    let (status_line, content) = match &logged {
+        Some((status_line, content)) => (status_line.as_str(), content.as_bytes()),
+        None => (status_line, content.as_ref()),
+    };
+    let content = json_case::outbound(status_line, content);
+    let content = content.as_ref();
+    body_log::response(status_line, content);
+    let status_line = with_header(status_line, &format!("Content-Length: {}", content.len()));
+    let head = locale::added(&trace_context::added(&request_id::added(&security_headers::added(&status_line))));
+    let bytes = head.len() + content.len();
+    let written = tracing::info_span!("write", bytes)
+        .in_scope(|| write_slices(stream, &mut [IoSlice::new(head.as_bytes()), IoSlice::new(content)]));
+    logged_write(&written, &head, bytes);
+    written
+}
+
+// What the access log counts of a write of the head, or of a part after it
+fn logged_write(written: &io::Result<()>, head: &str, bytes: usize) {
+    match written {
+        Ok(()) => access_log::responded(head, bytes),
+        Err(_) => access_log::dropped(),
+    }
+}
+
+// With as few writes as the stream takes, the small ones would wait for the
+// acknowledgement of the one before otherwise
+fn write_slices(stream: &mut impl Write, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match stream.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// The path of the target of a request, without its query nor its fragment, nor
+// the scheme and host of a target in absolute form, as proxies may send it
+pub(crate) fn get_path(request: &str) -> &str {
+    let target = request.split_whitespace().nth(1).unwrap_or_default();
+    let target = match target.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") => {
+            rest.find(['/', '?', '#']).map_or("", |start| &rest[start..])
+        }
+        _ => target,
+    };
+    target.split(['?', '#']).next().unwrap_or_default()
+}
+
+// The segments of the path the routes are matched on. The empty ones are left
+// out, so a trailing or a doubled slash routes as without it, and nothing is
+// decoded nor resolved: /users/%31 and /users/./1 are not /users/1.
+pub(crate) fn get_segments(request: &str) -> Vec<&str> {
+    get_path(request).split('/').filter(|segment| !segment.is_empty()).collect()
+}
+
+// User ids are written as plain decimal numbers in 1..=i32::MAX: no sign, no
+// leading zeros, so every user has exactly one URL
+pub(crate) fn parse_id(segment: &str) -> Result<i32, &'static str> {
+    let reason = if segment.is_empty() {
+        "id must not be empty"
+    } else if !segment.bytes().all(|byte| byte.is_ascii_digit()) {
+        "id must be a positive whole number"
+    } else if segment.bytes().all(|byte| byte == b'0') {
+        "id must be at least 1"
+    } else if segment.starts_with('0') {
+        "id must be a positive whole number without leading zeros"
+    } else {
+        match segment.parse::<i32>() {
+            Ok(id) => {
+                return Ok(id);
+            }
+            Err(_) => "id is larger than any user id",
+        }
+    };
+    Err(reason)
+}
+
+// What keeps a request from being routed, size being how much of it was read: a
+// request line that doesn't fit in HEAD_BYTES, or isn't one, a head that doesn't,
+// or a body announced longer than body_bytes()
+pub(crate) fn malformed(request: &str, size: usize) -> Option<Malformed> {
+    let head_full = size >= HEAD_BYTES;
+    if request.find('\n').map_or(head_full, |end| end >= HEAD_BYTES) {
+        return Some(Malformed::RequestLineTooLong);
+    }
+    let request_line = request.split('\n').next().unwrap_or_default().trim_end_matches('\r');
+    let tchar = |byte: u8| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte);
+    let absolute = |target: &str| {
+        target.split_once("://").is_some_and(|(scheme, _)| {
+            scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https")
+        })
+    };
+    let well_formed = match request_line.split(' ').collect::<Vec<_>>().as_slice() {
+        [method, target, version] => {
+            !method.is_empty() && method.bytes().all(tchar)
+                && (target.starts_with('/') || absolute(target))
+                && version.starts_with("HTTP/1.")
+        }
+        _ => false,
+    };
+    if !well_formed {
+        return Some(Malformed::RequestLine);
+    }
+    let Some(end) = request.find("\r\n\r\n") else {
+        return head_full.then_some(Malformed::HeaderTooLarge);
+    };
+    if end + 4 > HEAD_BYTES {
+        return Some(Malformed::HeaderTooLarge);
+    }
+    let length = get_header(&request[..end + 2], "Content-Length").and_then(|length| length.parse::<usize>().ok());
+    length.is_some_and(|length| length > body_bytes()).then_some(Malformed::BodyTooLarge)
+}
+
+// Read the request into buffer, however many reads it arrives in: until its head
+// ended and as much of the body as its Content-Length says followed, either is
+// past its limit, the buffer is full, or the client stops sending. The size read, or the error of a read
+// before anything was, or of the read that timed out, whatever came before it.
+pub(crate) fn read_request(stream: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < buffer.len() && !complete(&buffer[..size]) {
+        match stream.read(&mut buffer[size..]) {
+            Ok(0) => break,
+            Ok(read) => size += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            // Not the request it announced, which the client had the time to send
+            Err(e) if timed_out(&e) => return Err(e),
+            // Answered with what came, which may be malformed
+            Err(_) if size > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(size)
+}
+
+// The error of a read past the read timeout of the stream
+pub(crate) fn timed_out(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+// Whether the head of what was read ended, and the body it announces followed, or
+// either is too long to, which malformed answers without reading the rest
+fn complete(read: &[u8]) -> bool {
+    let Some(end) = read.windows(4).position(|window| window == b"\r\n\r\n") else {
+        return read.len() >= HEAD_BYTES;
+    };
+    let head = String::from_utf8_lossy(&read[..end + 2]);
+    let length = get_header(&head, "Content-Length").and_then(|length| length.parse::<usize>().ok());
+    let too_long = end + 4 > HEAD_BYTES || length.is_some_and(|length| length > body_bytes());
+    too_long || read.len() - (end + 4) >= length.unwrap_or(0)
+}
+
+pub(crate) fn get_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .split("\r\n")
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+// The query of the target of a request, without its fragment, empty without one
+pub(crate) fn get_query(request: &str) -> &str {
+    let target = request.split_whitespace().nth(1).unwrap_or_default();
+    let target = target.split('#').next().unwrap_or_default();
+    target.split_once('?').map_or("", |(_, query)| query)
+}
+
+// The value of the first parameter named name, as it was sent, for
+// decode_query_value. Its key is compared decoded, and those without an = have no
+// value.
+pub(crate) fn get_query_param<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    get_query(request)
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name || decode_query_value(key) == name)
+        .map(|(_, value)| value)
+}
+
+// Undo the percent-encoding of a query string value, '+' standing for a space
+pub(crate) fn decode_query_value(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok().filter(|hex| {
+                    hex.bytes().all(|byte| byte.is_ascii_hexdigit())
+                });
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// All that follows the head, blank lines in it included, nothing when the head
+// has no end
+pub(crate) fn get_body(request: &str) -> &str {
+    request.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+}
+
+// The JSON of a response body, in a serialize span
+pub(crate) fn serialized(value: &impl serde::Serialize) -> String {
+    let _serializing = tracing::info_span!("serialize").entered();
+    serde_json::to_string(value).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oidc;
+    use crate::properties::{ check, without_one, without_one_char, Cases };
+
+    // Takes a few bytes at a time
+    struct Trickle(Vec<u8>);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let size = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..size]);
+            Ok(size)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn responses_are_written_in_full() {
+        let mut written = Trickle(Vec::new());
+        write_response(&mut written, OK_RESPONSE, r#"{"id":1}"#).unwrap();
+        let head = with_header(OK_RESPONSE, "Content-Length: 8");
+        assert_eq!(String::from_utf8(written.0).unwrap(), format!("{}{{\"id\":1}}", head));
+
+        let mut written = Trickle(Vec::new());
+        write_response(&mut written, NOT_FOUND, "").unwrap();
+        assert_eq!(written.0, with_header(NOT_FOUND, "Content-Length: 0").as_bytes());
+    }
+
+    // Sends a few bytes at a time, then what then says: the end of the stream, an
+    // error, or nothing ever, which the reads mustn't wait for
+    struct Packets<'a>(Vec<&'a [u8]>, Option<io::ErrorKind>);
+
+    impl Read for Packets<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return match self.1 {
+                    Some(io::ErrorKind::UnexpectedEof) => Ok(0),
+                    Some(kind) => Err(kind.into()),
+                    None => panic!("read past the request"),
+                };
+            }
+            let packet = self.0.remove(0);
+            let size = packet.len().min(buf.len());
+            buf[..size].copy_from_slice(&packet[..size]);
+            if size < packet.len() {
+                self.0.insert(0, &packet[size..]);
+            }
+            Ok(size)
+        }
+    }
+
+    fn read(packets: &[&[u8]], then: Option<io::ErrorKind>, limit: usize) -> io::Result<String> {
+        let mut buffer = vec![0; limit];
+        let size = read_request(&mut Packets(packets.to_vec(), then), &mut buffer)?;
+        Ok(String::from_utf8(buffer[..size].to_vec()).unwrap())
+    }
+
+    #[test]
+    fn requests_are_read_across_packets() {
+        let head: [&'static [u8]; 2] = [b"POST /users HTTP/1.1\r\nHo", b"st: localhost\r\nContent-Length: 7\r\n"];
+        let post = [head[0], head[1], b"\r\n{\"a\"", b":1}"];
+        let request = "POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Length: 7\r\n\r\n{\"a\":1}";
+        assert_eq!(read(&post, None, request_bytes()).unwrap(), request);
+        let get = read(&[b"GET /users HTTP/1.1\r\n", b"\r\n"], None, request_bytes());
+        assert_eq!(get.unwrap(), "GET /users HTTP/1.1\r\n\r\n");
+
+        // Up to the limit, and what came before the client stopped or failed
+        assert_eq!(read(&post, None, 16).unwrap(), &request[..16]);
+        let cut = head.concat();
+        let eof = Some(io::ErrorKind::UnexpectedEof);
+        assert_eq!(read(&head, eof, request_bytes()).unwrap().as_bytes(), cut);
+        let timed_out = read(&head, Some(io::ErrorKind::WouldBlock), request_bytes());
+        assert_eq!(timed_out.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        let reset = read(&[], Some(io::ErrorKind::ConnectionReset), request_bytes());
+        assert_eq!(reset.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+
+        // Not waiting for a body that won't be read
+        let announced = format!("POST /users HTTP/1.1\r\nContent-Length: {}\r\n\r\n", DEFAULT_BODY_BYTES + 1);
+        assert_eq!(read(&[announced.as_bytes()], None, request_bytes()).unwrap(), announced);
+    }
+
+    #[test]
+    fn the_parts_of_a_request_are_read_from_it() {
+        let request = "GET /users/7?email=ada%40example.com&name_contains=Ada+L&flag HTTP/1.1\r\n\
+            Host: localhost\r\nX-Read-Primary:  true \r\n\r\n{\"name\": \"Ada\"}";
+        assert_eq!(get_path(request), "/users/7");
+        assert_eq!(get_path("GET /users/7#top HTTP/1.1\r\n\r\n"), "/users/7");
+        assert_eq!(get_path("GET http://localhost:8080/users/7?limit=1 HTTP/1.1\r\n\r\n"), "/users/7");
+        assert_eq!(get_path("GET /users?next=http://localhost/users/7 HTTP/1.1\r\n\r\n"), "/users");
+        assert_eq!(get_header(request, "x-read-primary"), Some("true"));
+        assert_eq!(get_header(request, "Accept"), None);
+        assert_eq!(get_query_param(request, "email").map(decode_query_value).as_deref(), Some("ada@example.com"));
+        assert_eq!(get_query_param(request, "name_contains").map(decode_query_value).as_deref(), Some("Ada L"));
+        assert_eq!(get_query_param(request, "flag"), None);
+        assert_eq!(decode_query_value("100%"), "100%");
+        assert_eq!(decode_query_value("%+1%-f"), "% 1%-f");
+        assert_eq!(get_body(request), "{\"name\": \"Ada\"}");
+        assert_eq!(get_body("POST /users HTTP/1.1\r\n\r\n{\r\n\r\n}"), "{\r\n\r\n}");
+        assert_eq!(get_body("POST /users HTTP/1.1\r\nHost: loc"), "");
+        // What the body says isn't a header
+        assert_eq!(get_header("POST /users HTTP/1.1\r\n\r\nHost: localhost", "Host"), None);
+        assert_eq!(with_header(BAD_REQUEST, "Retry-After: 1"), "HTTP/1.1 400 BAD REQUEST\r\nRetry-After: 1\r\n\r\n");
+    }
+
+    // What the keys and values written in a query are made of
+    const QUERY_CHARS: [char; 16] = ['a', 'b', '_', '-', ' ', '&', '=', '+', '%', '#', '?', '/', 'é', '日', '🦀', '~'];
+
+    // The parameters of a query, and whether its target has a fragment after it
+    #[derive(Clone, Debug)]
+    struct Query {
+        params: Vec<(String, String)>,
+        fragment: bool,
+    }
+
+    impl Query {
+        fn generate(cases: &mut Cases) -> Query {
+            let text = |cases: &mut Cases| cases.some(4, |cases| cases.pick(&QUERY_CHARS)).into_iter().collect();
+            Query { params: cases.some(5, |cases| (text(cases), text(cases))), fragment: cases.one_in(4) }
+        }
+
+        fn shrink(&self) -> Vec<Query> {
+            let mut smaller: Vec<Query> =
+                without_one(&self.params).into_iter().map(|params| Query { params, ..self.clone() }).collect();
+            for (index, (key, value)) in self.params.iter().enumerate() {
+                let with = |key: String, value: String| {
+                    let mut params = self.params.clone();
+                    params[index] = (key, value);
+                    Query { params, ..self.clone() }
+                };
+                smaller.extend(without_one_char(key).into_iter().map(|key| with(key, value.clone())));
+                smaller.extend(without_one_char(value).into_iter().map(|value| with(key.clone(), value)));
+            }
+            smaller.extend(self.fragment.then(|| Query { fragment: false, ..self.clone() }));
+            smaller
+        }
+    }
+
+    #[test]
+    fn query_parameters_are_read_back_as_they_were_written() {
+        check(0x9e41, Query::generate, Query::shrink, |query| {
+            let params: Vec<(&str, &str)> =
+                query.params.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+            let fragment = if query.fragment { "#top" } else { "" };
+            let request = format!("GET /users?{}{} HTTP/1.1\r\nHost: localhost\r\n\r\n", oidc::form(&params), fragment);
+            // The first value of each key, empty ones included
+            params.iter().all(|(key, _)| {
+                let first = params.iter().find(|(each, _)| each == key).map(|(_, value)| value.to_string());
+                get_query_param(&request, key).map(decode_query_value) == first
+            })
+        });
+    }
+
+    #[test]
+    fn percent_decoding_undoes_the_encoding() {
+        // Each character as it is, or encoded when it could be read as something else
+        let generate = |cases: &mut Cases| cases.some(8, |cases| (cases.pick(&QUERY_CHARS), cases.one_in(2)));
+        check(0xdec0de, generate, |chars| without_one(chars), |chars| {
+            let encoded: String = chars
+                .iter()
+                .map(|(char, encoded)| match char {
+                    '%' | '+' | '&' | '=' | '#' | ' ' => oidc::encode(&char.to_string()),
+                    char if *encoded => oidc::encode(&char.to_string()),
+                    char => char.to_string(),
+                })
+                .collect();
+            decode_query_value(&encoded) == chars.iter().map(|(char, _)| char).collect::<String>()
+        });
+    }
+
+    #[test]
+    fn a_fragment_isnt_part_of_the_last_parameter() {
+        let request = "GET /users?limit=5#top HTTP/1.1\r\n\r\n";
+        assert_eq!(get_query_param(request, "limit"), Some("5"));
+        assert_eq!(get_query_param("GET /users#top?limit=5 HTTP/1.1\r\n\r\n", "limit"), None);
+    }
+
+    #[test]
+    fn an_encoded_key_names_its_parameter() {
+        let request = "GET /users?name%5Fcontains=Ada&%C3%A9=%C3%A9 HTTP/1.1\r\n\r\n";
+        assert_eq!(get_query_param(request, "name_contains"), Some("Ada"));
+        assert_eq!(get_query_param(request, "é").map(decode_query_value).as_deref(), Some("é"));
+    }
+
+    #[test]
+    fn a_user_has_one_id_in_the_path() {
+        assert_eq!(parse_id("42"), Ok(42));
+        assert_eq!(parse_id("2147483647"), Ok(i32::MAX));
+        let refused = [
+            ("", "id must not be empty"),
+            ("-1", "id must be a positive whole number"),
+            ("0", "id must be at least 1"),
+            ("007", "id must be a positive whole number without leading zeros"),
+            ("2147483648", "id is larger than any user id"),
+        ];
+        for (segment, reason) in refused {
+            assert_eq!(parse_id(segment), Err(reason), "{}", segment);
+        }
+    }
+
+    #[test]
+    fn a_request_is_routed_once_its_request_line_and_head_are_whole() {
+        let malformed = |request: &str| match malformed(request, request.len()) {
+            None => "",
+            Some(Malformed::RequestLineTooLong) => "request_line_too_long",
+            Some(Malformed::RequestLine) => "bad_request_line",
+            Some(Malformed::HeaderTooLarge) => "header_too_large",
+            Some(Malformed::BodyTooLarge) => "body_too_large",
+            Some(Malformed::InvalidId(..) | Malformed::TimedOut) => unreachable!(),
+        };
+        assert_eq!(malformed("GET /users HTTP/1.1\r\nHost: localhost\r\n\r\n"), "");
+        assert_eq!(malformed("get http://localhost/users?limit=5 HTTP/1.0\r\n\r\n"), "");
+        // As much as was read so far
+        assert_eq!(malformed("POST /users HTTP/1.1\r\nContent-Le"), "");
+        let request_lines = ["NONSENSE", "GET /users", "GET  /users HTTP/1.1", "GET users HTTP/1.1", "GET / HTTP/2"];
+        for request_line in request_lines.into_iter().chain(["G(T / HTTP/1.1"]) {
+            assert_eq!(malformed(&format!("{}\r\n\r\n", request_line)), "bad_request_line", "{}", request_line);
+        }
+        assert_eq!(malformed(""), "bad_request_line");
+
+        let long = format!("GET /{} HTTP/1.1", "a".repeat(HEAD_BYTES));
+        assert_eq!(malformed(&long[..HEAD_BYTES]), "request_line_too_long");
+        let headers = format!("GET /users HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(HEAD_BYTES));
+        assert_eq!(malformed(&headers[..HEAD_BYTES]), "header_too_large");
+        assert_eq!(malformed(&headers), "header_too_large");
+        // With the head whole, the rest is the body, up to MAX_BODY_BYTES of it
+        let body = format!("POST /users HTTP/1.1\r\n\r\n{}", "a".repeat(HEAD_BYTES));
+        assert_eq!(malformed(&body), "");
+        let announced = |length: usize| format!("POST /graphql HTTP/1.1\r\nContent-Length: {}\r\n\r\n", length);
+        assert_eq!(malformed(&announced(DEFAULT_BODY_BYTES)), "");
+        assert_eq!(malformed(&announced(DEFAULT_BODY_BYTES + 1)), "body_too_large");
+    }
+}
@@ -0,0 +1,297 @@
+use openssl::nid::Nid;
+use openssl::ssl::{ ErrorCode, SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode };
+use openssl::x509::{ X509Ref, X509 };
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ self, Read, Write };
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream };
+use std::os::fd::{ AsRawFd, RawFd };
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::auth::Role;
+use crate::config::{ self, number_from_env };
+use crate::shutdown;
+
+// How long a client has to finish the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long a read of the client waits for the rest of a record, while what the
+// server writes waits to be relayed
+const RECORD_WAIT: Duration = Duration::from_millis(50);
+
+// The connections relayed to the server, by the address the server sees them
+// from, until they are closed
+static RELAYED: Mutex<Option<HashMap<SocketAddr, Relayed>>> = Mutex::new(None);
+
+thread_local! {
+    // Who the certificate of the request being handled was issued to, set by enter
+    static CURRENT: RefCell<Option<(String, Role)>> = const { RefCell::new(None) };
+}
+
+// With TLS_PORT, HTTPS on that port of BIND_ADDRESS as well, with the certificate
+// chain of TLS_CERT_PATH and the key of TLS_KEY_PATH, in PEM. With
+// TLS_CLIENT_CA_PATH the clients are asked for a certificate signed by one of the
+// CAs of that bundle, and one that isn't is refused in the handshake:
+// TLS_CLIENT_CERT=required (the default) refuses a client without any, optional
+// lets it authenticate otherwise. The CN of the certificate, or else its first
+// subject alternative name, is the principal of its requests, with the role of
+// TLS_CLIENT_ROLE, admin by default as for the keys.
+#[derive(Clone)]
+pub struct HttpsConfig {
+    pub port: u16,
+    pub client_certs: Option<ClientCerts>,
+    acceptor: SslAcceptor,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientCerts {
+    pub required: bool,
+    pub role: Role,
+}
+
+// Never shows the key
+impl std::fmt::Debug for HttpsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("HttpsConfig")
+            .field("port", &self.port)
+            .field("client_certs", &self.client_certs)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HttpsConfig {
+    // None without TLS_PORT, when only HTTP is served
+    pub fn from_env() -> Result<Option<Self>, String> {
+        if config::var("TLS_PORT").is_err() {
+            return Ok(None);
+        }
+        let port = number_from_env("TLS_PORT", 0)?;
+        let port = u16::try_from(port).map_err(|_| format!("TLS_PORT must be at most {}, got {}", u16::MAX, port))?;
+        let (Ok(cert_path), Ok(key_path)) = (config::var("TLS_CERT_PATH"), config::var("TLS_KEY_PATH")) else {
+            return Err("TLS_PORT needs TLS_CERT_PATH and TLS_KEY_PATH".to_owned());
+        };
+        let ca_path = config::var("TLS_CLIENT_CA_PATH").ok();
+        let required = match config::var("TLS_CLIENT_CERT").as_deref() {
+            Ok("required") | Err(_) => true,
+            Ok("optional") => false,
+            Ok(value) => return Err(format!("TLS_CLIENT_CERT must be required or optional, got {:?}", value)),
+        };
+        let role = match config::var("TLS_CLIENT_ROLE") {
+            Ok(role) => role.parse().map_err(|e| format!("TLS_CLIENT_ROLE: {}", e))?,
+            Err(_) => Role::Admin,
+        };
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())
+            .map_err(|e| format!("Can't set up TLS: {}", e))?;
+        acceptor
+            .set_certificate_chain_file(&cert_path)
+            .map_err(|e| format!("Can't read TLS_CERT_PATH {}: {}", cert_path, e))?;
+        acceptor
+            .set_private_key_file(&key_path, SslFiletype::PEM)
+            .map_err(|e| format!("Can't read TLS_KEY_PATH {}: {}", key_path, e))?;
+        acceptor
+            .check_private_key()
+            .map_err(|e| format!("TLS_KEY_PATH must be the key of the certificate of TLS_CERT_PATH: {}", e))?;
+        let client_certs = match ca_path {
+            Some(ca_path) => {
+                let bundle =
+                    fs::read(&ca_path).map_err(|e| format!("Can't read TLS_CLIENT_CA_PATH {}: {}", ca_path, e))?;
+                let cas = X509::stack_from_pem(&bundle)
+                    .ok()
+                    .filter(|cas| !cas.is_empty())
+                    .ok_or_else(|| format!("TLS_CLIENT_CA_PATH {} must hold certificates in PEM", ca_path))?;
+                acceptor
+                    .set_ca_file(&ca_path)
+                    .map_err(|e| format!("Can't read TLS_CLIENT_CA_PATH {}: {}", ca_path, e))?;
+                // The CAs the clients are told to pick a certificate of
+                for ca in cas {
+                    acceptor
+                        .add_client_ca(&ca)
+                        .map_err(|e| format!("Invalid TLS_CLIENT_CA_PATH {}: {}", ca_path, e))?;
+                }
+                let verify = match required {
+                    true => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+                    false => SslVerifyMode::PEER,
+                };
+                acceptor.set_verify(verify);
+                Some(ClientCerts { required, role })
+            }
+            None if config::var("TLS_CLIENT_CERT").is_ok() || config::var("TLS_CLIENT_ROLE").is_ok() => {
+                return Err("TLS_CLIENT_CERT and TLS_CLIENT_ROLE need TLS_CLIENT_CA_PATH".to_owned());
+            }
+            None => None,
+        };
+        Ok(Some(HttpsConfig { port, client_certs, acceptor: acceptor.build() }))
+    }
+}
+
+// A connection decrypted and relayed to the server
+struct Relayed {
+    client: SocketAddr,
+    // The subject of its certificate, with the role it is given
+    certificate: Option<(String, Role)>,
+}
+
+// Listen on the port of the config, on the address of the server, relaying each
+// connection to the server once decrypted, on a thread of its own. The server
+// answers it as any other, knowing the client and its certificate by the address
+// of the relay. The listener is returned to be stopped as the server's.
+pub fn start(config: HttpsConfig, server: SocketAddr) -> io::Result<RawFd> {
+    let listener = TcpListener::bind(SocketAddr::new(server.ip(), config.port))?;
+    let listening = listener.as_raw_fd();
+    // The server is reached on the loopback when it listens on every address
+    let server = match server.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), server.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), server.port()),
+        _ => server,
+    };
+    thread::spawn(move || accept(listener, &config, server));
+    Ok(listening)
+}
+
+fn accept(listener: TcpListener, config: &HttpsConfig, server: SocketAddr) {
+    loop {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(_) if shutdown::draining() => break,
+            Err(e) => {
+                log::error!("Error accepting a TLS connection: {}", e);
+                continue;
+            }
+        };
+        let (acceptor, role) = (config.acceptor.clone(), config.client_certs.as_ref().map(|certs| certs.role));
+        thread::spawn(move || relay(&acceptor, role, stream, server));
+    }
+}
+
+// Refused in the handshake when the certificate the client was asked for isn't
+// one of the CAs', or when it has none and one is required
+fn relay(acceptor: &SslAcceptor, role: Option<Role>, stream: TcpStream, server: SocketAddr) {
+    let Ok(client) = stream.peer_addr() else {
+        return;
+    };
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok();
+    let mut tls = match acceptor.accept(stream) {
+        Ok(tls) => tls,
+        Err(e) => {
+            log::warn!("TLS handshake with {} failed: {}", client, e);
+            return;
+        }
+    };
+    let certificate = tls.ssl().peer_certificate().and_then(|certificate| subject(&certificate)).zip(role);
+    let relayed = TcpStream::connect(server).and_then(|relayed| Ok((relayed.local_addr()?, relayed)));
+    let (relay, relayed) = match relayed {
+        Ok(relayed) => relayed,
+        Err(e) => {
+            log::error!("Error relaying the TLS connection of {} to {}: {}", client, server, e);
+            tls.shutdown().ok();
+            return;
+        }
+    };
+    RELAYED.lock().unwrap().get_or_insert_with(HashMap::new).insert(relay, Relayed { client, certificate });
+    tls.get_ref().set_read_timeout(Some(RECORD_WAIT)).ok();
+    pump(&mut tls, relayed);
+    RELAYED.lock().unwrap().get_or_insert_with(HashMap::new).remove(&relay);
+    tls.shutdown().ok();
+}
+
+// Copy what either side sends to the other until the server closes the
+// connection, or the client can't be written to anymore. When the client is done
+// sending, so is the relay to the server.
+fn pump(tls: &mut SslStream<TcpStream>, mut relayed: TcpStream) {
+    let mut buffer = [0; 16 * 1024];
+    let mut from_client = true;
+    loop {
+        // What openssl decrypted already is ready without the socket being so
+        let pending = from_client && tls.ssl().pending() > 0;
+        // A negative fd is left out
+        let client = if from_client { tls.get_ref().as_raw_fd() } else { -1 };
+        let mut fds = [
+            libc::pollfd { fd: client, events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: relayed.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+        if !pending && unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return;
+        }
+
+        if pending || fds[0].revents != 0 {
+            match tls.ssl_read(&mut buffer) {
+                Ok(read) => {
+                    if relayed.write_all(&buffer[..read]).is_err() {
+                        return;
+                    }
+                }
+                // Only part of a record came
+                Err(e) if e.code() == ErrorCode::WANT_READ => {}
+                Err(_) => {
+                    from_client = false;
+                    relayed.shutdown(Shutdown::Write).ok();
+                }
+            }
+        }
+        if fds[1].revents != 0 {
+            match relayed.read(&mut buffer) {
+                Ok(read) if read > 0 => {
+                    if tls.write_all(&buffer[..read]).is_err() {
+                        return;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+// The CN of the certificate, or else the first of its DNS names, emails and URIs
+fn subject(certificate: &X509Ref) -> Option<String> {
+    let common_name = certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().to_string().ok());
+    common_name.or_else(|| {
+        certificate.subject_alt_names()?.iter().find_map(|name| {
+            name.dnsname().or_else(|| name.email()).or_else(|| name.uri()).map(str::to_owned)
+        })
+    })
+}
+
+// The address of the client of the connection, which is that of the relay when it
+// came over TLS
+pub fn peer_addr(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let peer = stream.peer_addr()?;
+    let relayed = RELAYED.lock().unwrap();
+    Ok(relayed.as_ref().and_then(|relayed| relayed.get(&peer)).map_or(peer, |relayed| relayed.client))
+}
+
+// Take the certificate the client of the connection authenticated with, if any,
+// as that of the request handled on this thread, until the returned guard is
+// dropped
+pub fn enter(stream: &TcpStream) -> Entered {
+    let certificate = stream.peer_addr().ok().and_then(|peer| {
+        let relayed = RELAYED.lock().unwrap();
+        relayed.as_ref()?.get(&peer)?.certificate.clone()
+    });
+    CURRENT.set(certificate);
+    Entered
+}
+
+pub struct Entered;
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.set(None);
+    }
+}
+
+// Who the verified certificate of the request being handled was issued to, and
+// the role that gives them
+pub fn client_certificate() -> Option<(String, Role)> {
+    CURRENT.with_borrow(Clone::clone)
+}
@@ -0,0 +1,130 @@
+use postgres::Error as PostgresError;
+use postgres::GenericClient;
+use sha2::{ Digest, Sha256 };
+use std::sync::OnceLock;
+
+use crate::{ config, encryption };
+use crate::tables;
+
+// Keys are honored for a day unless IDEMPOTENCY_KEY_TTL_SECS says otherwise
+const DEFAULT_KEY_TTL_SECS: f64 = 24.0 * 60.0 * 60.0;
+
+static KEY_TTL_SECS: OnceLock<f64> = OnceLock::new();
+
+pub enum Claim {
+    // First use of the key: go ahead and store the response with `complete`
+    New,
+    // The key was already used with the same body: answer with the stored response
+    Replay(String, String),
+    // The key was already used with a different body
+    Mismatch,
+    // The key was already used with the same body, but its response was encrypted
+    // with another key
+    Unreadable(String),
+}
+
+pub fn hash_body(body: &str) -> String {
+    format!("{:x}", Sha256::digest(body.as_bytes()))
+}
+
+// Claim the key for this request. Must run in the transaction doing the work: the
+// inserted row stays locked until it commits, so a concurrent request with the same
+// key waits for it and then sees the stored response instead of inserting twice.
+// Each tenant has keys of its own.
+pub fn claim(
+    client: &mut impl GenericClient,
+    tenant: &str,
+    key: &str,
+    request_hash: &str
+) -> Result<Claim, PostgresError> {
+    // Expired keys behave like new ones
+    client.execute(
+        tables::sql(
+            "DELETE FROM {idempotency_keys}
+            WHERE tenant_id = $3 AND key = $1 AND created_at < now() - make_interval(secs => $2)"
+        ),
+        &[&key, &key_ttl_secs(), &tenant]
+    )?;
+
+    let inserted = client.execute(
+        tables::sql(
+            "INSERT INTO {idempotency_keys} (tenant_id, key, request_hash) VALUES ($3, $1, $2)
+            ON CONFLICT (tenant_id, key) DO NOTHING"
+        ),
+        &[&key, &request_hash, &tenant]
+    )?;
+    if inserted == 1 {
+        return Ok(Claim::New);
+    }
+
+    let row = client.query_one(
+        tables::sql(
+            "SELECT request_hash, status_line, response_body, response_encrypted FROM {idempotency_keys}
+            WHERE tenant_id = $2 AND key = $1"
+        ),
+        &[&key, &tenant]
+    )?;
+    let stored_hash: String = row.get(0);
+    if stored_hash != request_hash {
+        return Ok(Claim::Mismatch);
+    }
+
+    let status_line: Option<String> = row.get(1);
+    let response_body: Option<String> = row.get(2);
+    match encryption::open_response(response_body.unwrap_or_default(), row.get(3)) {
+        Ok(body) => Ok(Claim::Replay(status_line.unwrap_or_default(), body)),
+        Err(e) => Ok(Claim::Unreadable(e)),
+    }
+}
+
+// Store the response for a claimed key, in the same transaction as the claim. It
+// is that of the user created, whose email it carries: it is encrypted with
+// ENCRYPTION_KEY.
+pub fn complete(
+    client: &mut impl GenericClient,
+    tenant: &str,
+    key: &str,
+    user_id: i32,
+    status_line: &str,
+    response_body: &str
+) -> Result<(), PostgresError> {
+    let (response_body, encrypted) = encryption::seal_response(response_body);
+    client.execute(
+        tables::sql(
+            "UPDATE {idempotency_keys} SET status_line = $2, response_body = $3, response_encrypted = $5, user_id = $6
+            WHERE tenant_id = $4 AND key = $1"
+        ),
+        &[&key, &status_line, &response_body, &tenant, &encrypted, &user_id]
+    )?;
+    Ok(())
+}
+
+// Drop the stored responses for a user, which carry their personal data. Only
+// successful creates are stored, with the user's id since migration 0018, and
+// before that in bodies starting with it.
+pub fn forget_user(client: &mut impl GenericClient, tenant: &str, user_id: i32) -> Result<u64, PostgresError> {
+    client.execute(
+        tables::sql(
+            "DELETE FROM {idempotency_keys}
+            WHERE tenant_id = $2 AND (user_id = $1 OR response_body LIKE '{\"id\":' || $1::int || ',%')"
+        ),
+        &[&user_id, &tenant]
+    )
+}
+
+pub fn key_ttl_from_env() -> Result<f64, String> {
+    match config::var("IDEMPOTENCY_KEY_TTL_SECS") {
+        Ok(ttl) => ttl.trim().parse().ok().filter(|ttl: &f64| ttl.is_finite() && *ttl > 0.0).ok_or_else(|| {
+            format!("IDEMPOTENCY_KEY_TTL_SECS must be a positive number of seconds, got {:?}", ttl)
+        }),
+        Err(_) => Ok(DEFAULT_KEY_TTL_SECS),
+    }
+}
+
+pub fn init(key_ttl_secs: f64) {
+    KEY_TTL_SECS.set(key_ttl_secs).ok();
+}
+
+fn key_ttl_secs() -> f64 {
+    KEY_TTL_SECS.get().copied().unwrap_or(DEFAULT_KEY_TTL_SECS)
+}
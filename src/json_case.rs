@@ -0,0 +1,183 @@
+use serde_json::{ Map, Value };
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+use crate::config;
+
+// That of the responses of POST /graphql
+const GRAPHQL_RESPONSE: &str = "Content-Type: application/graphql-response+json";
+
+static CASE: OnceLock<JsonCase> = OnceLock::new();
+
+// JSON_CASE, how the keys of the JSON of the API are written: snake_case, as the
+// handlers write them, or camelCase, for clients that expect it. With camelCase
+// every JSON response, its errors and the events of the streams included, is sent
+// with its keys in camelCase, and the fields the errors name too, and the keys of
+// the JSON bodies of the requests are read in camelCase or in snake_case, for the
+// clients to move over one at a time. GraphQL is camelCase either way.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JsonCase {
+    Snake,
+    Camel,
+}
+
+impl JsonCase {
+    pub fn from_env() -> Result<Self, String> {
+        match config::var("JSON_CASE").as_deref().map(str::trim) {
+            Ok("snake_case") | Err(_) => Ok(JsonCase::Snake),
+            Ok("camelCase") => Ok(JsonCase::Camel),
+            Ok(case) => Err(format!("JSON_CASE must be snake_case or camelCase, got {:?}", case)),
+        }
+    }
+}
+
+pub fn init(case: JsonCase) {
+    CASE.set(case).ok();
+}
+
+fn camel_case() -> bool {
+    CASE.get() == Some(&JsonCase::Camel)
+}
+
+// The body of a response as it is sent: its JSON in the case of the API, what
+// isn't JSON as it is, and that of GraphQL too, whose keys are those its document
+// asked for
+pub fn outbound<'a>(status_line: &str, content: &'a [u8]) -> Cow<'a, [u8]> {
+    if !camel_case() || !looks_like_json(content) || status_line.contains(GRAPHQL_RESPONSE) {
+        return Cow::Borrowed(content);
+    }
+    match serde_json::from_slice::<Value>(content) {
+        Ok(mut value) => {
+            rename(&mut value, &to_camel_case);
+            Cow::Owned(value.to_string().into_bytes())
+        }
+        Err(_) => Cow::Borrowed(content),
+    }
+}
+
+// A value sent on its own, as an event, in the case of the API
+pub fn outbound_value(value: &Value) -> Cow<'_, Value> {
+    if !camel_case() {
+        return Cow::Borrowed(value);
+    }
+    let mut value = value.clone();
+    rename(&mut value, &to_camel_case);
+    Cow::Owned(value)
+}
+
+// The request with the keys of its JSON body in snake_case, for the handlers to
+// read, whichever case they were sent in. Its signature was checked already.
+pub fn inbound(request: &str) -> Cow<'_, str> {
+    let Some((head, body)) = request.split_once("\r\n\r\n").filter(|_| camel_case()) else {
+        return Cow::Borrowed(request);
+    };
+    let Some(mut value) = Some(body).filter(|body| looks_like_json(body.as_bytes())).and_then(|body| {
+        serde_json::from_str::<Value>(body).ok()
+    }) else {
+        return Cow::Borrowed(request);
+    };
+    rename(&mut value, &to_snake_case);
+    Cow::Owned(format!("{}\r\n\r\n{}", head, value))
+}
+
+// The keys of value, and of what it holds, in snake_case
+pub fn snake_case_keys(value: &mut Value) {
+    rename(value, &to_snake_case);
+}
+
+fn looks_like_json(content: &[u8]) -> bool {
+    matches!(content.iter().find(|byte| !byte.is_ascii_whitespace()), Some(b'{' | b'['))
+}
+
+// The keys renamed, and the values of the field keys of the errors, that name one
+fn rename(value: &mut Value, case: &dyn Fn(&str) -> Option<String>) {
+    match value {
+        Value::Object(object) => {
+            let renamed: Map<String, Value> = std::mem::take(object)
+                .into_iter()
+                .map(|(key, mut value)| {
+                    match (key.as_str(), &mut value) {
+                        ("field", Value::String(field)) => *field = case(field).unwrap_or_else(|| field.clone()),
+                        (_, value) => rename(value, case),
+                    }
+                    (case(&key).unwrap_or(key), value)
+                })
+                .collect();
+            *object = renamed;
+        }
+        Value::Array(values) => values.iter_mut().for_each(|value| rename(value, case)),
+        _ => {}
+    }
+}
+
+// created_at as createdAt, None for the keys that aren't snake_case words, like
+// the names of variables or the routes in the stats
+fn to_camel_case(key: &str) -> Option<String> {
+    let snake_case = key.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'_');
+    if !snake_case || !key.contains('_') || key.starts_with('_') || key.ends_with('_') {
+        return None;
+    }
+    let mut words = key.split('_').filter(|word| !word.is_empty());
+    let mut camel_case = words.next()?.to_owned();
+    for word in words {
+        let mut chars = word.chars();
+        camel_case.extend(chars.next().map(|first| first.to_ascii_uppercase()));
+        camel_case.push_str(chars.as_str());
+    }
+    Some(camel_case)
+}
+
+// createdAt as created_at, None for those that aren't camelCase words
+fn to_snake_case(key: &str) -> Option<String> {
+    let camel_case =
+        key.starts_with(|c: char| c.is_ascii_lowercase()) && key.chars().all(|c| c.is_ascii_alphanumeric());
+    if !camel_case || !key.contains(|c: char| c.is_ascii_uppercase()) {
+        return None;
+    }
+    let mut snake_case = String::with_capacity(key.len() + 4);
+    for c in key.chars() {
+        if c.is_ascii_uppercase() {
+            snake_case.push('_');
+        }
+        snake_case.push(c.to_ascii_lowercase());
+    }
+    Some(snake_case)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn the_keys_go_from_one_case_to_the_other() {
+        assert_eq!(to_camel_case("email_display").as_deref(), Some("emailDisplay"));
+        assert_eq!(to_camel_case("retry_after_2").as_deref(), Some("retryAfter2"));
+        for key in ["email", "APP_ENV", "/users/{id}", "_private", "trailing_", "emailDisplay"] {
+            assert_eq!(to_camel_case(key), None, "{}", key);
+        }
+        assert_eq!(to_snake_case("currentPassword").as_deref(), Some("current_password"));
+        for key in ["email", "new_password", "X-Api-Key", "Primary"] {
+            assert_eq!(to_snake_case(key), None, "{}", key);
+        }
+    }
+
+    #[test]
+    fn the_fields_named_by_the_errors_are_renamed_too() {
+        let mut value = json!({
+            "errors": [{ "field": "new_password", "code": "too_short", "max_length": 72 }],
+            "verified_at": null,
+            "config": { "env": { "APP_ENV": "test" } },
+        });
+        rename(&mut value, &to_camel_case);
+        let expected = json!({
+            "errors": [{ "field": "newPassword", "code": "too_short", "maxLength": 72 }],
+            "verifiedAt": null,
+            "config": { "env": { "APP_ENV": "test" } },
+        });
+        assert_eq!(value, expected);
+        snake_case_keys(&mut value);
+        assert_eq!(value["errors"][0]["field"], "new_password");
+        assert!(value["verified_at"].is_null() && value["errors"][0]["max_length"] == 72);
+    }
+}
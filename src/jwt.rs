@@ -0,0 +1,229 @@
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{ Algorithm, DecodingKey, EncodingKey, Header, Validation };
+use std::fs;
+use std::net::IpAddr;
+
+use crate::auth::Role;
+use crate::config::{ self, number_from_env };
+use crate::repository::{ Account, UserRepository };
+use crate::secret::Secret;
+use crate::errors::repository_error_response;
+use crate::http::{ NOT_IMPLEMENTED, OK_RESPONSE };
+use crate::{ auth, clock, password, refresh, tenant };
+
+const DEFAULT_LIFETIME_SECS: u64 = 3600;
+const DEFAULT_LEEWAY_SECS: u64 = 30;
+const DEFAULT_REFRESH_LIFETIME_SECS: u64 = 30 * 24 * 60 * 60;
+
+// The tokens of POST /login, signed with HS256 and JWT_SECRET, or with RS256 and
+// the PEM keys of JWT_PRIVATE_KEY_FILE and JWT_PUBLIC_KEY_FILE. They are good for
+// JWT_LIFETIME_SECS, and their times are checked JWT_LEEWAY_SECS loosely, for the
+// clocks of the instances that don't quite agree. With REFRESH_TOKENS=true, POST
+// /login hands out a refresh token too, for POST /token/refresh to trade for the
+// next token within REFRESH_TOKEN_LIFETIME_SECS, see refresh. Postgres only.
+#[derive(Clone)]
+pub struct JwtConfig {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    pub lifetime: u64,
+    pub leeway: u64,
+    // None without refresh tokens
+    pub refresh_lifetime: Option<u64>,
+}
+
+// Never shows the keys
+impl std::fmt::Debug for JwtConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("JwtConfig")
+            .field("algorithm", &self.algorithm)
+            .field("lifetime", &self.lifetime)
+            .field("leeway", &self.leeway)
+            .field("refresh_lifetime", &self.refresh_lifetime)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwtConfig {
+    // None without a secret or a key, when there is no logging in
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let secret = config::var("JWT_SECRET").ok().map(Secret::new);
+        let private_key = config::var("JWT_PRIVATE_KEY_FILE").ok();
+        let public_key = config::var("JWT_PUBLIC_KEY_FILE").ok();
+        let (algorithm, encoding, decoding) = match (secret, private_key, public_key) {
+            (None, None, None) => return Ok(None),
+            (Some(secret), None, None) => {
+                let secret = secret.expose();
+                if secret.len() < 32 {
+                    return Err("JWT_SECRET must be at least 32 bytes".to_owned());
+                }
+                let (encoding, decoding) =
+                    (EncodingKey::from_secret(secret.as_bytes()), DecodingKey::from_secret(secret.as_bytes()));
+                (Algorithm::HS256, encoding, decoding)
+            }
+            (None, Some(private_key), Some(public_key)) => {
+                let encoding = EncodingKey::from_rsa_pem(&read_key("JWT_PRIVATE_KEY_FILE", &private_key)?)
+                    .map_err(|e| format!("JWT_PRIVATE_KEY_FILE must be an RSA private key in PEM: {}", e))?;
+                let decoding = DecodingKey::from_rsa_pem(&read_key("JWT_PUBLIC_KEY_FILE", &public_key)?)
+                    .map_err(|e| format!("JWT_PUBLIC_KEY_FILE must be an RSA public key in PEM: {}", e))?;
+                (Algorithm::RS256, encoding, decoding)
+            }
+            _ => {
+                return Err(
+                    "Set either JWT_SECRET, or both JWT_PRIVATE_KEY_FILE and JWT_PUBLIC_KEY_FILE".to_owned()
+                );
+            }
+        };
+        let lifetime = number_from_env("JWT_LIFETIME_SECS", DEFAULT_LIFETIME_SECS)?;
+        if lifetime == 0 {
+            return Err("JWT_LIFETIME_SECS must be at least 1".to_owned());
+        }
+        let leeway = number_from_env("JWT_LEEWAY_SECS", DEFAULT_LEEWAY_SECS)?;
+        let refresh_lifetime = match config::var("REFRESH_TOKENS").as_deref() {
+            Ok("false") | Err(_) => None,
+            Ok("true") => Some(number_from_env("REFRESH_TOKEN_LIFETIME_SECS", DEFAULT_REFRESH_LIFETIME_SECS)?),
+            Ok(value) => return Err(format!("REFRESH_TOKENS must be true or false, got {:?}", value)),
+        };
+        if refresh_lifetime == Some(0) {
+            return Err("REFRESH_TOKEN_LIFETIME_SECS must be at least 1".to_owned());
+        }
+        Ok(Some(JwtConfig { algorithm, encoding, decoding, lifetime, leeway, refresh_lifetime }))
+    }
+}
+
+fn read_key(name: &str, path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("Can't read {} {}: {}", name, path, e))
+}
+
+// What a token says of who has it, seconds since the epoch for the times
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    // The id of the user
+    pub sub: String,
+    pub tenant: String,
+    pub role: Role,
+    pub iat: u64,
+    pub nbf: u64,
+    pub exp: u64,
+}
+
+impl Claims {
+    // The actor of what the request does
+    pub fn name(&self) -> String {
+        format!("user {}", self.sub)
+    }
+}
+
+// Why a token was turned away, as the code and the detail of the 401
+#[derive(Debug, PartialEq)]
+pub struct Rejected {
+    pub code: &'static str,
+    pub detail: &'static str,
+}
+
+pub fn issue(config: &JwtConfig, user_id: i32, tenant: String, role: Role) -> String {
+    let now = clock::unix_secs();
+    let claims = Claims {
+        sub: user_id.to_string(),
+        tenant,
+        role,
+        iat: now,
+        nbf: now,
+        exp: now + config.lifetime,
+    };
+    jsonwebtoken::encode(&Header::new(config.algorithm), &claims, &config.encoding).expect("the key signs")
+}
+
+// The times are checked here rather than by jsonwebtoken, against the clock of
+// the app instead of the system's
+pub fn decode(config: &JwtConfig, token: &str) -> Result<Claims, Rejected> {
+    let mut validation = Validation::new(config.algorithm);
+    validation.validate_exp = false;
+    validation.set_required_spec_claims(&["exp", "iat", "sub"]);
+    let decoded = jsonwebtoken::decode::<Claims>(token, &config.decoding, &validation);
+    let claims = decoded.map(|data| data.claims).map_err(|e| match e.kind() {
+        ErrorKind::InvalidSignature => {
+            Rejected { code: "token_invalid_signature", detail: "The signature of the token isn't valid" }
+        }
+        _ => Rejected { code: "token_malformed", detail: "The token isn't a JWT of this API" },
+    })?;
+    let now = clock::unix_secs();
+    if claims.exp.saturating_add(config.leeway) < now {
+        return Err(Rejected { code: "token_expired", detail: "The token has expired" });
+    }
+    if claims.nbf > now.saturating_add(config.leeway) {
+        return Err(Rejected { code: "token_not_yet_valid", detail: "The token isn't valid yet" });
+    }
+    Ok(claims)
+}
+
+// POST /login with {"email": ..., "password": ...}, a token for the user of the
+// tenant of the request, and a refresh token with REFRESH_TOKENS=true
+pub fn handle_login_request(
+    repository: &dyn UserRepository,
+    request: &str,
+    client: Option<IpAddr>
+) -> (String, String) {
+    let Some(config) = auth::jwt() else {
+        let message = "Only available with JWT_SECRET or JWT_PRIVATE_KEY_FILE";
+        return (NOT_IMPLEMENTED.to_owned(), message.to_owned());
+    };
+    match password::log_in(repository, request, client) {
+        Ok(account) => logged_in(config, &account),
+        Err(response) => response,
+    }
+}
+
+// The response of a login of the account, the refresh token with it if there are
+// any, for POST /login and the logins of the OIDC provider
+pub fn logged_in(config: &JwtConfig, account: &Account) -> (String, String) {
+    let refresh_token = match config.refresh_lifetime.map(|lifetime| refresh::issue(lifetime, account.id)) {
+        Some(Ok(refresh_token)) => Some(refresh_token),
+        Some(Err(e)) => {
+            return repository_error_response(e, "Error issuing the refresh token");
+        }
+        None => None,
+    };
+    let token = issue(config, account.id, tenant::current(), account.role);
+    (OK_RESPONSE.to_owned(), refresh::tokens_body(config, token, refresh_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> JwtConfig {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        JwtConfig {
+            algorithm: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            lifetime: 60,
+            leeway: 5,
+            refresh_lifetime: None,
+        }
+    }
+
+    fn signed(config: &JwtConfig, iat: u64, nbf: u64, exp: u64) -> String {
+        let (sub, tenant, role) = ("7".to_owned(), "default".to_owned(), Role::Writer);
+        let claims = Claims { sub, tenant, role, iat, nbf, exp };
+        jsonwebtoken::encode(&Header::new(config.algorithm), &claims, &config.encoding).unwrap()
+    }
+
+    #[test]
+    fn tokens_are_checked_within_the_leeway() {
+        let config = config();
+        let claims = decode(&config, &issue(&config, 7, "default".to_owned(), Role::Reader)).unwrap();
+        assert_eq!((claims.sub.as_str(), claims.role), ("7", Role::Reader));
+        assert_eq!(claims.exp, claims.iat + 60);
+
+        let now = clock::unix_secs();
+        assert!(decode(&config, &signed(&config, now - 100, now - 100, now - 2)).is_ok());
+        let expired = decode(&config, &signed(&config, now - 100, now - 100, now - 10)).unwrap_err();
+        assert_eq!(expired.code, "token_expired");
+        assert!(decode(&config, &signed(&config, now, now + 2, now + 60)).is_ok());
+        let early = decode(&config, &signed(&config, now, now + 10, now + 60)).unwrap_err();
+        assert_eq!(early.code, "token_not_yet_valid");
+        assert_eq!(decode(&config, "not.a.token").unwrap_err().code, "token_malformed");
+    }
+}
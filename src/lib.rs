@@ -0,0 +1,337 @@
+use std::backtrace::{ Backtrace, BacktraceStatus };
+use std::net::{ SocketAddr, TcpListener, TcpStream };
+use std::os::fd::{ AsRawFd, RawFd };
+use std::io;
+use std::panic::{ self, AssertUnwindSafe };
+use std::sync::{ Arc, OnceLock };
+use std::thread::{ self, JoinHandle };
+
+use connections::{ Admission, Connections, Slot };
+use config::Config;
+use db::{ circuit, permits };
+use router::handle_client;
+use shutdown::ShutdownConfig;
+use repository::bulkhead::Bulkhead;
+use repository::chaos::Chaotic;
+use repository::circuit::CircuitBreaker;
+use repository::traced::Traced;
+use repository::UserRepository;
+use workers::Workers;
+
+pub use db::{ open_repositories, Repositories };
+pub use models::User;
+
+#[macro_use]
+extern crate serde_derive;
+
+mod access_log;
+mod admin;
+mod alerts;
+mod api_keys;
+mod audit;
+mod auth;
+mod backup;
+mod body_log;
+mod cache;
+mod chaos;
+pub mod cli;
+pub mod client;
+pub mod clock;
+mod coalesce;
+pub mod config;
+mod connections;
+mod credentials;
+mod db;
+mod debug_stats;
+mod disconnect;
+mod encryption;
+mod error_reports;
+mod errors;
+mod fixtures;
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzzing;
+mod graphql;
+mod handlers;
+mod health;
+mod http;
+mod https;
+mod idempotency;
+mod json_case;
+mod jwt;
+mod locale;
+mod lockout;
+mod log_sampling;
+pub mod logger;
+mod mail;
+mod models;
+mod oidc;
+mod otlp;
+mod maintenance;
+mod metrics;
+mod migrations;
+mod outbox;
+mod pagination;
+mod password;
+mod password_reset;
+mod pool;
+mod profile;
+#[cfg(test)]
+mod properties;
+mod proxy;
+mod rate_limit;
+mod read_only;
+mod redact;
+mod refresh;
+pub mod reload;
+pub mod repository;
+mod request_id;
+mod resource;
+mod route_metrics;
+mod route_timeout;
+mod router;
+mod schema;
+mod secret;
+mod security_headers;
+mod sessions;
+mod shutdown;
+mod signing;
+mod slow;
+mod spans;
+mod sse;
+mod statsd;
+mod tables;
+mod tenant;
+mod tls;
+mod trace_context;
+pub mod validation;
+mod verification;
+mod workers;
+mod ws;
+
+// The accepted connections waiting for a worker
+static WORKERS: OnceLock<Workers> = OnceLock::new();
+
+// The connections open at once
+static CONNECTIONS: OnceLock<Connections> = OnceLock::new();
+
+// Read the settings into the modules, each keeping its part, and set up what the
+// commands and the server share. Once per process: the modules hold their state
+// in statics.
+pub fn init(mut config: Config) -> Result<Arc<Config>, String> {
+    debug_stats::init();
+    clock::init(Arc::clone(&config.clock));
+    // They hold the state of their module too, so they are handed over, not copied
+    verification::init(config.verification.take());
+    password_reset::init(config.password_reset.take());
+    oidc::init(config.oidc.take());
+    let config = Arc::new(config);
+    config::init(Arc::clone(&config));
+    otlp::init(config.otlp.clone());
+    spans::init();
+    error_reports::init(config.error_reports.clone());
+    errors::init(config.error_details);
+    admin::init(config.admin_endpoints);
+
+    tables::init(config.naming.clone());
+    json_case::init(config.json_case);
+    locale::init(config.default_language);
+    tenant::init(config.tenancy);
+    encryption::init(config.encryption_key.clone());
+    http::init(config.max_body_bytes);
+    idempotency::init(config.idempotency_key_ttl_secs);
+    backup::init(config.backup_dir.clone());
+    proxy::init(config.trusted_proxies.clone());
+    redact::init(config.sensitive_fields.clone());
+    security_headers::init(config.security_headers.clone());
+    cache::init(config.cache.clone());
+    auth::init(config.auth.clone());
+    maintenance::init(config.maintenance.clone());
+    read_only::init(config.read_only.clone());
+    chaos::init(config.chaos);
+    route_timeout::init(config.route_timeout.clone());
+    body_log::init(config.body_log.clone());
+    slow::init(config.slow);
+    pagination::init(config.pagination);
+    graphql::init(config.graphql);
+    health::init(config.health.clone());
+    log_sampling::init(config.log_sampling.clone());
+    coalesce::init(config.coalesce.clone());
+    rate_limit::init(config.rate_limit.clone());
+    lockout::init(config.lockout.clone());
+    audit::init(config.audit.clone());
+    validation::init(config.validation.clone());
+    db::init(&config)?;
+    WORKERS.set(Workers::new(config.workers.clone())).ok();
+    CONNECTIONS.set(Connections::new(config.connections.clone())).ok();
+    // Last, for its thread reads the metrics of all of them
+    statsd::init(config.statsd.clone());
+    Ok(config)
+}
+
+// Log the panics instead of printing them, and report them to SENTRY_DSN or
+// ERROR_WEBHOOK_URL when set. What they say goes through redact::text, like the
+// errors logged, for an unwrap() may show a connection string. The backtrace of
+// RUST_BACKTRACE=1 is in the same record.
+pub fn report_panics() {
+    panic::set_hook(Box::new(|info| {
+        let thread = thread::current();
+        let mut message = format!("thread '{}' {}", thread.name().unwrap_or("<unnamed>"), info);
+        let backtrace = Backtrace::capture();
+        if backtrace.status() == BacktraceStatus::Captured {
+            message.push_str(&format!("\nstack backtrace:\n{}", backtrace));
+        }
+        let error_id = errors::panicked();
+        log::error!(target: "panic", error_id = error_id.as_str(); "{}", redact::text(&message));
+        error_reports::report("panic", &error_id, &message);
+    }));
+}
+
+// Serve the repositories on config.bind, on threads of their own, the modules
+// having been set up by init
+pub fn start(config: Arc<Config>, repositories: Repositories) -> io::Result<ServerHandle> {
+    let listener = shutdown::bind(config.bind, &config.shutdown)?;
+    let local_addr = listener.local_addr()?;
+    // The one of HTTPS, with TLS_PORT, stops along with it
+    let mut listening = vec![listener.as_raw_fd()];
+    if let Some(https) = config.https.clone() {
+        listening.push(https::start(https, local_addr)?);
+    }
+
+    // Handle the requests on a fixed number of workers, so that a flood of
+    // connections waits in the queue instead of getting a thread each
+    let workers = workers();
+    for _ in 0..workers.config().threads {
+        let repositories = repositories.clone();
+        thread::spawn(move || workers.work(|stream, slot| serve(stream, slot, &repositories)));
+    }
+    let accepting = thread::spawn(move || accept(listener, &config.shutdown));
+    Ok(ServerHandle { local_addr, listeners: listening, accepting })
+}
+
+// The whole server, for a program of its own or a test to run in process: init,
+// then start serving the repository. Once per process, as init.
+pub fn run_server(config: Config, repository: Arc<dyn UserRepository>) -> io::Result<ServerHandle> {
+    let config = init(config).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    start(config, Repositories { users: repository, primary_reads: None })
+}
+
+// The server start runs
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    listeners: Vec<RawFd>,
+    accepting: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    // With the port picked, when PORT is 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // What the first SIGTERM does: stop accepting connections, answer 503 to
+    // /readyz, and stop once the open connections are closed, or after
+    // SHUTDOWN_GRACE_MS
+    pub fn shutdown(&self) {
+        stop_accepting(&self.listeners);
+    }
+
+    // Shut down on SIGTERM or SIGINT, exiting at once on a second one
+    pub fn shutdown_on_signals(&self) -> io::Result<()> {
+        let listeners = self.listeners.clone();
+        shutdown::on_signal(move || stop_accepting(&listeners))
+    }
+
+    // Once it is shut down
+    pub fn join(self) -> thread::Result<()> {
+        self.accepting.join()
+    }
+}
+
+// The accept loops stop once accept fails while draining. The listeners are only
+// closed after, so that they are still those of the server then.
+fn stop_accepting(listeners: &[RawFd]) {
+    if shutdown::start_draining() {
+        connections().wake();
+        listeners.iter().for_each(|listener| shutdown::stop_accepting(*listener));
+    }
+}
+
+fn accept(listener: TcpListener, shutdown_config: &ShutdownConfig) {
+    let (workers, connections) = (workers(), connections());
+    loop {
+        connections.wait_for_room();
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(_) if shutdown::draining() => break,
+            Err(e) => {
+                log::error!("Error accepting a connection: {}", e);
+                continue;
+            }
+        };
+        // Before a worker waits on it
+        stream.set_read_timeout(Some(workers.config().read_timeout)).ok();
+        match connections.admit() {
+            Admission::Admitted(slot) => workers.submit(stream, slot),
+            Admission::Headroom(slot) => {
+                thread::spawn(move || connections.serve_headroom(stream, slot));
+            }
+            Admission::Refused => connections::refuse(stream, "Too many connections are open"),
+        }
+    }
+
+    // The new connections are refused, or go to the other servers on the port,
+    // those accepted already are handled before stopping
+    drop(listener);
+    log::warn!(
+        "Shutting down, waiting up to {:?} for the open connections ({})",
+        shutdown_config.grace,
+        connections.active()
+    );
+    let open = connections.wait_until_closed(shutdown_config.grace);
+    if open > 0 {
+        log::warn!("Closing the connections still open after {:?} ({})", shutdown_config.grace, open);
+    }
+    workers.stop();
+    otlp::flush();
+    statsd::flush();
+}
+
+// A handler that panics loses its connection, not the server nor its worker. The
+// client is answered a 500 with the id the panic was logged under, if it can
+// still be written to.
+fn serve(stream: TcpStream, slot: Slot<'static>, repositories: &Repositories) {
+    // Both use the same database, so they share the permits and the circuit. An
+    // open circuit turns requests away before they take a permit, and the spans of
+    // the operations only time them once they have one.
+    let traced = Traced::new(&*repositories.users);
+    let limited = Bulkhead::new(permits(), &traced);
+    let repository = CircuitBreaker::new(circuit(), &limited);
+    let traced_primary_reads = repositories.primary_reads.as_deref().map(Traced::new);
+    let limited_primary_reads = traced_primary_reads.as_ref().map(|reads| Bulkhead::new(permits(), reads));
+    let primary_reads = limited_primary_reads.as_ref().map(|reads| CircuitBreaker::new(circuit(), reads));
+    // The faults of the chaos mode are injected in front of all of them
+    let repository = Chaotic::new(&repository);
+    let primary_reads = primary_reads.as_ref().map(|reads| Chaotic::new(reads));
+    let primary_reads: &dyn UserRepository = primary_reads.as_ref().map_or(&repository, |reads| reads);
+
+    let peer = stream.peer_addr().map_or_else(|_| "an unknown peer".to_owned(), |peer| peer.to_string());
+    let answer = stream.try_clone().ok();
+    let handled =
+        panic::catch_unwind(AssertUnwindSafe(|| handle_client(stream, slot, &peer, &repository, primary_reads)));
+    if handled.is_err() {
+        let error_id = errors::take_panic_id();
+        log::error!("Error handling the request from {}: the handler panicked, logged as {}", peer, error_id);
+        if let Some(mut answer) = answer {
+            errors::write_panicked(&mut answer, &error_id).ok();
+        }
+    }
+}
+
+fn workers() -> &'static Workers {
+    WORKERS.get().expect("the workers are set up at startup")
+}
+
+fn connections() -> &'static Connections {
+    CONNECTIONS.get().expect("the connection limit is set up at startup")
+}
@@ -0,0 +1,171 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::config;
+use crate::http::{ get_header, with_header };
+
+// The messages of each language, by key: the titles of the problems by status,
+// the details by their code, the messages of the validation rules. English has
+// them all, the others may lack some.
+const CATALOGS: [(&str, &str); 3] = [
+    ("en", include_str!("locale/en.json")),
+    ("de", include_str!("locale/de.json")),
+    ("fr", include_str!("locale/fr.json")),
+];
+
+const ENGLISH: &str = "en";
+
+static MESSAGES: OnceLock<Vec<(&str, HashMap<String, String>)>> = OnceLock::new();
+static DEFAULT: OnceLock<&'static str> = OnceLock::new();
+
+thread_local! {
+    // The language of the request handled on this thread, set by enter, and
+    // whether it asked for one
+    static CURRENT: Cell<Option<(&'static str, bool)>> = const { Cell::new(None) };
+}
+
+// DEFAULT_LANGUAGE: the language of the requests without an Accept-Language, or
+// with none that has a catalog, English unless set
+pub fn default_from_env() -> Result<&'static str, String> {
+    match config::var("DEFAULT_LANGUAGE") {
+        Ok(language) => supported(language.trim()).ok_or_else(|| {
+            let languages: Vec<&str> = CATALOGS.iter().map(|(language, _)| *language).collect();
+            format!("DEFAULT_LANGUAGE must be one of {}, got {:?}", languages.join(", "), language)
+        }),
+        Err(_) => Ok(ENGLISH),
+    }
+}
+
+pub fn init(default: &'static str) {
+    DEFAULT.set(default).ok();
+}
+
+fn default() -> &'static str {
+    DEFAULT.get().copied().unwrap_or(ENGLISH)
+}
+
+fn supported(tag: &str) -> Option<&'static str> {
+    // de-CH is read as de
+    let primary = tag.split(['-', '_']).next().unwrap_or(tag);
+    CATALOGS.iter().map(|(language, _)| *language).find(|language| language.eq_ignore_ascii_case(primary))
+}
+
+// The language of a request: the one of its Accept-Language with the highest
+// q-value that has a catalog, ties going to the first listed, * to the default,
+// q=0 to none of them. The default without a header or a language of ours.
+pub fn negotiate(accept_language: &str) -> &'static str {
+    let mut ranges: Vec<(&str, f64)> = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let tag = parts.next().filter(|tag| !tag.is_empty())?;
+            let q = parts.find_map(|param| param.strip_prefix("q=")).map_or(Some(1.0), |q| q.parse().ok())?;
+            Some((tag, q)).filter(|(_, q): &(&str, f64)| *q > 0.0 && *q <= 1.0)
+        })
+        .collect();
+    ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    ranges.iter().find_map(|(tag, _)| if *tag == "*" { Some(default()) } else { supported(tag) }).unwrap_or(default())
+}
+
+// The language of the request for the messages written on this thread, until the
+// returned guard is dropped
+pub fn enter(request: &str) -> Entered {
+    let language = get_header(request, "Accept-Language").map(|header| (negotiate(header), true));
+    CURRENT.set(Some(language.unwrap_or((default(), false))));
+    Entered
+}
+
+pub struct Entered;
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.set(None);
+    }
+}
+
+// The language of the request on this thread, the default outside of one
+pub fn current() -> &'static str {
+    CURRENT.get().map_or_else(default, |(language, _)| language)
+}
+
+// The head of a response with the language its messages are in, for the requests
+// that asked for one
+pub fn added(head: &str) -> String {
+    match CURRENT.get() {
+        Some((language, true)) if !head.is_empty() => with_header(head, &format!("Content-Language: {}", language)),
+        _ => head.to_owned(),
+    }
+}
+
+fn messages() -> &'static [(&'static str, HashMap<String, String>)] {
+    MESSAGES.get_or_init(|| {
+        let parsed = |(language, catalog): &(&'static str, &str)| {
+            (*language, serde_json::from_str(catalog).expect("the catalogs are valid JSON"))
+        };
+        CATALOGS.iter().map(parsed).collect()
+    })
+}
+
+fn lookup(language: &str, key: &str) -> Option<&'static str> {
+    let messages = messages().iter().find(|(each, _)| *each == language)?;
+    messages.1.get(key).map(String::as_str)
+}
+
+// The message of key in the language of the request, or else in English, with the
+// {name} of each argument replaced by its value. The others are left as they are.
+pub fn text(key: &str, args: &[(&str, &str)]) -> String {
+    let message = lookup(current(), key).or_else(|| lookup(ENGLISH, key)).unwrap_or(key);
+    args.iter().fold(message.to_owned(), |message, (name, value)| message.replace(&format!("{{{}}}", name), value))
+}
+
+// The title of a problem of status in the language of the request, or else the
+// English one given
+pub fn title(status: u16, english: &str) -> String {
+    lookup(current(), &format!("title.{}", status)).unwrap_or(english).to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_language_with_the_highest_q_value_wins() {
+        assert_eq!(negotiate("de"), "de");
+        assert_eq!(negotiate("fr-CA, en;q=0.8"), "fr");
+        assert_eq!(negotiate("en;q=0.3, it, de-AT;q=0.9, fr;q=0.5"), "de");
+        assert_eq!(negotiate("fr;q=0.5, de;q=0.5"), "fr");
+        assert_eq!(negotiate("it, es;q=0.9"), "en");
+        assert_eq!(negotiate("it, *;q=0.1"), "en");
+        assert_eq!(negotiate("de;q=0, fr;q=bad, *"), "en");
+        assert_eq!(negotiate(""), "en");
+    }
+
+    #[test]
+    fn every_message_has_an_english_one_with_the_same_arguments() {
+        let arguments = |message: &str| {
+            let names = message.split('{').skip(1).filter_map(|rest| rest.split_once('}'));
+            let mut arguments: Vec<String> = names.map(|(name, _)| name.to_owned()).collect();
+            arguments.sort();
+            arguments
+        };
+        let english = &messages()[0].1;
+        for (language, messages) in messages() {
+            for (key, message) in messages {
+                let english = english.get(key).unwrap_or_else(|| panic!("{} has {} but not en", language, key));
+                assert_eq!(arguments(message), arguments(english), "{} of {}", key, language);
+            }
+        }
+    }
+
+    #[test]
+    fn a_message_missing_in_a_language_is_english() {
+        CURRENT.set(Some(("de", true)));
+        assert_eq!(text("user_not_found", &[("id", "5")]), "Benutzer mit der ID 5 wurde nicht gefunden");
+        assert_eq!(text("validation.password_elsewhere", &[]), "password is changed with PUT /users/{id}/password");
+        assert_eq!(title(404, "Not Found"), "Nicht gefunden");
+        assert_eq!(title(418, "I'm a teapot"), "I'm a teapot");
+        CURRENT.set(None);
+        assert_eq!(text("user_not_found", &[("id", "5")]), "User with ID 5 not found");
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{ Mutex, OnceLock };
+use std::time::{ Duration, Instant };
+
+use crate::config::number_from_env;
+use crate::http::{ decode_query_value, get_query_param, BAD_REQUEST, OK_RESPONSE };
+use crate::{ clock, locale, tenant, validation };
+
+const DEFAULT_MAX_FAILURES: u64 = 5;
+const DEFAULT_MAX_FAILURES_PER_IP: u64 = 20;
+const DEFAULT_WINDOW_SECS: u64 = 15 * 60;
+const DEFAULT_LOCKOUT_SECS: u64 = 15 * 60;
+
+// How often the failures that no longer count are forgotten
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+static LOCKOUTS: OnceLock<Option<Lockouts>> = OnceLock::new();
+
+// The logins of POST /login and POST /session that failed: an email with
+// LOGIN_MAX_FAILURES of them within LOGIN_FAILURE_WINDOW_SECS is locked for
+// LOGIN_LOCKOUT_SECS, whether or not it is the email of a user, and so is a
+// client address with LOGIN_MAX_FAILURES_PER_IP, for every email it tries. Logging
+// in starts the count of the email over, not that of the address, which a client
+// with an account of its own could reset between guesses otherwise. Each instance
+// counts the failures it saw, and forgets them when it stops. LOGIN_MAX_FAILURES=0
+// turns the lockouts off, LOGIN_MAX_FAILURES_PER_IP=0 those of the addresses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LockoutConfig {
+    pub max_failures: u64,
+    pub max_failures_per_ip: u64,
+    pub window: Duration,
+    pub lockout: Duration,
+}
+
+impl LockoutConfig {
+    // None without lockouts
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let max_failures = number_from_env("LOGIN_MAX_FAILURES", DEFAULT_MAX_FAILURES)?;
+        if max_failures == 0 {
+            return Ok(None);
+        }
+        let max_failures_per_ip = number_from_env("LOGIN_MAX_FAILURES_PER_IP", DEFAULT_MAX_FAILURES_PER_IP)?;
+        let window = number_from_env("LOGIN_FAILURE_WINDOW_SECS", DEFAULT_WINDOW_SECS)?;
+        let lockout = number_from_env("LOGIN_LOCKOUT_SECS", DEFAULT_LOCKOUT_SECS)?;
+        if window == 0 || lockout == 0 {
+            return Err("LOGIN_FAILURE_WINDOW_SECS and LOGIN_LOCKOUT_SECS must be at least 1".to_owned());
+        }
+        Ok(Some(LockoutConfig {
+            max_failures,
+            max_failures_per_ip,
+            window: Duration::from_secs(window),
+            lockout: Duration::from_secs(lockout),
+        }))
+    }
+}
+
+pub fn init(config: Option<LockoutConfig>) {
+    LOCKOUTS.set(config.map(Lockouts::new)).ok();
+}
+
+// The failures of an email or an address since the first that still counts
+struct Failures {
+    count: u64,
+    first: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn locked(&self, now: Instant) -> Option<Duration> {
+        self.locked_until.filter(|until| *until > now).map(|until| until - now)
+    }
+
+    // Whether there is nothing left to remember
+    fn expired(&self, window: Duration, now: Instant) -> bool {
+        self.locked(now).is_none() && now.duration_since(self.first) >= window
+    }
+}
+
+struct Counts {
+    // By tenant and normalized email
+    by_email: HashMap<(String, String), Failures>,
+    by_ip: HashMap<IpAddr, Failures>,
+    cleaned_up: Instant,
+}
+
+struct Lockouts {
+    config: LockoutConfig,
+    counts: Mutex<Counts>,
+}
+
+// One more failure, which locks at max. Whether it did.
+fn count<K: Eq + Hash>(
+    failures: &mut HashMap<K, Failures>,
+    key: K,
+    max: u64,
+    config: &LockoutConfig,
+    now: Instant
+) -> bool {
+    let failures = failures.entry(key).or_insert(Failures { count: 0, first: now, locked_until: None });
+    if failures.expired(config.window, now) {
+        *failures = Failures { count: 0, first: now, locked_until: None };
+    }
+    failures.count += 1;
+    if failures.count < max {
+        return false;
+    }
+    *failures = Failures { count: 0, first: now, locked_until: Some(now + config.lockout) };
+    true
+}
+
+impl Lockouts {
+    fn new(config: LockoutConfig) -> Self {
+        let counts = Counts { by_email: HashMap::new(), by_ip: HashMap::new(), cleaned_up: clock::instant() };
+        Lockouts { config, counts: Mutex::new(counts) }
+    }
+
+    fn locked(&self, key: &(String, String), client: Option<IpAddr>, now: Instant) -> Option<Duration> {
+        let counts = self.counts.lock().unwrap();
+        let email = counts.by_email.get(key).and_then(|failures| failures.locked(now));
+        let ip = client.and_then(|client| counts.by_ip.get(&client)).and_then(|failures| failures.locked(now));
+        email.max(ip)
+    }
+
+    fn failed(&self, key: (String, String), client: Option<IpAddr>, now: Instant) -> bool {
+        let config = &self.config;
+        let mut counts = self.counts.lock().unwrap();
+        if now.duration_since(counts.cleaned_up) >= CLEANUP_INTERVAL {
+            counts.by_email.retain(|_, failures| !failures.expired(config.window, now));
+            counts.by_ip.retain(|_, failures| !failures.expired(config.window, now));
+            counts.cleaned_up = now;
+        }
+        let email = count(&mut counts.by_email, key, config.max_failures, config, now);
+        let ip = client.filter(|_| config.max_failures_per_ip > 0);
+        let ip = ip.is_some_and(|client| count(&mut counts.by_ip, client, config.max_failures_per_ip, config, now));
+        email || ip
+    }
+
+    fn succeeded(&self, key: &(String, String)) {
+        self.counts.lock().unwrap().by_email.remove(key);
+    }
+
+    // Whether there was anything to clear
+    fn clear(&self, key: Option<&(String, String)>, client: Option<IpAddr>) -> bool {
+        let mut counts = self.counts.lock().unwrap();
+        let email = key.and_then(|key| counts.by_email.remove(key)).is_some();
+        let ip = client.and_then(|client| counts.by_ip.remove(&client)).is_some();
+        email || ip
+    }
+}
+
+fn lockouts() -> Option<&'static Lockouts> {
+    LOCKOUTS.get_or_init(|| None).as_ref()
+}
+
+fn key(email: &str) -> (String, String) {
+    (tenant::current(), email.to_owned())
+}
+
+// The seconds until the normalized email may log in again, from the client, in
+// the tenant entered. None unless it is locked.
+pub fn retry_after(email: &str, client: Option<IpAddr>) -> Option<u64> {
+    let locked = lockouts()?.locked(&key(email), client, clock::instant())?;
+    Some(locked.as_secs_f64().ceil().max(1.0) as u64)
+}
+
+// Whether the failure locked the email or the client
+pub fn failed(email: &str, client: Option<IpAddr>) -> bool {
+    lockouts().is_some_and(|lockouts| lockouts.failed(key(email), client, clock::instant()))
+}
+
+pub fn succeeded(email: &str) {
+    if let Some(lockouts) = lockouts() {
+        lockouts.succeeded(&key(email));
+    }
+}
+
+// The 429 of a locked login
+pub fn locked_response(retry_after: u64) -> (String, String) {
+    let status_line = format!(
+        "HTTP/1.1 429 TOO MANY REQUESTS\r\nContent-Type: application/problem+json\r\nRetry-After: {}\r\n\r\n",
+        retry_after
+    );
+    let body = serde_json::json!({
+        "type": "about:blank",
+        "title": locale::title(429, "Too Many Requests"),
+        "status": 429,
+        "detail": format!("Too many failed logins, retry in {} seconds", retry_after),
+        "code": "login_locked",
+    });
+    (status_line, body.to_string())
+}
+
+// DELETE /admin/lockouts?email=...&ip=..., which lets the email of the tenant of
+// the request, the address or both log in again at once
+pub fn handle_clear_lockout_request(request: &str) -> (String, String) {
+    let Some(lockouts) = lockouts() else {
+        return (BAD_REQUEST.to_owned(), "There are no lockouts with LOGIN_MAX_FAILURES=0".to_owned());
+    };
+    let email =
+        get_query_param(request, "email").map(|email| validation::normalize_email(decode_query_value(email).trim()));
+    let client = match get_query_param(request, "ip").map(|ip| decode_query_value(ip).parse::<IpAddr>()) {
+        Some(Ok(client)) => Some(client),
+        Some(Err(e)) => return (BAD_REQUEST.to_owned(), format!("Invalid ip: {}", e)),
+        None => None,
+    };
+    if email.is_none() && client.is_none() {
+        return (BAD_REQUEST.to_owned(), "Give the email, the ip or both of the lockout to clear".to_owned());
+    }
+
+    let cleared = lockouts.clear(email.as_deref().map(key).as_ref(), client);
+    (OK_RESPONSE.to_owned(), serde_json::json!({ "cleared": cleared }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockouts() -> Lockouts {
+        Lockouts::new(LockoutConfig {
+            max_failures: 3,
+            max_failures_per_ip: 5,
+            window: Duration::from_secs(60),
+            lockout: Duration::from_secs(300),
+        })
+    }
+
+    fn key(email: &str) -> (String, String) {
+        ("default".to_owned(), email.to_owned())
+    }
+
+    #[test]
+    fn emails_are_locked_after_their_failures_within_the_window() {
+        let (lockouts, now) = (lockouts(), Instant::now());
+        let ada = key("ada@example.com");
+        lockouts.failed(ada.clone(), None, now);
+        // Out of the window, the first doesn't count anymore
+        lockouts.failed(ada.clone(), None, now + Duration::from_secs(61));
+        assert!(!lockouts.failed(ada.clone(), None, now + Duration::from_secs(62)));
+        assert_eq!(lockouts.locked(&ada, None, now + Duration::from_secs(62)), None);
+        // The failure that locks says so
+        assert!(lockouts.failed(ada.clone(), None, now + Duration::from_secs(63)));
+        let locked = lockouts.locked(&ada, None, now + Duration::from_secs(63));
+        assert_eq!(locked, Some(Duration::from_secs(300)));
+        assert_eq!(lockouts.locked(&ada, None, now + Duration::from_secs(363)), None);
+
+        // Logging in starts over
+        let alan = key("alan@example.com");
+        lockouts.failed(alan.clone(), None, now);
+        lockouts.failed(alan.clone(), None, now);
+        lockouts.succeeded(&alan);
+        lockouts.failed(alan.clone(), None, now);
+        assert_eq!(lockouts.locked(&alan, None, now), None);
+    }
+
+    #[test]
+    fn addresses_are_locked_for_every_email() {
+        let (lockouts, now) = (lockouts(), Instant::now());
+        let client = Some("203.0.113.7".parse().unwrap());
+        for i in 0..5 {
+            lockouts.failed(key(&format!("user{}@example.com", i)), client, now);
+        }
+        assert!(lockouts.locked(&key("grace@example.com"), client, now).is_some());
+        assert_eq!(lockouts.locked(&key("grace@example.com"), Some("203.0.113.8".parse().unwrap()), now), None);
+
+        assert!(lockouts.clear(None, client));
+        assert_eq!(lockouts.locked(&key("grace@example.com"), client, now), None);
+        assert!(!lockouts.clear(Some(&key("grace@example.com")), None));
+    }
+}
@@ -0,0 +1,106 @@
+use std::fs;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ OnceLock, RwLock };
+
+use crate::config;
+use crate::credentials;
+
+static CONFIG: OnceLock<LogSamplingConfig> = OnceLock::new();
+static STATE: RwLock<State> = RwLock::new(State { rate: 1.0, reload_requests: 0 });
+// Since startup
+static SAMPLED_OUT: AtomicU64 = AtomicU64::new(0);
+
+// ACCESS_LOG_SAMPLE_RATE of the 2xx and 3xx lines of the access log are kept, 0.01
+// for one in a hundred, and all of them by default. The others, 4xx and 5xx, the
+// auth failures among them, and those of the slow requests, always are, and every
+// request is counted in the metrics either way. Whether one is kept only depends
+// on its request_id, so the instances behind the same proxy keep the same ones.
+// ACCESS_LOG_SAMPLE_FILE, holding the rate, is read again after a SIGHUP.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogSamplingConfig {
+    pub rate: f64,
+    pub file: Option<String>,
+}
+
+impl LogSamplingConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let file = config::var("ACCESS_LOG_SAMPLE_FILE").ok();
+        let rate = match (&file, config::var("ACCESS_LOG_SAMPLE_RATE")) {
+            (Some(path), _) => read_file(path)?,
+            (None, Ok(rate)) => parse_rate(&rate).map_err(|e| format!("ACCESS_LOG_SAMPLE_RATE {}", e))?,
+            (None, Err(_)) => 1.0,
+        };
+        Ok(LogSamplingConfig { rate, file })
+    }
+}
+
+fn parse_rate(rate: &str) -> Result<f64, String> {
+    match rate.trim().parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("must be a number between 0 and 1, got {:?}", rate.trim())),
+    }
+}
+
+fn read_file(path: &str) -> Result<f64, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Can't read ACCESS_LOG_SAMPLE_FILE {}: {}", path, e))?;
+    parse_rate(&content).map_err(|e| format!("ACCESS_LOG_SAMPLE_FILE {} {}", path, e))
+}
+
+struct State {
+    rate: f64,
+    // credentials::reload_requests() when the file was last read
+    reload_requests: u64,
+}
+
+pub fn init(config: LogSamplingConfig) {
+    *STATE.write().unwrap() = State { rate: config.rate, reload_requests: credentials::reload_requests() };
+    if config.file.is_some() {
+        credentials::reload_on_sighup();
+    }
+    CONFIG.set(config).ok();
+}
+
+// The rate now, the file read again first if there was a SIGHUP since
+fn rate() -> f64 {
+    let reload_requests = credentials::reload_requests();
+    let state = STATE.read().unwrap();
+    match CONFIG.get().and_then(|config| config.file.as_deref()) {
+        Some(path) if state.reload_requests != reload_requests => {
+            drop(state);
+            let mut state = STATE.write().unwrap();
+            state.reload_requests = reload_requests;
+            match read_file(path) {
+                Ok(rate) => state.rate = rate,
+                Err(e) => log::warn!("Keeping the access log sample rate at {}: {}", state.rate, e),
+            }
+            state.rate
+        }
+        _ => state.rate,
+    }
+}
+
+// Whether the line of a successful request is kept, always for one without an id
+pub fn keep(request_id: Option<&str>) -> bool {
+    let kept = request_id.is_none_or(|request_id| kept(request_id, rate()));
+    if !kept {
+        SAMPLED_OUT.fetch_add(1, Ordering::Relaxed);
+    }
+    kept
+}
+
+// FNV-1a, the same on every instance and version unlike the std hashers, mixed
+// for ids differing by their last characters not to hash close to each other
+fn kept(request_id: &str, rate: f64) -> bool {
+    let hash = request_id.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    let hash = (hash ^ (hash >> 33)).wrapping_mul(0xff51_afd7_ed55_8ccd);
+    let hash = (hash ^ (hash >> 33)).wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    rate >= 1.0 || ((hash ^ (hash >> 33)) as f64 / u64::MAX as f64) < rate
+}
+
+// The lines left out so far
+pub fn sampled_out() -> u64 {
+    SAMPLED_OUT.load(Ordering::Relaxed)
+}
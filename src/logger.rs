@@ -0,0 +1,252 @@
+use chrono::{ SecondsFormat, Utc };
+use log::kv::{ self, Key, Value, VisitSource, VisitValue };
+use log::{ Level, LevelFilter, Log, Metadata, Record };
+use serde_json::Map;
+use std::sync::RwLock;
+
+use crate::config;
+use crate::profile::Profile;
+use crate::request_id;
+
+static LOGGER: RwLock<Option<Logger>> = RwLock::new(None);
+
+// What the server logs, the access log, its warnings and its errors, with RUST_LOG
+// as env_logger reads it: a level, off, error, warn, info or debug, or one for the
+// targets, as info,access=off, info by default. The errors and the warnings go to
+// stderr, the rest to stdout. With LOG_FORMAT=plain, the default but in prod, each
+// is a line of the time, the level, the target and the message, then its fields as
+// name=value. With LOG_FORMAT=json, that of prod, it is a JSON object of one line,
+// of timestamp, level, target, message and the fields, the panics and their
+// backtraces included. The lines logged while a request is answered have their
+// request_id too.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Logger {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+    format: Format,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Plain,
+    Json,
+}
+
+impl Logger {
+    pub fn from_env() -> Result<Self, String> {
+        let format = match config::var("LOG_FORMAT").as_deref() {
+            Ok("plain") => Format::Plain,
+            Ok("json") => Format::Json,
+            Err(_) if Profile::from_env() == Profile::Prod => Format::Json,
+            Err(_) => Format::Plain,
+            Ok(value) => return Err(format!("LOG_FORMAT must be plain or json, got {:?}", value)),
+        };
+        let directives = config::var("RUST_LOG").unwrap_or_default();
+        let mut logger = Logger { default: LevelFilter::Info, targets: Vec::new(), format };
+        for directive in directives.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            let level = |level: &str| {
+                level.parse::<LevelFilter>().map_err(|_| {
+                    format!("RUST_LOG must set levels of off, error, warn, info, debug or trace, got {:?}", directive)
+                })
+            };
+            match directive.split_once('=') {
+                Some((target, filter)) if !target.is_empty() => {
+                    logger.targets.push((target.to_owned(), level(filter)?));
+                }
+                Some(_) => return Err(format!("RUST_LOG has a level without its target: {:?}", directive)),
+                None => logger.default = level(directive)?,
+            }
+        }
+        Ok(logger)
+    }
+
+    // That of --log-level, for the targets RUST_LOG doesn't set a level for
+    pub fn with_default_level(self, default: LevelFilter) -> Self {
+        Logger { default, ..self }
+    }
+
+    // The level of the target, from the longest of the targets it is under
+    fn level(&self, target: &str) -> LevelFilter {
+        let under = |prefix: &str| target == prefix || target.starts_with(&format!("{}::", prefix));
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| under(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = line(self.format, record);
+        match record.level() {
+            Level::Error | Level::Warn => eprintln!("{}", line),
+            _ => println!("{}", line),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// The one set in log, logging with LOGGER as it is now
+struct Current;
+
+impl Log for Current {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        LOGGER.read().unwrap().as_ref().is_some_and(|logger| logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(logger) = LOGGER.read().unwrap().as_ref() {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// The fields of a record, as JSON, in their order
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if is_null(&value) {
+            serde_json::Value::Null
+        } else if let Some(number) = value.to_u64() {
+            number.into()
+        } else if let Some(number) = value.to_i64() {
+            number.into()
+        } else if let Some(number) = value.to_f64() {
+            number.into()
+        } else if let Some(boolean) = value.to_bool() {
+            boolean.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.push((key.to_string(), value));
+        Ok(())
+    }
+}
+
+// Whether the value is that of a None
+fn is_null(value: &Value) -> bool {
+    struct Null(bool);
+
+    impl<'v> VisitValue<'v> for Null {
+        fn visit_any(&mut self, _: Value) -> Result<(), kv::Error> {
+            Ok(())
+        }
+
+        fn visit_null(&mut self) -> Result<(), kv::Error> {
+            self.0 = true;
+            Ok(())
+        }
+    }
+
+    let mut null = Null(false);
+    value.visit(&mut null).ok();
+    null.0
+}
+
+fn line(format: Format, record: &Record) -> String {
+    let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let mut fields = Fields(Vec::new());
+    record.key_values().visit(&mut fields).ok();
+    // Logged while a request is answered
+    if let Some(request_id) = request_id::current() {
+        fields.0.push(("request_id".to_owned(), request_id.into()));
+    }
+    match format {
+        Format::Plain => {
+            let mut line = format!("{} {} {}: {}", time, record.level(), record.target(), record.args());
+            for (name, value) in fields.0 {
+                match value {
+                    serde_json::Value::String(text) => line.push_str(&format!(" {}={:?}", name, text)),
+                    value => line.push_str(&format!(" {}={}", name, value)),
+                }
+            }
+            line
+        }
+        Format::Json => {
+            let mut object = Map::new();
+            object.insert("timestamp".to_owned(), time.into());
+            object.insert("level".to_owned(), record.level().as_str().into());
+            object.insert("target".to_owned(), record.target().into());
+            object.insert("message".to_owned(), record.args().to_string().into());
+            // Never in place of those above
+            for (name, value) in fields.0 {
+                object.entry(name).or_insert(value);
+            }
+            serde_json::Value::Object(object).to_string()
+        }
+    }
+}
+
+// At startup, and again when a SIGHUP changed RUST_LOG or LOG_FORMAT
+pub fn init(logger: Logger) {
+    let max = logger.targets.iter().map(|(_, level)| *level).fold(logger.default, Ord::max);
+    let first = LOGGER.write().unwrap().replace(logger).is_none();
+    if !first || log::set_logger(&Current).is_ok() {
+        log::set_max_level(max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_have_their_own_levels() {
+        let logger = Logger {
+            default: LevelFilter::Warn,
+            targets: vec![("access".to_owned(), LevelFilter::Info), ("access::streams".to_owned(), LevelFilter::Off)],
+            format: Format::Plain,
+        };
+        assert_eq!(logger.level("access"), LevelFilter::Info);
+        assert_eq!(logger.level("access::users"), LevelFilter::Info);
+        assert_eq!(logger.level("access::streams"), LevelFilter::Off);
+        assert_eq!(logger.level("accessible"), LevelFilter::Warn);
+        assert_eq!(logger.level("rust_postgresql_tutorial"), LevelFilter::Warn);
+    }
+
+    fn logged(format: Format, level: Level, message: &str) -> String {
+        let fields = [
+            ("route", Value::from("GET /users/{id}")),
+            ("status", Value::from(503)),
+            ("principal", Value::null()),
+        ];
+        let mut record = Record::builder();
+        record.level(level).target("access").key_values(&fields);
+        line(format, &record.args(format_args!("{}", message)).build())
+    }
+
+    #[test]
+    fn json_lines_are_one_object_each() {
+        let panicked = "thread 'main' panicked at \"src/main.rs\"\nstack backtrace:\n 0: main";
+        let line = logged(Format::Json, Level::Error, panicked);
+        assert!(!line.contains('\n'), "{}", line);
+        let object: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(object["level"], "ERROR");
+        assert_eq!(object["target"], "access");
+        assert_eq!(object["message"], panicked);
+        assert_eq!(object["route"], "GET /users/{id}");
+        assert_eq!(object["status"], 503);
+        assert!(object["principal"].is_null(), "{}", line);
+        let timestamp = object["timestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok(), "{}", timestamp);
+    }
+
+    #[test]
+    fn plain_lines_end_with_their_fields() {
+        let line = logged(Format::Plain, Level::Info, "GET /users/1 503");
+        let fields = r#"route="GET /users/{id}" status=503 principal=null"#;
+        assert!(line.ends_with(&format!(" INFO access: GET /users/1 503 {}", fields)), "{}", line);
+    }
+}
@@ -0,0 +1,156 @@
+use std::io::{ self, BufRead, BufReader, Write };
+use std::net::{ TcpStream, ToSocketAddrs };
+use std::time::Duration;
+
+use crate::config::{ self, number_from_env };
+
+const DEFAULT_SMTP_PORT: u64 = 25;
+
+// For connecting to the relay, and for each of its replies
+const SMTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How the users are sent the links of the verifications and the password resets
+pub trait Sender: Send + Sync {
+    fn send(&self, to: &str, subject: &str, text: &str) -> Result<(), String>;
+}
+
+// Prints the mail, for development and for the relays reading the output
+pub struct LogSender;
+
+impl Sender for LogSender {
+    fn send(&self, to: &str, subject: &str, text: &str) -> Result<(), String> {
+        log::info!("Mail to {} ({}): {}", to, subject, text);
+        Ok(())
+    }
+}
+
+// Mails through the SMTP relay at host:port, from SMTP_FROM. Without TLS or a
+// login, for a relay of the private network that takes it from there.
+pub struct SmtpSender {
+    pub host: String,
+    pub port: u16,
+    pub from: String,
+}
+
+impl Sender for SmtpSender {
+    fn send(&self, to: &str, subject: &str, text: &str) -> Result<(), String> {
+        self.converse(to, subject, text)
+            .map_err(|e| format!("the SMTP relay {}:{} failed: {}", self.host, self.port, e))
+    }
+}
+
+impl SmtpSender {
+    fn converse(&self, to: &str, subject: &str, text: &str) -> io::Result<()> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::other("no address"))?;
+        let mut stream = TcpStream::connect_timeout(&address, SMTP_TIMEOUT)?;
+        stream.set_read_timeout(Some(SMTP_TIMEOUT))?;
+        let mut replies = BufReader::new(stream.try_clone()?);
+
+        reply(&mut replies, "220")?;
+        let message = format!("From: <{}>\r\nTo: <{}>\r\nSubject: {}\r\n\r\n{}\r\n.", self.from, to, subject, text);
+        let commands = [
+            ("EHLO localhost".to_owned(), "250"),
+            (format!("MAIL FROM:<{}>", self.from), "250"),
+            (format!("RCPT TO:<{}>", to), "25"),
+            ("DATA".to_owned(), "354"),
+            (message, "250"),
+            ("QUIT".to_owned(), "221"),
+        ];
+        for (command, expected) in commands {
+            stream.write_all(format!("{}\r\n", command).as_bytes())?;
+            reply(&mut replies, expected)?;
+        }
+        Ok(())
+    }
+}
+
+// The last line of the reply, unless its code doesn't start with expected
+fn reply(replies: &mut impl BufRead, expected: &str) -> io::Result<String> {
+    loop {
+        let mut line = String::new();
+        if replies.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the relay hung up"));
+        }
+        // 250-... until the last line, 250 ...
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        if !line.starts_with(expected) {
+            return Err(io::Error::other(format!("the relay answered {:?}", line.trim_end())));
+        }
+        return Ok(line);
+    }
+}
+
+// The sender of MAIL_SENDER: log by default, or smtp through SMTP_HOST:SMTP_PORT
+// from SMTP_FROM, see SmtpSender
+pub fn sender_from_env() -> Result<Box<dyn Sender>, String> {
+    match config::var("MAIL_SENDER").as_deref() {
+        Ok("log") | Err(_) => Ok(Box::new(LogSender)),
+        Ok("smtp") => {
+            let (Ok(host), Ok(from)) = (config::var("SMTP_HOST"), config::var("SMTP_FROM")) else {
+                return Err("MAIL_SENDER=smtp needs SMTP_HOST and SMTP_FROM".to_owned());
+            };
+            let port = number_from_env("SMTP_PORT", DEFAULT_SMTP_PORT)?;
+            let port = u16::try_from(port).map_err(|_| format!("SMTP_PORT must be a port, got {}", port))?;
+            Ok(Box::new(SmtpSender { host, port, from }))
+        }
+        Ok(value) => Err(format!("MAIL_SENDER must be log or smtp, got {:?}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn mails_go_through_the_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let relay = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut lines = BufReader::new(stream.try_clone().unwrap());
+            let mut received = String::new();
+            stream.write_all(b"220 relay ready\r\n").unwrap();
+            for reply in ["250-relay\r\n250 8BITMIME\r\n", "250 ok\r\n", "250 ok\r\n", "354 go on\r\n"] {
+                lines.read_line(&mut received).unwrap();
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+            while !received.ends_with("\r\n.\r\n") {
+                lines.read_line(&mut received).unwrap();
+            }
+            stream.write_all(b"250 queued\r\n").unwrap();
+            lines.read_line(&mut received).unwrap();
+            stream.write_all(b"221 bye\r\n").unwrap();
+            lines.read_to_string(&mut received).unwrap();
+            received
+        });
+
+        let sender = SmtpSender { host: "127.0.0.1".to_owned(), port, from: "noreply@example.com".to_owned() };
+        sender.send("ada@example.com", "Verify your email", "Open https://example.com/verify?token=abc").unwrap();
+        let received = relay.join().unwrap();
+        assert!(received.starts_with("EHLO localhost\r\nMAIL FROM:<noreply@example.com>\r\n"), "{}", received);
+        assert!(received.contains("RCPT TO:<ada@example.com>\r\nDATA\r\n"), "{}", received);
+        assert!(received.contains("Subject: Verify your email\r\n\r\nOpen https://example.com/"), "{}", received);
+        assert!(received.ends_with(".\r\nQUIT\r\n"), "{}", received);
+    }
+
+    #[test]
+    fn relays_that_refuse_are_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"554 no thanks\r\n").unwrap();
+        });
+        let sender = SmtpSender { host: "127.0.0.1".to_owned(), port, from: "noreply@example.com".to_owned() };
+        let error = sender.send("ada@example.com", "Verify your email", "Open the link").unwrap_err();
+        assert!(error.contains("554 no thanks"), "{}", error);
+    }
+}
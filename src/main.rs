@@ -1,191 +1,99 @@
-use postgres::{ Client, NoTls };
-use postgres::Error as PostgresError;
-use std::net::{ TcpListener, TcpStream };
-use std::io::{ Read, Write };
 use std::env;
+use std::io;
+use std::process;
+use std::sync::Arc;
 
-#[macro_use]
-extern crate serde_derive;
-
-// Define the model in a struct
-#[derive(Serialize, Deserialize, Debug)]
-struct User {
-    pub id: Option<i32>,
-    pub name: String,
-    pub email: String,
-}
-
-// Environment variables defined in the docker compose to connect ot the DB
-const DB_URL: &str = env!("DATABASE_URL");
-
-const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n";
-const BAD_REQUEST: &str = "HTTP/1.1 400 BAD REQUEST\r\n\r\n";
-const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\n\r\n";
-const INTERNAL_SERVER_ERROR: &str = "HTTP/1.1 500 INTERNAL SERVER ERROR\r\n\r\n";
-
-const CREATE_USERS_TABLE_QUERY: &str =
-    "CREATE TABLE IF NOT EXISTS users (
-        id SERIAL PRIMARY KEY, name VARCHAR NOT NULL, email VARCHAR UNIQUE NOT NULL
-    )";
+use rust_postgresql_tutorial::cli::{ self, Command };
+use rust_postgresql_tutorial::client;
+use rust_postgresql_tutorial::config::{ self, Config };
+use rust_postgresql_tutorial::logger::{ self, Logger };
+use rust_postgresql_tutorial::reload;
 
 fn main() {
-    // Set the database
-    if set_database().is_err() {
-        return;
-    }
-
-    // Start the server
-    let port = env::var("PORT").unwrap();
-    let addr = format!("0.0.0.0:{}", port);
-
-    let listener = TcpListener::bind(&addr).unwrap();
-
-    // Handle the requests
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => handle_client(stream),
-            Err(e) => println!("Error: {}", e),
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cli = match cli::parse(&args) {
+        Ok(cli) => cli,
+        Err(usage) => {
+            eprintln!("{}", usage);
+            process::exit(cli::EXIT_USAGE);
         }
+    };
+    // Without reading the settings, which may not be there
+    match cli.command {
+        Command::Version => return println!("{}", cli::version()),
+        Command::Help => return println!("{}", cli::USAGE),
+        _ => {}
     }
-}
-
-// Database setup: change this accordingly to the model
-fn set_database() -> Result<(), PostgresError> {
-    let mut client = Client::connect(DB_URL, NoTls).unwrap(); // db connection
-    client.batch_execute(CREATE_USERS_TABLE_QUERY)?; // Create the table
-    Ok(())
-}
-
-// Handle the requests
-fn handle_client(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    let mut request = String::new();
-
-    match stream.read(&mut buffer) {
-        Ok(size) => {
-            request.push_str(&String::from_utf8_lossy(&buffer[..size]));
-
-            let (status_line, content) = match () {
-                _ if request.starts_with("GET /users/") => handle_get_user_request(&request),
-                _ if request.starts_with("GET /users") => handle_get_all_request(&request),
-                _ if request.starts_with("POST /users") => handle_post_request(&request),
-                _ if request.starts_with("PUT /users") => handle_update_request(&request),
-                _ if request.starts_with("DELETE /users") => handle_delete_request(&request),
-
-                _ => (NOT_FOUND.to_owned(), "404 Not Found".to_owned()),
-            };
-
-            stream.write_all(format!("{}{}", status_line, content).as_bytes()).unwrap();
-        }
-        Err(e) => eprintln!("Error: {}", e),
+    let with_level = |logger: Logger| match cli.log_level {
+        Some(level) => logger.with_default_level(level),
+        None => logger,
+    };
+    // Before anything reads the environment, while there is one thread
+    let env_file_warnings = config::load_env_files();
+    // Only the client settings, the API it calls has the others
+    if let Command::Client(command) = &cli.command {
+        env_file_warnings.iter().for_each(|warning| eprintln!("{}", warning));
+        process::exit(client::run(command, &mut io::stdout()));
     }
-}
-
-// Get one user
-fn handle_get_user_request(request: &str) -> (String, String) {
-    let id = get_id(&request);
-
-    match id.parse::<i32>() {
-        Ok(id_int) => {
-            let mut client = Client::connect(DB_URL, NoTls).unwrap();
-            match client.query_one("SELECT * FROM users WHERE id = $1", &[&id_int]) {
-                Ok(row) => {
-                    let user = User { id: row.get(0), name: row.get(1), email: row.get(2) };
-                    (OK_RESPONSE.to_owned(), serde_json::to_string(&user).unwrap())
+    let mut config = match Config::from_env(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            match e.logger {
+                Some(logger) => {
+                    logger::init(with_level(logger));
+                    env_file_warnings.iter().for_each(|warning| log::warn!(target: "config", "{}", warning));
+                    e.errors.iter().for_each(|error| log::error!("{}", error));
                 }
-                Err(_) => (NOT_FOUND.to_owned(), format!("User with ID {} not found", id)),
+                None => env_file_warnings.iter().chain(&e.errors).for_each(|line| eprintln!("{}", line)),
             }
-        }
-        Err(_) => (BAD_REQUEST.to_owned(), format!("Invalid ID: {}", id)),
-    }
-}
-
-//Get all users
-fn handle_get_all_request(_request: &str) -> (String, String) {
-    let mut client = Client::connect(DB_URL, NoTls).unwrap();
-
-    let users: Vec<User> = client
-        .query("SELECT id, name, email FROM users", &[])
-        .unwrap()
-        .into_iter()
-        .map(|row| User { id: row.get(0), name: row.get(1), email: row.get(2) })
-        .collect();
-
-    (OK_RESPONSE.to_owned(), serde_json::to_string(&users).unwrap())
-}
-
-//Create a new user
-fn handle_post_request(request: &str) -> (String, String) {
-    let user = deserialize_user_from_request_body(&request);
-
-    match user {
-        Ok(user) => {
-            let mut client = Client::connect(DB_URL, NoTls).unwrap();
-            if
-                let Err(_) = client.execute(
-                    "INSERT INTO users (name, email) VALUES ($1, $2)",
-                    &[&user.name, &user.email]
-                )
-            {
-                return (
-                    INTERNAL_SERVER_ERROR.to_owned(),
-                    "Failed to insert user into database".to_owned(),
-                );
+            if let Command::Check(options) = &cli.command {
+                cli::report_invalid_config(&e.errors, options.json, &mut io::stdout());
             }
-
-            (OK_RESPONSE.to_owned(), serde_json::to_string(&user).unwrap())
+            process::exit(cli::EXIT_CONFIG);
         }
-        Err(_) => (BAD_REQUEST.to_owned(), "Invalid request body".to_owned()),
+    };
+    config.logger = with_level(config.logger);
+    logger::init(config.logger.clone());
+    if let Some(file) = config.file.as_ref().filter(|file| !file.unknown.is_empty()) {
+        log::warn!("Unknown settings in the config file {}, left out: {}", file.path, file.unknown.join(", "));
     }
-}
-
-// Update user
-fn handle_update_request(request: &str) -> (String, String) {
-    let id = get_id(&request);
-    let user = deserialize_user_from_request_body(&request);
-
-    match (id.parse::<i32>(), user) {
-        (Ok(id_int), Ok(new_user)) => {
-            let mut client = Client::connect(DB_URL, NoTls).unwrap();
-            match
-                client.execute(
-                    "UPDATE users SET name=$2, email=$3 WHERE id=$1",
-                    &[&id_int, &new_user.name, &new_user.email]
-                )
-            {
-                Ok(_) => (OK_RESPONSE.to_owned(), serde_json::to_string(&new_user).unwrap()),
-                Err(e) => (INTERNAL_SERVER_ERROR.to_owned(), format!("Error updating user: {}", e)),
-            }
+    env_file_warnings.iter().for_each(|warning| log::warn!(target: "config", "{}", warning));
+    config.effective.iter().for_each(|setting| log::debug!(target: "config", "{}", setting));
+    let config = match rust_postgresql_tutorial::init(config) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{}", e);
+            process::exit(cli::EXIT_CONFIG);
         }
-        (Err(e), _) => (BAD_REQUEST.to_owned(), format!("Invalid ID: {}. Error: {}", id, e)),
-        (_, Err(_)) => (BAD_REQUEST.to_owned(), "Invalid request body".to_owned()),
+    };
+    rust_postgresql_tutorial::report_panics();
+
+    // The other commands exit once done, without serving
+    let options = match cli.command {
+        Command::Serve(options) => options,
+        command => process::exit(cli::run(&config, command, &mut io::stdout())),
+    };
+    log::info!("{} with APP_ENV {}, the {} profile", cli::version(), config.app_env, config.profile.name());
+    if let Some(e) = config.refused_to_serve() {
+        log::error!("{}", e);
+        process::exit(cli::EXIT_CONFIG);
     }
-}
-
-// Delete user
-fn handle_delete_request(request: &str) -> (String, String) {
-    let id = get_id(&request);
-
-    if let Ok(id_int) = id.parse::<i32>() {
-        let mut client = Client::connect(DB_URL, NoTls).unwrap();
-        let rows_affected = client.execute("DELETE FROM users WHERE id = $1", &[&id_int]).unwrap();
-
-        if rows_affected == 1 {
-            (OK_RESPONSE.to_owned(), serde_json::to_string(&id).unwrap())
-        } else {
-            (NOT_FOUND.to_owned(), format!("User with ID {} not found", id_int))
+    let repositories = rust_postgresql_tutorial::open_repositories(&config, &options);
+    reload::init(&config, cli.config.clone(), cli.log_level);
+
+    let server = match rust_postgresql_tutorial::start(Arc::clone(&config), repositories) {
+        Ok(server) => server,
+        Err(e) => {
+            log::error!("Can't listen on {}: {}", config.bind, e);
+            process::exit(cli::EXIT_DEPENDENCY);
         }
-    } else {
-        (BAD_REQUEST.to_owned(), format!("Invalid ID: {}", id))
+    };
+    if let Err(e) = server.shutdown_on_signals() {
+        log::error!("Can't handle the shutdown signals: {}", e);
+        process::exit(cli::EXIT_DEPENDENCY);
     }
+    if server.join().is_err() {
+        process::exit(1);
+    }
+    process::exit(0);
 }
-
-fn get_id(request: &str) -> &str {
-    request.split('/').nth(2).unwrap_or_default().split_whitespace().next().unwrap_or_default()
-}
-
-fn deserialize_user_from_request_body(request: &str) -> Result<User, serde_json::Error> {
-    let request_body = request.split("\r\n\r\n").last().unwrap_or("");
-    let user: Result<User, _> = serde_json::from_str(request_body);
-    user
-}
\ No newline at end of file
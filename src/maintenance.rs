@@ -0,0 +1,155 @@
+use std::fs;
+use std::sync::RwLock;
+
+use crate::credentials;
+use crate::config::{ self, number_from_env };
+use crate::http::{ get_body, BAD_REQUEST, OK_RESPONSE };
+use crate::locale;
+
+const DEFAULT_MESSAGE: &str = "The service is down for maintenance";
+const DEFAULT_RETRY_AFTER: u64 = 60;
+
+static FILE: RwLock<Option<String>> = RwLock::new(None);
+static STATE: RwLock<State> = RwLock::new(State { maintenance: None, reload_requests: 0 });
+
+// While on, every request but the probes, the metrics and the switch itself is
+// answered 503, or only the writes when read_only. Turned on at startup with
+// MAINTENANCE=on or read-only, at runtime with POST /admin/maintenance, or by
+// MAINTENANCE_FILE, read again after a SIGHUP; the latest of them wins.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Maintenance {
+    pub enabled: bool,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default = "default_message")]
+    pub message: String,
+    // Seconds, for Retry-After
+    #[serde(default = "default_retry_after")]
+    pub retry_after: u64,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Maintenance { enabled: false, read_only: false, message: default_message(), retry_after: DEFAULT_RETRY_AFTER }
+    }
+}
+
+fn default_message() -> String {
+    DEFAULT_MESSAGE.to_owned()
+}
+
+fn default_retry_after() -> u64 {
+    DEFAULT_RETRY_AFTER
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaintenanceConfig {
+    pub initial: Maintenance,
+    // Holds a Maintenance in JSON
+    pub file: Option<String>,
+}
+
+impl MaintenanceConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let (enabled, read_only) = match config::var("MAINTENANCE").as_deref() {
+            Ok("off") | Err(_) => (false, false),
+            Ok("on") => (true, false),
+            Ok("read-only") => (true, true),
+            Ok(value) => return Err(format!("MAINTENANCE must be off, on or read-only, got {:?}", value)),
+        };
+        let initial = Maintenance {
+            enabled,
+            read_only,
+            message: config::var("MAINTENANCE_MESSAGE").unwrap_or_else(|_| default_message()),
+            retry_after: number_from_env("MAINTENANCE_RETRY_AFTER", DEFAULT_RETRY_AFTER)?,
+        };
+        let file = config::var("MAINTENANCE_FILE").ok();
+        let initial = match &file {
+            Some(path) => read_file(path)?,
+            None => initial,
+        };
+        Ok(MaintenanceConfig { initial, file })
+    }
+}
+
+fn read_file(path: &str) -> Result<Maintenance, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Can't read MAINTENANCE_FILE {}: {}", path, e))?;
+    serde_json::from_str(&content).map_err(|e| format!("Invalid MAINTENANCE_FILE {}: {}", path, e))
+}
+
+struct State {
+    // None until init, which is off
+    maintenance: Option<Maintenance>,
+    // credentials::reload_requests() when the file was last read
+    reload_requests: u64,
+}
+
+// At startup, and again when a SIGHUP changed it, over POST /admin/maintenance
+pub fn init(config: MaintenanceConfig) {
+    let reads_file = config.file.is_some();
+    *STATE.write().unwrap() =
+        State { maintenance: Some(config.initial), reload_requests: credentials::reload_requests() };
+    *FILE.write().unwrap() = config.file;
+    if reads_file {
+        credentials::reload_on_sighup();
+    }
+}
+
+// What it is now, the file read again first if there was a SIGHUP since
+pub fn current() -> Option<Maintenance> {
+    let reload_requests = credentials::reload_requests();
+    let state = STATE.read().unwrap();
+    let path = FILE.read().unwrap().clone();
+    match path {
+        Some(path) if state.reload_requests != reload_requests => {
+            drop(state);
+            let mut state = STATE.write().unwrap();
+            state.reload_requests = reload_requests;
+            match read_file(&path) {
+                Ok(maintenance) => state.maintenance = Some(maintenance),
+                Err(e) => log::warn!("Keeping the maintenance mode as it was: {}", e),
+            }
+            state.maintenance.clone()
+        }
+        _ => state.maintenance.clone(),
+    }
+}
+
+// The response to a request turned away by the maintenance, if it is
+pub fn turned_away(method: &str, segments: &[&str]) -> Option<(String, String)> {
+    if matches!(segments, ["health"] | ["livez"] | ["metrics"] | ["admin", "maintenance"]) {
+        return None;
+    }
+    let maintenance = current().filter(|maintenance| maintenance.enabled)?;
+    if maintenance.read_only && matches!(method, "GET" | "HEAD") {
+        return None;
+    }
+    let status_line = format!(
+        "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Type: application/problem+json\r\nRetry-After: {}\r\n\r\n",
+        maintenance.retry_after
+    );
+    let body = serde_json::json!({
+        "type": "about:blank",
+        "title": locale::title(503, "Service Unavailable"),
+        "status": 503,
+        "detail": maintenance.message,
+    });
+    Some((status_line, body.to_string()))
+}
+
+// GET /admin/maintenance
+pub fn handle_get_maintenance_request() -> (String, String) {
+    let maintenance = current().unwrap_or_default();
+    (OK_RESPONSE.to_owned(), serde_json::to_string(&maintenance).unwrap())
+}
+
+// POST /admin/maintenance, with a Maintenance
+pub fn handle_set_maintenance_request(request: &str) -> (String, String) {
+    let maintenance: Maintenance = match serde_json::from_str(get_body(request)) {
+        Ok(maintenance) => maintenance,
+        Err(_) => return (BAD_REQUEST.to_owned(), "Invalid request body".to_owned()),
+    };
+    STATE.write().unwrap().maintenance = Some(maintenance.clone());
+    log::warn!("Maintenance mode set to {:?}", maintenance);
+    (OK_RESPONSE.to_owned(), serde_json::to_string(&maintenance).unwrap())
+}
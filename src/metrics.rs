@@ -0,0 +1,309 @@
+use std::fmt::Write;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::Duration;
+
+use crate::cache;
+use crate::chaos;
+use crate::alerts;
+use crate::coalesce;
+use crate::error_reports;
+use crate::log_sampling;
+use crate::otlp;
+use crate::pool::{ Pool, PoolStats };
+use crate::repository::circuit::State;
+use crate::route_metrics;
+use crate::route_timeout;
+use crate::slow;
+use crate::spans;
+use crate::statsd;
+use crate::db::{ circuit, permits, POOL, READ_POOL };
+use crate::http::{ NOT_IMPLEMENTED, OK_RESPONSE };
+use crate::{ connections, workers };
+
+const METRICS_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\r\n";
+
+// GET /metrics, in the text format of Prometheus. The numbers are kept by what
+// they are about, this only writes them out.
+pub fn handle_metrics_request() -> (String, String) {
+    let mut body = String::new();
+    let circuit = circuit();
+
+    let state = circuit.state();
+    metric(&mut body, "circuit_breaker_state", "gauge", "State of the circuit breaker in front of the database");
+    for each in [State::Closed, State::Open, State::HalfOpen] {
+        writeln!(body, "circuit_breaker_state{{state=\"{}\"}} {}", each.name(), (each == state) as u8).unwrap();
+    }
+    metric(&mut body, "circuit_breaker_transitions_total", "counter", "Changes of state of the circuit breaker");
+    for to in [State::Closed, State::Open, State::HalfOpen] {
+        let transitions = circuit.transitions(to);
+        writeln!(body, "circuit_breaker_transitions_total{{to=\"{}\"}} {}", to.name(), transitions).unwrap();
+    }
+
+    let permits = permits();
+    metric(&mut body, "db_operations_in_progress", "gauge", "Repository operations holding a permit");
+    writeln!(body, "db_operations_in_progress {}", permits.in_use()).unwrap();
+    metric(&mut body, "db_operation_wait_seconds", "histogram", "Time repository operations waited for a permit");
+    permits.wait_time.write(&mut body, "db_operation_wait_seconds", "");
+    metric(&mut body, "db_operations_rejected_total", "counter", "Repository operations that got no permit in time");
+    writeln!(body, "db_operations_rejected_total {}", permits.rejected.load(Ordering::Relaxed)).unwrap();
+    let help = "Time the repository operations took, by operation";
+    metric(&mut body, "db_operation_duration_seconds", "histogram", help);
+    for (operation, time) in spans::statement_times() {
+        time.write(&mut body, "db_operation_duration_seconds", &format!("operation=\"{}\"", operation));
+    }
+
+    if let Some(dropped) = error_reports::dropped() {
+        metric(&mut body, "error_reports_dropped_total", "counter", "Errors not reported as the queue was full");
+        writeln!(body, "error_reports_dropped_total {}", dropped).unwrap();
+    }
+    if let Some((dropped, failures)) = statsd::counts() {
+        metric(&mut body, "statsd_dropped_total", "counter", "Metrics not sent to StatsD as the queue was full");
+        writeln!(body, "statsd_dropped_total {}", dropped).unwrap();
+        metric(&mut body, "statsd_send_failures_total", "counter", "Datagrams of metrics StatsD couldn't be sent");
+        writeln!(body, "statsd_send_failures_total {}", failures).unwrap();
+    }
+    if let Some(dropped) = otlp::dropped() {
+        metric(&mut body, "otlp_spans_dropped_total", "counter", "Spans not exported as the queue was full");
+        writeln!(body, "otlp_spans_dropped_total {}", dropped).unwrap();
+    }
+
+    let workers = workers();
+    metric(&mut body, "workers", "gauge", "Worker threads handling the requests");
+    writeln!(body, "workers {}", workers.config().threads).unwrap();
+    metric(&mut body, "workers_busy", "gauge", "Workers handling a request");
+    writeln!(body, "workers_busy {}", workers.busy()).unwrap();
+    metric(&mut body, "worker_queue_depth", "gauge", "Accepted connections waiting for a worker");
+    writeln!(body, "worker_queue_depth {}", workers.queue_depth()).unwrap();
+    metric(&mut body, "requests_shed_total", "counter", "Connections answered 503 because the queue stayed full");
+    writeln!(body, "requests_shed_total {}", workers.shed.load(Ordering::Relaxed)).unwrap();
+
+    let connections = connections();
+    metric(&mut body, "connections_active", "gauge", "Open connections, the streams included");
+    writeln!(body, "connections_active {}", connections.active()).unwrap();
+    metric(&mut body, "connections_max", "gauge", "Connections open at once before new ones are refused");
+    writeln!(body, "connections_max {}", connections.config().max_connections).unwrap();
+    metric(&mut body, "connections_rejected_total", "counter", "Connections answered 503 for being over the limit");
+    writeln!(body, "connections_rejected_total {}", connections.rejected.load(Ordering::Relaxed)).unwrap();
+
+    if let Some(cache) = cache::cache() {
+        if let Some(size) = cache.size() {
+            metric(&mut body, "user_cache_entries", "gauge", "Responses of GET /users/{id} in the cache");
+            writeln!(body, "user_cache_entries {}", size).unwrap();
+        }
+        metric(&mut body, "user_cache_hits_total", "counter", "Users answered from the cache");
+        writeln!(body, "user_cache_hits_total {}", cache.hits.load(Ordering::Relaxed)).unwrap();
+        metric(&mut body, "user_cache_misses_total", "counter", "Users not in the cache, or expired");
+        writeln!(body, "user_cache_misses_total {}", cache.misses.load(Ordering::Relaxed)).unwrap();
+        if let Some(evictions) = cache.evictions() {
+            metric(&mut body, "user_cache_evictions_total", "counter", "Users dropped from the cache to make room");
+            writeln!(body, "user_cache_evictions_total {}", evictions).unwrap();
+        }
+    }
+
+    metric(&mut body, "requests_coalesced_total", "counter", "Requests answered with the response of a concurrent one");
+    writeln!(body, "requests_coalesced_total {}", coalesce::flights().coalesced.load(Ordering::Relaxed)).unwrap();
+
+    metric(&mut body, "route_timeouts_total", "counter", "Requests answered 504 for running past their route timeout");
+    for (route, count) in route_timeout::timeouts() {
+        writeln!(body, "route_timeouts_total{{route=\"{}\"}} {}", route, count).unwrap();
+    }
+
+    let (slow_requests, slow_queries) = slow::counts();
+    metric(&mut body, "slow_events_total", "counter", "Requests and queries logged for taking too long");
+    writeln!(body, "slow_events_total{{kind=\"request\"}} {}", slow_requests).unwrap();
+    writeln!(body, "slow_events_total{{kind=\"query\"}} {}", slow_queries).unwrap();
+
+    metric(&mut body, "chaos_faults_injected_total", "counter", "Faults injected by the chaos mode, by fault");
+    for (fault, count) in chaos::injected() {
+        writeln!(body, "chaos_faults_injected_total{{fault=\"{}\"}} {}", fault, count).unwrap();
+    }
+
+    metric(&mut body, "access_log_sampled_out_total", "counter", "Lines of successful requests left out of the log");
+    writeln!(body, "access_log_sampled_out_total {}", log_sampling::sampled_out()).unwrap();
+
+    write_alert_metrics(&mut body);
+    write_request_metrics(&mut body);
+    write_pool_metrics(&mut body);
+
+    (METRICS_RESPONSE.to_owned(), body)
+}
+
+// One series each, for alerting on
+fn write_alert_metrics(body: &mut String) {
+    let counts = alerts::counts();
+    let counters = [
+        ("requests_total", "Requests answered or not, those without a request aside", counts.requests),
+        ("requests_4xx_total", "Requests answered with a 4xx", counts.client_errors),
+        ("requests_5xx_total", "Requests answered with a 5xx, or whose handler panicked first", counts.server_errors),
+        ("requests_db_errors_total", "Requests failed by the database", counts.db_errors),
+        ("requests_auth_failures_total", "Requests answered 401 or 403", counts.auth_failures),
+        ("requests_panicked_total", "Requests whose handler panicked", counts.panics),
+    ];
+    for (name, help, count) in counters {
+        metric(body, name, "counter", help);
+        writeln!(body, "{} {}", name, count).unwrap();
+    }
+}
+
+// By the method and route template of the requests, or unmatched, see
+// route_metrics::route, and for the counts the class of their status
+fn write_request_metrics(body: &mut String) {
+    let requested = route_metrics::requested();
+    let help = "Time the requests took to be answered, by route";
+    metric(body, "http_request_duration_seconds", "histogram", help);
+    for requested in &requested {
+        let (method, template) = requested.route.labels();
+        let labels = format!("method=\"{}\",route=\"{}\"", method, template);
+        requested.duration.write(body, "http_request_duration_seconds", &labels);
+    }
+    metric(body, "http_requests_total", "counter", "Requests answered, by route and status class");
+    for requested in &requested {
+        let (method, template) = requested.route.labels();
+        for (class, count) in &requested.statuses {
+            let labels = format!("method=\"{}\",route=\"{}\",status_class=\"{}\"", method, template, class);
+            writeln!(body, "http_requests_total{{{}}} {}", labels, count).unwrap();
+        }
+    }
+}
+
+fn write_pool_metrics(body: &mut String) {
+    let pools = pools();
+    let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let per_pool = |body: &mut String, name: &str, kind: &str, help: &str, value: &dyn Fn(&Pool) -> u64| {
+        metric(body, name, kind, help);
+        for (label, pool) in &pools {
+            writeln!(body, "{}{{pool=\"{}\"}} {}", name, label, value(pool)).unwrap();
+        }
+    };
+
+    per_pool(body, "db_pool_max_size", "gauge", "Connections the pool may open", &|pool| {
+        pool.config().max_size as u64
+    });
+    per_pool(body, "db_pool_size", "gauge", "Connections open, idle or in use", &|pool| {
+        pool.stats().open.load(Ordering::Relaxed) as u64
+    });
+    per_pool(body, "db_pool_idle", "gauge", "Connections waiting in the pool", &|pool| idle(pool.stats()));
+    per_pool(body, "db_pool_in_use", "gauge", "Connections checked out", &|pool| {
+        pool.stats().in_use.load(Ordering::Relaxed) as u64
+    });
+    per_pool(body, "db_pool_checkouts_total", "counter", "Connections checked out of the pool", &|pool| {
+        count(&pool.stats().checkouts)
+    });
+    per_pool(body, "db_pool_checkout_timeouts_total", "counter", "Checkouts that timed out", &|pool| {
+        count(&pool.stats().checkout_timeouts)
+    });
+    per_pool(body, "db_pool_connections_discarded_total", "counter", "Broken connections closed", &|pool| {
+        count(&pool.stats().discarded)
+    });
+    per_pool(body, "db_transaction_retries_total", "counter", "Transactions run again after a conflict", &|pool| {
+        count(&pool.stats().transaction_retries)
+    });
+
+    metric(body, "db_pool_checkout_wait_seconds", "histogram", "Time waited for a connection");
+    for (label, pool) in &pools {
+        pool.stats().checkout_wait.write(body, "db_pool_checkout_wait_seconds", &format!("pool=\"{}\"", label));
+    }
+}
+
+// GET /debug/pool, the numbers of /metrics about the pools. They are read without
+// going through the pools, so they answer even when every connection is in use.
+pub fn handle_pool_status_request() -> (String, String) {
+    if pools().is_empty() {
+        return (NOT_IMPLEMENTED.to_owned(), "Only available when DATABASE_URL is a Postgres database".to_owned());
+    }
+    (OK_RESPONSE.to_owned(), serde_json::Value::Object(pool_status()).to_string())
+}
+
+// The stats of the pools of Postgres, by label, none without them
+pub fn pool_status() -> serde_json::Map<String, serde_json::Value> {
+    let mut status = serde_json::Map::new();
+    for (label, pool) in pools() {
+        let stats = pool.stats();
+        let pool_status = serde_json::json!({
+            "max_size": pool.config().max_size,
+            "size": stats.open.load(Ordering::Relaxed),
+            "idle": idle(stats),
+            "in_use": stats.in_use.load(Ordering::Relaxed),
+            "checkouts": stats.checkouts.load(Ordering::Relaxed),
+            "checkout_timeouts": stats.checkout_timeouts.load(Ordering::Relaxed),
+            "checkout_wait_seconds": {
+                "count": stats.checkout_wait.count(),
+                "sum": stats.checkout_wait.sum().as_secs_f64(),
+            },
+            "discarded": stats.discarded.load(Ordering::Relaxed),
+            "transaction_retries": stats.transaction_retries.load(Ordering::Relaxed),
+        });
+        status.insert(label.to_owned(), pool_status);
+    }
+    status
+}
+
+// The pools of Postgres, with the label of their metrics
+fn pools() -> Vec<(&'static str, &'static Pool)> {
+    let primary = POOL.get().map(|pool| ("primary", pool));
+    let replica = READ_POOL.get().map(|pool| ("replica", pool));
+    primary.into_iter().chain(replica).collect()
+}
+
+// The label of the metrics of the pool
+pub fn label(pool: &Pool) -> &'static str {
+    pools().into_iter().find(|(_, each)| std::ptr::eq(*each, pool)).map_or("primary", |(label, _)| label)
+}
+
+// The connections being opened are counted as idle until checked out
+fn idle(stats: &PoolStats) -> u64 {
+    stats.open.load(Ordering::Relaxed).saturating_sub(stats.in_use.load(Ordering::Relaxed)) as u64
+}
+
+fn metric(body: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(body, "# HELP {} {}", name, help).unwrap();
+    writeln!(body, "# TYPE {} {}", name, kind).unwrap();
+}
+
+// Upper bounds of the buckets of the histograms of durations, in seconds
+const BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+// Durations counted in buckets, each with those not longer than its bound
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    // The samples, after the HELP and TYPE of the metric, with labels like a="b"
+    fn write(&self, body: &mut String, name: &str, labels: &str) {
+        let (bucket_labels, labels) = if labels.is_empty() {
+            (String::new(), String::new())
+        } else {
+            (format!("{},", labels), format!("{{{}}}", labels))
+        };
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            let count = bucket.load(Ordering::Relaxed);
+            writeln!(body, "{}_bucket{{{}le=\"{}\"}} {}", name, bucket_labels, bound, count).unwrap();
+        }
+        writeln!(body, "{}_bucket{{{}le=\"+Inf\"}} {}", name, bucket_labels, self.count()).unwrap();
+        writeln!(body, "{}_sum{} {}", name, labels, self.sum().as_secs_f64()).unwrap();
+        writeln!(body, "{}_count{} {}", name, labels, self.count()).unwrap();
+    }
+}
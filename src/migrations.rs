@@ -0,0 +1,512 @@
+use postgres::{ Client, GenericClient, Transaction };
+use sha2::{ Digest, Sha256 };
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::profile::Profile;
+use crate::tables::{ self, Naming };
+use crate::{ config, redact, validation };
+
+// The files of migrations/, embedded by build.rs as (version, description, up, down)
+include!(concat!(env!("OUT_DIR"), "/sql_migrations.rs"));
+
+// Migrations that need more than SQL, numbered along with the files, as
+// (version, description, up, down)
+const RUST_MIGRATIONS: &[(i64, &str, RustStep, Option<RustStep>)] = &[
+    (2, "normalize stored emails and names", normalize_stored_text, None),
+];
+
+const CREATE_SCHEMA_MIGRATIONS_TABLE_QUERY: &str =
+    "CREATE TABLE IF NOT EXISTS {schema_migrations} (
+        version BIGINT PRIMARY KEY, description VARCHAR NOT NULL, checksum VARCHAR,
+        applied_at TIMESTAMPTZ NOT NULL DEFAULT now(), rolled_back_at TIMESTAMPTZ
+    )";
+
+// The table predates these columns on databases migrated by earlier versions
+const ADD_MISSING_COLUMNS_QUERY: &str =
+    "ALTER TABLE {schema_migrations} ADD COLUMN IF NOT EXISTS checksum VARCHAR,
+        ADD COLUMN IF NOT EXISTS rolled_back_at TIMESTAMPTZ";
+
+// How often check mode looks for the missing migrations again
+const DEFAULT_CHECK_INTERVAL_SECS: u64 = 5;
+
+// The migrations check mode is waiting for, empty once the schema caught up
+static WAITING_FOR: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Held while migrating, so that instances starting together apply each migration once
+const MIGRATIONS_LOCK_KEY: i64 = 0x7275_7374_6170_6901;
+
+// Emails are unique regardless of case
+const CREATE_EMAIL_LOWER_INDEX_QUERY: &str =
+    "CREATE UNIQUE INDEX IF NOT EXISTS {users_email_lower_key} ON {users} (lower(email))";
+
+// First line of the SQL migrations that can't run in a transaction, like CREATE
+// INDEX CONCURRENTLY. Postgres runs the statements of one query in a transaction
+// anyway, so they must hold a single statement.
+const NO_TRANSACTION: &str = "-- migrate: no-transaction";
+
+type RustStep = fn(&mut Transaction) -> Result<(), Box<dyn Error>>;
+
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub up: Step,
+    // None when the migration can't be undone
+    pub down: Option<Step>,
+}
+
+#[derive(Clone, Copy)]
+pub enum Step {
+    Sql(&'static str),
+    Rust(RustStep),
+}
+
+impl Migration {
+    // Applied migrations must not change, which is checked for the SQL ones. The
+    // checksum is over the SQL without a prefix, the same whatever the names are.
+    fn checksum(&self) -> Option<String> {
+        match self.up {
+            Step::Sql(sql) => Some(format!("{:x}", Sha256::digest(Naming::default().render(sql).as_bytes()))),
+            Step::Rust(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04} ({})", self.version, self.description)
+    }
+}
+
+// What to do with pending migrations at startup, from MIGRATIONS_MODE, apply by
+// default but in prod, where it is check
+#[derive(Debug, PartialEq)]
+pub enum Mode {
+    Apply,
+    // Another job applies them: serve only once it has, checking again every
+    // MIGRATIONS_CHECK_INTERVAL_SECS
+    Check(Duration),
+    // The schema is someone else's business
+    Ignore,
+}
+
+impl Mode {
+    pub fn from_env() -> Result<Self, String> {
+        let mode = config::var("MIGRATIONS_MODE");
+        let mode = mode.as_deref().unwrap_or(match Profile::from_env() {
+            Profile::Prod => "check",
+            Profile::Dev | Profile::Test => "apply",
+        });
+        match mode {
+            "apply" => Ok(Mode::Apply),
+            "check" => {
+                let interval = match config::var("MIGRATIONS_CHECK_INTERVAL_SECS") {
+                    Ok(value) =>
+                        value.trim().parse().ok().filter(|secs| *secs > 0).ok_or_else(|| {
+                            format!("MIGRATIONS_CHECK_INTERVAL_SECS must be a positive number, got {:?}", value)
+                        })?,
+                    Err(_) => DEFAULT_CHECK_INTERVAL_SECS,
+                };
+                Ok(Mode::Check(Duration::from_secs(interval)))
+            }
+            "ignore" => Ok(Mode::Ignore),
+            value => Err(format!("MIGRATIONS_MODE must be apply, check or ignore, got {:?}", value)),
+        }
+    }
+}
+
+// The pending migrations the server waits for before serving, in check mode
+pub fn waiting_for() -> Vec<String> {
+    WAITING_FOR.lock().unwrap().clone()
+}
+
+pub fn set_waiting_for(pending: &[Migration]) {
+    *WAITING_FOR.lock().unwrap() = pending.iter().map(|migration| migration.to_string()).collect();
+}
+
+// How far `migrate down` goes
+#[derive(Debug, PartialEq)]
+pub enum RollbackTarget {
+    // The given number of migrations, newest first
+    Steps(usize),
+    // Every migration after the given version, which stays applied
+    Version(i64),
+}
+
+// Every migration in the order they are applied, numbered 1, 2, 3... without gaps
+pub fn all() -> Result<Vec<Migration>, String> {
+    let sql = SQL_MIGRATIONS.iter().map(|&(version, description, up, down)| Migration {
+        version,
+        description,
+        up: Step::Sql(up),
+        down: down.map(Step::Sql),
+    });
+    let rust = RUST_MIGRATIONS.iter().map(|&(version, description, up, down)| Migration {
+        version,
+        description,
+        up: Step::Rust(up),
+        down: down.map(Step::Rust),
+    });
+    let mut migrations: Vec<Migration> = sql.chain(rust).collect();
+    migrations.sort_by_key(|migration| migration.version);
+
+    for (expected, migration) in (1..).zip(&migrations) {
+        if migration.version < expected {
+            return Err(format!("migration {:04} is defined twice", migration.version));
+        }
+        if migration.version > expected {
+            return Err(format!("migrations are numbered without gaps, {:04} is missing", expected));
+        }
+    }
+    Ok(migrations)
+}
+
+// Apply the pending migrations, each in its own transaction, and return them
+pub fn apply(client: &mut Client) -> Result<Vec<Migration>, Box<dyn Error>> {
+    apply_up_to(client, i64::MAX)
+}
+
+// Only those up to the version, included, for `migrate --to`
+pub fn apply_up_to(client: &mut Client, version: i64) -> Result<Vec<Migration>, Box<dyn Error>> {
+    tables::create_schema(client)?;
+    with_lock(client, |client| {
+        prepare_table(client)?;
+        // Checked under the lock: another instance may have just applied some
+        let mut pending = pending(client)?;
+        pending.retain(|migration| migration.version <= version);
+
+        for migration in &pending {
+            log::info!("Applying migration {}", migration);
+            run_step(client, migration, migration.up, |transaction| {
+                transaction.execute(
+                    tables::sql(
+                        "INSERT INTO {schema_migrations} (version, description, checksum) VALUES ($1, $2, $3)
+                        ON CONFLICT (version) DO UPDATE SET description = $2, checksum = $3,
+                            applied_at = now(), rolled_back_at = NULL"
+                    ),
+                    &[&migration.version, &migration.description, &migration.checksum()]
+                )
+            })?;
+        }
+
+        Ok(pending)
+    })
+}
+
+// Undo applied migrations, newest first and each in its own transaction, and
+// return them. Nothing is undone when one of them can't be.
+pub fn roll_back(client: &mut Client, target: RollbackTarget) -> Result<Vec<Migration>, Box<dyn Error>> {
+    with_lock(client, |client| {
+        prepare_table(client)?;
+        let (applied, _) = applied(client)?;
+        let mut versions: Vec<i64> = applied.iter().map(|applied| applied.version).rev().collect();
+        match target {
+            RollbackTarget::Steps(steps) => versions.truncate(steps),
+            RollbackTarget::Version(version) => versions.retain(|applied| *applied > version),
+        }
+
+        let mut migrations = all()?;
+        let mut selected = Vec::new();
+        for version in versions {
+            match migrations.iter().position(|migration| migration.version == version) {
+                Some(index) => selected.push(migrations.remove(index)),
+                None => {
+                    return Err(
+                        format!(
+                            "migration {:04} is unknown to this version, roll it back with the version that applied it",
+                            version
+                        ).into()
+                    );
+                }
+            }
+        }
+
+        let irreversible: Vec<String> = selected
+            .iter()
+            .filter(|migration| migration.down.is_none())
+            .map(|migration| migration.to_string())
+            .collect();
+        if !irreversible.is_empty() {
+            return Err(format!("can't roll back irreversible migrations: {}", irreversible.join(", ")).into());
+        }
+
+        for migration in &selected {
+            log::info!("Rolling back migration {}", migration);
+            run_step(client, migration, migration.down.unwrap(), |transaction| {
+                transaction.execute(
+                    tables::sql("UPDATE {schema_migrations} SET rolled_back_at = now() WHERE version = $1"),
+                    &[&migration.version]
+                )
+            })?;
+        }
+
+        Ok(selected)
+    })
+}
+
+fn with_lock<T>(
+    client: &mut Client,
+    work: impl FnOnce(&mut Client) -> Result<T, Box<dyn Error>>
+) -> Result<T, Box<dyn Error>> {
+    client.execute("SELECT pg_advisory_lock($1)", &[&MIGRATIONS_LOCK_KEY])?;
+    let result = work(client);
+    client.execute("SELECT pg_advisory_unlock($1)", &[&MIGRATIONS_LOCK_KEY])?;
+    result
+}
+
+fn prepare_table(client: &mut Client) -> Result<(), postgres::Error> {
+    client.batch_execute(tables::sql(CREATE_SCHEMA_MIGRATIONS_TABLE_QUERY))?;
+    client.batch_execute(tables::sql(ADD_MISSING_COLUMNS_QUERY))
+}
+
+// Run one step of a migration and record it, in the same transaction unless the
+// step is NO_TRANSACTION: then it is recorded once it succeeded
+fn run_step(
+    client: &mut Client,
+    migration: &Migration,
+    step: Step,
+    record: impl FnOnce(&mut Transaction) -> Result<u64, postgres::Error>
+) -> Result<(), Box<dyn Error>> {
+    if let Step::Sql(sql) = step {
+        if sql.starts_with(NO_TRANSACTION) {
+            if let Err(e) = client.batch_execute(tables::sql(sql)) {
+                return Err(format!("migration {} failed: {}", migration, crate::errors::with_causes(&e)).into());
+            }
+            let mut transaction = client.transaction()?;
+            record(&mut transaction)?;
+            transaction.commit()?;
+            return Ok(());
+        }
+    }
+
+    let mut transaction = client.transaction()?;
+    let result = match step {
+        Step::Sql(sql) => transaction.batch_execute(tables::sql(sql)).map_err(Into::into),
+        Step::Rust(step) => step(&mut transaction),
+    };
+    if let Err(e) = result {
+        return Err(format!("migration {} failed: {}", migration, crate::errors::with_causes(&*e)).into());
+    }
+    record(&mut transaction)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+// A row of schema_migrations
+struct Applied {
+    version: i64,
+    checksum: Option<String>,
+}
+
+// The applied migrations, oldest first, and whether checksums are recorded. Only
+// reads, so it works with the table as any earlier version left it.
+fn applied(client: &mut impl GenericClient) -> Result<(Vec<Applied>, bool), postgres::Error> {
+    let columns: Vec<String> = client
+        .query(
+            "SELECT column_name::text FROM information_schema.columns
+            WHERE table_schema = current_schema() AND table_name = $1",
+            &[&tables::prefixed("schema_migrations")]
+        )?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    if columns.is_empty() {
+        return Ok((Vec::new(), false));
+    }
+
+    let has_checksums = columns.iter().any(|column| column == "checksum");
+    let checksum = if has_checksums { "checksum" } else { "NULL::varchar" };
+    let filter = if columns.iter().any(|column| column == "rolled_back_at") {
+        "WHERE rolled_back_at IS NULL"
+    } else {
+        ""
+    };
+    let rows = client.query(
+        &format!(
+            "SELECT version, {} FROM {} {} ORDER BY version",
+            checksum,
+            tables::sql("{schema_migrations}"),
+            filter
+        ),
+        &[]
+    )?;
+    let applied = rows.iter().map(|row| Applied { version: row.get(0), checksum: row.get(1) });
+    Ok((applied.collect(), has_checksums))
+}
+
+// The latest migration applied, 0 on a database never migrated
+pub fn schema_version(client: &mut impl GenericClient) -> Result<i64, postgres::Error> {
+    let (applied, _) = applied(client)?;
+    Ok(applied.iter().map(|applied| applied.version).max().unwrap_or(0))
+}
+
+// The migrations not applied yet. Fails when an applied migration was edited since,
+// or when a pending one is numbered before one already applied.
+pub fn pending(client: &mut Client) -> Result<Vec<Migration>, Box<dyn Error>> {
+    let migrations = all()?;
+    let (applied, has_checksums) = applied(client)?;
+
+    for Applied { version, checksum } in &applied {
+        let migration = match migrations.iter().find(|migration| migration.version == *version) {
+            Some(migration) => migration,
+            None => {
+                log::warn!("Migration {:04} is applied but unknown to this version", version);
+                continue;
+            }
+        };
+        match (checksum, migration.checksum()) {
+            (Some(applied), Some(current)) if *applied != current => {
+                return Err(
+                    format!(
+                        "migration {} was edited after it was applied: restore it and add a new migration instead",
+                        migration
+                    ).into()
+                );
+            }
+            // Applied before checksums were recorded
+            (None, Some(current)) if has_checksums => {
+                client.execute(
+                    tables::sql("UPDATE {schema_migrations} SET checksum = $2 WHERE version = $1"),
+                    &[&version, &current]
+                )?;
+            }
+            _ => {}
+        }
+    }
+
+    let latest = applied.iter().map(|applied| applied.version).max().unwrap_or(0);
+    let pending: Vec<Migration> = migrations
+        .into_iter()
+        .filter(|migration| !applied.iter().any(|applied| applied.version == migration.version))
+        .collect();
+    if let Some(migration) = pending.iter().find(|migration| migration.version < latest) {
+        return Err(
+            format!(
+                "migration {} is pending but {:04} is already applied, apply it by hand or renumber it",
+                migration,
+                latest
+            ).into()
+        );
+    }
+
+    Ok(pending)
+}
+
+// Emails then names, as the server used to on every start before migrations
+fn normalize_stored_text(transaction: &mut Transaction) -> Result<(), Box<dyn Error>> {
+    normalize_stored_emails(transaction)?;
+    normalize_stored_unicode(transaction)?;
+    Ok(())
+}
+
+// Emails used to be stored as sent. Lowercase the existing ones and enforce
+// case-insensitive uniqueness, unless some rows only differ by case: those
+// have to be merged by hand first, so report them and stop.
+fn normalize_stored_emails(transaction: &mut Transaction) -> Result<(), Box<dyn Error>> {
+    let duplicates = transaction.query(
+        tables::sql(
+            "SELECT lower(trim(email)), array_agg(id ORDER BY id) FROM {users}
+            GROUP BY lower(trim(email)) HAVING count(*) > 1"
+        ),
+        &[]
+    )?;
+
+    if !duplicates.is_empty() {
+        let report: Vec<String> = duplicates
+            .iter()
+            .map(|row| {
+                let email: String = row.get(0);
+                let ids: Vec<i32> = row.get(1);
+                format!("{} (ids {:?})", email, ids)
+            })
+            .collect();
+        return Err(
+            format!(
+                "some emails are used by several users once case is ignored, merge them before upgrading: {}",
+                report.join(", ")
+            ).into()
+        );
+    }
+
+    transaction.execute(
+        tables::sql("UPDATE {users} SET email = lower(trim(email)) WHERE email <> lower(trim(email))"),
+        &[]
+    )?;
+    transaction.batch_execute(tables::sql(CREATE_EMAIL_LOWER_INDEX_QUERY))?;
+    Ok(())
+}
+
+// Bring the names and emails stored before they were normalized to NFC. Rows that
+// only differ by encoding end up identical, which likely means the same person was
+// created twice: they are reported, not merged.
+fn normalize_stored_unicode(transaction: &mut Transaction) -> Result<(), postgres::Error> {
+    // ASCII text is always in NFC
+    let rows = transaction.query(
+        tables::sql("SELECT id, name, email FROM {users} WHERE name ~ '[^[:ascii:]]' OR email ~ '[^[:ascii:]]'"),
+        &[]
+    )?;
+
+    let mut changed = Vec::new();
+    for row in &rows {
+        let id: i32 = row.get(0);
+        let name: String = row.get(1);
+        let email: String = row.get(2);
+        let normalized_name = validation::normalize_name(&name);
+        let normalized_email = validation::normalize_email(&email);
+        if normalized_name == name && normalized_email == email {
+            continue;
+        }
+        changed.push(id);
+
+        // Checked first, a unique violation would abort the whole migration
+        let taken = transaction
+            .query_opt(
+                tables::sql("SELECT 1 FROM {users} WHERE lower(email) = lower($1) AND id <> $2"),
+                &[&normalized_email, &id]
+            )?
+            .is_some();
+        if taken {
+            let email = redact::email(&email);
+            log::warn!("User {} keeps email {:?}: its normalized form belongs to another user", id, email);
+            transaction.execute(tables::sql("UPDATE {users} SET name = $2 WHERE id = $1"), &[&id, &normalized_name])?;
+        } else {
+            transaction.execute(
+                tables::sql("UPDATE {users} SET name = $2, email = $3 WHERE id = $1"),
+                &[&id, &normalized_name, &normalized_email]
+            )?;
+        }
+    }
+
+    if changed.is_empty() {
+        return Ok(());
+    }
+    let duplicates = transaction.query(
+        tables::sql(
+            "SELECT name, array_agg(id ORDER BY id) FROM {users} GROUP BY name
+            HAVING count(*) > 1 AND bool_or(id = ANY($1))"
+        ),
+        &[&changed]
+    )?;
+    for row in &duplicates {
+        let name: String = row.get(0);
+        let ids: Vec<i32> = row.get(1);
+        log::warn!("Users {:?} share the name {:?} once normalized, they may be duplicates", ids, redact::name(&name));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The table names of migrations/ are between braces since the prefix became
+    // configurable, databases migrated before then must still match
+    #[test]
+    fn checksums_are_over_the_sql_without_a_prefix() {
+        let first = all().unwrap().into_iter().next().unwrap();
+        assert_eq!(first.checksum().unwrap(), "ce693fc2b00dc6b4bd14b2c94197db2cf0fae9788df902b7defbab7c51fde3cf");
+    }
+}
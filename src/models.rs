@@ -0,0 +1,46 @@
+use chrono::{ DateTime, Utc };
+
+use crate::validation;
+
+// Define the model in a struct
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub id: Option<i32>,
+    pub name: String,
+    pub email: String,
+    #[serde(default, skip_deserializing)]
+    pub anonymized: bool,
+    // The email with its domain in Unicode, when it is stored in punycode
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub email_display: Option<String>,
+    // When the user proved the email is theirs, with EMAIL_VERIFICATION=true
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub verified_at: Option<DateTime<Utc>>,
+}
+
+impl User {
+    pub(crate) fn new(id: Option<i32>, name: String, email: String, anonymized: bool) -> Self {
+        let email_display = validation::display_email(&email);
+        User { id, name, email, anonymized, email_display, verified_at: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn what_the_api_sets_isnt_read_from_the_requests() {
+        let user = User::new(Some(1), "Ada".to_owned(), "ada@xn--bcher-kva.example".to_owned(), false);
+        assert_eq!(user.email_display.as_deref(), Some("ada@bücher.example"));
+
+        let sent = json!({
+            "name": "Ada", "email": "ada@example.com", "anonymized": true, "verified_at": "2024-01-01T00:00:00Z"
+        });
+        let user: User = serde_json::from_value(sent).unwrap();
+        assert!(!user.anonymized && user.verified_at.is_none());
+        let written = json!({ "id": null, "name": "Ada", "email": "ada@example.com", "anonymized": false });
+        assert_eq!(serde_json::to_value(&user).unwrap(), written);
+    }
+}
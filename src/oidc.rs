@@ -0,0 +1,581 @@
+use base64::engine::general_purpose::{ STANDARD, URL_SAFE_NO_PAD };
+use base64::Engine;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{ Jwk, JwkSet };
+use jsonwebtoken::{ Algorithm, DecodingKey, Validation };
+use native_tls::TlsConnector;
+use serde::de::DeserializeOwned;
+use sha2::{ Digest, Sha256 };
+use std::collections::HashMap;
+use std::io::{ self, Read, Write };
+use std::net::{ TcpStream, ToSocketAddrs };
+use std::sync::{ Mutex, OnceLock };
+use std::time::{ Duration, Instant };
+
+use crate::config::{ self, number_from_env };
+use crate::repository::{ Account, UserRepository };
+use crate::secret::Secret;
+use crate::validation::NewUser;
+use crate::verification::problem;
+use crate::errors::repository_error_response;
+use crate::http::{
+    decode_query_value, get_header, get_query_param, with_header, INTERNAL_SERVER_ERROR, NOT_IMPLEMENTED,
+    UNPROCESSABLE_ENTITY
+};
+use crate::{ api_keys, auth, clock, jwt, sessions, tenant, validation };
+use crate::trace_context;
+
+const DEFAULT_SCOPES: &str = "openid email profile";
+const DEFAULT_LOGIN_TIMEOUT_SECS: u64 = 10 * 60;
+
+// The logins started and not finished yet that are kept at most, for those of
+// the clients that never come back to be forgotten rather than pile up
+const MAX_PENDING: usize = 10_000;
+
+// For connecting to the provider, and for each read and write
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The most of a response of the provider that is read
+const MAX_RESPONSE_BYTES: u64 = 1024 * 1024;
+
+// How far apart the clocks of the provider and the server may be
+const LEEWAY_SECS: u64 = 60;
+
+// The state of the login the browser started
+const STATE_COOKIE: &str = "oidc_state";
+
+// Those of a public key, so that a key of the provider is never taken for the
+// secret of an HMAC
+const ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+const FOUND: &str = "HTTP/1.1 302 FOUND\r\n\r\n";
+const BAD_REQUEST_PROBLEM: &str = "HTTP/1.1 400 BAD REQUEST\r\nContent-Type: application/problem+json\r\n\r\n";
+const FORBIDDEN_PROBLEM: &str = "HTTP/1.1 403 FORBIDDEN\r\nContent-Type: application/problem+json\r\n\r\n";
+const BAD_GATEWAY_PROBLEM: &str = "HTTP/1.1 502 BAD GATEWAY\r\nContent-Type: application/problem+json\r\n\r\n";
+const UNAVAILABLE_PROBLEM: &str =
+    "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Type: application/problem+json\r\nRetry-After: 5\r\n\r\n";
+
+static CONFIG: OnceLock<Option<OidcConfig>> = OnceLock::new();
+
+// With OIDC_ISSUER_URL, the users log in with the OpenID Connect provider there
+// rather than with a password: GET /auth/login sends the browser to the provider,
+// with a state and a PKCE challenge, and the provider sends it back to
+// OIDC_REDIRECT_URI, which is GET /auth/callback of the server. The code it
+// brings is traded for an ID token, whose email, once the provider verified it,
+// is that of the user who logs in, created on their first login. The login goes
+// on with a session with SESSIONS=true, or else for a token, as POST /login. The
+// endpoints and the keys of the provider are discovered from its
+// /.well-known/openid-configuration, and the keys fetched again when a token is
+// signed with one of its new ones. A login has OIDC_LOGIN_TIMEOUT_SECS to come
+// back, to the instance that started it.
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<Secret<String>>,
+    pub redirect_uri: String,
+    // Separated by spaces, openid among them
+    pub scopes: String,
+    pub login_timeout: Duration,
+    // Discovered when first needed
+    provider: Mutex<Option<Provider>>,
+    keys: Mutex<Option<JwkSet>>,
+    // By their state
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+// What the discovery document says of the provider
+#[derive(Clone, Deserialize)]
+struct Provider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+// A login sent to the provider, until it comes back
+struct Pending {
+    nonce: String,
+    verifier: String,
+    tenant: String,
+    started: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+// The claims of an ID token that are read, those of the spec checked on the way
+#[derive(Deserialize)]
+struct IdClaims {
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    name: Option<String>,
+    preferred_username: Option<String>,
+}
+
+impl OidcConfig {
+    // None without a provider
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(issuer) = config::var("OIDC_ISSUER_URL") else {
+            return Ok(None);
+        };
+        parse_url(&issuer).map_err(|e| format!("OIDC_ISSUER_URL {}", e))?;
+        let (Ok(client_id), Ok(redirect_uri)) = (config::var("OIDC_CLIENT_ID"), config::var("OIDC_REDIRECT_URI")) else {
+            return Err("OIDC_ISSUER_URL needs OIDC_CLIENT_ID and OIDC_REDIRECT_URI".to_owned());
+        };
+        let scopes = config::var("OIDC_SCOPES").unwrap_or_else(|_| DEFAULT_SCOPES.to_owned());
+        if !scopes.split_whitespace().any(|scope| scope == "openid") {
+            return Err(format!("OIDC_SCOPES must have openid among them, got {:?}", scopes));
+        }
+        let login_timeout = number_from_env("OIDC_LOGIN_TIMEOUT_SECS", DEFAULT_LOGIN_TIMEOUT_SECS)?;
+        if login_timeout == 0 {
+            return Err("OIDC_LOGIN_TIMEOUT_SECS must be at least 1".to_owned());
+        }
+        Ok(Some(OidcConfig {
+            issuer,
+            client_id,
+            client_secret: config::var("OIDC_CLIENT_SECRET").ok().filter(|secret| !secret.is_empty()).map(Secret::new),
+            redirect_uri,
+            scopes,
+            login_timeout: Duration::from_secs(login_timeout),
+            provider: Mutex::new(None),
+            keys: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    // The endpoints of the provider, discovered once. The issuer must be the one
+    // configured, for the ID tokens to be checked against it.
+    fn provider(&self) -> Result<Provider, String> {
+        let mut provider = self.provider.lock().unwrap();
+        if let Some(provider) = &*provider {
+            return Ok(provider.clone());
+        }
+        let url = format!("{}/.well-known/openid-configuration", self.issuer.trim_end_matches('/'));
+        let discovered: Provider = fetch_json("GET", &url, "", "")?;
+        if discovered.issuer != self.issuer {
+            return Err(format!("{} is the discovery of {:?}, not of OIDC_ISSUER_URL", url, discovered.issuer));
+        }
+        *provider = Some(discovered.clone());
+        Ok(discovered)
+    }
+
+    // The key of the provider with the id, the only one without an id. The keys
+    // are fetched again for an id that isn't among them, the provider may have
+    // rotated its keys since.
+    fn key(&self, provider: &Provider, kid: Option<&str>) -> Result<Option<Jwk>, String> {
+        let find = |keys: &JwkSet| match kid {
+            Some(kid) => keys.find(kid).cloned(),
+            None => Some(&keys.keys).filter(|keys| keys.len() == 1).map(|keys| keys[0].clone()),
+        };
+        let mut keys = self.keys.lock().unwrap();
+        if let Some(key) = keys.as_ref().and_then(find) {
+            return Ok(Some(key));
+        }
+        let fetched: JwkSet = fetch_json("GET", &provider.jwks_uri, "", "")?;
+        let key = find(&fetched);
+        *keys = Some(fetched);
+        Ok(key)
+    }
+
+    // Keeps the login until it comes back, or refuses it with too many pending
+    fn start(&self, state: String, login: Pending) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, pending| login.started.duration_since(pending.started) < self.login_timeout);
+        if pending.len() >= MAX_PENDING {
+            return false;
+        }
+        pending.insert(state, login);
+        true
+    }
+
+    // The ID token the code is traded for, None when the provider refuses it
+    fn exchange(&self, provider: &Provider, code: &str, verifier: &str) -> Result<Option<String>, String> {
+        let mut fields = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_uri),
+            ("code_verifier", verifier),
+        ];
+        let mut headers = "Content-Type: application/x-www-form-urlencoded\r\n".to_owned();
+        match &self.client_secret {
+            Some(secret) => {
+                let credentials = format!("{}:{}", encode(&self.client_id), encode(secret.expose()));
+                headers.push_str(&format!("Authorization: Basic {}\r\n", STANDARD.encode(credentials)));
+            }
+            None => fields.push(("client_id", &self.client_id)),
+        }
+        let url = &provider.token_endpoint;
+        match fetch("POST", url, &headers, &form(&fields))? {
+            (200, body) => match serde_json::from_str::<TokenResponse>(&body) {
+                Ok(tokens) => Ok(Some(tokens.id_token)),
+                Err(e) => Err(format!("POST {} answered no ID token: {}", url, e)),
+            },
+            (400 | 401, _) => Ok(None),
+            (status, _) => Err(format!("POST {} answered {}", url, status)),
+        }
+    }
+
+    // The claims of the ID token, if it is the provider's, for this server and this
+    // login, and still valid
+    fn verify(&self, provider: &Provider, id_token: &str, nonce: &str) -> Result<IdClaims, (String, String)> {
+        let invalid = |detail: &str| auth::unauthorized_problem("invalid_id_token", detail);
+        let header = jsonwebtoken::decode_header(id_token).map_err(|_| invalid("The ID token isn't a JWT"))?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(invalid(&format!("The ID token is signed with {:?}, not with a public key", header.alg)));
+        }
+        let key = match self.key(provider, header.kid.as_deref()) {
+            Ok(Some(key)) => key,
+            Ok(None) => {
+                return Err(invalid("The ID token is signed with none of the keys of the provider"));
+            }
+            Err(e) => {
+                return Err(provider_failed(&e));
+            }
+        };
+        let key = DecodingKey::from_jwk(&key).map_err(|_| invalid("The key of the ID token isn't supported"))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&provider.issuer]);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        validation.leeway = LEEWAY_SECS;
+        let claims = match jsonwebtoken::decode::<IdClaims>(id_token, &key, &validation) {
+            Ok(data) => data.claims,
+            Err(e) if *e.kind() == ErrorKind::ExpiredSignature => {
+                return Err(auth::unauthorized_problem("id_token_expired", "The ID token has expired"));
+            }
+            Err(e) => {
+                return Err(invalid(&format!("The ID token isn't valid: {}", e)));
+            }
+        };
+        // Only the provider knows it, the token wasn't replayed from another login
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(auth::unauthorized_problem("invalid_nonce", "The ID token isn't the one of this login"));
+        }
+        Ok(claims)
+    }
+}
+
+pub fn init(config: Option<OidcConfig>) {
+    CONFIG.set(config).ok();
+}
+
+fn only_with_oidc() -> Result<&'static OidcConfig, (String, String)> {
+    let config = CONFIG.get_or_init(|| None).as_ref();
+    config.ok_or_else(|| (NOT_IMPLEMENTED.to_owned(), "Only available with OIDC_ISSUER_URL".to_owned()))
+}
+
+fn provider_failed(error: &str) -> (String, String) {
+    log::error!("The OIDC provider failed: {}", error);
+    let detail = "The identity provider can't be reached or answered in a way it shouldn't";
+    problem(BAD_GATEWAY_PROBLEM, 502, "Bad Gateway", "provider_unavailable", detail)
+}
+
+// Percent-encoded, all but the unreserved characters of RFC 3986
+pub(crate) fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// As a query string, or the body of a form
+pub(crate) fn form(fields: &[(&str, &str)]) -> String {
+    let fields: Vec<String> =
+        fields.iter().map(|(name, value)| format!("{}={}", encode(name), encode(value))).collect();
+    fields.join("&")
+}
+
+pub struct Url<'a> {
+    tls: bool,
+    // With the port if there was one, for Host
+    authority: &'a str,
+    host: &'a str,
+    port: u16,
+    target: String,
+}
+
+pub fn parse_url(url: &str) -> Result<Url<'_>, String> {
+    let (tls, rest) = match url.split_once("://") {
+        Some(("https", rest)) => (true, rest),
+        Some(("http", rest)) => (false, rest),
+        _ => return Err(format!("must be an http or https URL, got {:?}", url)),
+    };
+    let (authority, target) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    let (host, port) = match authority.rsplit_once(':').filter(|(_, port)| !port.contains(']')) {
+        Some((host, port)) => (host, port.parse().map_err(|_| format!("has an invalid port, got {:?}", url))?),
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(format!("must have a host, got {:?}", url));
+    }
+    let target = if target.starts_with('/') { target.to_owned() } else { format!("/{}", target) };
+    Ok(Url { tls, authority, host, port, target })
+}
+
+// The status and the body of the response of the provider, or of the collector of
+// the spans, over TLS for https. Sent from a span, it has its traceparent unless
+// headers has one.
+pub fn fetch(method: &str, url: &str, headers: &str, body: &str) -> Result<(u16, String), String> {
+    let parsed = parse_url(url)?;
+    exchange(&parsed, method, headers, body).map_err(|e| format!("{} {} failed: {}", method, url, e))
+}
+
+fn fetch_json<T: DeserializeOwned>(method: &str, url: &str, headers: &str, body: &str) -> Result<T, String> {
+    match fetch(method, url, headers, body)? {
+        (200, body) => serde_json::from_str(&body).map_err(|e| format!("{} {} answered {}", method, url, e)),
+        (status, _) => Err(format!("{} {} answered {}", method, url, status)),
+    }
+}
+
+fn exchange(url: &Url, method: &str, headers: &str, body: &str) -> io::Result<(u16, String)> {
+    let address = (url.host, url.port).to_socket_addrs()?.next().ok_or_else(|| io::Error::other("no address"))?;
+    let stream = TcpStream::connect_timeout(&address, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    let length = if body.is_empty() { String::new() } else { format!("Content-Length: {}\r\n", body.len()) };
+    let traced = match headers.to_ascii_lowercase().contains("traceparent:") {
+        true => String::new(),
+        false => trace_context::outbound(),
+    };
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n{}{}{}\r\n{}",
+        method,
+        url.target,
+        url.authority,
+        headers,
+        traced,
+        length,
+        body
+    );
+    let response = if url.tls {
+        let connector = TlsConnector::new().map_err(io::Error::other)?;
+        round_trip(connector.connect(url.host, stream).map_err(io::Error::other)?, &request)?
+    } else {
+        round_trip(stream, &request)?
+    };
+
+    let broken = || io::Error::other("the response broke off");
+    let end = response.windows(4).position(|window| window == b"\r\n\r\n").ok_or_else(broken)?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let status = head.split_whitespace().nth(1).and_then(|status| status.parse().ok()).ok_or_else(broken)?;
+    let body = &response[end + 4..];
+    let encoding = get_header(&head, "Transfer-Encoding");
+    let chunked = encoding.is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+    let body = if chunked { dechunked(body).ok_or_else(broken)? } else { body.to_vec() };
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn round_trip(mut stream: impl Read + Write, request: &str) -> io::Result<Vec<u8>> {
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE_BYTES).read_to_end(&mut response)?;
+    Ok(response)
+}
+
+// The body of a chunked response, None if it broke off before the last chunk or
+// has a size that isn't one
+pub(crate) fn dechunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let line = body.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line]).ok()?.split(';').next()?.trim();
+        let size = Some(size).filter(|size| size.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        let size = usize::from_str_radix(size?, 16).ok()?;
+        if size == 0 {
+            return Some(decoded);
+        }
+        let end = (line + 2).checked_add(size)?;
+        decoded.extend_from_slice(body.get(line + 2..end)?);
+        body = body.get(end.checked_add(2)?..)?;
+    }
+}
+
+// GET /auth/login, a redirect to the provider, the state in a cookie as well for
+// the callback to be sure the browser that comes back is the one that left
+pub fn handle_login_request() -> (String, String) {
+    let config = match only_with_oidc() {
+        Ok(config) => config,
+        Err(response) => {
+            return response;
+        }
+    };
+    let provider = match config.provider() {
+        Ok(provider) => provider,
+        Err(e) => {
+            return provider_failed(&e);
+        }
+    };
+    let (state, nonce, verifier) = (api_keys::generate(), api_keys::generate(), api_keys::generate());
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    let login = Pending { nonce: nonce.clone(), verifier, tenant: tenant::current(), started: clock::instant() };
+    if !config.start(state.clone(), login) {
+        let detail = "Too many logins are waiting for the identity provider, try again later";
+        return problem(UNAVAILABLE_PROBLEM, 503, "Service Unavailable", "too_many_logins", detail);
+    }
+
+    let query = form(&[
+        ("response_type", "code"),
+        ("client_id", &config.client_id),
+        ("redirect_uri", &config.redirect_uri),
+        ("scope", &config.scopes),
+        ("state", &state),
+        ("nonce", &nonce),
+        ("code_challenge", &challenge),
+        ("code_challenge_method", "S256"),
+    ]);
+    let endpoint = &provider.authorization_endpoint;
+    let location = format!("{}{}{}", endpoint, if endpoint.contains('?') { '&' } else { '?' }, query);
+    let cookie = format!(
+        "Set-Cookie: {}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax; Secure",
+        STATE_COOKIE,
+        state,
+        config.login_timeout.as_secs()
+    );
+    (with_header(&with_header(FOUND, &format!("Location: {}", location)), &cookie), String::new())
+}
+
+// The account of the user with the email, or of the user created for it, named as
+// the provider has them
+fn provision(repository: &dyn UserRepository, claims: &IdClaims, email: &str) -> Result<Account, (String, String)> {
+    let email = validation::normalize_email(email);
+    match repository.credentials(&email) {
+        Ok(Some(account)) => return Ok(account),
+        Ok(None) => {}
+        Err(e) => {
+            return Err(repository_error_response(e, "Error logging in"));
+        }
+    }
+    let local_part = email.split('@').next().unwrap_or_default().to_owned();
+    let name = claims.name.clone().or_else(|| claims.preferred_username.clone()).unwrap_or(local_part);
+    let mut user = NewUser { name, email, password: None, password_hash: None };
+    let errors = validation::validate_fields(&mut user);
+    if !errors.is_empty() {
+        return Err((UNPROCESSABLE_ENTITY.to_owned(), validation::errors_body(&errors)));
+    }
+    if let Err(e) = repository.create(&user, false, None) {
+        return Err(repository_error_response(e, "Error creating the user"));
+    }
+    match repository.credentials(&user.email) {
+        Ok(Some(account)) => Ok(account),
+        Ok(None) => Err((INTERNAL_SERVER_ERROR.to_owned(), "The user created can't be found".to_owned())),
+        Err(e) => Err(repository_error_response(e, "Error logging in")),
+    }
+}
+
+// GET /auth/callback?code=...&state=..., where the provider sends the browser
+// back: the code is traded for the ID token of the user, who logs in
+pub fn handle_callback_request(repository: &dyn UserRepository, request: &str) -> (String, String) {
+    let config = match only_with_oidc() {
+        Ok(config) => config,
+        Err(response) => {
+            return response;
+        }
+    };
+    let param = |name| get_query_param(request, name).map(decode_query_value);
+    if let Some(error) = param("error") {
+        let detail = param("error_description").unwrap_or(error);
+        return auth::unauthorized_problem("login_refused", &format!("The identity provider refused: {}", detail));
+    }
+    // A state is good once, whatever comes of it
+    let state = param("state").unwrap_or_default();
+    let login = config.pending.lock().unwrap().remove(&state);
+    let ours = |login: &Pending| {
+        sessions::cookie(request, STATE_COOKIE) == Some(state.as_str())
+            && login.tenant == tenant::current()
+            && clock::elapsed(login.started) < config.login_timeout
+    };
+    let Some(login) = login.filter(ours) else {
+        let detail = "The login wasn't started by this browser, or took too long, start it again";
+        return problem(BAD_REQUEST_PROBLEM, 400, "Bad Request", "invalid_state", detail);
+    };
+    let Some(code) = param("code") else {
+        return problem(BAD_REQUEST_PROBLEM, 400, "Bad Request", "missing_code", "The identity provider sent no code");
+    };
+
+    let provider = match config.provider() {
+        Ok(provider) => provider,
+        Err(e) => {
+            return provider_failed(&e);
+        }
+    };
+    let id_token = match config.exchange(&provider, &code, &login.verifier) {
+        Ok(Some(id_token)) => id_token,
+        Ok(None) => {
+            return auth::unauthorized_problem("invalid_code", "The identity provider refused the code");
+        }
+        Err(e) => {
+            return provider_failed(&e);
+        }
+    };
+    let claims = match config.verify(&provider, &id_token, &login.nonce) {
+        Ok(claims) => claims,
+        Err(response) => {
+            return response;
+        }
+    };
+    // The users are linked by their email, which has to be theirs
+    let Some(email) = claims.email.as_deref().filter(|_| claims.email_verified == Some(true)) else {
+        let detail = "The identity provider hasn't verified the email of the user";
+        return problem(FORBIDDEN_PROBLEM, 403, "Forbidden", "email_not_verified", detail);
+    };
+    let account = match provision(repository, &claims, email) {
+        Ok(account) => account,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    let (status_line, body) = match (auth::sessions(), auth::jwt()) {
+        (Some(config), _) => sessions::started(config, account.id),
+        (None, Some(config)) => jwt::logged_in(config, &account),
+        (None, None) => (NOT_IMPLEMENTED.to_owned(), "Needs SESSIONS=true or JWT_SECRET".to_owned()),
+    };
+    let cleared = format!("Set-Cookie: {}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax; Secure", STATE_COOKIE);
+    (with_header(&status_line, &cleared), body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_split_and_forms_encoded() {
+        let url = parse_url("https://id.example.com:8443/realms/app?x=1").unwrap();
+        assert_eq!((url.tls, url.authority, url.host, url.port), (true, "id.example.com:8443", "id.example.com", 8443));
+        assert_eq!(url.target, "/realms/app?x=1");
+        let url = parse_url("http://[::1]").unwrap();
+        assert_eq!((url.host, url.port, url.target.as_str()), ("::1", 80, "/"));
+        assert!(parse_url("ftp://id.example.com").is_err());
+        assert!(parse_url("https://:443/").is_err());
+
+        let query = form(&[("scope", "openid email"), ("redirect_uri", "https://a.b/c?d=e")]);
+        assert_eq!(query, "scope=openid%20email&redirect_uri=https%3A%2F%2Fa.b%2Fc%3Fd%3De");
+    }
+
+    #[test]
+    fn chunked_bodies_are_put_back_together() {
+        assert_eq!(dechunked(b"4\r\n{\"a\"\r\n3;ext=1\r\n:1}\r\n0\r\n\r\n").as_deref(), Some(&b"{\"a\":1}"[..]));
+        assert_eq!(dechunked(b"4\r\n{\"a\"\r\n"), None);
+        assert_eq!(dechunked(b"zz\r\n"), None);
+        assert_eq!(dechunked(b"+4\r\n{\"a\"\r\n0\r\n\r\n"), None);
+        assert_eq!(dechunked(b"ffffffffffffffff\r\n{\"a\"\r\n"), None);
+    }
+}
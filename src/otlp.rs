@@ -0,0 +1,266 @@
+use serde_json::{ json, Value };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::mpsc::{ self, Receiver, RecvTimeoutError, SyncSender, TrySendError };
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use crate::oidc;
+use crate::config::{ self, number_from_env };
+use crate::trace_context::{ hex, Trace };
+
+const DEFAULT_MAX_QUEUE_SIZE: u64 = 2048;
+const DEFAULT_MAX_EXPORT_BATCH_SIZE: u64 = 512;
+const DEFAULT_SCHEDULE_DELAY_MS: u64 = 5000;
+
+// How long the spans still queued have to be exported when shutting down
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+// With OTEL_EXPORTER_OTLP_ENDPOINT, the spans are sent to its /v1/traces as
+// OTLP/HTTP JSON, with the service.name of OTEL_SERVICE_NAME and the
+// deployment.environment of APP_ENV. They are queued, up to OTEL_BSP_MAX_QUEUE_SIZE
+// of them, the others dropped, and sent by a thread of their own in batches of up
+// to OTEL_BSP_MAX_EXPORT_BATCH_SIZE at least every OTEL_BSP_SCHEDULE_DELAY
+// milliseconds, so a collector that is slow or gone never holds up a request.
+// OTEL_TRACES_SAMPLER picks the traces sent: always_on, always_off, traceidratio
+// with the ratio of OTEL_TRACES_SAMPLER_ARG, or parentbased_ of one of them, the
+// default being parentbased_always_on, for the spans to follow their parent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OtlpConfig {
+    pub url: String,
+    pub service_name: String,
+    pub environment: String,
+    pub sampler: Sampler,
+    pub max_queue_size: usize,
+    pub max_export_batch_size: usize,
+    pub schedule_delay: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sampler {
+    pub parent_based: bool,
+    // The share of the traces sampled, of those without a parent when parent_based
+    pub ratio: f64,
+}
+
+impl OtlpConfig {
+    // None without an endpoint
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(endpoint) = config::var("OTEL_EXPORTER_OTLP_ENDPOINT") else {
+            return Ok(None);
+        };
+        let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        oidc::parse_url(&url).map_err(|e| format!("OTEL_EXPORTER_OTLP_ENDPOINT {}", e))?;
+        let service_name = config::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_owned());
+        let environment = config::var("APP_ENV").unwrap_or_else(|_| "development".to_owned());
+
+        let sampler = config::var("OTEL_TRACES_SAMPLER").unwrap_or_else(|_| "parentbased_always_on".to_owned());
+        let (parent_based, root) = match sampler.strip_prefix("parentbased_") {
+            Some(root) => (true, root),
+            None => (false, sampler.as_str()),
+        };
+        let ratio = match root {
+            "always_on" => 1.0,
+            "always_off" => 0.0,
+            "traceidratio" => match config::var("OTEL_TRACES_SAMPLER_ARG") {
+                Ok(arg) => arg.trim().parse().ok().filter(|ratio| (0.0..=1.0).contains(ratio)).ok_or_else(|| {
+                    format!("OTEL_TRACES_SAMPLER_ARG must be a ratio between 0 and 1, got {:?}", arg)
+                })?,
+                Err(_) => 1.0,
+            },
+            _ => {
+                return Err(format!(
+                    "OTEL_TRACES_SAMPLER must be always_on, always_off, traceidratio or parentbased_ one of them, \
+                     got {:?}",
+                    sampler
+                ))
+            }
+        };
+
+        let max_queue_size = number_from_env("OTEL_BSP_MAX_QUEUE_SIZE", DEFAULT_MAX_QUEUE_SIZE)?.max(1) as usize;
+        let max_export_batch_size =
+            number_from_env("OTEL_BSP_MAX_EXPORT_BATCH_SIZE", DEFAULT_MAX_EXPORT_BATCH_SIZE)?.max(1) as usize;
+        let schedule_delay = number_from_env("OTEL_BSP_SCHEDULE_DELAY", DEFAULT_SCHEDULE_DELAY_MS)?;
+        let schedule_delay = Duration::from_millis(schedule_delay);
+        Ok(Some(OtlpConfig {
+            url,
+            service_name,
+            environment,
+            sampler: Sampler { parent_based, ratio },
+            max_queue_size,
+            max_export_batch_size,
+            schedule_delay,
+        }))
+    }
+}
+
+impl Sampler {
+    // Whether the spans of the trace are sent, parent_sampled being what was
+    // decided for the parent of the span, if it has one
+    fn sampled(&self, trace_id: &[u8; 16], parent_sampled: Option<bool>) -> bool {
+        match parent_sampled {
+            Some(sampled) if self.parent_based => sampled,
+            // The same for every span of the trace, as the other services decide
+            _ => {
+                let low = u64::from_be_bytes(trace_id[8..].try_into().unwrap());
+                (low as f64) < self.ratio * u64::MAX as f64 || self.ratio >= 1.0
+            }
+        }
+    }
+}
+
+struct Exporter {
+    sampler: Sampler,
+    queue: SyncSender<Message>,
+    dropped: AtomicU64,
+}
+
+enum Message {
+    Span(Value),
+    // Send what is queued, then say so
+    Flush(mpsc::Sender<()>),
+}
+
+pub fn init(config: Option<OtlpConfig>) {
+    let Some(config) = config else {
+        return;
+    };
+    let (queue, queued) = mpsc::sync_channel(config.max_queue_size);
+    let exporter = Exporter { sampler: config.sampler, queue, dropped: AtomicU64::new(0) };
+    if EXPORTER.set(exporter).is_ok() {
+        thread::spawn(move || export(&config, queued));
+    }
+}
+
+// Whether the spans of a new one of the trace are exported, parent_sampled being
+// what was decided for its parent. Without exporting, what the parent says is
+// passed on.
+pub fn sampled(trace_id: &[u8; 16], parent_sampled: Option<bool>) -> bool {
+    match EXPORTER.get() {
+        Some(exporter) => exporter.sampler.sampled(trace_id, parent_sampled),
+        None => parent_sampled.unwrap_or(false),
+    }
+}
+
+// Queue the span, closed now, unless its trace isn't sampled or the queue is full
+pub fn ended(trace: &Trace, name: &str, fields: &[(&'static str, String)]) {
+    let Some(exporter) = EXPORTER.get().filter(|_| trace.sampled) else {
+        return;
+    };
+    let attributes: Vec<Value> =
+        fields.iter().map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } })).collect();
+    let mut span = json!({
+        "traceId": hex(&trace.trace_id),
+        "spanId": hex(&trace.span_id),
+        "name": name,
+        // Server for the requests, internal for what is done answering them
+        "kind": if trace.parent_span_id.is_none() || trace.remote_parent { 2 } else { 1 },
+        "startTimeUnixNano": nanos(trace.started),
+        "endTimeUnixNano": nanos(SystemTime::now()),
+        "attributes": attributes,
+    });
+    if let Some(parent_span_id) = trace.parent_span_id {
+        span["parentSpanId"] = hex(&parent_span_id).into();
+    }
+    if let Err(TrySendError::Full(_)) = exporter.queue.try_send(Message::Span(span)) {
+        exporter.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// The spans dropped for a full queue, None unless exporting
+pub fn dropped() -> Option<u64> {
+    EXPORTER.get().map(|exporter| exporter.dropped.load(Ordering::Relaxed))
+}
+
+// Send the spans still queued, waiting for them a while at most
+pub fn flush() {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    let (flushed, done) = mpsc::channel();
+    if exporter.queue.send(Message::Flush(flushed)).is_ok() && done.recv_timeout(FLUSH_TIMEOUT).is_err() {
+        log::warn!("Gave up exporting the spans still queued after {:?}", FLUSH_TIMEOUT);
+    }
+}
+
+fn export(config: &OtlpConfig, queued: Receiver<Message>) {
+    let mut batch = Vec::new();
+    let mut deadline = Instant::now() + config.schedule_delay;
+    loop {
+        let (send, flushed) = match queued.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Message::Span(span)) => {
+                batch.push(span);
+                (batch.len() >= config.max_export_batch_size, None)
+            }
+            Ok(Message::Flush(flushed)) => (true, Some(flushed)),
+            Err(RecvTimeoutError::Timeout) => (true, None),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if send {
+            if !batch.is_empty() {
+                send_batch(config, &batch);
+                batch.clear();
+            }
+            deadline = Instant::now() + config.schedule_delay;
+        }
+        if let Some(flushed) = flushed {
+            flushed.send(()).ok();
+        }
+    }
+}
+
+fn send_batch(config: &OtlpConfig, spans: &[Value]) {
+    let attribute = |key: &str, value: &str| json!({ "key": key, "value": { "stringValue": value } });
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", &config.service_name),
+                    attribute("deployment.environment", &config.environment),
+                    attribute("service.version", env!("CARGO_PKG_VERSION")),
+                ],
+            },
+            "scopeSpans": [{ "scope": { "name": env!("CARGO_PKG_NAME") }, "spans": spans }],
+        }],
+    });
+    let headers = "Content-Type: application/json\r\n";
+    match oidc::fetch("POST", &config.url, headers, &body.to_string()) {
+        Ok((200..=299, _)) => {}
+        Ok((status, _)) => log::warn!("Can't export {} spans to {}: answered {}", spans.len(), config.url, status),
+        Err(e) => log::warn!("Can't export {} spans: {}", spans.len(), e),
+    }
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ratios_sample_by_trace_id() {
+        let sampler = Sampler { parent_based: false, ratio: 0.5 };
+        let trace_id = |low: u64| {
+            let mut trace_id = [0xff; 16];
+            trace_id[8..].copy_from_slice(&low.to_be_bytes());
+            trace_id
+        };
+        assert!(sampler.sampled(&trace_id(0), None));
+        assert!(sampler.sampled(&trace_id(u64::MAX / 2 - 1024), Some(false)));
+        assert!(!sampler.sampled(&trace_id(u64::MAX / 2 + 1024), Some(true)));
+
+        let parent_based = Sampler { parent_based: true, ..sampler };
+        assert!(parent_based.sampled(&trace_id(u64::MAX), Some(true)));
+        assert!(!parent_based.sampled(&trace_id(0), Some(false)));
+        assert!(!parent_based.sampled(&trace_id(u64::MAX), None));
+
+        let always = Sampler { parent_based: false, ratio: 1.0 };
+        assert!(always.sampled(&trace_id(u64::MAX), None));
+        let never = Sampler { parent_based: false, ratio: 0.0 };
+        assert!(!never.sampled(&trace_id(0), None));
+    }
+}
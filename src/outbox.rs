@@ -0,0 +1,264 @@
+use chrono::{ DateTime, Utc };
+use postgres::{ Client, GenericClient, Row };
+use postgres::Error as PostgresError;
+use serde_json::Value;
+use std::thread;
+use std::time::Duration;
+
+use crate::db::connector;
+use crate::{ auth, encryption, redact, tables, tenant };
+
+// NOTIFY channel every recorded event is announced on, prefixed like the tables
+// so that instances sharing a database only hear their own
+const USER_CHANGES_CHANNEL: &str = "user_changes";
+
+// How long the dispatcher sleeps when there is nothing left to deliver
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Maximum number of events claimed (and returned by GET /events) at once
+const BATCH_SIZE: i64 = 100;
+
+const FETCH_SINCE_QUERY: &str =
+    "SELECT id, event_type, payload, created_at, delivered_at, actor FROM {events_outbox}
+    WHERE id > $1 AND tenant_id = $3 ORDER BY id LIMIT $2";
+
+// The batch of the dispatcher
+const CLAIM_QUERY: &str =
+    "SELECT id, event_type, payload FROM {events_outbox}
+    WHERE delivered_at IS NULL ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED";
+
+#[derive(Serialize, Debug)]
+pub struct Event {
+    pub id: i64,
+    pub event_type: String,
+    pub payload: Value,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    // The name of the API key of the request
+    pub actor: Option<String>,
+}
+
+// Start receiving the notifications of the recorded events on this connection
+pub fn listen(client: &mut Client) -> Result<(), PostgresError> {
+    client.batch_execute(&format!("LISTEN {}", tables::identifier(&tables::prefixed(USER_CHANGES_CHANNEL))))
+}
+
+// Payload of the notification sent on USER_CHANGES_CHANNEL
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EventNotice {
+    pub id: i64,
+    #[serde(default = "tenant::default_tenant")]
+    pub tenant_id: String,
+    pub event_type: String,
+    pub payload: Value,
+}
+
+// Record an event and announce it to listeners. Call it on the transaction of the
+// mutation it describes, so the event exists (and the NOTIFY fires) if and only if
+// the mutation was committed. Its actor is the key of the request being handled.
+// The email of the payload is stored encrypted with ENCRYPTION_KEY, the notice
+// carries it as it is.
+pub fn enqueue(
+    client: &mut impl GenericClient,
+    tenant: &str,
+    event_type: &str,
+    payload: &Value
+) -> Result<i64, PostgresError> {
+    let row = client.query_one(
+        tables::sql(
+            "INSERT INTO {events_outbox} (event_type, payload, tenant_id, actor) VALUES ($1, $2, $3, $4) RETURNING id"
+        ),
+        &[&event_type, &encryption::seal_payload(payload), &tenant, &auth::current()]
+    )?;
+    let id = row.get(0);
+
+    let notice = EventNotice {
+        id,
+        tenant_id: tenant.to_owned(),
+        event_type: event_type.to_owned(),
+        payload: payload.clone(),
+    };
+    client.execute(
+        "SELECT pg_notify($1, $2)",
+        &[&tables::prefixed(USER_CHANGES_CHANNEL), &serde_json::to_string(&notice).unwrap()]
+    )?;
+
+    Ok(id)
+}
+
+// Overwrite the personal data a user's past events carry with their scrubbed
+// values, the email in the clear: the made up ones aren't encrypted
+pub fn scrub_user_events(
+    client: &mut impl GenericClient,
+    tenant: &str,
+    user_id: i32,
+    name: &str,
+    email: &str
+) -> Result<u64, PostgresError> {
+    client.execute(
+        tables::sql(
+            "UPDATE {events_outbox}
+            SET payload = (payload - 'encrypted_email') || jsonb_build_object('name', $2::text, 'email', $3::text)
+            WHERE payload->'id' = to_jsonb($1::int) AND (payload ? 'email' OR payload ? 'encrypted_email')
+                AND tenant_id = $4"
+        ),
+        &[&user_id, &name, &email, &tenant]
+    )
+}
+
+// The events of the tenant with an id greater than `since_id`, oldest first
+pub fn fetch_since(client: &mut impl GenericClient, tenant: &str, since_id: i64) -> Result<Vec<Event>, PostgresError> {
+    let events = client
+        .query(tables::sql(FETCH_SINCE_QUERY), &[&since_id, &BATCH_SIZE, &tenant])?
+        .iter()
+        .map(event_from_row)
+        .collect();
+
+    Ok(events)
+}
+
+// The whole history of a user, oldest first
+pub fn fetch_for_user(
+    client: &mut impl GenericClient,
+    tenant: &str,
+    user_id: i32
+) -> Result<Vec<Event>, PostgresError> {
+    let events = client
+        .query(
+            tables::sql(
+                "SELECT id, event_type, payload, created_at, delivered_at, actor FROM {events_outbox}
+                WHERE payload->'id' = to_jsonb($1::int) AND tenant_id = $2 ORDER BY id"
+            ),
+            &[&user_id, &tenant]
+        )?
+        .iter()
+        .map(event_from_row)
+        .collect();
+
+    Ok(events)
+}
+
+fn event_from_row(row: &Row) -> Event {
+    Event {
+        id: row.get(0),
+        event_type: row.get(1),
+        payload: encryption::open_payload(row.get(2)),
+        created_at: row.get(3),
+        delivered_at: row.get(4),
+        actor: row.get(5),
+    }
+}
+
+// Background loop delivering undelivered events. Safe to run on several instances
+// at once: rows are claimed with SKIP LOCKED so each event is sent by one of them.
+pub fn run_dispatcher() {
+    let mut client: Option<Client> = None;
+
+    loop {
+        let connection = match client.as_mut() {
+            Some(connection) if !connection.is_closed() => connection,
+            _ =>
+                match connector().connect() {
+                    Ok(connection) => client.insert(connection),
+                    Err(e) => {
+                        log::error!("Outbox dispatcher failed to connect: {}", e);
+                        thread::sleep(POLL_INTERVAL);
+                        continue;
+                    }
+                }
+        };
+
+        match dispatch_all(connection) {
+            Ok(0) => thread::sleep(POLL_INTERVAL),
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("Outbox dispatch error: {}", e);
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+// A batch of the main schema, and one of the schema of every tenant when they have
+// their own
+fn dispatch_all(client: &mut Client) -> Result<usize, PostgresError> {
+    let mut dispatched = dispatch_batch(client)?;
+    for schema in tenant::provisioned_schemas(client)? {
+        tenant::use_schema(client, &schema)?;
+        let result = dispatch_batch(client);
+        tables::reset_search_path(client)?;
+        dispatched += result?;
+    }
+    Ok(dispatched)
+}
+
+// Claim a batch of undelivered events, deliver them in order and mark them delivered.
+// If the process dies before the commit, the rows stay undelivered and are picked up again.
+fn dispatch_batch(client: &mut Client) -> Result<usize, PostgresError> {
+    let mut transaction = client.transaction()?;
+
+    let rows = transaction.query(tables::sql(CLAIM_QUERY), &[&BATCH_SIZE])?;
+
+    for row in &rows {
+        let id: i64 = row.get(0);
+        let event_type: String = row.get(1);
+        let payload = encryption::open_payload(row.get(2));
+
+        deliver(id, &event_type, &payload);
+        transaction.execute(tables::sql("UPDATE {events_outbox} SET delivered_at = now() WHERE id = $1"), &[&id])?;
+    }
+
+    transaction.commit()?;
+    Ok(rows.len())
+}
+
+// There are no webhooks yet, so delivering an event means logging it, without
+// what the logs mustn't show of the users
+fn deliver(id: i64, event_type: &str, payload: &Value) {
+    log::info!("Event {} {}: {}", id, event_type, redact::json(payload));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ migrations, pool };
+
+    // On a large outbox, mostly delivered, neither query reads it all
+    #[test]
+    fn the_events_of_a_tenant_and_the_undelivered_ones_are_indexed() {
+        let Some(pool) = pool::test_pool() else {
+            return;
+        };
+        let mut client = pool.get().unwrap();
+        client
+            .batch_execute(
+                "DROP SCHEMA IF EXISTS explain_outbox_test CASCADE; CREATE SCHEMA explain_outbox_test;
+                SET search_path TO explain_outbox_test"
+            )
+            .unwrap();
+        migrations::apply(&mut client).unwrap();
+        client
+            .batch_execute(
+                "INSERT INTO events_outbox (event_type, payload, tenant_id, delivered_at)
+                SELECT 'user.created', jsonb_build_object('id', i), 'tenant-' || i % 200,
+                    CASE WHEN i < 39900 THEN now() END
+                FROM generate_series(1, 40000) i;
+                ANALYZE events_outbox"
+            )
+            .unwrap();
+
+        let explain = |client: &mut pool::PooledClient, query: &str, params: &[&(dyn postgres::types::ToSql + Sync)]| {
+            let rows = client.query(&format!("EXPLAIN {}", query), params).unwrap();
+            rows.iter().map(|row| row.get::<_, String>(0)).collect::<Vec<_>>().join("\n")
+        };
+        let plans = [
+            explain(&mut client, tables::sql(FETCH_SINCE_QUERY), &[&30000_i64, &BATCH_SIZE, &"tenant-7"]),
+            explain(&mut client, tables::sql(CLAIM_QUERY), &[&BATCH_SIZE]),
+        ];
+        for plan in &plans {
+            assert!(!plan.contains("Seq Scan"), "{}", plan);
+        }
+
+        client.batch_execute("RESET search_path; DROP SCHEMA explain_outbox_test CASCADE").unwrap();
+    }
+}
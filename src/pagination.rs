@@ -0,0 +1,171 @@
+use std::sync::RwLock;
+
+use crate::config::number_from_env;
+use crate::errors::ApiError;
+use crate::http::{ decode_query_value, get_path, get_query, get_query_param, with_header };
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const DEFAULT_MAX_PAGE_SIZE: usize = 1000;
+const DEFAULT_EXPORT_MAX_PAGE_SIZE: usize = 10_000;
+
+static CONFIG: RwLock<PaginationConfig> = RwLock::new(PaginationConfig {
+    default: DEFAULT_PAGE_SIZE,
+    max: DEFAULT_MAX_PAGE_SIZE,
+    export_max: DEFAULT_EXPORT_MAX_PAGE_SIZE,
+});
+
+// PAGE_SIZE_DEFAULT, how many users GET /users answers, and how many events GET
+// /admin/auth-events, without a ?limit=, and PAGE_SIZE_MAX the most they answer
+// whatever the limit. The history of GET /users/{id}/export is all of it unless
+// limited, up to EXPORT_PAGE_SIZE_MAX events. A limit over the maximum is lowered
+// to it rather than refused, the response saying so with X-Limit-Clamped: true.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PaginationConfig {
+    pub default: usize,
+    pub max: usize,
+    pub export_max: usize,
+}
+
+impl PaginationConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let default = number_from_env("PAGE_SIZE_DEFAULT", DEFAULT_PAGE_SIZE as u64)? as usize;
+        let max = number_from_env("PAGE_SIZE_MAX", DEFAULT_MAX_PAGE_SIZE as u64)? as usize;
+        let export_max = number_from_env("EXPORT_PAGE_SIZE_MAX", DEFAULT_EXPORT_MAX_PAGE_SIZE as u64)? as usize;
+        if default == 0 || max == 0 || export_max == 0 {
+            return Err("PAGE_SIZE_DEFAULT, PAGE_SIZE_MAX and EXPORT_PAGE_SIZE_MAX must be at least 1".to_owned());
+        }
+        if default > max {
+            return Err(format!("PAGE_SIZE_DEFAULT must not be over PAGE_SIZE_MAX, got {} and {}", default, max));
+        }
+        Ok(PaginationConfig { default, max, export_max })
+    }
+}
+
+// At startup, and again when a SIGHUP changed it
+pub fn init(config: PaginationConfig) {
+    *CONFIG.write().unwrap() = config;
+}
+
+fn config() -> PaginationConfig {
+    *CONFIG.read().unwrap()
+}
+
+// How many a response holds at most, and whether that is less than was asked for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub value: usize,
+    pub clamped: bool,
+}
+
+// The ?limit= of a list, or else PAGE_SIZE_DEFAULT, at most PAGE_SIZE_MAX
+pub fn limit(request: &str) -> Result<Limit, ApiError> {
+    let config = config();
+    limit_of(request, config.default, config.max)
+}
+
+// The ?limit= of the history of an export, or else all of it, at most
+// EXPORT_PAGE_SIZE_MAX events
+pub fn export_limit(request: &str) -> Result<Limit, ApiError> {
+    let max = config().export_max;
+    limit_of(request, max, max)
+}
+
+fn limit_of(request: &str, default: usize, max: usize) -> Result<Limit, ApiError> {
+    let Some(limit) = get_query_param(request, "limit") else {
+        return Ok(Limit { value: default, clamped: false });
+    };
+    match limit.parse::<i64>() {
+        Ok(limit) if limit >= 1 => {
+            let asked = usize::try_from(limit).unwrap_or(usize::MAX);
+            Ok(Limit { value: asked.min(max), clamped: asked > max })
+        }
+        _ => Err(ApiError::BadRequest(format!("limit must be a number of at least 1, got {:?}", limit))),
+    }
+}
+
+// The limit argument of a list of GraphQL, as a ?limit= would be
+pub fn limit_argument(limit: Option<i64>) -> Result<Limit, String> {
+    let config = config();
+    match limit {
+        None => Ok(Limit { value: config.default, clamped: false }),
+        Some(limit) if limit >= 1 => {
+            let asked = usize::try_from(limit).unwrap_or(usize::MAX);
+            Ok(Limit { value: asked.min(config.max), clamped: asked > config.max })
+        }
+        Some(limit) => Err(format!("limit must be at least 1, got {}", limit)),
+    }
+}
+
+// The ?after_id= of a page, the id of the last of the page before, None for the
+// first page
+pub fn after_id(request: &str) -> Result<Option<i64>, ApiError> {
+    match get_query_param(request, "after_id").map(|id| (id, id.parse::<i64>())) {
+        None => Ok(None),
+        Some((_, Ok(id))) if id >= 0 => Ok(Some(id)),
+        Some((id, _)) => Err(ApiError::BadRequest(format!("after_id must be an id, got {:?}", id))),
+    }
+}
+
+// The head of a page of the response to request: X-Limit-Clamped when the limit
+// was lowered, and a Link to the first page and, when there is one, to the page
+// after the id next, both with the limit the response has and the other
+// parameters of the request
+pub fn linked(status_line: &str, request: &str, limit: Limit, next: Option<i64>) -> String {
+    let mut links = vec![format!("<{}>; rel=\"first\"", url(request, limit, None))];
+    links.extend(next.map(|next| format!("<{}>; rel=\"next\"", url(request, limit, Some(next)))));
+    let status_line = with_header(status_line, &format!("Link: {}", links.join(", ")));
+    match limit.clamped {
+        true => with_header(&status_line, "X-Limit-Clamped: true"),
+        false => status_line,
+    }
+}
+
+fn url(request: &str, limit: Limit, after_id: Option<i64>) -> String {
+    let mut params: Vec<String> = get_query(request)
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .filter(|param| {
+            let name = decode_query_value(param.split('=').next().unwrap_or_default());
+            !matches!(name.as_str(), "limit" | "after_id")
+        })
+        .map(str::to_owned)
+        .collect();
+    params.push(format!("limit={}", limit.value));
+    params.extend(after_id.map(|id| format!("after_id={}", id)));
+    format!("{}?{}", get_path(request), params.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(target: &str) -> String {
+        format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target)
+    }
+
+    #[test]
+    fn a_limit_over_the_maximum_is_lowered_to_it() {
+        let limit = |target: &str| limit_of(&request(target), 100, 1000).ok();
+        assert_eq!(limit("/users"), Some(Limit { value: 100, clamped: false }));
+        assert_eq!(limit("/users?limit=1000"), Some(Limit { value: 1000, clamped: false }));
+        assert_eq!(limit("/users?limit=5000"), Some(Limit { value: 1000, clamped: true }));
+        assert_eq!(limit(&format!("/users?limit={}", i64::MAX)), Some(Limit { value: 1000, clamped: true }));
+        for limit in ["0", "-1", "ten", ""] {
+            let refused = limit_of(&request(&format!("/users?limit={}", limit)), 100, 1000);
+            assert!(matches!(refused, Err(ApiError::BadRequest(_))), "{}", limit);
+        }
+    }
+
+    #[test]
+    fn the_links_keep_the_other_parameters() {
+        let page = request("/users?name_contains=ada&limit=5000&after_id=7");
+        let head = linked("HTTP/1.1 200 OK\r\n\r\n", &page, Limit { value: 1000, clamped: true }, Some(1007));
+        let expected = "HTTP/1.1 200 OK\r\n\
+            Link: </users?name_contains=ada&limit=1000>; rel=\"first\", \
+            </users?name_contains=ada&limit=1000&after_id=1007>; rel=\"next\"\r\n\
+            X-Limit-Clamped: true\r\n\r\n";
+        assert_eq!(head, expected);
+        assert_eq!(after_id(&page).ok(), Some(Some(7)));
+        assert!(after_id(&request("/users?after_id=-1")).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use argon2::password_hash::{ PasswordHash, PasswordHasher, PasswordVerifier, SaltString };
+use argon2::{ Algorithm, Argon2, Params, Version };
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+use crate::repository::{ Account, RepositoryError, UserRepository };
+use crate::errors::{ repository_error_response, user_not_found, with_causes };
+use crate::http::{ get_body, BAD_REQUEST, OK_RESPONSE, UNPROCESSABLE_ENTITY };
+use crate::{ audit, auth, locale, lockout, read_only, redact, validation };
+
+const SALT_LENGTH: usize = 16;
+
+const FORBIDDEN_PROBLEM: &str = "HTTP/1.1 403 FORBIDDEN\r\nContent-Type: application/problem+json\r\n\r\n";
+
+// A password of a request, never written out, not even in Debug
+#[derive(Deserialize)]
+#[serde(transparent)]
+pub struct Password(String);
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Password(..)")
+    }
+}
+
+impl Password {
+    pub fn hash(&self) -> String {
+        hash(&self.0)
+    }
+
+    pub fn verify(&self, hash: Option<&str>) -> bool {
+        verify(&self.0, hash)
+    }
+
+    pub fn characters(&self) -> usize {
+        self.0.chars().count()
+    }
+}
+
+// Argon2id with the parameters of the argon2 crate, 19 MiB and 2 passes, and a
+// salt of its own, in the PHC string format that carries both
+pub fn hash(password: &str) -> String {
+    let mut salt = [0; SALT_LENGTH];
+    File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut salt))
+        .expect("the system has random bytes");
+    let salt = SaltString::encode_b64(&salt).expect("the salt is long enough");
+    Argon2::default().hash_password(password.as_bytes(), &salt).expect("the parameters are valid").to_string()
+}
+
+// Whether the password is the one of the hash. Without a hash, one of another
+// password is checked so that it takes as long, and the users can't be told
+// apart from how long logging in takes.
+pub fn verify(password: &str, hash: Option<&str>) -> bool {
+    static DECOY: OnceLock<String> = OnceLock::new();
+
+    let (hash, decoy) = match hash {
+        Some(hash) => (hash, false),
+        None => (DECOY.get_or_init(|| self::hash("not anyone's password")).as_str(), true),
+    };
+    let Ok(hash) = PasswordHash::new(hash) else {
+        log::error!("A stored password hash isn't in the PHC string format");
+        return false;
+    };
+    Argon2::default().verify_password(password.as_bytes(), &hash).is_ok() && !decoy
+}
+
+// Whether the hash was made with other parameters than hash would use now, and is
+// worth making again the next time the password is at hand
+pub fn needs_rehash(hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return true;
+    };
+    let current = Params::default();
+    let same = hash.algorithm == Algorithm::Argon2id.ident() &&
+        hash.version == Some(Version::V0x13.into()) &&
+        Params::try_from(&hash).is_ok_and(|params| {
+            (params.m_cost(), params.t_cost(), params.p_cost()) ==
+                (current.m_cost(), current.t_cost(), current.p_cost())
+        });
+    !same
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Login {
+    email: String,
+    password: Password,
+}
+
+// The account of the user whose email and password the body of POST /login or
+// POST /session holds, or the response turning the request away, the 429 of a
+// lockout included
+pub fn log_in(
+    repository: &dyn UserRepository,
+    request: &str,
+    client: Option<IpAddr>
+) -> Result<Account, (String, String)> {
+    let login = serde_json::from_str::<Login>(get_body(request))
+        .map_err(|e| (BAD_REQUEST.to_owned(), format!("Invalid request body: {}", e)))?;
+
+    let email = validation::normalize_email(login.email.trim());
+    audit::attempted(&redact::email(&email));
+    // Before the user is looked for, so that it is the same whether there is one
+    if let Some(retry_after) = lockout::retry_after(&email, client) {
+        audit::refused("locked_out");
+        return Err(lockout::locked_response(retry_after));
+    }
+    let account = match repository.credentials(&email) {
+        Ok(account) => account,
+        Err(e) => {
+            return Err(repository_error_response(e, "Error logging in"));
+        }
+    };
+    // Checked even without a user, which takes as long
+    let hash = account.as_ref().and_then(|account| account.password_hash.as_deref());
+    let verified = login.password.verify(hash);
+    let Some(account) = account.filter(|_| verified) else {
+        let locked = lockout::failed(&email, client);
+        let refused = auth::unauthorized_problem("invalid_credentials", "The email or the password isn't valid");
+        if locked {
+            audit::locked();
+        }
+        return Err(refused);
+    };
+    lockout::succeeded(&email);
+    audit::authenticated(&format!("user {}", account.id));
+    // The one time the password is at hand to bring its hash up to date
+    if account.password_hash.as_deref().is_some_and(needs_rehash) && !read_only::enabled() {
+        if let Err(e) = repository.set_password_hash(account.id, &login.password.hash()) {
+            log::error!("Error rehashing the password of user {}: {}", account.id, with_causes(&e));
+        }
+    }
+    Ok(account)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PasswordChange {
+    current_password: Password,
+    new_password: Password,
+}
+
+// PUT /users/{id}/password with {"current_password": ..., "new_password": ...}. A
+// user without a password has no current one to give, and can't get one here.
+pub fn handle_change_password_request(repository: &dyn UserRepository, request: &str, id: i32) -> (String, String) {
+    let change = match serde_json::from_str::<PasswordChange>(get_body(request)) {
+        Ok(change) => change,
+        Err(e) => {
+            return (BAD_REQUEST.to_owned(), validation::body_error(get_body(request), &e));
+        }
+    };
+    if let Some(error) = validation::validate_password("new_password", &change.new_password) {
+        return (UNPROCESSABLE_ENTITY.to_owned(), validation::errors_body(&[error]));
+    }
+
+    let hash = match repository.password_hash(id) {
+        Ok(hash) => hash,
+        Err(RepositoryError::NotFound) => {
+            return user_not_found(id);
+        }
+        Err(e) => {
+            return repository_error_response(e, "Error changing the password");
+        }
+    };
+    if !change.current_password.verify(hash.as_deref()) {
+        let body = serde_json::json!({
+            "type": "about:blank",
+            "title": locale::title(403, "Forbidden"),
+            "status": 403,
+            "detail": locale::text("invalid_current_password", &[]),
+            "code": "invalid_current_password",
+        });
+        return (FORBIDDEN_PROBLEM.to_owned(), body.to_string());
+    }
+
+    match repository.set_password_hash(id, &change.new_password.hash()) {
+        Ok(()) => (OK_RESPONSE.to_owned(), serde_json::json!({ "id": id }).to_string()),
+        Err(RepositoryError::NotFound) => user_not_found(id),
+        Err(e) => repository_error_response(e, "Error changing the password"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_are_salted_and_verified() {
+        let (first, second) = (hash("correct horse"), hash("correct horse"));
+        assert!(first.starts_with("$argon2id$"), "{}", first);
+        assert_ne!(first, second);
+        assert!(verify("correct horse", Some(&first)));
+        assert!(!verify("battery staple", Some(&first)));
+        assert!(!verify("not anyone's password", None));
+        assert!(!verify("correct horse", Some("plaintext")));
+        assert_eq!(format!("{:?}", Password("correct horse".to_owned())), "Password(..)");
+    }
+
+    #[test]
+    fn hashes_of_other_parameters_are_rehashed() {
+        assert!(!needs_rehash(&hash("correct horse")));
+        let params = Params::new(Params::MIN_M_COST, 1, 1, None).unwrap();
+        let salt = SaltString::encode_b64(&[0; SALT_LENGTH]).unwrap();
+        let weaker = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(b"correct horse", &salt)
+            .unwrap()
+            .to_string();
+        assert!(verify("correct horse", Some(&weaker)));
+        assert!(needs_rehash(&weaker));
+        assert!(needs_rehash("plaintext"));
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{ Mutex, OnceLock };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use crate::mail::{ self, Sender };
+use crate::password::Password;
+use crate::config::{ self, number_from_env };
+use crate::pool::TransactionOptions;
+use crate::repository::{ RepositoryError, UserRepository };
+use crate::verification::problem;
+use crate::db::pool;
+use crate::errors::{ repository_error_response, with_causes };
+use crate::http::{ get_body, BAD_REQUEST, NOT_IMPLEMENTED, OK_RESPONSE, UNPROCESSABLE_ENTITY };
+use crate::{ api_keys, clock, locale, outbox, tables, tenant, validation };
+
+const DEFAULT_LIFETIME_SECS: u64 = 30 * 60;
+const DEFAULT_URL: &str = "http://localhost:8080/reset-password?token={token}";
+const DEFAULT_MAX_REQUESTS: u64 = 3;
+const DEFAULT_MAX_REQUESTS_PER_IP: u64 = 10;
+const DEFAULT_WINDOW_SECS: u64 = 60 * 60;
+
+// How often the windows that are over are forgotten
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+const ACCEPTED: &str = "HTTP/1.1 202 ACCEPTED\r\nContent-Type: application/json\r\n\r\n";
+const BAD_REQUEST_PROBLEM: &str = "HTTP/1.1 400 BAD REQUEST\r\nContent-Type: application/problem+json\r\n\r\n";
+const GONE_PROBLEM: &str = "HTTP/1.1 410 GONE\r\nContent-Type: application/problem+json\r\n\r\n";
+
+static CONFIG: OnceLock<Option<PasswordResetConfig>> = OnceLock::new();
+
+// With PASSWORD_RESET=true, POST /password-reset/request mails the user with the
+// email the link of PASSWORD_RESET_URL, with a token in place of {token}, and
+// POST /password-reset/confirm sets their new password with that token, once,
+// within PASSWORD_RESET_TOKEN_LIFETIME_SECS. An email may be asked for
+// PASSWORD_RESET_MAX_REQUESTS times per PASSWORD_RESET_WINDOW_SECS, and a client
+// address may ask PASSWORD_RESET_MAX_REQUESTS_PER_IP times, counted by each
+// instance. The links go out through the sender of MAIL_SENDER. Postgres only.
+pub struct PasswordResetConfig {
+    pub lifetime: u64,
+    pub url: String,
+    pub max_requests: u64,
+    pub max_requests_per_ip: u64,
+    pub window: Duration,
+    pub sender: Box<dyn Sender>,
+    requests: Mutex<Requests>,
+}
+
+// The requests of each email and each address since their window started
+struct Requests {
+    // By tenant and normalized email
+    by_email: HashMap<(String, String), (u64, Instant)>,
+    by_ip: HashMap<IpAddr, (u64, Instant)>,
+    cleaned_up: Instant,
+}
+
+impl Requests {
+    fn new() -> Self {
+        Requests { by_email: HashMap::new(), by_ip: HashMap::new(), cleaned_up: clock::instant() }
+    }
+}
+
+impl PasswordResetConfig {
+    // None without password resets
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match config::var("PASSWORD_RESET").as_deref() {
+            Ok("false") | Err(_) => return Ok(None),
+            Ok("true") => {}
+            Ok(value) => return Err(format!("PASSWORD_RESET must be true or false, got {:?}", value)),
+        }
+        let lifetime = number_from_env("PASSWORD_RESET_TOKEN_LIFETIME_SECS", DEFAULT_LIFETIME_SECS)?;
+        let window = number_from_env("PASSWORD_RESET_WINDOW_SECS", DEFAULT_WINDOW_SECS)?;
+        if lifetime == 0 || window == 0 {
+            let error = "PASSWORD_RESET_TOKEN_LIFETIME_SECS and PASSWORD_RESET_WINDOW_SECS must be at least 1";
+            return Err(error.to_owned());
+        }
+        let url = config::var("PASSWORD_RESET_URL").unwrap_or_else(|_| DEFAULT_URL.to_owned());
+        if !url.contains("{token}") {
+            return Err(format!("PASSWORD_RESET_URL must have {{token}} in it, got {:?}", url));
+        }
+        Ok(Some(PasswordResetConfig {
+            lifetime,
+            url,
+            max_requests: number_from_env("PASSWORD_RESET_MAX_REQUESTS", DEFAULT_MAX_REQUESTS)?,
+            max_requests_per_ip: number_from_env("PASSWORD_RESET_MAX_REQUESTS_PER_IP", DEFAULT_MAX_REQUESTS_PER_IP)?,
+            window: Duration::from_secs(window),
+            sender: mail::sender_from_env()?,
+            requests: Mutex::new(Requests::new()),
+        }))
+    }
+
+    // Counts one more request of the email and the client, or how long until they
+    // may ask again if either is out of requests
+    fn refused(&self, email: (String, String), client: Option<IpAddr>, now: Instant) -> Option<Duration> {
+        let window = self.window;
+        let mut requests = self.requests.lock().unwrap();
+        if now.duration_since(requests.cleaned_up) >= CLEANUP_INTERVAL {
+            requests.by_email.retain(|_, (_, started)| now.duration_since(*started) < window);
+            requests.by_ip.retain(|_, (_, started)| now.duration_since(*started) < window);
+            requests.cleaned_up = now;
+        }
+
+        let Requests { by_email, by_ip, .. } = &mut *requests;
+        let mut counts = vec![(by_email.entry(email).or_insert((0, now)), self.max_requests)];
+        if let Some(client) = client {
+            counts.push((by_ip.entry(client).or_insert((0, now)), self.max_requests_per_ip));
+        }
+        for ((count, started), _) in counts.iter_mut() {
+            if now.duration_since(*started) >= window {
+                (*count, *started) = (0, now);
+            }
+        }
+        let refused = counts.iter().filter(|((count, _), max)| count >= max).map(|((_, started), _)| *started + window);
+        if let Some(until) = refused.max() {
+            return Some(until - now);
+        }
+        for ((count, _), _) in counts {
+            *count += 1;
+        }
+        None
+    }
+}
+
+pub fn init(config: Option<PasswordResetConfig>) {
+    CONFIG.set(config).ok();
+}
+
+fn only_with_resets() -> Result<&'static PasswordResetConfig, (String, String)> {
+    let config = CONFIG.get_or_init(|| None).as_ref();
+    config.ok_or_else(|| (NOT_IMPLEMENTED.to_owned(), "Only available with PASSWORD_RESET=true".to_owned()))
+}
+
+// Mail the user of the tenant entered a new token. The tokens they were sent
+// before and didn't use are no good after.
+fn issue(config: &PasswordResetConfig, user_id: i32, email: &str) -> Result<(), RepositoryError> {
+    let token = api_keys::generate();
+    let (token_hash, tenant, lifetime) = (api_keys::hash(&token), tenant::current(), config.lifetime as f64);
+    pool().get()?.with_transaction(TransactionOptions::default(), |transaction| {
+        transaction.execute(
+            tables::sql("DELETE FROM {password_reset_tokens} WHERE user_id = $1 AND used_at IS NULL"),
+            &[&user_id]
+        )?;
+        transaction.execute(
+            tables::sql(
+                "INSERT INTO {password_reset_tokens} (token_hash, user_id, tenant_id, expires_at)
+                VALUES ($1, $2, $3, now() + $4 * interval '1 second')"
+            ),
+            &[&token_hash, &user_id, &tenant, &lifetime]
+        )?;
+        Ok::<_, RepositoryError>(())
+    })?;
+
+    let text = format!("Open {} to choose a new password", config.url.replace("{token}", &token));
+    config.sender.send(email, "Reset your password", &text).map_err(|e| RepositoryError::Db(e.into()))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResetRequest {
+    email: String,
+}
+
+// POST /password-reset/request with {"email": ...}. It is a 202 whether or not the
+// email is a user's, and takes as long: the token is issued and sent afterwards.
+pub fn handle_request_reset_request(
+    repository: &dyn UserRepository,
+    request: &str,
+    client: Option<IpAddr>
+) -> (String, String) {
+    let config = match only_with_resets() {
+        Ok(config) => config,
+        Err(response) => {
+            return response;
+        }
+    };
+    let reset = match serde_json::from_str::<ResetRequest>(get_body(request)) {
+        Ok(reset) => reset,
+        Err(e) => {
+            return (BAD_REQUEST.to_owned(), validation::body_error(get_body(request), &e));
+        }
+    };
+    let (email, tenant) = (validation::normalize_email(reset.email.trim()), tenant::current());
+
+    if let Some(retry_after) = config.refused((tenant.clone(), email.clone()), client, clock::instant()) {
+        let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        let status_line = format!(
+            "HTTP/1.1 429 TOO MANY REQUESTS\r\nContent-Type: application/problem+json\r\nRetry-After: {}\r\n\r\n",
+            retry_after
+        );
+        let detail = format!("Too many password resets were asked for, retry in {} seconds", retry_after);
+        return problem(&status_line, 429, "Too Many Requests", "reset_rate_limited", &detail);
+    }
+    let account = match repository.credentials(&email) {
+        Ok(account) => account,
+        Err(e) => {
+            return repository_error_response(e, "Error resetting the password");
+        }
+    };
+    if let Some(account) = account {
+        thread::spawn(move || {
+            let _entered = tenant::enter(tenant);
+            if let Err(e) = issue(config, account.id, &email) {
+                log::error!("Error sending the password reset of user {}: {}", account.id, with_causes(&e));
+            }
+        });
+    }
+    let body = serde_json::json!({ "detail": "If the email is a user's, a link to reset the password is on its way" });
+    (ACCEPTED.to_owned(), body.to_string())
+}
+
+// What became of a token given to POST /password-reset/confirm
+enum Reset {
+    // The user, and how many sessions they had
+    User(i32, u64),
+    Expired,
+    Used,
+    Unknown,
+}
+
+// Sets the hash of the new password, uses the token up and ends the sessions and
+// the refresh tokens
+fn reset(token: &str, password_hash: &str) -> Result<Reset, RepositoryError> {
+    let (token_hash, tenant) = (api_keys::hash(token), tenant::current());
+    pool().get()?.with_transaction(TransactionOptions::default(), |transaction| {
+        let row = transaction.query_opt(
+            tables::sql(
+                "SELECT user_id, expires_at <= now(), used_at IS NOT NULL FROM {password_reset_tokens}
+                WHERE token_hash = $1 AND tenant_id = $2 FOR UPDATE"
+            ),
+            &[&token_hash, &tenant]
+        )?;
+        let user_id: i32 = match row {
+            None => return Ok(Reset::Unknown),
+            Some(row) if row.get::<_, bool>(2) => return Ok(Reset::Used),
+            Some(row) if row.get::<_, bool>(1) => return Ok(Reset::Expired),
+            Some(row) => row.get(0),
+        };
+
+        transaction.execute(
+            tables::sql("UPDATE {password_reset_tokens} SET used_at = now() WHERE token_hash = $1"),
+            &[&token_hash]
+        )?;
+        let updated = transaction.execute(
+            tables::sql("UPDATE {users} SET password_hash = $2 WHERE id = $1 AND anonymized_at IS NULL"),
+            &[&user_id, &password_hash]
+        )?;
+        if updated == 0 {
+            return Ok(Reset::Unknown);
+        }
+        let sessions = transaction.execute(tables::sql("DELETE FROM {sessions} WHERE user_id = $1"), &[&user_id])?;
+        transaction.execute(tables::sql("DELETE FROM {refresh_tokens} WHERE user_id = $1"), &[&user_id])?;
+        outbox::enqueue(transaction, &tenant, "user.password_reset", &serde_json::json!({ "id": user_id }))?;
+        Ok(Reset::User(user_id, sessions))
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResetConfirmation {
+    token: String,
+    new_password: Password,
+}
+
+// POST /password-reset/confirm with {"token": ..., "new_password": ...}. The
+// sessions and the refresh tokens of the user end, their access tokens last until
+// they expire.
+pub fn handle_confirm_reset_request(request: &str) -> (String, String) {
+    if let Err(response) = only_with_resets() {
+        return response;
+    }
+    let confirmation = match serde_json::from_str::<ResetConfirmation>(get_body(request)) {
+        Ok(confirmation) => confirmation,
+        Err(e) => {
+            return (BAD_REQUEST.to_owned(), validation::body_error(get_body(request), &e));
+        }
+    };
+    if let Some(error) = validation::validate_password("new_password", &confirmation.new_password) {
+        return (UNPROCESSABLE_ENTITY.to_owned(), validation::errors_body(&[error]));
+    }
+
+    match reset(confirmation.token.trim(), &confirmation.new_password.hash()) {
+        Ok(Reset::User(id, sessions)) => {
+            (OK_RESPONSE.to_owned(), serde_json::json!({ "id": id, "sessions_ended": sessions }).to_string())
+        }
+        Ok(Reset::Expired) => {
+            problem(GONE_PROBLEM, 410, "Gone", "token_expired", &locale::text("token_expired", &[]))
+        }
+        Ok(Reset::Used) => {
+            problem(BAD_REQUEST_PROBLEM, 400, "Bad Request", "token_used", &locale::text("token_used", &[]))
+        }
+        Ok(Reset::Unknown) => {
+            problem(BAD_REQUEST_PROBLEM, 400, "Bad Request", "invalid_token", "The token isn't a password reset token")
+        }
+        Err(e) => repository_error_response(e, "Error resetting the password"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mail::LogSender;
+
+    fn config() -> PasswordResetConfig {
+        PasswordResetConfig {
+            lifetime: 60,
+            url: DEFAULT_URL.to_owned(),
+            max_requests: 2,
+            max_requests_per_ip: 3,
+            window: Duration::from_secs(60),
+            sender: Box::new(LogSender),
+            requests: Mutex::new(Requests::new()),
+        }
+    }
+
+    fn email(email: &str) -> (String, String) {
+        ("default".to_owned(), email.to_owned())
+    }
+
+    #[test]
+    fn emails_and_addresses_are_limited_per_window() {
+        let (config, now) = (config(), Instant::now());
+        let client = Some("203.0.113.7".parse().unwrap());
+        assert_eq!(config.refused(email("ada@example.com"), client, now), None);
+        assert_eq!(config.refused(email("ada@example.com"), client, now), None);
+        let later = now + Duration::from_secs(20);
+        assert_eq!(config.refused(email("ada@example.com"), client, later), Some(Duration::from_secs(40)));
+        // The refused request didn't count for the address
+        assert_eq!(config.refused(email("alan@example.com"), client, later), None);
+        assert!(config.refused(email("grace@example.com"), client, later).is_some());
+        assert_eq!(config.refused(email("grace@example.com"), None, later), None);
+
+        let next_window = now + Duration::from_secs(60);
+        assert_eq!(config.refused(email("ada@example.com"), client, next_window), None);
+    }
+}
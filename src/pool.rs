@@ -0,0 +1,823 @@
+use postgres::error::SqlState;
+use postgres::types::ToSql;
+use postgres::{ CancelToken, Client, GenericClient, IsolationLevel, Row, Statement, Transaction };
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::ops::{ Deref, DerefMut };
+use std::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+use std::sync::{ Condvar, Mutex };
+use std::thread;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use crate::config::{ self, flag_from_env, number_from_env };
+use crate::profile::Profile;
+use crate::metrics::{ self, Histogram };
+use crate::tls::Connector;
+use crate::{ access_log, disconnect, statsd, tables, tenant };
+
+const DEFAULT_MIN_SIZE: usize = 1;
+pub const DEFAULT_MAX_SIZE: usize = 10;
+const DEFAULT_CHECKOUT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_STATEMENT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_EXPORT_STATEMENT_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_TRANSACTION_RETRIES: u64 = 3;
+const FIRST_TRANSACTION_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+const DEFAULT_CONNECT_RETRY_BUDGET: Duration = Duration::from_secs(30);
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// How long the liveness check of an idle connection may take
+const VALIDATION_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Sizes and timeout from DB_POOL_MIN_SIZE, DB_POOL_MAX_SIZE and
+// DB_POOL_CHECKOUT_TIMEOUT_MS. The statement_timeout of the connections comes
+// from DB_STATEMENT_TIMEOUT_MS, and from DB_EXPORT_STATEMENT_TIMEOUT_MS for the
+// exports; 0 means no limit, as in Postgres. A transaction that loses to a
+// concurrent one is retried DB_TRANSACTION_RETRIES times, after a random part of
+// the delay unless RETRY_JITTER=false, the default of the test profile.
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    pub min_size: usize,
+    pub max_size: usize,
+    pub checkout_timeout: Duration,
+    pub statement_timeout: Duration,
+    pub export_statement_timeout: Duration,
+    pub transaction_retries: u64,
+    pub jitter: bool,
+}
+
+impl PoolConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let config = PoolConfig {
+            min_size: number_from_env("DB_POOL_MIN_SIZE", DEFAULT_MIN_SIZE as u64)? as usize,
+            max_size: number_from_env("DB_POOL_MAX_SIZE", DEFAULT_MAX_SIZE as u64)? as usize,
+            checkout_timeout: Duration::from_millis(
+                number_from_env("DB_POOL_CHECKOUT_TIMEOUT_MS", DEFAULT_CHECKOUT_TIMEOUT.as_millis() as u64)?
+            ),
+            statement_timeout: Duration::from_millis(
+                number_from_env("DB_STATEMENT_TIMEOUT_MS", DEFAULT_STATEMENT_TIMEOUT.as_millis() as u64)?
+            ),
+            export_statement_timeout: Duration::from_millis(
+                number_from_env("DB_EXPORT_STATEMENT_TIMEOUT_MS", DEFAULT_EXPORT_STATEMENT_TIMEOUT.as_millis() as u64)?
+            ),
+            transaction_retries: number_from_env("DB_TRANSACTION_RETRIES", DEFAULT_TRANSACTION_RETRIES)?,
+            jitter: retry_jitter()?,
+        };
+
+        if config.max_size == 0 {
+            return Err("DB_POOL_MAX_SIZE must be at least 1".to_owned());
+        }
+        if config.min_size > config.max_size {
+            return Err(
+                format!(
+                    "DB_POOL_MIN_SIZE ({}) must not be greater than DB_POOL_MAX_SIZE ({})",
+                    config.min_size,
+                    config.max_size
+                )
+            );
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug)]
+pub enum PoolError {
+    // Every connection was in use for the whole checkout timeout
+    Timeout,
+    Connect(postgres::Error),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolError::Timeout => write!(f, "timed out waiting for a database connection"),
+            PoolError::Connect(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PoolError::Timeout => None,
+            PoolError::Connect(e) => Some(e),
+        }
+    }
+}
+
+// Whether the error is about the connection rather than the query: the server is
+// gone or terminated the session, so the same query may well work elsewhere
+pub fn is_connection_error(error: &postgres::Error) -> bool {
+    if error.is_closed() {
+        return true;
+    }
+    match error.code() {
+        Some(code) =>
+            *code == SqlState::ADMIN_SHUTDOWN ||
+                *code == SqlState::CRASH_SHUTDOWN ||
+                *code == SqlState::CANNOT_CONNECT_NOW,
+        None => std::error::Error::source(error).is_some_and(|source| source.is::<io::Error>()),
+    }
+}
+
+// Whether the transaction was aborted for the sake of a concurrent one: it was
+// chosen as the victim of a deadlock, or couldn't be serialized with the others
+pub fn is_transaction_conflict(error: &postgres::Error) -> bool {
+    error.code() == Some(&SqlState::T_R_SERIALIZATION_FAILURE) || error.code() == Some(&SqlState::T_R_DEADLOCK_DETECTED)
+}
+
+// Whether statement_timeout, or a cancel request, stopped the query
+pub fn is_query_canceled(error: &postgres::Error) -> bool {
+    error.code() == Some(&SqlState::QUERY_CANCELED)
+}
+
+// For the statements that may run longer than the pool's statement_timeout. Only
+// lasts until the end of the transaction.
+pub fn set_local_statement_timeout(transaction: &mut Transaction, timeout: Duration) -> Result<(), postgres::Error> {
+    transaction.batch_execute(&format!("SET LOCAL statement_timeout = {}", timeout.as_millis()))
+}
+
+// Whether a prepared statement no longer matches the table it reads, after
+// something like an ALTER TABLE changed a column's type
+pub fn is_stale_statement(error: &postgres::Error) -> bool {
+    error.code() == Some(&SqlState::FEATURE_NOT_SUPPORTED) &&
+        error.as_db_error().is_some_and(|e| e.message().contains("cached plan must not change result type"))
+}
+
+// How with_transaction starts its transaction. The default is the server's:
+// read committed, read write.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransactionOptions {
+    pub isolation_level: Option<IsolationLevel>,
+    pub read_only: bool,
+}
+
+// The errors the work of a transaction may return. The transaction helpers need
+// to tell which ones running the work again could get past.
+pub trait TransactionError: From<postgres::Error> + fmt::Display {
+    fn is_stale_statement(&self) -> bool;
+    fn is_transaction_conflict(&self) -> bool;
+}
+
+impl TransactionError for postgres::Error {
+    fn is_stale_statement(&self) -> bool {
+        is_stale_statement(self)
+    }
+
+    fn is_transaction_conflict(&self) -> bool {
+        is_transaction_conflict(self)
+    }
+}
+
+// Reusable connections for the request handlers. Connections are opened on demand
+// up to max_size; when they are all checked out, get waits for one to come back.
+pub struct Pool {
+    connector: Connector,
+    config: PoolConfig,
+    state: Mutex<State>,
+    returned: Condvar,
+    stats: PoolStats,
+}
+
+// What the pool has been doing, kept up to date on every checkout and return so
+// that it can be read without waiting on the pool. Counts are since startup.
+#[derive(Default)]
+pub struct PoolStats {
+    // Idle plus checked out connections
+    pub open: AtomicUsize,
+    pub in_use: AtomicUsize,
+    pub checkouts: AtomicU64,
+    // Checkouts given up after the checkout timeout
+    pub checkout_timeouts: AtomicU64,
+    pub checkout_wait: Histogram,
+    // Connections closed because they were found broken
+    pub discarded: AtomicU64,
+    // Transactions run again after a conflict
+    pub transaction_retries: AtomicU64,
+}
+
+struct State {
+    idle: Vec<Connection>,
+    // Idle plus checked out connections
+    open: usize,
+    // What cancels the query running on each checked out connection, by its id,
+    // and the watched request it was checked out for
+    checked_out: HashMap<u64, (CancelToken, Option<u64>)>,
+    last_id: u64,
+}
+
+impl Pool {
+    // Opens min_size connections right away, so a bad configuration shows at startup
+    pub fn new(connector: Connector, config: PoolConfig) -> Result<Self, postgres::Error> {
+        let mut idle = Vec::with_capacity(config.max_size);
+        for id in 1..=config.min_size as u64 {
+            idle.push(Connection::open(&connector, &config, id)?);
+        }
+
+        let stats = PoolStats::default();
+        stats.open.store(idle.len(), Ordering::Relaxed);
+        Ok(Pool {
+            connector,
+            state: Mutex::new(State {
+                open: idle.len(),
+                last_id: idle.len() as u64,
+                idle,
+                checked_out: HashMap::new(),
+            }),
+            returned: Condvar::new(),
+            stats,
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &PoolStats {
+        &self.stats
+    }
+
+    // Ask the server to stop whatever the connections checked out for a request
+    // are running. Their transactions fail, and the connections stay usable.
+    pub fn cancel_checked_out(&self, request: u64) {
+        let tokens: Vec<CancelToken> = self.state.lock().unwrap().checked_out
+            .values()
+            .filter(|(_, checked_out_for)| *checked_out_for == Some(request))
+            .map(|(token, _)| token.clone())
+            .collect();
+        for token in tokens {
+            if let Err(e) = self.connector.cancel(&token) {
+                log::error!("Error cancelling a query: {}", e);
+            }
+        }
+    }
+
+    pub fn get(&self) -> Result<PooledClient<'_>, PoolError> {
+        let start = Instant::now();
+        let client = self.wait_for_connection(start + self.config.checkout_timeout).and_then(Self::enter_schema);
+        match client {
+            Ok(_) => {
+                self.stats.checkouts.fetch_add(1, Ordering::Relaxed);
+                self.stats.checkout_wait.observe(start.elapsed());
+                statsd::timing("db_pool_checkout_wait", start.elapsed(), &[("pool", metrics::label(self))]);
+                access_log::pool_waited(start.elapsed());
+            }
+            Err(PoolError::Timeout) => {
+                self.stats.checkout_timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {}
+        }
+        client
+    }
+
+    fn wait_for_connection(&self, deadline: Instant) -> Result<PooledClient<'_>, PoolError> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(mut connection) = state.idle.pop() {
+                drop(state);
+                // The server may have closed it while it sat in the pool, and a
+                // terminated backend isn't noticed until the connection is used
+                if !connection.client.is_closed() && connection.client.is_valid(VALIDATION_TIMEOUT).is_ok() {
+                    return Ok(self.check_out(connection));
+                }
+                drop(connection);
+                self.stats.discarded.fetch_add(1, Ordering::Relaxed);
+                state = self.state.lock().unwrap();
+                state.open -= 1;
+                self.stats.open.fetch_sub(1, Ordering::Relaxed);
+                continue;
+            }
+
+            if state.open < self.config.max_size {
+                // Connect without holding the lock, the slot is reserved meanwhile
+                state.open += 1;
+                self.stats.open.fetch_add(1, Ordering::Relaxed);
+                state.last_id += 1;
+                let id = state.last_id;
+                drop(state);
+                return match Connection::open(&self.connector, &self.config, id) {
+                    Ok(connection) => Ok(self.check_out(connection)),
+                    Err(e) => {
+                        self.release_slot();
+                        Err(PoolError::Connect(e))
+                    }
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(PoolError::Timeout);
+            }
+            state = self.returned.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    // With a schema per tenant, the connection is for the schema of the tenant of
+    // the request until it is put back. Postgres plans the prepared statements
+    // again when the search_path changes, so they are safe to keep.
+    fn enter_schema(mut client: PooledClient<'_>) -> Result<PooledClient<'_>, PoolError> {
+        let Some(schema) = tenant::entered_schema() else {
+            return Ok(client);
+        };
+        match tenant::use_schema(&mut client, &schema) {
+            Ok(()) => {
+                client.connection.as_mut().unwrap().in_tenant_schema = true;
+                Ok(client)
+            }
+            Err(e) => {
+                client.discard();
+                Err(PoolError::Connect(e))
+            }
+        }
+    }
+
+    // Run a read-only operation, and run it once more on a fresh connection if the
+    // first one turns out to be dead. Only for operations that are safe to repeat.
+    pub fn read<T>(
+        &self,
+        mut operation: impl FnMut(&mut PooledClient) -> Result<T, postgres::Error>
+    ) -> Result<T, ReadError> {
+        let mut client = self.get().map_err(ReadError::Unavailable)?;
+        match operation(&mut client) {
+            Err(e) if is_connection_error(&e) => {
+                client.discard();
+                let mut client = self.get().map_err(ReadError::Unavailable)?;
+                operation(&mut client).map_err(ReadError::from)
+            }
+            result => result.map_err(ReadError::from),
+        }
+    }
+
+    fn check_out(&self, connection: Connection) -> PooledClient<'_> {
+        let token = connection.client.cancel_token();
+        self.state.lock().unwrap().checked_out.insert(connection.id, (token, disconnect::current()));
+        self.stats.in_use.fetch_add(1, Ordering::Relaxed);
+        PooledClient { pool: self, connection: Some(connection) }
+    }
+
+    fn put_back(&self, mut connection: Connection) {
+        // The next checkout may be for another tenant, or for none
+        let reset = !connection.in_tenant_schema || tables::reset_search_path(&mut connection.client).is_ok();
+        connection.in_tenant_schema = false;
+        let mut state = self.state.lock().unwrap();
+        state.checked_out.remove(&connection.id);
+        self.stats.in_use.fetch_sub(1, Ordering::Relaxed);
+        if !reset || connection.client.is_closed() {
+            drop(state);
+            self.stats.discarded.fetch_add(1, Ordering::Relaxed);
+            self.release_slot();
+            return;
+        }
+        state.idle.push(connection);
+        drop(state);
+        self.returned.notify_one();
+    }
+
+    fn release_slot(&self) {
+        self.state.lock().unwrap().open -= 1;
+        self.stats.open.fetch_sub(1, Ordering::Relaxed);
+        self.returned.notify_one();
+    }
+}
+
+// A connection with the statements prepared on it, which go away with it
+struct Connection {
+    id: u64,
+    client: Client,
+    statements: Statements,
+    // Pointed at the schema of a tenant by the checkout
+    in_tenant_schema: bool,
+}
+
+impl Connection {
+    fn open(connector: &Connector, config: &PoolConfig, id: u64) -> Result<Self, postgres::Error> {
+        let mut client = connector.connect()?;
+        client.batch_execute(&format!("SET statement_timeout = {}", config.statement_timeout.as_millis()))?;
+        Ok(Connection { id, client, statements: Statements::default(), in_tenant_schema: false })
+    }
+}
+
+// Statements prepared once per connection, by query text
+#[derive(Default)]
+pub struct Statements(HashMap<String, Statement>);
+
+impl Statements {
+    pub fn prepare(&mut self, client: &mut impl GenericClient, query: &str) -> Result<Statement, postgres::Error> {
+        if let Some(statement) = self.0.get(query) {
+            return Ok(statement.clone());
+        }
+        let statement = client.prepare(query)?;
+        self.0.insert(query.to_owned(), statement.clone());
+        Ok(statement)
+    }
+
+    fn forget(&mut self, query: &str) {
+        self.0.remove(query);
+    }
+}
+
+// A checked out connection, back to the pool when dropped
+pub struct PooledClient<'a> {
+    pool: &'a Pool,
+    connection: Option<Connection>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.connection.as_ref().unwrap().client
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        &mut self.connection.as_mut().unwrap().client
+    }
+}
+
+impl PooledClient<'_> {
+    // Close the connection instead of returning it, after it failed
+    pub fn discard(mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.state.lock().unwrap().checked_out.remove(&connection.id);
+            self.pool.stats.in_use.fetch_sub(1, Ordering::Relaxed);
+            self.pool.stats.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+        self.pool.release_slot();
+    }
+
+    pub fn query_cached(&mut self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, postgres::Error> {
+        self.with_statement(query, |client, statement| {
+            match statement {
+                Some(statement) => client.query(statement, params),
+                None => client.query(query, params),
+            }
+        })
+    }
+
+    // Run the query through a portal in a read-only transaction, handing each row
+    // to each as they arrive fetch_size at a time, until it returns false. Like the
+    // others it is run again from the start if the statement went stale.
+    pub fn for_each_row_cached(
+        &mut self,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+        fetch_size: i32,
+        mut each: impl FnMut(&Row) -> bool
+    ) -> Result<(), postgres::Error> {
+        self.with_statement(query, |client, statement| {
+            let mut transaction = client.build_transaction().read_only(true).start()?;
+            let portal = match statement {
+                Some(statement) => transaction.bind(statement, params)?,
+                None => transaction.bind(query, params)?,
+            };
+            loop {
+                let rows = transaction.query_portal(&portal, fetch_size)?;
+                if !rows.iter().all(&mut each) || rows.len() < fetch_size as usize {
+                    return Ok(());
+                }
+            }
+        })
+    }
+
+    pub fn query_opt_cached(
+        &mut self,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)]
+    ) -> Result<Option<Row>, postgres::Error> {
+        self.with_statement(query, |client, statement| {
+            match statement {
+                Some(statement) => client.query_opt(statement, params),
+                None => client.query_opt(query, params),
+            }
+        })
+    }
+
+    // Run the query with its prepared statement, which is prepared again if it went
+    // stale. When it can't be prepared the query is sent as is, and fails if it must.
+    fn with_statement<T>(
+        &mut self,
+        query: &str,
+        mut run: impl FnMut(&mut Client, Option<&Statement>) -> Result<T, postgres::Error>
+    ) -> Result<T, postgres::Error> {
+        let Connection { client, statements, .. } = self.connection.as_mut().unwrap();
+        let statement = match statements.prepare(client, query) {
+            Ok(statement) => statement,
+            Err(_) => {
+                return run(client, None);
+            }
+        };
+
+        match run(client, Some(&statement)) {
+            Err(e) if is_stale_statement(&e) => {
+                statements.forget(query);
+                let statement = statements.prepare(client, query)?;
+                run(client, Some(&statement))
+            }
+            result => result,
+        }
+    }
+
+    // Run work in a transaction, with the statements of this connection. work must
+    // commit last, if at all: when the transaction is aborted nothing was
+    // committed, so it can be run again from the start. That happens once with
+    // freshly prepared statements when one went stale, and up to
+    // transaction_retries times, after a short random delay, when it lost to a
+    // concurrent transaction.
+    pub fn transaction_with_statements<T>(
+        &mut self,
+        work: impl FnMut(Transaction<'_>, &mut Statements) -> Result<T, postgres::Error>
+    ) -> Result<T, postgres::Error> {
+        self.run_transaction(TransactionOptions::default(), work)
+    }
+
+    // Run work in a transaction started with options: it's committed when work
+    // returns Ok, and rolled back when work returns Err or panics. It's run again
+    // like with transaction_with_statements, so work must not have effects outside
+    // the database. The transaction holds the client, so calls can't nest; work can
+    // open a savepoint on the transaction it is given instead.
+    pub fn with_transaction<T, E: TransactionError>(
+        &mut self,
+        options: TransactionOptions,
+        mut work: impl FnMut(&mut Transaction<'_>) -> Result<T, E>
+    ) -> Result<T, E> {
+        // Dropping a transaction that is neither committed nor rolled back, as on
+        // an early return or while unwinding, rolls it back
+        self.run_transaction(options, |mut transaction, _| {
+            let value = work(&mut transaction)?;
+            transaction.commit()?;
+            Ok(value)
+        })
+    }
+
+    fn run_transaction<T, E: TransactionError>(
+        &mut self,
+        options: TransactionOptions,
+        mut work: impl FnMut(Transaction<'_>, &mut Statements) -> Result<T, E>
+    ) -> Result<T, E> {
+        let pool = self.pool;
+        let Connection { client, statements, .. } = self.connection.as_mut().unwrap();
+        let mut reprepared = false;
+        let mut retries = 0;
+        let mut delay = FIRST_TRANSACTION_RETRY_DELAY;
+
+        loop {
+            let mut builder = client.build_transaction().read_only(options.read_only);
+            if let Some(isolation_level) = options.isolation_level {
+                builder = builder.isolation_level(isolation_level);
+            }
+            let e = match builder.start().map_err(E::from).and_then(|transaction| work(transaction, statements)) {
+                Err(e) if e.is_stale_statement() && !reprepared => {
+                    statements.0.clear();
+                    reprepared = true;
+                    continue;
+                }
+                Err(e) if e.is_transaction_conflict() => e,
+                result => {
+                    return result;
+                }
+            };
+            if retries >= pool.config.transaction_retries {
+                return Err(e);
+            }
+
+            retries += 1;
+            let total = pool.stats.transaction_retries.fetch_add(1, Ordering::Relaxed) + 1;
+            let sleep = delay / 2 + jitter(delay / 2, pool.config.jitter);
+            log::warn!(
+                "Transaction aborted by a concurrent one, retry {} in {:?} ({} since startup): {}",
+                retries,
+                sleep,
+                total,
+                e
+            );
+            thread::sleep(sleep);
+            delay *= 2;
+        }
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.put_back(connection);
+        }
+    }
+}
+
+pub enum ReadError {
+    // No working connection could be had
+    Unavailable(PoolError),
+    Query(postgres::Error),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Unavailable(e) => write!(f, "{}", e),
+            ReadError::Query(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<postgres::Error> for ReadError {
+    fn from(error: postgres::Error) -> Self {
+        if is_connection_error(&error) {
+            ReadError::Unavailable(PoolError::Connect(error))
+        } else {
+            ReadError::Query(error)
+        }
+    }
+}
+
+// How long to keep trying to reach the database at startup, from
+// DB_CONNECT_RETRY_SECS and DB_CONNECT_MAX_ATTEMPTS (unlimited by default), with
+// RETRY_JITTER as for the transactions
+#[derive(Debug)]
+pub struct RetryConfig {
+    pub budget: Duration,
+    pub max_attempts: Option<u64>,
+    pub jitter: bool,
+}
+
+impl RetryConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let budget = number_from_env("DB_CONNECT_RETRY_SECS", DEFAULT_CONNECT_RETRY_BUDGET.as_secs())?;
+        let max_attempts = match config::var("DB_CONNECT_MAX_ATTEMPTS") {
+            Ok(_) => Some(number_from_env("DB_CONNECT_MAX_ATTEMPTS", 0)?),
+            Err(_) => None,
+        };
+        if max_attempts == Some(0) {
+            return Err("DB_CONNECT_MAX_ATTEMPTS must be at least 1".to_owned());
+        }
+
+        Ok(RetryConfig { budget: Duration::from_secs(budget), max_attempts, jitter: retry_jitter()? })
+    }
+}
+
+// Connect, retrying with exponential backoff and jitter while the database can't
+// be reached, as when the API starts before Postgres does. Errors that won't go
+// away by waiting, like a wrong password or a failed TLS handshake, are returned
+// right away.
+pub fn connect_with_retry(connector: &Connector, config: &RetryConfig) -> Result<Client, postgres::Error> {
+    let started = Instant::now();
+    let deadline = started + config.budget;
+    let mut delay = FIRST_RETRY_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        let e = match connector.connect() {
+            Ok(client) => {
+                return Ok(client);
+            }
+            Err(e) => e,
+        };
+
+        let out_of_attempts = config.max_attempts.is_some_and(|max_attempts| attempt >= max_attempts);
+        let now = Instant::now();
+        if !is_transient(&e) {
+            return Err(e);
+        }
+        if out_of_attempts || now >= deadline {
+            log::error!("Giving up on the database after {} attempts in {:.1?}", attempt, started.elapsed());
+            return Err(e);
+        }
+
+        // Half the delay plus a random part of the other half, so that instances
+        // started together don't retry in lockstep
+        let sleep = (delay / 2 + jitter(delay / 2, config.jitter)).min(deadline - now);
+        log::warn!("Database connection attempt {} failed, retrying in {:?}: {}", attempt, sleep, e);
+        thread::sleep(sleep);
+
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+        attempt += 1;
+    }
+}
+
+fn is_transient(error: &postgres::Error) -> bool {
+    match error.code() {
+        // Still starting up, shutting down or out of connection slots
+        Some(code) =>
+            *code == SqlState::CANNOT_CONNECT_NOW || *code == SqlState::TOO_MANY_CONNECTIONS,
+        // Not a server error: refused, unreachable, closed. TLS errors aren't
+        // transient, the server will present the same certificate next time.
+        None => error.is_closed() || std::error::Error::source(error).is_some_and(|source| source.is::<io::Error>()),
+    }
+}
+
+// Whether retries wait a random part of their delay, so that instances don't retry
+// in lockstep, or always the same for the tests to be reproducible
+fn retry_jitter() -> Result<bool, String> {
+    flag_from_env("RETRY_JITTER", Profile::from_env() != Profile::Test)
+}
+
+fn jitter(max: Duration, enabled: bool) -> Duration {
+    if !enabled {
+        return Duration::ZERO;
+    }
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos() as u64;
+    Duration::from_nanos(nanos % (max.as_nanos() as u64).max(1))
+}
+
+// A small pool on TEST_DATABASE_URL, for the tests that need a database; they
+// are skipped without one
+#[cfg(test)]
+pub fn test_pool() -> Option<Pool> {
+    let Some(credentials) = crate::credentials::Credentials::from_env("TEST_DATABASE_URL").unwrap() else {
+        eprintln!("TEST_DATABASE_URL is not set, skipping");
+        return None;
+    };
+    let config = PoolConfig {
+        min_size: 0,
+        max_size: 2,
+        checkout_timeout: Duration::from_millis(200),
+        statement_timeout: Duration::ZERO,
+        export_statement_timeout: Duration::ZERO,
+        transaction_retries: DEFAULT_TRANSACTION_RETRIES,
+        jitter: true,
+    };
+    Some(Pool::new(Connector::from_credentials(credentials).unwrap(), config).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Barrier;
+
+    // Two transactions locking the same rows in opposite orders deadlock, and
+    // Postgres aborts one of them; it is run again and both go through
+    #[test]
+    fn deadlocks_are_retried() {
+        let Some(pool) = test_pool() else {
+            return;
+        };
+        pool.get()
+            .unwrap()
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS pool_deadlock_test (id INT PRIMARY KEY, value INT NOT NULL);
+                INSERT INTO pool_deadlock_test VALUES (1, 0), (2, 0) ON CONFLICT (id) DO UPDATE SET value = 0"
+            )
+            .unwrap();
+
+        let both_locked = Barrier::new(2);
+        let attempts = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for (first, second) in [(1, 2), (2, 1)] {
+                let (pool, both_locked, attempts) = (&pool, &both_locked, &attempts);
+                scope.spawn(move || {
+                    let mut client = pool.get().unwrap();
+                    client
+                        .transaction_with_statements(|mut transaction, _| {
+                            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                            let update = "UPDATE pool_deadlock_test SET value = value + 1 WHERE id = $1";
+                            transaction.execute(update, &[&first])?;
+                            // The retry mustn't wait, the other one is done by then
+                            if attempt < 2 {
+                                both_locked.wait();
+                            }
+                            transaction.execute(update, &[&second])?;
+                            transaction.commit()
+                        })
+                        .unwrap();
+                });
+            }
+        });
+
+        assert!(attempts.load(Ordering::SeqCst) > 2, "no transaction was retried");
+        assert!(pool.stats.transaction_retries.load(Ordering::Relaxed) >= 1);
+        let rows = pool.get().unwrap().query("SELECT value FROM pool_deadlock_test ORDER BY id", &[]).unwrap();
+        let values: Vec<i32> = rows.iter().map(|row| row.get(0)).collect();
+        assert_eq!(values, [2, 2]);
+    }
+
+    // Every connection checked out: the next checkout times out, and the stats
+    // show it without needing the pool
+    #[test]
+    fn stats_follow_a_saturated_pool() {
+        let Some(pool) = test_pool() else {
+            return;
+        };
+        let stats = pool.stats();
+
+        let first = pool.get().unwrap();
+        let second = pool.get().unwrap();
+        assert_eq!(stats.in_use.load(Ordering::Relaxed), pool.config().max_size);
+        assert!(matches!(pool.get(), Err(PoolError::Timeout)));
+        assert_eq!(stats.checkout_timeouts.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.checkouts.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.checkout_wait.count(), 2);
+
+        drop(first);
+        second.discard();
+        assert_eq!(stats.in_use.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.open.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.discarded.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,62 @@
+use crate::config;
+
+pub const DEFAULT_APP_ENV: &str = "development";
+
+// What APP_ENV changes the defaults of the settings by, the others left alone:
+//
+//                     dev      test     prod
+//   LOG_FORMAT        plain    plain    json
+//   ERROR_DETAILS     true     true     false, the 500s only answer their error id
+//   MIGRATIONS_MODE   apply    apply    check, another job applies them
+//   ADMIN_ENDPOINTS   true     true     false, nor the /debug endpoints, and false
+//                                      without APP_ENV too
+//   REQUIRE_AUTH      false    false    true, refusing to serve an open API
+//   RETRY_JITTER      true     false    true
+//   GRAPHIQL          true     false    false
+//
+// dev is development, dev or local, test is test, and any other environment,
+// production and staging among them, is prod. Each of those settings still wins
+// over the profile when it is set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Profile {
+    Dev,
+    Test,
+    Prod,
+}
+
+impl Profile {
+    pub fn from_env() -> Self {
+        Profile::of(&config::var("APP_ENV").unwrap_or_else(|_| DEFAULT_APP_ENV.to_owned()))
+    }
+
+    pub fn of(app_env: &str) -> Self {
+        match app_env {
+            "development" | "dev" | "local" => Profile::Dev,
+            "test" => Profile::Test,
+            _ => Profile::Prod,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::Dev => "dev",
+            Profile::Test => "test",
+            Profile::Prod => "prod",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_other_environment_is_prod() {
+        assert_eq!(Profile::of("development"), Profile::Dev);
+        assert_eq!(Profile::of("local"), Profile::Dev);
+        assert_eq!(Profile::of("test"), Profile::Test);
+        assert_eq!(Profile::of("production"), Profile::Prod);
+        assert_eq!(Profile::of("staging"), Profile::Prod);
+        assert_eq!(Profile::of("Development"), Profile::Prod);
+    }
+}
@@ -0,0 +1,73 @@
+// For the property tests: cases made at random from a seed, the same on every
+// run, and when a property fails for one, the smallest case it still fails for
+
+use std::fmt::Debug;
+use std::panic::{ self, AssertUnwindSafe };
+
+use crate::admin::SplitMix64;
+
+// How many cases each property is checked for
+const CASES: usize = 1000;
+
+pub struct Cases(SplitMix64);
+
+impl Cases {
+    // One of 0..bound
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.0.next() % bound as u64) as usize
+    }
+
+    pub fn pick<T: Copy>(&mut self, from: &[T]) -> T {
+        from[self.below(from.len())]
+    }
+
+    pub fn one_in(&mut self, chances: usize) -> bool {
+        self.below(chances) == 0
+    }
+
+    // Up to max of them
+    pub fn some<T>(&mut self, max: usize, mut each: impl FnMut(&mut Cases) -> T) -> Vec<T> {
+        let count = self.below(max + 1);
+        (0..count).map(|_| each(self)).collect()
+    }
+}
+
+// That holds is true for the cases generate makes, or else a panic with the
+// smallest one it isn't true for: the failing case is replaced by the first of
+// the smaller ones of shrink that still fails, until none does. A panic of holds
+// counts as a failure.
+pub fn check<T: Debug>(
+    seed: u64,
+    generate: impl Fn(&mut Cases) -> T,
+    shrink: impl Fn(&T) -> Vec<T>,
+    holds: impl Fn(&T) -> bool
+) {
+    let fails = |case: &T| !panic::catch_unwind(AssertUnwindSafe(|| holds(case))).unwrap_or(false);
+    let mut cases = Cases(SplitMix64(seed));
+    for _ in 0..CASES {
+        let mut case = generate(&mut cases);
+        if !fails(&case) {
+            continue;
+        }
+        while let Some(smaller) = shrink(&case).into_iter().find(|smaller| fails(smaller)) {
+            case = smaller;
+        }
+        panic!("The property doesn't hold for {:?}", case);
+    }
+}
+
+// Each of items with one of them taken out, or of the characters of a text
+pub fn without_one<T: Clone>(items: &[T]) -> Vec<Vec<T>> {
+    (0..items.len())
+        .map(|index| {
+            let mut smaller = items.to_vec();
+            smaller.remove(index);
+            smaller
+        })
+        .collect()
+}
+
+pub fn without_one_char(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    without_one(&chars).into_iter().map(|chars| chars.into_iter().collect()).collect()
+}
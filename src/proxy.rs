@@ -0,0 +1,71 @@
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+use crate::config;
+use crate::http::get_header;
+
+static TRUSTED: OnceLock<Vec<IpAddr>> = OnceLock::new();
+
+// The proxies in front of the server, from TRUSTED_PROXIES: addresses separated
+// by commas. X-Forwarded-For is only believed when a request comes through one.
+pub fn from_env() -> Result<Vec<IpAddr>, String> {
+    let Ok(value) = config::var("TRUSTED_PROXIES") else {
+        return Ok(Vec::new());
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(|address| address.parse().map_err(|_| format!("TRUSTED_PROXIES has an invalid address: {:?}", address)))
+        .collect()
+}
+
+pub fn init(trusted: Vec<IpAddr>) {
+    TRUSTED.set(trusted).ok();
+}
+
+// The address of the client the request is from
+pub fn client_ip(request: &str, peer: IpAddr) -> IpAddr {
+    resolve(TRUSTED.get_or_init(Vec::new), peer, get_header(request, "X-Forwarded-For"))
+}
+
+// Each proxy appends the address it got the request from, so the client is the
+// last address that isn't one of the proxies. Those before it may be made up.
+fn resolve(trusted: &[IpAddr], peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+    if !trusted.contains(&peer) {
+        return peer;
+    }
+    let mut client = peer;
+    for address in forwarded_for.unwrap_or_default().rsplit(',') {
+        match address.trim().parse() {
+            Ok(address) => {
+                client = address;
+                if !trusted.contains(&address) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_client_is_the_last_address_before_the_trusted_proxies() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let trusted = [proxy, "10.0.0.2".parse().unwrap()];
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+
+        let forwarded_for = Some("198.51.100.1, 203.0.113.7, 10.0.0.2");
+        assert_eq!(resolve(&trusted, proxy, forwarded_for), client);
+        assert_eq!(resolve(&trusted, proxy, Some("203.0.113.7")), client);
+        // Without a proxy in between the header is the client's to make up
+        assert_eq!(resolve(&trusted, client, Some("198.51.100.1")), client);
+        assert_eq!(resolve(&trusted, proxy, None), proxy);
+        assert_eq!(resolve(&trusted, proxy, Some("not an address, 10.0.0.2")), "10.0.0.2".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{ Mutex, RwLock };
+use std::time::{ Duration, Instant };
+
+use crate::clock;
+use crate::config::number_from_env;
+
+// How often the buckets that filled up again are forgotten
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+static LIMITER: RwLock<Option<Limiter>> = RwLock::new(None);
+
+// A sustained rate per minute, and how many requests may come at once
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub per_minute: u64,
+    pub burst: u64,
+}
+
+impl Limit {
+    // None when the rate is not set. The burst is ten seconds' worth by default.
+    fn from_env(rate_name: &str, burst_name: &str) -> Result<Option<Self>, String> {
+        let per_minute = number_from_env(rate_name, 0)?;
+        if per_minute == 0 {
+            return Ok(None);
+        }
+        let burst = number_from_env(burst_name, (per_minute / 6).max(1))?;
+        if burst == 0 {
+            return Err(format!("{} must be at least 1", burst_name));
+        }
+        Ok(Some(Limit { per_minute, burst }))
+    }
+
+    fn per_second(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+// Requests per client address, RATE_LIMIT_PER_MINUTE with bursts of
+// RATE_LIMIT_BURST, off unless the rate is set. The mutations may have a
+// stricter limit of their own, RATE_LIMIT_MUTATIONS_PER_MINUTE and
+// RATE_LIMIT_MUTATIONS_BURST, counted apart from the other requests. Those of an
+// API key, or of whoever else authenticated, may be limited too, wherever they
+// come from, with RATE_LIMIT_PER_KEY_PER_MINUTE and RATE_LIMIT_PER_KEY_BURST.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    pub requests: Option<Limit>,
+    pub mutations: Option<Limit>,
+    pub per_key: Option<Limit>,
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Result<Self, String> {
+        Ok(RateLimitConfig {
+            requests: Limit::from_env("RATE_LIMIT_PER_MINUTE", "RATE_LIMIT_BURST")?,
+            mutations: Limit::from_env("RATE_LIMIT_MUTATIONS_PER_MINUTE", "RATE_LIMIT_MUTATIONS_BURST")?,
+            per_key: Limit::from_env("RATE_LIMIT_PER_KEY_PER_MINUTE", "RATE_LIMIT_PER_KEY_BURST")?,
+        })
+    }
+
+    fn limit(&self, subject: &Subject) -> Option<Limit> {
+        match subject {
+            Subject::Address(_, true) => self.mutations,
+            Subject::Address(_, false) => self.requests,
+            Subject::Key(_) => self.per_key,
+        }
+    }
+}
+
+// At startup, and again when a SIGHUP changed it, the buckets starting over
+pub fn init(config: RateLimitConfig) {
+    let limited = config.requests.is_some() || config.mutations.is_some() || config.per_key.is_some();
+    *LIMITER.write().unwrap() = limited.then(|| Limiter::new(config));
+}
+
+// Takes a token from the bucket of the client, None when its requests aren't limited
+pub fn check(client: IpAddr, mutation: bool) -> Option<Decision> {
+    LIMITER.read().unwrap().as_ref()?.check(client, mutation, clock::instant())
+}
+
+// Takes a token from the bucket of the principal, by its name, once it
+// authenticated: a key can't be guessed past the limit of the address
+pub fn check_key(name: &str) -> Option<Decision> {
+    LIMITER.read().unwrap().as_ref()?.check_key(name, clock::instant())
+}
+
+// The buckets kept, one per client and kind of request, None when the requests
+// aren't limited
+pub fn tracked() -> Option<usize> {
+    let limiter = LIMITER.read().unwrap();
+    let clients = limiter.as_ref()?.buckets.lock().unwrap().by_client.len();
+    Some(clients)
+}
+
+// Whether a request may go on, and what the X-RateLimit headers tell the client
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    // Seconds until the bucket is full again
+    pub reset: u64,
+    // Seconds until the next token, when refused
+    pub retry_after: u64,
+}
+
+impl Decision {
+    pub fn headers(&self) -> String {
+        format!(
+            "X-RateLimit-Limit: {}\r\nX-RateLimit-Remaining: {}\r\nX-RateLimit-Reset: {}",
+            self.limit,
+            self.remaining,
+            self.reset
+        )
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Limiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+}
+
+// Whose requests a bucket counts
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Subject {
+    // The client, and whether for the mutations
+    Address(IpAddr, bool),
+    // The name of the principal
+    Key(String),
+}
+
+struct Buckets {
+    by_client: HashMap<Subject, Bucket>,
+    cleaned_up: Instant,
+}
+
+impl Limiter {
+    fn new(config: RateLimitConfig) -> Self {
+        Limiter { config, buckets: Mutex::new(Buckets { by_client: HashMap::new(), cleaned_up: clock::instant() }) }
+    }
+
+    fn check(&self, client: IpAddr, mutation: bool, now: Instant) -> Option<Decision> {
+        let subject = match (mutation, self.config.mutations) {
+            (true, Some(_)) => Subject::Address(client, true),
+            _ => Subject::Address(client, false),
+        };
+        self.take(subject, now)
+    }
+
+    fn check_key(&self, name: &str, now: Instant) -> Option<Decision> {
+        self.take(Subject::Key(name.to_owned()), now)
+    }
+
+    fn take(&self, subject: Subject, now: Instant) -> Option<Decision> {
+        let limit = self.config.limit(&subject)?;
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.duration_since(buckets.cleaned_up) >= CLEANUP_INTERVAL {
+            self.clean_up(&mut buckets, now);
+        }
+
+        let bucket = buckets.by_client.entry(subject).or_insert(Bucket { tokens: limit.burst as f64, updated: now });
+        bucket.tokens = refilled(bucket, limit, now);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        let seconds_until = |tokens: f64| (tokens.max(0.0) / limit.per_second()).ceil() as u64;
+        Some(Decision {
+            allowed,
+            limit: limit.burst,
+            remaining: bucket.tokens.floor() as u64,
+            reset: seconds_until(limit.burst as f64 - bucket.tokens),
+            retry_after: seconds_until(1.0 - bucket.tokens).max(1),
+        })
+    }
+
+    // The buckets full by now are as good as new, so they needn't be kept
+    fn clean_up(&self, buckets: &mut Buckets, now: Instant) {
+        let config = &self.config;
+        buckets.by_client.retain(|subject, bucket| {
+            config.limit(subject).is_some_and(|limit| refilled(bucket, limit, now) < limit.burst as f64)
+        });
+        buckets.cleaned_up = now;
+    }
+}
+
+fn refilled(bucket: &Bucket, limit: Limit, now: Instant) -> f64 {
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    (bucket.tokens + elapsed * limit.per_second()).min(limit.burst as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> Limiter {
+        Limiter::new(RateLimitConfig {
+            requests: Some(Limit { per_minute: 60, burst: 3 }),
+            mutations: Some(Limit { per_minute: 6, burst: 1 }),
+            per_key: Some(Limit { per_minute: 60, burst: 2 }),
+        })
+    }
+
+    #[test]
+    fn buckets_refill_at_the_sustained_rate() {
+        let (limiter, client, now) = (limiter(), "203.0.113.7".parse().unwrap(), Instant::now());
+        let allowed: Vec<bool> = (0..4).map(|_| limiter.check(client, false, now).unwrap().allowed).collect();
+        assert_eq!(allowed, [true, true, true, false]);
+
+        let refused = limiter.check(client, false, now).unwrap();
+        assert_eq!((refused.remaining, refused.reset, refused.retry_after), (0, 3, 1));
+        let later = limiter.check(client, false, now + Duration::from_secs(1)).unwrap();
+        assert_eq!((later.allowed, later.remaining), (true, 0));
+
+        // The mutations are counted apart, and the other clients too
+        assert!(limiter.check(client, true, now).unwrap().allowed);
+        let refused = limiter.check(client, true, now).unwrap();
+        assert_eq!((refused.allowed, refused.retry_after), (false, 10));
+        assert!(limiter.check("203.0.113.8".parse().unwrap(), false, now).unwrap().allowed);
+    }
+
+    #[test]
+    fn the_requests_of_a_key_are_counted_together_wherever_they_come_from() {
+        let (limiter, now) = (limiter(), Instant::now());
+        let allowed: Vec<bool> = (0..3).map(|_| limiter.check_key("ci", now).unwrap().allowed).collect();
+        assert_eq!(allowed, [true, true, false]);
+        assert!(limiter.check_key("deploy", now).unwrap().allowed);
+        // Apart from those of the addresses
+        assert!(limiter.check("203.0.113.7".parse().unwrap(), false, now).unwrap().allowed);
+
+        let unlimited = Limiter::new(RateLimitConfig { per_key: None, ..limiter.config.clone() });
+        assert_eq!(unlimited.check_key("ci", now), None);
+    }
+
+    #[test]
+    fn full_buckets_are_forgotten() {
+        let (limiter, client, now) = (limiter(), "203.0.113.7".parse().unwrap(), Instant::now());
+        limiter.check(client, false, now);
+        // Still half a token short at the cleanup
+        limiter.check("203.0.113.8".parse().unwrap(), false, now + CLEANUP_INTERVAL - Duration::from_millis(500));
+        limiter.check("203.0.113.9".parse().unwrap(), false, now + CLEANUP_INTERVAL);
+        let buckets = limiter.buckets.lock().unwrap();
+        let client = |subject: &Subject| match subject {
+            Subject::Address(client, _) => client.to_string(),
+            Subject::Key(name) => name.clone(),
+        };
+        let mut kept: Vec<String> = buckets.by_client.keys().map(client).collect();
+        kept.sort();
+        assert_eq!(kept, ["203.0.113.8", "203.0.113.9"]);
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::RwLock;
+
+use crate::http::{ get_body, BAD_REQUEST, OK_RESPONSE };
+use crate::{ config, locale };
+
+const FORBIDDEN_PROBLEM: &str = "HTTP/1.1 403 FORBIDDEN\r\nContent-Type: application/problem+json\r\n\r\n";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static EXEMPT: RwLock<Vec<Route>> = RwLock::new(Vec::new());
+
+// With READ_ONLY=true, or after POST /admin/read-only, the writes are answered 403
+// before anything is read from them, so that nothing is written while the
+// database is failed over or being looked into. READ_ONLY_EXEMPT lists the routes
+// still served, as "POST /users/validate,…", none by default besides the switch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadOnlyConfig {
+    pub enabled: bool,
+    pub exempt: Vec<Route>,
+}
+
+// A method and a path without its query
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub method: String,
+    pub path: String,
+}
+
+impl ReadOnlyConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let enabled = match config::var("READ_ONLY").as_deref() {
+            Ok("true") => true,
+            Ok("false") | Err(_) => false,
+            Ok(value) => return Err(format!("READ_ONLY must be true or false, got {:?}", value)),
+        };
+        let exempt = config::var("READ_ONLY_EXEMPT").unwrap_or_default();
+        let exempt = exempt
+            .split(',')
+            .map(str::trim)
+            .filter(|route| !route.is_empty())
+            .map(|route| match route.split_once(' ') {
+                Some((method, path)) if path.starts_with('/') => {
+                    Ok(Route { method: method.to_owned(), path: path.trim_end_matches('/').to_owned() })
+                }
+                _ => Err(format!("READ_ONLY_EXEMPT must list routes as \"METHOD /path\", got {:?}", route)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(ReadOnlyConfig { enabled, exempt })
+    }
+}
+
+// At startup, and again when a SIGHUP changed it, over POST /admin/read-only
+pub fn init(config: ReadOnlyConfig) {
+    ENABLED.store(config.enabled, Ordering::Relaxed);
+    *EXEMPT.write().unwrap() = config.exempt;
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// The response to a write turned away, if the server is read-only
+pub fn rejected(method: &str, segments: &[&str]) -> Option<(String, String)> {
+    let switch_or_login = matches!(segments, ["admin", "read-only"] | ["login"] | ["session"]);
+    if !enabled() || !matches!(method, "POST" | "PUT" | "PATCH" | "DELETE") || switch_or_login {
+        return None;
+    }
+    let path = format!("/{}", segments.join("/"));
+    let exempt = EXEMPT.read().unwrap().iter().any(|route| route.method == method && route.path == path);
+    if exempt {
+        return None;
+    }
+    let body = serde_json::json!({
+        "type": "about:blank",
+        "title": locale::title(403, "Forbidden"),
+        "status": 403,
+        "detail": "The service is read-only, no writes are accepted",
+    });
+    Some((FORBIDDEN_PROBLEM.to_owned(), body.to_string()))
+}
+
+// GET /admin/read-only
+pub fn handle_get_read_only_request() -> (String, String) {
+    (OK_RESPONSE.to_owned(), serde_json::json!({ "enabled": enabled() }).to_string())
+}
+
+#[derive(Deserialize, Debug)]
+struct ReadOnlyRequest {
+    enabled: bool,
+}
+
+// POST /admin/read-only, with {"enabled": …}
+pub fn handle_set_read_only_request(request: &str) -> (String, String) {
+    let read_only: ReadOnlyRequest = match serde_json::from_str(get_body(request)) {
+        Ok(read_only) => read_only,
+        Err(_) => return (BAD_REQUEST.to_owned(), "Invalid request body".to_owned()),
+    };
+    ENABLED.store(read_only.enabled, Ordering::Relaxed);
+    log::warn!("Read-only mode {}", if read_only.enabled { "on" } else { "off" });
+    handle_get_read_only_request()
+}
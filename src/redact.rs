@@ -0,0 +1,176 @@
+use serde_json::Value;
+use std::sync::OnceLock;
+
+use crate::config;
+use crate::secret;
+
+const DEFAULT_SENSITIVE_FIELDS: &str = "password,current_password,new_password,token,key,secret";
+
+// What stands for what was taken out
+const REDACTED: &str = "[redacted]";
+
+// Where Postgres echoes the values of a row in the DETAIL of its errors, up to the
+// last parenthesis of the line
+const ECHOED_VALUES: [&str; 2] = ["Failing row contains (", ")=("];
+
+static SENSITIVE_FIELDS: OnceLock<Vec<String>> = OnceLock::new();
+
+// What the logs and the error messages may show of the users: an email as its first
+// letter and its domain, a***@example.com, a name as its initial, and nothing of
+// the fields of LOG_SENSITIVE_FIELDS, separated by commas, the passwords, tokens
+// and keys by default. Everything logged or answered that may hold them goes
+// through here.
+pub fn sensitive_fields_from_env() -> Vec<String> {
+    let fields = config::var("LOG_SENSITIVE_FIELDS").unwrap_or_else(|_| DEFAULT_SENSITIVE_FIELDS.to_owned());
+    fields.split(',').map(str::trim).filter(|field| !field.is_empty()).map(str::to_lowercase).collect()
+}
+
+pub fn init(sensitive_fields: Vec<String>) {
+    SENSITIVE_FIELDS.set(sensitive_fields).ok();
+}
+
+fn sensitive_fields() -> &'static [String] {
+    SENSITIVE_FIELDS.get_or_init(|| DEFAULT_SENSITIVE_FIELDS.split(',').map(str::to_owned).collect())
+}
+
+pub fn email(email: &str) -> String {
+    match email.rsplit_once('@') {
+        Some((local, domain)) => match local.chars().next() {
+            Some(first) => format!("{}***@{}", first, domain),
+            None => format!("***@{}", domain),
+        },
+        None => "***".to_owned(),
+    }
+}
+
+pub fn name(name: &str) -> String {
+    match name.trim().chars().next() {
+        Some(initial) => format!("{}.", initial),
+        None => String::new(),
+    }
+}
+
+fn in_local_part(c: char) -> bool {
+    c.is_alphanumeric() || "._%+-".contains(c)
+}
+
+fn in_domain(c: char) -> bool {
+    c.is_alphanumeric() || ".-".contains(c)
+}
+
+// The text with the emails in it masked
+fn emails(text: &str) -> String {
+    let mut masked = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('@') {
+        let local = rest[..at].char_indices().rev().take_while(|(_, c)| in_local_part(*c)).last();
+        let start = local.map_or(at, |(i, _)| i);
+        let end = rest[at + 1..].find(|c| !in_domain(c)).map_or(rest.len(), |i| at + 1 + i);
+        let domain = rest[at + 1..end].trim_end_matches('.');
+        let end = at + 1 + domain.len();
+        masked.push_str(&rest[..start]);
+        if start < at && domain.contains('.') {
+            masked.push_str(&email(&rest[start..end]));
+        } else {
+            masked.push_str(&rest[start..end]);
+        }
+        rest = &rest[end..];
+    }
+    masked.push_str(rest);
+    masked
+}
+
+// A message to be logged or answered, the errors of the database included: their
+// emails are masked, the values of the rows they echo taken out, and the secrets
+// of the config and the passwords of connection strings too, see secret::scrub
+pub fn text(text: &str) -> String {
+    let text = secret::scrub(text);
+    let lines = text.split('\n').map(|line| {
+        let echoed = ECHOED_VALUES.iter().filter_map(|marker| Some(line.find(marker)? + marker.len())).min();
+        match echoed.zip(line.rfind(')')).filter(|(start, end)| start <= end) {
+            Some((start, end)) => format!("{}{}{}", &line[..start], REDACTED, &line[end..]),
+            None => line.to_owned(),
+        }
+    });
+    emails(&lines.collect::<Vec<_>>().join("\n"))
+}
+
+// A JSON document to be logged, a request body or the payload of an event: the
+// sensitive fields taken out, the emails and the names masked, at any depth
+pub fn json(value: &Value) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .iter()
+                .map(|(field, value)| {
+                    let redacted = match (field.to_lowercase().as_str(), value) {
+                        (field, _) if sensitive_fields().iter().any(|sensitive| sensitive == field) => {
+                            Value::from(REDACTED)
+                        }
+                        ("email", Value::String(address)) => Value::from(email(address)),
+                        ("name", Value::String(full_name)) => Value::from(name(full_name)),
+                        _ => json(value),
+                    };
+                    (field.clone(), redacted)
+                })
+                .collect()
+        ),
+        Value::Array(values) => Value::Array(values.iter().map(json).collect()),
+        Value::String(string) => Value::from(emails(string)),
+        value => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emails_and_names_are_masked() {
+        assert_eq!(email("ada@example.com"), "a***@example.com");
+        assert_eq!(email("@example.com"), "***@example.com");
+        assert_eq!(email("nobody"), "***");
+        assert_eq!(name(" Ada Lovelace"), "A.");
+        assert_eq!(name("Édouard"), "É.");
+
+        let logged = "User 7 keeps email \"Ada.L+x@Example.com\": it belongs to ada@example.com.";
+        assert_eq!(text(logged), "User 7 keeps email \"A***@Example.com\": it belongs to a***@example.com.");
+        assert_eq!(text("no emails @ all, nor user@localhost"), "no emails @ all, nor user@localhost");
+    }
+
+    #[test]
+    fn database_errors_lose_the_values_they_echo() {
+        let errors = [
+            "db error: ERROR: duplicate key value violates unique constraint \"users_tenant_email_key\"\n\
+            DETAIL: Key (tenant_id, lower(email))=(default, ada@example.com) already exists.",
+            "db error: ERROR: new row for relation \"users\" violates check constraint \"users_name_check\"\n\
+            DETAIL: Failing row contains (7, Ada Lovelace, ada@example.com, null).",
+            "Error creating user: could not insert ada@example.com",
+        ];
+        for error in errors {
+            let scrubbed = text(error);
+            assert!(!scrubbed.contains("ada@example.com") && !scrubbed.contains("Lovelace"), "{}", scrubbed);
+        }
+        assert!(text(errors[0]).ends_with("DETAIL: Key (tenant_id, lower(email))=([redacted]) already exists."));
+        assert!(text(errors[1]).ends_with("DETAIL: Failing row contains ([redacted])."));
+    }
+
+    #[test]
+    fn logged_documents_lose_their_sensitive_fields() {
+        let event = serde_json::json!({
+            "id": 7,
+            "user": { "name": "Ada Lovelace", "email": "ada@example.com", "password": "correct horse" },
+            "changes": [{ "field": "email", "from": "ada@example.com", "to": "ada@lovelace.org" }],
+            "Token": "abc",
+        });
+        let redacted = serde_json::json!({
+            "id": 7,
+            "user": { "name": "A.", "email": "a***@example.com", "password": "[redacted]" },
+            "changes": [{ "field": "email", "from": "a***@example.com", "to": "a***@lovelace.org" }],
+            "Token": "[redacted]",
+        });
+        assert_eq!(json(&event), redacted);
+        let body = serde_json::json!({ "email": "ada@example.com", "new_password": "tr0ub4dor" });
+        assert_eq!(json(&body), serde_json::json!({ "email": "a***@example.com", "new_password": "[redacted]" }));
+    }
+}
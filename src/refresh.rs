@@ -0,0 +1,208 @@
+use postgres::Transaction;
+
+use crate::auth::Role;
+use crate::jwt::{ self, JwtConfig };
+use crate::pool::TransactionOptions;
+use crate::repository::{ stored_role, RepositoryError };
+use crate::db::pool;
+use crate::errors::repository_error_response;
+use crate::http::{ get_body, BAD_REQUEST, NOT_IMPLEMENTED, OK_RESPONSE };
+use crate::{ api_keys, auth, tables, tenant, validation };
+
+// The refresh tokens of REFRESH_TOKENS=true, see JwtConfig. Each is good once:
+// POST /token/refresh replaces it with the next of its chain, along with the new
+// access token, and a replaced token used again revokes the whole chain, for
+// either the user or whoever stole it has the latest. POST /logout revokes the
+// token given, and DELETE /admin/users/{id}/refresh-tokens all those of the user.
+// The access tokens stay good until they expire, nothing is looked up for them.
+
+// What became of a refresh token given to POST /token/refresh
+enum Refreshed {
+    // The next token, with the user and their role now
+    Next(String, i32, Role),
+    Expired,
+    Revoked,
+    // Replaced before, the chain is revoked
+    Reused,
+    Unknown,
+}
+
+// A new token of the chain for the user of the tenant entered
+fn insert(
+    transaction: &mut Transaction,
+    lifetime: u64,
+    user_id: i32,
+    chain_id: &str
+) -> Result<String, RepositoryError> {
+    let token = api_keys::generate();
+    transaction.execute(
+        tables::sql(
+            "INSERT INTO {refresh_tokens} (token_hash, user_id, tenant_id, chain_id, expires_at)
+            VALUES ($1, $2, $3, $4, now() + $5 * interval '1 second')"
+        ),
+        &[&api_keys::hash(&token), &user_id, &tenant::current(), &chain_id, &(lifetime as f64)]
+    )?;
+    Ok(token)
+}
+
+// The first token of a new chain, at login
+pub fn issue(lifetime: u64, user_id: i32) -> Result<String, RepositoryError> {
+    pool().get()?.with_transaction(TransactionOptions::default(), |transaction| {
+        // The tokens that expired go on the way, chains and all
+        transaction.execute(
+            tables::sql(
+                "DELETE FROM {refresh_tokens} WHERE chain_id IN (
+                    SELECT chain_id FROM {refresh_tokens} GROUP BY chain_id HAVING max(expires_at) <= now()
+                )"
+            ),
+            &[]
+        )?;
+        insert(transaction, lifetime, user_id, &api_keys::generate())
+    })
+}
+
+fn refresh(lifetime: u64, token: &str) -> Result<Refreshed, RepositoryError> {
+    let (token_hash, tenant) = (api_keys::hash(token), tenant::current());
+    pool().get()?.with_transaction(TransactionOptions::default(), |transaction| {
+        let row = transaction.query_opt(
+            tables::sql(
+                "SELECT r.user_id, r.chain_id, r.expires_at <= now(), r.rotated_at IS NOT NULL,
+                    r.revoked_at IS NOT NULL, u.role
+                FROM {refresh_tokens} r JOIN {users} u ON u.id = r.user_id
+                WHERE r.token_hash = $1 AND r.tenant_id = $2 AND u.anonymized_at IS NULL FOR UPDATE OF r"
+            ),
+            &[&token_hash, &tenant]
+        )?;
+        let Some(row) = row else {
+            return Ok(Refreshed::Unknown);
+        };
+        let (user_id, chain_id): (i32, String) = (row.get(0), row.get(1));
+        if row.get::<_, bool>(4) {
+            return Ok(Refreshed::Revoked);
+        }
+        if row.get::<_, bool>(3) {
+            transaction.execute(
+                tables::sql(
+                    "UPDATE {refresh_tokens} SET revoked_at = now() WHERE chain_id = $1 AND revoked_at IS NULL"
+                ),
+                &[&chain_id]
+            )?;
+            return Ok(Refreshed::Reused);
+        }
+        if row.get::<_, bool>(2) {
+            return Ok(Refreshed::Expired);
+        }
+
+        transaction.execute(
+            tables::sql("UPDATE {refresh_tokens} SET rotated_at = now() WHERE token_hash = $1"),
+            &[&token_hash]
+        )?;
+        let next = insert(transaction, lifetime, user_id, &chain_id)?;
+        Ok(Refreshed::Next(next, user_id, stored_role(row.get(5))))
+    })
+}
+
+// The response of a login, or of a refresh, with the refresh token if there is one
+pub fn tokens_body(config: &JwtConfig, token: String, refresh_token: Option<String>) -> String {
+    let mut body = serde_json::json!({ "token": token, "token_type": "Bearer", "expires_in": config.lifetime });
+    if let Some((refresh_token, lifetime)) = refresh_token.zip(config.refresh_lifetime) {
+        body["refresh_token"] = refresh_token.into();
+        body["refresh_expires_in"] = lifetime.into();
+    }
+    body.to_string()
+}
+
+fn only_with_refresh_tokens() -> Result<(&'static JwtConfig, u64), (String, String)> {
+    let config = auth::jwt().and_then(|config| Some(config).zip(config.refresh_lifetime));
+    config.ok_or_else(|| (NOT_IMPLEMENTED.to_owned(), "Only available with REFRESH_TOKENS=true".to_owned()))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+fn refresh_token(request: &str) -> Result<String, (String, String)> {
+    match serde_json::from_str::<RefreshRequest>(get_body(request)) {
+        Ok(refresh) => Ok(refresh.refresh_token.trim().to_owned()),
+        Err(e) => Err((BAD_REQUEST.to_owned(), validation::body_error(get_body(request), &e))),
+    }
+}
+
+// POST /token/refresh with {"refresh_token": ...}, a new access token and the
+// refresh token to use next time
+pub fn handle_refresh_request(request: &str) -> (String, String) {
+    let (config, lifetime) = match only_with_refresh_tokens() {
+        Ok(config) => config,
+        Err(response) => {
+            return response;
+        }
+    };
+    let token = match refresh_token(request) {
+        Ok(token) => token,
+        Err(response) => {
+            return response;
+        }
+    };
+
+    match refresh(lifetime, &token) {
+        Ok(Refreshed::Next(next, user_id, role)) => {
+            let token = jwt::issue(config, user_id, tenant::current(), role);
+            (OK_RESPONSE.to_owned(), tokens_body(config, token, Some(next)))
+        }
+        Ok(Refreshed::Expired) => auth::unauthorized_problem("refresh_token_expired", "The refresh token has expired"),
+        Ok(Refreshed::Revoked) => auth::unauthorized_problem("refresh_token_revoked", "The refresh token was revoked"),
+        Ok(Refreshed::Reused) => auth::unauthorized_problem(
+            "refresh_token_reused",
+            "The refresh token was used before, every token of its login is revoked"
+        ),
+        Ok(Refreshed::Unknown) => auth::unauthorized_problem("invalid_refresh_token", "The refresh token isn't valid"),
+        Err(e) => repository_error_response(e, "Error refreshing the token"),
+    }
+}
+
+// POST /logout with {"refresh_token": ...}, which revokes it if it is one
+pub fn handle_logout_request(request: &str) -> (String, String) {
+    if let Err(response) = only_with_refresh_tokens() {
+        return response;
+    }
+    let token = match refresh_token(request) {
+        Ok(token) => token,
+        Err(response) => {
+            return response;
+        }
+    };
+    let query = tables::sql(
+        "UPDATE {refresh_tokens} SET revoked_at = now()
+        WHERE token_hash = $1 AND tenant_id = $2 AND revoked_at IS NULL"
+    );
+    let (token_hash, tenant) = (api_keys::hash(&token), tenant::current());
+    let revoked = pool().get().map_err(RepositoryError::from).and_then(|mut client| {
+        client.execute(query, &[&token_hash, &tenant]).map_err(RepositoryError::from)
+    });
+    match revoked {
+        Ok(_) => (OK_RESPONSE.to_owned(), serde_json::json!({ "logged_out": true }).to_string()),
+        Err(e) => repository_error_response(e, "Error logging out"),
+    }
+}
+
+// DELETE /admin/users/{id}/refresh-tokens, which revokes every refresh token of
+// the user, for the next refresh to fail
+pub fn handle_revoke_user_request(id: i32) -> (String, String) {
+    if let Err(response) = only_with_refresh_tokens() {
+        return response;
+    }
+    let query = tables::sql(
+        "UPDATE {refresh_tokens} SET revoked_at = now() WHERE user_id = $1 AND tenant_id = $2 AND revoked_at IS NULL"
+    );
+    let tenant = tenant::current();
+    // Once, run again after a connection failure it would count none revoked
+    let revoked = pool().get().map_err(RepositoryError::from).and_then(|mut client| {
+        client.execute(query, &[&id, &tenant]).map_err(RepositoryError::from)
+    });
+    match revoked {
+        Ok(revoked) => (OK_RESPONSE.to_owned(), serde_json::json!({ "id": id, "revoked": revoked }).to_string()),
+        Err(e) => repository_error_response(e, "Error revoking the refresh tokens"),
+    }
+}
@@ -0,0 +1,229 @@
+use log::LevelFilter;
+use std::collections::BTreeMap;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Mutex;
+
+use crate::config::Config;
+use crate::credentials;
+use crate::logger::{ self, Logger };
+use crate::maintenance::{ self, MaintenanceConfig };
+use crate::pagination::{ self, PaginationConfig };
+use crate::rate_limit::{ self, RateLimitConfig };
+use crate::read_only::{ self, ReadOnlyConfig };
+use crate::slow::{ self, SlowConfig };
+use crate::validation::{ self, ValidationConfig };
+
+// Those a SIGHUP changes while the server runs, the others being read at startup only
+const RELOADED: &[&str] = &[
+    "RUST_LOG",
+    "LOG_FORMAT",
+    "RATE_LIMIT_PER_MINUTE",
+    "RATE_LIMIT_BURST",
+    "RATE_LIMIT_MUTATIONS_PER_MINUTE",
+    "RATE_LIMIT_MUTATIONS_BURST",
+    "MAINTENANCE",
+    "MAINTENANCE_MESSAGE",
+    "MAINTENANCE_RETRY_AFTER",
+    "MAINTENANCE_FILE",
+    "READ_ONLY",
+    "READ_ONLY_EXEMPT",
+    "VALIDATION_*",
+    "SLOW_REQUEST_MS",
+    "SLOW_QUERY_MS",
+    "PAGE_SIZE_*",
+    "EXPORT_PAGE_SIZE_MAX",
+];
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+// credentials::reload_requests() when the settings were last read, not to lock
+// STATE for every request
+static SEEN: AtomicU64 = AtomicU64::new(0);
+// The settings read at startup are the first, each reload that changed one of
+// them the next
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+// What of the Config a SIGHUP applies: the log levels and format, the rate limits,
+// the maintenance and read-only modes, the validation rules, the slow request and
+// query thresholds and the page sizes. The rest, as the address, the database or
+// the pool, needs a restart, which the reload warns of when it changed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeConfig {
+    pub logger: Logger,
+    pub rate_limit: RateLimitConfig,
+    pub maintenance: MaintenanceConfig,
+    pub read_only: ReadOnlyConfig,
+    pub validation: ValidationConfig,
+    pub slow: SlowConfig,
+    pub pagination: PaginationConfig,
+}
+
+impl RuntimeConfig {
+    pub fn of(config: &Config) -> Self {
+        RuntimeConfig {
+            logger: config.logger.clone(),
+            rate_limit: config.rate_limit.clone(),
+            maintenance: config.maintenance.clone(),
+            read_only: config.read_only.clone(),
+            validation: config.validation.clone(),
+            slow: config.slow,
+            pagination: config.pagination,
+        }
+    }
+}
+
+struct State {
+    runtime: RuntimeConfig,
+    effective: Vec<String>,
+    // That of --config, and --log-level, which are read again with the rest
+    file: Option<String>,
+    log_level: Option<LevelFilter>,
+}
+
+// The settings the server started with, which a SIGHUP reads again from the
+// environment and the config file, the modules having been initialized with them
+pub fn init(config: &Config, file: Option<String>, log_level: Option<LevelFilter>) {
+    SEEN.store(credentials::reload_requests(), Ordering::Relaxed);
+    *STATE.lock().unwrap() =
+        Some(State { runtime: RuntimeConfig::of(config), effective: config.effective.clone(), file, log_level });
+    credentials::reload_on_sighup();
+}
+
+// How many times the settings were applied, 1 for those of startup
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Relaxed)
+}
+
+// Before a request is answered: the settings read again if there was a SIGHUP
+// since, and applied unless they aren't valid, those before being kept then
+pub fn reload_if_requested() {
+    let reload_requests = credentials::reload_requests();
+    if SEEN.load(Ordering::Relaxed) == reload_requests {
+        return;
+    }
+    let mut state = STATE.lock().unwrap();
+    if SEEN.swap(reload_requests, Ordering::Relaxed) == reload_requests {
+        return;
+    }
+    let Some(state) = state.as_mut() else {
+        return;
+    };
+    match Config::from_env(state.file.as_deref()) {
+        Ok(mut config) => {
+            if let Some(level) = state.log_level {
+                config.logger = config.logger.with_default_level(level);
+            }
+            apply_to(state, &config);
+        }
+        Err(e) => log::error!(
+            target: "config",
+            "Keeping the settings of generation {}, those after the SIGHUP aren't valid: {}",
+            generation(),
+            e
+        ),
+    }
+}
+
+// Those of the config, as after a SIGHUP, for the tests
+#[cfg(test)]
+pub fn apply(config: &Config) {
+    if let Some(state) = STATE.lock().unwrap().as_mut() {
+        apply_to(state, config);
+    }
+}
+
+fn apply_to(state: &mut State, config: &Config) {
+    let runtime = RuntimeConfig::of(config);
+    let (before, after) = (values(&state.effective), values(&config.effective));
+    let mut changed: Vec<&str> = before.keys().chain(after.keys()).copied().collect();
+    changed.sort();
+    changed.dedup();
+    changed.retain(|name| before.get(name) != after.get(name));
+    let (reloaded, restart): (Vec<&str>, Vec<&str>) = changed.into_iter().partition(|name| is_reloaded(name));
+
+    if runtime != state.runtime {
+        let old = &state.runtime;
+        if runtime.logger != old.logger {
+            logger::init(runtime.logger.clone());
+        }
+        if runtime.rate_limit != old.rate_limit {
+            rate_limit::init(runtime.rate_limit.clone());
+        }
+        if runtime.maintenance != old.maintenance {
+            maintenance::init(runtime.maintenance.clone());
+        }
+        if runtime.read_only != old.read_only {
+            read_only::init(runtime.read_only.clone());
+        }
+        if runtime.validation != old.validation {
+            validation::init(runtime.validation.clone());
+        }
+        if runtime.slow != old.slow {
+            slow::init(runtime.slow);
+        }
+        if runtime.pagination != old.pagination {
+            pagination::init(runtime.pagination);
+        }
+        let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+        for name in &reloaded {
+            let (old, new) = (before.get(name).unwrap_or(&"unset"), after.get(name).unwrap_or(&"unset"));
+            log::info!(target: "config", "{} {} -> {}, generation {}", name, old, new, generation);
+        }
+    }
+    if !restart.is_empty() {
+        log::warn!(target: "config", "Changed, but only applied after a restart: {}", restart.join(", "));
+    }
+    state.runtime = runtime;
+    state.effective = config.effective.clone();
+}
+
+// The values of the effective settings, NAME=value (where it was set), by name
+fn values(effective: &[String]) -> BTreeMap<&str, &str> {
+    effective
+        .iter()
+        .filter_map(|setting| setting.split_once('='))
+        .map(|(name, value)| (name, value.rsplit_once(" (").map_or(value, |(value, _)| value)))
+        .collect()
+}
+
+fn is_reloaded(name: &str) -> bool {
+    RELOADED.iter().any(|reloaded| match reloaded.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == *reloaded,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(vars: &[(&str, &str)]) -> Config {
+        let vars = [("DATABASE_URL", "memory://")].iter().chain(vars);
+        Config::from_vars(vars.map(|(name, value)| (name.to_string(), value.to_string()))).unwrap()
+    }
+
+    #[test]
+    fn the_settings_are_told_apart_by_their_name() {
+        let effective = ["PORT=9000 (environment)".to_owned(), "LOG_FORMAT=json (config file)".to_owned()];
+        assert_eq!(values(&effective), BTreeMap::from([("LOG_FORMAT", "json"), ("PORT", "9000")]));
+        assert!(is_reloaded("RATE_LIMIT_BURST") && is_reloaded("VALIDATION_NAME_MAX_LENGTH"));
+        assert!(!is_reloaded("PORT") && !is_reloaded("RATE_LIMIT"));
+    }
+
+    #[test]
+    fn a_new_rate_limit_is_applied_at_once() {
+        let client = "203.0.113.7".parse().unwrap();
+        let started = config(&[]);
+        rate_limit::init(started.rate_limit.clone());
+        init(&started, None, None);
+        assert!(rate_limit::check(client, false).is_none());
+
+        apply(&config(&[("RATE_LIMIT_PER_MINUTE", "1"), ("RATE_LIMIT_BURST", "1")]));
+        assert_eq!(generation(), 2);
+        assert!(rate_limit::check(client, false).unwrap().allowed);
+        assert!(!rate_limit::check(client, false).unwrap().allowed);
+
+        // Nothing that changed, nothing applied
+        apply(&config(&[("RATE_LIMIT_PER_MINUTE", "1"), ("RATE_LIMIT_BURST", "1")]));
+        assert_eq!(generation(), 2);
+    }
+}
@@ -0,0 +1,184 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use crate::auth::Role;
+use crate::outbox::Event;
+use crate::validation::NewUser;
+use crate::models::User;
+
+pub mod bulkhead;
+pub mod chaos;
+pub mod circuit;
+pub mod memory;
+pub mod postgres;
+pub mod sqlite;
+pub mod traced;
+
+// Where the users are stored, picked at startup from DATABASE_URL. The handlers go
+// through it for everything every backend supports.
+pub trait UserRepository: Send + Sync {
+    fn find(&self, id: i32) -> Result<User, RepositoryError>;
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError>;
+
+    // The users of list in the same order, handed to each as they are read rather
+    // than all at once, until it returns false
+    fn list_each(&self, filter: &UserFilter, each: &mut dyn FnMut(User) -> bool) -> Result<(), RepositoryError> {
+        for user in self.list(filter)? {
+            if !each(user) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // With dry_run the user is inserted and rolled back, and comes back without an id
+    fn create(
+        &self,
+        user: &NewUser,
+        dry_run: bool,
+        idempotency: Option<&IdempotencyKey>
+    ) -> Result<Created, RepositoryError>;
+
+    fn update(&self, id: i32, user: &NewUser, dry_run: bool) -> Result<User, RepositoryError>;
+
+    fn delete(&self, id: i32, dry_run: bool) -> Result<(), RepositoryError>;
+
+    // Emails are compared regardless of case
+    fn email_taken(&self, email: &str) -> Result<bool, RepositoryError>;
+
+    // Whether the database answers
+    fn ping(&self) -> Result<(), RepositoryError>;
+
+    // The account of the user with the email, normalized, for logging in
+    fn credentials(&self, email: &str) -> Result<Option<Account>, RepositoryError>;
+
+    // The hash of the password of the user, None when they have none
+    fn password_hash(&self, id: i32) -> Result<Option<String>, RepositoryError>;
+
+    fn set_password_hash(&self, id: i32, hash: &str) -> Result<(), RepositoryError>;
+
+    fn set_role(&self, id: i32, role: Role) -> Result<(), RepositoryError>;
+
+    // The rest are built on the outbox and idempotency tables of Postgres
+
+    // Scrub the personal data of a user for good. Anonymizing twice is a no-op.
+    fn anonymize(&self, _id: i32, _dry_run: bool) -> Result<User, RepositoryError> {
+        Err(RepositoryError::Unsupported("Anonymizing users is only supported with Postgres"))
+    }
+
+    // A user and the events recorded about them, oldest first, as one snapshot
+    fn export(&self, _id: i32) -> Result<(User, Vec<Event>), RepositoryError> {
+        Err(RepositoryError::Unsupported("Exporting users is only supported with Postgres"))
+    }
+
+    fn events_since(&self, _since_id: i64) -> Result<Vec<Event>, RepositoryError> {
+        Err(RepositoryError::Unsupported("Events are only recorded with Postgres"))
+    }
+
+    // Delete everything and start the ids over
+    fn reset(&self) -> Result<(), RepositoryError> {
+        Err(RepositoryError::Unsupported("Resetting is only supported with Postgres"))
+    }
+
+    // Insert the users whose email is free, returning their ids
+    fn seed(&self, _users: Vec<User>) -> Result<Vec<i32>, RepositoryError> {
+        Err(RepositoryError::Unsupported("Seeding is only supported with Postgres"))
+    }
+
+    // Keep a query running for that long, for testing the timeouts
+    fn sleep(&self, _duration: Duration) -> Result<(), RepositoryError> {
+        Err(RepositoryError::Unsupported("Sleeping is only supported with Postgres"))
+    }
+}
+
+// What logging in needs of a user: the hash of their password, if they have one,
+// and the role their tokens and sessions have
+#[derive(Clone, Debug, PartialEq)]
+pub struct Account {
+    pub id: i32,
+    pub password_hash: Option<String>,
+    pub role: Role,
+}
+
+// The ?email= and ?name_contains= filters of GET /users, normalized like the
+// stored values, and the ?after_id= of its pages, the users with a greater id
+#[derive(Default)]
+pub struct UserFilter {
+    pub email: Option<String>,
+    pub name_contains: Option<String>,
+    pub after_id: Option<i32>,
+}
+
+// The Idempotency-Key of a create, with the response to store for replays
+pub struct IdempotencyKey<'a> {
+    pub key: &'a str,
+    pub request_hash: String,
+    pub response: &'a dyn Fn(&User) -> (String, String),
+}
+
+pub enum Created {
+    User(User),
+    // The key was already used with the same body: the response stored then
+    Replay(String, String),
+    // The key was already used with a different body
+    KeyMismatch,
+}
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    NotFound,
+    Conflict(Conflict),
+    // No connection to the database could be had
+    Unavailable(Box<dyn Error + Send + Sync>),
+    // The query ran longer than the statement timeout
+    Timeout(Box<dyn Error + Send + Sync>),
+    // Too many operations were in progress, the client should try again shortly
+    Overloaded,
+    // The database was unreachable just before, it isn't asked again for that long
+    CircuitOpen(Duration),
+    // The backend can't do this
+    Unsupported(&'static str),
+    Db(Box<dyn Error + Send + Sync>),
+}
+
+#[derive(Debug)]
+pub enum Conflict {
+    EmailTaken,
+    // Anonymized users can't be changed back
+    Anonymized,
+    // Concurrent transactions kept getting in the way, trying again later should work
+    Concurrent,
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RepositoryError::NotFound => write!(f, "not found"),
+            RepositoryError::Conflict(Conflict::EmailTaken) => write!(f, "email is already taken"),
+            RepositoryError::Conflict(Conflict::Anonymized) => write!(f, "user has been anonymized"),
+            RepositoryError::Conflict(Conflict::Concurrent) => write!(f, "conflict with concurrent changes, please retry"),
+            RepositoryError::Unavailable(e) => write!(f, "{}", e),
+            RepositoryError::Timeout(e) => write!(f, "{}", e),
+            RepositoryError::Overloaded => write!(f, "too many database operations in progress"),
+            RepositoryError::CircuitOpen(retry_after) => {
+                write!(f, "database unreachable, not trying again for {}ms", retry_after.as_millis())
+            }
+            RepositoryError::Unsupported(what) => write!(f, "{}", what),
+            RepositoryError::Db(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for RepositoryError {}
+
+// LIKE pattern matching values that contain the given text literally
+fn contains_pattern(text: &str) -> String {
+    format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+}
+
+// A role as the tables hold it, where only valid ones are written
+pub fn stored_role(role: &str) -> Role {
+    role.parse().unwrap_or(Role::Reader)
+}
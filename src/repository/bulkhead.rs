@@ -0,0 +1,332 @@
+use std::cell::Cell;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Condvar, Mutex };
+use std::time::{ Duration, Instant };
+
+use crate::metrics::Histogram;
+use crate::statsd;
+use crate::auth::Role;
+use crate::outbox::Event;
+use crate::config::number_from_env;
+use crate::repository::{ Account, Created, IdempotencyKey, RepositoryError, UserFilter, UserRepository };
+use crate::validation::NewUser;
+use crate::models::User;
+
+const DEFAULT_WAIT: Duration = Duration::from_millis(100);
+
+thread_local! {
+    // How long the operations of the request handled on this thread waited for a
+    // permit, for its Server-Timing
+    static WAITED: Cell<Option<Duration>> = const { Cell::new(None) };
+}
+
+// At most DB_MAX_CONCURRENT_OPERATIONS repository operations run at once, the
+// size of the pool by default. The others wait up to DB_OPERATION_WAIT_MS for
+// their turn, then give up.
+#[derive(Clone, Debug)]
+pub struct BulkheadConfig {
+    pub max_concurrent: usize,
+    pub wait: Duration,
+}
+
+impl BulkheadConfig {
+    pub fn from_env(default_max_concurrent: usize) -> Result<Self, String> {
+        let config = BulkheadConfig {
+            max_concurrent: number_from_env("DB_MAX_CONCURRENT_OPERATIONS", default_max_concurrent as u64)? as usize,
+            wait: Duration::from_millis(number_from_env("DB_OPERATION_WAIT_MS", DEFAULT_WAIT.as_millis() as u64)?),
+        };
+
+        if config.max_concurrent == 0 {
+            return Err("DB_MAX_CONCURRENT_OPERATIONS must be at least 1".to_owned());
+        }
+
+        Ok(config)
+    }
+}
+
+// The permits of the operations in progress, shared by the repositories in front
+// of the database. A burst of requests is turned away quickly instead of piling
+// up on the connections of the pool until their checkout times out.
+pub struct Permits {
+    config: BulkheadConfig,
+    in_use: Mutex<usize>,
+    released: Condvar,
+    // Since startup
+    pub wait_time: Histogram,
+    pub rejected: AtomicU64,
+}
+
+impl Permits {
+    pub fn new(config: BulkheadConfig) -> Self {
+        Permits {
+            config,
+            in_use: Mutex::new(0),
+            released: Condvar::new(),
+            wait_time: Histogram::default(),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn in_use(&self) -> usize {
+        *self.in_use.lock().unwrap()
+    }
+
+    fn acquire(&self) -> Result<Permit<'_>, RepositoryError> {
+        let start = Instant::now();
+        let deadline = start + self.config.wait;
+        let mut in_use = self.in_use.lock().unwrap();
+        let acquired = loop {
+            if *in_use < self.config.max_concurrent {
+                *in_use += 1;
+                break true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break false;
+            }
+            in_use = self.released.wait_timeout(in_use, deadline - now).unwrap().0;
+        };
+        drop(in_use);
+
+        let waited = start.elapsed();
+        WAITED.set(Some(WAITED.get().unwrap_or_default() + waited));
+        if !acquired {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(RepositoryError::Overloaded);
+        }
+        self.wait_time.observe(waited);
+        statsd::timing("db_operation_wait", waited, &[]);
+        Ok(Permit(self))
+    }
+}
+
+struct Permit<'a>(&'a Permits);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.in_use.lock().unwrap() -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+// How long the operations run on this thread since the last call waited for their
+// permits, None when there were none
+pub fn take_waited() -> Option<Duration> {
+    WAITED.take()
+}
+
+// A repository whose operations each need a permit
+pub struct Bulkhead<'a> {
+    permits: &'a Permits,
+    repository: &'a dyn UserRepository,
+}
+
+impl<'a> Bulkhead<'a> {
+    pub fn new(permits: &'a Permits, repository: &'a dyn UserRepository) -> Self {
+        Bulkhead { permits, repository }
+    }
+
+    fn call<T>(
+        &self,
+        operation: impl FnOnce(&dyn UserRepository) -> Result<T, RepositoryError>
+    ) -> Result<T, RepositoryError> {
+        let _permit = self.permits.acquire()?;
+        operation(self.repository)
+    }
+}
+
+impl UserRepository for Bulkhead<'_> {
+    fn find(&self, id: i32) -> Result<User, RepositoryError> {
+        self.call(|repository| repository.find(id))
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        self.call(|repository| repository.list(filter))
+    }
+
+    fn list_each(&self, filter: &UserFilter, each: &mut dyn FnMut(User) -> bool) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.list_each(filter, each))
+    }
+
+    fn create(
+        &self,
+        user: &NewUser,
+        dry_run: bool,
+        idempotency: Option<&IdempotencyKey>
+    ) -> Result<Created, RepositoryError> {
+        self.call(|repository| repository.create(user, dry_run, idempotency))
+    }
+
+    fn update(&self, id: i32, user: &NewUser, dry_run: bool) -> Result<User, RepositoryError> {
+        self.call(|repository| repository.update(id, user, dry_run))
+    }
+
+    fn delete(&self, id: i32, dry_run: bool) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.delete(id, dry_run))
+    }
+
+    fn email_taken(&self, email: &str) -> Result<bool, RepositoryError> {
+        self.call(|repository| repository.email_taken(email))
+    }
+
+    fn ping(&self) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.ping())
+    }
+
+    fn credentials(&self, email: &str) -> Result<Option<Account>, RepositoryError> {
+        self.call(|repository| repository.credentials(email))
+    }
+
+    fn password_hash(&self, id: i32) -> Result<Option<String>, RepositoryError> {
+        self.call(|repository| repository.password_hash(id))
+    }
+
+    fn set_password_hash(&self, id: i32, hash: &str) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.set_password_hash(id, hash))
+    }
+
+    fn set_role(&self, id: i32, role: Role) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.set_role(id, role))
+    }
+
+    fn anonymize(&self, id: i32, dry_run: bool) -> Result<User, RepositoryError> {
+        self.call(|repository| repository.anonymize(id, dry_run))
+    }
+
+    fn export(&self, id: i32) -> Result<(User, Vec<Event>), RepositoryError> {
+        self.call(|repository| repository.export(id))
+    }
+
+    fn events_since(&self, since_id: i64) -> Result<Vec<Event>, RepositoryError> {
+        self.call(|repository| repository.events_since(since_id))
+    }
+
+    fn reset(&self) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.reset())
+    }
+
+    fn seed(&self, users: Vec<User>) -> Result<Vec<i32>, RepositoryError> {
+        self.call(|repository| repository.seed(users))
+    }
+
+    fn sleep(&self, duration: Duration) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.sleep(duration))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::memory::MemoryRepository;
+    use std::sync::Barrier;
+    use std::thread;
+
+    // As slow as a database that takes a while to answer
+    struct SlowRepository(MemoryRepository);
+
+    impl UserRepository for SlowRepository {
+        fn find(&self, id: i32) -> Result<User, RepositoryError> {
+            self.0.find(id)
+        }
+
+        fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+            self.0.list(filter)
+        }
+
+        fn create(
+            &self,
+            user: &NewUser,
+            dry_run: bool,
+            idempotency: Option<&IdempotencyKey>
+        ) -> Result<Created, RepositoryError> {
+            self.0.create(user, dry_run, idempotency)
+        }
+
+        fn update(&self, id: i32, user: &NewUser, dry_run: bool) -> Result<User, RepositoryError> {
+            self.0.update(id, user, dry_run)
+        }
+
+        fn delete(&self, id: i32, dry_run: bool) -> Result<(), RepositoryError> {
+            self.0.delete(id, dry_run)
+        }
+
+        fn email_taken(&self, email: &str) -> Result<bool, RepositoryError> {
+            self.0.email_taken(email)
+        }
+
+        fn ping(&self) -> Result<(), RepositoryError> {
+            self.0.ping()
+        }
+
+        fn credentials(&self, email: &str) -> Result<Option<Account>, RepositoryError> {
+            self.0.credentials(email)
+        }
+
+        fn password_hash(&self, id: i32) -> Result<Option<String>, RepositoryError> {
+            self.0.password_hash(id)
+        }
+
+        fn set_password_hash(&self, id: i32, hash: &str) -> Result<(), RepositoryError> {
+            self.0.set_password_hash(id, hash)
+        }
+
+        fn set_role(&self, id: i32, role: Role) -> Result<(), RepositoryError> {
+            self.0.set_role(id, role)
+        }
+
+        fn sleep(&self, duration: Duration) -> Result<(), RepositoryError> {
+            std::thread::sleep(duration);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn excess_operations_are_turned_away_after_the_wait() {
+        let permits = Permits::new(BulkheadConfig { max_concurrent: 2, wait: Duration::from_millis(50) });
+        let repository = SlowRepository(MemoryRepository::default());
+        let bulkhead = Bulkhead::new(&permits, &repository);
+
+        let started = Barrier::new(3);
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    started.wait();
+                    bulkhead.sleep(Duration::from_secs(1)).unwrap();
+                });
+            }
+            started.wait();
+            while permits.in_use() < 2 {
+                thread::yield_now();
+            }
+
+            let start = Instant::now();
+            assert!(matches!(bulkhead.ping(), Err(RepositoryError::Overloaded)));
+            let waited = start.elapsed();
+            assert!(waited >= Duration::from_millis(50) && waited < Duration::from_millis(500), "{:?}", waited);
+            assert!(take_waited().unwrap() >= Duration::from_millis(50));
+            assert_eq!(permits.rejected.load(Ordering::Relaxed), 1);
+        });
+
+        // Once they are done there is room again, without waiting
+        bulkhead.ping().unwrap();
+        assert_eq!(permits.in_use(), 0);
+        assert!(take_waited().unwrap() < Duration::from_millis(50));
+        assert_eq!(permits.wait_time.count(), 3);
+    }
+
+    #[test]
+    fn an_operation_waits_for_a_permit_to_be_released() {
+        let permits = Permits::new(BulkheadConfig { max_concurrent: 1, wait: Duration::from_secs(5) });
+        let repository = SlowRepository(MemoryRepository::default());
+        let bulkhead = Bulkhead::new(&permits, &repository);
+
+        thread::scope(|scope| {
+            scope.spawn(|| bulkhead.sleep(Duration::from_millis(100)).unwrap());
+            while permits.in_use() < 1 {
+                thread::yield_now();
+            }
+            bulkhead.ping().unwrap();
+        });
+        assert_eq!(permits.rejected.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+use crate::auth::Role;
+use crate::chaos;
+use crate::outbox::Event;
+use crate::repository::{ Account, Created, IdempotencyKey, RepositoryError, UserFilter, UserRepository };
+use crate::validation::NewUser;
+use crate::models::User;
+
+// A repository whose operations fail, CHAOS_DB_ERROR_RATE of them, as if the
+// database couldn't be reached, before the one it wraps is asked anything
+pub struct Chaotic<'a> {
+    repository: &'a dyn UserRepository,
+}
+
+impl<'a> Chaotic<'a> {
+    pub fn new(repository: &'a dyn UserRepository) -> Self {
+        Chaotic { repository }
+    }
+
+    fn call<T>(
+        &self,
+        name: &str,
+        operation: impl FnOnce(&dyn UserRepository) -> Result<T, RepositoryError>
+    ) -> Result<T, RepositoryError> {
+        match chaos::repository_fault(name) {
+            Some(e) => Err(e),
+            None => operation(self.repository),
+        }
+    }
+}
+
+impl UserRepository for Chaotic<'_> {
+    fn find(&self, id: i32) -> Result<User, RepositoryError> {
+        self.call("find", |repository| repository.find(id))
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        self.call("list", |repository| repository.list(filter))
+    }
+
+    fn list_each(&self, filter: &UserFilter, each: &mut dyn FnMut(User) -> bool) -> Result<(), RepositoryError> {
+        self.call("list_each", |repository| repository.list_each(filter, each))
+    }
+
+    fn create(
+        &self,
+        user: &NewUser,
+        dry_run: bool,
+        idempotency: Option<&IdempotencyKey>
+    ) -> Result<Created, RepositoryError> {
+        self.call("create", |repository| repository.create(user, dry_run, idempotency))
+    }
+
+    fn update(&self, id: i32, user: &NewUser, dry_run: bool) -> Result<User, RepositoryError> {
+        self.call("update", |repository| repository.update(id, user, dry_run))
+    }
+
+    fn delete(&self, id: i32, dry_run: bool) -> Result<(), RepositoryError> {
+        self.call("delete", |repository| repository.delete(id, dry_run))
+    }
+
+    fn email_taken(&self, email: &str) -> Result<bool, RepositoryError> {
+        self.call("email_taken", |repository| repository.email_taken(email))
+    }
+
+    fn ping(&self) -> Result<(), RepositoryError> {
+        self.call("ping", |repository| repository.ping())
+    }
+
+    fn credentials(&self, email: &str) -> Result<Option<Account>, RepositoryError> {
+        self.call("credentials", |repository| repository.credentials(email))
+    }
+
+    fn password_hash(&self, id: i32) -> Result<Option<String>, RepositoryError> {
+        self.call("password_hash", |repository| repository.password_hash(id))
+    }
+
+    fn set_password_hash(&self, id: i32, hash: &str) -> Result<(), RepositoryError> {
+        self.call("set_password_hash", |repository| repository.set_password_hash(id, hash))
+    }
+
+    fn set_role(&self, id: i32, role: Role) -> Result<(), RepositoryError> {
+        self.call("set_role", |repository| repository.set_role(id, role))
+    }
+
+    fn anonymize(&self, id: i32, dry_run: bool) -> Result<User, RepositoryError> {
+        self.call("anonymize", |repository| repository.anonymize(id, dry_run))
+    }
+
+    fn export(&self, id: i32) -> Result<(User, Vec<Event>), RepositoryError> {
+        self.call("export", |repository| repository.export(id))
+    }
+
+    fn events_since(&self, since_id: i64) -> Result<Vec<Event>, RepositoryError> {
+        self.call("events_since", |repository| repository.events_since(since_id))
+    }
+
+    fn reset(&self) -> Result<(), RepositoryError> {
+        self.call("reset", |repository| repository.reset())
+    }
+
+    fn seed(&self, users: Vec<User>) -> Result<Vec<i32>, RepositoryError> {
+        self.call("seed", |repository| repository.seed(users))
+    }
+
+    fn sleep(&self, duration: Duration) -> Result<(), RepositoryError> {
+        self.call("sleep", |repository| repository.sleep(duration))
+    }
+}
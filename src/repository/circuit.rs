@@ -0,0 +1,460 @@
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+use crate::auth::Role;
+use crate::outbox::Event;
+use crate::config::number_from_env;
+use crate::pool::PoolError;
+use crate::repository::{ Account, Created, IdempotencyKey, RepositoryError, UserFilter, UserRepository };
+use crate::validation::NewUser;
+use crate::models::User;
+
+const DEFAULT_FAILURE_THRESHOLD: u64 = 5;
+const DEFAULT_FAILURE_WINDOW: Duration = Duration::from_secs(10);
+const DEFAULT_COOL_DOWN: Duration = Duration::from_secs(5);
+const DEFAULT_HALF_OPEN_PROBES: u64 = 3;
+
+// What a request turned away while the probes run is told to wait
+const HALF_OPEN_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+// The circuit opens after CIRCUIT_FAILURE_THRESHOLD connection failures in a row
+// within CIRCUIT_FAILURE_WINDOW_MS, stays open for CIRCUIT_COOL_DOWN_MS, then
+// closes once CIRCUIT_HALF_OPEN_PROBES requests in a row got through. A threshold
+// of 0 keeps it closed.
+#[derive(Clone, Debug)]
+pub struct CircuitConfig {
+    pub failure_threshold: u64,
+    pub failure_window: Duration,
+    pub cool_down: Duration,
+    pub half_open_probes: u64,
+}
+
+impl CircuitConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let config = CircuitConfig {
+            failure_threshold: number_from_env("CIRCUIT_FAILURE_THRESHOLD", DEFAULT_FAILURE_THRESHOLD)?,
+            failure_window: Duration::from_millis(
+                number_from_env("CIRCUIT_FAILURE_WINDOW_MS", DEFAULT_FAILURE_WINDOW.as_millis() as u64)?
+            ),
+            cool_down: Duration::from_millis(
+                number_from_env("CIRCUIT_COOL_DOWN_MS", DEFAULT_COOL_DOWN.as_millis() as u64)?
+            ),
+            half_open_probes: number_from_env("CIRCUIT_HALF_OPEN_PROBES", DEFAULT_HALF_OPEN_PROBES)?,
+        };
+
+        if config.half_open_probes == 0 {
+            return Err("CIRCUIT_HALF_OPEN_PROBES must be at least 1".to_owned());
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Closed,
+    // Failing fast without asking the database
+    Open,
+    // Letting a few requests through to see whether the database is back
+    HalfOpen,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open => "open",
+            State::HalfOpen => "half_open",
+        }
+    }
+}
+
+enum Phase {
+    Closed { failures: u64, first_failure: Instant },
+    Open { until: Instant },
+    HalfOpen { in_flight: u64, succeeded: u64 },
+}
+
+impl Phase {
+    fn state(&self) -> State {
+        match self {
+            Phase::Closed { .. } => State::Closed,
+            Phase::Open { .. } => State::Open,
+            Phase::HalfOpen { .. } => State::HalfOpen,
+        }
+    }
+}
+
+// Whether the database is worth asking, shared by the repositories in front of it.
+// When Postgres is down every request would otherwise wait for a connect timeout.
+pub struct Circuit {
+    config: CircuitConfig,
+    phase: Mutex<Phase>,
+    // Since startup, by the state entered, in the order of State
+    transitions: [AtomicU64; 3],
+}
+
+impl Circuit {
+    pub fn new(config: CircuitConfig) -> Self {
+        Circuit {
+            config,
+            phase: Mutex::new(Phase::Closed { failures: 0, first_failure: Instant::now() }),
+            transitions: Default::default(),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.phase.lock().unwrap().state()
+    }
+
+    pub fn transitions(&self, to: State) -> u64 {
+        self.transitions[to as usize].load(Ordering::Relaxed)
+    }
+
+    // Whether a request may go to the database, and whether it's a probe
+    fn admit(&self) -> Result<bool, RepositoryError> {
+        let mut phase = self.phase.lock().unwrap();
+        if let Phase::Open { until } = *phase {
+            let now = Instant::now();
+            if now < until {
+                return Err(RepositoryError::CircuitOpen(until - now));
+            }
+            self.enter(&mut phase, Phase::HalfOpen { in_flight: 0, succeeded: 0 });
+            log::warn!(
+                "Circuit breaker half-open: letting {} requests through to the database",
+                self.config.half_open_probes
+            );
+        }
+
+        match &mut *phase {
+            Phase::HalfOpen { in_flight, succeeded } => {
+                if *in_flight + *succeeded >= self.config.half_open_probes {
+                    return Err(RepositoryError::CircuitOpen(HALF_OPEN_RETRY_AFTER));
+                }
+                *in_flight += 1;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn record(&self, probe: bool, failed: bool) {
+        let mut phase = self.phase.lock().unwrap();
+        let now = Instant::now();
+        match &mut *phase {
+            Phase::Closed { failures, first_failure } if failed => {
+                if *failures == 0 || now.duration_since(*first_failure) > self.config.failure_window {
+                    *failures = 0;
+                    *first_failure = now;
+                }
+                *failures += 1;
+                if self.config.failure_threshold > 0 && *failures >= self.config.failure_threshold {
+                    log::warn!(
+                        "Circuit breaker open: {} database connection failures in a row, failing fast for {:?}",
+                        failures,
+                        self.config.cool_down
+                    );
+                    self.enter(&mut phase, Phase::Open { until: now + self.config.cool_down });
+                }
+            }
+            Phase::Closed { failures, .. } => *failures = 0,
+            Phase::HalfOpen { .. } if probe && failed => {
+                log::warn!(
+                    "Circuit breaker open: the database still can't be reached, failing fast for {:?}",
+                    self.config.cool_down
+                );
+                self.enter(&mut phase, Phase::Open { until: now + self.config.cool_down });
+            }
+            Phase::HalfOpen { in_flight, succeeded } if probe => {
+                *in_flight -= 1;
+                *succeeded += 1;
+                if *succeeded >= self.config.half_open_probes {
+                    log::warn!("Circuit breaker closed: the database answers again");
+                    self.enter(&mut phase, Phase::Closed { failures: 0, first_failure: now });
+                }
+            }
+            // Requests let through before the circuit opened
+            _ => {}
+        }
+    }
+
+    fn enter(&self, phase: &mut Phase, next: Phase) {
+        *phase = next;
+        self.transitions[phase.state() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// A repository that fails fast with CircuitOpen while the circuit is open.
+// Connection failures are what opens it: a database that answers with an error, or
+// a pool with every connection busy, is still up.
+pub struct CircuitBreaker<'a> {
+    circuit: &'a Circuit,
+    repository: &'a dyn UserRepository,
+}
+
+impl<'a> CircuitBreaker<'a> {
+    pub fn new(circuit: &'a Circuit, repository: &'a dyn UserRepository) -> Self {
+        CircuitBreaker { circuit, repository }
+    }
+
+    fn call<T>(
+        &self,
+        operation: impl FnOnce(&dyn UserRepository) -> Result<T, RepositoryError>
+    ) -> Result<T, RepositoryError> {
+        let probe = self.circuit.admit()?;
+        let result = operation(self.repository);
+        self.circuit.record(probe, result.as_ref().err().is_some_and(is_connection_failure));
+        result
+    }
+}
+
+// All the connections being busy doesn't mean the database is down
+fn is_connection_failure(error: &RepositoryError) -> bool {
+    match error {
+        RepositoryError::Unavailable(e) => !matches!(e.downcast_ref(), Some(PoolError::Timeout)),
+        _ => false,
+    }
+}
+
+impl UserRepository for CircuitBreaker<'_> {
+    fn find(&self, id: i32) -> Result<User, RepositoryError> {
+        self.call(|repository| repository.find(id))
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        self.call(|repository| repository.list(filter))
+    }
+
+    fn list_each(&self, filter: &UserFilter, each: &mut dyn FnMut(User) -> bool) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.list_each(filter, each))
+    }
+
+    fn create(
+        &self,
+        user: &NewUser,
+        dry_run: bool,
+        idempotency: Option<&IdempotencyKey>
+    ) -> Result<Created, RepositoryError> {
+        self.call(|repository| repository.create(user, dry_run, idempotency))
+    }
+
+    fn update(&self, id: i32, user: &NewUser, dry_run: bool) -> Result<User, RepositoryError> {
+        self.call(|repository| repository.update(id, user, dry_run))
+    }
+
+    fn delete(&self, id: i32, dry_run: bool) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.delete(id, dry_run))
+    }
+
+    fn email_taken(&self, email: &str) -> Result<bool, RepositoryError> {
+        self.call(|repository| repository.email_taken(email))
+    }
+
+    fn ping(&self) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.ping())
+    }
+
+    fn credentials(&self, email: &str) -> Result<Option<Account>, RepositoryError> {
+        self.call(|repository| repository.credentials(email))
+    }
+
+    fn password_hash(&self, id: i32) -> Result<Option<String>, RepositoryError> {
+        self.call(|repository| repository.password_hash(id))
+    }
+
+    fn set_password_hash(&self, id: i32, hash: &str) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.set_password_hash(id, hash))
+    }
+
+    fn set_role(&self, id: i32, role: Role) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.set_role(id, role))
+    }
+
+    fn anonymize(&self, id: i32, dry_run: bool) -> Result<User, RepositoryError> {
+        self.call(|repository| repository.anonymize(id, dry_run))
+    }
+
+    fn export(&self, id: i32) -> Result<(User, Vec<Event>), RepositoryError> {
+        self.call(|repository| repository.export(id))
+    }
+
+    fn events_since(&self, since_id: i64) -> Result<Vec<Event>, RepositoryError> {
+        self.call(|repository| repository.events_since(since_id))
+    }
+
+    fn reset(&self) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.reset())
+    }
+
+    fn seed(&self, users: Vec<User>) -> Result<Vec<i32>, RepositoryError> {
+        self.call(|repository| repository.seed(users))
+    }
+
+    fn sleep(&self, duration: Duration) -> Result<(), RepositoryError> {
+        self.call(|repository| repository.sleep(duration))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    // Pings fail while down is set, like a database that can't be reached
+    #[derive(Default)]
+    struct FlakyRepository {
+        down: AtomicBool,
+        pings: AtomicU64,
+    }
+
+    impl UserRepository for FlakyRepository {
+        fn find(&self, _id: i32) -> Result<User, RepositoryError> {
+            Err(RepositoryError::NotFound)
+        }
+
+        // As when every connection of the pool is checked out
+        fn list(&self, _filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+            Err(PoolError::Timeout.into())
+        }
+
+        fn create(
+            &self,
+            _user: &NewUser,
+            _dry_run: bool,
+            _idempotency: Option<&IdempotencyKey>
+        ) -> Result<Created, RepositoryError> {
+            unimplemented!()
+        }
+
+        fn update(&self, _id: i32, _user: &NewUser, _dry_run: bool) -> Result<User, RepositoryError> {
+            unimplemented!()
+        }
+
+        fn delete(&self, _id: i32, _dry_run: bool) -> Result<(), RepositoryError> {
+            unimplemented!()
+        }
+
+        fn email_taken(&self, _email: &str) -> Result<bool, RepositoryError> {
+            unimplemented!()
+        }
+
+        fn ping(&self) -> Result<(), RepositoryError> {
+            self.pings.fetch_add(1, Ordering::Relaxed);
+            if self.down.load(Ordering::Relaxed) {
+                Err(RepositoryError::Unavailable("connection refused".into()))
+            } else {
+                Ok(())
+            }
+        }
+
+        fn credentials(&self, _email: &str) -> Result<Option<Account>, RepositoryError> {
+            unimplemented!()
+        }
+
+        fn password_hash(&self, _id: i32) -> Result<Option<String>, RepositoryError> {
+            unimplemented!()
+        }
+
+        fn set_password_hash(&self, _id: i32, _hash: &str) -> Result<(), RepositoryError> {
+            unimplemented!()
+        }
+
+        fn set_role(&self, _id: i32, _role: Role) -> Result<(), RepositoryError> {
+            unimplemented!()
+        }
+    }
+
+    fn config() -> CircuitConfig {
+        CircuitConfig {
+            failure_threshold: 3,
+            failure_window: Duration::from_secs(10),
+            cool_down: Duration::from_millis(50),
+            half_open_probes: 2,
+        }
+    }
+
+    #[test]
+    fn goes_from_closed_to_open_to_half_open_and_back() {
+        let circuit = Circuit::new(config());
+        let repository = FlakyRepository::default();
+        let breaker = CircuitBreaker::new(&circuit, &repository);
+
+        repository.down.store(true, Ordering::Relaxed);
+        for _ in 0..2 {
+            assert!(matches!(breaker.ping(), Err(RepositoryError::Unavailable(_))));
+        }
+        assert_eq!(circuit.state(), State::Closed);
+        assert!(matches!(breaker.ping(), Err(RepositoryError::Unavailable(_))));
+        assert_eq!(circuit.state(), State::Open);
+
+        // Open: failing fast, without asking the repository
+        match breaker.ping() {
+            Err(RepositoryError::CircuitOpen(retry_after)) => assert!(retry_after <= Duration::from_millis(50)),
+            _ => panic!("the circuit should be open"),
+        }
+        assert_eq!(repository.pings.load(Ordering::Relaxed), 3);
+
+        // A failed probe opens it again
+        thread::sleep(Duration::from_millis(60));
+        assert!(matches!(breaker.ping(), Err(RepositoryError::Unavailable(_))));
+        assert_eq!(circuit.state(), State::Open);
+
+        // Two probes that get through close it
+        repository.down.store(false, Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(60));
+        breaker.ping().unwrap();
+        assert_eq!(circuit.state(), State::HalfOpen);
+        breaker.ping().unwrap();
+        assert_eq!(circuit.state(), State::Closed);
+
+        assert_eq!(circuit.transitions(State::Open), 2);
+        assert_eq!(circuit.transitions(State::HalfOpen), 2);
+        assert_eq!(circuit.transitions(State::Closed), 1);
+    }
+
+    #[test]
+    fn only_consecutive_connection_failures_count() {
+        let circuit = Circuit::new(config());
+        let repository = FlakyRepository::default();
+        let breaker = CircuitBreaker::new(&circuit, &repository);
+
+        for _ in 0..5 {
+            repository.down.store(true, Ordering::Relaxed);
+            breaker.ping().unwrap_err();
+            breaker.ping().unwrap_err();
+            repository.down.store(false, Ordering::Relaxed);
+            breaker.ping().unwrap();
+            // The database answering with an error is no connection failure
+            assert!(matches!(breaker.find(1), Err(RepositoryError::NotFound)));
+            assert!(matches!(breaker.list(&UserFilter::default()), Err(RepositoryError::Unavailable(_))));
+        }
+        assert_eq!(circuit.state(), State::Closed);
+    }
+
+    #[test]
+    fn failures_spread_over_more_than_the_window_dont_open_it() {
+        let circuit = Circuit::new(CircuitConfig { failure_window: Duration::from_millis(20), ..config() });
+        let repository = FlakyRepository { down: AtomicBool::new(true), ..Default::default() };
+        let breaker = CircuitBreaker::new(&circuit, &repository);
+
+        for _ in 0..4 {
+            breaker.ping().unwrap_err();
+            thread::sleep(Duration::from_millis(15));
+        }
+        assert_eq!(circuit.state(), State::Closed);
+    }
+
+    #[test]
+    fn half_open_lets_a_limited_number_of_probes_through() {
+        let circuit = Circuit::new(config());
+        let repository = FlakyRepository::default();
+        let breaker = CircuitBreaker::new(&circuit, &repository);
+        circuit.enter(&mut circuit.phase.lock().unwrap(), Phase::HalfOpen { in_flight: 2, succeeded: 0 });
+
+        // Both probes are still running
+        assert!(matches!(breaker.ping(), Err(RepositoryError::CircuitOpen(_))));
+        assert_eq!(repository.pings.load(Ordering::Relaxed), 0);
+    }
+}
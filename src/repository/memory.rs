@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::sync::{ RwLock, RwLockReadGuard, RwLockWriteGuard };
+use std::thread;
+use std::time::Duration;
+
+use crate::auth::Role;
+use crate::repository::{ Account, Conflict, Created, IdempotencyKey, RepositoryError, UserFilter, UserRepository };
+use crate::validation::NewUser;
+use crate::models::User;
+
+// Users in a map that lives as long as the process, for tests and demos that
+// shouldn't need any database. Serves the same endpoints as SQLite.
+#[derive(Default)]
+pub struct MemoryRepository {
+    state: RwLock<State>,
+}
+
+#[derive(Default)]
+struct State {
+    users: HashMap<i32, User>,
+    // Ids are never reused, like a sequence
+    last_id: i32,
+    // Lowercased email to the id of its user
+    emails: HashMap<String, i32>,
+    // Of the users with a password
+    password_hashes: HashMap<i32, String>,
+    // Of the users that aren't writers
+    roles: HashMap<i32, Role>,
+}
+
+impl MemoryRepository {
+    pub fn handles(url: &str) -> bool {
+        url == "memory://"
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        // Every change is complete before the lock is released
+        self.state.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl State {
+    fn email_taken_by_other(&self, email: &str, id: Option<i32>) -> bool {
+        self.emails.get(&email.to_lowercase()).is_some_and(|&owner| Some(owner) != id)
+    }
+}
+
+impl UserRepository for MemoryRepository {
+    fn find(&self, id: i32) -> Result<User, RepositoryError> {
+        self.read().users.get(&id).cloned().ok_or(RepositoryError::NotFound)
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        let email = filter.email.as_ref().map(|email| email.to_lowercase());
+        let name_contains = filter.name_contains.as_ref().map(|name| name.to_lowercase());
+
+        let mut users: Vec<User> = self
+            .read()
+            .users.values()
+            .filter(|user| email.as_ref().is_none_or(|email| user.email.to_lowercase() == *email))
+            .filter(|user| name_contains.as_ref().is_none_or(|name| user.name.to_lowercase().contains(name)))
+            .filter(|user| filter.after_id.is_none_or(|after_id| user.id.is_some_and(|id| id > after_id)))
+            .cloned()
+            .collect();
+        users.sort_by_key(|user| user.id);
+        Ok(users)
+    }
+
+    fn create(
+        &self,
+        new_user: &NewUser,
+        dry_run: bool,
+        idempotency: Option<&IdempotencyKey>
+    ) -> Result<Created, RepositoryError> {
+        if idempotency.is_some() {
+            return Err(RepositoryError::Unsupported("Idempotency-Key is only supported with Postgres"));
+        }
+
+        let mut state = self.write();
+        if state.email_taken_by_other(&new_user.email, None) {
+            return Err(RepositoryError::Conflict(Conflict::EmailTaken));
+        }
+        if dry_run {
+            return Ok(Created::User(User::new(None, new_user.name.clone(), new_user.email.clone(), false)));
+        }
+
+        state.last_id += 1;
+        let id = state.last_id;
+        let user = User::new(Some(id), new_user.name.clone(), new_user.email.clone(), false);
+        state.emails.insert(user.email.to_lowercase(), id);
+        state.users.insert(id, user.clone());
+        if let Some(hash) = &new_user.password_hash {
+            state.password_hashes.insert(id, hash.clone());
+        }
+        Ok(Created::User(user))
+    }
+
+    fn update(&self, id: i32, new_user: &NewUser, dry_run: bool) -> Result<User, RepositoryError> {
+        let mut state = self.write();
+        let old_email = match state.users.get(&id) {
+            Some(user) if user.anonymized => {
+                return Err(RepositoryError::Conflict(Conflict::Anonymized));
+            }
+            Some(user) => user.email.to_lowercase(),
+            None => {
+                return Err(RepositoryError::NotFound);
+            }
+        };
+        if state.email_taken_by_other(&new_user.email, Some(id)) {
+            return Err(RepositoryError::Conflict(Conflict::EmailTaken));
+        }
+
+        let user = User::new(Some(id), new_user.name.clone(), new_user.email.clone(), false);
+        if !dry_run {
+            state.emails.remove(&old_email);
+            state.emails.insert(user.email.to_lowercase(), id);
+            state.users.insert(id, user.clone());
+        }
+        Ok(user)
+    }
+
+    fn delete(&self, id: i32, dry_run: bool) -> Result<(), RepositoryError> {
+        let mut state = self.write();
+        let user = state.users.get(&id).ok_or(RepositoryError::NotFound)?;
+        if !dry_run {
+            let email = user.email.to_lowercase();
+            state.emails.remove(&email);
+            state.users.remove(&id);
+            state.password_hashes.remove(&id);
+            state.roles.remove(&id);
+        }
+        Ok(())
+    }
+
+    fn email_taken(&self, email: &str) -> Result<bool, RepositoryError> {
+        Ok(self.read().email_taken_by_other(email, None))
+    }
+
+    fn ping(&self) -> Result<(), RepositoryError> {
+        Ok(())
+    }
+
+    fn credentials(&self, email: &str) -> Result<Option<Account>, RepositoryError> {
+        let state = self.read();
+        let id = state.emails.get(&email.to_lowercase()).copied();
+        Ok(id.map(|id| Account {
+            id,
+            password_hash: state.password_hashes.get(&id).cloned(),
+            role: state.roles.get(&id).copied().unwrap_or(Role::Writer),
+        }))
+    }
+
+    fn password_hash(&self, id: i32) -> Result<Option<String>, RepositoryError> {
+        let state = self.read();
+        if !state.users.contains_key(&id) {
+            return Err(RepositoryError::NotFound);
+        }
+        Ok(state.password_hashes.get(&id).cloned())
+    }
+
+    fn set_password_hash(&self, id: i32, hash: &str) -> Result<(), RepositoryError> {
+        let mut state = self.write();
+        if !state.users.contains_key(&id) {
+            return Err(RepositoryError::NotFound);
+        }
+        state.password_hashes.insert(id, hash.to_owned());
+        Ok(())
+    }
+
+    fn set_role(&self, id: i32, role: Role) -> Result<(), RepositoryError> {
+        let mut state = self.write();
+        if !state.users.contains_key(&id) {
+            return Err(RepositoryError::NotFound);
+        }
+        state.roles.insert(id, role);
+        Ok(())
+    }
+
+    // Nothing runs to be cancelled, the caller just waits
+    fn sleep(&self, duration: Duration) -> Result<(), RepositoryError> {
+        thread::sleep(duration);
+        Ok(())
+    }
+}
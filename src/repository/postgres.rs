@@ -0,0 +1,665 @@
+use postgres::error::SqlState;
+use postgres::types::ToSql;
+use postgres::{ IsolationLevel, Row, Transaction };
+use std::cell::Cell;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+use crate::outbox::{ self, Event };
+use crate::pool::{ self, Pool, PoolError, PooledClient, ReadError, TransactionError, TransactionOptions };
+use crate::auth::Role;
+use crate::repository::{
+    contains_pattern,
+    stored_role,
+    Account,
+    Conflict,
+    Created,
+    IdempotencyKey,
+    RepositoryError,
+    UserFilter,
+    UserRepository,
+};
+use crate::validation::NewUser;
+use crate::models::User;
+use crate::{ encryption, idempotency, tables, tenant };
+
+// The statements run by most requests, prepared once per connection. The table
+// names are between braces, see tables::sql. Every query is for the rows of one
+// tenant, the one of the request: the others' don't exist as far as it can tell.
+// Encrypted emails are found by their hash, the others as they are, see
+// encryption::open_email.
+const SELECT_USER_QUERY: &str =
+    "SELECT id, name, email, anonymized_at IS NOT NULL, email_hash IS NOT NULL, verified_at FROM {users}
+    WHERE id = $1 AND tenant_id = $2";
+const SELECT_USERS_QUERY: &str =
+    "SELECT id, name, email, anonymized_at IS NOT NULL, email_hash IS NOT NULL, verified_at FROM {users}
+    WHERE tenant_id = $3
+        AND ($1::text IS NULL OR email_hash = $4 OR (email_hash IS NULL AND lower(email) = lower($1)))
+        AND ($2::text IS NULL OR name ILIKE $2)
+        AND ($5::int IS NULL OR id > $5)
+    ORDER BY id";
+const INSERT_USER_QUERY: &str =
+    "INSERT INTO {users} (name, email, email_hash, tenant_id, password_hash) VALUES ($1, $2, $3, $4, $5) RETURNING id";
+const UPDATE_USER_QUERY: &str =
+    "UPDATE {users} SET name=$2, email=$3, email_hash=$4 WHERE id=$1 AND tenant_id=$5 AND anonymized_at IS NULL";
+const DELETE_USER_QUERY: &str = "DELETE FROM {users} WHERE id = $1 AND tenant_id = $2";
+
+// How long reads stay on the primary once the replica couldn't be reached
+const REPLICA_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+// The rows of GET /users read at a time
+const FETCH_SIZE: i32 = 500;
+
+// Everything the API stores, emptied by POST /admin/reset, whatever the tenant
+const RESET_QUERY: &str =
+    "TRUNCATE {users}, {sessions}, {refresh_tokens}, {verification_tokens}, {password_reset_tokens}, {events_outbox},
+    {idempotency_keys} RESTART IDENTITY";
+
+thread_local! {
+    // Set while the work of with_transaction runs
+    static IN_TRANSACTION: Cell<bool> = const { Cell::new(false) };
+}
+
+// Users in Postgres. Every change records its event in the outbox, in the same
+// transaction. With a replica, find, list and the email lookup of validation
+// read from it; everything else, exports included, uses the primary.
+pub struct PostgresRepository {
+    pool: &'static Pool,
+    replica: Option<&'static Pool>,
+    // Set when the replica couldn't be reached, reads skip it until then
+    replica_down_until: Mutex<Option<Instant>>,
+}
+
+impl PostgresRepository {
+    pub fn new(pool: &'static Pool, replica: Option<&'static Pool>) -> Self {
+        PostgresRepository { pool, replica, replica_down_until: Mutex::new(None) }
+    }
+
+    // Run work in a transaction on the primary, as PooledClient::with_transaction
+    // does. Called again from inside work, it would check out another connection
+    // for a transaction of its own, which wouldn't roll back with the first one:
+    // that is refused, work should use the transaction it is given.
+    pub fn with_transaction<T>(
+        &self,
+        options: TransactionOptions,
+        work: impl FnMut(&mut Transaction<'_>) -> Result<T, RepositoryError>
+    ) -> Result<T, RepositoryError> {
+        if IN_TRANSACTION.get() {
+            return Err(RepositoryError::Db("with_transaction called inside another transaction".into()));
+        }
+        let mut client = self.pool.get()?;
+        IN_TRANSACTION.set(true);
+        let _in_transaction = InTransaction;
+        client.with_transaction(options, work)
+    }
+
+    // A read from the replica, or from the primary while the replica is down
+    fn replica_read<T>(
+        &self,
+        mut operation: impl FnMut(&mut PooledClient) -> Result<T, postgres::Error>
+    ) -> Result<T, ReadError> {
+        let replica = self.replica.filter(|_| {
+            let mut down_until = self.replica_down_until.lock().unwrap();
+            match *down_until {
+                Some(until) if Instant::now() < until => false,
+                _ => {
+                    *down_until = None;
+                    true
+                }
+            }
+        });
+
+        if let Some(replica) = replica {
+            let error = match replica.read(&mut operation) {
+                Err(ReadError::Unavailable(e)) => crate::errors::with_causes(&e),
+                Err(ReadError::Query(e)) if pool::is_connection_error(&e) => crate::errors::with_causes(&e),
+                result => {
+                    return result;
+                }
+            };
+            log::warn!(
+                "Read replica unavailable, reading from the primary for the next {}s: {}",
+                REPLICA_RETRY_INTERVAL.as_secs(),
+                error
+            );
+            *self.replica_down_until.lock().unwrap() = Some(Instant::now() + REPLICA_RETRY_INTERVAL);
+        }
+        self.pool.read(operation)
+    }
+}
+
+impl UserRepository for PostgresRepository {
+    fn find(&self, id: i32) -> Result<User, RepositoryError> {
+        let tenant = tenant::current();
+        let row = self.replica_read(|client| {
+            client.query_opt_cached(tables::sql(SELECT_USER_QUERY), &[&id, &tenant])
+        })?;
+        row.map(|row| user_from_row(&row)).ok_or(RepositoryError::NotFound)?
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        let name_pattern = filter.name_contains.as_deref().map(contains_pattern);
+        let email_hash = filter.email.as_deref().and_then(encryption::lookup_hash);
+        let tenant = tenant::current();
+        let rows = self.replica_read(|client| {
+            let params: [&(dyn ToSql + Sync); 5] =
+                [&filter.email, &name_pattern, &tenant, &email_hash, &filter.after_id];
+            client.query_cached(tables::sql(SELECT_USERS_QUERY), &params)
+        })?;
+        rows.iter().map(user_from_row).collect()
+    }
+
+    // The rows come FETCH_SIZE at a time. When the query is run again after a
+    // connection failure the users handed out already are skipped, they are ordered
+    // by id.
+    fn list_each(&self, filter: &UserFilter, each: &mut dyn FnMut(User) -> bool) -> Result<(), RepositoryError> {
+        let name_pattern = filter.name_contains.as_deref().map(contains_pattern);
+        let email_hash = filter.email.as_deref().and_then(encryption::lookup_hash);
+        let tenant = tenant::current();
+        let (mut last_id, mut failed) = (None, None);
+        self.replica_read(|client| {
+            let params: [&(dyn ToSql + Sync); 5] =
+                [&filter.email, &name_pattern, &tenant, &email_hash, &filter.after_id];
+            client.for_each_row_cached(tables::sql(SELECT_USERS_QUERY), &params, FETCH_SIZE, |row| {
+                let id: i32 = row.get(0);
+                if last_id.is_some_and(|last_id| id <= last_id) {
+                    return true;
+                }
+                last_id = Some(id);
+                match user_from_row(row) {
+                    Ok(user) => each(user),
+                    Err(e) => {
+                        failed = Some(e);
+                        false
+                    }
+                }
+            })
+        })?;
+        failed.map_or(Ok(()), Err)
+    }
+
+    fn create(
+        &self,
+        new_user: &NewUser,
+        dry_run: bool,
+        idempotency: Option<&IdempotencyKey>
+    ) -> Result<Created, RepositoryError> {
+        let mut user = User::new(None, new_user.name.clone(), new_user.email.clone(), false);
+        let tenant = tenant::current();
+        let mut client = self.pool.get()?;
+        let result = client.transaction_with_statements(|mut transaction, statements| {
+            if let Some(idempotency) = idempotency {
+                match idempotency::claim(&mut transaction, &tenant, idempotency.key, &idempotency.request_hash)? {
+                    idempotency::Claim::New => {}
+                    idempotency::Claim::Replay(status_line, body) => {
+                        return Ok(Ok(Created::Replay(status_line, body)));
+                    }
+                    idempotency::Claim::Mismatch => {
+                        return Ok(Ok(Created::KeyMismatch));
+                    }
+                    idempotency::Claim::Unreadable(e) => {
+                        return Ok(Err(RepositoryError::Db(e.into())));
+                    }
+                }
+            }
+
+            // Checked after the claim so that a replayed request doesn't find its own user
+            if email_taken(&mut transaction, &tenant, &user.email, None)? {
+                return Ok(Err(RepositoryError::Conflict(Conflict::EmailTaken)));
+            }
+
+            let insert = statements.prepare(&mut transaction, tables::sql(INSERT_USER_QUERY))?;
+            let (email, email_hash) = encryption::seal_email(&user.email);
+            let params: [&(dyn ToSql + Sync); 5] = [&user.name, &email, &email_hash, &tenant, &new_user.password_hash];
+            let row = transaction.query_one(&insert, &params)?;
+            user.id = row.get(0);
+            outbox::enqueue(&mut transaction, &tenant, "user.created", &serde_json::to_value(&user).unwrap())?;
+
+            if dry_run {
+                // The id came from a sequence that is rolled back with the rest
+                user.id = None;
+                finish(transaction, dry_run)?;
+                return Ok(Ok(Created::User(user.clone())));
+            }
+
+            if let Some(idempotency) = idempotency {
+                let (status_line, body) = (idempotency.response)(&user);
+                let id = user.id.unwrap();
+                idempotency::complete(&mut transaction, &tenant, idempotency.key, id, &status_line, &body)?;
+            }
+            transaction.commit()?;
+            Ok(Ok(Created::User(user.clone())))
+        });
+
+        result?
+    }
+
+    fn update(&self, id: i32, new_user: &NewUser, dry_run: bool) -> Result<User, RepositoryError> {
+        let user = User::new(Some(id), new_user.name.clone(), new_user.email.clone(), false);
+        let tenant = tenant::current();
+        let mut client = self.pool.get()?;
+        let result = client.transaction_with_statements(|mut transaction, statements| {
+            let update = statements.prepare(&mut transaction, tables::sql(UPDATE_USER_QUERY))?;
+            let (email, email_hash) = encryption::seal_email(&user.email);
+            let rows_affected = transaction.execute(&update, &[&id, &user.name, &email, &email_hash, &tenant])?;
+            if rows_affected == 1 {
+                // The unique index can't tell an encrypted email from the same one
+                // stored as it is, before the key was set. Dropping the transaction
+                // rolls the update back.
+                if email_taken(&mut transaction, &tenant, &user.email, Some(id))? {
+                    return Ok(Err(RepositoryError::Conflict(Conflict::EmailTaken)));
+                }
+                outbox::enqueue(&mut transaction, &tenant, "user.updated", &serde_json::to_value(&user).unwrap())?;
+                finish(transaction, dry_run)?;
+                return Ok(Ok(()));
+            }
+
+            // Anonymization is irreversible, so anonymized users can't be changed back
+            let anonymized = transaction
+                .query_opt(
+                    tables::sql("SELECT 1 FROM {users} WHERE id = $1 AND tenant_id = $2 AND anonymized_at IS NOT NULL"),
+                    &[&id, &tenant]
+                )?
+                .is_some();
+            Ok(Err(not_updated(anonymized)))
+        });
+
+        result?.map(|_| user)
+    }
+
+    fn delete(&self, id: i32, dry_run: bool) -> Result<(), RepositoryError> {
+        let tenant = tenant::current();
+        let mut client = self.pool.get()?;
+        let deleted = client.transaction_with_statements(|mut transaction, statements| {
+            let delete = statements.prepare(&mut transaction, tables::sql(DELETE_USER_QUERY))?;
+            let rows_affected = transaction.execute(&delete, &[&id, &tenant])?;
+            if rows_affected == 1 {
+                outbox::enqueue(&mut transaction, &tenant, "user.deleted", &serde_json::json!({ "id": id }))?;
+            }
+            finish(transaction, dry_run)?;
+            Ok(rows_affected == 1)
+        })?;
+
+        if deleted { Ok(()) } else { Err(RepositoryError::NotFound) }
+    }
+
+    fn email_taken(&self, email: &str) -> Result<bool, RepositoryError> {
+        let tenant = tenant::current();
+        Ok(self.replica_read(|client| email_taken(&mut **client, &tenant, email, None))?)
+    }
+
+    // From the primary, so that the users can log in as soon as they are created
+    fn credentials(&self, email: &str) -> Result<Option<Account>, RepositoryError> {
+        let tenant = tenant::current();
+        let query = tables::sql(
+            "SELECT id, password_hash, role FROM {users}
+            WHERE tenant_id = $2 AND (email_hash = $3 OR (email_hash IS NULL AND lower(email) = lower($1)))"
+        );
+        let lookup_hash = encryption::lookup_hash(email);
+        let row = self.pool.read(|client| client.query_opt(query, &[&email, &tenant, &lookup_hash]))?;
+        Ok(row.map(|row| Account { id: row.get(0), password_hash: row.get(1), role: stored_role(row.get(2)) }))
+    }
+
+    // From the primary too, it is what the new password is checked against
+    fn password_hash(&self, id: i32) -> Result<Option<String>, RepositoryError> {
+        let tenant = tenant::current();
+        let query = tables::sql("SELECT password_hash FROM {users} WHERE id = $1 AND tenant_id = $2");
+        let row = self.pool.read(|client| client.query_opt(query, &[&id, &tenant]))?;
+        row.map(|row| row.get(0)).ok_or(RepositoryError::NotFound)
+    }
+
+    // Not for the anonymized users, who have no password to change
+    fn set_password_hash(&self, id: i32, hash: &str) -> Result<(), RepositoryError> {
+        let tenant = tenant::current();
+        let query = tables::sql(
+            "UPDATE {users} SET password_hash = $3 WHERE id = $1 AND tenant_id = $2 AND anonymized_at IS NULL"
+        );
+        let rows_affected = self.pool.get()?.execute(query, &[&id, &tenant, &hash])?;
+        if rows_affected == 1 { Ok(()) } else { Err(RepositoryError::NotFound) }
+    }
+
+    // The tokens already issued keep the role they were issued with until they expire
+    fn set_role(&self, id: i32, role: Role) -> Result<(), RepositoryError> {
+        let tenant = tenant::current();
+        let query = tables::sql("UPDATE {users} SET role = $3 WHERE id = $1 AND tenant_id = $2");
+        let rows_affected = self.pool.get()?.execute(query, &[&id, &tenant, &role.as_str()])?;
+        if rows_affected == 1 { Ok(()) } else { Err(RepositoryError::NotFound) }
+    }
+
+    fn ping(&self) -> Result<(), RepositoryError> {
+        self.pool.read(|client| client.simple_query("SELECT 1"))?;
+        Ok(())
+    }
+
+    // The row and its id stay, along with the events scrubbed of the personal data
+    fn anonymize(&self, id: i32, dry_run: bool) -> Result<User, RepositoryError> {
+        let tenant = tenant::current();
+        let mut client = self.pool.get()?;
+        let row = client.transaction_with_statements(|mut transaction, _| {
+            let existing = transaction.query_opt(
+                tables::sql(
+                    "SELECT id, name, email, anonymized_at IS NOT NULL, email_hash IS NOT NULL, verified_at
+                    FROM {users} WHERE id = $1 AND tenant_id = $2 FOR UPDATE"
+                ),
+                &[&id, &tenant]
+            )?;
+
+            let row = match existing {
+                Some(row) if row.get::<_, bool>(3) => row,
+                Some(_) => {
+                    let row = transaction.query_one(
+                        tables::sql(
+                            "UPDATE {users} SET name = 'Deleted User',
+                                email = 'anon-' || md5(random()::text || clock_timestamp()::text) || '@example.invalid',
+                                email_hash = NULL, password_hash = NULL, anonymized_at = now()
+                            WHERE id = $1 RETURNING id, name, email, anonymized_at IS NOT NULL, false, verified_at"
+                        ),
+                        &[&id]
+                    )?;
+
+                    // The made up email of an anonymized user is never encrypted
+                    outbox::scrub_user_events(&mut transaction, &tenant, id, row.get(1), row.get(2))?;
+                    idempotency::forget_user(&mut transaction, &tenant, id)?;
+                    transaction.execute(tables::sql("DELETE FROM {sessions} WHERE user_id = $1"), &[&id])?;
+                    transaction.execute(tables::sql("DELETE FROM {refresh_tokens} WHERE user_id = $1"), &[&id])?;
+                    transaction.execute(tables::sql("DELETE FROM {verification_tokens} WHERE user_id = $1"), &[&id])?;
+                    transaction.execute(tables::sql("DELETE FROM {password_reset_tokens} WHERE user_id = $1"), &[&id])?;
+                    outbox::enqueue(&mut transaction, &tenant, "user.anonymized", &serde_json::json!({ "id": id }))?;
+                    row
+                }
+                None => {
+                    return Ok(None);
+                }
+            };
+
+            finish(transaction, dry_run)?;
+            Ok(Some(row))
+        })?;
+
+        row.map(|row| user_from_row(&row)).ok_or(RepositoryError::NotFound)?
+    }
+
+    fn export(&self, id: i32) -> Result<(User, Vec<Event>), RepositoryError> {
+        let options = TransactionOptions { isolation_level: Some(IsolationLevel::RepeatableRead), read_only: true };
+        let tenant = tenant::current();
+        let export = self.pool.read(|client| {
+            client.with_transaction(options, |transaction| {
+                pool::set_local_statement_timeout(transaction, self.pool.config().export_statement_timeout)?;
+                let row = match transaction.query_opt(tables::sql(SELECT_USER_QUERY), &[&id, &tenant])? {
+                    Some(row) => row,
+                    None => {
+                        return Ok(None);
+                    }
+                };
+                let history = outbox::fetch_for_user(transaction, &tenant, id)?;
+                Ok(Some((row, history)))
+            })
+        })?;
+
+        let (row, history) = export.ok_or(RepositoryError::NotFound)?;
+        Ok((user_from_row(&row)?, history))
+    }
+
+    fn events_since(&self, since_id: i64) -> Result<Vec<Event>, RepositoryError> {
+        let tenant = tenant::current();
+        Ok(self.pool.read(|client| outbox::fetch_since(&mut **client, &tenant, since_id))?)
+    }
+
+    fn reset(&self) -> Result<(), RepositoryError> {
+        self.pool.get()?.batch_execute(tables::sql(RESET_QUERY))?;
+        Ok(())
+    }
+
+    fn seed(&self, users: Vec<User>) -> Result<Vec<i32>, RepositoryError> {
+        let tenant = tenant::current();
+        self.with_transaction(TransactionOptions::default(), |transaction| {
+            let mut ids = Vec::new();
+            for user in &users {
+                // Skip generated emails that happen to exist already
+                let (email, email_hash) = encryption::seal_email(&user.email);
+                let row = transaction.query_opt(
+                    tables::sql(
+                        "INSERT INTO {users} (name, email, email_hash, tenant_id) VALUES ($1, $2, $3, $4)
+                        ON CONFLICT DO NOTHING RETURNING id"
+                    ),
+                    &[&user.name, &email, &email_hash, &tenant]
+                )?;
+                if let Some(row) = row {
+                    let user = User::new(row.get(0), user.name.clone(), user.email.clone(), false);
+                    outbox::enqueue(transaction, &tenant, "user.created", &serde_json::to_value(&user).unwrap())?;
+                    ids.extend(user.id);
+                }
+            }
+            Ok(ids)
+        })
+    }
+
+    fn sleep(&self, duration: Duration) -> Result<(), RepositoryError> {
+        let mut client = self.pool.get()?;
+        client.execute("SELECT pg_sleep($1)", &[&duration.as_secs_f64()])?;
+        Ok(())
+    }
+}
+
+struct InTransaction;
+
+impl Drop for InTransaction {
+    fn drop(&mut self) {
+        IN_TRANSACTION.set(false);
+    }
+}
+
+fn finish(transaction: Transaction, dry_run: bool) -> Result<(), postgres::Error> {
+    if dry_run { transaction.rollback() } else { transaction.commit() }
+}
+
+fn is_unique_violation(error: &postgres::Error) -> bool {
+    error.code() == Some(&SqlState::UNIQUE_VIOLATION)
+}
+
+// From id, name, email, whether anonymized, whether the email is encrypted and
+// when it was verified
+fn user_from_row(row: &Row) -> Result<User, RepositoryError> {
+    let id: i32 = row.get(0);
+    let email = encryption::open_email(row.get(2), row.get(4))
+        .map_err(|e| RepositoryError::Db(format!("can't read the email of user {}: {}", id, e).into()))?;
+    let mut user = User::new(Some(id), row.get(1), email, row.get(3));
+    user.verified_at = row.get(5);
+    Ok(user)
+}
+
+fn not_updated(anonymized: bool) -> RepositoryError {
+    if anonymized { RepositoryError::Conflict(Conflict::Anonymized) } else { RepositoryError::NotFound }
+}
+
+// By another user than except, whether the email is encrypted or stored as it is
+fn email_taken(
+    client: &mut impl postgres::GenericClient,
+    tenant: &str,
+    email: &str,
+    except: Option<i32>
+) -> Result<bool, postgres::Error> {
+    let query = tables::sql(
+        "SELECT 1 FROM {users}
+        WHERE tenant_id = $2 AND (email_hash = $3 OR (email_hash IS NULL AND lower(email) = lower($1)))
+            AND id IS DISTINCT FROM $4"
+    );
+    Ok(client.query_opt(query, &[&email, &tenant, &encryption::lookup_hash(email), &except])?.is_some())
+}
+
+impl From<postgres::Error> for RepositoryError {
+    fn from(error: postgres::Error) -> Self {
+        if is_unique_violation(&error) {
+            // A concurrent request took the email between the check and the insert
+            RepositoryError::Conflict(Conflict::EmailTaken)
+        } else if pool::is_connection_error(&error) {
+            RepositoryError::Unavailable(error.into())
+        } else if pool::is_transaction_conflict(&error) {
+            // Still failing after the retries of the transaction helpers
+            RepositoryError::Conflict(Conflict::Concurrent)
+        } else if pool::is_query_canceled(&error) {
+            RepositoryError::Timeout(error.into())
+        } else {
+            RepositoryError::Db(error.into())
+        }
+    }
+}
+
+impl TransactionError for RepositoryError {
+    fn is_stale_statement(&self) -> bool {
+        match self {
+            RepositoryError::Db(e) => e.downcast_ref::<postgres::Error>().is_some_and(pool::is_stale_statement),
+            _ => false,
+        }
+    }
+
+    fn is_transaction_conflict(&self) -> bool {
+        matches!(self, RepositoryError::Conflict(Conflict::Concurrent))
+    }
+}
+
+impl From<PoolError> for RepositoryError {
+    fn from(error: PoolError) -> Self {
+        RepositoryError::Unavailable(error.into())
+    }
+}
+
+impl From<ReadError> for RepositoryError {
+    fn from(error: ReadError) -> Self {
+        match error {
+            ReadError::Unavailable(e) => e.into(),
+            ReadError::Query(e) => e.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+    use postgres::types::ToSql;
+    use std::panic::{ self, AssertUnwindSafe };
+
+    fn repository() -> Option<PostgresRepository> {
+        let pool = Box::leak(Box::new(pool::test_pool()?));
+        Some(PostgresRepository::new(pool, None))
+    }
+
+    #[test]
+    fn with_transaction_rolls_back_on_err_and_panic() {
+        let Some(repository) = repository() else {
+            return;
+        };
+        let marker = format!("rollback-{}", std::process::id());
+        let insert = |transaction: &mut Transaction| -> Result<(), RepositoryError> {
+            transaction.execute("INSERT INTO transaction_test VALUES ($1)", &[&marker])?;
+            Ok(())
+        };
+        let count = || -> i64 {
+            let mut client = repository.pool.get().unwrap();
+            client.query_one("SELECT count(*) FROM transaction_test WHERE marker = $1", &[&marker]).unwrap().get(0)
+        };
+        repository.pool.get().unwrap().batch_execute("CREATE TABLE IF NOT EXISTS transaction_test (marker TEXT)").unwrap();
+
+        let result = repository.with_transaction(TransactionOptions::default(), |transaction| {
+            insert(transaction)?;
+            Err::<(), _>(RepositoryError::NotFound)
+        });
+        assert!(matches!(result, Err(RepositoryError::NotFound)));
+        assert_eq!(count(), 0);
+
+        let result = panic::catch_unwind(
+            AssertUnwindSafe(|| {
+                repository.with_transaction(TransactionOptions::default(), |transaction| -> Result<(), _> {
+                    insert(transaction)?;
+                    panic!("in the middle of the transaction");
+                })
+            })
+        );
+        assert!(result.is_err());
+        assert_eq!(count(), 0);
+
+        repository.with_transaction(TransactionOptions::default(), insert).unwrap();
+        assert_eq!(count(), 1);
+        repository.pool.get().unwrap().execute("DELETE FROM transaction_test WHERE marker = $1", &[&marker]).unwrap();
+    }
+
+    #[test]
+    fn with_transaction_applies_its_options() {
+        let Some(repository) = repository() else {
+            return;
+        };
+        let show = |transaction: &mut Transaction| -> Result<(String, String), RepositoryError> {
+            let isolation_level = transaction.query_one("SHOW transaction_isolation", &[])?.get(0);
+            let read_only = transaction.query_one("SHOW transaction_read_only", &[])?.get(0);
+            Ok((isolation_level, read_only))
+        };
+
+        let settings = repository.with_transaction(TransactionOptions::default(), show).unwrap();
+        assert_eq!(settings, ("read committed".to_owned(), "off".to_owned()));
+
+        let options = TransactionOptions { isolation_level: Some(IsolationLevel::Serializable), read_only: true };
+        let settings = repository.with_transaction(options, show).unwrap();
+        assert_eq!(settings, ("serializable".to_owned(), "on".to_owned()));
+    }
+
+    #[test]
+    fn with_transaction_refuses_to_nest() {
+        let Some(repository) = repository() else {
+            return;
+        };
+        let result = repository.with_transaction(TransactionOptions::default(), |_| {
+            repository.with_transaction(TransactionOptions::default(), |_| Ok(()))
+        });
+        assert!(result.unwrap_err().to_string().contains("inside another transaction"));
+
+        // Nesting is only refused while the outer transaction runs
+        repository.with_transaction(TransactionOptions::default(), |_| Ok(())).unwrap();
+    }
+
+    fn plan(client: &mut PooledClient, query: &str, params: &[&(dyn ToSql + Sync)]) -> String {
+        let rows = client.query(&format!("EXPLAIN {}", query), params).unwrap();
+        rows.iter().map(|row| row.get::<_, String>(0)).collect::<Vec<_>>().join("\n")
+    }
+
+    // On a table of many tenants, the queries of one of them don't read it all
+    #[test]
+    fn the_queries_of_a_tenant_use_indexes() {
+        let Some(repository) = repository() else {
+            return;
+        };
+        let mut client = repository.pool.get().unwrap();
+        client
+            .batch_execute(
+                "DROP SCHEMA IF EXISTS explain_test CASCADE; CREATE SCHEMA explain_test;
+                SET search_path TO explain_test"
+            )
+            .unwrap();
+        migrations::apply(&mut client).unwrap();
+        client
+            .batch_execute(
+                "INSERT INTO users (name, email, tenant_id)
+                SELECT 'User ' || i, 'user' || i || '@example.com', 'tenant-' || i % 200
+                FROM generate_series(1, 40000) i;
+                ANALYZE users"
+            )
+            .unwrap();
+
+        let tenant = "tenant-7";
+        let no_hash = None::<&str>;
+        let mut users = |email: Option<&str>, name: Option<&str>, after_id: Option<i32>| {
+            plan(&mut client, tables::sql(SELECT_USERS_QUERY), &[&email, &name, &tenant, &no_hash, &after_id])
+        };
+        let plans = [
+            users(None, None, None),
+            users(Some("user7@example.com"), None, None),
+            users(None, Some("%User 1%"), None),
+            users(None, None, Some(500)),
+            plan(&mut client, tables::sql(SELECT_USER_QUERY), &[&7, &tenant]),
+        ];
+        for plan in &plans {
+            assert!(!plan.contains("Seq Scan"), "{}", plan);
+        }
+
+        client.batch_execute("RESET search_path; DROP SCHEMA explain_test CASCADE").unwrap();
+    }
+}
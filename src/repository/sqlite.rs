@@ -0,0 +1,206 @@
+use rusqlite::{ ffi, params, Connection, OptionalExtension };
+use std::sync::Mutex;
+
+use crate::auth::Role;
+use crate::repository::{
+    contains_pattern,
+    stored_role,
+    Account,
+    Conflict,
+    Created,
+    IdempotencyKey,
+    RepositoryError,
+    UserFilter,
+    UserRepository,
+};
+use crate::validation::NewUser;
+use crate::models::User;
+
+// The users table of the Postgres migrations in SQLite's dialect. Nothing else is
+// created: the outbox and the idempotency keys only exist with Postgres.
+const CREATE_USERS_QUERY: &str =
+    "CREATE TABLE IF NOT EXISTS users (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL,
+        email TEXT NOT NULL,
+        anonymized_at TEXT,
+        password_hash TEXT,
+        role TEXT NOT NULL DEFAULT 'writer'
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS users_email_lower_key ON users (lower(email));";
+
+// For the files created before there were passwords, or roles
+const ADD_COLUMN_QUERIES: [(&str, &str); 2] = [
+    ("password_hash", "ALTER TABLE users ADD COLUMN password_hash TEXT"),
+    ("role", "ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'writer'"),
+];
+
+const SELECT_USER_QUERY: &str = "SELECT id, name, email, anonymized_at IS NOT NULL FROM users WHERE id = ?1";
+const SELECT_USERS_QUERY: &str =
+    "SELECT id, name, email, anonymized_at IS NOT NULL FROM users
+    WHERE (?1 IS NULL OR lower(email) = lower(?1)) AND (?2 IS NULL OR name LIKE ?2 ESCAPE '\\')
+        AND (?3 IS NULL OR id > ?3)
+    ORDER BY id";
+const INSERT_USER_QUERY: &str = "INSERT INTO users (name, email, password_hash) VALUES (?1, ?2, ?3)";
+const UPDATE_USER_QUERY: &str = "UPDATE users SET name = ?2, email = ?3 WHERE id = ?1 AND anonymized_at IS NULL";
+const DELETE_USER_QUERY: &str = "DELETE FROM users WHERE id = ?1";
+
+// Users in a SQLite file, or in memory, for running without Postgres. Only the
+// CRUD endpoints are served: changes record no events and Idempotency-Key isn't
+// supported.
+pub struct SqliteRepository {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteRepository {
+    // sqlite://path, sqlite://:memory: or :memory:
+    pub fn open(url: &str) -> Result<Self, rusqlite::Error> {
+        let path = url.strip_prefix("sqlite://").unwrap_or(url);
+        let connection = if path == ":memory:" { Connection::open_in_memory()? } else { Connection::open(path)? };
+        connection.execute_batch(CREATE_USERS_QUERY)?;
+        for (column, query) in ADD_COLUMN_QUERIES {
+            let exists = connection
+                .prepare("SELECT 1 FROM pragma_table_info('users') WHERE name = ?1")?
+                .exists([column])?;
+            if !exists {
+                connection.execute_batch(query)?;
+            }
+        }
+        Ok(SqliteRepository { connection: Mutex::new(connection) })
+    }
+
+    pub fn handles(url: &str) -> bool {
+        url.starts_with("sqlite://") || url == ":memory:"
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // A panic mid-request leaves nothing half done, the transactions roll back on drop
+        self.connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl UserRepository for SqliteRepository {
+    fn find(&self, id: i32) -> Result<User, RepositoryError> {
+        let connection = self.connection();
+        let user = connection.query_row(SELECT_USER_QUERY, [id], user_from_row).optional()?;
+        user.ok_or(RepositoryError::NotFound)
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        let name_pattern = filter.name_contains.as_deref().map(contains_pattern);
+        let connection = self.connection();
+        let mut statement = connection.prepare_cached(SELECT_USERS_QUERY)?;
+        let users = statement.query_map(params![filter.email, name_pattern, filter.after_id], user_from_row)?;
+        Ok(users.collect::<Result<_, _>>()?)
+    }
+
+    fn create(
+        &self,
+        new_user: &NewUser,
+        dry_run: bool,
+        idempotency: Option<&IdempotencyKey>
+    ) -> Result<Created, RepositoryError> {
+        if idempotency.is_some() {
+            return Err(RepositoryError::Unsupported("Idempotency-Key is only supported with Postgres"));
+        }
+
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        if email_taken(&transaction, &new_user.email)? {
+            return Err(RepositoryError::Conflict(Conflict::EmailTaken));
+        }
+        transaction.execute(INSERT_USER_QUERY, params![new_user.name, new_user.email, new_user.password_hash])?;
+        let id = if dry_run { None } else { Some(transaction.last_insert_rowid() as i32) };
+        finish(transaction, dry_run)?;
+
+        Ok(Created::User(User::new(id, new_user.name.clone(), new_user.email.clone(), false)))
+    }
+
+    fn update(&self, id: i32, new_user: &NewUser, dry_run: bool) -> Result<User, RepositoryError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let rows_affected = transaction.execute(UPDATE_USER_QUERY, params![id, new_user.name, new_user.email])?;
+        if rows_affected == 0 {
+            let anonymized = transaction
+                .query_row("SELECT 1 FROM users WHERE id = ?1 AND anonymized_at IS NOT NULL", [id], |_| Ok(()))
+                .optional()?
+                .is_some();
+            return Err(
+                if anonymized { RepositoryError::Conflict(Conflict::Anonymized) } else { RepositoryError::NotFound }
+            );
+        }
+        finish(transaction, dry_run)?;
+
+        Ok(User::new(Some(id), new_user.name.clone(), new_user.email.clone(), false))
+    }
+
+    fn delete(&self, id: i32, dry_run: bool) -> Result<(), RepositoryError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let rows_affected = transaction.execute(DELETE_USER_QUERY, [id])?;
+        finish(transaction, dry_run)?;
+
+        if rows_affected == 1 { Ok(()) } else { Err(RepositoryError::NotFound) }
+    }
+
+    fn email_taken(&self, email: &str) -> Result<bool, RepositoryError> {
+        Ok(email_taken(&self.connection(), email)?)
+    }
+
+    fn ping(&self) -> Result<(), RepositoryError> {
+        self.connection().query_row("SELECT 1", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    fn credentials(&self, email: &str) -> Result<Option<Account>, RepositoryError> {
+        let connection = self.connection();
+        let query = "SELECT id, password_hash, role FROM users WHERE lower(email) = lower(?1)";
+        let row = connection.query_row(query, [email], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?))
+        });
+        Ok(row.optional()?.map(|(id, password_hash, role)| Account { id, password_hash, role: stored_role(&role) }))
+    }
+
+    fn password_hash(&self, id: i32) -> Result<Option<String>, RepositoryError> {
+        let connection = self.connection();
+        let query = "SELECT password_hash FROM users WHERE id = ?1";
+        connection.query_row(query, [id], |row| row.get(0)).optional()?.ok_or(RepositoryError::NotFound)
+    }
+
+    fn set_password_hash(&self, id: i32, hash: &str) -> Result<(), RepositoryError> {
+        let query = "UPDATE users SET password_hash = ?2 WHERE id = ?1";
+        let rows_affected = self.connection().execute(query, params![id, hash])?;
+        if rows_affected == 1 { Ok(()) } else { Err(RepositoryError::NotFound) }
+    }
+
+    fn set_role(&self, id: i32, role: Role) -> Result<(), RepositoryError> {
+        let query = "UPDATE users SET role = ?2 WHERE id = ?1";
+        let rows_affected = self.connection().execute(query, params![id, role.as_str()])?;
+        if rows_affected == 1 { Ok(()) } else { Err(RepositoryError::NotFound) }
+    }
+}
+
+fn email_taken(connection: &Connection, email: &str) -> Result<bool, rusqlite::Error> {
+    let row = connection.query_row("SELECT 1 FROM users WHERE lower(email) = lower(?1)", [email], |_| Ok(()));
+    Ok(row.optional()?.is_some())
+}
+
+fn finish(transaction: rusqlite::Transaction, dry_run: bool) -> Result<(), rusqlite::Error> {
+    if dry_run { transaction.rollback() } else { transaction.commit() }
+}
+
+fn user_from_row(row: &rusqlite::Row) -> Result<User, rusqlite::Error> {
+    Ok(User::new(row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+}
+
+impl From<rusqlite::Error> for RepositoryError {
+    fn from(error: rusqlite::Error) -> Self {
+        match error.sqlite_error() {
+            // From users_email_lower_key
+            Some(e) if e.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE => {
+                RepositoryError::Conflict(Conflict::EmailTaken)
+            }
+            _ => RepositoryError::Db(error.into()),
+        }
+    }
+}
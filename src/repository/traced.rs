@@ -0,0 +1,168 @@
+use std::cell::Cell;
+use std::time::{ Duration, Instant };
+use tracing::field::Empty;
+
+use crate::access_log;
+use crate::auth::Role;
+use crate::outbox::Event;
+use crate::repository::{ Account, Created, IdempotencyKey, RepositoryError, UserFilter, UserRepository };
+use crate::validation::NewUser;
+use crate::models::User;
+
+// A repository whose operations each have a db span, of the statement, the table
+// it is mostly about, and the rows it returned or changed once it succeeded. They
+// are counted in the access log of the request too.
+pub struct Traced<'a> {
+    repository: &'a dyn UserRepository,
+}
+
+impl<'a> Traced<'a> {
+    pub fn new(repository: &'a dyn UserRepository) -> Self {
+        Traced { repository }
+    }
+
+    fn call<T>(
+        &self,
+        statement: &str,
+        table: Option<&str>,
+        rows: impl FnOnce(&T) -> usize,
+        operation: impl FnOnce(&dyn UserRepository) -> Result<T, RepositoryError>
+    ) -> Result<T, RepositoryError> {
+        let span = tracing::info_span!("db", statement, table = Empty, rows = Empty);
+        if let Some(table) = table {
+            span.record("table", table);
+        }
+        let _in_db = span.enter();
+        let started = Instant::now();
+        let result = operation(self.repository);
+        access_log::db_called(started.elapsed());
+        if let Ok(result) = &result {
+            span.record("rows", rows(result));
+        }
+        result
+    }
+}
+
+fn one<T>(_: &T) -> usize {
+    1
+}
+
+fn none<T>(_: &T) -> usize {
+    0
+}
+
+impl UserRepository for Traced<'_> {
+    fn find(&self, id: i32) -> Result<User, RepositoryError> {
+        self.call("find", Some("users"), one, |repository| repository.find(id))
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        self.call("list", Some("users"), Vec::len, |repository| repository.list(filter))
+    }
+
+    fn list_each(&self, filter: &UserFilter, each: &mut dyn FnMut(User) -> bool) -> Result<(), RepositoryError> {
+        let listed = Cell::new(0);
+        let mut counted = |user| {
+            listed.set(listed.get() + 1);
+            each(user)
+        };
+        let rows = |_: &()| listed.get();
+        self.call("list_each", Some("users"), rows, |repository| repository.list_each(filter, &mut counted))
+    }
+
+    fn create(
+        &self,
+        user: &NewUser,
+        dry_run: bool,
+        idempotency: Option<&IdempotencyKey>
+    ) -> Result<Created, RepositoryError> {
+        self.call("create", Some("users"), one, |repository| repository.create(user, dry_run, idempotency))
+    }
+
+    fn update(&self, id: i32, user: &NewUser, dry_run: bool) -> Result<User, RepositoryError> {
+        self.call("update", Some("users"), one, |repository| repository.update(id, user, dry_run))
+    }
+
+    fn delete(&self, id: i32, dry_run: bool) -> Result<(), RepositoryError> {
+        self.call("delete", Some("users"), one, |repository| repository.delete(id, dry_run))
+    }
+
+    fn email_taken(&self, email: &str) -> Result<bool, RepositoryError> {
+        self.call("email_taken", Some("users"), |taken| *taken as usize, |repository| repository.email_taken(email))
+    }
+
+    fn ping(&self) -> Result<(), RepositoryError> {
+        self.call("ping", None, none, |repository| repository.ping())
+    }
+
+    fn credentials(&self, email: &str) -> Result<Option<Account>, RepositoryError> {
+        let rows = |account: &Option<Account>| account.is_some() as usize;
+        self.call("credentials", Some("users"), rows, |repository| repository.credentials(email))
+    }
+
+    fn password_hash(&self, id: i32) -> Result<Option<String>, RepositoryError> {
+        self.call("password_hash", Some("users"), one, |repository| repository.password_hash(id))
+    }
+
+    fn set_password_hash(&self, id: i32, hash: &str) -> Result<(), RepositoryError> {
+        self.call("set_password_hash", Some("users"), one, |repository| repository.set_password_hash(id, hash))
+    }
+
+    fn set_role(&self, id: i32, role: Role) -> Result<(), RepositoryError> {
+        self.call("set_role", Some("users"), one, |repository| repository.set_role(id, role))
+    }
+
+    fn anonymize(&self, id: i32, dry_run: bool) -> Result<User, RepositoryError> {
+        self.call("anonymize", Some("users"), one, |repository| repository.anonymize(id, dry_run))
+    }
+
+    fn export(&self, id: i32) -> Result<(User, Vec<Event>), RepositoryError> {
+        let rows = |(_, events): &(User, Vec<Event>)| 1 + events.len();
+        self.call("export", Some("users"), rows, |repository| repository.export(id))
+    }
+
+    fn events_since(&self, since_id: i64) -> Result<Vec<Event>, RepositoryError> {
+        self.call("events_since", Some("events_outbox"), Vec::len, |repository| repository.events_since(since_id))
+    }
+
+    fn reset(&self) -> Result<(), RepositoryError> {
+        self.call("reset", Some("users"), none, |repository| repository.reset())
+    }
+
+    fn seed(&self, users: Vec<User>) -> Result<Vec<i32>, RepositoryError> {
+        self.call("seed", Some("users"), Vec::len, |repository| repository.seed(users))
+    }
+
+    fn sleep(&self, duration: Duration) -> Result<(), RepositoryError> {
+        self.call("sleep", None, none, |repository| repository.sleep(duration))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::memory::MemoryRepository;
+    use crate::spans::Spans;
+
+    #[test]
+    fn each_operation_is_a_db_span() {
+        let memory = MemoryRepository::default();
+        let repository = Traced::new(&memory);
+        let dispatch = Spans::collecting();
+        tracing::dispatcher::with_default(&dispatch, || {
+            let (name, email) = ("Ada".to_owned(), "ada@example.com".to_owned());
+            let user = NewUser { name, email, password: None, password_hash: None };
+            repository.create(&user, false, None).unwrap();
+            repository.list(&UserFilter::default()).unwrap();
+            repository.find(42).unwrap_err();
+            repository.list_each(&UserFilter::default(), &mut |_| true).unwrap();
+        });
+
+        let closed = Spans::closed(&dispatch);
+        let db: Vec<_> = closed.iter().map(|span| (span.field("statement"), span.field("rows"))).collect();
+        let expected = [("create", Some("1")), ("list", Some("1")), ("find", None), ("list_each", Some("1"))];
+        assert_eq!(db, expected.map(|(statement, rows)| (Some(statement), rows)));
+        assert!(closed.iter().all(|span| span.name == "db" && span.field("table") == Some("users")));
+        assert!(closed.iter().all(|span| span.field("duration_ms").is_some()));
+    }
+}
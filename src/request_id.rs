@@ -0,0 +1,94 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+
+use crate::http::{ get_header, with_header };
+
+// As long as an id the client picks may be, a UUID is 36
+const MAX_LENGTH: usize = 128;
+
+thread_local! {
+    // The id of the request handled on this thread, set by enter
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// The id of a request, the X-Request-Id it was sent with, or a new UUIDv4 when it
+// has none or one that is too long or has more than letters, digits and - _ . in
+// it, so that what a client sends can't forge the lines of the log
+pub fn from_request(request: &str) -> String {
+    match get_header(request, "X-Request-Id") {
+        Some(id) if is_valid(id) => id.to_owned(),
+        _ => generate(),
+    }
+}
+
+fn is_valid(id: &str) -> bool {
+    (1..=MAX_LENGTH).contains(&id.len())
+        && id.bytes().all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'))
+}
+
+pub fn generate() -> String {
+    let mut bytes = [0; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut bytes))
+        .expect("the system has random bytes");
+    // Version 4, variant 10
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+// The id of the request on this thread, for the log and the response, until the
+// returned guard is dropped
+pub fn enter(id: String) -> Entered {
+    CURRENT.set(Some(id));
+    Entered
+}
+
+pub struct Entered;
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.set(None);
+    }
+}
+
+// The id entered on this thread, None outside of requests
+pub fn current() -> Option<String> {
+    CURRENT.with_borrow(Clone::clone)
+}
+
+// The head of a response with the id of the request on this thread
+pub fn added(head: &str) -> String {
+    match current() {
+        Some(id) if !head.is_empty() => with_header(head, &format!("X-Request-Id: {}", id)),
+        _ => head.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_safe_ids_are_taken() {
+        let request = |id: &str| format!("GET /users HTTP/1.1\r\nX-Request-Id: {}\r\n\r\n", id);
+        assert_eq!(from_request(&request("01HZX3K8Q4-retry.2_b")), "01HZX3K8Q4-retry.2_b");
+        for hostile in ["", "a b", "a\"b", "é", &"a".repeat(MAX_LENGTH + 1)] {
+            let id = from_request(&request(hostile));
+            assert_ne!(id, hostile);
+            assert!(is_valid(&id), "{}", id);
+        }
+    }
+
+    #[test]
+    fn generated_ids_are_uuids_v4() {
+        let id = generate();
+        let groups: Vec<usize> = id.split('-').map(str::len).collect();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert_eq!(&id[14..15], "4");
+        assert!("89ab".contains(&id[19..20]), "{}", id);
+        assert_ne!(generate(), id);
+    }
+}
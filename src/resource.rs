@@ -0,0 +1,447 @@
+// What can fail in a handler is answered as an ApiError, none of it unwrapped
+#![cfg_attr(not(test), deny(clippy::unwrap_used, clippy::expect_used))]
+
+use std::fmt::Display;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::errors::{ ApiError, Handled, Malformed };
+use crate::handlers::{ deserialize_body, dry_run_body, invalid_body, is_dry_run };
+use crate::http::{ serialized, OK_RESPONSE };
+use crate::pagination;
+use crate::validation::ValidationError;
+
+// A resource the handlers below create, read, list, update and delete, the users
+// being one (handlers::UserResource). It says how its records are stored and
+// checked, and has hooks around the changes: those before can veto one with the
+// ApiError it is answered, those after do what goes with it once it is done.
+// Where the records are stored is up to the resource, the users having their SQL
+// in the repository of each backend. The router serves each resource with route.
+pub(crate) trait CrudResource {
+    type Id: Copy + Display + Serialize + Into<i64>;
+    type Model: Serialize;
+    type CreatePayload: DeserializeOwned;
+    type UpdatePayload: DeserializeOwned;
+
+    // What a record is called, in the codes of its errors
+    const NAME: &'static str;
+    // The segment of its routes, /{PATH} and /{PATH}/{id}
+    const PATH: &'static str;
+
+    fn id_of(model: &Self::Model) -> Option<Self::Id>;
+
+    // The id of a segment of the path, or why it isn't one
+    fn parse_id(segment: &str) -> Result<Self::Id, &'static str>;
+
+    // The 404 of a record that isn't there
+    fn not_found(id: Self::Id) -> ApiError {
+        ApiError::Missing(Self::NAME, id.to_string())
+    }
+
+    // All that is wrong with a payload at once. They may normalize its values.
+    fn validate_create(&self, payload: &mut Self::CreatePayload) -> Vec<ValidationError>;
+    fn validate_update(&self, payload: &mut Self::UpdatePayload) -> Vec<ValidationError>;
+
+    fn find(&self, id: Self::Id) -> Result<Self::Model, ApiError>;
+    // Each record of the list of the request after the id, in the order of the
+    // ids, until each says to stop
+    fn list_each(
+        &self,
+        request: &str,
+        after_id: Option<i64>,
+        each: &mut dyn FnMut(Self::Model) -> bool
+    ) -> Result<(), ApiError>;
+    fn create(&self, request: &str, payload: Self::CreatePayload, dry_run: bool) -> Result<Stored<Self>, ApiError>;
+    fn update(&self, id: Self::Id, payload: Self::UpdatePayload, dry_run: bool) -> Result<Self::Model, ApiError>;
+    fn delete(&self, id: Self::Id, dry_run: bool) -> Result<(), ApiError>;
+
+    // Run for the dry runs too, which answer what the request would be, but not
+    // those after
+    fn before_create(&self, _payload: &Self::CreatePayload) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    fn after_create(&self, _model: &Self::Model) {}
+
+    fn before_update(&self, _id: Self::Id, _payload: &Self::UpdatePayload) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    fn after_update(&self, _id: Self::Id, _model: &Self::Model) {}
+
+    fn before_delete(&self, _id: Self::Id) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    fn after_delete(&self, _id: Self::Id) {}
+
+    // Whether the route may be taken for the record of the id, or for the list
+    // without one, before anything else of the request is read
+    fn authorize(&self, _id: Option<Self::Id>, _route: &str) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    // The response of one record, which a resource can answer its own way, as the
+    // users do from their cache
+    fn get(&self, id: Self::Id) -> Handled {
+        Ok((OK_RESPONSE.to_owned(), serialized(&self.find(id)?)))
+    }
+
+    // The response of a page of the list, which a resource can answer its own way
+    // too, as the users do coalesced
+    fn get_list(&self, request: &str) -> Handled
+    where
+        Self: Sized,
+    {
+        list(self, request)
+    }
+}
+
+// The routes of the resource: GET and POST /{PATH}, and GET, PUT and DELETE
+// /{PATH}/{id}. None for a request of another route.
+pub(crate) fn route<R: CrudResource>(
+    resource: &R,
+    method: &str,
+    segments: &[&str],
+    request: &str,
+    route: &str
+) -> Option<Handled> {
+    let handled = match (method, segments) {
+        ("GET", [path]) if *path == R::PATH => {
+            resource.authorize(None, route).and_then(|()| resource.get_list(request))
+        }
+        ("POST", [path]) if *path == R::PATH => create(resource, request),
+        ("GET" | "PUT" | "DELETE", [path, segment]) if *path == R::PATH => {
+            let invalid = |reason| ApiError::Malformed(Malformed::InvalidId((*segment).to_owned(), reason));
+            let id = R::parse_id(segment).map_err(invalid);
+            id.and_then(|id| {
+                resource.authorize(Some(id), route)?;
+                match method {
+                    "GET" => resource.get(id),
+                    "PUT" => update(resource, request, id),
+                    _ => delete(resource, request, id),
+                }
+            })
+        }
+        _ => return None,
+    };
+    Some(handled)
+}
+
+// What a create stored, or the response it was answered already, as that of the
+// request it repeats
+pub(crate) enum Stored<R: CrudResource + ?Sized> {
+    Record(R::Model),
+    Answered((String, String)),
+}
+
+// A page of the records: at most ?limit= of them, those after the one of
+// ?after_id=, with a Link to the next page when there are more
+pub(crate) fn list<R: CrudResource>(resource: &R, request: &str) -> Handled {
+    let (limit, after_id) = (pagination::limit(request)?, pagination::after_id(request)?);
+    let (mut models, mut more) = (Vec::new(), false);
+    resource.list_each(request, after_id, &mut |model| {
+        // The one after the page only says there is a next one
+        if models.len() == limit.value {
+            more = true;
+            return false;
+        }
+        models.push(model);
+        true
+    })?;
+    let next = models.last().and_then(R::id_of).filter(|_| more).map(Into::into);
+    Ok((pagination::linked(OK_RESPONSE, request, limit, next), serialized(&models)))
+}
+
+pub(crate) fn create<R: CrudResource>(resource: &R, request: &str) -> Handled {
+    let payload = deserialize_body(request).map_err(|e| invalid_body(request, e))?;
+    let dry_run = is_dry_run(request);
+    match created(resource, request, payload, dry_run)? {
+        // Nothing was stored, so there is no id
+        Stored::Record(model) if dry_run => {
+            let mut body = serde_json::to_value(&model)?;
+            if let Some(object) = body.as_object_mut() {
+                object.remove("id");
+            }
+            Ok((OK_RESPONSE.to_owned(), dry_run_body(body)))
+        }
+        Stored::Record(model) => Ok((OK_RESPONSE.to_owned(), serialized(&model))),
+        Stored::Answered(response) => Ok(response),
+    }
+}
+
+pub(crate) fn update<R: CrudResource>(resource: &R, request: &str, id: R::Id) -> Handled {
+    let dry_run = is_dry_run(request);
+    let payload = deserialize_body(request).map_err(|e| invalid_body(request, e))?;
+    let model = updated(resource, id, payload, dry_run)?;
+    match dry_run {
+        true => Ok((OK_RESPONSE.to_owned(), dry_run_body(serde_json::to_value(&model)?))),
+        false => Ok((OK_RESPONSE.to_owned(), serialized(&model))),
+    }
+}
+
+pub(crate) fn delete<R: CrudResource>(resource: &R, request: &str, id: R::Id) -> Handled {
+    let dry_run = is_dry_run(request);
+    deleted(resource, id, dry_run)?;
+    match dry_run {
+        true => Ok((OK_RESPONSE.to_owned(), dry_run_body(serde_json::json!({ "id": id })))),
+        false => Ok((OK_RESPONSE.to_owned(), serde_json::to_string(&id.to_string())?)),
+    }
+}
+
+// The changes as the handlers above make them, validated and with their hooks,
+// for those answering them otherwise, as GraphQL does
+pub(crate) fn created<R: CrudResource>(
+    resource: &R,
+    request: &str,
+    mut payload: R::CreatePayload,
+    dry_run: bool
+) -> Result<Stored<R>, ApiError> {
+    let errors = resource.validate_create(&mut payload);
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+    resource.before_create(&payload)?;
+    let stored = resource.create(request, payload, dry_run)?;
+    if let Stored::Record(model) = &stored {
+        if !dry_run {
+            resource.after_create(model);
+        }
+    }
+    Ok(stored)
+}
+
+pub(crate) fn updated<R: CrudResource>(
+    resource: &R,
+    id: R::Id,
+    mut payload: R::UpdatePayload,
+    dry_run: bool
+) -> Result<R::Model, ApiError> {
+    let errors = resource.validate_update(&mut payload);
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+    resource.before_update(id, &payload)?;
+    let model = resource.update(id, payload, dry_run)?;
+    if !dry_run {
+        resource.after_update(id, &model);
+    }
+    Ok(model)
+}
+
+pub(crate) fn deleted<R: CrudResource>(resource: &R, id: R::Id, dry_run: bool) -> Result<(), ApiError> {
+    resource.before_delete(id)?;
+    resource.delete(id, dry_run)?;
+    if !dry_run {
+        resource.after_delete(id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{ BAD_REQUEST_PROBLEM, CONFLICT_PROBLEM, NOT_FOUND_PROBLEM, UNPROCESSABLE_ENTITY_PROBLEM };
+    use std::sync::Mutex;
+
+    // Notes kept in memory, the locked ones taking no changes: a resource with
+    // nothing but the trait, as a new one would be
+    #[derive(Default)]
+    struct Notes {
+        notes: Mutex<Vec<Note>>,
+        deleted: Mutex<Vec<i32>>,
+    }
+
+    #[derive(Clone, Serialize)]
+    struct Note {
+        id: i32,
+        text: String,
+        locked: bool,
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct NotePayload {
+        text: String,
+        #[serde(default)]
+        locked: bool,
+    }
+
+    impl CrudResource for Notes {
+        type Id = i32;
+        type Model = Note;
+        type CreatePayload = NotePayload;
+        type UpdatePayload = NotePayload;
+
+        const NAME: &'static str = "note";
+        const PATH: &'static str = "notes";
+
+        fn id_of(note: &Note) -> Option<i32> {
+            Some(note.id)
+        }
+
+        fn parse_id(segment: &str) -> Result<i32, &'static str> {
+            segment.parse().map_err(|_| "id must be a whole number")
+        }
+
+        fn validate_create(&self, payload: &mut NotePayload) -> Vec<ValidationError> {
+            payload.text = payload.text.trim().to_owned();
+            match payload.text.is_empty() {
+                true => vec![ValidationError::new("text", "required", "Text is required")],
+                false => Vec::new(),
+            }
+        }
+
+        fn validate_update(&self, payload: &mut NotePayload) -> Vec<ValidationError> {
+            self.validate_create(payload)
+        }
+
+        fn find(&self, id: i32) -> Result<Note, ApiError> {
+            let notes = self.notes.lock().unwrap();
+            notes.iter().find(|note| note.id == id).cloned().ok_or(Self::not_found(id))
+        }
+
+        fn list_each(
+            &self,
+            _request: &str,
+            after_id: Option<i64>,
+            each: &mut dyn FnMut(Note) -> bool
+        ) -> Result<(), ApiError> {
+            let notes = self.notes.lock().unwrap().clone();
+            let after = notes.into_iter().filter(|note| after_id.is_none_or(|after_id| i64::from(note.id) > after_id));
+            for note in after {
+                if !each(note) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+
+        fn create(&self, _request: &str, payload: NotePayload, dry_run: bool) -> Result<Stored<Self>, ApiError> {
+            let mut notes = self.notes.lock().unwrap();
+            let note = Note { id: notes.len() as i32 + 1, text: payload.text, locked: payload.locked };
+            if !dry_run {
+                notes.push(note.clone());
+            }
+            Ok(Stored::Record(note))
+        }
+
+        fn update(&self, id: i32, payload: NotePayload, dry_run: bool) -> Result<Note, ApiError> {
+            let mut notes = self.notes.lock().unwrap();
+            let note = notes.iter_mut().find(|note| note.id == id).ok_or(Self::not_found(id))?;
+            let updated = Note { id, text: payload.text, locked: payload.locked };
+            if !dry_run {
+                *note = updated.clone();
+            }
+            Ok(updated)
+        }
+
+        fn delete(&self, id: i32, dry_run: bool) -> Result<(), ApiError> {
+            let mut notes = self.notes.lock().unwrap();
+            let index = notes.iter().position(|note| note.id == id).ok_or(Self::not_found(id))?;
+            if !dry_run {
+                notes.remove(index);
+            }
+            Ok(())
+        }
+
+        fn before_update(&self, id: i32, _payload: &NotePayload) -> Result<(), ApiError> {
+            match self.find(id)?.locked {
+                true => Err(ApiError::Conflict(format!("Note {} is locked", id))),
+                false => Ok(()),
+            }
+        }
+
+        fn after_delete(&self, id: i32) {
+            self.deleted.lock().unwrap().push(id);
+        }
+    }
+
+    fn request(method: &str, target: &str, body: &str) -> String {
+        format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n{}", method, target, body)
+    }
+
+    fn answered(handled: Handled) -> (String, String) {
+        handled.unwrap_or_else(ApiError::response)
+    }
+
+    fn json(body: &str) -> serde_json::Value {
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn a_resource_is_served_with_nothing_but_the_trait() {
+        let notes = Notes::default();
+        for text in ["first", " second ", "third"] {
+            let body = serde_json::json!({ "text": text }).to_string();
+            let (status_line, _) = answered(create(&notes, &request("POST", "/notes", &body)));
+            assert_eq!(status_line, OK_RESPONSE);
+        }
+        let (status_line, body) = answered(notes.get(2));
+        assert_eq!((status_line.as_str(), json(&body)["text"].as_str()), (OK_RESPONSE, Some("second")));
+        assert_eq!(answered(notes.get(9)).0, NOT_FOUND_PROBLEM);
+
+        let (status_line, body) = answered(list(&notes, &request("GET", "/notes?limit=2", "")));
+        assert!(status_line.contains(", </notes?limit=2&after_id=2>; rel=\"next\"\r\n"), "{}", status_line);
+        assert_eq!(json(&body).as_array().unwrap().len(), 2);
+        let (_, body) = answered(list(&notes, &request("GET", "/notes?limit=2&after_id=2", "")));
+        assert_eq!(json(&body)[0]["text"], "third");
+
+        let (_, body) = answered(create(&notes, &request("POST", "/notes?dry_run=true", r#"{"text": "fourth"}"#)));
+        assert_eq!((json(&body).get("id"), &json(&body)["dry_run"]), (None, &serde_json::Value::Bool(true)));
+        let blank = answered(create(&notes, &request("POST", "/notes", r#"{"text": " "}"#)));
+        assert_eq!(blank.0, UNPROCESSABLE_ENTITY_PROBLEM);
+        assert_eq!(answered(create(&notes, &request("POST", "/notes", r#"{"title": "x"}"#))).0, BAD_REQUEST_PROBLEM);
+
+        let (_, body) = answered(update(&notes, &request("PUT", "/notes/1", r#"{"text": "edited"}"#), 1));
+        assert_eq!(json(&body)["text"], "edited");
+        assert_eq!(answered(delete(&notes, &request("DELETE", "/notes/3?dry_run=true", ""), 3)).0, OK_RESPONSE);
+        let (status_line, body) = answered(delete(&notes, &request("DELETE", "/notes/3", ""), 3));
+        assert_eq!((status_line.as_str(), body.as_str()), (OK_RESPONSE, "\"3\""));
+        assert_eq!(notes.notes.lock().unwrap().len(), 2);
+        assert_eq!(*notes.deleted.lock().unwrap(), [3]);
+    }
+
+    #[test]
+    fn a_resource_is_routed_under_its_path() {
+        let notes = Notes::default();
+        let routed = |method: &str, target: &str, body: &str| {
+            let segments: Vec<&str> = target.trim_start_matches('/').split('/').collect();
+            let handled = route(&notes, method, &segments, &request(method, target, body), target);
+            handled.map(answered)
+        };
+        let (status_line, body) = routed("POST", "/notes", r#"{"text": "first"}"#).unwrap();
+        assert_eq!((status_line.as_str(), json(&body)["id"].as_i64()), (OK_RESPONSE, Some(1)));
+        assert_eq!(json(&routed("GET", "/notes", "").unwrap().1)[0]["text"], "first");
+        assert_eq!(json(&routed("PUT", "/notes/1", r#"{"text": "edited"}"#).unwrap().1)["text"], "edited");
+        assert_eq!(json(&routed("GET", "/notes/1", "").unwrap().1)["text"], "edited");
+        assert_eq!(routed("DELETE", "/notes/1", "").unwrap().0, OK_RESPONSE);
+
+        // Its 404s and its 400s are its own
+        let (status_line, body) = routed("GET", "/notes/1", "").unwrap();
+        assert_eq!((status_line.as_str(), &json(&body)["code"]), (NOT_FOUND_PROBLEM, &"note_not_found".into()));
+        let (status_line, body) = routed("PUT", "/notes/first", r#"{"text": "edited"}"#).unwrap();
+        assert_eq!((status_line.as_str(), &json(&body)["value"]), (BAD_REQUEST_PROBLEM, &"first".into()));
+        // The other routes are left to the router
+        for (method, target) in [("PATCH", "/notes/1"), ("GET", "/notes/1/history"), ("GET", "/users")] {
+            assert!(routed(method, target, "").is_none(), "{} {}", method, target);
+        }
+    }
+
+    #[test]
+    fn a_hook_before_an_update_vetoes_it() {
+        let notes = Notes::default();
+        answered(create(&notes, &request("POST", "/notes", r#"{"text": "kept", "locked": true}"#)));
+
+        let edit = request("PUT", "/notes/1", r#"{"text": "edited"}"#);
+        let (status_line, body) = answered(update(&notes, &edit, 1));
+        let detail = serde_json::from_str::<serde_json::Value>(&body).unwrap()["detail"].clone();
+        assert_eq!((status_line.as_str(), detail), (CONFLICT_PROBLEM, "Note 1 is locked".into()));
+        assert_eq!(notes.find(1).unwrap().text, "kept");
+        // Nor can its dry run, which answers what it would
+        let edit = request("PUT", "/notes/1?dry_run=true", r#"{"text": "edited"}"#);
+        assert_eq!(answered(update(&notes, &edit, 1)).0, CONFLICT_PROBLEM);
+        assert_eq!(answered(update(&notes, &edit, 2)).0, NOT_FOUND_PROBLEM);
+    }
+}
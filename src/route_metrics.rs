@@ -0,0 +1,333 @@
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::metrics::Histogram;
+use crate::statsd;
+
+// The routes of handle_client, by method and template, each segment of {id}
+// standing for any one. The first matching one is the route of a request.
+pub(crate) const ROUTES: [(&str, &str); 54] = [
+    ("GET", "/users"),
+    ("GET", "/users/events"),
+    ("GET", "/users/{id}"),
+    ("POST", "/users"),
+    ("POST", "/users/validate"),
+    ("POST", "/users/verify"),
+    ("PUT", "/users/{id}"),
+    ("DELETE", "/users/{id}"),
+    ("PUT", "/users/{id}/password"),
+    ("PUT", "/users/{id}/role"),
+    ("POST", "/users/{id}/resend-verification"),
+    ("POST", "/users/{id}/anonymize"),
+    ("GET", "/users/{id}/export"),
+    ("GET", "/ws"),
+    ("GET", "/events"),
+    ("POST", "/login"),
+    ("POST", "/token/refresh"),
+    ("POST", "/logout"),
+    ("POST", "/session"),
+    ("DELETE", "/session"),
+    ("GET", "/session/csrf"),
+    ("GET", "/auth/login"),
+    ("GET", "/auth/callback"),
+    ("POST", "/password-reset/request"),
+    ("POST", "/password-reset/confirm"),
+    ("GET", "/graphql"),
+    ("POST", "/graphql"),
+    ("GET", "/health"),
+    ("GET", "/health/details"),
+    ("GET", "/livez"),
+    ("GET", "/version"),
+    ("GET", "/readyz"),
+    ("GET", "/metrics"),
+    ("GET", "/debug/pool"),
+    ("GET", "/debug/stats"),
+    ("POST", "/admin/reset"),
+    ("POST", "/admin/backup"),
+    ("POST", "/admin/seed"),
+    ("GET", "/admin/sleep"),
+    ("GET", "/admin/fail"),
+    ("GET", "/admin/tenants"),
+    ("POST", "/admin/tenants"),
+    ("GET", "/admin/auth-events"),
+    ("GET", "/admin/api-keys"),
+    ("POST", "/admin/api-keys"),
+    ("DELETE", "/admin/api-keys/{id}"),
+    ("GET", "/admin/maintenance"),
+    ("POST", "/admin/maintenance"),
+    ("GET", "/admin/read-only"),
+    ("POST", "/admin/read-only"),
+    ("GET", "/admin/chaos"),
+    ("POST", "/admin/chaos"),
+    ("DELETE", "/admin/users/{id}/refresh-tokens"),
+    ("DELETE", "/admin/lockouts"),
+];
+
+// The methods of the requests matching none of the routes, any other counted as
+// other
+const METHODS: [&str; 8] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS", "other"];
+
+// What the statuses are counted by, none for the requests never answered
+const STATUS_CLASSES: [&str; 6] = ["1xx", "2xx", "3xx", "4xx", "5xx", "none"];
+
+static STATS: OnceLock<Vec<RouteStats>> = OnceLock::new();
+
+// The route of a request: an index into ROUTES, or past them into METHODS for
+// the unmatched ones
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Route(usize);
+
+// The requests of a route so far, for /metrics
+pub struct Requested {
+    pub route: Route,
+    pub duration: &'static Histogram,
+    // How many were answered by status class, those with none left out
+    pub statuses: Vec<(&'static str, u64)>,
+}
+
+#[derive(Default)]
+struct RouteStats {
+    duration: Histogram,
+    statuses: [AtomicU64; STATUS_CLASSES.len()],
+}
+
+// The route the request of method and the segments of its path is counted under.
+// The label set is fixed, the method and template of one of ROUTES, or unmatched
+// and the method, for the raw paths not to be labels.
+pub fn route(method: &str, segments: &[&str]) -> Route {
+    match first_match(&ROUTES, method, segments) {
+        Some(index) => Route(index),
+        None => Route(ROUTES.len() + METHODS.iter().position(|each| *each == method).unwrap_or(METHODS.len() - 1)),
+    }
+}
+
+// The methods of the routes whose template the segments match, for the Allow of
+// the requests with another
+pub(crate) fn allowed(segments: &[&str]) -> Vec<&'static str> {
+    let mut methods = Vec::new();
+    for (method, template) in ROUTES {
+        if matches(template, segments) && !methods.contains(&method) {
+            methods.push(method);
+        }
+    }
+    methods
+}
+
+// The index of the first of routes with method and a template segments match
+fn first_match(routes: &[(&str, &str)], method: &str, segments: &[&str]) -> Option<usize> {
+    routes.iter().position(|(route_method, template)| *route_method == method && matches(template, segments))
+}
+
+fn matches(template: &str, segments: &[&str]) -> bool {
+    let mut template = template.split('/').filter(|segment| !segment.is_empty());
+    let mut segments = segments.iter();
+    loop {
+        match (template.next(), segments.next()) {
+            (None, None) => return true,
+            (Some("{id}"), Some(_)) => {}
+            (Some(expected), Some(segment)) if expected == *segment => {}
+            _ => return false,
+        }
+    }
+}
+
+impl Route {
+    // Its method and route labels
+    pub fn labels(self) -> (&'static str, &'static str) {
+        match ROUTES.get(self.0) {
+            Some((method, template)) => (method, template),
+            None => (METHODS[self.0 - ROUTES.len()], "unmatched"),
+        }
+    }
+}
+
+fn stats() -> &'static [RouteStats] {
+    STATS.get_or_init(|| (0..ROUTES.len() + METHODS.len()).map(|_| RouteStats::default()).collect())
+}
+
+// A request of route was answered with status, None if it never was, after
+// duration
+pub fn observe(route: Route, status: Option<u16>, duration: Duration) {
+    let stats = &stats()[route.0];
+    stats.duration.observe(duration);
+    let (method, template) = route.labels();
+    statsd::timing("http_request_duration", duration, &[("method", method), ("route", template)]);
+    let class = match status {
+        Some(status @ 100..=599) => (status / 100 - 1) as usize,
+        _ => STATUS_CLASSES.len() - 1,
+    };
+    stats.statuses[class].fetch_add(1, Ordering::Relaxed);
+}
+
+// The routes requested so far
+pub fn requested() -> Vec<Requested> {
+    let requested = stats().iter().enumerate().filter(|(_, stats)| stats.duration.count() > 0);
+    requested
+        .map(|(index, stats)| {
+            let statuses = STATUS_CLASSES
+                .iter()
+                .zip(&stats.statuses)
+                .map(|(class, count)| (*class, count.load(Ordering::Relaxed)))
+                .filter(|(_, count)| *count > 0)
+                .collect();
+            Requested { route: Route(index), duration: &stats.duration, statuses }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::get_segments;
+    use crate::properties::{ check, without_one, Cases };
+
+    #[test]
+    fn requests_are_counted_by_their_route_template() {
+        assert_eq!(route("GET", &["users", "42"]).labels(), ("GET", "/users/{id}"));
+        assert_eq!(route("GET", &["users", "events"]).labels(), ("GET", "/users/events"));
+        assert_eq!(route("DELETE", &["admin", "api-keys", "7"]).labels(), ("DELETE", "/admin/api-keys/{id}"));
+        assert_eq!(route("GET", &[]).labels(), ("GET", "unmatched"));
+        assert_eq!(route("GET", &["users", "42", "nope"]).labels(), ("GET", "unmatched"));
+        assert_eq!(route("BREW", &["users"]).labels(), ("other", "unmatched"));
+        assert_eq!(route("PATCH", &["users", "1"]).labels(), ("PATCH", "unmatched"));
+    }
+
+    #[test]
+    fn a_path_allows_the_methods_of_its_routes() {
+        assert_eq!(allowed(&["users", "1"]), ["GET", "PUT", "DELETE"]);
+        assert_eq!(allowed(&["users"]), ["GET", "POST"]);
+        assert_eq!(allowed(&["admin", "chaos"]), ["GET", "POST"]);
+        assert!(allowed(&["users", "1", "nope"]).is_empty());
+    }
+
+    // A table of routes, and a request to match against it: the segments of its
+    // path, and the slashes and the query it is sent with
+    #[derive(Clone, Debug)]
+    struct Request {
+        routes: Vec<(&'static str, String)>,
+        method: &'static str,
+        path: Vec<&'static str>,
+        query: &'static str,
+        doubled_slashes: bool,
+        trailing_slash: bool,
+    }
+
+    impl Request {
+        fn generate(cases: &mut Cases) -> Request {
+            let template = |cases: &mut Cases| {
+                let segments = cases.some(3, |cases| cases.pick(&["users", "admin", "a", "{id}"]));
+                (cases.pick(&["GET", "POST"]), format!("/{}", segments.join("/")))
+            };
+            Request {
+                routes: cases.some(6, template),
+                method: cases.pick(&["GET", "POST"]),
+                path: cases.some(3, |cases| cases.pick(&["users", "admin", "a", "1", "42", "x"])),
+                query: cases.pick(&["", "?limit=5", "?q=a/b&after_id=1", "?"]),
+                doubled_slashes: cases.one_in(4),
+                trailing_slash: cases.one_in(4),
+            }
+        }
+
+        fn shrink(&self) -> Vec<Request> {
+            let mut smaller: Vec<Request> = without_one(&self.routes)
+                .into_iter()
+                .map(|routes| Request { routes, ..self.clone() })
+                .chain(without_one(&self.path).into_iter().map(|path| Request { path, ..self.clone() }))
+                .collect();
+            smaller.extend((!self.query.is_empty()).then(|| Request { query: "", ..self.clone() }));
+            smaller.extend(self.doubled_slashes.then(|| Request { doubled_slashes: false, ..self.clone() }));
+            smaller.extend(self.trailing_slash.then(|| Request { trailing_slash: false, ..self.clone() }));
+            smaller
+        }
+
+        // Its segments as they are read from the request sent
+        fn sent(&self) -> Vec<String> {
+            let separator = if self.doubled_slashes { "//" } else { "/" };
+            let slash = if self.trailing_slash { "/" } else { "" };
+            let target = format!("/{}{}{}", self.path.join(separator), slash, self.query);
+            let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", self.method, target);
+            get_segments(&request).into_iter().map(str::to_owned).collect()
+        }
+    }
+
+    #[test]
+    fn the_route_matched_gives_back_the_path() {
+        check(0x7a81e, Request::generate, Request::shrink, |request| {
+            let routes: Vec<(&str, &str)> =
+                request.routes.iter().map(|(method, template)| (*method, template.as_str())).collect();
+            let sent = request.sent();
+            let sent: Vec<&str> = sent.iter().map(String::as_str).collect();
+            let matched = first_match(&routes, request.method, &request.path);
+            // However the path is sent
+            if first_match(&routes, request.method, &sent) != matched {
+                return false;
+            }
+            let Some((_, template)) = matched.map(|index| routes[index]) else {
+                return true;
+            };
+            // With the segments its ids stand for put back in it
+            let mut ids = template
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .zip(&request.path)
+                .filter(|(segment, _)| *segment == "{id}")
+                .map(|(_, id)| *id);
+            let path: Vec<&str> = template
+                .split('/')
+                .map(|segment| if segment == "{id}" { ids.next().unwrap() } else { segment })
+                .collect();
+            path.join("/") == format!("/{}", request.path.join("/"))
+        });
+    }
+
+    // Could both match a path, segment by segment
+    fn overlap(first: &str, second: &str) -> bool {
+        let (first, second): (Vec<&str>, Vec<&str>) = (first.split('/').collect(), second.split('/').collect());
+        first.len() == second.len()
+            && first.iter().zip(&second).all(|(a, b)| a == b || *a == "{id}" || *b == "{id}")
+    }
+
+    // Literal wherever the other route is, and somewhere it isn't
+    fn more_specific(first: &str, second: &str) -> bool {
+        let pairs: Vec<(&str, &str)> = first.split('/').zip(second.split('/')).collect();
+        pairs.iter().all(|(a, b)| *b == "{id}" || a == b) && pairs.iter().any(|(a, b)| *a != "{id}" && *b == "{id}")
+    }
+
+    #[test]
+    fn a_path_of_two_routes_is_counted_under_the_more_specific() {
+        for (index, (method, first)) in ROUTES.iter().enumerate() {
+            for (_, second) in ROUTES[index + 1..].iter().filter(|(other, _)| other == method) {
+                if overlap(first, second) {
+                    assert!(more_specific(first, second), "{} {} shadows {}", method, second, first);
+                }
+            }
+        }
+
+        // Paths like those of the routes, each id standing for a number or a word
+        let words: Vec<&str> = ROUTES.iter().flat_map(|(_, template)| template.split('/')).collect();
+        let generate = |cases: &mut Cases| {
+            let (method, template) = cases.pick(&ROUTES);
+            let segments = template.split('/').filter(|segment| !segment.is_empty());
+            let path: Vec<&str> = segments
+                .map(|segment| match segment {
+                    "{id}" if cases.one_in(2) => "42",
+                    "{id}" => cases.pick(&words),
+                    segment => segment,
+                })
+                .collect();
+            (method, path)
+        };
+        let shrink = |(method, path): &(&'static str, Vec<&'static str>)| -> Vec<(&'static str, Vec<&'static str>)> {
+            without_one(path).into_iter().map(|path| (*method, path)).collect()
+        };
+        check(0x5ec1f1c, generate, shrink, |(method, path)| {
+            let matching: Vec<&str> = ROUTES
+                .iter()
+                .filter(|(each, template)| each == method && matches(template, path))
+                .map(|(_, template)| *template)
+                .collect();
+            matching.iter().skip(1).all(|other| more_specific(matching[0], other))
+        });
+    }
+}
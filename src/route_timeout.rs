@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+use std::sync::{ Mutex, OnceLock };
+use std::time::{ Duration, Instant };
+
+use crate::config::number_from_env;
+use crate::http::GATEWAY_TIMEOUT_PROBLEM;
+use crate::locale;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_BATCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+static CONFIG: OnceLock<RouteTimeoutConfig> = OnceLock::new();
+// Since startup, by route
+static TIMED_OUT: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+// How long a handler may take from when its request was read: ROUTE_TIMEOUT_MS,
+// or ROUTE_BATCH_TIMEOUT_MS for the exports and the admin routes that work on
+// every user; 0 is no limit. The streams have none. Past it the queries of the
+// request are cancelled, and what the handler answers is replaced by a 504,
+// whether or not its write was done.
+#[derive(Clone, Debug)]
+pub struct RouteTimeoutConfig {
+    pub timeout: Duration,
+    pub batch_timeout: Duration,
+}
+
+impl RouteTimeoutConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let timeout = number_from_env("ROUTE_TIMEOUT_MS", DEFAULT_TIMEOUT.as_millis() as u64)?;
+        let batch_timeout = number_from_env("ROUTE_BATCH_TIMEOUT_MS", DEFAULT_BATCH_TIMEOUT.as_millis() as u64)?;
+        Ok(RouteTimeoutConfig {
+            timeout: Duration::from_millis(timeout),
+            batch_timeout: Duration::from_millis(batch_timeout),
+        })
+    }
+}
+
+pub fn init(config: RouteTimeoutConfig) {
+    CONFIG.set(config).ok();
+}
+
+fn config() -> &'static RouteTimeoutConfig {
+    CONFIG.get_or_init(|| RouteTimeoutConfig { timeout: DEFAULT_TIMEOUT, batch_timeout: DEFAULT_BATCH_TIMEOUT })
+}
+
+// The route of a request, its ids left out, as the logs and the metrics name it
+pub fn route(method: &str, segments: &[&str]) -> String {
+    let is_id = |segment: &str| segment.bytes().all(|byte| byte.is_ascii_digit());
+    let path: Vec<&str> = segments.iter().map(|segment| if is_id(segment) { "{id}" } else { segment }).collect();
+    format!("{} /{}", method, path.join("/"))
+}
+
+// None when there is no limit
+pub fn budget(segments: &[&str]) -> Option<Duration> {
+    let config = config();
+    let batch = matches!(segments, ["users", _, "export"] | ["admin", "backup" | "reset" | "seed"]);
+    let budget = if batch { config.batch_timeout } else { config.timeout };
+    Some(budget).filter(|budget| !budget.is_zero())
+}
+
+// The 504 answering a request that took longer than its budget, logged and
+// counted
+pub fn timed_out(route: &str, started: Instant, budget: Option<Duration>) -> Option<(String, String)> {
+    let (budget, elapsed) = (budget?, started.elapsed());
+    if elapsed < budget {
+        return None;
+    }
+    log::warn!("{} timed out after {:?}, its budget is {:?}", route, elapsed, budget);
+    *TIMED_OUT.lock().unwrap().entry(route.to_owned()).or_default() += 1;
+    let body = serde_json::json!({
+        "type": "about:blank",
+        "title": locale::title(504, "Gateway Timeout"),
+        "status": 504,
+        "detail": format!("{} took longer than {:?}", route, budget),
+    });
+    Some((GATEWAY_TIMEOUT_PROBLEM.to_owned(), body.to_string()))
+}
+
+// The requests that timed out, by route
+pub fn timeouts() -> Vec<(String, u64)> {
+    TIMED_OUT.lock().unwrap().iter().map(|(route, count)| (route.clone(), *count)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_have_their_ids_left_out() {
+        assert_eq!(route("GET", &["users", "42"]), "GET /users/{id}");
+        assert_eq!(route("POST", &["users", "7", "anonymize"]), "POST /users/{id}/anonymize");
+        assert_eq!(route("GET", &[]), "GET /");
+        assert_eq!(budget(&["users", "7", "export"]), Some(DEFAULT_BATCH_TIMEOUT));
+        assert_eq!(budget(&["users"]), Some(DEFAULT_TIMEOUT));
+    }
+
+    #[test]
+    fn handlers_past_their_budget_are_answered_504() {
+        let started = Instant::now() - Duration::from_millis(300);
+        assert_eq!(timed_out("GET /users/{id}", started, Some(Duration::from_secs(1))), None);
+        assert_eq!(timed_out("GET /users/{id}", started, None), None);
+
+        let (status_line, body) = timed_out("GET /users/{id}", started, Some(Duration::from_millis(200))).unwrap();
+        assert!(status_line.starts_with("HTTP/1.1 504 "), "{}", status_line);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["detail"], "GET /users/{id} took longer than 200ms");
+        assert!(timeouts().contains(&("GET /users/{id}".to_owned(), 1)));
+    }
+}
@@ -0,0 +1,677 @@
+// Nothing that can fail while a request is answered is unwrapped
+#![cfg_attr(not(test), deny(clippy::unwrap_used, clippy::expect_used))]
+
+use std::borrow::Cow;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Instant;
+
+use tracing::field::Empty;
+use tracing::Span;
+
+use crate::auth::{ self, Role, Scope };
+use crate::connections::{ self, Slot };
+use crate::db::POOL;
+use crate::errors::{ repository_error_response, ApiError, Handled, Malformed };
+use crate::handlers::{
+    handle_anonymize_request, handle_export_request, handle_get_events_request, handle_health_request,
+    handle_livez_request, handle_readyz_request, handle_set_role_request, handle_validate_request,
+    handle_version_request, UserResource
+};
+use crate::http::{
+    get_header, get_path, get_segments, malformed, parse_id, read_request, request_bytes, timed_out, with_header,
+    write_response, BAD_REQUEST, NOT_FOUND, NOT_IMPLEMENTED, SERVICE_UNAVAILABLE
+};
+use crate::rate_limit::{ self, Decision };
+use crate::repository::{ self, UserRepository };
+use crate::resource;
+use crate::{
+    access_log, admin, api_keys, audit, backup, body_log, chaos, debug_stats, disconnect, graphql, health, https,
+    json_case, jwt, locale, lockout, maintenance, metrics, migrations, oidc, password, password_reset, proxy,
+    read_only, refresh, reload, request_id, route_metrics, route_timeout, sessions, spans, sse, tenant, trace_context,
+    verification, ws
+};
+
+// The role the routes of handle_client need: any for reading, a writer for the
+// changes, an admin for the history of the users and whatever is under /admin or
+// /debug, the routes to come included
+fn required_role(method: &str, segments: &[&str]) -> Role {
+    match (method, segments) {
+        (_, ["admin", ..] | ["debug", ..]) => Role::Admin,
+        ("GET", ["events"] | ["users", "events"] | ["ws"] | ["users", _, "export"]) => Role::Admin,
+        ("PUT", ["users", _, "role"]) => Role::Admin,
+        // Logging in and out, checking a user, and a password with the current one
+        ("POST", ["login"] | ["session"] | ["users", "validate"]) | ("DELETE", ["session"]) => Role::Reader,
+        ("POST", ["token", "refresh"] | ["logout"]) => Role::Reader,
+        // And an email, with the token sent to it, or another token
+        ("POST", ["users", "verify"] | ["users", _, "resend-verification"]) => Role::Reader,
+        // And a forgotten password, with the token mailed
+        ("POST", ["password-reset", "request"] | ["password-reset", "confirm"]) => Role::Reader,
+        ("PUT", ["users", _, "password"]) => Role::Reader,
+        // Each of the fields of a document needs what its route does
+        ("POST", ["graphql"]) => Role::Reader,
+        ("POST" | "PUT" | "PATCH" | "DELETE", _) => Role::Writer,
+        _ => Role::Reader,
+    }
+}
+
+// The scope the keys minted with some need for the routes of handle_client: the
+// admin one for those of the admin role, one for reading, changing or deleting
+// the users, by the method, and none for logging in and the rest
+fn required_scope(method: &str, segments: &[&str]) -> Option<Scope> {
+    match (method, segments) {
+        _ if required_role(method, segments) == Role::Admin => Some(Scope::Admin),
+        ("GET" | "HEAD", ["users", ..]) | ("POST", ["users", "validate"]) => Some(Scope::UsersRead),
+        ("POST", ["users", "verify"] | ["users", _, "resend-verification"]) | ("PUT", ["users", _, "password"]) => None,
+        ("POST" | "PUT" | "PATCH", ["users", ..]) => Some(Scope::UsersWrite),
+        ("DELETE", ["users", ..]) => Some(Scope::UsersDelete),
+        _ => None,
+    }
+}
+
+// Handle the requests
+// The connection counts as open until slot is dropped
+pub(crate) fn handle_client(
+    mut stream: TcpStream,
+    slot: Slot<'static>,
+    peer: &str,
+    repository: &dyn UserRepository,
+    primary_reads: &dyn UserRepository
+) {
+    let mut buffer = vec![0; request_bytes()];
+
+    match read_request(&mut stream, &mut buffer) {
+        Ok(size) => {
+            let started = Instant::now();
+            // With the settings as they are after a SIGHUP
+            reload::reload_if_requested();
+            // Borrowed from the buffer unless it isn't valid UTF-8
+            let request = String::from_utf8_lossy(&buffer[..size]);
+
+            let method = request.split_whitespace().next().unwrap_or_default();
+            let segments = get_segments(&request);
+            let client = https::peer_addr(&stream).ok().map(|peer| proxy::client_ip(&request, peer.ip()));
+            // The certificate it authenticates with, when it came over TLS with one
+            let _certificate = https::enter(&stream);
+            let route = route_timeout::route(method, &segments);
+            // What the request does: a GraphQL document that isn't a mutation only
+            // reads, though it is posted
+            let acting = match (method, segments.as_slice()) {
+                ("POST", ["graphql"]) if !graphql::mutates(&request) => "GET",
+                _ => method,
+            };
+            // In every line logged while it is answered, and in the response
+            let request_id = request_id::from_request(&request);
+            let _request_id = request_id::enter(request_id.clone());
+            // The language of the messages of its errors
+            let _language = locale::enter(&request);
+            // The request span is under the traceparent it was sent with
+            let _trace_context = trace_context::enter(trace_context::from_request(&request));
+            let request_span = tracing::info_span!(
+                "request",
+                method,
+                route = route.as_str(),
+                request_id = request_id.as_str(),
+                db_calls = Empty,
+                db_ms = Empty,
+                pool_wait_ms = Empty
+            );
+            let _in_request = request_span.enter();
+            // Logged once answered, whichever way it was
+            let _logged = access_log::start(&request, route.clone(), client, started, request_span.clone());
+            body_log::request(&request);
+
+            // A request line longer than what is read would be routed on what of its
+            // target fits in it, a head on what of its headers does, and a body on
+            // its first MAX_BODY_BYTES. A connection
+            // closed without a request goes on to be counted as dropped.
+            if let Some(malformed) = (size > 0).then(|| malformed(&request, size)).flatten() {
+                connections::discard_unread(&mut stream);
+                let (status_line, content) = ApiError::Malformed(malformed).response();
+                write_response(&mut stream, &status_line, &content).ok();
+                return;
+            }
+
+            // Until the migrations are applied only the probes, the metrics and the
+            // stats answer
+            let probe = matches!(
+                segments.as_slice(),
+                ["health"] | ["health", "details"] | ["livez"] | ["readyz"] | ["version"] | ["metrics"]
+                    | ["debug", "stats"]
+            );
+            let waiting_for = migrations::waiting_for();
+            if !waiting_for.is_empty() && !probe {
+                let body = format!("Waiting for migrations to be applied: {}", waiting_for.join(", "));
+                write_response(&mut stream, SERVICE_UNAVAILABLE, &body).ok();
+                return;
+            }
+
+            // Before anything is asked of the database, except by the probes and the
+            // metrics. A connection closed without a request isn't counted.
+            let rate_limited = size > 0 && !probe;
+            let decision = match client {
+                Some(client) if rate_limited => {
+                    let mutation = matches!(acting, "POST" | "PUT" | "PATCH" | "DELETE");
+                    rate_limit::check(client, mutation)
+                }
+                _ => None,
+            };
+            if let Some(decision) = decision.as_ref().filter(|decision| !decision.allowed) {
+                let (status_line, content) = too_many_requests(decision);
+                write_response(&mut stream, &status_line, &content).ok();
+                return;
+            }
+
+            // After the rate limit, which slows down the guessing of keys. A connection
+            // closed without a request isn't audited either.
+            let _audited = (size > 0).then(|| audit::enter(client, route.clone()));
+            // The exemptions are of the path as it is routed, without a trailing slash
+            let path: String = segments.iter().map(|segment| format!("/{}", segment)).collect();
+            let principal = match auth::authenticate(&request, &path) {
+                Ok(principal) => principal,
+                Err((status_line, content)) => {
+                    write_response(&mut stream, &status_line, &content).ok();
+                    return;
+                }
+            };
+            // Then those of the key it authenticated with, and the headers say what is
+            // left of whichever bucket is emptier
+            let key_decision = principal.as_ref().filter(|_| rate_limited).and_then(|principal| {
+                rate_limit::check_key(&principal.name)
+            });
+            if let Some(decision) = key_decision.as_ref().filter(|decision| !decision.allowed) {
+                let (status_line, content) = too_many_requests(decision);
+                write_response(&mut stream, &status_line, &content).ok();
+                return;
+            }
+            let decision = decision.into_iter().chain(key_decision).min_by_key(|decision| decision.remaining);
+            let (required, scope) = (required_role(method, &segments), required_scope(method, &segments));
+            if let Err((status_line, content)) = auth::authorize(principal.as_ref(), required, scope, &route) {
+                write_response(&mut stream, &status_line, &content).ok();
+                return;
+            }
+            let _principal = auth::enter(principal);
+            // Once its signature was checked on the body as it was sent. The fields of
+            // GraphQL are camelCase whatever JSON_CASE is.
+            let request = match segments.as_slice() {
+                ["graphql"] => Cow::Borrowed(request.as_ref()),
+                _ => json_case::inbound(&request),
+            };
+
+            let turned_away =
+                maintenance::turned_away(acting, &segments).or_else(|| read_only::rejected(acting, &segments));
+            if let Some((status_line, content)) = turned_away {
+                write_response(&mut stream, &status_line, &content).ok();
+                return;
+            }
+
+            // The streams listen to the notifications of Postgres
+            let streaming = method == "GET" && matches!(segments.as_slice(), ["users", "events"] | ["ws"]);
+            if streaming && POOL.get().is_none() {
+                let body = "Only available when DATABASE_URL is a Postgres database";
+                write_response(&mut stream, NOT_IMPLEMENTED, body).ok();
+                return;
+            }
+
+            // Everything about users is for the tenant of the request
+            let tenant_scoped = !matches!(
+                segments.as_slice(),
+                ["health", ..] | ["livez"] | ["readyz"] | ["version"] | ["metrics"] | ["debug", ..]
+                    | ["admin", "api-keys", ..]
+                    | ["admin", "auth-events" | "backup" | "chaos" | "fail" | "maintenance" | "read-only" | "sleep"]
+                    | ["admin", "tenants"]
+            );
+            let tenant = match tenant::from_request(&request) {
+                Ok(tenant) => tenant,
+                Err(e) if tenant_scoped => {
+                    write_response(&mut stream, BAD_REQUEST, e).ok();
+                    return;
+                }
+                Err(_) => tenant::DEFAULT_TENANT.to_owned(),
+            };
+            // With a schema per tenant, only once it was provisioned
+            if tenant_scoped {
+                let response = match tenant::is_provisioned(&tenant) {
+                    Ok(true) => None,
+                    Ok(false) => Some((NOT_FOUND.to_owned(), format!("Tenant {} not found", tenant))),
+                    Err(e) => Some(repository_error_response(e, "Error finding the tenant")),
+                };
+                if let Some((status_line, content)) = response {
+                    write_response(&mut stream, &status_line, &content).ok();
+                    return;
+                }
+            }
+
+            // Those reading from the primary want what it has now, not what is cached
+            let read_primary = method == "GET" && get_header(&request, "X-Read-Primary") == Some("true");
+            let repository = if read_primary { primary_reads } else { repository };
+
+            // The faults of the chaos mode, none for a connection closed without a request
+            let faulted = (size > 0).then(|| chaos::before_handler(method, &segments)).flatten();
+            if let Some((status_line, content)) = faulted {
+                write_response(&mut stream, &status_line, &content).ok();
+                return;
+            }
+
+            // The streams keep the connection open, so they get a thread of their own
+            // rather than holding a worker until the client leaves
+            if method == "GET" && segments == ["users", "events"] {
+                let last_event_id = get_header(&request, "Last-Event-ID").and_then(|id| id.parse().ok());
+                let (logged, request_span) = (access_log::hand_off(), request_span.clone());
+                let context = trace_context::current();
+                thread::spawn(move || {
+                    let _request_id = request_id::enter(request_id);
+                    let _trace_context = trace_context::enter(context);
+                    let _in_request = request_span.enter();
+                    let _logged = access_log::resume(logged);
+                    sse::stream_user_events(stream, tenant, last_event_id);
+                    drop(slot);
+                });
+                return;
+            }
+            if method == "GET" && segments == ["ws"] {
+                let (request, logged) = (request.into_owned(), access_log::hand_off());
+                let (request_span, context) = (request_span.clone(), trace_context::current());
+                thread::spawn(move || {
+                    let _request_id = request_id::enter(request_id);
+                    let _trace_context = trace_context::enter(context);
+                    let _in_request = request_span.enter();
+                    let _logged = access_log::resume(logged);
+                    ws::handle_upgrade(stream, &request, tenant);
+                    drop(slot);
+                });
+                return;
+            }
+
+            let budget = route_timeout::budget(&segments);
+            let watch = disconnect::watch(&stream, budget.map(|budget| started + budget));
+            // The other routes are for the whole server, and the main schema
+            let entered = tenant_scoped.then(|| tenant::enter(tenant));
+
+            let users = UserResource { use_cache: !read_primary, ..UserResource::new(repository) };
+            // The errors of the handlers get their status and their body here, the
+            // routes of the resources first
+            let handled = match resource::route(&users, method, &segments, &request, &route) {
+                Some(handled) => handled,
+                None => match (method, segments.as_slice()) {
+                    ("POST", ["login"]) => Ok(jwt::handle_login_request(repository, &request, client)),
+                    ("POST", ["token", "refresh"]) => Ok(refresh::handle_refresh_request(&request)),
+                    ("POST", ["logout"]) => Ok(refresh::handle_logout_request(&request)),
+                    ("POST", ["session"]) => Ok(sessions::handle_create_session_request(repository, &request, client)),
+                    ("GET", ["auth", "login"]) => Ok(oidc::handle_login_request()),
+                    // The users it creates are looked up right after
+                    ("GET", ["auth", "callback"]) => Ok(oidc::handle_callback_request(primary_reads, &request)),
+                    ("DELETE", ["session"]) => Ok(sessions::handle_delete_session_request(&request)),
+                    ("GET", ["session", "csrf"]) => Ok(sessions::handle_csrf_request(&request)),
+                    ("POST", ["users", "validate"]) => handle_validate_request(repository, &request),
+                    ("POST", ["users", "verify"]) => Ok(verification::handle_verify_request(&request)),
+                    ("POST", ["password-reset", "request"]) => {
+                        Ok(password_reset::handle_request_reset_request(repository, &request, client))
+                    }
+                    ("POST", ["password-reset", "confirm"]) => {
+                        Ok(password_reset::handle_confirm_reset_request(&request))
+                    }
+                    ("POST", ["graphql"]) => Ok(graphql::handle_graphql_request(repository, &request)),
+                    ("GET", ["graphql"]) if graphql::graphiql() => Ok(graphql::handle_graphiql_request()),
+                    ("PUT", ["users", id, "password"]) => with_own_id(id, &route, |id| {
+                        Ok(password::handle_change_password_request(repository, &request, id))
+                    }),
+                    ("PUT", ["users", id, "role"]) => {
+                        with_id(id, |id| handle_set_role_request(repository, &request, id))
+                    }
+                    ("POST", ["users", id, "resend-verification"]) => {
+                        with_own_id(id, &route, |id| Ok(verification::handle_resend_request(repository, id)))
+                    }
+                    ("POST", ["users", id, "anonymize"]) => {
+                        with_own_id(id, &route, |id| handle_anonymize_request(repository, &request, id))
+                    }
+                    ("GET", ["users", id, "export"]) => {
+                        with_id(id, |id| handle_export_request(repository, &request, id))
+                    }
+                    ("GET", ["events"]) => handle_get_events_request(repository, &request),
+                    ("GET", ["health"]) => Ok(handle_health_request(repository)),
+                    ("GET", ["health", "details"]) => Ok(health::handle_details_request(repository)),
+                    ("GET", ["livez"]) => Ok(handle_livez_request()),
+                    ("GET", ["version"]) => Ok(handle_version_request()),
+                    ("GET", ["readyz"]) => Ok(handle_readyz_request(repository)),
+                    ("GET", ["metrics"]) => Ok(metrics::handle_metrics_request()),
+                    ("GET", ["debug", "pool"]) if admin::endpoints_enabled() => {
+                        Ok(metrics::handle_pool_status_request())
+                    }
+                    ("GET", ["debug", "stats"]) if admin::endpoints_enabled() => {
+                        Ok(debug_stats::handle_stats_request())
+                    }
+                    ("POST", ["admin", "reset"]) if admin::endpoints_enabled() => {
+                        Ok(admin::handle_reset_request(repository, &request))
+                    }
+                    ("POST", ["admin", "backup"]) if admin::endpoints_enabled() => Ok(backup::handle_backup_request()),
+                    ("POST", ["admin", "tenants"]) if admin::endpoints_enabled() => {
+                        Ok(tenant::handle_create_tenant_request(&request))
+                    }
+                    ("GET", ["admin", "tenants"]) if admin::endpoints_enabled() => {
+                        Ok(tenant::handle_list_tenants_request())
+                    }
+                    ("GET", ["admin", "auth-events"]) if admin::endpoints_enabled() => {
+                        Ok(audit::handle_list_events_request(&request))
+                    }
+                    ("POST", ["admin", "api-keys"]) if admin::endpoints_enabled() => {
+                        Ok(api_keys::handle_create_api_key_request(&request))
+                    }
+                    ("GET", ["admin", "api-keys"]) if admin::endpoints_enabled() => {
+                        Ok(api_keys::handle_list_api_keys_request())
+                    }
+                    ("DELETE", ["admin", "api-keys", id]) if admin::endpoints_enabled() => {
+                        with_id(id, |id| Ok(api_keys::handle_revoke_api_key_request(id)))
+                    }
+                    ("GET", ["admin", "maintenance"]) if admin::endpoints_enabled() => {
+                        Ok(maintenance::handle_get_maintenance_request())
+                    }
+                    ("POST", ["admin", "maintenance"]) if admin::endpoints_enabled() => {
+                        Ok(maintenance::handle_set_maintenance_request(&request))
+                    }
+                    ("GET", ["admin", "read-only"]) if admin::endpoints_enabled() => {
+                        Ok(read_only::handle_get_read_only_request())
+                    }
+                    ("POST", ["admin", "read-only"]) if admin::endpoints_enabled() => {
+                        Ok(read_only::handle_set_read_only_request(&request))
+                    }
+                    ("GET", ["admin", "chaos"]) if admin::endpoints_enabled() && chaos::available() => {
+                        Ok(chaos::handle_get_chaos_request())
+                    }
+                    ("POST", ["admin", "chaos"]) if admin::endpoints_enabled() && chaos::available() => {
+                        Ok(chaos::handle_set_chaos_request(&request))
+                    }
+                    ("POST", ["admin", "seed"]) if admin::endpoints_enabled() => {
+                        Ok(admin::handle_seed_request(repository, &request))
+                    }
+                    ("DELETE", ["admin", "users", id, "refresh-tokens"]) if admin::endpoints_enabled() => {
+                        with_id(id, |id| Ok(refresh::handle_revoke_user_request(id)))
+                    }
+                    ("DELETE", ["admin", "lockouts"]) if admin::endpoints_enabled() => {
+                        Ok(lockout::handle_clear_lockout_request(&request))
+                    }
+                    ("GET", ["admin", "sleep"]) if admin::endpoints_enabled() => {
+                        Ok(admin::handle_sleep_request(repository, &request))
+                    }
+                    ("GET", ["admin", "fail"]) if admin::endpoints_enabled() => {
+                        Ok(admin::handle_fail_request(&request))
+                    }
+
+                    _ => Err(unrouted(&request, &segments)),
+                },
+            };
+            let (status_line, content) = handled.unwrap_or_else(ApiError::response);
+            drop(entered);
+            drop(watch);
+            let (status_line, content) =
+                route_timeout::timed_out(&route, started, budget).unwrap_or((status_line, content));
+
+            let status_line = with_response_headers(status_line, decision.as_ref());
+            if size > 0 && chaos::disconnects(method, &segments) {
+                write_response(&mut chaos::CutOff(&mut stream), &status_line, &content).ok();
+                return;
+            }
+            // A client gone before it was answered is counted by the access log
+            write_response(&mut stream, &status_line, &content).ok();
+        }
+        Err(e) => {
+            let client = https::peer_addr(&stream).ok().map(|peer| peer.ip());
+            let _logged = access_log::start("", "-".to_owned(), client, Instant::now(), Span::none());
+            // It held a worker for as long as it may, another one has it now
+            if timed_out(&e) {
+                let (status_line, content) = ApiError::Malformed(Malformed::TimedOut).response();
+                write_response(&mut stream, &status_line, &content).ok();
+                return;
+            }
+            log::error!("Error reading the request from {}: {}", peer, e);
+        }
+    }
+}
+
+fn too_many_requests(decision: &Decision) -> (String, String) {
+    let status_line = format!(
+        "HTTP/1.1 429 TOO MANY REQUESTS\r\nContent-Type: application/json\r\nRetry-After: {}\r\n{}\r\n\r\n",
+        decision.retry_after,
+        decision.headers()
+    );
+    let body = serde_json::json!({
+        "error": {
+            "code": "rate_limited",
+            "message": format!("Too many requests, retry in {} seconds", decision.retry_after),
+        }
+    });
+    (status_line, body.to_string())
+}
+
+// How long the request waited for the database to be free, and the rate limit,
+// added to the headers at once
+fn with_response_headers(status_line: String, decision: Option<&Decision>) -> String {
+    let mut headers = Vec::new();
+    // The time waited for permits, then that of the spans under the request
+    let waited = repository::bulkhead::take_waited().map(|waited| ("db-wait", waited));
+    let timings: Vec<String> = waited
+        .into_iter()
+        .chain(spans::take_timings())
+        .map(|(name, duration)| format!("{};dur={:.3}", name, duration.as_secs_f64() * 1000.0))
+        .collect();
+    if !timings.is_empty() {
+        headers.push(format!("Server-Timing: {}", timings.join(", ")));
+    }
+    if let Some(decision) = decision {
+        headers.push(decision.headers());
+    }
+    if headers.is_empty() {
+        status_line
+    } else {
+        with_header(&status_line, &headers.join("\r\n"))
+    }
+}
+
+// What a request none of the routes took is answered: a 405 with the methods of
+// the routes of its path, or a 404 when there are none, the admin routes having
+// none while they are off, nor GET /graphql without GraphiQL
+fn unrouted(request: &str, segments: &[&str]) -> ApiError {
+    let hidden = matches!(segments, ["admin" | "debug", ..]) && !admin::endpoints_enabled()
+        || segments == ["admin", "chaos"] && !chaos::available();
+    let mut allowed = if hidden { Vec::new() } else { route_metrics::allowed(segments) };
+    if segments == ["graphql"] && !graphql::graphiql() {
+        allowed.retain(|method| *method != "GET");
+    }
+    match allowed {
+        allowed if allowed.is_empty() => ApiError::NoRoute(get_path(request).to_owned()),
+        allowed => ApiError::MethodNotAllowed(allowed),
+    }
+}
+
+// Run a handler for the user id in the path, or answer 400 if it isn't one
+fn with_id(segment: &str, handler: impl FnOnce(i32) -> Handled) -> Handled {
+    let invalid = |reason| ApiError::Malformed(Malformed::InvalidId(segment.to_owned(), reason));
+    handler(parse_id(segment).map_err(invalid)?)
+}
+
+// For the routes of one user's record, which the accounts that aren't admins
+// only have for their own
+fn with_own_id(segment: &str, route: &str, handler: impl FnOnce(i32) -> Handled) -> Handled {
+    with_id(segment, |id| auth::owner_check(id, route).map_or_else(Ok, |()| handler(id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::OK_RESPONSE;
+
+    #[test]
+    fn the_routes_need_the_role_and_the_scope_of_what_they_do() {
+        let routes = [
+            ("GET", "/users", Role::Reader, Some(Scope::UsersRead)),
+            ("HEAD", "/users/1", Role::Reader, Some(Scope::UsersRead)),
+            ("POST", "/users", Role::Writer, Some(Scope::UsersWrite)),
+            ("DELETE", "/users/1", Role::Writer, Some(Scope::UsersDelete)),
+            ("PUT", "/users/1/password", Role::Reader, None),
+            ("PUT", "/users/1/role", Role::Admin, Some(Scope::Admin)),
+            ("GET", "/users/1/export", Role::Admin, Some(Scope::Admin)),
+            ("POST", "/login", Role::Reader, None),
+            ("GET", "/health", Role::Reader, None),
+            ("POST", "/graphql", Role::Reader, None),
+            // Those to come under /admin too
+            ("GET", "/admin/anything", Role::Admin, Some(Scope::Admin)),
+        ];
+        for (method, path, role, scope) in routes {
+            let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+            let required = (required_role(method, &segments), required_scope(method, &segments));
+            assert_eq!(required, (role, scope), "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn a_handler_only_runs_for_an_id() {
+        let found = with_id("5", |id| Ok((OK_RESPONSE.to_owned(), id.to_string())));
+        assert_eq!(found.ok(), Some((OK_RESPONSE.to_owned(), "5".to_owned())));
+        let refused = with_id("05", |_| unreachable!());
+        let Err(ApiError::Malformed(Malformed::InvalidId(segment, reason))) = refused else {
+            panic!("{:?}", refused);
+        };
+        assert!(segment == "05" && reason.contains("leading zeros"), "{}", reason);
+    }
+
+    // What a target is routed to: a route, a route with a valid id, one that
+    // answers 400 to its id, or none, which answers 404
+    #[derive(Debug, PartialEq)]
+    enum Routed {
+        To(&'static str),
+        WithId(&'static str, i32),
+        InvalidId(&'static str),
+        Unmatched,
+    }
+
+    // As handle_client reads it, the routes of route_metrics standing for those it
+    // matches
+    fn routed(method: &str, target: &[u8]) -> Routed {
+        let request = [method.as_bytes(), b" ", target, b" HTTP/1.1\r\nHost: localhost\r\n\r\n"].concat();
+        let request = String::from_utf8_lossy(&request);
+        let segments = get_segments(&request);
+        let template = match route_metrics::route(method, &segments).labels() {
+            (_, "unmatched") => return Routed::Unmatched,
+            (_, template) => template,
+        };
+        let mut ids = template.split('/').filter(|segment| !segment.is_empty()).zip(&segments);
+        match ids.find(|(segment, _)| *segment == "{id}").map(|(_, id)| parse_id(id)) {
+            None => Routed::To(template),
+            Some(Ok(id)) => Routed::WithId(template, id),
+            Some(Err(_)) => Routed::InvalidId(template),
+        }
+    }
+
+    #[test]
+    fn each_target_has_one_route_and_one_id() {
+        use Routed::*;
+        let targets: &[(&str, &[u8], Routed)] = &[
+            ("GET", b"/users", To("/users")),
+            ("GET", b"/users/", To("/users")),
+            ("GET", b"/users?limit=5&after_id=7", To("/users")),
+            ("GET", b"/users/?limit=5", To("/users")),
+            ("GET", b"/users/42", WithId("/users/{id}", 42)),
+            ("GET", b"/users/42?fields=name", WithId("/users/{id}", 42)),
+            ("GET", b"/users/42/", WithId("/users/{id}", 42)),
+            ("GET", b"/users//42", WithId("/users/{id}", 42)),
+            ("GET", b"/users/42#top", WithId("/users/{id}", 42)),
+            ("GET", b"http://localhost:8080/users/42", WithId("/users/{id}", 42)),
+            ("GET", b"/users/42/extra", Unmatched),
+            ("GET", b"/users/42/export/extra", Unmatched),
+            // Nothing is decoded: an id is only digits
+            ("GET", b"/users/%34%32", InvalidId("/users/{id}")),
+            ("GET", b"/users/4%2F2", InvalidId("/users/{id}")),
+            ("GET", b"/%75sers/42", Unmatched),
+            // Nor are the dot segments resolved
+            ("GET", b"/users/./42", Unmatched),
+            ("POST", b"/users/42/../../admin/reset", Unmatched),
+            ("GET", b"/users/-1", InvalidId("/users/{id}")),
+            ("GET", b"/users/+1", InvalidId("/users/{id}")),
+            ("GET", b"/users/0", InvalidId("/users/{id}")),
+            ("GET", b"/users/042", InvalidId("/users/{id}")),
+            ("GET", b"/users/2147483648", InvalidId("/users/{id}")),
+            ("GET", b"/users/\xff42", InvalidId("/users/{id}")),
+            ("GET", b"/users/42\xc3", InvalidId("/users/{id}")),
+            ("GET", b"/\xffusers/42", Unmatched),
+            ("GET", b"/users/validate", InvalidId("/users/{id}")),
+            ("GET", b"/", Unmatched),
+            ("GET", b"", Unmatched),
+            ("PATCH", b"/users/42", Unmatched),
+            ("get", b"/users", Unmatched),
+            ("GET", b"/USERS", Unmatched),
+            ("GET", b"/users/events", To("/users/events")),
+            ("POST", b"/users", To("/users")),
+            ("POST", b"/users/validate", To("/users/validate")),
+            ("POST", b"/users/verify?token=abc", To("/users/verify")),
+            ("PUT", b"/users/42", WithId("/users/{id}", 42)),
+            ("DELETE", b"/users/42", WithId("/users/{id}", 42)),
+            ("DELETE", b"/users/", Unmatched),
+            ("PUT", b"/users/42/password", WithId("/users/{id}/password", 42)),
+            ("PUT", b"/users/42/role", WithId("/users/{id}/role", 42)),
+            ("PUT", b"/users/me/role", InvalidId("/users/{id}/role")),
+            ("POST", b"/users/42/resend-verification", WithId("/users/{id}/resend-verification", 42)),
+            ("POST", b"/users/42/anonymize", WithId("/users/{id}/anonymize", 42)),
+            ("GET", b"/users/42/export?limit=10", WithId("/users/{id}/export", 42)),
+            ("GET", b"/ws", To("/ws")),
+            ("GET", b"/events", To("/events")),
+            ("POST", b"/login", To("/login")),
+            ("POST", b"//login", To("/login")),
+            ("POST", b"/token/refresh", To("/token/refresh")),
+            ("POST", b"/logout", To("/logout")),
+            ("POST", b"/session", To("/session")),
+            ("DELETE", b"/session", To("/session")),
+            ("GET", b"/session/csrf", To("/session/csrf")),
+            ("GET", b"/auth/login", To("/auth/login")),
+            ("GET", b"/auth/callback?code=abc&state=def", To("/auth/callback")),
+            ("POST", b"/password-reset/request", To("/password-reset/request")),
+            ("POST", b"/password-reset/confirm", To("/password-reset/confirm")),
+            ("GET", b"/graphql", To("/graphql")),
+            ("POST", b"/graphql", To("/graphql")),
+            ("GET", b"/health", To("/health")),
+            ("GET", b"/health/details", To("/health/details")),
+            ("GET", b"/livez", To("/livez")),
+            ("GET", b"/version", To("/version")),
+            ("GET", b"/readyz", To("/readyz")),
+            ("GET", b"/metrics", To("/metrics")),
+            ("GET", b"/debug/pool", To("/debug/pool")),
+            ("GET", b"/debug/stats", To("/debug/stats")),
+            ("POST", b"/admin/reset", To("/admin/reset")),
+            ("POST", b"/admin/backup", To("/admin/backup")),
+            ("POST", b"/admin/seed?count=10", To("/admin/seed")),
+            ("GET", b"/admin/sleep?ms=10", To("/admin/sleep")),
+            ("GET", b"/admin/fail", To("/admin/fail")),
+            ("GET", b"/admin/tenants", To("/admin/tenants")),
+            ("POST", b"/admin/tenants", To("/admin/tenants")),
+            ("GET", b"/admin/auth-events", To("/admin/auth-events")),
+            ("GET", b"/admin/api-keys", To("/admin/api-keys")),
+            ("POST", b"/admin/api-keys", To("/admin/api-keys")),
+            ("DELETE", b"/admin/api-keys/7", WithId("/admin/api-keys/{id}", 7)),
+            ("DELETE", b"/admin/api-keys/seven", InvalidId("/admin/api-keys/{id}")),
+            ("GET", b"/admin/maintenance", To("/admin/maintenance")),
+            ("POST", b"/admin/maintenance", To("/admin/maintenance")),
+            ("GET", b"/admin/read-only", To("/admin/read-only")),
+            ("POST", b"/admin/read-only", To("/admin/read-only")),
+            ("GET", b"/admin/chaos", To("/admin/chaos")),
+            ("POST", b"/admin/chaos", To("/admin/chaos")),
+            ("DELETE", b"/admin/users/7/refresh-tokens", WithId("/admin/users/{id}/refresh-tokens", 7)),
+            ("DELETE", b"/admin/lockouts", To("/admin/lockouts")),
+            ("GET", b"/admin/anything", Unmatched),
+        ];
+        for (method, target, expected) in targets {
+            assert_eq!(routed(method, target), *expected, "{} {}", method, String::from_utf8_lossy(target));
+        }
+        // A path of its own is routed on all of it, however long, as long as the
+        // request line fits in what is read of it
+        let long = format!("/users/{}", "1".repeat(900));
+        assert_eq!(routed("GET", long.as_bytes()), InvalidId("/users/{id}"));
+        assert_eq!(routed("GET", format!("{}/42", "/users".repeat(150)).as_bytes()), Unmatched);
+
+        // Each route has its rows, so that a new one is added with its own
+        for (method, template) in route_metrics::ROUTES {
+            let tested = targets.iter().any(|(each, _, routed)| {
+                *each == method && matches!(routed, To(t) | WithId(t, _) | InvalidId(t) if *t == template)
+            });
+            assert!(tested, "{} {} has no target in the table", method, template);
+        }
+    }
+}
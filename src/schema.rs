@@ -0,0 +1,127 @@
+use postgres::Client;
+use std::error::Error;
+
+use crate::config;
+use crate::tables;
+
+// The columns of users the API reads and writes, as (name, accepted data types,
+// nullable). User is built from them in repository::postgres.
+const USERS_COLUMNS: &[(&str, &[&str], bool)] = &[
+    ("id", &["integer"], false),
+    ("name", &["character varying", "text"], false),
+    ("email", &["character varying", "text"], false),
+    ("anonymized_at", &["timestamp with time zone"], true),
+    ("tenant_id", &["character varying", "text"], false),
+    ("email_hash", &["character varying", "text"], true),
+    ("password_hash", &["character varying", "text"], true),
+    ("role", &["character varying", "text"], false),
+    ("verified_at", &["timestamp with time zone"], true),
+];
+
+// What a mismatch between the users table and the API does, from SCHEMA_CHECK
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strictness {
+    // Refuse to start
+    Strict,
+    // Log the differences and serve anyway
+    Warn,
+    Off,
+}
+
+impl Strictness {
+    pub fn from_env() -> Result<Self, String> {
+        match config::var("SCHEMA_CHECK").as_deref() {
+            Ok("strict") | Err(_) => Ok(Strictness::Strict),
+            Ok("warn") => Ok(Strictness::Warn),
+            Ok("off") => Ok(Strictness::Off),
+            Ok(value) => Err(format!("SCHEMA_CHECK must be strict, warn or off, got {:?}", value)),
+        }
+    }
+}
+
+struct Column {
+    name: String,
+    data_type: String,
+    nullable: bool,
+    has_default: bool,
+}
+
+// Compare the users table with what the API expects, and log every difference.
+// Extra columns are only a warning, the API never reads them.
+pub fn check(client: &mut Client, strictness: &Strictness) -> Result<(), Box<dyn Error>> {
+    if *strictness == Strictness::Off {
+        return Ok(());
+    }
+
+    let columns: Vec<Column> = client
+        .query(
+            "SELECT column_name::text, data_type::text, is_nullable = 'YES', column_default IS NOT NULL
+            FROM information_schema.columns
+            WHERE table_schema = current_schema() AND table_name = $1",
+            &[&tables::prefixed("users")]
+        )?
+        .iter()
+        .map(|row| Column { name: row.get(0), data_type: row.get(1), nullable: row.get(2), has_default: row.get(3) })
+        .collect();
+    if columns.is_empty() {
+        return found_differences(strictness, vec![format!("missing table {}", tables::prefixed("users"))]);
+    }
+
+    let mut differences = Vec::new();
+    for &(name, data_types, nullable) in USERS_COLUMNS {
+        let column = match columns.iter().find(|column| column.name == name) {
+            Some(column) => column,
+            None => {
+                differences.push(format!("missing column {}", name));
+                continue;
+            }
+        };
+
+        if !data_types.contains(&column.data_type.as_str()) {
+            differences.push(
+                format!("column {} is {} but the API expects {}", name, column.data_type, data_types.join(" or "))
+            );
+        }
+        if column.nullable && !nullable {
+            differences.push(format!("column {} is nullable but the API expects NOT NULL", name));
+        }
+        if !column.nullable && nullable {
+            differences.push(format!("column {} is NOT NULL but the API expects it nullable", name));
+        }
+    }
+
+    for column in &columns {
+        if USERS_COLUMNS.iter().any(|&(name, _, _)| name == column.name) {
+            continue;
+        }
+        if !column.nullable && !column.has_default {
+            log::warn!(
+                "Unknown column users.{} is NOT NULL without a default, creating users will fail",
+                column.name
+            );
+        } else {
+            log::warn!("Unknown column users.{}", column.name);
+        }
+    }
+
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        found_differences(strictness, differences)
+    }
+}
+
+fn found_differences(strictness: &Strictness, differences: Vec<String>) -> Result<(), Box<dyn Error>> {
+    for difference in &differences {
+        log::warn!("Schema mismatch: {}", difference);
+    }
+    if *strictness == Strictness::Strict {
+        return Err(
+            format!(
+                "the users table doesn't match what the API expects ({}), set SCHEMA_CHECK=warn to serve anyway",
+                differences.join(", ")
+            ).into()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use std::sync::OnceLock;
+
+use crate::config;
+use crate::http::get_header;
+
+// Each header, by the variable that replaces its value, an empty one leaving it out
+const HEADERS: [(&str, &str, &str); 3] = [
+    ("X-Content-Type-Options", "SECURITY_HEADER_X_CONTENT_TYPE_OPTIONS", "nosniff"),
+    ("X-Frame-Options", "SECURITY_HEADER_X_FRAME_OPTIONS", "DENY"),
+    ("Referrer-Policy", "SECURITY_HEADER_REFERRER_POLICY", "no-referrer"),
+];
+const HSTS: (&str, &str, &str) =
+    ("Strict-Transport-Security", "SECURITY_HEADER_STRICT_TRANSPORT_SECURITY", "max-age=31536000; includeSubDomains");
+const CSP: (&str, &str, &str) = (
+    "Content-Security-Policy",
+    "SECURITY_HEADER_CONTENT_SECURITY_POLICY",
+    "default-src 'none'; frame-ancestors 'none'",
+);
+
+static CONFIG: OnceLock<SecurityHeadersConfig> = OnceLock::new();
+
+// The headers every response gets, whatever its status, unless the handler set
+// them already: X-Content-Type-Options, X-Frame-Options and Referrer-Policy, then
+// Strict-Transport-Security with TLS_TERMINATED_UPSTREAM=true, for the proxies in
+// front of the server that serve HTTPS, and Content-Security-Policy on the
+// text/html responses. SECURITY_HEADER_<NAME> replaces the value of a header, the
+// name in upper case with underscores, and an empty value leaves it out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecurityHeadersConfig {
+    // As Name: value
+    pub headers: Vec<String>,
+    pub html_headers: Vec<String>,
+}
+
+impl SecurityHeadersConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let tls = match config::var("TLS_TERMINATED_UPSTREAM").as_deref() {
+            Ok("false") | Err(_) => false,
+            Ok("true") => true,
+            Ok(value) => return Err(format!("TLS_TERMINATED_UPSTREAM must be true or false, got {:?}", value)),
+        };
+        let header = |(name, variable, default): (&str, &str, &str)| {
+            let value = config::var(variable).unwrap_or_else(|_| default.to_owned());
+            if value.contains(['\r', '\n']) {
+                return Err(format!("{} must be on one line", variable));
+            }
+            let value = Some(value.trim().to_owned()).filter(|value| !value.is_empty());
+            Ok(value.map(|value| format!("{}: {}", name, value)))
+        };
+        let mut headers = HEADERS.into_iter().map(header).collect::<Result<Vec<_>, _>>()?;
+        if tls {
+            headers.push(header(HSTS)?);
+        }
+        let html_headers = header(CSP)?.into_iter().collect();
+        Ok(SecurityHeadersConfig { headers: headers.into_iter().flatten().collect(), html_headers })
+    }
+
+    // The status line and headers with those of the config they don't have
+    fn added(&self, head: &str) -> String {
+        let html = get_header(head, "Content-Type").is_some_and(|content_type| content_type.starts_with("text/html"));
+        let missing: Vec<&str> = self
+            .headers
+            .iter()
+            .chain(self.html_headers.iter().filter(|_| html))
+            .map(String::as_str)
+            .filter(|header| header.split_once(':').is_some_and(|(name, _)| get_header(head, name).is_none()))
+            .collect();
+        if missing.is_empty() {
+            return head.to_owned();
+        }
+        let headers = head.strip_suffix("\r\n\r\n").unwrap_or(head);
+        format!("{}\r\n{}\r\n\r\n", headers, missing.join("\r\n"))
+    }
+}
+
+pub fn init(config: SecurityHeadersConfig) {
+    CONFIG.set(config).ok();
+}
+
+// The head of a response with the security headers, as it is without a config
+pub fn added(head: &str) -> String {
+    match CONFIG.get() {
+        Some(config) => config.added(head),
+        None => head.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_are_added_unless_there_already() {
+        let config = SecurityHeadersConfig {
+            headers: vec!["X-Frame-Options: DENY".to_owned(), "Referrer-Policy: no-referrer".to_owned()],
+            html_headers: vec!["Content-Security-Policy: default-src 'none'".to_owned()],
+        };
+        let head = "HTTP/1.1 404 NOT FOUND\r\nContent-Type: application/json\r\nx-frame-options: SAMEORIGIN\r\n\r\n";
+        assert_eq!(config.added(head), head.replace("\r\n\r\n", "\r\nReferrer-Policy: no-referrer\r\n\r\n"));
+
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\r\n";
+        let added = config.added(head);
+        assert!(added.ends_with("\r\nContent-Security-Policy: default-src 'none'\r\n\r\n"), "{}", added);
+        assert!(added.contains("\r\nX-Frame-Options: DENY\r\n"), "{}", added);
+    }
+}
@@ -0,0 +1,278 @@
+use chrono::{ DateTime, Utc };
+use sha2::{ Digest, Sha256 };
+use std::net::IpAddr;
+
+use crate::jwt::Claims;
+use crate::config::{ self, number_from_env };
+use crate::repository::{ stored_role, RepositoryError, UserRepository };
+use crate::db::pool;
+use crate::errors::repository_error_response;
+use crate::http::{ get_header, with_header, BAD_REQUEST, NOT_IMPLEMENTED, OK_RESPONSE };
+use crate::{ api_keys, auth, password, read_only, tables, tenant };
+
+// The name of the cookie
+pub const COOKIE: &str = "session";
+
+// The CSRF token of the session, in a cookie the pages can read, and where they
+// send it back
+const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+const DEFAULT_IDLE_SECS: u64 = 30 * 60;
+const DEFAULT_LIFETIME_SECS: u64 = 12 * 60 * 60;
+
+// With SESSIONS=true, POST /session logs in for a cookie rather than a token, for
+// the browsers that can't set Authorization. A session ends SESSION_IDLE_SECS
+// after its last request, and SESSION_LIFETIME_SECS after it began however busy
+// it was. Each session has a CSRF token too, in the csrf_token cookie and from
+// GET /session/csrf, which the requests that change anything with the cookie send
+// back in X-CSRF-Token, for those another site makes the browser send to fail.
+// Postgres only, the sessions are rows of its sessions table.
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    pub idle: u64,
+    pub lifetime: u64,
+}
+
+impl SessionConfig {
+    // None without sessions
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match config::var("SESSIONS").as_deref() {
+            Ok("false") | Err(_) => return Ok(None),
+            Ok("true") => {}
+            Ok(value) => return Err(format!("SESSIONS must be true or false, got {:?}", value)),
+        }
+        let idle = number_from_env("SESSION_IDLE_SECS", DEFAULT_IDLE_SECS)?;
+        let lifetime = number_from_env("SESSION_LIFETIME_SECS", DEFAULT_LIFETIME_SECS)?;
+        if idle == 0 || lifetime == 0 {
+            return Err("SESSION_IDLE_SECS and SESSION_LIFETIME_SECS must be at least 1".to_owned());
+        }
+        Ok(Some(SessionConfig { idle, lifetime }))
+    }
+}
+
+// The value of the cookie with that name, in any of the Cookie headers, without
+// the double quotes RFC 6265 allows around it
+pub fn cookie<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .split("\r\n")
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(key, _)| key.trim().eq_ignore_ascii_case("Cookie"))
+        .flat_map(|(_, cookies)| cookies.split(';'))
+        .filter_map(|cookie| cookie.split_once('='))
+        .find(|(key, _)| key.trim() == name)
+        .map(|(_, value)| {
+            let value = value.trim();
+            value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value)
+        })
+}
+
+fn set_cookie(id: &str) -> String {
+    format!("Set-Cookie: {}={}; Path=/; HttpOnly; SameSite=Lax; Secure", COOKIE, id)
+}
+
+// Without HttpOnly, for the scripts of the pages to read it
+fn set_csrf_cookie(csrf_token: &str) -> String {
+    format!("Set-Cookie: {}={}; Path=/; SameSite=Lax; Secure", CSRF_COOKIE, csrf_token)
+}
+
+// For the sessions that ended, and those that never were, with their CSRF token
+pub fn clear_cookie() -> String {
+    format!(
+        "Set-Cookie: {}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax; Secure\r\n\
+        Set-Cookie: {}=; Path=/; Max-Age=0; SameSite=Lax; Secure",
+        COOKIE,
+        CSRF_COOKIE
+    )
+}
+
+// A session found by the id of its cookie
+pub struct Session {
+    pub claims: Claims,
+    // None for the sessions begun before there were tokens
+    pub csrf_token: Option<String>,
+}
+
+impl Session {
+    // Whether the request sent the CSRF token of the session, compared by their
+    // digests in as long whatever it sent
+    pub fn csrf_sent(&self, request: &str) -> bool {
+        let (Some(expected), Some(sent)) = (&self.csrf_token, get_header(request, CSRF_HEADER)) else {
+            return false;
+        };
+        let (expected, sent) = (Sha256::digest(expected.as_bytes()), Sha256::digest(sent.as_bytes()));
+        expected.iter().zip(sent.iter()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+    }
+}
+
+// The session with this id in the tenant, its claims as a token would have them,
+// or None once it ended and for ids that aren't any. Each request moves its end
+// up, as far as the lifetime allows, unless the service is read-only.
+pub fn find(config: &SessionConfig, id: &str, tenant: &str) -> Result<Option<Session>, RepositoryError> {
+    let _entered = tenant::enter(tenant.to_owned());
+    let id_hash = api_keys::hash(id);
+    let row = if read_only::enabled() {
+        let query = tables::sql(
+            "SELECT user_id, EXTRACT(EPOCH FROM s.created_at)::bigint, EXTRACT(EPOCH FROM expires_at)::bigint, role,
+                csrf_token
+            FROM {sessions} s JOIN {users} u ON u.id = s.user_id
+            WHERE s.id = $1 AND s.tenant_id = $2 AND expires_at > now()"
+        );
+        pool().read(|client| client.query_opt(query, &[&id_hash, &tenant]))?
+    } else {
+        let query = tables::sql(
+            "UPDATE {sessions} s SET last_seen = now(),
+                expires_at = LEAST(s.created_at + $3 * interval '1 second', now() + $4 * interval '1 second')
+            FROM {users} u
+            WHERE s.id = $1 AND s.tenant_id = $2 AND expires_at > now() AND u.id = s.user_id
+            RETURNING user_id, EXTRACT(EPOCH FROM s.created_at)::bigint, EXTRACT(EPOCH FROM expires_at)::bigint, role,
+                csrf_token"
+        );
+        let (lifetime, idle) = (config.lifetime as f64, config.idle as f64);
+        pool().get()?.query_opt(query, &[&id_hash, &tenant, &lifetime, &idle])?
+    };
+
+    Ok(row.map(|row| {
+        let (user_id, created, expires): (i32, i64, i64) = (row.get(0), row.get(1), row.get(2));
+        let claims = Claims {
+            sub: user_id.to_string(),
+            tenant: tenant.to_owned(),
+            // The role of the user now, unlike a token's
+            role: stored_role(row.get(3)),
+            iat: created as u64,
+            nbf: created as u64,
+            exp: expires as u64,
+        };
+        Session { claims, csrf_token: row.get(4) }
+    }))
+}
+
+// A new session of the user in the tenant entered, its CSRF token, and when it
+// ends unless used. The sessions that ended are deleted on the way.
+fn create(config: &SessionConfig, user_id: i32) -> Result<(String, String, DateTime<Utc>), RepositoryError> {
+    let (id, csrf_token) = (api_keys::generate(), api_keys::generate());
+    let mut client = pool().get()?;
+    client.execute(tables::sql("DELETE FROM {sessions} WHERE expires_at <= now()"), &[])?;
+    let row = client.query_one(
+        tables::sql(
+            "INSERT INTO {sessions} (id, user_id, tenant_id, expires_at, csrf_token)
+            VALUES ($1, $2, $3, now() + $4 * interval '1 second', $5) RETURNING expires_at"
+        ),
+        &[
+            &api_keys::hash(&id),
+            &user_id,
+            &tenant::current(),
+            &(config.idle.min(config.lifetime) as f64),
+            &csrf_token,
+        ]
+    )?;
+    Ok((id, csrf_token, row.get(0)))
+}
+
+fn only_with_sessions() -> Result<&'static SessionConfig, (String, String)> {
+    auth::sessions().ok_or_else(|| (NOT_IMPLEMENTED.to_owned(), "Only available with SESSIONS=true".to_owned()))
+}
+
+// POST /session with {"email": ..., "password": ...}, the cookie of a new session
+pub fn handle_create_session_request(
+    repository: &dyn UserRepository,
+    request: &str,
+    client: Option<IpAddr>
+) -> (String, String) {
+    let config = match only_with_sessions() {
+        Ok(config) => config,
+        Err(response) => {
+            return response;
+        }
+    };
+    match password::log_in(repository, request, client) {
+        Ok(account) => started(config, account.id),
+        Err(response) => response,
+    }
+}
+
+// The response of a new session of the user, with its cookies, for POST /session
+// and the logins of the OIDC provider
+pub fn started(config: &SessionConfig, user_id: i32) -> (String, String) {
+    match create(config, user_id) {
+        Ok((id, csrf_token, expires_at)) => {
+            let body = serde_json::json!({ "user_id": user_id, "expires_at": expires_at, "csrf_token": csrf_token });
+            let status_line = with_header(&with_header(OK_RESPONSE, &set_cookie(&id)), &set_csrf_cookie(&csrf_token));
+            (status_line, body.to_string())
+        }
+        Err(e) => repository_error_response(e, "Error starting the session"),
+    }
+}
+
+// GET /session/csrf, the CSRF token of the session of the cookie, in the cookie
+// again as well. A session begun before there were tokens gets one.
+pub fn handle_csrf_request(request: &str) -> (String, String) {
+    if let Err(response) = only_with_sessions() {
+        return response;
+    }
+    let Some(id) = cookie(request, COOKIE) else {
+        return (BAD_REQUEST.to_owned(), "Only for the requests with the cookie of a session".to_owned());
+    };
+    let (id_hash, tenant, csrf_token) = (api_keys::hash(id), tenant::current(), api_keys::generate());
+    let row = if read_only::enabled() {
+        let query = tables::sql(
+            "SELECT csrf_token FROM {sessions} WHERE id = $1 AND tenant_id = $2 AND expires_at > now()"
+        );
+        pool().read(|client| client.query_opt(query, &[&id_hash, &tenant])).map_err(RepositoryError::from)
+    } else {
+        let query = tables::sql(
+            "UPDATE {sessions} SET csrf_token = COALESCE(csrf_token, $3)
+            WHERE id = $1 AND tenant_id = $2 AND expires_at > now() RETURNING csrf_token"
+        );
+        pool().get().map_err(RepositoryError::from).and_then(|mut client| {
+            client.query_opt(query, &[&id_hash, &tenant, &csrf_token]).map_err(RepositoryError::from)
+        })
+    };
+    match row.map(|row| row.and_then(|row| row.get::<_, Option<String>>(0))) {
+        Ok(Some(csrf_token)) => {
+            let body = serde_json::json!({ "csrf_token": csrf_token });
+            (with_header(OK_RESPONSE, &set_csrf_cookie(&csrf_token)), body.to_string())
+        }
+        // It ended since the request was let in, or is read-only without a token
+        Ok(None) => auth::unauthorized_problem("invalid_session", "The session ended or never was"),
+        Err(e) => repository_error_response(e, "Error reading the CSRF token"),
+    }
+}
+
+// DELETE /session, which ends the session of the cookie if there is one and
+// clears the cookie either way
+pub fn handle_delete_session_request(request: &str) -> (String, String) {
+    if let Err(response) = only_with_sessions() {
+        return response;
+    }
+    if let Some(id) = cookie(request, COOKIE) {
+        let query = tables::sql("DELETE FROM {sessions} WHERE id = $1 AND tenant_id = $2");
+        let (id_hash, tenant) = (api_keys::hash(id), tenant::current());
+        let ended = pool().get().map_err(RepositoryError::from).and_then(|mut client| {
+            client.execute(query, &[&id_hash, &tenant]).map_err(RepositoryError::from)
+        });
+        if let Err(e) = ended {
+            return repository_error_response(e, "Error ending the session");
+        }
+    }
+    let body = serde_json::json!({ "logged_out": true });
+    (with_header(OK_RESPONSE, &clear_cookie()), body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookies_are_found_among_the_others() {
+        let request = "GET /users HTTP/1.1\r\nCookie: theme=dark; session=abc\r\n\r\n";
+        assert_eq!(cookie(request, "session"), Some("abc"));
+        let request = "GET /users HTTP/1.1\r\ncookie: theme=dark\r\nCookie:session=\"a=b\" ; lang=en\r\n\r\n";
+        assert_eq!(cookie(request, "session"), Some("a=b"));
+        assert_eq!(cookie(request, "lang"), Some("en"));
+        let request = "GET /users HTTP/1.1\r\nCookie: my_session=abc; sessions=def\r\n\r\nCookie: session=body";
+        assert_eq!(cookie(request, "session"), None);
+    }
+}
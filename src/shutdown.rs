@@ -0,0 +1,102 @@
+use socket2::{ Domain, Protocol, Socket, Type };
+use std::fs::File;
+use std::io::{ self, Read };
+use std::net::{ SocketAddr, TcpListener };
+use std::os::fd::{ FromRawFd, RawFd };
+use std::sync::atomic::{ AtomicBool, AtomicI32, AtomicUsize, Ordering };
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{ flag_from_env, number_from_env };
+
+const DEFAULT_GRACE: Duration = Duration::from_secs(20);
+// As std binds
+const BACKLOG: i32 = 128;
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+// Where the signal handler writes to wake the thread that shuts the server down
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+// On SIGTERM or SIGINT the server stops accepting connections and answers 503 to
+// /readyz, then exits once the open connections are closed, or after
+// SHUTDOWN_GRACE_MS. A second signal exits at once.
+//
+// With REUSE_PORT=true the port is bound with SO_REUSEPORT, so that a new version
+// can be started on it before the old one is sent SIGTERM: the kernel spreads the
+// new connections over both until the old one stops accepting, then hands them all
+// to the new one while the old one drains.
+#[derive(Clone, Debug)]
+pub struct ShutdownConfig {
+    pub grace: Duration,
+    pub reuse_port: bool,
+}
+
+impl ShutdownConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let grace = number_from_env("SHUTDOWN_GRACE_MS", DEFAULT_GRACE.as_millis() as u64)?;
+        let reuse_port = flag_from_env("REUSE_PORT", false)?;
+        Ok(ShutdownConfig { grace: Duration::from_millis(grace), reuse_port })
+    }
+}
+
+pub fn bind(addr: SocketAddr, config: &ShutdownConfig) -> io::Result<TcpListener> {
+    if !config.reuse_port {
+        return TcpListener::bind(addr);
+    }
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+// Wakes the accept loop with an error, and takes the listener out of those sharing
+// the port, which get its new connections from then on. The connections it had
+// queued but not accepted yet are reset.
+pub fn stop_accepting(listener: RawFd) {
+    unsafe { libc::shutdown(listener, libc::SHUT_RD) };
+}
+
+// Since the first signal, or ServerHandle::shutdown
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+// Whether the server wasn't draining yet
+pub fn start_draining() -> bool {
+    !DRAINING.swap(true, Ordering::Relaxed)
+}
+
+// Run on_signal on a thread of its own after the first signal
+pub fn on_signal(on_signal: impl FnOnce() + Send + 'static) -> io::Result<()> {
+    extern "C" fn request_shutdown(_signal: libc::c_int) {
+        if SIGNALS.fetch_add(1, Ordering::Relaxed) > 0 {
+            unsafe { libc::_exit(1) };
+        }
+        let byte = 1u8;
+        unsafe { libc::write(WAKE_FD.load(Ordering::Relaxed), &byte as *const u8 as *const libc::c_void, 1) };
+    }
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut woken = unsafe { File::from_raw_fd(fds[0]) };
+    WAKE_FD.store(fds[1], Ordering::Relaxed);
+
+    // Only touches atomics and writes to the pipe, which are safe in a signal handler
+    unsafe {
+        for signal in [libc::SIGTERM, libc::SIGINT] {
+            libc::signal(signal, request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+
+    thread::spawn(move || {
+        if woken.read(&mut [0]).is_ok() {
+            on_signal();
+        }
+    });
+    Ok(())
+}
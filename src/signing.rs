@@ -0,0 +1,281 @@
+use hmac::{ Hmac, Mac };
+use sha2::{ Digest, Sha256 };
+use std::collections::hash_map::{ Entry, HashMap };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Mutex, OnceLock };
+
+use crate::jwt::Rejected;
+use crate::config::{ self, number_from_env };
+use crate::repository::RepositoryError;
+use crate::db::pool;
+use crate::http::{ get_body, get_header };
+use crate::{ clock, tables };
+
+type HmacSha256 = Hmac<Sha256>;
+
+const DEFAULT_MAX_SKEW_SECS: u64 = 5 * 60;
+const MAX_NONCE_LENGTH: usize = 128;
+// The nonces kept in memory before the forgotten ones are swept out, at the least
+const MIN_SWEEP: usize = 1024;
+// One in so many nonces checked in the database sweeps out the forgotten ones
+const SWEEP_EVERY: u64 = 100;
+
+static SEEN: OnceLock<Mutex<Seen>> = OnceLock::new();
+static DATABASE_CHECKS: AtomicU64 = AtomicU64::new(0);
+
+// With REQUEST_SIGNING=true, a request may be signed with one of the keys of
+// API_KEYS rather than carry it: X-Signature holds the HMAC-SHA256 in hex, with
+// the key as the secret, of the method, the target as sent, X-Timestamp and the
+// SHA-256 in hex of the body, a line each, and X-Timestamp the seconds since the
+// epoch, within SIGNATURE_MAX_SKEW_SECS of the clock of the server either way.
+// With X-Nonce, random and new for each request, the nonce is signed too, on a
+// line after X-Timestamp, and the key can't send it again while the timestamp is
+// good: the replay is turned away as nonce_replayed. SIGNATURE_NONCE_REQUIRED=true
+// takes no signature without a nonce. The nonces are kept in memory, or with
+// SIGNATURE_NONCE_STORE=postgres in the database, for every instance to know them.
+// Those of the database can't sign, only their hashes are stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SigningConfig {
+    pub max_skew: u64,
+    pub nonce_required: bool,
+    pub nonce_store: NonceStore,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NonceStore {
+    Memory,
+    Postgres,
+}
+
+impl SigningConfig {
+    // None without signed requests
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match config::var("REQUEST_SIGNING").as_deref() {
+            Ok("false") | Err(_) => Ok(None),
+            Ok("true") => {
+                let max_skew = number_from_env("SIGNATURE_MAX_SKEW_SECS", DEFAULT_MAX_SKEW_SECS)?;
+                let nonce_required = match config::var("SIGNATURE_NONCE_REQUIRED").as_deref() {
+                    Ok("false") | Err(_) => false,
+                    Ok("true") => true,
+                    Ok(value) => return Err(format!("SIGNATURE_NONCE_REQUIRED must be true or false, got {:?}", value)),
+                };
+                let nonce_store = match config::var("SIGNATURE_NONCE_STORE").as_deref() {
+                    Ok("memory") | Err(_) => NonceStore::Memory,
+                    Ok("postgres") => NonceStore::Postgres,
+                    Ok(value) => {
+                        return Err(format!("SIGNATURE_NONCE_STORE must be memory or postgres, got {:?}", value));
+                    }
+                };
+                Ok(Some(SigningConfig { max_skew, nonce_required, nonce_store }))
+            }
+            Ok(value) => Err(format!("REQUEST_SIGNING must be true or false, got {:?}", value)),
+        }
+    }
+}
+
+// What is signed, see SigningConfig
+fn message(method: &str, target: &str, timestamp: &str, nonce: Option<&str>, body: &[u8]) -> String {
+    match nonce {
+        Some(nonce) => format!("{}\n{}\n{}\n{}\n{:x}", method, target, timestamp, nonce, Sha256::digest(body)),
+        None => format!("{}\n{}\n{}\n{:x}", method, target, timestamp, Sha256::digest(body)),
+    }
+}
+
+fn mac(secret: &str, message: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(message.as_bytes());
+    mac
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+// The secret among secrets the request was signed with, None when it isn't
+// signed. Every secret is tried, each compared in constant time.
+pub fn signer<'a, T>(
+    config: &SigningConfig,
+    request: &str,
+    secrets: &'a [T],
+    secret: impl Fn(&T) -> &str
+) -> Option<Result<&'a T, Rejected>> {
+    let signature = get_header(request, "X-Signature")?;
+    Some(check(config, request, clock::unix_secs()).and_then(|message| {
+        let signature = decode_hex(signature).ok_or(INVALID)?;
+        let found = secrets.iter().fold(None, |found, candidate| {
+            if mac(secret(candidate), &message).verify_slice(&signature).is_ok() { Some(candidate) } else { found }
+        });
+        found.ok_or(INVALID)
+    }))
+}
+
+const INVALID: Rejected =
+    Rejected { code: "invalid_signature", detail: "X-Signature isn't the signature of the request by any key" };
+
+// The message the signature must be of, unless the timestamp is missing or too far
+// off to take it
+fn check(config: &SigningConfig, request: &str, now: u64) -> Result<String, Rejected> {
+    let Some(timestamp) = get_header(request, "X-Timestamp") else {
+        return Err(Rejected { code: "invalid_signature", detail: "X-Signature needs X-Timestamp" });
+    };
+    let Ok(seconds) = timestamp.parse::<u64>() else {
+        return Err(Rejected { code: "invalid_signature", detail: "X-Timestamp must be seconds since the epoch" });
+    };
+    if seconds.abs_diff(now) > config.max_skew {
+        return Err(Rejected {
+            code: "signature_expired",
+            detail: "X-Timestamp is too far from the time of the server, sign the request again",
+        });
+    }
+    let nonce = get_header(request, "X-Nonce");
+    if nonce.is_none() && config.nonce_required {
+        return Err(Rejected { code: "invalid_signature", detail: "X-Signature needs X-Nonce" });
+    }
+    if nonce.is_some_and(|nonce| nonce.len() > MAX_NONCE_LENGTH || !nonce.bytes().all(|byte| byte.is_ascii_graphic())) {
+        return Err(Rejected {
+            code: "invalid_signature",
+            detail: "X-Nonce must be at most 128 characters, printable and without spaces",
+        });
+    }
+    let mut parts = request.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    Ok(message(method, target, timestamp, nonce, get_body(request).as_bytes()))
+}
+
+// The nonces seen in memory, by key, with the second they are forgotten at
+struct Seen {
+    nonces: HashMap<(String, String), u64>,
+    // How many there may be before the forgotten ones are swept out
+    next_sweep: usize,
+}
+
+impl Seen {
+    fn first_use(&mut self, key: &str, nonce: &str, forgotten: u64, now: u64) -> bool {
+        if self.nonces.len() >= self.next_sweep {
+            self.nonces.retain(|_, forgotten| *forgotten > now);
+            self.next_sweep = (self.nonces.len() * 2).max(MIN_SWEEP);
+        }
+        match self.nonces.entry((key.to_owned(), nonce.to_owned())) {
+            Entry::Occupied(seen) if *seen.get() > now => false,
+            Entry::Occupied(mut seen) => {
+                seen.insert(forgotten);
+                true
+            }
+            Entry::Vacant(unseen) => {
+                unseen.insert(forgotten);
+                true
+            }
+        }
+    }
+}
+
+// One statement, for two instances checking the same nonce at once to insert it
+// only once between them
+fn first_use_in_database(key: &str, nonce: &str, forgotten: u64) -> Result<bool, RepositoryError> {
+    if DATABASE_CHECKS.fetch_add(1, Ordering::Relaxed).is_multiple_of(SWEEP_EVERY) {
+        let query = tables::sql("DELETE FROM {signature_nonces} WHERE forgotten_at <= now()");
+        pool().get()?.execute(query, &[])?;
+    }
+    let query = tables::sql(
+        "INSERT INTO {signature_nonces} AS seen (key_name, nonce, forgotten_at) VALUES ($1, $2, to_timestamp($3))
+        ON CONFLICT (key_name, nonce) DO UPDATE SET forgotten_at = EXCLUDED.forgotten_at
+        WHERE seen.forgotten_at <= now()"
+    );
+    let forgotten = forgotten as f64;
+    // Once: run again after a connection failure, an insert that was committed
+    // would take the nonce for a replay
+    Ok(pool().get()?.execute(query, &[&key, &nonce, &forgotten])? == 1)
+}
+
+// Whether the nonce of a request signed with the key is new to it, true for a
+// request without one. It is remembered until its timestamp is too old to take.
+pub fn first_use(config: &SigningConfig, key: &str, request: &str) -> Result<bool, RepositoryError> {
+    let Some(nonce) = get_header(request, "X-Nonce") else {
+        return Ok(true);
+    };
+    let timestamp = get_header(request, "X-Timestamp").and_then(|timestamp| timestamp.parse::<u64>().ok());
+    let forgotten = timestamp.unwrap_or_default() + config.max_skew + 1;
+    match config.nonce_store {
+        NonceStore::Memory => {
+            let seen = SEEN.get_or_init(|| Mutex::new(Seen { nonces: HashMap::new(), next_sweep: MIN_SWEEP }));
+            Ok(seen.lock().unwrap().first_use(key, nonce, forgotten, clock::unix_secs()))
+        }
+        NonceStore::Postgres => first_use_in_database(key, nonce, forgotten),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &str = r#"{"name":"Ada Lovelace","email":"ada@example.com"}"#;
+    // By Python's hmac, of the message of BODY
+    const SIGNATURE: &str = "229f2d7a529ec921dd99d4af29e882ce82da42d0301d834de7699721e7e110a8";
+
+    // The X-Signature of the request as its sender computes it
+    fn sign(secret: &str, method: &str, target: &str, timestamp: &str, body: &[u8]) -> String {
+        let signature = mac(secret, &message(method, target, timestamp, None, body)).finalize().into_bytes();
+        signature.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn request(signature: &str, timestamp: &str, body: &str) -> String {
+        format!("POST /users HTTP/1.1\r\nX-Signature: {}\r\nX-Timestamp: {}\r\n\r\n{}", signature, timestamp, body)
+    }
+
+    #[test]
+    fn signatures_are_the_hmac_of_the_message() {
+        assert_eq!(sign("s3cret", "POST", "/users", "1700000000", BODY.as_bytes()), SIGNATURE);
+        let config = SigningConfig { max_skew: 300, nonce_required: false, nonce_store: NonceStore::Memory };
+        let message = check(&config, &request(SIGNATURE, "1700000000", BODY), 1700000299).unwrap();
+        assert!(mac("s3cret", &message).verify_slice(&decode_hex(SIGNATURE).unwrap()).is_ok());
+
+        // Another body is another message
+        let tampered = request(SIGNATURE, "1700000000", &BODY.replace("Ada", "Eve"));
+        let message = check(&config, &tampered, 1700000000).unwrap();
+        assert!(mac("s3cret", &message).verify_slice(&decode_hex(SIGNATURE).unwrap()).is_err());
+    }
+
+    #[test]
+    fn timestamps_out_of_the_window_are_turned_away() {
+        let config = SigningConfig { max_skew: 300, nonce_required: false, nonce_store: NonceStore::Memory };
+        let code = |timestamp, now| check(&config, &request(SIGNATURE, timestamp, BODY), now).unwrap_err().code;
+        assert_eq!(code("1700000000", 1700000301), "signature_expired");
+        assert_eq!(code("1700000301", 1700000000), "signature_expired");
+        assert_eq!(code("yesterday", 1700000000), "invalid_signature");
+        assert_eq!(decode_hex("0g"), None);
+        assert_eq!(decode_hex("a"), None);
+    }
+
+    #[test]
+    fn nonces_are_signed_and_needed_when_required() {
+        let config = SigningConfig { max_skew: 300, nonce_required: true, nonce_store: NonceStore::Memory };
+        let code = |request: &str| check(&config, request, 1700000000).unwrap_err().code;
+        assert_eq!(code(&request(SIGNATURE, "1700000000", BODY)), "invalid_signature");
+        let with_nonce = |nonce: &str| request(SIGNATURE, &format!("1700000000\r\nX-Nonce: {}", nonce), BODY);
+        assert_eq!(code(&with_nonce(&"n".repeat(129))), "invalid_signature");
+
+        let message = check(&config, &with_nonce("3f2a9c"), 1700000000).unwrap();
+        assert_eq!(message, format!("POST\n/users\n1700000000\n3f2a9c\n{:x}", Sha256::digest(BODY)));
+    }
+
+    #[test]
+    fn nonces_are_seen_once_until_forgotten() {
+        let mut seen = Seen { nonces: HashMap::new(), next_sweep: 4 };
+        assert!(seen.first_use("deploy", "a", 1700000301, 1700000000));
+        assert!(!seen.first_use("deploy", "a", 1700000301, 1700000300));
+        // Another key may use it, and the key again once it is forgotten
+        assert!(seen.first_use("ci", "a", 1700000301, 1700000000));
+        assert!(seen.first_use("deploy", "a", 1700000602, 1700000301));
+
+        // The forgotten ones are swept out as more come
+        for nonce in ["b", "c"] {
+            assert!(seen.first_use("deploy", nonce, 1700000302, 1700000001));
+        }
+        assert!(seen.first_use("deploy", "e", 1700000700, 1700000400));
+        assert_eq!(seen.nonces.len(), 2);
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::config::number_from_env;
+
+const DEFAULT_SLOW_REQUEST: Duration = Duration::from_millis(1000);
+const DEFAULT_SLOW_QUERY: Duration = Duration::from_millis(250);
+
+// What RUST_LOG sets the level of the lines by, slow=off for none of them
+const TARGET: &str = "slow";
+
+static CONFIG: RwLock<SlowConfig> =
+    RwLock::new(SlowConfig { request: DEFAULT_SLOW_REQUEST, query: DEFAULT_SLOW_QUERY });
+// Since startup
+static SLOW_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static SLOW_QUERIES: AtomicU64 = AtomicU64::new(0);
+
+// A request span longer than SLOW_REQUEST_MS, or a db span longer than
+// SLOW_QUERY_MS, is logged at warn with its route and how long it took, the
+// request_id of the lines being that of the request, and for a query the name of
+// its statement and the rows it returned, never the values it was called with.
+// 0 is never.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SlowConfig {
+    pub request: Duration,
+    pub query: Duration,
+}
+
+impl SlowConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let request = number_from_env("SLOW_REQUEST_MS", DEFAULT_SLOW_REQUEST.as_millis() as u64)?;
+        let query = number_from_env("SLOW_QUERY_MS", DEFAULT_SLOW_QUERY.as_millis() as u64)?;
+        Ok(SlowConfig { request: Duration::from_millis(request), query: Duration::from_millis(query) })
+    }
+}
+
+// At startup, and again when a SIGHUP changed it
+pub fn init(config: SlowConfig) {
+    *CONFIG.write().unwrap() = config;
+}
+
+fn config() -> SlowConfig {
+    *CONFIG.read().unwrap()
+}
+
+fn is_slow(duration: Duration, threshold: Duration) -> bool {
+    !threshold.is_zero() && duration > threshold
+}
+
+// Whether a request that took duration is logged as slow
+pub fn is_slow_request(duration: Duration) -> bool {
+    is_slow(duration, config().request)
+}
+
+fn field<'a>(fields: &'a [(&'static str, String)], name: &str) -> &'a str {
+    fields.iter().find(|(field, _)| *field == name).map_or("-", |(_, value)| value.as_str())
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+// A request span of these fields closed after duration
+pub fn request(fields: &[(&'static str, String)], duration: Duration) {
+    if !is_slow(duration, config().request) {
+        return;
+    }
+    SLOW_REQUESTS.fetch_add(1, Ordering::Relaxed);
+    let route = field(fields, "route");
+    log::warn!(
+        target: TARGET,
+        kind = "request",
+        route = route,
+        duration_ms = milliseconds(duration);
+        "Slow request: {} took {:?}",
+        route,
+        duration
+    );
+}
+
+// A db span of these fields closed after duration, under the request of route
+pub fn query(fields: &[(&'static str, String)], duration: Duration, route: impl FnOnce() -> Option<String>) {
+    if !is_slow(duration, config().query) {
+        return;
+    }
+    SLOW_QUERIES.fetch_add(1, Ordering::Relaxed);
+    let route = route().unwrap_or_else(|| "-".to_owned());
+    let statement = field(fields, "statement");
+    let rows = field(fields, "rows");
+    log::warn!(
+        target: TARGET,
+        kind = "query",
+        route = route.as_str(),
+        statement = statement,
+        rows = rows.parse::<u64>().ok(),
+        duration_ms = milliseconds(duration);
+        "Slow query: {} of {} took {:?}",
+        statement,
+        route,
+        duration
+    );
+}
+
+// How many requests and queries were slow, since startup
+pub fn counts() -> (u64, u64) {
+    (SLOW_REQUESTS.load(Ordering::Relaxed), SLOW_QUERIES.load(Ordering::Relaxed))
+}
@@ -0,0 +1,338 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+use tracing::field::{ Field, Visit };
+use tracing::span::{ Attributes, Id, Record };
+use tracing::{ Dispatch, Event, Level, Metadata, Subscriber };
+
+use crate::metrics::Histogram;
+use crate::otlp;
+use crate::statsd;
+use crate::slow;
+use crate::trace_context::{ self, Trace };
+
+thread_local! {
+    // The spans entered on this thread, the innermost last
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+    // How long the spans under the request of this thread took so far, by name in
+    // the order they first closed, for its Server-Timing
+    static TIMINGS: RefCell<Vec<(&'static str, Duration)>> = const { RefCell::new(Vec::new()) };
+}
+
+// How long the statements of the repositories took, by statement, for /metrics.
+// There are as many as the operations of a repository.
+static STATEMENT_TIMES: Mutex<Vec<(String, &'static Histogram)>> = Mutex::new(Vec::new());
+
+// The spans of tracing, timed: a request of method, route and request_id for each
+// request, and under it parse_body, serialize, write, and db for each call to the
+// repository, of statement, table and rows. A span closes once each of its
+// handles is dropped, and is exported then with OTEL_EXPORTER_OTLP_ENDPOINT, and
+// logged when it was slow, see slow::SlowConfig. The
+// events of tracing are logged like those of log, their fields as the fields of
+// the line.
+pub struct Spans {
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, Open>>,
+    // Every span closed, for the tests to look at
+    #[cfg(test)]
+    collected: Mutex<Vec<Closed>>,
+}
+
+struct Open {
+    #[cfg(test)]
+    id: u64,
+    name: &'static str,
+    parent: Option<u64>,
+    fields: Vec<(&'static str, String)>,
+    started: Instant,
+    handles: usize,
+    // Where it is in its trace
+    trace: Trace,
+}
+
+// A closed span, with its duration_ms among its fields
+#[cfg(test)]
+#[derive(Clone, Debug)]
+pub struct Closed {
+    pub id: u64,
+    pub name: &'static str,
+    pub parent: Option<u64>,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+#[cfg(test)]
+impl Closed {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|(field, _)| *field == name).map(|(_, value)| value.as_str())
+    }
+}
+
+struct Fields<'a>(&'a mut Vec<(&'static str, String)>);
+
+impl Fields<'_> {
+    fn set(&mut self, name: &'static str, value: String) {
+        match self.0.iter_mut().find(|(field, _)| *field == name) {
+            Some((_, old)) => *old = value,
+            None => self.0.push((name, value)),
+        }
+    }
+}
+
+impl Visit for Fields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field.name(), format!("{:?}", value));
+    }
+}
+
+impl Spans {
+    fn new() -> Self {
+        Spans {
+            next_id: AtomicU64::new(1),
+            open: Mutex::new(HashMap::new()),
+            #[cfg(test)]
+            collected: Mutex::new(Vec::new()),
+        }
+    }
+
+    // One that keeps the spans once closed, for the tests
+    #[cfg(test)]
+    pub fn collecting() -> Dispatch {
+        Dispatch::new(Spans::new())
+    }
+
+    #[cfg(test)]
+    pub fn closed(dispatch: &Dispatch) -> Vec<Closed> {
+        dispatch.downcast_ref::<Spans>().unwrap().collected.lock().unwrap().clone()
+    }
+
+    fn close(&self, mut open: Open) {
+        let duration = open.started.elapsed();
+        if open.parent.is_some() {
+            TIMINGS.with_borrow_mut(|timings| match timings.iter_mut().find(|(name, _)| *name == open.name) {
+                Some((_, total)) => *total += duration,
+                None => timings.push((open.name, duration)),
+            });
+        }
+        let statement = open.fields.iter().find(|(name, _)| *name == "statement");
+        if let Some((_, statement)) = statement.filter(|_| open.name == "db") {
+            statement_time(statement).observe(duration);
+            statsd::timing("db_operation_duration", duration, &[("operation", statement)]);
+        }
+        match open.name {
+            "request" if open.parent.is_none() => slow::request(&open.fields, duration),
+            "db" => slow::query(&open.fields, duration, || self.route(open.parent)),
+            _ => {}
+        }
+        otlp::ended(&open.trace, open.name, &open.fields);
+        open.fields.push(("duration_ms", format!("{:.3}", duration.as_secs_f64() * 1000.0)));
+        #[cfg(test)]
+        let closed = Closed { id: open.id, name: open.name, parent: open.parent, fields: open.fields };
+        #[cfg(test)]
+        self.collected.lock().unwrap().push(closed);
+    }
+
+    // The route of the request the span of parent is under
+    fn route(&self, mut parent: Option<u64>) -> Option<String> {
+        let open = self.open.lock().unwrap();
+        while let Some(span) = open.get(&parent?) {
+            match span.fields.iter().find(|(name, _)| *name == "route") {
+                Some((_, route)) if span.name == "request" => return Some(route.clone()),
+                _ => parent = span.parent,
+            }
+        }
+        None
+    }
+}
+
+impl Subscriber for Spans {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.is_span() || log::logger().enabled(&log_metadata(metadata))
+    }
+
+    fn new_span(&self, span: &Attributes) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let parent = match span.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if span.is_contextual() => ENTERED.with_borrow(|entered| entered.last().copied()),
+            None => None,
+        };
+        // A new request, the timings of the one before are no longer anyone's
+        if parent.is_none() {
+            TIMINGS.take();
+        }
+        let mut fields = Vec::new();
+        span.record(&mut Fields(&mut fields));
+        let name = span.metadata().name();
+        let mut open_spans = self.open.lock().unwrap();
+        let trace = Trace::new(parent.and_then(|parent| open_spans.get(&parent)).map(|parent| &parent.trace));
+        if parent.is_none() && name == "request" {
+            trace_context::started(&trace);
+        }
+        let open = Open {
+            #[cfg(test)]
+            id,
+            name,
+            parent,
+            fields,
+            started: Instant::now(),
+            handles: 1,
+            trace,
+        };
+        open_spans.insert(id, open);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record) {
+        if let Some(open) = self.open.lock().unwrap().get_mut(&span.into_u64()) {
+            values.record(&mut Fields(&mut open.fields));
+        }
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event) {
+        let mut fields = Vec::new();
+        event.record(&mut Fields(&mut fields));
+        let message = match fields.iter().position(|(name, _)| *name == "message") {
+            Some(index) => fields.remove(index).1,
+            None => String::new(),
+        };
+        let pairs: Vec<(&str, &str)> = fields.iter().map(|(name, value)| (*name, value.as_str())).collect();
+        let metadata = event.metadata();
+        log::logger().log(
+            &log::Record::builder()
+                .metadata(log_metadata(metadata))
+                .module_path(metadata.module_path())
+                .file(metadata.file())
+                .line(metadata.line())
+                .key_values(&pairs.as_slice())
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with_borrow_mut(|entered| entered.push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with_borrow_mut(|entered| {
+            if let Some(index) = entered.iter().rposition(|id| *id == span.into_u64()) {
+                entered.remove(index);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(open) = self.open.lock().unwrap().get_mut(&span.into_u64()) {
+            open.handles += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let id = span.into_u64();
+        let mut open = self.open.lock().unwrap();
+        let Some(handles) = open.get_mut(&id).map(|open| {
+            open.handles -= 1;
+            open.handles
+        }) else {
+            return false;
+        };
+        if handles > 0 {
+            return false;
+        }
+        let closed = open.remove(&id).unwrap();
+        drop(open);
+        self.close(closed);
+        true
+    }
+}
+
+fn log_metadata<'a>(metadata: &Metadata<'a>) -> log::Metadata<'a> {
+    let level = match *metadata.level() {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        Level::TRACE => log::Level::Trace,
+    };
+    log::Metadata::builder().level(level).target(metadata.target()).build()
+}
+
+fn statement_time(statement: &str) -> &'static Histogram {
+    let mut times = STATEMENT_TIMES.lock().unwrap();
+    match times.iter().find(|(name, _)| name == statement) {
+        Some((_, histogram)) => histogram,
+        None => {
+            let histogram = Box::leak(Box::default());
+            times.push((statement.to_owned(), histogram));
+            histogram
+        }
+    }
+}
+
+// The trace of the span entered on this thread
+pub fn current_trace() -> Option<Trace> {
+    let id = ENTERED.with_borrow(|entered| entered.last().copied())?;
+    tracing::dispatcher::get_default(|dispatch| {
+        let open = dispatch.downcast_ref::<Spans>()?.open.lock().unwrap();
+        open.get(&id).map(|open| open.trace.clone())
+    })
+}
+
+pub fn statement_times() -> Vec<(String, &'static Histogram)> {
+    STATEMENT_TIMES.lock().unwrap().clone()
+}
+
+// How long the spans under the request on this thread took since the last call,
+// by name
+pub fn take_timings() -> Vec<(&'static str, Duration)> {
+    TIMINGS.take()
+}
+
+pub fn init() {
+    tracing::dispatcher::set_global_default(Dispatch::new(Spans::new())).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_are_under_the_one_entered() {
+        let dispatch = Spans::collecting();
+        let timings = tracing::dispatcher::with_default(&dispatch, || {
+            let request = tracing::info_span!("request", method = "GET", route = "GET /users/{id}");
+            let _in_request = request.enter();
+            {
+                let db = tracing::info_span!("db", statement = "find", table = "users", rows = tracing::field::Empty);
+                let _in_db = db.enter();
+                db.record("rows", 1);
+            }
+            tracing::info_span!("write").in_scope(|| tracing::info_span!("flush").in_scope(|| {}));
+            take_timings()
+        });
+
+        let closed = Spans::closed(&dispatch);
+        let names: Vec<&str> = closed.iter().map(|span| span.name).collect();
+        assert_eq!(names, ["db", "flush", "write", "request"]);
+        let (db, flush, write, request) = (&closed[0], &closed[1], &closed[2], &closed[3]);
+        assert_eq!((request.parent, db.parent, write.parent), (None, Some(request.id), Some(request.id)));
+        assert_eq!(flush.parent, Some(write.id));
+        assert_eq!((db.field("table"), db.field("rows")), (Some("users"), Some("1")));
+        assert!(db.field("duration_ms").unwrap().parse::<f64>().is_ok(), "{:?}", db);
+        assert_eq!(request.field("route"), Some("GET /users/{id}"));
+
+        let names: Vec<&str> = timings.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["db", "flush", "write"]);
+        assert!(statement_times().iter().any(|(statement, histogram)| statement == "find" && histogram.count() > 0));
+    }
+}
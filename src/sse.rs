@@ -0,0 +1,143 @@
+use serde_json::Value;
+use std::error::Error;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::mpsc::{ self, Receiver, RecvTimeoutError, Sender };
+use std::sync::{ Arc, Condvar, Mutex };
+use std::time::Duration;
+
+use crate::db::pool;
+use crate::outbox::{ self, EventNotice };
+use crate::{ access_log, json_case, request_id, security_headers, tenant, trace_context };
+
+const EVENT_STREAM_RESPONSE: &str =
+    "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n";
+
+// A comment is sent when nothing happened for this long, which also detects
+// clients that went away
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+// How long a stream waits for the broadcaster to listen, when it isn't
+const LISTEN_WAIT: Duration = Duration::from_secs(5);
+
+// A stream being sent, and where the notices of its tenant go
+struct Subscriber {
+    id: u64,
+    tenant: String,
+    notices: Sender<Arc<EventNotice>>,
+}
+
+// The streams are only subscribed while the broadcaster listens, so that none
+// misses what it didn't hear
+struct Subscribers {
+    listening: bool,
+    all: Vec<Subscriber>,
+}
+
+static SUBSCRIBERS: Mutex<Subscribers> = Mutex::new(Subscribers { listening: false, all: Vec::new() });
+static LISTENING: Condvar = Condvar::new();
+static NEXT_SUBSCRIBER_ID: AtomicU64 = AtomicU64::new(1);
+
+// The notices of a stream, until it is dropped
+struct Subscription {
+    id: u64,
+    notices: Receiver<Arc<EventNotice>>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        SUBSCRIBERS.lock().unwrap().all.retain(|subscriber| subscriber.id != self.id);
+    }
+}
+
+fn subscribe(tenant: &str) -> Result<Subscription, &'static str> {
+    let subscribers = SUBSCRIBERS.lock().unwrap();
+    let (mut subscribers, _) =
+        LISTENING.wait_timeout_while(subscribers, LISTEN_WAIT, |subscribers| !subscribers.listening).unwrap();
+    if !subscribers.listening {
+        return Err("the notifications aren't listened for");
+    }
+    let id = NEXT_SUBSCRIBER_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, notices) = mpsc::channel();
+    subscribers.all.push(Subscriber { id, tenant: tenant.to_owned(), notices: sender });
+    Ok(Subscription { id, notices })
+}
+
+// From the broadcaster of ws.rs, whose one connection listens for every stream:
+// the notice to those of its tenant
+pub(crate) fn publish(notice: EventNotice) {
+    let notice = Arc::new(notice);
+    let subscribers = SUBSCRIBERS.lock().unwrap();
+    for subscriber in subscribers.all.iter().filter(|subscriber| subscriber.tenant == notice.tenant_id) {
+        subscriber.notices.send(notice.clone()).ok();
+    }
+}
+
+// The broadcaster listens, or stopped. Once it listens again, having maybe missed
+// notices meanwhile, the streams of before are ended, for their clients to
+// reconnect with the Last-Event-ID they got to.
+pub(crate) fn set_listening(listening: bool) {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    if listening {
+        subscribers.all.clear();
+    }
+    subscribers.listening = listening;
+    LISTENING.notify_all();
+}
+
+// Stream the user changes of the tenant to the client until it disconnects. Runs on
+// its own thread, fed the notices by the broadcaster rather than listening itself,
+// so the clients don't each hold a connection.
+pub fn stream_user_events(mut stream: TcpStream, tenant: String, last_event_id: Option<i64>) {
+    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+
+    if let Err(e) = serve(&mut stream, &tenant, last_event_id) {
+        log::info!("Event stream to {} closed: {}", peer, e);
+    }
+}
+
+fn serve(stream: &mut TcpStream, tenant: &str, last_event_id: Option<i64>) -> Result<(), Box<dyn Error>> {
+    // Subscribed before replaying so nothing committed in between is missed
+    let subscription = subscribe(tenant)?;
+    let head = trace_context::added(&request_id::added(&security_headers::added(EVENT_STREAM_RESPONSE)));
+    stream.write_all(head.as_bytes())?;
+    access_log::responded(&head, head.len());
+
+    let mut last_sent_id = 0;
+    if let Some(since_id) = last_event_id {
+        // Read from the schema of the tenant, when it has one
+        let _tenant = tenant::enter(tenant.to_owned());
+        last_sent_id = since_id;
+        loop {
+            let events = pool()
+                .read(|client| outbox::fetch_since(&mut **client, tenant, last_sent_id))
+                .map_err(|e| e.to_string())?;
+            if events.is_empty() {
+                break;
+            }
+            for event in events {
+                write_event(stream, event.id, &event.event_type, &event.payload)?;
+                last_sent_id = event.id;
+            }
+        }
+    }
+
+    loop {
+        match subscription.notices.recv_timeout(KEEPALIVE_INTERVAL) {
+            // Unless already sent while replaying
+            Ok(notice) if notice.id <= last_sent_id => {}
+            Ok(notice) => {
+                write_event(stream, notice.id, &notice.event_type, &notice.payload)?;
+                last_sent_id = notice.id;
+            }
+            Err(RecvTimeoutError::Timeout) => stream.write_all(b": keepalive\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Err("the notifications are listened for again".into()),
+        }
+    }
+}
+
+fn write_event(stream: &mut TcpStream, id: i64, event_type: &str, data: &Value) -> std::io::Result<()> {
+    let data = json_case::outbound_value(data);
+    stream.write_all(format!("id: {}\nevent: {}\ndata: {}\n\n", id, event_type, data).as_bytes())
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{ ToSocketAddrs, UdpSocket };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::mpsc::{ self, Receiver, RecvTimeoutError, SyncSender, TrySendError };
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use crate::config::{ self, number_from_env };
+use crate::metrics;
+use crate::trace_context;
+
+const DEFAULT_QUEUE_SIZE: u64 = 10_000;
+const DEFAULT_INTERVAL_MS: u64 = 10_000;
+// What fits in a datagram over an Ethernet MTU of 1500, with the headers of IP and UDP
+const DEFAULT_MAX_PACKET_SIZE: u64 = 1432;
+
+// How long the last counters have to be sent when shutting down
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+// With STATSD_ADDR, a host:port, the metrics of /metrics are sent there too, in
+// the DogStatsD format, for the setups that take them over UDP instead of
+// scraping: the samples of the histograms as timings when they are observed, kept
+// to the share of STATSD_SAMPLE_RATE, and every STATSD_INTERVAL_MS milliseconds
+// the counters, by how much they grew since, and the gauges. The names are those
+// of /metrics, the histograms without their _seconds, under STATSD_PREFIX, and
+// the labels are tags, with env of APP_ENV, service of STATSD_SERVICE and those
+// of STATSD_TAGS, key:value separated by commas, on every line. The lines are
+// queued, up to STATSD_QUEUE_SIZE of them, the others dropped, and sent by a
+// thread of their own in datagrams of up to STATSD_MAX_PACKET_SIZE bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatsdConfig {
+    pub addr: String,
+    pub prefix: String,
+    pub tags: Vec<String>,
+    pub sample_rate: f64,
+    pub interval: Duration,
+    pub queue_size: usize,
+    pub max_packet_size: usize,
+}
+
+impl StatsdConfig {
+    // None without an address
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(addr) = config::var("STATSD_ADDR") else {
+            return Ok(None);
+        };
+        let addr = addr.trim().to_owned();
+        if !addr.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) {
+            return Err(format!("STATSD_ADDR must be a host:port, got {:?}", addr));
+        }
+        let prefix = config::var("STATSD_PREFIX").unwrap_or_default();
+        let prefix = match prefix.trim().trim_end_matches('.') {
+            "" => String::new(),
+            prefix => format!("{}.", prefix),
+        };
+        if !prefix.chars().all(name_char) {
+            return Err(format!("STATSD_PREFIX can only have letters, digits, _ and ., got {:?}", prefix));
+        }
+
+        let environment = config::var("APP_ENV").unwrap_or_else(|_| "development".to_owned());
+        let service = config::var("STATSD_SERVICE").unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_owned());
+        let mut tags = vec![format!("env:{}", environment), format!("service:{}", service)];
+        let extra = config::var("STATSD_TAGS").unwrap_or_default();
+        tags.extend(extra.split(',').map(str::trim).filter(|tag| !tag.is_empty()).map(str::to_owned));
+        if let Some(tag) = tags.iter().find(|tag| !tag.contains(':') || tag.contains(['|', '#', ',', '\n'])) {
+            return Err(format!("STATSD_TAGS must be key:value separated by commas, got the tag {:?}", tag));
+        }
+
+        let sample_rate = match config::var("STATSD_SAMPLE_RATE") {
+            Ok(rate) => rate.trim().parse().ok().filter(|rate| *rate > 0.0 && *rate <= 1.0).ok_or_else(|| {
+                format!("STATSD_SAMPLE_RATE must be a ratio above 0, up to 1, got {:?}", rate)
+            })?,
+            Err(_) => 1.0,
+        };
+        let interval = Duration::from_millis(number_from_env("STATSD_INTERVAL_MS", DEFAULT_INTERVAL_MS)?.max(1));
+        let queue_size = number_from_env("STATSD_QUEUE_SIZE", DEFAULT_QUEUE_SIZE)?.max(1) as usize;
+        let max_packet_size = number_from_env("STATSD_MAX_PACKET_SIZE", DEFAULT_MAX_PACKET_SIZE)?.max(1) as usize;
+        Ok(Some(StatsdConfig { addr, prefix, tags, sample_rate, interval, queue_size, max_packet_size }))
+    }
+}
+
+struct Sink {
+    prefix: String,
+    tags: String,
+    sample_rate: f64,
+    queue: SyncSender<Message>,
+    dropped: AtomicU64,
+    failures: AtomicU64,
+}
+
+enum Message {
+    Line(String),
+    // Send the counters and the gauges now, then say so
+    Flush(mpsc::Sender<()>),
+}
+
+pub fn init(config: Option<StatsdConfig>) {
+    let Some(config) = config else {
+        return;
+    };
+    let (queue, queued) = mpsc::sync_channel(config.queue_size);
+    let sink = Sink {
+        prefix: config.prefix.clone(),
+        tags: config.tags.join(","),
+        sample_rate: config.sample_rate,
+        queue,
+        dropped: AtomicU64::new(0),
+        failures: AtomicU64::new(0),
+    };
+    if SINK.set(sink).is_ok() {
+        thread::spawn(move || send(&config, queued));
+    }
+}
+
+// A sample of the histogram name, with the tags of its labels, unless it isn't one
+// of the share sent
+pub fn timing(name: &str, duration: Duration, tags: &[(&str, &str)]) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    let mut line = format!("{}:{:.3}|ms", name, duration.as_secs_f64() * 1000.0);
+    if sink.sample_rate < 1.0 {
+        let random = u64::from_be_bytes(trace_context::random());
+        if random as f64 >= sink.sample_rate * u64::MAX as f64 {
+            return;
+        }
+        line.push_str(&format!("|@{}", sink.sample_rate));
+    }
+    let tags: Vec<String> = tags.iter().map(|(key, value)| format!("{}:{}", key, tag_value(value))).collect();
+    sink.queue_line(line, &tags);
+}
+
+// The lines dropped for a full queue and the datagrams that couldn't be sent, None
+// unless sending
+pub fn counts() -> Option<(u64, u64)> {
+    SINK.get().map(|sink| (sink.dropped.load(Ordering::Relaxed), sink.failures.load(Ordering::Relaxed)))
+}
+
+// Send the counters and the gauges as they are now, waiting for them a while at most
+pub fn flush() {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    let (flushed, done) = mpsc::channel();
+    if sink.queue.send(Message::Flush(flushed)).is_ok() && done.recv_timeout(FLUSH_TIMEOUT).is_err() {
+        log::warn!("Gave up sending the last metrics to StatsD after {:?}", FLUSH_TIMEOUT);
+    }
+}
+
+impl Sink {
+    // Under the prefix, with the tags given and the constant ones
+    fn line(&self, line: &str, tags: &[String]) -> String {
+        let separator = if tags.is_empty() { "" } else { "," };
+        format!("{}{}|#{}{}{}", self.prefix, line, tags.join(","), separator, self.tags)
+    }
+
+    fn queue_line(&self, line: String, tags: &[String]) {
+        if let Err(TrySendError::Full(_)) = self.queue.try_send(Message::Line(self.line(&line, tags))) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn send(config: &StatsdConfig, queued: Receiver<Message>) {
+    let sink = SINK.get().expect("the sink is set before its thread starts");
+    let mut sender = Sender { config, socket: None, failed: 0, logged: None };
+    let mut counters = HashMap::new();
+    let mut deadline = Instant::now() + config.interval;
+    loop {
+        let mut lines = Vec::new();
+        let (interval, flushed) = match queued.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Message::Line(line)) => {
+                lines.push(line);
+                // What else is queued goes in the same datagrams
+                let mut flushed = None;
+                while let Ok(message) = queued.try_recv() {
+                    match message {
+                        Message::Line(line) => lines.push(line),
+                        Message::Flush(sender) => {
+                            flushed = Some(sender);
+                            break;
+                        }
+                    }
+                }
+                (flushed.is_some(), flushed)
+            }
+            Ok(Message::Flush(flushed)) => (true, Some(flushed)),
+            Err(RecvTimeoutError::Timeout) => (true, None),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if interval {
+            let (_, body) = metrics::handle_metrics_request();
+            lines.extend(interval_lines(&body, &mut counters).into_iter().map(|(line, tags)| sink.line(&line, &tags)));
+            deadline = Instant::now() + config.interval;
+        }
+        sender.send_all(sink, &lines);
+        if let Some(flushed) = flushed {
+            flushed.send(()).ok();
+        }
+    }
+}
+
+// The socket, connected once the address resolves, and the failures not logged yet
+struct Sender<'a> {
+    config: &'a StatsdConfig,
+    socket: Option<UdpSocket>,
+    failed: u64,
+    logged: Option<Instant>,
+}
+
+impl Sender<'_> {
+    fn send_all(&mut self, sink: &Sink, lines: &[String]) {
+        for packet in packets(lines, self.config.max_packet_size) {
+            if let Err(e) = self.send(&packet) {
+                sink.failures.fetch_add(1, Ordering::Relaxed);
+                self.failed += 1;
+                // Once an interval at most, with how many failed since
+                if self.logged.is_none_or(|logged| logged.elapsed() >= self.config.interval) {
+                    log::warn!("Can't send metrics to StatsD at {}: {} ({} failed)", self.config.addr, e, self.failed);
+                    self.failed = 0;
+                    self.logged = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, packet: &str) -> io::Result<()> {
+        if self.socket.is_none() {
+            self.socket = Some(connect(&self.config.addr)?);
+        }
+        let sent = self.socket.as_ref().unwrap().send(packet.as_bytes());
+        if sent.is_err() {
+            // To resolve the address again for the next ones
+            self.socket = None;
+        }
+        sent.map(|_| ())
+    }
+}
+
+fn connect(addr: &str) -> io::Result<UdpSocket> {
+    let resolved = addr.to_socket_addrs()?.next();
+    let resolved = resolved.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let socket = UdpSocket::bind(if resolved.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.connect(resolved)?;
+    Ok(socket)
+}
+
+// The lines joined by newlines into datagrams of up to max_size bytes, a longer
+// line going alone in one
+fn packets(lines: &[String], max_size: usize) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= max_size => {
+                packet.push('\n');
+                packet.push_str(line);
+            }
+            _ => packets.push(line.clone()),
+        }
+    }
+    packets
+}
+
+// The counters of the text of /metrics by how much they grew since the values of
+// the last interval, kept in previous, those that didn't left out, and the gauges.
+// The histograms are sent as their samples are taken.
+fn interval_lines(text: &str, previous: &mut HashMap<String, f64>) -> Vec<(String, Vec<String>)> {
+    let mut lines = Vec::new();
+    let mut kind = "";
+    for line in text.lines() {
+        if let Some(metric) = line.strip_prefix("# TYPE ") {
+            kind = metric.split(' ').nth(1).unwrap_or("");
+            continue;
+        }
+        let Some((series, value)) = line.rsplit_once(' ').filter(|_| !line.starts_with('#')) else {
+            continue;
+        };
+        let Ok(value) = value.parse::<f64>() else {
+            continue;
+        };
+        let (name, labels) = series.split_once('{').unwrap_or((series, ""));
+        let tags = labels
+            .trim_end_matches('}')
+            .split("\",")
+            .filter_map(|label| label.split_once("=\""))
+            .map(|(key, value)| format!("{}:{}", key, tag_value(value.trim_end_matches('"'))))
+            .collect();
+        match kind {
+            "counter" => {
+                // A counter that went down started over
+                let last = previous.insert(series.to_owned(), value).filter(|last| *last <= value);
+                let grown = value - last.unwrap_or(0.0);
+                if grown > 0.0 {
+                    lines.push((format!("{}:{}|c", name, grown), tags));
+                }
+            }
+            "gauge" => lines.push((format!("{}:{}|g", name, value), tags)),
+            _ => {}
+        }
+    }
+    lines
+}
+
+// What separates the tags and the parts of a line can't be in a value
+fn tag_value(value: &str) -> String {
+    value.replace([',', '|', '#', '\n'], "_")
+}
+
+fn name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_counters_are_sent_by_how_much_they_grew() {
+        let text = "\
+# HELP requests_total Requests
+# TYPE requests_total counter
+requests_total 5
+# TYPE http_requests_total counter
+http_requests_total{method=\"GET\",route=\"/users/{id}\",status_class=\"2xx\"} 2
+# TYPE workers_busy gauge
+workers_busy 3
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_count{method=\"GET\",route=\"/users\"} 7
+";
+        let mut previous = HashMap::new();
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+        let expected = [
+            ("requests_total:5|c".to_owned(), tags(&[])),
+            ("http_requests_total:2|c".to_owned(), tags(&["method:GET", "route:/users/{id}", "status_class:2xx"])),
+            ("workers_busy:3|g".to_owned(), tags(&[])),
+        ];
+        assert_eq!(interval_lines(text, &mut previous), expected);
+
+        let text = text.replace("requests_total 5", "requests_total 8");
+        let lines = interval_lines(&text, &mut previous);
+        assert_eq!(lines, [("requests_total:3|c".to_owned(), tags(&[])), ("workers_busy:3|g".to_owned(), tags(&[]))]);
+    }
+
+    #[test]
+    fn the_lines_are_batched_up_to_the_packet_size() {
+        let lines: Vec<String> = ["a:1|c", "b:2|c", "c:3|c", "a_much_longer_one:4|c"].map(str::to_owned).into();
+        assert_eq!(packets(&lines, 11), ["a:1|c\nb:2|c", "c:3|c", "a_much_longer_one:4|c"]);
+        assert_eq!(packets(&lines, 1432), [lines.join("\n")]);
+    }
+}
@@ -0,0 +1,191 @@
+use postgres::Client;
+use std::collections::HashMap;
+use std::sync::{ Mutex, OnceLock };
+
+use crate::config;
+
+// The tables and indexes of the API, written between braces in the SQL of the
+// queries and of migrations/: "SELECT name FROM {users}"
+const OBJECTS: [&str; 27] = [
+    "users",
+    "events_outbox",
+    "idempotency_keys",
+    "schema_migrations",
+    "users_email_key",
+    "users_email_lower_key",
+    "users_tenant_email_key",
+    "users_tenant_email_hash_key",
+    "events_outbox_tenant_id_idx",
+    "events_outbox_undelivered_idx",
+    "idempotency_keys_pkey",
+    "tenants",
+    "api_keys",
+    "api_keys_key_hash_key",
+    "sessions",
+    "sessions_user_id_idx",
+    "verification_tokens",
+    "verification_tokens_user_id_idx",
+    "password_reset_tokens",
+    "password_reset_tokens_user_id_idx",
+    "refresh_tokens",
+    "refresh_tokens_user_id_idx",
+    "refresh_tokens_chain_id_idx",
+    "signature_nonces",
+    "signature_nonces_forgotten_at_idx",
+    "auth_audit",
+    "auth_audit_occurred_at_idx",
+];
+
+// Postgres cuts longer identifiers, so prefixed names could end up the same
+const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+// The longest of the names Postgres derives from ours, which OBJECTS may be
+// longer than
+const LONGEST_DERIVED_NAME: &str = "schema_migrations_pkey";
+
+static NAMING: OnceLock<Naming> = OnceLock::new();
+
+// Where the tables of the API are, so that several instances can share a database:
+// in DATABASE_SCHEMA rather than wherever search_path points, and with their names
+// starting with DATABASE_TABLE_PREFIX. Without either the SQL is as written.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Naming {
+    pub schema: Option<String>,
+    pub prefix: String,
+}
+
+impl Naming {
+    pub fn from_env() -> Result<Self, String> {
+        let naming = Naming {
+            schema: config::var("DATABASE_SCHEMA").ok().filter(|schema| !schema.is_empty()),
+            prefix: config::var("DATABASE_TABLE_PREFIX").unwrap_or_default(),
+        };
+
+        if let Some(schema) = &naming.schema {
+            if schema.len() > MAX_IDENTIFIER_LENGTH || schema.contains('\0') {
+                return Err(format!("DATABASE_SCHEMA must be at most {} bytes, without NUL", MAX_IDENTIFIER_LENGTH));
+            }
+        }
+        let longest_name = OBJECTS.iter().map(|object| object.len()).chain([LONGEST_DERIVED_NAME.len()]).max();
+        let max_prefix_length = MAX_IDENTIFIER_LENGTH - longest_name.unwrap();
+        if naming.prefix.len() > max_prefix_length || naming.prefix.contains('\0') {
+            return Err(format!("DATABASE_TABLE_PREFIX must be at most {} bytes, without NUL", max_prefix_length));
+        }
+
+        Ok(naming)
+    }
+
+    // The SQL with the names of this configuration in place of the braces
+    pub fn render(&self, template: &str) -> String {
+        let mut sql = template.to_owned();
+        for object in OBJECTS {
+            sql = sql.replace(&format!("{{{}}}", object), &identifier(&self.prefixed(object)));
+        }
+        sql
+    }
+
+    pub fn prefixed(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+}
+
+pub fn init(naming: Naming) {
+    NAMING.set(naming).ok();
+}
+
+// The default one until init, in the tests and with the other backends
+fn naming() -> &'static Naming {
+    NAMING.get_or_init(Naming::default)
+}
+
+// The SQL of a query with the names of the configuration, rendered once
+pub fn sql(template: &'static str) -> &'static str {
+    static RENDERED: Mutex<Option<HashMap<&'static str, &'static str>>> = Mutex::new(None);
+
+    let mut rendered = RENDERED.lock().unwrap();
+    rendered.get_or_insert_with(HashMap::new).entry(template).or_insert_with(|| {
+        Box::leak(naming().render(template).into_boxed_str())
+    })
+}
+
+// The name of a table or a channel, unquoted, as information_schema and
+// pg_notify see it
+pub fn prefixed(name: &str) -> String {
+    naming().prefixed(name)
+}
+
+// A name ready to go into SQL. Lowercase names are left alone, so that the SQL
+// without a prefix stays as it was written; the others are quoted.
+pub fn identifier(name: &str) -> String {
+    let plain = name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if plain {
+        name.to_owned()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+// Point a new connection at DATABASE_SCHEMA. Only the schema itself is searched,
+// so a table missing from it is an error instead of one found elsewhere.
+pub fn set_search_path(client: &mut Client) -> Result<(), postgres::Error> {
+    match &naming().schema {
+        Some(schema) => client.batch_execute(&format!("SET search_path TO \"{}\"", schema.replace('"', "\"\""))),
+        None => Ok(()),
+    }
+}
+
+// Back to the search_path of a new connection, after it was pointed elsewhere
+pub fn reset_search_path(client: &mut Client) -> Result<(), postgres::Error> {
+    match &naming().schema {
+        Some(_) => set_search_path(client),
+        None => client.batch_execute("RESET search_path"),
+    }
+}
+
+// Create DATABASE_SCHEMA if it's missing. Creating it needs the CREATE privilege
+// on the database, which the role needs nothing else for once the schema exists.
+pub fn create_schema(client: &mut Client) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(schema) = &naming().schema else {
+        return Ok(());
+    };
+    if client.query_opt("SELECT 1 FROM pg_namespace WHERE nspname = $1", &[schema])?.is_some() {
+        return Ok(());
+    }
+
+    log::info!("Creating schema {}", schema);
+    let create = format!("CREATE SCHEMA IF NOT EXISTS \"{}\"", schema.replace('"', "\"\""));
+    client.batch_execute(&create).map_err(|e| {
+        format!("schema {} is missing and can't be created, create it or grant CREATE on the database: {}", schema, e)
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_prefixed_and_quoted_when_needed() {
+        let naming = Naming { schema: None, prefix: "tenant-a_".to_owned() };
+        assert_eq!(
+            naming.render("SELECT id FROM {users} JOIN {events_outbox} ON true"),
+            r#"SELECT id FROM "tenant-a_users" JOIN "tenant-a_events_outbox" ON true"#
+        );
+
+        let naming = Naming { schema: None, prefix: "tenant_b_".to_owned() };
+        let index = naming.render("CREATE INDEX {users_email_lower_key}");
+        assert_eq!(index, "CREATE INDEX tenant_b_users_email_lower_key");
+
+        assert_eq!(identifier(r#"Odd"name"#), r#""Odd""name""#);
+        assert_eq!(identifier("1users"), r#""1users""#);
+    }
+
+    // The checksums of the migrations applied before the names were configurable
+    // are over the SQL as it is without a prefix
+    #[test]
+    fn sql_without_a_prefix_is_unchanged() {
+        let sql = "UPDATE {events_outbox} SET payload = '{\"id\":1}'";
+        assert_eq!(Naming::default().render(sql), "UPDATE events_outbox SET payload = '{\"id\":1}'");
+    }
+}
@@ -0,0 +1,325 @@
+use chrono::{ DateTime, Utc };
+use postgres::{ Client, GenericClient, Row };
+use std::cell::RefCell;
+use std::error::Error;
+use std::sync::{ Mutex, OnceLock };
+
+use crate::repository::RepositoryError;
+use crate::db::{ connector, pool };
+use crate::errors::{ repository_error_response, with_causes };
+use crate::http::{ get_body, get_header, BAD_REQUEST, CONFLICT, INTERNAL_SERVER_ERROR, NOT_IMPLEMENTED, OK_RESPONSE };
+use crate::{ config, migrations, tables };
+
+// The tenant of the rows that predate tenancy, and of everything without it
+pub const DEFAULT_TENANT: &str = "default";
+
+// As long as a Postgres identifier
+const MAX_TENANT_LENGTH: usize = 63;
+
+// The schema of a tenant is its id after this, so with schemas ids are shorter
+const SCHEMA_PREFIX: &str = "tenant_";
+
+// The tenants with a schema of their own, in the main schema
+const CREATE_REGISTRY_QUERY: &str =
+    "CREATE TABLE IF NOT EXISTS {tenants} (
+        id VARCHAR PRIMARY KEY, schema_name VARCHAR NOT NULL, schema_version BIGINT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )";
+
+static TENANCY: OnceLock<Tenancy> = OnceLock::new();
+
+// The tenants known to be in the registry, which only grows
+static PROVISIONED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+thread_local! {
+    // The tenant of the request handled on this thread, set by enter
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// How the customers sharing a deployment are kept apart, from TENANCY
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tenancy {
+    // Everything belongs to the default tenant
+    None,
+    // Every row has the tenant_id of the customer it belongs to, and every request
+    // names its tenant in X-Tenant-Id. A tenant exists once a request names it.
+    Column,
+    // As Column, and the tables of each tenant are in a schema of its own,
+    // tenant_<id>, that POST /admin/tenants creates. Connections are pointed at the
+    // schema of the request when checked out, and back at the main one when
+    // returned.
+    Schema,
+}
+
+impl Tenancy {
+    pub fn from_env() -> Result<Self, String> {
+        match config::var("TENANCY").as_deref() {
+            Ok("none") | Err(_) => Ok(Tenancy::None),
+            Ok("column") => Ok(Tenancy::Column),
+            Ok("schema") => Ok(Tenancy::Schema),
+            Ok(value) => Err(format!("TENANCY must be none, column or schema, got {:?}", value)),
+        }
+    }
+}
+
+pub fn init(tenancy: Tenancy) {
+    TENANCY.set(tenancy).ok();
+}
+
+fn tenancy() -> Tenancy {
+    *TENANCY.get_or_init(|| Tenancy::None)
+}
+
+// The tenant a request is for. Without tenancy it is always the default one and
+// X-Tenant-Id is ignored.
+pub fn from_request(request: &str) -> Result<String, String> {
+    if tenancy() == Tenancy::None {
+        return Ok(DEFAULT_TENANT.to_owned());
+    }
+    match get_header(request, "X-Tenant-Id") {
+        Some(tenant) if is_valid(tenant) => Ok(tenant.to_owned()),
+        Some(_) => Err(
+            format!("X-Tenant-Id must be 1 to {} of a-z, 0-9, _ and -, starting with a-z or 0-9", max_length())
+        ),
+        None => Err("X-Tenant-Id is required".to_owned()),
+    }
+}
+
+fn is_valid(tenant: &str) -> bool {
+    tenant.len() <= max_length()
+        && tenant.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && tenant.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+// With schemas the prefix of the schema takes some of the identifier
+fn max_length() -> usize {
+    match tenancy() {
+        Tenancy::Schema => MAX_TENANT_LENGTH - SCHEMA_PREFIX.len(),
+        _ => MAX_TENANT_LENGTH,
+    }
+}
+
+// Act for the tenant on this thread until the returned guard is dropped
+pub fn enter(tenant: String) -> Entered {
+    CURRENT.set(Some(tenant));
+    Entered
+}
+
+pub struct Entered;
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.set(None);
+    }
+}
+
+// The tenant entered on this thread, the default one outside of requests
+pub fn current() -> String {
+    CURRENT.with_borrow(|tenant| tenant.clone()).unwrap_or_else(|| DEFAULT_TENANT.to_owned())
+}
+
+// For the notifications of instances that predate tenancy
+pub fn default_tenant() -> String {
+    DEFAULT_TENANT.to_owned()
+}
+
+// The schema of the tenant, with schemas per tenant
+pub fn schema_for(tenant: &str) -> Option<String> {
+    (tenancy() == Tenancy::Schema).then(|| format!("{}{}", SCHEMA_PREFIX, tenant))
+}
+
+// The schema of the tenant entered on this thread, if any
+pub fn entered_schema() -> Option<String> {
+    CURRENT.with_borrow(|tenant| tenant.as_deref().and_then(schema_for))
+}
+
+// Point the connection at the schema of a tenant, tables::reset_search_path undoes it
+pub fn use_schema(client: &mut Client, schema: &str) -> Result<(), postgres::Error> {
+    client.batch_execute(&format!("SET search_path TO {}", quoted(schema)))
+}
+
+fn quoted(schema: &str) -> String {
+    format!("\"{}\"", schema.replace('"', "\"\""))
+}
+
+// Whether the tenant may be served: with schemas, once provisioned
+pub fn is_provisioned(tenant: &str) -> Result<bool, RepositoryError> {
+    if tenancy() != Tenancy::Schema || PROVISIONED.lock().unwrap().iter().any(|known| known == tenant) {
+        return Ok(true);
+    }
+    // Maybe by another instance
+    let query = tables::sql("SELECT 1 FROM {tenants} WHERE id = $1");
+    let row = pool().read(|client| client.query_opt(query, &[&tenant]))?;
+    if row.is_some() {
+        PROVISIONED.lock().unwrap().push(tenant.to_owned());
+    }
+    Ok(row.is_some())
+}
+
+// With schemas, create the registry if needed
+pub fn prepare_registry(client: &mut Client) -> Result<(), postgres::Error> {
+    if tenancy() != Tenancy::Schema {
+        return Ok(());
+    }
+    client.batch_execute(tables::sql(CREATE_REGISTRY_QUERY))
+}
+
+// The schemas of the provisioned tenants, none without schemas
+pub fn provisioned_schemas(client: &mut impl GenericClient) -> Result<Vec<String>, postgres::Error> {
+    if tenancy() != Tenancy::Schema {
+        return Ok(Vec::new());
+    }
+    let rows = client.query(tables::sql("SELECT schema_name FROM {tenants} ORDER BY id"), &[])?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+// Apply the pending migrations to the schema of every tenant, after the main one
+pub fn migrate_all(client: &mut Client) -> Result<(), Box<dyn Error>> {
+    migrate_all_up_to(client, i64::MAX)
+}
+
+// Up to the version of `migrate --to`, like the main schema
+pub fn migrate_all_up_to(client: &mut Client, up_to: i64) -> Result<(), Box<dyn Error>> {
+    if tenancy() != Tenancy::Schema {
+        return Ok(());
+    }
+    prepare_registry(client)?;
+    let rows = client.query(tables::sql("SELECT id, schema_name FROM {tenants} ORDER BY id"), &[])?;
+    for row in &rows {
+        let (tenant, schema): (String, String) = (row.get(0), row.get(1));
+        let migrated = migrate_schema(client, &schema, up_to).map_err(|e| format!("tenant {}: {}", tenant, e));
+        let (applied, version) = migrated?;
+        if applied > 0 {
+            log::info!("Migrated the schema of tenant {} to version {}", tenant, version);
+        }
+        client.execute(tables::sql("UPDATE {tenants} SET schema_version = $2 WHERE id = $1"), &[&tenant, &version])?;
+    }
+    Ok(())
+}
+
+// Create the schema if needed and bring it up to date, with the migrations of the
+// main one, with the number of migrations applied and the version it is at. The
+// connection is pointed back at the main schema afterwards.
+fn migrate_schema(client: &mut Client, schema: &str, up_to: i64) -> Result<(usize, i64), Box<dyn Error>> {
+    client.batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {}", quoted(schema)))?;
+    use_schema(client, schema)?;
+    let result = migrations::apply_up_to(client, up_to)
+        .and_then(|applied| Ok((applied.len(), migrations::schema_version(client)?)));
+    tables::reset_search_path(client)?;
+    result
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewTenant {
+    id: String,
+}
+
+// POST /admin/tenants with {"id": ...}: create the schema of a tenant and migrate
+// it. On a connection of its own, like the migrations at startup.
+pub fn handle_create_tenant_request(request: &str) -> (String, String) {
+    if tenancy() != Tenancy::Schema {
+        return (NOT_IMPLEMENTED.to_owned(), "Only available with TENANCY=schema".to_owned());
+    }
+    let tenant = match serde_json::from_str::<NewTenant>(get_body(request)) {
+        Ok(NewTenant { id }) if is_valid(&id) => id,
+        Ok(_) => {
+            let message = format!("id must be 1 to {} of a-z, 0-9, _ and -, starting with a-z or 0-9", max_length());
+            return (BAD_REQUEST.to_owned(), message);
+        }
+        Err(e) => {
+            return (BAD_REQUEST.to_owned(), format!("Invalid request body: {}", e));
+        }
+    };
+
+    match provision(&tenant) {
+        Ok(Some(created)) => {
+            log::info!("Provisioned tenant {}", tenant);
+            (OK_RESPONSE.to_owned(), created.to_string())
+        }
+        Ok(None) => (CONFLICT.to_owned(), format!("Tenant {} already exists", tenant)),
+        Err(e) => {
+            log::error!("Provisioning tenant {} failed: {}", tenant, with_causes(&*e));
+            (INTERNAL_SERVER_ERROR.to_owned(), "Provisioning failed".to_owned())
+        }
+    }
+}
+
+// None when the tenant was already provisioned
+fn provision(tenant: &str) -> Result<Option<serde_json::Value>, Box<dyn Error>> {
+    let mut client = connector().connect()?;
+    prepare_registry(&mut client)?;
+    let exists = client.query_opt(tables::sql("SELECT 1 FROM {tenants} WHERE id = $1"), &[&tenant])?;
+    if exists.is_some() {
+        return Ok(None);
+    }
+
+    let schema = schema_for(tenant).expect("provisioning is only for schemas");
+    let (_, version) = migrate_schema(&mut client, &schema, i64::MAX)?;
+    // Another instance may have provisioned it meanwhile, the schema is the same
+    let inserted = client.query_opt(
+        tables::sql(
+            "INSERT INTO {tenants} (id, schema_name, schema_version) VALUES ($1, $2, $3)
+            ON CONFLICT (id) DO NOTHING RETURNING id, schema_name, schema_version, created_at"
+        ),
+        &[&tenant, &schema, &version]
+    )?;
+    let Some(row) = inserted else {
+        return Ok(None);
+    };
+    PROVISIONED.lock().unwrap().push(tenant.to_owned());
+    Ok(Some(to_json(&row)))
+}
+
+// A row of the registry, from id, schema_name, schema_version and created_at
+fn to_json(row: &Row) -> serde_json::Value {
+    let (id, schema, version): (String, String, i64) = (row.get(0), row.get(1), row.get(2));
+    let created_at: DateTime<Utc> = row.get(3);
+    serde_json::json!({ "id": id, "schema": schema, "schema_version": version, "created_at": created_at })
+}
+
+// GET /admin/tenants, the registry
+pub fn handle_list_tenants_request() -> (String, String) {
+    if tenancy() != Tenancy::Schema {
+        return (NOT_IMPLEMENTED.to_owned(), "Only available with TENANCY=schema".to_owned());
+    }
+    let rows = pool().read(|client| {
+        client.query(
+            tables::sql("SELECT id, schema_name, schema_version, created_at FROM {tenants} ORDER BY id"),
+            &[]
+        )
+    });
+    match rows {
+        Ok(rows) => {
+            let tenants: Vec<serde_json::Value> = rows.iter().map(to_json).collect();
+            (OK_RESPONSE.to_owned(), serde_json::Value::from(tenants).to_string())
+        }
+        Err(e) => repository_error_response(e.into(), "Error listing tenants"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenants_are_lowercase_slugs() {
+        assert!(is_valid("acme"));
+        assert!(is_valid("acme-eu_2"));
+        assert!(is_valid(&"a".repeat(MAX_TENANT_LENGTH)));
+        assert!(!is_valid(""));
+        assert!(!is_valid("-acme"));
+        assert!(!is_valid("Acme"));
+        assert!(!is_valid("acme corp"));
+        assert!(!is_valid(&"a".repeat(MAX_TENANT_LENGTH + 1)));
+    }
+
+    #[test]
+    fn the_tenant_is_left_with_the_guard() {
+        assert_eq!(current(), DEFAULT_TENANT);
+        let entered = enter("acme".to_owned());
+        assert_eq!(current(), "acme");
+        drop(entered);
+        assert_eq!(current(), DEFAULT_TENANT);
+    }
+}
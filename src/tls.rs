@@ -0,0 +1,301 @@
+use native_tls::{ Certificate, TlsConnector };
+use postgres::config::SslMode;
+use postgres::error::SqlState;
+use postgres::{ CancelToken, Client, Config, NoTls };
+use postgres_native_tls::MakeTlsConnector;
+use std::fs;
+use std::sync::{ Arc, RwLock };
+use std::time::Duration;
+
+use crate::credentials::{ self, Credentials, Secrets };
+use crate::http::decode_query_value;
+use crate::tables;
+
+// How much of the server's certificate is checked, with the meaning libpq gives
+// to sslmode. Without a root certificate, prefer and require encrypt but trust
+// any certificate; with one they check it like verify-ca.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TlsMode {
+    Disable,
+    Prefer,
+    Require,
+    // The certificate must be signed by a trusted CA
+    VerifyCa,
+    // As verify-ca, and it must also be issued for the host connected to
+    VerifyFull,
+}
+
+impl TlsMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TlsMode::Disable => "disable",
+            TlsMode::Prefer => "prefer",
+            TlsMode::Require => "require",
+            TlsMode::VerifyCa => "verify-ca",
+            TlsMode::VerifyFull => "verify-full",
+        }
+    }
+
+    pub(crate) fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "disable" => Ok(TlsMode::Disable),
+            "prefer" => Ok(TlsMode::Prefer),
+            "require" => Ok(TlsMode::Require),
+            "verify-ca" => Ok(TlsMode::VerifyCa),
+            "verify-full" => Ok(TlsMode::VerifyFull),
+            _ =>
+                Err(
+                    format!(
+                        "sslmode must be one of disable, prefer, require, verify-ca or verify-full, got {:?}",
+                        value
+                    )
+                ),
+        }
+    }
+}
+
+// How the connections are encrypted, as the connection string and the variables
+// ask, for check to report
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsSettings {
+    pub mode: TlsMode,
+    pub root_cert: Option<String>,
+}
+
+// Opens connections to the database, with or without TLS as the connection
+// string asks. Everything that connects goes through it. Clones share the
+// credentials, read again by whichever clone finds them out of date.
+#[derive(Clone)]
+pub struct Connector {
+    current: Arc<RwLock<Loaded>>,
+    credentials: Arc<Credentials>,
+}
+
+// What the connections are opened with, from the credentials as last read
+struct Loaded {
+    config: Config,
+    tls: Option<MakeTlsConnector>,
+    tls_settings: TlsSettings,
+    secrets: Secrets,
+    // credentials::reload_requests() when they were read
+    reload_requests: u64,
+}
+
+impl Connector {
+    pub fn from_credentials(credentials: Credentials) -> Result<Self, String> {
+        let reload_requests = credentials::reload_requests();
+        let secrets = credentials.read()?;
+        let (config, tls, tls_settings) = configure(&secrets, &credentials)?;
+        let loaded = Loaded { config, tls, tls_settings, secrets, reload_requests };
+        Ok(Connector { current: Arc::new(RwLock::new(loaded)), credentials: Arc::new(credentials) })
+    }
+
+    // With credentials from files, a connection refused for them is tried again
+    // once the files are read again, in case they were rotated since
+    pub fn connect(&self) -> Result<Client, postgres::Error> {
+        let stale = self.current.read().unwrap().reload_requests != credentials::reload_requests();
+        if stale {
+            self.reload("after SIGHUP");
+        }
+
+        match self.connect_once() {
+            Err(e) if is_authentication_failure(&e) && self.reload("after an authentication failure") => {
+                self.connect_once()
+            }
+            result => result,
+        }
+    }
+
+    fn connect_once(&self) -> Result<Client, postgres::Error> {
+        let (config, tls) = {
+            let current = self.current.read().unwrap();
+            (current.config.clone(), current.tls.clone())
+        };
+        let mut client = match tls {
+            Some(tls) => config.connect(tls)?,
+            None => config.connect(NoTls)?,
+        };
+        tables::set_search_path(&mut client)?;
+        Ok(client)
+    }
+
+    // Read the credentials again, true when they changed and are used from now on
+    fn reload(&self, reason: &str) -> bool {
+        if !self.credentials.reads_files() {
+            return false;
+        }
+        let reload_requests = credentials::reload_requests();
+        let mut current = self.current.write().unwrap();
+        current.reload_requests = reload_requests;
+
+        let secrets = match self.credentials.read() {
+            Ok(secrets) => secrets,
+            Err(e) => {
+                log::error!("Error reading the database credentials again: {}", e);
+                return false;
+            }
+        };
+        if secrets == current.secrets {
+            return false;
+        }
+        match configure(&secrets, &self.credentials) {
+            Ok((config, tls, tls_settings)) => {
+                log::info!("Read new database credentials {}", reason);
+                *current = Loaded { config, tls, tls_settings, secrets, reload_requests };
+                true
+            }
+            Err(e) => {
+                log::warn!("Ignoring the new database credentials: {}", e);
+                false
+            }
+        }
+    }
+
+    // Each attempt to connect given up after the timeout, unless the connection
+    // string has one
+    pub fn with_connect_timeout(self, timeout: Duration) -> Self {
+        let mut current = self.current.write().unwrap();
+        if current.config.get_connect_timeout().is_none() {
+            current.config.connect_timeout(timeout);
+        }
+        drop(current);
+        self
+    }
+
+    pub fn tls_settings(&self) -> TlsSettings {
+        self.current.read().unwrap().tls_settings.clone()
+    }
+
+    // The cancel request goes over a connection of its own, encrypted like the others
+    pub fn cancel(&self, token: &CancelToken) -> Result<(), postgres::Error> {
+        match self.current.read().unwrap().tls.clone() {
+            Some(tls) => token.cancel_query(tls),
+            None => token.cancel_query(NoTls),
+        }
+    }
+}
+
+// sslmode and sslrootcert come from the connection string, or else from
+// PGSSLMODE and PGSSLROOTCERT, as the credentials were read with. The postgres
+// crate doesn't know the verify modes nor sslrootcert, so they are taken out
+// before it parses the rest. Errors never include the connection string, which
+// holds the password.
+fn configure(
+    secrets: &Secrets,
+    credentials: &Credentials
+) -> Result<(Config, Option<MakeTlsConnector>, TlsSettings), String> {
+    let (url, params) = take_tls_params(secrets.url.expose());
+    let mut config = url
+        .parse::<Config>()
+        .map_err(|e| format!("DATABASE_URL is not a valid connection string: {}", e))?;
+    if let (None, Some(password)) = (config.get_password(), &secrets.password) {
+        config.password(password.expose());
+    }
+
+    let mode = match params.mode {
+        Some(mode) => TlsMode::parse(&mode)?,
+        None => credentials.ssl_mode.unwrap_or(TlsMode::Prefer),
+    };
+    let root_cert = params.root_cert.or_else(|| credentials.ssl_root_cert.clone());
+
+    config.ssl_mode(match mode {
+        TlsMode::Disable => SslMode::Disable,
+        TlsMode::Prefer => SslMode::Prefer,
+        TlsMode::Require | TlsMode::VerifyCa | TlsMode::VerifyFull => SslMode::Require,
+    });
+    let tls = match mode {
+        TlsMode::Disable => None,
+        _ => Some(MakeTlsConnector::new(tls_connector(mode, root_cert.as_deref())?)),
+    };
+
+    Ok((config, tls, TlsSettings { mode, root_cert }))
+}
+
+// Wrong password, or no such role
+fn is_authentication_failure(error: &postgres::Error) -> bool {
+    error.code().is_some_and(|code| {
+        *code == SqlState::INVALID_PASSWORD || *code == SqlState::INVALID_AUTHORIZATION_SPECIFICATION
+    })
+}
+
+fn tls_connector(mode: TlsMode, root_cert: Option<&str>) -> Result<TlsConnector, String> {
+    let mut builder = TlsConnector::builder();
+
+    match root_cert {
+        Some(path) => {
+            let pem = fs::read(path).map_err(|e| format!("Can't read the root certificate {}: {}", path, e))?;
+            let certificates = Certificate::stack_from_pem(&pem).map_err(|e|
+                format!("Invalid root certificate {}: {}", path, e)
+            )?;
+            if certificates.is_empty() {
+                return Err(format!("No certificate found in {}", path));
+            }
+            for certificate in certificates {
+                builder.add_root_certificate(certificate);
+            }
+        }
+        None if mode == TlsMode::Prefer || mode == TlsMode::Require => {
+            builder.danger_accept_invalid_certs(true);
+        }
+        // Verified against the system's trusted CAs
+        None => {}
+    }
+    if mode != TlsMode::VerifyFull {
+        builder.danger_accept_invalid_hostnames(true);
+    }
+
+    builder.build().map_err(|e| format!("Can't set up TLS: {}", e))
+}
+
+#[derive(Default)]
+struct TlsParams {
+    mode: Option<String>,
+    root_cert: Option<String>,
+}
+
+// Split sslmode and sslrootcert off the connection string, which is either a
+// postgres:// URL or space separated key=value pairs
+fn take_tls_params(url: &str) -> (String, TlsParams) {
+    let mut params = TlsParams::default();
+    let mut take = |key: &str, value: String| {
+        match key {
+            "sslmode" => params.mode = Some(value),
+            "sslrootcert" => params.root_cert = Some(value),
+            _ => {
+                return false;
+            }
+        }
+        true
+    };
+
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        let (base, query) = match url.split_once('?') {
+            Some((base, query)) => (base, query),
+            None => {
+                return (url.to_owned(), params);
+            }
+        };
+        let rest: Vec<&str> = query
+            .split('&')
+            .filter(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                !take(key, decode_query_value(value))
+            })
+            .collect();
+
+        if rest.is_empty() {
+            (base.to_owned(), params)
+        } else {
+            (format!("{}?{}", base, rest.join("&")), params)
+        }
+    } else {
+        let rest: Vec<&str> = url
+            .split_whitespace()
+            .filter(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                !take(key, value.trim_matches('\'').to_owned())
+            })
+            .collect();
+        (rest.join(" "), params)
+    }
+}
@@ -0,0 +1,258 @@
+use std::cell::{ Cell, RefCell };
+use std::fs::File;
+use std::io::Read;
+use std::time::SystemTime;
+
+use crate::http::{ get_header, with_header };
+use crate::{ otlp, spans };
+
+// The longest tracestate passed on, as the spec asks to keep at least
+const MAX_STATE_LENGTH: usize = 512;
+
+thread_local! {
+    // The trace context of the request being answered on this thread
+    static CURRENT: RefCell<Context> = const { RefCell::new(Context { remote: None, request: None }) };
+    // Seeded once per thread, reading /dev/urandom for every span would cost more
+    // than the span
+    static RANDOM: Cell<u64> = const { Cell::new(0) };
+}
+
+// The W3C Trace Context of the requests: the traceparent they were sent with, when
+// it is valid, is the parent of their request span, which is in the same trace,
+// and else it is the root of a new one. The responses have the traceparent of the
+// request span, and the requests sent while answering them, to the OIDC provider
+// or the error webhook, that of the span they were sent from. The tracestate,
+// unchanged, goes wherever the traceparent it came with does.
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    remote: Option<Remote>,
+    // Once its span is open
+    request: Option<Trace>,
+}
+
+// The traceparent and tracestate a request was sent with
+#[derive(Clone, Debug, PartialEq)]
+pub struct Remote {
+    trace_id: [u8; 16],
+    parent_id: [u8; 8],
+    sampled: bool,
+    state: Option<String>,
+}
+
+// The trace of a span, and where it is in it
+#[derive(Clone, Debug)]
+pub struct Trace {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: Option<[u8; 8]>,
+    // Whether the parent is in the service that sent the request
+    pub remote_parent: bool,
+    pub sampled: bool,
+    pub state: Option<String>,
+    pub started: SystemTime,
+}
+
+impl Trace {
+    // Under parent, or under what the request on this thread was sent with
+    pub fn new(parent: Option<&Trace>) -> Trace {
+        let remote = match parent {
+            Some(_) => None,
+            None => CURRENT.with_borrow(|current| current.remote.clone()),
+        };
+        let (trace_id, parent_span_id, parent_sampled, state) = match (parent, &remote) {
+            (Some(parent), _) => (parent.trace_id, Some(parent.span_id), Some(parent.sampled), &parent.state),
+            (None, Some(remote)) => (remote.trace_id, Some(remote.parent_id), Some(remote.sampled), &remote.state),
+            (None, None) => (random(), None, None, &None),
+        };
+        Trace {
+            trace_id,
+            span_id: random(),
+            parent_span_id,
+            remote_parent: remote.is_some(),
+            sampled: otlp::sampled(&trace_id, parent_sampled),
+            state: state.clone(),
+            started: SystemTime::now(),
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-0{}", hex(&self.trace_id), hex(&self.span_id), self.sampled as u8)
+    }
+
+    // traceparent, and tracestate if there is one, as header lines
+    fn headers(&self) -> String {
+        match &self.state {
+            Some(state) => format!("traceparent: {}\r\ntracestate: {}", self.traceparent(), state),
+            None => format!("traceparent: {}", self.traceparent()),
+        }
+    }
+}
+
+// The context the request was sent with, invalid traceparents being ignored
+pub fn from_request(request: &str) -> Context {
+    let remote = get_header(request, "traceparent").and_then(parse).map(|(trace_id, parent_id, sampled)| {
+        let state = get_header(request, "tracestate").filter(|state| valid_state(state)).map(str::to_owned);
+        Remote { trace_id, parent_id, sampled, state }
+    });
+    Context { remote, request: None }
+}
+
+// The trace id, parent id and sampled flag of a traceparent, of version 00 or of a
+// later one, read as 00 is
+fn parse(traceparent: &str) -> Option<([u8; 16], [u8; 8], bool)> {
+    let parts: Vec<&str> = traceparent.trim().split('-').collect();
+    let [version, trace_id, parent_id, flags, rest @ ..] = parts.as_slice() else {
+        return None;
+    };
+    if *version == "ff" || (*version == "00" && !rest.is_empty()) {
+        return None;
+    }
+    let _version: [u8; 1] = unhex(version)?;
+    let (trace_id, parent_id, [flags]) = (unhex(trace_id)?, unhex(parent_id)?, unhex(flags)?);
+    if trace_id == [0; 16] || parent_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, parent_id, flags & 1 == 1))
+}
+
+fn valid_state(state: &str) -> bool {
+    state.len() <= MAX_STATE_LENGTH
+        && state.is_ascii()
+        && state.split(',').map(str::trim).filter(|member| !member.is_empty()).all(|member| {
+            member.split_once('=').is_some_and(|(key, value)| !key.is_empty() && !value.is_empty())
+        })
+}
+
+// Lowercase hex only, as the spec writes it
+fn unhex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    let lowercase = text.bytes().all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte));
+    if text.len() != N * 2 || !lowercase {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// The context of the request on this thread, until the returned guard is dropped
+pub fn enter(context: Context) -> Entered {
+    CURRENT.set(context);
+    Entered
+}
+
+pub struct Entered;
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.take();
+    }
+}
+
+// For the thread answering the request from then on to enter
+pub fn current() -> Context {
+    CURRENT.with_borrow(Context::clone)
+}
+
+// The request span of the request on this thread was opened with trace
+pub fn started(trace: &Trace) {
+    CURRENT.with_borrow_mut(|current| current.request = Some(trace.clone()));
+}
+
+// The head of a response with the traceparent of the request span
+pub fn added(head: &str) -> String {
+    match CURRENT.with_borrow(|current| current.request.as_ref().map(Trace::headers)) {
+        Some(headers) => with_header(head, &headers),
+        None => head.to_owned(),
+    }
+}
+
+// The header lines of a request sent from the span entered on this thread, empty
+// outside of one
+pub fn outbound() -> String {
+    spans::current_trace().map_or_else(String::new, |trace| format!("{}\r\n", trace.headers()))
+}
+
+// Random enough for ids, that only have to be unique
+pub fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    RANDOM.with(|state| {
+        if state.get() == 0 {
+            let mut seed = [0; 8];
+            File::open("/dev/urandom")
+                .and_then(|mut random| random.read_exact(&mut seed))
+                .expect("the system has random bytes");
+            state.set(u64::from_ne_bytes(seed) | 1);
+        }
+        for chunk in bytes.chunks_mut(8) {
+            // SplitMix64
+            let next = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+            state.set(next);
+            let mut z = next;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            chunk.copy_from_slice(&(z ^ (z >> 31)).to_be_bytes()[..chunk.len()]);
+        }
+    });
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_valid_traceparents_are_taken() {
+        let parsed = parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!((hex(&parsed.0), hex(&parsed.1), parsed.2), (
+            "4bf92f3577b34da6a3ce929d0e0e4736".to_owned(),
+            "00f067aa0ba902b7".to_owned(),
+            true
+        ));
+        assert!(!parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").unwrap().2);
+        // Later versions may add fields
+        assert!(parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-what").is_some());
+
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-what",
+            "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1",
+        ] {
+            assert_eq!(parse(invalid), None, "{}", invalid);
+        }
+        assert!(valid_state("congo=t61rcWkgMzE,rojo=00f067aa0ba902b7"));
+        assert!(!valid_state("congo"));
+    }
+
+    #[test]
+    fn request_spans_are_under_the_traceparent_sent() {
+        let request = "GET / HTTP/1.1\r\ntraceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01\r\n\
+                       tracestate: congo=t61rcWkgMzE\r\n\r\n";
+        let _entered = enter(from_request(request));
+        let root = Trace::new(None);
+        assert_eq!(hex(&root.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(root.parent_span_id.map(|id| hex(&id)).as_deref(), Some("00f067aa0ba902b7"));
+        assert!(root.remote_parent && root.sampled);
+        let child = Trace::new(Some(&root));
+        assert_eq!((child.trace_id, child.parent_span_id), (root.trace_id, Some(root.span_id)));
+        assert!(!child.remote_parent);
+        assert_ne!(child.span_id, root.span_id);
+
+        started(&root);
+        let head = added("HTTP/1.1 200 OK\r\n\r\n");
+        let traceparent = format!("traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01\r\n", hex(&root.span_id));
+        assert!(head.contains(&traceparent), "{}", head);
+        assert!(head.contains("tracestate: congo=t61rcWkgMzE\r\n"), "{}", head);
+    }
+}
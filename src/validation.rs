@@ -0,0 +1,452 @@
+use std::sync::{ Arc, RwLock };
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config;
+use crate::locale;
+use crate::password::Password;
+use crate::repository::{ RepositoryError, UserRepository };
+
+// Default limits on the trimmed name, in characters
+pub const MIN_NAME_LENGTH: usize = 1;
+pub const MAX_NAME_LENGTH: usize = 100;
+
+// Longest address that fits in the SMTP forward-path
+pub const MAX_EMAIL_LENGTH: usize = 254;
+
+// Limits on a password, in characters. Argon2 takes as long as its input is, the
+// longest keeps a request from tying up a worker with megabytes of one.
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+
+// The rules that differ between deployments, read from the environment at startup:
+// VALIDATION_MIN_NAME_LENGTH, VALIDATION_MAX_NAME_LENGTH and the comma separated
+// VALIDATION_ALLOWED_EMAIL_DOMAINS and VALIDATION_DENIED_EMAIL_DOMAINS. An empty
+// allow list allows every domain; VALIDATION_MATCH_EMAIL_SUBDOMAINS=true makes both
+// lists cover the subdomains of their entries. With VALIDATION_STRICT_SANITIZATION=true
+// input that sanitize would change is rejected instead.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidationConfig {
+    pub min_name_length: usize,
+    pub max_name_length: usize,
+    pub allowed_email_domains: Vec<String>,
+    pub denied_email_domains: Vec<String>,
+    pub match_email_subdomains: bool,
+    pub strict_sanitization: bool,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            min_name_length: MIN_NAME_LENGTH,
+            max_name_length: MAX_NAME_LENGTH,
+            allowed_email_domains: Vec::new(),
+            denied_email_domains: Vec::new(),
+            match_email_subdomains: false,
+            strict_sanitization: false,
+        }
+    }
+}
+
+impl ValidationConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let defaults = ValidationConfig::default();
+        let config = ValidationConfig {
+            min_name_length: length_from_env("VALIDATION_MIN_NAME_LENGTH", defaults.min_name_length)?,
+            max_name_length: length_from_env("VALIDATION_MAX_NAME_LENGTH", defaults.max_name_length)?,
+            allowed_email_domains: domains_from_env("VALIDATION_ALLOWED_EMAIL_DOMAINS"),
+            denied_email_domains: domains_from_env("VALIDATION_DENIED_EMAIL_DOMAINS"),
+            match_email_subdomains: flag_from_env(
+                "VALIDATION_MATCH_EMAIL_SUBDOMAINS",
+                defaults.match_email_subdomains
+            )?,
+            strict_sanitization: flag_from_env("VALIDATION_STRICT_SANITIZATION", defaults.strict_sanitization)?,
+        };
+
+        if config.min_name_length == 0 {
+            return Err("VALIDATION_MIN_NAME_LENGTH must be at least 1".to_owned());
+        }
+        if config.min_name_length > config.max_name_length {
+            return Err(
+                format!(
+                    "VALIDATION_MIN_NAME_LENGTH ({}) must not be greater than VALIDATION_MAX_NAME_LENGTH ({})",
+                    config.min_name_length,
+                    config.max_name_length
+                )
+            );
+        }
+        let contradiction = config.allowed_email_domains
+            .iter()
+            .find(|domain| config.denied_email_domains.contains(domain));
+        if let Some(domain) = contradiction {
+            return Err(format!("{} is both an allowed and a denied email domain", domain));
+        }
+
+        Ok(config)
+    }
+
+    // Both sides are lowercase ASCII, list entries went through ascii_domain too
+    fn domain_matches(&self, domain: &str, entry: &str) -> bool {
+        domain == entry ||
+            (self.match_email_subdomains &&
+                domain.strip_suffix(entry).is_some_and(|subdomain| subdomain.ends_with('.')))
+    }
+}
+
+fn length_from_env(name: &str, default: usize) -> Result<usize, String> {
+    match config::var(name) {
+        Ok(value) => value.trim().parse().map_err(|_| format!("{} must be a number, got {:?}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+fn flag_from_env(name: &str, default: bool) -> Result<bool, String> {
+    match config::var(name) {
+        Ok(value) => value.trim().parse().map_err(|_| format!("{} must be true or false, got {:?}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+fn domains_from_env(name: &str) -> Vec<String> {
+    config::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|domain| domain.trim())
+        .filter(|domain| !domain.is_empty())
+        .map(ascii_domain)
+        .collect()
+}
+
+static CONFIG: RwLock<Option<Arc<ValidationConfig>>> = RwLock::new(None);
+
+// Install the configuration loaded at startup, or again after a SIGHUP changed it
+pub fn init(config: ValidationConfig) {
+    *CONFIG.write().unwrap() = Some(Arc::new(config));
+}
+
+fn config() -> Arc<ValidationConfig> {
+    CONFIG.read().unwrap().clone().unwrap_or_default()
+}
+
+// Body of POST /users, PUT /users/{id} and POST /users/validate, and what the
+// client commands send, without the password
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NewUser {
+    pub name: String,
+    pub email: String,
+    // Only for POST /users, which stores its hash. It is changed with
+    // PUT /users/{id}/password.
+    #[serde(default, skip_serializing)]
+    pub password: Option<Password>,
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ValidationError {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub(crate) fn new(field: &'static str, code: &'static str, message: impl Into<String>) -> Self {
+        ValidationError { field, code, message: message.into() }
+    }
+
+    pub fn email_taken() -> Self {
+        ValidationError::new("email", "taken", locale::text("validation.taken", &[]))
+    }
+
+    pub fn password_elsewhere() -> Self {
+        ValidationError::new("password", "not_allowed", locale::text("validation.password_elsewhere", &[]))
+    }
+}
+
+// Every rule a new user has to pass, database checks included. All the failures
+// are reported, not just the first, so a form can flag every field at once.
+pub fn validate_new_user(
+    repository: &dyn UserRepository,
+    user: &mut NewUser
+) -> Result<Vec<ValidationError>, RepositoryError> {
+    let mut errors = validate_fields(user);
+
+    // Only a well-formed email is worth looking up
+    if !errors.iter().any(|error| error.field == "email") && repository.email_taken(&user.email)? {
+        errors.push(ValidationError::email_taken());
+    }
+
+    Ok(errors)
+}
+
+// The rules that only look at the input itself. The user is cleaned up on the way:
+// what passes is what gets stored.
+pub fn validate_fields(user: &mut NewUser) -> Vec<ValidationError> {
+    validate_fields_with(&config(), user)
+}
+
+pub fn validate_fields_with(config: &ValidationConfig, user: &mut NewUser) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+
+    let name = sanitize_field(config, "name", &user.name, true).map(|name| normalize_name(&name));
+    let email = sanitize_field(config, "email", &user.email, false).map(|email| normalize_email(&email));
+
+    match name {
+        Ok(name) => {
+            errors.extend(validate_name(config, &name));
+            user.name = name;
+        }
+        Err(error) => errors.push(error),
+    }
+    match email {
+        Ok(email) => {
+            errors.extend(validate_email(&email).or_else(|| validate_email_domain(config, &email)));
+            user.email = email;
+        }
+        Err(error) => errors.push(error),
+    }
+    if let Some(password) = &user.password {
+        errors.extend(validate_password("password", password));
+    }
+
+    errors
+}
+
+// The same text can be encoded in several ways in Unicode, precomposed "é" or "e"
+// followed by a combining acute accent. Names are stored in NFC so that they are
+// found whichever form a client sends.
+pub fn normalize_name(name: &str) -> String {
+    name.nfc().collect()
+}
+
+// Emails are compared case-insensitively, so they are stored lowercased, with the
+// local part in NFC like names. Unicode domains are stored in punycode, the form DNS
+// uses, so müller@bücher.example and müller@xn--bcher-kva.example are the same
+// address. IDNA2008 keeps ß distinct: straße.de and strasse.de are two domains.
+pub fn normalize_email(email: &str) -> String {
+    match email.rsplit_once('@') {
+        Some((local, domain)) => {
+            let local: String = local.nfc().collect();
+            format!("{}@{}", local.to_lowercase(), ascii_domain(domain))
+        }
+        None => email.nfc().collect::<String>().to_lowercase(),
+    }
+}
+
+// A domain that isn't valid IDNA is left for validate_email to reject
+fn ascii_domain(domain: &str) -> String {
+    idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_lowercase())
+}
+
+// The stored email with the domain back in Unicode, if it has any punycode label
+pub fn display_email(email: &str) -> Option<String> {
+    let (local, domain) = email.rsplit_once('@')?;
+    if !domain.split('.').any(|label| label.starts_with("xn--")) {
+        return None;
+    }
+    match idna::domain_to_unicode(domain) {
+        (unicode, Ok(())) => Some(format!("{}@{}", local, unicode)),
+        (_, Err(_)) => None,
+    }
+}
+
+// Characters that render as nothing but can reorder or hide text: zero-width
+// spaces and joiners, bidi marks, embeddings, overrides and isolates, BOM
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200b}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2060}'..='\u{2064}' | '\u{2066}'..='\u{2069}' | '\u{feff}'
+    )
+}
+
+// Strip control characters and invisible formatting characters. Line breaks are left
+// for validation to reject. Names also get their runs of spaces and tabs collapsed.
+pub fn sanitize(value: &str, collapse_whitespace: bool) -> String {
+    let mut sanitized = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        let line_break = c == '\n' || c == '\r';
+        if is_invisible(c) || (c.is_control() && !line_break && c != '\t') {
+            continue;
+        }
+        if collapse_whitespace && c.is_whitespace() && !line_break {
+            if !sanitized.ends_with(' ') {
+                sanitized.push(' ');
+            }
+            continue;
+        }
+        sanitized.push(c);
+    }
+
+    sanitized
+}
+
+// Sanitize and trim a field, rejecting it when there's nothing left of it or, in
+// strict mode, when sanitizing changed it
+fn sanitize_field(
+    config: &ValidationConfig,
+    field: &'static str,
+    value: &str,
+    collapse_whitespace: bool
+) -> Result<String, ValidationError> {
+    let sanitized = sanitize(value, collapse_whitespace);
+
+    if config.strict_sanitization && sanitized != value {
+        let spaces = if collapse_whitespace { "_spaces" } else { "" };
+        let message = locale::text(&format!("validation.strict_characters{}", spaces), &[("field", field)]);
+        return Err(ValidationError::new(field, "invalid_characters", message));
+    }
+    if sanitized.trim().is_empty() && !value.trim().is_empty() {
+        let message = locale::text("validation.only_invisible", &[("field", field)]);
+        return Err(ValidationError::new(field, "invalid_characters", message));
+    }
+
+    Ok(sanitized.trim().to_owned())
+}
+
+// Expects the name already sanitized and trimmed
+pub fn validate_name(config: &ValidationConfig, name: &str) -> Option<ValidationError> {
+    let error = |code, key, args: &[(&str, &str)]| {
+        Some(ValidationError::new("name", code, locale::text(key, &[&[("field", "name")], args].concat())))
+    };
+    let length = name.chars().count();
+
+    if name.is_empty() {
+        return error("required", "validation.required", &[]);
+    }
+    if length < config.min_name_length {
+        return error("too_short", "validation.too_short", &[("min", &config.min_name_length.to_string())]);
+    }
+    if length > config.max_name_length {
+        return error("too_long", "validation.too_long", &[("max", &config.max_name_length.to_string())]);
+    }
+    if name.chars().any(char::is_control) {
+        return error("invalid_characters", "validation.name_characters", &[]);
+    }
+
+    None
+}
+
+// Taken as it is, spaces and all: the password that was typed is the one checked
+pub fn validate_password(field: &'static str, password: &Password) -> Option<ValidationError> {
+    let error = |code, key, limit: (&str, usize)| {
+        let message = locale::text(key, &[("field", field), (limit.0, &limit.1.to_string())]);
+        Some(ValidationError::new(field, code, message))
+    };
+    let length = password.characters();
+
+    if length < MIN_PASSWORD_LENGTH {
+        return error("too_short", "validation.too_short", ("min", MIN_PASSWORD_LENGTH));
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return error("too_long", "validation.too_long", ("max", MAX_PASSWORD_LENGTH));
+    }
+
+    None
+}
+
+// A pragmatic check rather than RFC 5321: one @, something before it and a dotted
+// domain after it. Quoted local parts and IP literals ([192.0.2.1]) are rejected,
+// unicode is allowed on both sides.
+pub fn validate_email(email: &str) -> Option<ValidationError> {
+    let error = |code, key| Some(ValidationError::new("email", code, locale::text(key, &[("field", "email")])));
+
+    if email.is_empty() {
+        return error("required", "validation.required");
+    }
+    if email.chars().count() > MAX_EMAIL_LENGTH {
+        let max = MAX_EMAIL_LENGTH.to_string();
+        let message = locale::text("validation.too_long", &[("field", "email"), ("max", &max)]);
+        return Some(ValidationError::new("email", "too_long", message));
+    }
+    if email.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return error("invalid_characters", "validation.email_characters");
+    }
+
+    let (local, domain) = match email.split_once('@') {
+        Some((_, domain)) if domain.contains('@') => {
+            return error("multiple_at", "validation.multiple_at");
+        }
+        Some(parts) => parts,
+        None => {
+            return error("missing_at", "validation.missing_at");
+        }
+    };
+
+    if local.is_empty() {
+        return error("empty_local_part", "validation.empty_local_part");
+    }
+    if !domain.contains('.') || domain.split('.').any(|label| label.is_empty()) {
+        return error("invalid_domain", "validation.invalid_domain");
+    }
+    if domain.starts_with('[') {
+        return error("invalid_domain", "validation.ip_domain");
+    }
+    // Unicode domains have been converted to punycode unless they aren't valid IDNA
+    if !domain.is_ascii() || idna::domain_to_unicode(domain).1.is_err() {
+        return error("invalid_domain", "validation.idna_domain");
+    }
+
+    None
+}
+
+// Expects a well-formed, lowercased email
+pub fn validate_email_domain(config: &ValidationConfig, email: &str) -> Option<ValidationError> {
+    let domain = email.rsplit_once('@').map_or(email, |(_, domain)| domain);
+    let message = locale::text("validation.domain_rejected", &[("domain", domain)]);
+
+    if config.denied_email_domains.iter().any(|denied| config.domain_matches(domain, denied)) {
+        return Some(ValidationError::new("email", "domain_denied", message));
+    }
+    let allowed = config.allowed_email_domains.iter().any(|allowed| config.domain_matches(domain, allowed));
+    if !config.allowed_email_domains.is_empty() && !allowed {
+        return Some(ValidationError::new("email", "domain_not_allowed", message));
+    }
+
+    None
+}
+
+// Response body listing the failed rules
+pub fn errors_body(errors: &[ValidationError]) -> String {
+    serde_json::json!({ "errors": errors }).to_string()
+}
+
+// Describe why a request body couldn't be read as a NewUser: which field is unknown,
+// missing or of the wrong type, and where in the body it went wrong
+pub fn body_error(body: &str, error: &serde_json::Error) -> String {
+    serde_json::json!({ "error": described(body, error) }).to_string()
+}
+
+// The members of body_error: its code, field, message, line and column
+pub fn described(body: &str, error: &serde_json::Error) -> serde_json::Value {
+    // serde_json ends its messages with the location, which is reported separately
+    let message = error.to_string();
+    let description = message.rsplit_once(" at line ").map_or(message.as_str(), |(description, _)| description);
+
+    let (code, field) = if description.starts_with("unknown field") {
+        ("unknown_field", description.split('`').nth(1).map(str::to_owned))
+    } else if description.starts_with("missing field") {
+        ("missing_field", description.split('`').nth(1).map(str::to_owned))
+    } else if error.is_data() {
+        // The message names the expected type but not the field, find it ourselves
+        ("invalid_type", mistyped_field(body).map(str::to_owned))
+    } else {
+        ("invalid_json", None)
+    };
+
+    serde_json::json!({
+        "code": code,
+        "field": field,
+        "message": description,
+        "line": error.line(),
+        "column": error.column(),
+    })
+}
+
+fn mistyped_field(body: &str) -> Option<&'static str> {
+    let value: serde_json::Value = serde_json::from_str(body).ok()?;
+    let object = value.as_object()?;
+    ["name", "email"]
+        .into_iter()
+        .find(|field| object.get(*field).is_some_and(|value| !value.is_string()))
+}
@@ -0,0 +1,214 @@
+use chrono::{ DateTime, Utc };
+use std::sync::OnceLock;
+use std::thread;
+
+use crate::mail::{ self, Sender };
+use crate::config::{ self, number_from_env };
+use crate::pool::TransactionOptions;
+use crate::repository::{ RepositoryError, UserRepository };
+use crate::db::pool;
+use crate::errors::{ repository_error_response, user_not_found };
+use crate::handlers::invalidate_cached;
+use crate::http::{ get_body, BAD_REQUEST, NOT_IMPLEMENTED, OK_RESPONSE };
+use crate::{ api_keys, locale, outbox, tables, tenant, validation };
+
+const DEFAULT_TOKEN_LIFETIME_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_URL: &str = "http://localhost:8080/verify?token={token}";
+
+const BAD_REQUEST_PROBLEM: &str = "HTTP/1.1 400 BAD REQUEST\r\nContent-Type: application/problem+json\r\n\r\n";
+const GONE_PROBLEM: &str = "HTTP/1.1 410 GONE\r\nContent-Type: application/problem+json\r\n\r\n";
+const CONFLICT_PROBLEM: &str = "HTTP/1.1 409 CONFLICT\r\nContent-Type: application/problem+json\r\n\r\n";
+
+static CONFIG: OnceLock<Option<VerificationConfig>> = OnceLock::new();
+
+// With EMAIL_VERIFICATION=true, each new user is sent the link of
+// VERIFICATION_URL, with their token in place of {token}, and POST /users/verify
+// marks them verified with that token, once. A token is good for
+// VERIFICATION_TOKEN_LIFETIME_SECS, and only until another one is sent. The link
+// goes out through the sender of MAIL_SENDER, see mail::sender_from_env.
+// Postgres only.
+pub struct VerificationConfig {
+    pub lifetime: u64,
+    pub url: String,
+    pub sender: Box<dyn Sender>,
+}
+
+impl VerificationConfig {
+    // None without verification
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match config::var("EMAIL_VERIFICATION").as_deref() {
+            Ok("false") | Err(_) => return Ok(None),
+            Ok("true") => {}
+            Ok(value) => return Err(format!("EMAIL_VERIFICATION must be true or false, got {:?}", value)),
+        }
+        let lifetime = number_from_env("VERIFICATION_TOKEN_LIFETIME_SECS", DEFAULT_TOKEN_LIFETIME_SECS)?;
+        if lifetime == 0 {
+            return Err("VERIFICATION_TOKEN_LIFETIME_SECS must be at least 1".to_owned());
+        }
+        let url = config::var("VERIFICATION_URL").unwrap_or_else(|_| DEFAULT_URL.to_owned());
+        if !url.contains("{token}") {
+            return Err(format!("VERIFICATION_URL must have {{token}} in it, got {:?}", url));
+        }
+        Ok(Some(VerificationConfig { lifetime, url, sender: mail::sender_from_env()? }))
+    }
+}
+
+pub fn init(config: Option<VerificationConfig>) {
+    CONFIG.set(config).ok();
+}
+
+pub fn config() -> Option<&'static VerificationConfig> {
+    CONFIG.get_or_init(|| None).as_ref()
+}
+
+// Send the user of the tenant entered a new token, which the one they had before
+// is no good after. The link goes out on a thread of its own, so that a slow
+// relay doesn't hold up the request.
+pub fn issue(config: &'static VerificationConfig, user_id: i32, email: &str) -> Result<(), RepositoryError> {
+    let token = api_keys::generate();
+    let (token_hash, tenant, lifetime) = (api_keys::hash(&token), tenant::current(), config.lifetime as f64);
+    pool().get()?.with_transaction(TransactionOptions::default(), |transaction| {
+        transaction.execute(
+            tables::sql("DELETE FROM {verification_tokens} WHERE user_id = $1 AND used_at IS NULL"),
+            &[&user_id]
+        )?;
+        transaction.execute(
+            tables::sql(
+                "INSERT INTO {verification_tokens} (token_hash, user_id, tenant_id, expires_at)
+                VALUES ($1, $2, $3, now() + $4 * interval '1 second')"
+            ),
+            &[&token_hash, &user_id, &tenant, &lifetime]
+        )?;
+        Ok::<_, RepositoryError>(())
+    })?;
+
+    let text = format!("Open {} to verify your email", config.url.replace("{token}", &token));
+    let email = email.to_owned();
+    thread::spawn(move || {
+        if let Err(e) = config.sender.send(&email, "Verify your email", &text) {
+            log::error!("Error sending the verification of user {}: {}", user_id, e);
+        }
+    });
+    Ok(())
+}
+
+// What became of a token given to POST /users/verify
+#[derive(Debug, PartialEq)]
+enum Verified {
+    User(i32, DateTime<Utc>),
+    Expired,
+    Used,
+    Unknown,
+}
+
+fn verify(token: &str) -> Result<Verified, RepositoryError> {
+    let (token_hash, tenant) = (api_keys::hash(token), tenant::current());
+    pool().get()?.with_transaction(TransactionOptions::default(), |transaction| {
+        let row = transaction.query_opt(
+            tables::sql(
+                "SELECT user_id, expires_at <= now(), used_at IS NOT NULL FROM {verification_tokens}
+                WHERE token_hash = $1 AND tenant_id = $2 FOR UPDATE"
+            ),
+            &[&token_hash, &tenant]
+        )?;
+        let user_id: i32 = match row {
+            None => return Ok(Verified::Unknown),
+            Some(row) if row.get::<_, bool>(2) => return Ok(Verified::Used),
+            Some(row) if row.get::<_, bool>(1) => return Ok(Verified::Expired),
+            Some(row) => row.get(0),
+        };
+
+        transaction.execute(
+            tables::sql("UPDATE {verification_tokens} SET used_at = now() WHERE token_hash = $1"),
+            &[&token_hash]
+        )?;
+        let row = transaction.query_one(
+            tables::sql("UPDATE {users} SET verified_at = now() WHERE id = $1 RETURNING verified_at"),
+            &[&user_id]
+        )?;
+        let verified_at: DateTime<Utc> = row.get(0);
+        let payload = serde_json::json!({ "id": user_id, "verified_at": verified_at });
+        outbox::enqueue(transaction, &tenant, "user.verified", &payload)?;
+        Ok(Verified::User(user_id, verified_at))
+    })
+}
+
+pub fn problem(status_line: &str, status: u16, title: &str, code: &str, detail: &str) -> (String, String) {
+    let body = serde_json::json!({
+        "type": "about:blank",
+        "title": locale::title(status, title),
+        "status": status,
+        "detail": detail,
+        "code": code,
+    });
+    (status_line.to_owned(), body.to_string())
+}
+
+fn only_with_verification() -> Result<&'static VerificationConfig, (String, String)> {
+    config().ok_or_else(|| (NOT_IMPLEMENTED.to_owned(), "Only available with EMAIL_VERIFICATION=true".to_owned()))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Verification {
+    token: String,
+}
+
+// POST /users/verify with {"token": ...}, which marks the user of the token
+// verified and uses the token up
+pub fn handle_verify_request(request: &str) -> (String, String) {
+    if let Err(response) = only_with_verification() {
+        return response;
+    }
+    let verification = match serde_json::from_str::<Verification>(get_body(request)) {
+        Ok(verification) => verification,
+        Err(e) => {
+            return (BAD_REQUEST.to_owned(), validation::body_error(get_body(request), &e));
+        }
+    };
+
+    match verify(verification.token.trim()) {
+        Ok(Verified::User(id, verified_at)) => {
+            invalidate_cached(id);
+            (OK_RESPONSE.to_owned(), serde_json::json!({ "id": id, "verified_at": verified_at }).to_string())
+        }
+        Ok(Verified::Expired) => {
+            problem(GONE_PROBLEM, 410, "Gone", "token_expired", &locale::text("token_expired", &[]))
+        }
+        Ok(Verified::Used) => {
+            problem(BAD_REQUEST_PROBLEM, 400, "Bad Request", "token_used", &locale::text("token_used", &[]))
+        }
+        Ok(Verified::Unknown) => {
+            problem(BAD_REQUEST_PROBLEM, 400, "Bad Request", "invalid_token", "The token isn't a verification token")
+        }
+        Err(e) => repository_error_response(e, "Error verifying the email"),
+    }
+}
+
+// POST /users/{id}/resend-verification, a new token for a user not verified yet
+pub fn handle_resend_request(repository: &dyn UserRepository, id: i32) -> (String, String) {
+    let config = match only_with_verification() {
+        Ok(config) => config,
+        Err(response) => {
+            return response;
+        }
+    };
+    let user = match repository.find(id) {
+        Ok(user) => user,
+        Err(RepositoryError::NotFound) => {
+            return user_not_found(id);
+        }
+        Err(e) => {
+            return repository_error_response(e, "Error finding the user");
+        }
+    };
+    if user.verified_at.is_some() || user.anonymized {
+        let detail = format!("User with ID {} has nothing to verify", id);
+        return problem(CONFLICT_PROBLEM, 409, "Conflict", "already_verified", &detail);
+    }
+
+    match issue(config, id, &user.email) {
+        Ok(()) => (OK_RESPONSE.to_owned(), serde_json::json!({ "id": id, "sent": true }).to_string()),
+        Err(e) => repository_error_response(e, "Error sending the verification"),
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::net::TcpStream;
+use std::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering };
+use std::sync::{ Condvar, Mutex };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use crate::connections::{ self, Slot };
+use crate::config::{ self, number_from_env };
+
+// Workers per CPU by default. The handlers mostly wait on the database.
+const THREADS_PER_CPU: usize = 8;
+const DEFAULT_QUEUE_SIZE: u64 = 1024;
+const DEFAULT_QUEUE_WAIT: Duration = Duration::from_millis(100);
+const DEFAULT_READ_TIMEOUT_MS: u64 = 5000;
+
+// What the accept loop does with a connection when the queue is full, from
+// WORKER_QUEUE_FULL
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WhenFull {
+    // Wait up to WORKER_QUEUE_WAIT_MS for room, then shed
+    Wait(Duration),
+    // Answer 503 right away
+    Shed,
+}
+
+// WORKER_THREADS threads handle the requests, THREADS_PER_CPU per CPU by default.
+// The connections accepted meanwhile wait in a queue of WORKER_QUEUE_SIZE. A client
+// has REQUEST_READ_TIMEOUT_MS, 5000 by default, for each read of its request, so
+// that one sending nothing, or less than its Content-Length, only holds a worker
+// that long.
+#[derive(Clone, Debug)]
+pub struct WorkersConfig {
+    pub threads: usize,
+    pub queue_size: usize,
+    pub when_full: WhenFull,
+    pub read_timeout: Duration,
+}
+
+impl WorkersConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get());
+        let when_full = match config::var("WORKER_QUEUE_FULL").as_deref() {
+            Ok("wait") | Err(_) => {
+                let wait = number_from_env("WORKER_QUEUE_WAIT_MS", DEFAULT_QUEUE_WAIT.as_millis() as u64)?;
+                WhenFull::Wait(Duration::from_millis(wait))
+            }
+            Ok("shed") => WhenFull::Shed,
+            Ok(value) => return Err(format!("WORKER_QUEUE_FULL must be wait or shed, got {:?}", value)),
+        };
+        let config = WorkersConfig {
+            threads: number_from_env("WORKER_THREADS", (cpus * THREADS_PER_CPU) as u64)? as usize,
+            queue_size: number_from_env("WORKER_QUEUE_SIZE", DEFAULT_QUEUE_SIZE)? as usize,
+            when_full,
+            read_timeout: Duration::from_millis(number_from_env("REQUEST_READ_TIMEOUT_MS", DEFAULT_READ_TIMEOUT_MS)?),
+        };
+
+        if config.threads == 0 {
+            return Err("WORKER_THREADS must be at least 1".to_owned());
+        }
+        if config.queue_size == 0 {
+            return Err("WORKER_QUEUE_SIZE must be at least 1".to_owned());
+        }
+        if config.read_timeout.is_zero() {
+            return Err("REQUEST_READ_TIMEOUT_MS must be at least 1".to_owned());
+        }
+
+        Ok(config)
+    }
+}
+
+// The accepted connections waiting for a worker. The workers are run by the
+// server, this only hands the connections out and keeps count.
+pub struct Workers {
+    config: WorkersConfig,
+    queue: Mutex<VecDeque<(TcpStream, Slot<'static>)>>,
+    queued: Condvar,
+    taken: Condvar,
+    busy: AtomicUsize,
+    // Set under the lock of the queue, not to miss a worker about to wait
+    stopped: AtomicBool,
+    // Since startup
+    pub shed: AtomicU64,
+}
+
+impl Workers {
+    pub fn new(config: WorkersConfig) -> Self {
+        Workers {
+            queue: Mutex::new(VecDeque::with_capacity(config.queue_size)),
+            config,
+            queued: Condvar::new(),
+            taken: Condvar::new(),
+            busy: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn config(&self) -> &WorkersConfig {
+        &self.config
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::Relaxed)
+    }
+
+    // Once the server stopped accepting: the idle workers return from work, the
+    // others once they are done with the connections queued
+    pub fn stop(&self) {
+        let _queue = self.queue.lock().unwrap();
+        self.stopped.store(true, Ordering::Relaxed);
+        self.queued.notify_all();
+    }
+
+    // Queue a connection for the next free worker, or turn it away when the
+    // queue stays full
+    pub fn submit(&self, stream: TcpStream, slot: Slot<'static>) {
+        let deadline = match self.config.when_full {
+            WhenFull::Wait(wait) => Instant::now() + wait,
+            WhenFull::Shed => Instant::now(),
+        };
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if queue.len() < self.config.queue_size {
+                queue.push_back((stream, slot));
+                drop(queue);
+                self.queued.notify_one();
+                return;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            queue = self.taken.wait_timeout(queue, deadline - now).unwrap().0;
+        }
+        drop(queue);
+
+        self.shed.fetch_add(1, Ordering::Relaxed);
+        connections::refuse(stream, "Too many requests are waiting for a worker");
+    }
+
+    // What a worker runs: the queued connections one after the other, until the
+    // workers are stopped and the queue is empty. handle must not panic, the
+    // worker would be gone.
+    pub fn work(&self, handle: impl Fn(TcpStream, Slot<'static>)) {
+        loop {
+            let mut queue = self.queue.lock().unwrap();
+            let (stream, slot) = loop {
+                match queue.pop_front() {
+                    Some(accepted) => break accepted,
+                    None if self.stopped.load(Ordering::Relaxed) => return,
+                    None => queue = self.queued.wait(queue).unwrap(),
+                }
+            };
+            drop(queue);
+            self.taken.notify_one();
+
+            self.busy.fetch_add(1, Ordering::Relaxed);
+            handle(stream, slot);
+            self.busy.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use postgres::fallible_iterator::FallibleIterator;
+use sha1::{ Digest, Sha1 };
+use std::error::Error;
+use std::io::{ self, Read, Write };
+use std::net::{ Shutdown, TcpStream };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::Duration;
+
+use crate::outbox::{ self, EventNotice };
+use crate::db::connector;
+use crate::errors::ApiError;
+use crate::http::{ get_header, write_response };
+use crate::{ access_log, cache, json_case, request_id, sse, trace_context };
+
+// Fixed GUID from RFC 6455 used to compute Sec-WebSocket-Accept
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+// Clients only send control frames and the odd text message, nothing big
+const MAX_FRAME_PAYLOAD: u64 = 64 * 1024;
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// A connected client. Writes are serialized through the mutex because both the
+// broadcaster and the client's reader thread (pongs, close replies) write to it.
+struct WsClient {
+    id: u64,
+    // Only the changes of its tenant are sent to it
+    tenant: String,
+    stream: Mutex<TcpStream>,
+}
+
+static CLIENTS: Mutex<Vec<Arc<WsClient>>> = Mutex::new(Vec::new());
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+struct Frame {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Complete the upgrade handshake, register the client and start its reader thread
+pub fn handle_upgrade(mut stream: TcpStream, request: &str, tenant: String) {
+    let key = match validate_handshake(request) {
+        Ok(key) => key,
+        Err(reason) => {
+            let (status_line, content) = ApiError::Handshake(reason).response();
+            write_response(&mut stream, &status_line, content).ok();
+            return;
+        }
+    };
+
+    let response = trace_context::added(&request_id::added(&format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )));
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        log::error!("Error: {}", e);
+        return;
+    }
+    access_log::responded(&response, response.len());
+
+    let reader = match stream.try_clone() {
+        Ok(reader) => reader,
+        Err(e) => {
+            log::error!("Error: {}", e);
+            return;
+        }
+    };
+
+    let client = Arc::new(WsClient {
+        id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+        tenant,
+        stream: Mutex::new(stream),
+    });
+    CLIENTS.lock().unwrap().push(Arc::clone(&client));
+
+    thread::spawn(move || read_frames(client, reader));
+}
+
+fn validate_handshake(request: &str) -> Result<&str, &'static str> {
+    let upgrade = get_header(request, "Upgrade").unwrap_or_default();
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return Err("Expected Upgrade: websocket");
+    }
+
+    let connection = get_header(request, "Connection").unwrap_or_default();
+    if !connection.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")) {
+        return Err("Expected Connection: Upgrade");
+    }
+
+    if get_header(request, "Sec-WebSocket-Version") != Some("13") {
+        return Err("Unsupported Sec-WebSocket-Version, expected 13");
+    }
+
+    // The key must be 16 random bytes, base64 encoded
+    let key = get_header(request, "Sec-WebSocket-Key").unwrap_or_default();
+    match BASE64.decode(key) {
+        Ok(bytes) if bytes.len() == 16 => Ok(key),
+        _ => Err("Invalid Sec-WebSocket-Key"),
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+// Handle the frames sent by a client until it closes or misbehaves
+fn read_frames(client: Arc<WsClient>, mut reader: TcpStream) {
+    loop {
+        let frame = match read_frame(&mut reader) {
+            Ok(frame) => frame,
+            Err(FrameError::TooBig) => {
+                send_close(&client, CLOSE_MESSAGE_TOO_BIG);
+                break;
+            }
+            Err(FrameError::Unmasked) => {
+                send_close(&client, CLOSE_PROTOCOL_ERROR);
+                break;
+            }
+            Err(FrameError::Disconnected) => break,
+        };
+
+        match frame.opcode {
+            OPCODE_PING => {
+                if send_frame(&client, OPCODE_PONG, &frame.payload).is_err() {
+                    break;
+                }
+            }
+            OPCODE_PONG | OPCODE_TEXT => {}
+            OPCODE_CLOSE => {
+                send_close(&client, CLOSE_NORMAL);
+                break;
+            }
+            // Binary, fragmented and unknown frames are not something this endpoint speaks
+            _ => {
+                send_close(&client, CLOSE_UNSUPPORTED_DATA);
+                break;
+            }
+        }
+    }
+
+    unregister(&client);
+}
+
+enum FrameError {
+    Disconnected,
+    TooBig,
+    Unmasked,
+}
+
+impl From<io::Error> for FrameError {
+    fn from(_: io::Error) -> Self {
+        FrameError::Disconnected
+    }
+}
+
+fn read_frame(reader: &mut TcpStream) -> Result<Frame, FrameError> {
+    let mut header = [0; 2];
+    reader.read_exact(&mut header)?;
+
+    let opcode = header[0] & 0x0f;
+    let masked = header[1] & 0x80 != 0;
+    let length = match header[1] & 0x7f {
+        126 => {
+            let mut extended = [0; 2];
+            reader.read_exact(&mut extended)?;
+            u16::from_be_bytes(extended) as u64
+        }
+        127 => {
+            let mut extended = [0; 8];
+            reader.read_exact(&mut extended)?;
+            u64::from_be_bytes(extended)
+        }
+        length => length as u64,
+    };
+
+    // Frames from clients must always be masked
+    if !masked {
+        return Err(FrameError::Unmasked);
+    }
+    if length > MAX_FRAME_PAYLOAD {
+        return Err(FrameError::TooBig);
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask)?;
+
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Frame { opcode, payload })
+}
+
+// Server frames are sent unfragmented and unmasked
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn send_frame(client: &WsClient, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    client.stream.lock().unwrap().write_all(&encode_frame(opcode, payload))
+}
+
+fn send_close(client: &WsClient, status: u16) {
+    send_frame(client, OPCODE_CLOSE, &status.to_be_bytes()).unwrap_or_default();
+}
+
+fn unregister(client: &WsClient) {
+    CLIENTS.lock().unwrap().retain(|registered| registered.id != client.id);
+    client.stream.lock().unwrap().shutdown(Shutdown::Both).unwrap_or_default();
+}
+
+// Send a text message to every connected client of the tenant, dropping the ones
+// that are gone
+fn broadcast(tenant: &str, message: &str) {
+    let clients = CLIENTS.lock().unwrap().clone();
+
+    for client in clients.iter().filter(|client| client.tenant == tenant) {
+        if send_frame(client, OPCODE_TEXT, message.as_bytes()).is_err() {
+            unregister(client);
+        }
+    }
+}
+
+// Background loop forwarding every user change to the WebSocket clients, to the
+// event streams and to the cache kept in memory. Changes come from LISTEN/NOTIFY, so mutations served by
+// other instances are included.
+pub fn run_broadcaster() {
+    loop {
+        if let Err(e) = listen_and_broadcast() {
+            log::error!("WebSocket broadcaster error: {}", e);
+        }
+        sse::set_listening(false);
+        thread::sleep(RECONNECT_INTERVAL);
+    }
+}
+
+fn listen_and_broadcast() -> Result<(), Box<dyn Error>> {
+    let mut client = connector().connect()?;
+    outbox::listen(&mut client)?;
+    cache::resubscribed();
+    sse::set_listening(true);
+
+    let mut notifications = client.notifications();
+    let mut iter = notifications.blocking_iter();
+
+    while let Some(notification) = iter.next()? {
+        match serde_json::from_str::<EventNotice>(notification.payload()) {
+            Ok(notice) => {
+                cache::changed(&notice.tenant_id, &notice.payload);
+                let message = serde_json::json!({
+                    "id": notice.id,
+                    "event": notice.event_type,
+                    "data": notice.payload,
+                });
+                broadcast(&notice.tenant_id, &json_case::outbound_value(&message).to_string());
+                sse::publish(notice);
+            }
+            Err(e) => log::warn!("Ignoring malformed notification: {}", e),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,135 @@
+// A line of the access log for every request once answered, whatever it was
+// answered with, and for the connections that never got an answer. RUST_LOG sets
+// its level, as the target access.
+
+mod common;
+
+use common::Server;
+use std::collections::HashMap;
+use std::env;
+use std::io::{ Read, Write };
+use std::net::TcpStream;
+use std::sync::mpsc::Receiver;
+use std::time::{ Duration, Instant };
+
+// The fields of the next line of the access log for the path, by name
+fn logged(lines: &Receiver<String>, path: &str) -> HashMap<String, String> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let line = lines.recv_timeout(left).unwrap_or_else(|_| panic!("{} wasn't logged", path));
+        let Some((_, message)) = line.split_once(" INFO access: ") else {
+            continue;
+        };
+        let fields = fields(&message[message.find(" method=").unwrap()..]);
+        if fields["path"] == path {
+            return fields;
+        }
+    }
+}
+
+// name=value and name="value" separated by spaces
+fn fields(message: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let mut rest = message;
+    while let Some((name, value)) = rest.split_once('=') {
+        let (value, after) = match value.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap(),
+            None => value.split_once(' ').unwrap_or((value, "")),
+        };
+        fields.insert(name.trim().to_owned(), value.to_owned());
+        rest = after;
+    }
+    fields
+}
+
+#[test]
+fn every_request_is_logged_once_answered() {
+    let (server, lines) = Server::start_capturing("memory://", &[]);
+
+    let user_agent = "User-Agent: curl/8.5.0\r\n";
+    let (status, body) = server.request_with_headers("GET", "/users?name_contains=ada", user_agent, None);
+    assert_eq!(status, 200);
+    let fields = logged(&lines, "/users?name_contains=ada");
+    assert_eq!(fields["method"], "GET");
+    assert_eq!(fields["route"], "GET /users");
+    assert_eq!(fields["status"], "200");
+    assert!(fields["bytes"].parse::<usize>().unwrap() > body.len(), "{:?}", fields);
+    assert!(fields["duration_ms"].parse::<f64>().unwrap() >= 0.0, "{:?}", fields);
+    assert_eq!(fields["client_ip"], "127.0.0.1");
+    assert_eq!(fields["user_agent"], "curl/8.5.0");
+
+    assert_eq!(server.request("GET", "/users/42", None).0, 404);
+    let fields = logged(&lines, "/users/42");
+    assert_eq!((fields["route"].as_str(), fields["status"].as_str()), ("GET /users/{id}", "404"));
+    assert_eq!(fields["user_agent"], "-");
+
+    // Without a method nor a target that make sense
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+    stream.write_all(b"NONSENSE\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let fields = logged(&lines, "-");
+    assert_eq!(fields["method"], "NONSENSE");
+    assert_eq!(fields["status"], response.split_whitespace().nth(1).unwrap());
+
+    // Closed before sending anything
+    drop(TcpStream::connect(("127.0.0.1", server.port())).unwrap());
+    let fields = logged(&lines, "-");
+    assert_eq!((fields["method"].as_str(), fields["status"].as_str()), ("-", "dropped"));
+}
+
+#[test]
+fn the_repository_operations_of_each_request_are_counted() {
+    let (server, lines) = Server::start_capturing("memory://", &[]);
+    assert_eq!(server.request("GET", "/users/42", None).0, 404);
+    let fields = logged(&lines, "/users/42");
+    assert_eq!(fields["db_calls"], "1", "{:?}", fields);
+    assert!(fields["db_ms"].parse::<f64>().unwrap() >= 0.0, "{:?}", fields);
+    // No pool to wait for
+    assert_eq!(fields["pool_wait_ms"], "0.0", "{:?}", fields);
+
+    // Nothing left over from the request before
+    assert_eq!(server.request("GET", "/livez", None).0, 200);
+    let fields = logged(&lines, "/livez");
+    assert_eq!((fields["db_calls"].as_str(), fields["db_ms"].as_str()), ("0", "0.0"), "{:?}", fields);
+}
+
+#[test]
+fn a_batch_insert_is_one_operation() {
+    let Some(database_url) = common::database_url("the batch insert test") else {
+        return;
+    };
+    let (server, lines) = Server::start_capturing(&database_url, &[("APP_ENV", "test")]);
+    let (status, body) = server.request("POST", "/admin/seed", Some(r#"{"count": 3}"#));
+    assert_eq!(status, 200, "{}", body);
+    let fields = logged(&lines, "/admin/seed");
+    assert_eq!(fields["db_calls"], "1", "{:?}", fields);
+    assert!(fields["db_ms"].parse::<f64>().unwrap() > 0.0, "{:?}", fields);
+    assert!(fields["pool_wait_ms"].parse::<f64>().unwrap() >= 0.0, "{:?}", fields);
+
+    assert_eq!(server.request("GET", "/users/2147483647", None).0, 404);
+    let fields = logged(&lines, "/users/2147483647");
+    assert_eq!(fields["db_calls"], "1", "{:?}", fields);
+}
+
+#[test]
+fn rust_log_turns_the_access_log_off() {
+    let (server, lines) = Server::start_capturing("memory://", &[("RUST_LOG", "info,access=off")]);
+    assert_eq!(server.request("GET", "/users", None).0, 200);
+    assert_eq!(server.request("GET", "/users/42", None).0, 404);
+    drop(server);
+    assert!(!lines.iter().any(|line| line.contains(" access: ")));
+}
+
+#[test]
+fn an_invalid_rust_log_keeps_the_server_from_starting() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .env("DATABASE_URL", "memory://")
+        .env("RUST_LOG", "access=loud")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Invalid log config: RUST_LOG"), "{}", stderr);
+}
@@ -0,0 +1,73 @@
+// /metrics has a counter without labels for each of what is alerted on: the
+// requests, the 4xx, the 5xx, those the database failed, the auth failures and
+// the panics, each request counted once.
+
+mod common;
+
+use common::{ unique_email, Server };
+
+const COUNTERS: [&str; 6] = [
+    "requests_total",
+    "requests_4xx_total",
+    "requests_5xx_total",
+    "requests_db_errors_total",
+    "requests_auth_failures_total",
+    "requests_panicked_total",
+];
+
+fn counts(server: &Server) -> [u64; 6] {
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    COUNTERS.map(|name| {
+        let prefix = format!("{} ", name);
+        let value = metrics.lines().find_map(|line| line.strip_prefix(&prefix));
+        value.unwrap_or_else(|| panic!("no {} in {}", name, metrics)).parse().unwrap()
+    })
+}
+
+// How much each counter went up while answering send, the request to /metrics
+// before it aside
+fn deltas(server: &Server, send: impl FnOnce()) -> Vec<(&'static str, u64)> {
+    let before = counts(server);
+    send();
+    let after = counts(server);
+    let mut deltas: Vec<(&str, u64)> =
+        COUNTERS.iter().zip(before.iter().zip(after)).map(|(name, (before, after))| (*name, after - before)).collect();
+    // The request to /metrics is only counted once answered
+    deltas[0].1 -= 1;
+    deltas.into_iter().filter(|(_, delta)| *delta > 0).collect()
+}
+
+#[test]
+fn each_request_is_counted_once_by_what_it_was_answered() {
+    let server = Server::start_with("memory://", &[("APP_ENV", "test")]);
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}"}}"#, unique_email("ada"));
+
+    let created = deltas(&server, || assert_eq!(server.request("POST", "/users", Some(&user)).0, 200));
+    assert_eq!(created, [("requests_total", 1)]);
+    let invalid = deltas(&server, || assert_eq!(server.request("POST", "/users", Some("{")).0, 400));
+    assert_eq!(invalid, [("requests_total", 1), ("requests_4xx_total", 1)]);
+    let missing = deltas(&server, || assert_eq!(server.request("GET", "/nope", None).0, 404));
+    assert_eq!(missing, [("requests_total", 1), ("requests_4xx_total", 1)]);
+    let taken = deltas(&server, || assert_eq!(server.request("POST", "/users", Some(&user)).0, 409));
+    assert_eq!(taken, [("requests_total", 1), ("requests_4xx_total", 1)]);
+    let db_error = deltas(&server, || assert_eq!(server.request("GET", "/admin/fail?with=db", None).0, 500));
+    assert_eq!(db_error, [("requests_total", 1), ("requests_5xx_total", 1), ("requests_db_errors_total", 1)]);
+    let failed = deltas(&server, || assert_eq!(server.request("GET", "/admin/fail", None).0, 500));
+    assert_eq!(failed, [("requests_total", 1), ("requests_5xx_total", 1)]);
+    // Answered once it has unwound
+    let panicked = deltas(&server, || assert_eq!(server.request("GET", "/admin/fail?with=panic", None).0, 500));
+    let expected = [("requests_total", 1), ("requests_5xx_total", 1), ("requests_panicked_total", 1)];
+    assert_eq!(panicked, expected);
+}
+
+#[test]
+fn requests_without_a_valid_key_are_auth_failures() {
+    let server = Server::start_with("memory://", &[("API_KEYS", "deploy:s3cret")]);
+    let unauthorized = deltas(&server, || assert_eq!(server.request("GET", "/users", None).0, 401));
+    let expected = [("requests_total", 1), ("requests_4xx_total", 1), ("requests_auth_failures_total", 1)];
+    assert_eq!(unauthorized, expected);
+    let authorized = deltas(&server, || {
+        assert_eq!(server.request_with_headers("GET", "/users", "X-Api-Key: s3cret\r\n", None).0, 200);
+    });
+    assert_eq!(authorized, [("requests_total", 1)]);
+}
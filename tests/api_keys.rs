@@ -0,0 +1,122 @@
+// API_KEY_STORE=database: the keys minted with POST /admin/api-keys are accepted
+// until revoked, and only their hash is kept. Needs TEST_DATABASE_URL.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+
+const ROOT: &str = "X-Api-Key: r00t\r\n";
+
+#[test]
+fn minted_keys_work_until_revoked() {
+    let Some(database_url) = common::database_url("the API key test") else {
+        return;
+    };
+    let vars = [("API_KEY_STORE", "database"), ("API_KEYS", "root:r00t"), ("APP_ENV", "test")];
+    let server = Server::start_with(&database_url, &vars);
+
+    let new = r#"{"name": "billing", "role": "reader", "scopes": ["users:read"]}"#;
+    let (status, body) = server.request_with_headers("POST", "/admin/api-keys", ROOT, Some(new));
+    assert_eq!(status, 200, "{}", body);
+    let minted = json(&body);
+    let (id, key) = (minted["id"].clone(), minted["key"].as_str().unwrap().to_owned());
+    assert_eq!(minted["scopes"], serde_json::json!(["users:read"]));
+    assert_eq!(minted["role"], "reader");
+    assert_eq!(key.len(), 64);
+
+    let headers = format!("X-Api-Key: {}\r\n", key);
+    let (status, body) = server.request_with_headers("GET", "/users", &headers, None);
+    assert_eq!(status, 200, "{}", body);
+    let (status, body) = server.request_with_headers("DELETE", "/users/1", &headers, None);
+    assert_eq!(status, 403, "{}", body);
+    assert_eq!(json(&body)["required_role"], "writer");
+
+    let (status, body) = server.request_with_headers("GET", "/admin/api-keys", ROOT, None);
+    assert_eq!(status, 200, "{}", body);
+    assert!(!body.contains(&key), "{}", body);
+    let keys = json(&body);
+    let listed = keys.as_array().unwrap().iter().find(|listed| listed["id"] == id).unwrap();
+    assert_eq!(listed["name"], "billing");
+    assert_eq!(listed["role"], "reader");
+    assert!(listed.get("key").is_none() && listed.get("key_hash").is_none(), "{}", listed);
+    assert!(listed["last_used_at"].is_string(), "{}", listed);
+    assert!(listed["revoked_at"].is_null(), "{}", listed);
+
+    let (status, body) = server.request_with_headers("DELETE", &format!("/admin/api-keys/{}", id), ROOT, None);
+    assert_eq!(status, 200, "{}", body);
+    assert!(json(&body)["revoked_at"].is_string(), "{}", body);
+    let (status, body) = server.request_with_headers("GET", "/users", &headers, None);
+    assert_eq!(status, 401, "{}", body);
+    assert_eq!(json(&body)["detail"], "The API key was revoked");
+
+    let (status, _) = server.request_with_headers("DELETE", "/admin/api-keys/2147483647", ROOT, None);
+    assert_eq!(status, 404);
+    let (status, _) = server.request_with_headers("GET", "/users", "X-Api-Key: n0pe\r\n", None);
+    assert_eq!(status, 401);
+}
+
+// What each combination of scopes lets a key do, as an admin, on the users and an
+// admin route: a key without any has them all, as does one with the admin scope
+#[test]
+fn scopes_limit_what_a_key_may_do() {
+    let Some(database_url) = common::database_url("the API key scopes test") else {
+        return;
+    };
+    let vars = [("API_KEY_STORE", "database"), ("API_KEYS", "root:r00t"), ("APP_ENV", "test")];
+    let server = Server::start_with(&database_url, &vars);
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}"}}"#, unique_email("ada"));
+    let (status, body) = server.request_with_headers("POST", "/users", ROOT, Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    let target = format!("/users/{}", json(&body)["id"]);
+    let combinations: [&[&str]; 8] = [
+        &[],
+        &["users:read"],
+        &["users:write"],
+        &["users:delete"],
+        &["users:read", "users:write"],
+        &["users:write", "users:delete"],
+        &["users:read", "users:write", "users:delete"],
+        &["admin"],
+    ];
+    for scopes in combinations {
+        let new = serde_json::json!({ "name": format!("{:?}", scopes), "role": "admin", "scopes": scopes });
+        let (status, body) = server.request_with_headers("POST", "/admin/api-keys", ROOT, Some(&new.to_string()));
+        assert_eq!(status, 200, "{}", body);
+        let headers = format!("X-Api-Key: {}\r\n", json(&body)["key"].as_str().unwrap());
+
+        let new_user = format!(r#"{{"name": "Grace Hopper", "email": "{}"}}"#, unique_email("grace"));
+        let changed_user = format!(r#"{{"name": "Ada King", "email": "{}"}}"#, unique_email("ada"));
+        let routes = [
+            ("GET", "/users", None, "users:read"),
+            ("POST", "/users", Some(new_user.as_str()), "users:write"),
+            ("PUT", target.as_str(), Some(changed_user.as_str()), "users:write"),
+            ("DELETE", "/users/2147483647", None, "users:delete"),
+            ("GET", "/admin/api-keys", None, "admin"),
+        ];
+        for (method, target, body, scope) in routes {
+            let allowed = scopes.is_empty() || scopes.contains(&scope) || scopes.contains(&"admin");
+            let (status, response) = server.request_with_headers(method, target, &headers, body);
+            if allowed {
+                assert!(matches!(status, 200 | 404), "{:?} {} {}: {} {}", scopes, method, target, status, response);
+            } else {
+                assert_eq!(status, 403, "{:?} {} {}: {}", scopes, method, target, response);
+                let problem = json(&response);
+                assert_eq!(problem["code"], "missing_scope");
+                assert_eq!(problem["required_scope"], scope);
+            }
+        }
+    }
+
+    // With the role checked first
+    let new = r#"{"name": "analytics", "role": "reader", "scopes": ["users:read", "users:delete"]}"#;
+    let (_, body) = server.request_with_headers("POST", "/admin/api-keys", ROOT, Some(new));
+    let headers = format!("X-Api-Key: {}\r\n", json(&body)["key"].as_str().unwrap());
+    let (status, body) = server.request_with_headers("DELETE", &target, &headers, None);
+    assert_eq!(status, 403);
+    assert_eq!(json(&body)["code"], "missing_role");
+
+    let new = r#"{"name": "typo", "scopes": ["users:raed"]}"#;
+    let (status, body) = server.request_with_headers("POST", "/admin/api-keys", ROOT, Some(new));
+    assert_eq!(status, 400);
+    assert!(body.contains("users:raed"), "{}", body);
+}
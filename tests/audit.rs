@@ -0,0 +1,156 @@
+// AUTH_AUDIT=true: a line of the audit target, of event_type auth_audit, for
+// every outcome of authenticating and every denial, the successes sampled with
+// AUTH_AUDIT_SUCCESS_SAMPLE. The events stored with AUTH_AUDIT_STORE=postgres, and
+// GET /admin/auth-events, need TEST_DATABASE_URL.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+use jsonwebtoken::{ EncodingKey, Header };
+use serde_json::Value;
+use std::sync::mpsc::Receiver;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+const SECRET: &str = "an HS256 secret of at least 32 bytes";
+const ROOT: &str = "X-Api-Key: r00t\r\n";
+const READER: &str = "X-Api-Key: r3ad3r\r\n";
+
+fn start(database_url: &str, vars: &[(&str, &str)]) -> (Server, Receiver<String>) {
+    let defaults = [
+        ("AUTH_AUDIT", "true"),
+        ("LOG_FORMAT", "json"),
+        ("API_KEYS", "root:r00t,reader:r3ad3r:reader"),
+        ("JWT_SECRET", SECRET),
+        ("JWT_LEEWAY_SECS", "0"),
+        ("LOGIN_MAX_FAILURES", "2"),
+    ];
+    Server::start_capturing(database_url, &[&defaults, vars].concat())
+}
+
+// The audit events printed until there are count of them
+fn events(lines: &Receiver<String>, count: usize) -> Vec<Value> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut events = Vec::new();
+    while events.len() < count {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let Ok(line) = lines.recv_timeout(left) else {
+            panic!("only {} audit events of {}: {:?}", events.len(), count, events);
+        };
+        match serde_json::from_str::<Value>(&line) {
+            Ok(event) if event["target"] == "audit" && event["event_type"] == "auth_audit" => events.push(event),
+            _ => {}
+        }
+    }
+    events
+}
+
+fn expired_token() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let claims = serde_json::json!({
+        "sub": "1", "tenant": "default", "role": "reader", "iat": now - 120, "nbf": now - 120, "exp": now - 60,
+    });
+    jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+}
+
+// A success, a bad key, an expired token, a denial, and bad passwords up to a
+// lockout, then a login while locked, the email of the user returned
+fn drive_each_outcome(server: &Server) -> String {
+    let email = unique_email("ada");
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}", "password": "correct horse battery"}}"#, email);
+    assert_eq!(server.request_with_headers("POST", "/users", ROOT, Some(&user)).0, 200);
+    assert_eq!(server.request_with_headers("GET", "/users/1", "X-Api-Key: wrong\r\n", None).0, 401);
+    let bearer = format!("Authorization: Bearer {}\r\n", expired_token());
+    assert_eq!(server.request_with_headers("GET", "/users/1", &bearer, None).0, 401);
+    assert_eq!(server.request_with_headers("DELETE", "/users/1", READER, None).0, 403);
+
+    let login = format!(r#"{{"email": "{}", "password": "wrong horse battery"}}"#, email);
+    assert_eq!(server.request("POST", "/login", Some(&login)).0, 401);
+    assert_eq!(server.request("POST", "/login", Some(&login)).0, 401);
+    assert_eq!(server.request("POST", "/login", Some(&login)).0, 429);
+    email
+}
+
+#[test]
+fn each_outcome_is_one_event() {
+    let (server, lines) = start("memory://", &[]);
+    let email = drive_each_outcome(&server);
+
+    let masked = format!("a***@{}", email.split_once('@').unwrap().1);
+    let masked = Some(masked.as_str());
+    let expected = [
+        ("authentication", "authenticated", Some("root"), "POST /users"),
+        ("authentication", "invalid_api_key", None, "GET /users/{id}"),
+        ("authentication", "token_expired", None, "GET /users/{id}"),
+        ("authentication", "authenticated", Some("reader"), "DELETE /users/{id}"),
+        ("authorization", "missing_role", Some("reader"), "DELETE /users/{id}"),
+        ("authentication", "invalid_credentials", masked, "POST /login"),
+        ("authentication", "invalid_credentials", masked, "POST /login"),
+        ("lockout", "lockout_triggered", masked, "POST /login"),
+        ("authentication", "locked_out", masked, "POST /login"),
+    ];
+    let events = events(&lines, expected.len());
+    for (event, (kind, outcome, principal, route)) in events.iter().zip(expected) {
+        assert_eq!(event["kind"], kind, "{}", event);
+        assert_eq!(event["outcome"], outcome, "{}", event);
+        assert_eq!(event["principal"].as_str(), principal, "{}", event);
+        assert_eq!(event["route"], route, "{}", event);
+        assert_eq!(event["client_ip"], "127.0.0.1", "{}", event);
+        assert_eq!(event["tenant"], "default", "{}", event);
+        assert!(event["request_id"].is_string(), "{}", event);
+        assert!(event["timestamp"].as_str().is_some_and(|timestamp| timestamp.ends_with('Z')), "{}", event);
+    }
+    assert!(!events.iter().any(|event| event.to_string().contains(&email)));
+}
+
+#[test]
+fn successes_are_sampled_and_failures_never() {
+    let (server, lines) = start("memory://", &[("AUTH_AUDIT_SUCCESS_SAMPLE", "3")]);
+    for _ in 0..4 {
+        assert_eq!(server.request_with_headers("GET", "/users/1", READER, None).0, 404);
+        assert_eq!(server.request_with_headers("GET", "/users/1", "X-Api-Key: wrong\r\n", None).0, 401);
+    }
+    assert_eq!(server.request("GET", "/users/1", None).0, 401);
+
+    let outcomes: Vec<Value> = events(&lines, 7).into_iter().map(|event| event["outcome"].clone()).collect();
+    let (authenticated, refused) = ("authenticated", "invalid_api_key");
+    let expected = [authenticated, refused, refused, refused, authenticated, refused, "missing_credentials"];
+    assert_eq!(outcomes, expected);
+}
+
+#[test]
+fn stored_events_are_listed_since_a_time() {
+    let Some(database_url) = common::database_url("the stored auth audit test") else {
+        return;
+    };
+    let vars = [("AUTH_AUDIT_STORE", "postgres"), ("API_KEY_STORE", "database"), ("ADMIN_ENDPOINTS", "true")];
+    let (server, _lines) = start(&database_url, &vars);
+    let since = chrono::Utc::now().to_rfc3339();
+
+    // A revoked key, along with the others
+    let (status, body) = server.request_with_headers("POST", "/admin/api-keys", ROOT, Some(r#"{"name": "ci"}"#));
+    assert_eq!(status, 200, "{}", body);
+    let minted = json(&body);
+    let revoke = format!("/admin/api-keys/{}", minted["id"]);
+    assert_eq!(server.request_with_headers("DELETE", &revoke, ROOT, None).0, 200);
+    let revoked = format!("X-Api-Key: {}\r\n", minted["key"].as_str().unwrap());
+    assert_eq!(server.request_with_headers("GET", "/users/1", &revoked, None).0, 401);
+    drive_each_outcome(&server);
+
+    let target = format!("/admin/auth-events?since={}&limit=1000", since.replace('+', "%2B"));
+    let (status, body) = server.request_with_headers("GET", &target, ROOT, None);
+    assert_eq!(status, 200, "{}", body);
+    let outcomes: Vec<String> = json(&body)
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|event| event["client_ip"] == "127.0.0.1")
+        .map(|event| event["outcome"].as_str().unwrap().to_owned())
+        .collect();
+    for outcome in ["api_key_revoked", "invalid_api_key", "token_expired", "missing_role", "lockout_triggered"] {
+        assert!(outcomes.iter().any(|stored| stored == outcome), "{} isn't in {:?}", outcome, outcomes);
+    }
+    assert!(outcomes.iter().filter(|outcome| *outcome == "authenticated").count() >= 3, "{:?}", outcomes);
+
+    let (status, _) = server.request_with_headers("GET", "/admin/auth-events?since=yesterday", ROOT, None);
+    assert_eq!(status, 400);
+}
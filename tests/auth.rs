@@ -0,0 +1,99 @@
+// API_KEYS: every request but those to the exempt paths needs a key, in X-Api-Key
+// or Authorization: Bearer. The name of the key is the actor of the events the
+// request causes, which needs TEST_DATABASE_URL. BASIC_AUTH_USERS: or a user and
+// its password, in Authorization: Basic.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+use std::io::Read;
+
+const KEYS: (&str, &str) = ("API_KEYS", "deploy:s3cret, ci:an0ther");
+
+fn assert_unauthorized(server: &Server, headers: &str, detail: &str) {
+    let (status, body) = server.request_with_headers("GET", "/users", headers, None);
+    assert_eq!(status, 401, "{}", body);
+    assert_eq!(json(&body)["detail"], detail);
+}
+
+#[test]
+fn requests_need_one_of_the_keys() {
+    let server = Server::start_with("memory://", &[KEYS]);
+
+    assert_unauthorized(&server, "", "An API key is needed, in X-Api-Key or Authorization: Bearer");
+    assert_unauthorized(&server, "X-Api-Key: s3cre\r\n", "The API key isn't valid");
+    assert_unauthorized(&server, "Authorization: Bearer deploy:s3cret\r\n", "The API key isn't valid");
+    let mut response = String::new();
+    server.send("DELETE", "/users/1", "", None).read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 401 "), "{}", response);
+    assert!(response.contains("\r\nWWW-Authenticate: Bearer realm=\"rust-api\"\r\n"), "{}", response);
+    assert!(response.contains("\r\nContent-Type: application/problem+json\r\n"), "{}", response);
+
+    for headers in ["X-Api-Key: s3cret\r\n", "Authorization: Bearer an0ther\r\n"] {
+        let (status, body) = server.request_with_headers("GET", "/users", headers, None);
+        assert_eq!(status, 200, "{}", body);
+    }
+    // The probes and the metrics by default
+    for target in ["/health", "/livez", "/readyz", "/metrics"] {
+        assert_eq!(server.request("GET", target, None).0, 200, "{}", target);
+    }
+}
+
+#[test]
+fn exempt_paths_are_configured() {
+    let server = Server::start_with("memory://", &[KEYS, ("AUTH_EXEMPT", "/livez")]);
+    assert_eq!(server.request("GET", "/livez", None).0, 200);
+    assert_eq!(server.request("GET", "/health", None).0, 401);
+    // The path as it is routed
+    for target in ["/livez/", "//livez", "/livez?verbose=1"] {
+        assert_eq!(server.request("GET", target, None).0, 200, "{}", target);
+    }
+    assert_eq!(server.request("GET", "/livez/extra", None).0, 401);
+    assert_eq!(server.request_with_headers("GET", "/health", "X-Api-Key: s3cret\r\n", None).0, 200);
+}
+
+#[test]
+fn the_key_is_the_actor_of_the_events() {
+    let Some(database_url) = common::database_url("the actor test") else {
+        return;
+    };
+    let server = Server::start_with(&database_url, &[KEYS]);
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}"}}"#, unique_email("ada"));
+    let (status, body) = server.request_with_headers("POST", "/users", "X-Api-Key: an0ther\r\n", Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    let id = json(&body)["id"].clone();
+
+    let export = format!("/users/{}/export", id);
+    let (status, body) = server.request_with_headers("GET", &export, "X-Api-Key: s3cret\r\n", None);
+    assert_eq!(status, 200, "{}", body);
+    let history = json(&body)["history"].clone();
+    assert_eq!(history[0]["event_type"], "user.created");
+    assert_eq!(history[0]["actor"], "ci");
+}
+
+#[test]
+fn basic_auth_users_are_checked_against_their_hash() {
+    // admin:s3cret
+    let users = ("BASIC_AUTH_USERS", "admin:$2b$04$O.vIcdqRYXi5KqXHrK/0.uleDGk9vG3omjysBgyJMwYQCqzQP3oju");
+    let server = Server::start_with("memory://", &[users]);
+
+    assert_unauthorized(&server, "", "A user is needed, in Authorization: Basic");
+    let mut response = String::new();
+    server.send("GET", "/users", "", None).read_to_string(&mut response).unwrap();
+    assert!(response.contains("\r\nWWW-Authenticate: Basic realm=\"rust-api\"\r\n"), "{}", response);
+    assert!(!response.contains("Bearer"), "{}", response);
+
+    let malformed = "Authorization: Basic must hold user:password in base64";
+    assert_unauthorized(&server, "Authorization: Basic n0t-base64!\r\n", malformed);
+    // admins3cret
+    assert_unauthorized(&server, "Authorization: Basic YWRtaW5zM2NyZXQ=\r\n", malformed);
+    // admin:wrong
+    assert_unauthorized(&server, "Authorization: Basic YWRtaW46d3Jvbmc=\r\n", "The user or the password isn't valid");
+    // Keys aren't a way in without API_KEYS
+    assert_unauthorized(&server, "X-Api-Key: s3cret\r\n", "A user is needed, in Authorization: Basic");
+
+    let headers = "Authorization: Basic YWRtaW46czNjcmV0\r\n";
+    assert_eq!(server.request_with_headers("GET", "/users", headers, None).0, 200);
+    // The preflights of CORS have no credentials
+    assert_ne!(server.request("OPTIONS", "/users", None).0, 401);
+}
@@ -0,0 +1,97 @@
+// A seeded database taken through POST /admin/backup and the restore command,
+// each side in a schema of its own. Runs when TEST_DATABASE_URL points at a
+// database the tests may write to, whose role may create schemas.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+use postgres::{ Client, NoTls };
+use std::env;
+use std::path::PathBuf;
+use std::process::{ Command, Output };
+
+const SOURCE: &str = "backup-test-source";
+const TARGET: &str = "backup-test-target";
+
+fn run(database_url: &str, schema: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .args(args)
+        .env("DATABASE_URL", database_url)
+        .env("DATABASE_SCHEMA", schema)
+        .env("BACKUP_DIR", backup_dir())
+        .output()
+        .unwrap()
+}
+
+fn backup_dir() -> PathBuf {
+    env::temp_dir().join(format!("backup-test-{}", std::process::id()))
+}
+
+// Every row of the tables of the schema, as JSON
+fn contents(client: &mut Client, schema: &str) -> Vec<String> {
+    ["users ORDER BY id", "events_outbox ORDER BY id", "idempotency_keys ORDER BY key"]
+        .iter()
+        .map(|table| {
+            let query = format!("SELECT coalesce(json_agg(t)::text, '[]') FROM (SELECT * FROM \"{}\".{}) t", schema, table);
+            client.query_one(&query, &[]).unwrap().get(0)
+        })
+        .collect()
+}
+
+#[test]
+fn backups_restore_to_the_same_contents() {
+    let Some(database_url) = common::database_url("the backup test") else {
+        return;
+    };
+    let mut admin = Client::connect(&database_url, NoTls).unwrap();
+    admin.batch_execute(&format!("DROP SCHEMA IF EXISTS \"{}\" CASCADE", TARGET)).unwrap();
+    let backup_dir = backup_dir();
+
+    let source = Server::start_with(
+        &database_url,
+        &[("DATABASE_SCHEMA", SOURCE), ("ADMIN_ENDPOINTS", "true"), ("BACKUP_DIR", backup_dir.to_str().unwrap())]
+    );
+    assert_eq!(source.request("POST", "/admin/reset", None).0, 200);
+    let (status, body) = source.request("POST", "/admin/seed", Some(r#"{"count": 20, "seed": 7}"#));
+    assert_eq!(status, 200, "{}", body);
+    // Text that COPY and JSON both escape
+    let email = unique_email("escaped");
+    let key = format!("Idempotency-Key: {}\r\n", email);
+    let body = format!(r#"{{"name": "Back\\slash \"Quoted\" Ünïcode", "email": "{}"}}"#, email);
+    let (status, body) = source.request_with_headers("POST", "/users", &key, Some(&body));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(source.request("DELETE", "/users/3", None).0, 200);
+
+    let (status, body) = source.request("POST", "/admin/backup", None);
+    assert_eq!(status, 200, "{}", body);
+    let summary = json(&body);
+    assert_eq!(summary["rows"]["users"], 20);
+    assert_eq!(summary["rows"]["events_outbox"], 22);
+    assert_eq!(summary["rows"]["idempotency_keys"], 1);
+    let path = summary["path"].as_str().unwrap();
+
+    let restored = run(&database_url, TARGET, &["restore", path]);
+    assert!(restored.status.success(), "{}", String::from_utf8_lossy(&restored.stderr));
+    assert_eq!(contents(&mut admin, TARGET), contents(&mut admin, SOURCE));
+
+    // The rows are there now, replacing them takes --force
+    let refused = run(&database_url, TARGET, &["restore", path]);
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("--force"));
+    let forced = run(&database_url, TARGET, &["restore", path, "--force"]);
+    assert!(forced.status.success(), "{}", String::from_utf8_lossy(&forced.stderr));
+    assert_eq!(contents(&mut admin, TARGET), contents(&mut admin, SOURCE));
+
+    // The ids go on after the restored ones
+    let target = Server::start_with(&database_url, &[("DATABASE_SCHEMA", TARGET)]);
+    let body = format!(r#"{{"name": "After Restore", "email": "{}"}}"#, unique_email("after"));
+    let (status, body) = target.request("POST", "/users", Some(&body));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["id"], 22);
+
+    let backed_up = run(&database_url, TARGET, &["backup"]);
+    assert!(backed_up.status.success(), "{}", String::from_utf8_lossy(&backed_up.stderr));
+    let stdout = String::from_utf8_lossy(&backed_up.stdout);
+    assert!(stdout.contains("21 users, 23 events_outbox, 1 idempotency_keys"), "{}", stdout);
+    std::fs::remove_dir_all(&backup_dir).ok();
+}
@@ -0,0 +1,96 @@
+// RUST_LOG=body=debug logs the bodies of the requests changing something and of
+// the errors answered, redacted and cut short, for the routes of
+// DEBUG_BODY_ROUTES if it is set.
+
+mod common;
+
+use common::{ unique_email, Server };
+use serde_json::Value;
+use std::sync::mpsc::Receiver;
+use std::time::{ Duration, Instant };
+
+// The body events logged before the access event of the last request, to /livez
+fn body_events(server: &Server, lines: &Receiver<String>) -> Vec<Value> {
+    assert_eq!(server.request("GET", "/livez", None).0, 200);
+    let (deadline, mut events) = (Instant::now() + Duration::from_secs(10), Vec::new());
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let line = lines.recv_timeout(left).unwrap_or_else(|_| panic!("/livez wasn't logged: {:?}", events));
+        let Ok(event) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        if event["target"] == "access" && event["path"] == "/livez" {
+            return events;
+        }
+        if event["target"] == "body" {
+            events.push(event);
+        }
+    }
+}
+
+fn requests(server: &Server) {
+    let email = unique_email("ada");
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}", "password": "correct horse"}}"#, email);
+    assert_eq!(server.request("POST", "/users", Some(&user)).0, 200);
+    assert_eq!(server.request("POST", "/users", Some("{ not json")).0, 400);
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}"}}"#, unique_email("ada"));
+    assert_eq!(server.request("PUT", "/users/123456", Some(&user)).0, 404);
+    assert_eq!(server.request("GET", "/users/123456", None).0, 404);
+}
+
+#[test]
+fn nothing_is_logged_by_default() {
+    let (server, lines) = Server::start_capturing("memory://", &[("LOG_FORMAT", "json")]);
+    requests(&server);
+    assert_eq!(body_events(&server, &lines), Vec::<Value>::new());
+}
+
+#[test]
+fn bodies_are_logged_redacted_for_the_routes_asked_for() {
+    let vars = [("LOG_FORMAT", "json"), ("RUST_LOG", "info,body=debug"), ("DEBUG_BODY_ROUTES", "/users")];
+    let (server, lines) = Server::start_capturing("memory://", &vars);
+    requests(&server);
+    let headers = "X-Request-Id: body-1\r\nContent-Type: application/octet-stream\r\n";
+    assert_eq!(server.request_with_headers("POST", "/users", headers, Some("\u{1}\u{2}")).0, 400);
+    // Not under /users
+    assert_eq!(server.request("POST", "/nope", Some(r#"{"password": "guess"}"#)).0, 404);
+
+    let events = body_events(&server, &lines);
+    let logged: Vec<(&str, &str)> =
+        events.iter().map(|event| (event["kind"].as_str().unwrap(), event["route"].as_str().unwrap())).collect();
+    let expected = [
+        ("request", "POST /users"),
+        ("request", "POST /users"),
+        ("response", "POST /users"),
+        ("request", "PUT /users/{id}"),
+        ("response", "PUT /users/{id}"),
+        ("response", "GET /users/{id}"),
+        ("request", "POST /users"),
+        ("response", "POST /users"),
+    ];
+    assert_eq!(logged, expected, "{:?}", events);
+
+    let created = events[0]["body"].as_str().unwrap();
+    assert!(created.contains(r#""password":"[redacted]""#), "{}", created);
+    assert!(!created.contains("correct horse") && !created.contains("Lovelace"), "{}", created);
+    assert_eq!((events[0]["level"].as_str(), events[0]["truncated"].as_bool()), (Some("DEBUG"), Some(false)));
+    assert!(events[0]["request_id"].is_string(), "{}", events[0]);
+    let not_found: Value = serde_json::from_str(events[5]["body"].as_str().unwrap()).unwrap();
+    assert_eq!(not_found["detail"], "User with ID 123456 not found");
+
+    let binary = &events[6];
+    assert_eq!((binary["request_id"].as_str(), binary["bytes"].as_u64()), (Some("body-1"), Some(2)));
+    assert!(binary.get("body").is_none(), "{}", binary);
+}
+
+#[test]
+fn bodies_are_cut_to_the_cap() {
+    let vars = [("LOG_FORMAT", "json"), ("RUST_LOG", "info,body=debug"), ("DEBUG_BODY_MAX_BYTES", "16")];
+    let (server, lines) = Server::start_capturing("memory://", &vars);
+    assert_eq!(server.request("GET", "/users/123456", None).0, 404);
+
+    let events = body_events(&server, &lines);
+    assert_eq!(events.len(), 1, "{:?}", events);
+    assert_eq!(events[0]["body"], r#"{"code":"user_no"#);
+    assert_eq!((events[0]["truncated"].as_bool(), events[0]["bytes"].as_u64()), (Some(true), Some(120)));
+}
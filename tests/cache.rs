@@ -0,0 +1,117 @@
+// USER_CACHE_TTL_MS: GET /users/{id} answered from memory until the user is
+// written, by this instance or another sharing its Postgres database, the entry
+// expires or makes room for others.
+
+mod common;
+
+use common::{ json, Server, TestDatabase };
+use std::io::Read;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+// The status line and headers, and the body
+fn get(server: &Server, target: &str, headers: &str) -> (String, String) {
+    let mut response = String::new();
+    server.send("GET", target, headers, None).read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.to_owned(), body.to_owned())
+}
+
+fn create(server: &Server, name: &str) -> String {
+    let user = format!(r#"{{"name": "{}", "email": "{}@example.com"}}"#, name, name.to_lowercase().replace(' ', "."));
+    let (status, body) = server.request("POST", "/users", Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    format!("/users/{}", json(&body)["id"])
+}
+
+fn metric(server: &Server, name: &str) -> u64 {
+    let (_, body) = server.request("GET", "/metrics", None);
+    let line = body.lines().find(|line| line.starts_with(&format!("{} ", name))).unwrap();
+    line[name.len() + 1..].parse().unwrap()
+}
+
+#[test]
+fn users_are_cached_until_written() {
+    let server = Server::start_with("memory://", &[("USER_CACHE_TTL_MS", "60000")]);
+    let path = create(&server, "Ada Lovelace");
+
+    let (head, body) = get(&server, &path, "");
+    assert!(head.contains("\r\nX-Cache: MISS"), "{}", head);
+    let etag = head.lines().find_map(|line| line.strip_prefix("ETag: ")).unwrap().to_owned();
+    let (head, cached) = get(&server, &path, "");
+    assert!(head.contains("\r\nX-Cache: HIT"), "{}", head);
+    assert!(head.contains(&format!("\r\nETag: {}", etag)), "{}", head);
+    assert_eq!(cached, body);
+    // Unless the primary is asked for
+    let (head, _) = get(&server, &path, "X-Read-Primary: true\r\n");
+    assert!(!head.contains("X-Cache"), "{}", head);
+
+    let renamed = r#"{"name": "Ada King", "email": "ada.king@example.com"}"#;
+    assert_eq!(server.request("PUT", &path, Some(renamed)).0, 200);
+    let (head, body) = get(&server, &path, "");
+    assert!(head.contains("\r\nX-Cache: MISS"), "{}", head);
+    assert!(!head.contains(&etag), "{}", head);
+    assert_eq!(json(&body)["name"], "Ada King");
+
+    // Dry runs change nothing
+    let dry_run = format!("{}?dry_run=true", path);
+    assert_eq!(server.request("PUT", &dry_run, Some(r#"{"name": "Nobody", "email": "nobody@example.com"}"#)).0, 200);
+    assert!(get(&server, &path, "").0.contains("\r\nX-Cache: HIT"));
+
+    assert_eq!(server.request("DELETE", &path, None).0, 200);
+    assert_eq!(server.request("GET", &path, None).0, 404);
+    assert_eq!(metric(&server, "user_cache_hits_total"), 2);
+}
+
+#[test]
+fn entries_expire() {
+    let server = Server::start_with("memory://", &[("USER_CACHE_TTL_MS", "300")]);
+    let path = create(&server, "Ada Lovelace");
+
+    assert!(get(&server, &path, "").0.contains("\r\nX-Cache: MISS"));
+    assert!(get(&server, &path, "").0.contains("\r\nX-Cache: HIT"));
+    thread::sleep(Duration::from_millis(400));
+    assert!(get(&server, &path, "").0.contains("\r\nX-Cache: MISS"));
+}
+
+#[test]
+fn the_least_recently_used_make_room() {
+    let server = Server::start_with("memory://", &[("USER_CACHE_TTL_MS", "60000"), ("USER_CACHE_MAX_ENTRIES", "2")]);
+    let paths = [create(&server, "Ada Lovelace"), create(&server, "Alan Turing"), create(&server, "Grace Hopper")];
+
+    get(&server, &paths[0], "");
+    get(&server, &paths[1], "");
+    get(&server, &paths[0], "");
+    get(&server, &paths[2], "");
+    assert!(get(&server, &paths[0], "").0.contains("\r\nX-Cache: HIT"));
+    assert!(get(&server, &paths[1], "").0.contains("\r\nX-Cache: MISS"));
+    assert_eq!(metric(&server, "user_cache_entries"), 2);
+    assert_eq!(metric(&server, "user_cache_evictions_total"), 2);
+}
+
+#[test]
+fn the_writes_of_another_instance_drop_the_entries() {
+    let Some(database) = TestDatabase::create("cache") else {
+        return;
+    };
+    let vars = [("USER_CACHE_TTL_MS", "60000")];
+    let (writer, reader) = (database.start_server(&vars), database.start_server(&vars));
+    let path = create(&writer, "Ada Lovelace");
+
+    assert!(get(&reader, &path, "").0.contains("\r\nX-Cache: MISS"));
+    assert!(get(&reader, &path, "").0.contains("\r\nX-Cache: HIT"));
+    let renamed = r#"{"name": "Ada King", "email": "ada.king@example.com"}"#;
+    assert_eq!(writer.request("PUT", &path, Some(renamed)).0, 200);
+
+    // Once the notification reached it
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let (head, body) = get(&reader, &path, "");
+        if json(&body)["name"] == "Ada King" {
+            assert!(head.contains("\r\nX-Cache: MISS"), "{}", head);
+            break;
+        }
+        assert!(Instant::now() < deadline, "still cached: {}", body);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
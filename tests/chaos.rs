@@ -0,0 +1,130 @@
+// The chaos mode: faults injected in a share of the requests, set by CHAOS_* or
+// at runtime with POST /admin/chaos, here in all of them, each fault logged under
+// the chaos target and counted apart from the real ones.
+
+mod common;
+
+use common::{ json, Server };
+use serde_json::Value;
+use std::io::Read;
+use std::process::Command;
+use std::sync::mpsc::Receiver;
+use std::time::{ Duration, Instant };
+
+const ADA: &str = r#"{"name": "Ada Lovelace", "email": "ada@example.com"}"#;
+
+fn set(server: &Server, chaos: &str) {
+    let (status, body) = server.request("POST", "/admin/chaos", Some(chaos));
+    assert_eq!(status, 200, "{}", body);
+}
+
+fn injected(server: &Server, fault: &str) -> u64 {
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    let series = format!("chaos_faults_injected_total{{fault=\"{}\"}} ", fault);
+    let line = metrics.lines().find_map(|line| line.strip_prefix(series.as_str()));
+    line.unwrap_or_else(|| panic!("no {} in {}", series, metrics)).parse().unwrap()
+}
+
+// The next fault logged, the log being JSON
+fn chaos_event(lines: &Receiver<String>) -> Value {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let line = lines.recv_timeout(left).expect("no fault was logged");
+        match serde_json::from_str::<Value>(&line) {
+            Ok(event) if event["target"] == "chaos" && !event["fault"].is_null() => return event,
+            _ => continue,
+        }
+    }
+}
+
+#[test]
+fn the_requests_are_failed_before_their_handler() {
+    let vars = [("APP_ENV", "test"), ("LOG_FORMAT", "json"), ("CHAOS_ERROR_RATE", "1")];
+    let (server, lines) = Server::start_capturing("memory://", &vars);
+    let response = server.call("POST", "/users", "X-Request-Id: chaos-1\r\n", Some(ADA));
+    assert_eq!(response.status, 503, "{}", response.body);
+    assert_eq!(response.header("Retry-After"), Some("1"));
+    assert_eq!(response.header("X-Chaos-Fault"), Some("error"));
+    assert_eq!(json(&response.body)["code"], "chaos_fault");
+    let event = chaos_event(&lines);
+    assert_eq!((&event["level"], &event["fault"]), (&Value::from("WARN"), &Value::from("error")), "{}", event);
+    assert_eq!(event["request_id"], "chaos-1");
+
+    // Not the probes, nor the switch
+    for target in ["/health", "/livez", "/readyz", "/metrics", "/admin/chaos"] {
+        assert_eq!(server.request("GET", target, None).0, 200, "{}", target);
+    }
+    set(&server, r#"{"error_rate": 1, "error_status": 500}"#);
+    assert_eq!(server.request("GET", "/users", None).0, 500);
+    assert_eq!(injected(&server, "error"), 2);
+
+    set(&server, "{}");
+    assert_eq!(server.request("POST", "/users", Some(ADA)).0, 200);
+    let (_, body) = server.request("GET", "/admin/chaos", None);
+    assert_eq!(json(&body)["error_rate"], 0.0);
+    assert_eq!(injected(&server, "error"), 2);
+}
+
+#[test]
+fn the_requests_are_delayed() {
+    let server = Server::start_with("memory://", &[("APP_ENV", "test")]);
+    set(&server, r#"{"delay_rate": 1, "delay_ms": 300}"#);
+    let started = Instant::now();
+    let (status, body) = server.request("GET", "/users", None);
+    assert_eq!(status, 200, "{}", body);
+    assert!(started.elapsed() >= Duration::from_millis(300), "{:?}", started.elapsed());
+    assert_eq!(injected(&server, "delay"), 1);
+    assert_eq!(injected(&server, "error"), 0);
+}
+
+#[test]
+fn the_repository_fails_without_opening_the_circuit() {
+    let server = Server::start_with("memory://", &[("APP_ENV", "test"), ("CHAOS_DB_ERROR_RATE", "1")]);
+    for _ in 0..10 {
+        let response = server.call("GET", "/users/1", "", None);
+        assert_eq!(response.status, 503, "{}", response.body);
+        assert!(response.body.contains("injected by the chaos mode"), "{}", response.body);
+    }
+    assert!(injected(&server, "db_error") >= 10);
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    assert!(metrics.contains("circuit_breaker_state{state=\"closed\"} 1\n"), "{}", metrics);
+
+    set(&server, r#"{"db_error_rate": 0}"#);
+    assert_eq!(server.request("GET", "/users/1", None).0, 404);
+}
+
+#[test]
+fn the_responses_are_cut_off() {
+    let server = Server::start_with("memory://", &[("APP_ENV", "test")]);
+    let whole = |server: &Server| {
+        let mut response = Vec::new();
+        server.send("GET", "/users", "", None).read_to_end(&mut response).unwrap();
+        response
+    };
+    let complete = whole(&server);
+    set(&server, r#"{"disconnect_rate": 1}"#);
+    let cut_off = whole(&server);
+    assert!(cut_off.len() < complete.len() * 3 / 4, "{}", String::from_utf8_lossy(&cut_off));
+    assert!(complete.starts_with(&cut_off[..16]));
+    assert_eq!(injected(&server, "disconnect"), 1);
+}
+
+#[test]
+fn there_is_no_chaos_in_prod() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .env("DATABASE_URL", "memory://")
+        .env("APP_ENV", "production")
+        .env("REQUIRE_AUTH", "false")
+        .env("CHAOS_ERROR_RATE", "0.5")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let error = "Invalid chaos config: faults are only injected with the dev and test profiles, not prod";
+    assert!(stderr.contains(error), "{}", stderr);
+
+    let vars = [("APP_ENV", "production"), ("REQUIRE_AUTH", "false"), ("ADMIN_ENDPOINTS", "true")];
+    let server = Server::start_with("memory://", &vars);
+    assert_eq!(server.request("POST", "/admin/chaos", Some(r#"{"error_rate": 1}"#)).0, 404);
+}
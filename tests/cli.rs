@@ -0,0 +1,119 @@
+// The exit codes of the binary, for the scripts running it: 1 for arguments it
+// doesn't know, 2 for invalid settings, 3 for a database that fails it. The
+// checks of a real database run when TEST_DATABASE_URL points at one whose role
+// may create schemas.
+
+mod common;
+
+use postgres::{ Client, NoTls };
+use std::env;
+use std::process::{ Command, Output };
+
+fn run(args: &[&str], vars: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .args(args)
+        .env_remove("DATABASE_URL")
+        .envs(vars.iter().copied())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn version_needs_no_settings() {
+    let output = run(&["version"], &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "rust_postgresql_tutorial 0.1.0\n");
+}
+
+#[test]
+fn each_failure_has_its_exit_code() {
+    let unknown = run(&["frobnicate"], &[("DATABASE_URL", "memory://")]);
+    assert_eq!(unknown.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&unknown.stderr).contains("unknown command \"frobnicate\""));
+
+    let unset = run(&["check"], &[]);
+    assert_eq!(unset.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&unset.stderr).contains("DATABASE_URL is not set"));
+
+    let vars = [("DATABASE_URL", "postgres://postgres@127.0.0.1:1/api"), ("DB_CONNECT_MAX_ATTEMPTS", "1")];
+    let unreachable = run(&["check"], &vars);
+    assert_eq!(unreachable.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&unreachable.stdout).contains("database: failed, "));
+    assert_eq!(run(&["migrate"], &vars).status.code(), Some(3));
+}
+
+#[test]
+fn the_log_level_applies_to_the_commands() {
+    let vars = [("DATABASE_URL", "postgres://postgres@127.0.0.1:1/api"), ("DB_CONNECT_MAX_ATTEMPTS", "1")];
+    let quiet = run(&["migrate", "--log-level", "off"], &vars);
+    assert_eq!(quiet.status.code(), Some(3));
+    assert_eq!(String::from_utf8_lossy(&quiet.stderr), "");
+}
+
+// The report of check --json, and the code it exited with
+fn checked(args: &[&str], vars: &[(&str, &str)]) -> (Option<i32>, serde_json::Value) {
+    let output = run(&[&["check", "--json"], args].concat(), vars);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    (output.status.code(), serde_json::from_str(&stdout).unwrap_or_else(|e| panic!("{}: {}", e, stdout)))
+}
+
+// The status of each step, by name
+fn steps(report: &serde_json::Value) -> Vec<(String, String)> {
+    let steps = report["checks"].as_array().unwrap().iter();
+    steps.map(|step| (step["name"].as_str().unwrap().to_owned(), step["status"].as_str().unwrap().to_owned())).collect()
+}
+
+fn step(name: &str, status: &str) -> (String, String) {
+    (name.to_owned(), status.to_owned())
+}
+
+#[test]
+fn check_reports_each_step_as_json() {
+    let (code, report) = checked(&[], &[]);
+    assert_eq!(code, Some(2));
+    assert_eq!(steps(&report), [step("config", "failed")]);
+    assert_eq!(report["checks"][0]["detail"], "DATABASE_URL is not set");
+
+    let vars = [("DATABASE_URL", "postgres://postgres@127.0.0.1:1/api"), ("DB_CONNECT_MAX_ATTEMPTS", "1")];
+    let (code, report) = checked(&["--timeout", "2"], &vars);
+    assert_eq!((code, &report["status"]), (Some(3), &serde_json::json!("failed")));
+    assert_eq!(steps(&report), [step("config", "ok"), step("database", "failed")]);
+    assert!(report["checks"][1]["detail"].as_str().unwrap().contains("onnection refused"), "{}", report);
+}
+
+#[test]
+fn check_tells_a_good_database_from_a_missing_table_and_a_read_only_one() {
+    let Some(database_url) = common::database_url("the check tests") else {
+        return;
+    };
+    const SCHEMA: &str = "cli-check-test";
+    let mut admin = Client::connect(&database_url, NoTls).unwrap();
+    admin.batch_execute(&format!("DROP SCHEMA IF EXISTS \"{}\" CASCADE", SCHEMA)).unwrap();
+    let vars = [("DATABASE_URL", database_url.as_str()), ("DATABASE_SCHEMA", SCHEMA)];
+    assert_eq!(run(&["migrate"], &vars).status.code(), Some(0));
+
+    let (code, report) = checked(&["--write-probe"], &vars);
+    assert_eq!((code, &report["status"]), (Some(0), &serde_json::json!("ok")), "{}", report);
+    let expected = ["config", "database", "tls", "migrations", "schema", "write"].map(|name| step(name, "ok"));
+    assert_eq!(steps(&report), expected);
+    // Rolled back
+    let query = format!("SELECT count(*) FROM \"{}\".users", SCHEMA);
+    assert_eq!(admin.query_one(&query, &[]).unwrap().get::<_, i64>(0), 0);
+
+    // Connected as a standby would take it, with the transactions read-only
+    let separator = if database_url.contains('?') { '&' } else { '?' };
+    let read_only = format!("{}{}options=-c%20default_transaction_read_only%3Don", database_url, separator);
+    let read_only_vars = [("DATABASE_URL", read_only.as_str()), ("DATABASE_SCHEMA", SCHEMA)];
+    let (code, report) = checked(&["--write-probe"], &read_only_vars);
+    assert_eq!(code, Some(3), "{}", report);
+    assert_eq!(steps(&report).last(), Some(&step("write", "failed")));
+    assert!(report["checks"][5]["detail"].as_str().unwrap().contains("read-only transaction"), "{}", report);
+
+    admin.batch_execute(&format!("DROP TABLE \"{}\".users CASCADE", SCHEMA)).unwrap();
+    let output = run(&["check"], &vars);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(3), "{}", stdout);
+    assert!(stdout.contains("\nmigrations: ok\nschema: failed, "), "{}", stdout);
+    assert!(stdout.contains("users"), "{}", stdout);
+    admin.batch_execute(&format!("DROP SCHEMA \"{}\" CASCADE", SCHEMA)).unwrap();
+}
@@ -0,0 +1,210 @@
+// The client and its commands against a server run in process through the
+// library, the users created, read, listed, updated and deleted over HTTP as those
+// commands do, with an API key or a token of POST /login.
+
+use rust_postgresql_tutorial::cli::{ EXIT_DEPENDENCY, EXIT_REFUSED };
+use rust_postgresql_tutorial::client::{
+    self, Auth, Client, ClientAction, ClientCommand, ClientError, ListParams, Problem
+};
+use rust_postgresql_tutorial::config::Config;
+use rust_postgresql_tutorial::repository::memory::MemoryRepository;
+use rust_postgresql_tutorial::run_server;
+use rust_postgresql_tutorial::validation::NewUser;
+use std::io::{ Read, Write };
+use std::net::{ SocketAddr, TcpStream };
+use std::process::Command;
+use std::sync::{ Arc, OnceLock };
+
+const KEY: &str = "an-ops-key-of-the-client-tests";
+
+// One per process, as the modules of the server keep their state in statics
+fn server() -> SocketAddr {
+    static ADDR: OnceLock<SocketAddr> = OnceLock::new();
+    *ADDR.get_or_init(|| {
+        let api_keys = format!("ops:{}", KEY);
+        let vars = [
+            ("DATABASE_URL", "memory://"),
+            ("BIND_ADDRESS", "127.0.0.1"),
+            ("PORT", "0"),
+            ("API_KEYS", &api_keys),
+            ("JWT_SECRET", "an HS256 secret of at least 32 bytes"),
+        ];
+        let config = Config::from_file_and_vars(None, vars.map(|(name, value)| (name.to_owned(), value.to_owned())));
+        let server = run_server(config.unwrap(), Arc::new(MemoryRepository::default())).unwrap();
+        // Serving until the tests exit
+        server.local_addr()
+    })
+}
+
+fn client() -> Client {
+    Client::new(&format!("http://{}", server()), Auth::ApiKey(KEY.to_owned())).unwrap()
+}
+
+fn new_user(name: &str, email: &str) -> NewUser {
+    NewUser { name: name.to_owned(), email: email.to_owned(), password: None, password_hash: None }
+}
+
+fn run(action: ClientAction, json: bool) -> (i32, String) {
+    let command = ClientCommand {
+        action,
+        base_url: Some(format!("http://{}/", server())),
+        api_key: Some(KEY.to_owned()),
+        json,
+    };
+    let mut out = Vec::new();
+    let code = client::run(&command, &mut out);
+    (code, String::from_utf8(out).unwrap())
+}
+
+#[test]
+fn the_users_make_the_round_trip() {
+    let client = client();
+    let created = client.create(&new_user("Ada", "ada@client.example.com")).unwrap();
+    let id = created.id.unwrap();
+    let fetched = client.get(id).unwrap();
+    assert_eq!((fetched.name.as_str(), fetched.email.as_str()), ("Ada", "ada@client.example.com"));
+    assert!(client.list().unwrap().iter().any(|user| user.id == Some(id)));
+
+    client.delete(id).unwrap();
+    match client.get(id) {
+        Err(ClientError::NotFound(problem)) => {
+            assert_eq!(problem.message, format!("User with ID {} not found", id));
+            let body = &problem.body;
+            assert_eq!((body["status"].as_u64(), body["code"].as_str()), (Some(404), Some("user_not_found")));
+        }
+        other => panic!("{:?}", other.map(|user| user.id)),
+    }
+}
+
+#[test]
+fn the_commands_print_a_table_or_json() {
+    let create = ClientAction::Create { name: "Grace".to_owned(), email: "grace@client.example.com".to_owned() };
+    let (code, out) = run(create, true);
+    assert_eq!(code, 0, "{}", out);
+    let user: serde_json::Value = serde_json::from_str(&out).unwrap();
+    let id = user["id"].as_i64().unwrap() as i32;
+    assert_eq!(user["email"], "grace@client.example.com");
+
+    let (code, out) = run(ClientAction::Get(id), false);
+    assert_eq!(code, 0);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", out);
+    assert!(lines[0].starts_with("ID") && lines[0].ends_with("EMAIL"), "{}", out);
+    assert!(lines[1].starts_with(&format!("{} ", id)) && lines[1].ends_with("Grace  grace@client.example.com"));
+
+    let (code, out) = run(ClientAction::List, true);
+    assert_eq!(code, 0);
+    assert!(serde_json::from_str::<Vec<serde_json::Value>>(&out).unwrap().iter().any(|user| user["id"] == id));
+    assert_eq!(run(ClientAction::Delete(id), false), (0, format!("Deleted user {}\n", id)));
+}
+
+#[test]
+fn what_the_api_refuses_is_a_message_and_an_exit_code() {
+    let client = client();
+    client.create(&new_user("Linus", "linus@client.example.com")).unwrap();
+    let error = client.create(&new_user("Linus", "linus@client.example.com")).unwrap_err();
+    assert_eq!(error.exit_code(), EXIT_REFUSED);
+    assert!(error.to_string().starts_with("The API answered 409: email: "), "{}", error);
+
+    let error = client.create(&new_user("", "not an email")).unwrap_err();
+    assert!(error.to_string().starts_with("The API answered 422: "), "{}", error);
+    let create = ClientAction::Create { name: "Ken".to_owned(), email: "not an email".to_owned() };
+    assert_eq!(run(create, false), (EXIT_REFUSED, String::new()));
+
+    let anonymous = Client::new(&format!("http://{}", server()), Auth::None).unwrap();
+    assert!(matches!(anonymous.list(), Err(ClientError::Refused(Problem { status: 401, .. }))));
+
+    // Nothing listens on port 1
+    let unreachable = Client::new("http://127.0.0.1:1", Auth::None).unwrap();
+    assert_eq!(unreachable.list().unwrap_err().exit_code(), EXIT_DEPENDENCY);
+    assert!(Client::new("ftp://api", Auth::None).is_err());
+    assert!(Client::new("http://api", Auth::Bearer("a\r\nX-Api-Key: other".to_owned())).is_err());
+}
+
+#[test]
+fn the_refusals_are_typed_with_what_the_api_answered() {
+    let client = client();
+    let taken = client.create(&new_user("Alan", "alan@client.example.com")).unwrap();
+    match client.create(&new_user("Alan", "alan@client.example.com")) {
+        Err(ClientError::Conflict(problem)) => assert_eq!((problem.status, problem.fields()), (409, vec!["email"])),
+        other => panic!("{:?}", other.map(|user| user.id)),
+    }
+    match client.update(taken.id.unwrap(), &new_user("", "alan@client.example.com")) {
+        Err(ClientError::Invalid(problem)) => {
+            assert_eq!(problem.fields(), vec!["name"], "{:?}", problem.body);
+            assert_eq!(problem.body["errors"][0]["code"], "required");
+        }
+        other => panic!("{:?}", other.map(|user| user.id)),
+    }
+    let updated = client.update(taken.id.unwrap(), &new_user("Alan Turing", "alan@client.example.com")).unwrap();
+    assert_eq!((updated.id, updated.name.as_str()), (taken.id, "Alan Turing"));
+}
+
+#[test]
+fn the_users_are_listed_a_page_at_a_time() {
+    let client = client();
+    for name in ["Page One", "Page Two", "Page Three"] {
+        let email = format!("{}@pages.example.com", name.replace(' ', ".").to_lowercase());
+        client.create(&new_user(name, &email)).unwrap();
+    }
+    let params = ListParams { limit: Some(2), name_contains: Some("Page ".to_owned()), ..ListParams::default() };
+    let pages: Vec<Vec<String>> = client
+        .pages(params.clone())
+        .map(|page| page.unwrap().into_iter().map(|user| user.name).collect())
+        .collect();
+    assert_eq!(pages, [vec!["Page One", "Page Two"], vec!["Page Three"]]);
+
+    let first = client.list_page(&params).unwrap();
+    let after = ListParams { after_id: first[0].id, ..params };
+    assert_eq!(client.list_page(&after).unwrap()[0].name, "Page Two");
+}
+
+// The body of what the server answered, for what the client doesn't send
+fn post(path: &str, headers: &str, body: &str) -> String {
+    let mut stream = TcpStream::connect(server()).unwrap();
+    let head = format!("POST {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}", path, headers);
+    // In one write: the server reads the request at once
+    let request = format!("{}Content-Length: {}\r\n\r\n{}", head, body.len(), body);
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.split_once("\r\n\r\n").unwrap().1.to_owned()
+}
+
+#[test]
+fn a_token_of_post_login_is_a_bearer() {
+    // The client never sends a password
+    let user = r#"{"name": "Edsger", "email": "edsger@client.example.com", "password": "correct horse battery"}"#;
+    let created = post("/users", &format!("X-Api-Key: {}\r\n", KEY), user);
+    let id = serde_json::from_str::<serde_json::Value>(&created).unwrap()["id"].as_i64().unwrap() as i32;
+    let login = r#"{"email": "edsger@client.example.com", "password": "correct horse battery"}"#;
+    let token: serde_json::Value = serde_json::from_str(&post("/login", "", login)).unwrap();
+
+    let token = Auth::Bearer(token["token"].as_str().unwrap().to_owned());
+    let bearer = Client::new(&format!("http://{}", server()), token).unwrap();
+    assert_eq!(bearer.get(id).unwrap().name, "Edsger");
+}
+
+#[test]
+fn the_binary_reads_the_base_url_and_the_key_from_the_environment() {
+    let id = client().create(&new_user("Barbara", "barbara@client.example.com")).unwrap().id.unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .args(["client", "users", "get", &id.to_string(), "--json"])
+        .env_remove("DATABASE_URL")
+        .env("CLIENT_BASE_URL", format!("http://{}", server()))
+        .env("CLIENT_API_KEY", KEY)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let user: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(user["name"], "Barbara");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .args(["client", "users", "delete", &id.to_string()])
+        .env("CLIENT_BASE_URL", format!("http://{}", server()))
+        .env_remove("CLIENT_API_KEY")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(EXIT_REFUSED));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("The API answered 401: "));
+}
@@ -0,0 +1,82 @@
+// The time of the app read from the clock of its config: with a test clock, which
+// stands still but when the test moves it on, the tokens expire and the rate
+// limits refill without the test waiting for them.
+
+use rust_postgresql_tutorial::clock::TestClock;
+use rust_postgresql_tutorial::config::Config;
+use rust_postgresql_tutorial::repository::memory::MemoryRepository;
+use rust_postgresql_tutorial::run_server;
+use std::io::{ Read, Write };
+use std::net::{ SocketAddr, TcpStream };
+use std::sync::Arc;
+use std::time::{ Duration, SystemTime };
+
+const ROOT: &str = "X-Api-Key: r00t\r\n";
+const ADA: &str = r#"{"name": "Ada Lovelace", "email": "ada@example.com", "password": "correct horse battery"}"#;
+
+// The status, the headers and the body
+fn request(addr: SocketAddr, method: &str, target: &str, headers: &str, body: &str) -> (u16, String, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let head = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}", method, target, headers);
+    // In one write: the server reads the request at once
+    let request = format!("{}Content-Length: {}\r\n\r\n{}", head, body.len(), body);
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    (status, head.to_owned(), body.to_owned())
+}
+
+#[test]
+fn tokens_expire_and_limits_refill_as_the_clock_moves_on() {
+    let vars = [
+        ("DATABASE_URL", "memory://"),
+        ("BIND_ADDRESS", "127.0.0.1"),
+        ("PORT", "0"),
+        ("API_KEYS", "root:r00t"),
+        ("JWT_SECRET", "an HS256 secret of at least 32 bytes"),
+        ("JWT_LIFETIME_SECS", "300"),
+        ("JWT_LEEWAY_SECS", "0"),
+        ("RATE_LIMIT_MUTATIONS_PER_MINUTE", "1"),
+        ("RATE_LIMIT_MUTATIONS_BURST", "2"),
+    ];
+    let mut config = Config::from_file_and_vars(None, vars.map(|(name, value)| (name.to_owned(), value.to_owned())))
+        .unwrap();
+    let clock = Arc::new(TestClock::new(SystemTime::now()));
+    config.clock = clock.clone();
+    let server = run_server(config, Arc::new(MemoryRepository::default())).unwrap();
+    let addr = server.local_addr();
+
+    // The two mutations of the burst, then none until a minute went by
+    let (status, _, body) = request(addr, "POST", "/users", ROOT, ADA);
+    assert_eq!(status, 200, "{}", body);
+    let login = r#"{"email": "ada@example.com", "password": "correct horse battery"}"#;
+    let (status, _, body) = request(addr, "POST", "/login", "", login);
+    assert_eq!(status, 200, "{}", body);
+    let token: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let bearer = format!("Authorization: Bearer {}\r\n", token["token"].as_str().unwrap());
+
+    let grace = r#"{"name": "Grace Hopper", "email": "grace@example.com"}"#;
+    let (status, head, _) = request(addr, "POST", "/users", ROOT, grace);
+    assert_eq!(status, 429);
+    assert!(head.contains("Retry-After: 60\r\n"), "{}", head);
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(request(addr, "POST", "/users", ROOT, grace).0, 429);
+    clock.advance(Duration::from_secs(31));
+    assert_eq!(request(addr, "POST", "/users", ROOT, grace).0, 200);
+
+    // Good until its lifetime is over, the leeway being 0
+    let (status, _, body) = request(addr, "GET", "/users/1", &bearer, "");
+    assert_eq!(status, 200, "{}", body);
+    clock.advance(Duration::from_secs(239));
+    assert_eq!(request(addr, "GET", "/users/1", &bearer, "").0, 200);
+    clock.advance(Duration::from_secs(1));
+    let (status, _, body) = request(addr, "GET", "/users/1", &bearer, "");
+    assert_eq!(status, 401, "{}", body);
+    assert!(body.contains("token_expired"), "{}", body);
+
+    server.shutdown();
+    server.join().unwrap();
+}
@@ -0,0 +1,58 @@
+// The settings are all read and checked before the server starts, and those that
+// are invalid all reported at once. A config file may set them too, each variable
+// of the environment winning over it.
+
+use std::process::Command;
+
+#[test]
+fn every_invalid_setting_is_logged_before_exiting() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .env("DATABASE_URL", "memory://")
+        .env("ROUTE_TIMEOUT_MS", "5s")
+        .env("WORKER_THREADS", "many")
+        .env("TENANCY", "schema")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for error in [
+        "Invalid route timeout config: ROUTE_TIMEOUT_MS must be a number, got \"5s\"",
+        "Invalid worker config: WORKER_THREADS must be a number, got \"many\"",
+        "TENANCY is only supported when DATABASE_URL is a Postgres database",
+    ] {
+        assert!(stderr.contains(error), "{}: {}", error, stderr);
+    }
+}
+
+#[test]
+fn an_invalid_log_config_is_reported_with_the_others() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .env_remove("DATABASE_URL")
+        .env("LOG_FORMAT", "xml")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr.lines().collect::<Vec<_>>(),
+        ["Invalid log config: LOG_FORMAT must be plain or json, got \"xml\"", "DATABASE_URL is not set"]
+    );
+}
+
+#[test]
+fn the_config_file_is_read_under_the_environment() {
+    let path = std::env::temp_dir().join(format!("config-file-test-{}.toml", std::process::id()));
+    std::fs::write(&path, "database_url = \"memory://\"\nlog_format = \"xml\"\npotr = 9000\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .args(["--config", path.to_str().unwrap(), "check"])
+        .env_remove("DATABASE_URL")
+        .env("LOG_FORMAT", "plain")
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Unknown settings in the config file"), "{}", stderr);
+    assert!(stderr.contains(".toml, left out: POTR"), "{}", stderr);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "config: ok\ndatabase: ok\n");
+}
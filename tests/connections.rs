@@ -0,0 +1,185 @@
+// The requests are handled by a fixed number of workers, the connections accepted
+// meanwhile wait in a queue: a client that connects and never sends its request
+// only holds up one worker, until REQUEST_READ_TIMEOUT_MS answers it 408, and a
+// full queue is turned away with 503s. Beyond MAX_CONNECTIONS the connections are
+// refused, or not accepted until there is room.
+
+mod common;
+
+use common::Server;
+use std::io::{ Read, Write };
+use std::net::TcpStream;
+use std::sync::{ mpsc, Arc, Barrier };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+#[test]
+fn an_idle_connection_doesnt_block_the_others() {
+    let server = Server::start("memory://");
+    let idle = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+
+    let (sent, received) = mpsc::channel();
+    thread::spawn(move || {
+        let response = server.request("GET", "/health", None);
+        sent.send(response).ok();
+        drop(server);
+    });
+    let (status, body) = received.recv_timeout(Duration::from_secs(5)).expect("the second connection wasn't served");
+    assert_eq!(status, 200, "{}", body);
+    drop(idle);
+}
+
+#[test]
+fn more_requests_than_workers_are_all_served() {
+    let server = Arc::new(Server::start_with("memory://", &[("WORKER_THREADS", "2")]));
+    let clients = 64;
+    let barrier = Arc::new(Barrier::new(clients));
+
+    let handles: Vec<_> = (0..clients)
+        .map(|client| {
+            let (server, barrier) = (server.clone(), barrier.clone());
+            thread::spawn(move || {
+                let user = format!(r#"{{"name": "User {}", "email": "user{}@example.com"}}"#, client, client);
+                barrier.wait();
+                server.request("POST", "/users", Some(&user))
+            })
+        })
+        .collect();
+    for handle in handles {
+        let (status, body) = handle.join().unwrap();
+        assert_eq!(status, 200, "{}", body);
+    }
+
+    let (_, body) = server.request("GET", "/users", None);
+    assert_eq!(common::json(&body).as_array().unwrap().len(), clients);
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    assert!(metrics.contains("\nworkers 2\n"), "{}", metrics);
+    assert!(metrics.contains("\nrequests_shed_total 0\n"), "{}", metrics);
+}
+
+// The response on a connection, None when there is none after a while
+fn response(mut stream: &TcpStream) -> Option<String> {
+    stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok().map(|_| response)
+}
+
+#[test]
+fn a_full_queue_is_shed_with_503() {
+    let vars = [("WORKER_THREADS", "1"), ("WORKER_QUEUE_SIZE", "1"), ("WORKER_QUEUE_FULL", "shed")];
+    let server = Server::start_with("memory://", &vars);
+
+    // Two idle connections left unanswered hold the worker and the queue. Those
+    // before may be shed while the connection Server::start waited with is queued.
+    let mut idle = Vec::new();
+    let mut shed = 0;
+    while idle.len() < 2 {
+        let stream = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        match response(&stream) {
+            Some(response) => {
+                assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+                shed += 1;
+            }
+            None => idle.push(stream),
+        }
+    }
+    let (status, body) = server.request("GET", "/health", None);
+    assert_eq!(status, 503, "{}", body);
+    assert_eq!(common::json(&body)["error"]["code"], "overloaded");
+    shed += 1;
+
+    // The worker is back to the queue once the idle clients leave, the queue may
+    // still be full for a moment
+    drop(idle);
+    let metrics = loop {
+        match server.request("GET", "/metrics", None) {
+            (200, metrics) => break metrics,
+            (status, body) => assert_eq!(status, 503, "{}", body),
+        }
+        shed += 1;
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(metrics.contains(&format!("\nrequests_shed_total {}\n", shed)), "{}", metrics);
+    assert!(metrics.contains("\nworker_queue_depth 0\n"), "{}", metrics);
+}
+
+#[test]
+fn as_many_idle_connections_as_workers_dont_block_the_others() {
+    let vars = [("WORKER_THREADS", "2"), ("REQUEST_READ_TIMEOUT_MS", "200")];
+    let server = Server::start_with("memory://", &vars);
+    // One sends nothing, the other less of its body than its Content-Length says
+    let mut idle = connect_idle(&server, 2);
+    idle[1].write_all(b"POST /users HTTP/1.1\r\nContent-Length: 100\r\n\r\n{\"name\"").unwrap();
+
+    let started = Instant::now();
+    let (status, body) = server.request("GET", "/health", None);
+    assert_eq!(status, 200, "{}", body);
+    assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+    for stream in &idle {
+        let response = response(stream).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 REQUEST TIMEOUT\r\n"), "{}", response);
+        assert!(response.contains("\"code\":\"request_timeout\""), "{}", response);
+    }
+}
+
+fn connect_idle(server: &Server, count: usize) -> Vec<TcpStream> {
+    (0..count).map(|_| TcpStream::connect(("127.0.0.1", server.port())).unwrap()).collect()
+}
+
+// Once the connection Server::start waited with is closed
+fn wait_for_no_other_connection(server: &Server) {
+    while !server.request("GET", "/metrics", None).1.contains("\nconnections_active 1\n") {
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn connections_over_the_limit_are_shed_but_livez_answers() {
+    let vars = [("MAX_CONNECTIONS", "5"), ("MAX_CONNECTIONS_HEADROOM", "2"), ("WORKER_THREADS", "8")];
+    let server = Server::start_with("memory://", &vars);
+    wait_for_no_other_connection(&server);
+
+    let idle = connect_idle(&server, 5);
+    thread::sleep(Duration::from_millis(100));
+    // Those in the headroom are refused once they had their chance to send a request
+    for extra in connect_idle(&server, 5) {
+        let response = response(&extra).or_else(|| response(&extra)).expect("no response over the limit");
+        assert!(response.starts_with("HTTP/1.1 503 SERVICE UNAVAILABLE\r\n"), "{}", response);
+        assert!(response.contains("\r\nRetry-After: 1\r\n"), "{}", response);
+    }
+    let (status, body) = server.request("GET", "/livez", None);
+    assert_eq!(status, 200, "{}", body);
+    let (status, body) = server.request("GET", "/health", None);
+    assert_eq!(status, 503, "{}", body);
+
+    drop(idle);
+    thread::sleep(Duration::from_millis(100));
+    let (status, metrics) = server.request("GET", "/metrics", None);
+    assert_eq!(status, 200, "{}", metrics);
+    assert!(metrics.contains("\nconnections_rejected_total 6\n"), "{}", metrics);
+    assert!(metrics.contains("\nconnections_active 1\n"), "{}", metrics);
+}
+
+#[test]
+fn connections_over_the_limit_wait_with_the_pause_policy() {
+    let vars = [("MAX_CONNECTIONS", "5"), ("MAX_CONNECTIONS_POLICY", "pause"), ("WORKER_THREADS", "8")];
+    let server = Server::start_with("memory://", &vars);
+    wait_for_no_other_connection(&server);
+
+    let idle = connect_idle(&server, 5);
+    let extra = connect_idle(&server, 5);
+    thread::sleep(Duration::from_millis(100));
+    // Not accepted, so neither answered nor refused
+    let request = server.send("GET", "/health", "", None);
+    assert!(response(&extra[0]).is_none());
+    assert!(response(&request).is_none());
+
+    // Accepted once there is room again
+    drop((idle, extra));
+    request.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut answer = String::new();
+    (&request).read_to_string(&mut answer).unwrap();
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"), "{}", answer);
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    assert!(metrics.contains("\nconnections_rejected_total 0\n"), "{}", metrics);
+}
@@ -0,0 +1,79 @@
+// Credentials read from files and rotated while the server runs. Needs
+// TEST_DATABASE_URL as a postgres:// URL of a role that may create roles, on a
+// server that checks the password of credentials_test; skipped otherwise.
+
+mod common;
+
+use common::Server;
+use postgres::{ Client, NoTls };
+use std::time::Duration;
+use std::{ env, fs, thread };
+
+const ROLE: &str = "credentials_test";
+
+// The URL with another user and password
+fn with_user(url: &str, user: &str) -> String {
+    let (scheme, rest) = url.split_once("://").expect("TEST_DATABASE_URL must be a postgres:// URL");
+    let host = rest.split_once('@').filter(|(userinfo, _)| !userinfo.contains('/')).map_or(rest, |(_, host)| host);
+    format!("{}://{}@{}", scheme, user, host)
+}
+
+#[test]
+fn rotated_passwords_are_picked_up_without_a_restart() {
+    let Some(database_url) = common::database_url("the credentials tests") else {
+        return;
+    };
+    let mut admin = Client::connect(&database_url, NoTls).unwrap();
+    admin
+        .batch_execute(
+            &format!(
+                "DO $$ BEGIN CREATE ROLE {0} LOGIN SUPERUSER; EXCEPTION WHEN duplicate_object THEN NULL; END $$;
+                ALTER ROLE {0} PASSWORD 'first-password'",
+                ROLE
+            )
+        )
+        .unwrap();
+    if Client::connect(&with_user(&database_url, &format!("{}:wrong", ROLE)), NoTls).is_ok() {
+        eprintln!("The database doesn't check the password of {}, skipping the credentials tests", ROLE);
+        return;
+    }
+
+    let password_file = env::temp_dir().join(format!("credentials-test-password-{}", std::process::id()));
+    fs::write(&password_file, "first-password\n").unwrap();
+    let server = Server::start_with(
+        &with_user(&database_url, ROLE),
+        &[("PGPASSWORD_FILE", password_file.to_str().unwrap())]
+    );
+    let (status, body) = server.request("GET", "/users?email=nobody@example.com", None);
+    assert_eq!(status, 200, "{}", body);
+
+    // Rotated: the open connections keep working until they die
+    admin.batch_execute(&format!("ALTER ROLE {} PASSWORD 'second-password'", ROLE)).unwrap();
+    fs::write(&password_file, "second-password\n").unwrap();
+    admin
+        .execute("SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE usename = $1", &[&ROLE])
+        .unwrap();
+
+    // The request that finds its connection dead may fail, the next ones connect
+    // with the new password
+    let mut statuses = Vec::new();
+    for _ in 0..5 {
+        let (status, _) = server.request("GET", "/users?email=nobody@example.com", None);
+        statuses.push(status);
+        if status == 200 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    assert_eq!(statuses.last(), Some(&200), "{:?}", statuses);
+
+    // The whole connection string can come from a file too
+    let url_file = env::temp_dir().join(format!("credentials-test-url-{}", std::process::id()));
+    fs::write(&url_file, with_user(&database_url, &format!("{}:second-password", ROLE))).unwrap();
+    let from_file = Server::start_with_env(&[("DATABASE_URL_FILE", url_file.to_str().unwrap())]);
+    let (status, body) = from_file.request("GET", "/users?email=nobody@example.com", None);
+    assert_eq!(status, 200, "{}", body);
+
+    fs::remove_file(password_file).ok();
+    fs::remove_file(url_file).ok();
+}
@@ -0,0 +1,458 @@
+// The CRUD endpoints, end to end against the server binary. Most of it runs on
+// the in-memory backend; the shared suites also run on SQLite and, when
+// TEST_DATABASE_URL points at a database the tests may write to, on Postgres.
+
+mod common;
+
+use common::{ json, unique_email, Server, TestDatabase };
+use serde_json::json;
+use std::io::Read;
+
+fn crud_suite(server: &Server) {
+    let email = unique_email("ada");
+    let (status, body) = server.request(
+        "POST",
+        "/users",
+        Some(&format!(r#"{{"name": "Ada Lovelace", "email": "{}"}}"#, email))
+    );
+    assert_eq!(status, 200, "{}", body);
+    let created = json(&body);
+    let id = created["id"].as_i64().unwrap();
+    assert_eq!(created["name"], "Ada Lovelace");
+    assert_eq!(created["email"], email.as_str());
+
+    let (status, body) = server.request("GET", &format!("/users/{}", id), None);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body), created);
+
+    let (status, body) = server.request("GET", &format!("/users?email={}", email.to_uppercase()), None);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body), serde_json::json!([created]));
+
+    // The email is taken whatever its case
+    let (status, body) = server.request(
+        "POST",
+        "/users",
+        Some(&format!(r#"{{"name": "Someone Else", "email": "{}"}}"#, email.to_uppercase()))
+    );
+    assert_eq!(status, 409, "{}", body);
+    assert_eq!(json(&body)["errors"][0]["code"], "taken");
+
+    let (status, body) = server.request(
+        "POST",
+        "/users/validate",
+        Some(&format!(r#"{{"name": "Someone Else", "email": "{}"}}"#, email))
+    );
+    assert_eq!(status, 422, "{}", body);
+
+    let new_email = unique_email("countess");
+    let (status, body) = server.request(
+        "PUT",
+        &format!("/users/{}", id),
+        Some(&format!(r#"{{"name": "Countess of Lovelace", "email": "{}"}}"#, new_email))
+    );
+    assert_eq!(status, 200, "{}", body);
+    let (_, body) = server.request("GET", &format!("/users/{}", id), None);
+    assert_eq!(json(&body)["name"], "Countess of Lovelace");
+
+    let (status, body) = server.request("DELETE", &format!("/users/{}", id), None);
+    assert_eq!(status, 200, "{}", body);
+    let (status, _) = server.request("GET", &format!("/users/{}", id), None);
+    assert_eq!(status, 404);
+    let (status, _) = server.request("DELETE", &format!("/users/{}", id), None);
+    assert_eq!(status, 404);
+    let (status, _) = server.request(
+        "PUT",
+        &format!("/users/{}", id),
+        Some(r#"{"name": "Nobody", "email": "nobody@example.com"}"#)
+    );
+    assert_eq!(status, 404);
+}
+
+// Every response of the CRUD routes in full, on a database without users yet
+fn fresh_database_suite(server: &Server) {
+    let listed = server.call("GET", "/users", "", None);
+    assert_eq!((listed.status, json(&listed.body)), (200, json!([])));
+    assert_eq!(listed.header("Content-Type"), Some("application/json"));
+
+    let ada = r#"{"name": "Ada Lovelace", "email": "ada@example.com"}"#;
+    let (status, body) = server.request("POST", "/users", Some(ada));
+    assert_eq!(status, 200, "{}", body);
+    let created = json(&body);
+    let id = created["id"].as_i64().unwrap();
+    assert_eq!(created, json!({ "id": id, "name": "Ada Lovelace", "email": "ada@example.com", "anonymized": false }));
+    let (status, body) = server.request("GET", "/users", None);
+    assert_eq!((status, json(&body)), (200, json!([created])));
+
+    let found = server.call("GET", &format!("/users/{}", id), "", None);
+    assert_eq!((found.status, json(&found.body)), (200, created));
+    assert!(found.header("ETag").is_some(), "{}", found.head);
+    let missing = server.call("GET", "/users/2147483647", "", None);
+    assert_eq!(missing.status, 404);
+    assert_eq!(missing.header("Content-Type"), Some("application/problem+json"));
+    assert_eq!(json(&missing.body)["detail"], "User with ID 2147483647 not found");
+
+    // Neither an invalid nor a malformed user is created
+    let (status, body) = server.request("POST", "/users", Some(r#"{"name": "", "email": "ada"}"#));
+    assert_eq!(status, 422, "{}", body);
+    assert_eq!(server.request("POST", "/users", Some("{")).0, 400);
+
+    let grace = r#"{"name": "Grace Hopper", "email": "grace@example.com"}"#;
+    let (status, body) = server.request("PUT", &format!("/users/{}", id), Some(grace));
+    assert_eq!(status, 200, "{}", body);
+    let updated = json!({ "id": id, "name": "Grace Hopper", "email": "grace@example.com", "anonymized": false });
+    assert_eq!(json(&body), updated);
+    assert_eq!(server.request("PUT", "/users/2147483647", Some(grace)).0, 404);
+
+    let (status, body) = server.request("DELETE", &format!("/users/{}", id), None);
+    assert_eq!((status, json(&body)), (200, json!(id.to_string())));
+    assert_eq!(server.request("DELETE", &format!("/users/{}", id), None).0, 404);
+    let (_, body) = server.request("GET", "/users", None);
+    assert_eq!(json(&body), json!([]));
+
+    let unknown = server.call("GET", "/nowhere", "", None);
+    assert_eq!(unknown.status, 404);
+    assert_eq!(unknown.header("Content-Type"), Some("application/problem+json"));
+    let problem = json(&unknown.body);
+    assert_eq!((&problem["code"], &problem["instance"]), (&json!("route_not_found"), &json!("/nowhere")));
+}
+
+fn dry_run_suite(server: &Server) {
+    let email = unique_email("dry");
+    let (status, body) = server.request(
+        "POST",
+        "/users?dry_run=true",
+        Some(&format!(r#"{{"name": "Dry Run", "email": "{}"}}"#, email))
+    );
+    assert_eq!(status, 200, "{}", body);
+    let body = json(&body);
+    assert_eq!(body["dry_run"], true);
+    assert!(body.get("id").is_none());
+
+    let (_, body) = server.request("GET", &format!("/users?email={}", email), None);
+    assert_eq!(json(&body), serde_json::json!([]));
+}
+
+// Listing is by id, with both filters ignoring case and % and _ matched literally
+fn list_suite(server: &Server) {
+    let tag = unique_email("list").replace(['@', '.', '-'], "");
+    let mut ids = Vec::new();
+    for name in [format!("{} 100% b", tag), format!("{} 100x a", tag), format!("{} 100% c", tag)] {
+        let (status, body) = server.request(
+            "POST",
+            "/users",
+            Some(&format!(r#"{{"name": "{}", "email": "{}"}}"#, name, unique_email("list")))
+        );
+        assert_eq!(status, 200, "{}", body);
+        ids.push(json(&body)["id"].as_i64().unwrap());
+    }
+
+    let (status, body) = server.request("GET", &format!("/users?name_contains={}+100%25", tag.to_uppercase()), None);
+    assert_eq!(status, 200, "{}", body);
+    let found: Vec<i64> = json(&body)
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["id"].as_i64().unwrap())
+        .collect();
+    assert_eq!(found, vec![ids[0], ids[2]]);
+
+    let (_, body) = server.request("GET", "/users", None);
+    let all: Vec<i64> = json(&body)
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["id"].as_i64().unwrap())
+        .collect();
+    let mut sorted = all.clone();
+    sorted.sort();
+    assert_eq!(all, sorted);
+}
+
+// Longer lists are sent in pages, each with a Link to the next one
+fn long_list_suite(server: &Server) {
+    let tag = unique_email("long").replace(['@', '.', '-'], "");
+    for i in 0..300 {
+        let user = format!(r#"{{"name": "{} {}", "email": "{}"}}"#, tag, i, unique_email("long"));
+        assert_eq!(server.request("POST", "/users", Some(&user)).0, 200);
+    }
+
+    let (mut target, mut pages, mut names) = (Some(format!("/users?name_contains={}&limit=120", tag)), 0, Vec::new());
+    while let Some(page) = target.take() {
+        let mut response = String::new();
+        server.send("GET", &page, "", None).read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        let users = json(body);
+        names.extend(users.as_array().unwrap().iter().map(|user| user["name"].as_str().unwrap().to_owned()));
+        let links = head.lines().find_map(|line| line.strip_prefix("Link: ")).unwrap();
+        let next = links.split(", ").find_map(|link| link.strip_suffix(">; rel=\"next\""));
+        target = next.map(|next| next[1..].to_owned());
+        pages += 1;
+    }
+    assert_eq!((pages, names.len()), (3, 300));
+    assert_eq!((names[0].clone(), names[299].clone()), (format!("{} 0", tag), format!("{} 299", tag)));
+}
+
+#[test]
+fn every_backend_answers_the_same_from_a_fresh_database() {
+    for database_url in ["memory://", "sqlite://:memory:"] {
+        fresh_database_suite(&Server::start(database_url));
+    }
+}
+
+// In a schema of its own, which is dropped after
+#[test]
+fn postgres_answers_the_same_from_a_fresh_database() {
+    let Some(database) = TestDatabase::create("crud") else {
+        return;
+    };
+    fresh_database_suite(&database.start_server(&[]));
+}
+
+#[test]
+fn crud_with_memory() {
+    let server = Server::start("memory://");
+    crud_suite(&server);
+    dry_run_suite(&server);
+    list_suite(&server);
+    long_list_suite(&server);
+}
+
+// JSON_CASE=camelCase: the same suites, with keys of a single word, and those of
+// more in camelCase both ways, snake_case still read
+#[test]
+fn crud_with_memory_in_camel_case() {
+    let server = Server::start_with("memory://", &[("JSON_CASE", "camelCase")]);
+    crud_suite(&server);
+    list_suite(&server);
+    long_list_suite(&server);
+
+    let dry_run = r#"{"name": "Dry", "email": "dry@example.com"}"#;
+    let (status, body) = server.request("POST", "/users?dry_run=true", Some(dry_run));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["dryRun"], true);
+    assert!(json(&body).get("dry_run").is_none());
+
+    let user = r#"{"name": "Ada", "email": "ada@bücher.example", "password": "correct horse battery"}"#;
+    let (status, body) = server.request("POST", "/users", Some(user));
+    assert_eq!(status, 200, "{}", body);
+    let created = json(&body);
+    assert_eq!(created["emailDisplay"], "ada@bücher.example");
+    assert!(created.get("email_display").is_none());
+    let id = created["id"].as_i64().unwrap();
+    let (_, body) = server.request("GET", &format!("/users?after_id={}", id - 1), None);
+    assert_eq!(json(&body)[0]["emailDisplay"], "ada@bücher.example");
+
+    // The fields named by the errors are in camelCase too
+    let target = format!("/users/{}/password", id);
+    let change = r#"{"currentPassword": "correct horse battery", "newPassword": "short"}"#;
+    let (status, body) = server.request("PUT", &target, Some(change));
+    assert_eq!(status, 422, "{}", body);
+    assert_eq!(json(&body)["errors"][0]["field"], "newPassword");
+    let (status, body) = server.request("PUT", &target, Some(r#"{"currentPassword": "x", "nickName": "y"}"#));
+    assert_eq!(status, 400, "{}", body);
+    assert_eq!(json(&body)["error"]["field"], "nickName");
+    let change = r#"{"currentPassword": "correct horse battery", "newPassword": "a longer new password"}"#;
+    assert_eq!(server.request("PUT", &target, Some(change)).0, 200);
+    let change = r#"{"current_password": "a longer new password", "new_password": "correct horse battery"}"#;
+    assert_eq!(server.request("PUT", &target, Some(change)).0, 200);
+
+    // snake_case by default
+    let (_, body) = Server::start("memory://").request("POST", "/users?dry_run=true", Some(dry_run));
+    assert_eq!(json(&body)["dry_run"], true);
+}
+
+#[test]
+fn memory_rejects_invalid_requests() {
+    let server = Server::start("memory://");
+
+    let (status, body) = server.request("POST", "/users", Some(r#"{"name": "", "email": "not an email"}"#));
+    assert_eq!(status, 422, "{}", body);
+    let body = json(&body);
+    let fields: Vec<&str> = body["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| error["field"].as_str().unwrap())
+        .collect();
+    assert_eq!(fields, ["name", "email"]);
+
+    let (status, _) = server.request("POST", "/users", Some(r#"{"name": "Ada"}"#));
+    assert_eq!(status, 400);
+
+    for id in ["0", "007", "-1", "abc", "99999999999"] {
+        let (status, body) = server.request("GET", &format!("/users/{}", id), None);
+        assert_eq!(status, 400, "{}: {}", id, body);
+        assert_eq!(json(&body)["code"], "invalid_id");
+    }
+
+    let (status, _) = server.request("GET", "/nowhere", None);
+    assert_eq!(status, 404);
+    // Rather than routed on what of it is read
+    let (status, body) = server.request("GET", &format!("/users/{}", "1".repeat(9000)), None);
+    assert_eq!(status, 414, "{}", body);
+}
+
+#[test]
+fn memory_keeps_ids_and_emails_consistent() {
+    let server = Server::start("memory://");
+    let create = |email: &str| {
+        let (status, body) = server.request(
+            "POST",
+            "/users",
+            Some(&format!(r#"{{"name": "Ada", "email": "{}"}}"#, email))
+        );
+        assert_eq!(status, 200, "{}", body);
+        json(&body)["id"].as_i64().unwrap()
+    };
+
+    let first = create("first@example.com");
+    let second = create("second@example.com");
+    assert_eq!(second, first + 1);
+
+    // Keeping one's own email is no conflict, taking someone else's is
+    let (status, body) = server.request(
+        "PUT",
+        &format!("/users/{}", first),
+        Some(r#"{"name": "Ada L", "email": "FIRST@example.com"}"#)
+    );
+    assert_eq!(status, 200, "{}", body);
+    let (status, _) = server.request(
+        "PUT",
+        &format!("/users/{}", first),
+        Some(r#"{"name": "Ada L", "email": "second@example.com"}"#)
+    );
+    assert_eq!(status, 409);
+
+    // A deleted user's email is free again, and its id isn't reused
+    let (status, _) = server.request("DELETE", &format!("/users/{}", second), None);
+    assert_eq!(status, 200);
+    assert_eq!(create("second@example.com"), second + 1);
+
+    // A dry run changes nothing
+    let (status, body) = server.request("DELETE", &format!("/users/{}?dry_run=true", first), None);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["dry_run"], true);
+    let (status, _) = server.request("GET", &format!("/users/{}", first), None);
+    assert_eq!(status, 200);
+}
+
+#[test]
+fn memory_answers_501_for_postgres_features() {
+    let server = Server::start("memory://");
+
+    for (method, target) in [("GET", "/events"), ("GET", "/users/1/export"), ("POST", "/users/1/anonymize")] {
+        let (status, body) = server.request(method, target, None);
+        assert_eq!(status, 501, "{} {}: {}", method, target, body);
+    }
+
+    let (status, body) = server.request_with_headers(
+        "POST",
+        "/users",
+        "Idempotency-Key: abc\r\n",
+        Some(&format!(r#"{{"name": "Ada", "email": "{}"}}"#, unique_email("key")))
+    );
+    assert_eq!(status, 501, "{}", body);
+}
+
+#[test]
+fn crud_with_sqlite() {
+    let server = Server::start("sqlite://:memory:");
+    crud_suite(&server);
+    dry_run_suite(&server);
+    list_suite(&server);
+
+    let (status, body) = server.request("GET", "/health", None);
+    assert_eq!(status, 200, "{}", body);
+}
+
+#[test]
+fn sqlite_rejects_idempotency_keys() {
+    let server = Server::start(":memory:");
+
+    let (status, body) = server.request_with_headers(
+        "POST",
+        "/users",
+        "Idempotency-Key: abc\r\n",
+        Some(&format!(r#"{{"name": "Ada", "email": "{}"}}"#, unique_email("key")))
+    );
+    assert_eq!(status, 501, "{}", body);
+}
+
+// What only Postgres does: replaying a create by its Idempotency-Key and
+// recording the events of the changes
+#[test]
+fn crud_with_postgres() {
+    let Some(database_url) = common::database_url("the Postgres suite") else {
+        return;
+    };
+    let server = Server::start(&database_url);
+    crud_suite(&server);
+    dry_run_suite(&server);
+    list_suite(&server);
+    long_list_suite(&server);
+
+    let email = unique_email("replay");
+    let key = format!("Idempotency-Key: {}\r\n", email);
+    let body = format!(r#"{{"name": "Replay", "email": "{}"}}"#, email);
+    let (status, first) = server.request_with_headers("POST", "/users", &key, Some(&body));
+    assert_eq!(status, 200, "{}", first);
+    let (status, replayed) = server.request_with_headers("POST", "/users", &key, Some(&body));
+    assert_eq!(status, 200, "{}", replayed);
+    assert_eq!(json(&replayed), json(&first));
+
+    let id = json(&first)["id"].as_i64().unwrap();
+    let (status, body) = server.request("GET", &format!("/users/{}/export", id), None);
+    assert_eq!(status, 200, "{}", body);
+    let event_types: Vec<String> = json(&body)["history"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| event["event_type"].as_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(event_types, ["user.created"]);
+
+    // A WebSocket asked for wrong is answered like the other errors
+    let response = server.call("GET", "/ws", "Upgrade: websocket\r\n", None);
+    assert_eq!(response.status, 400, "{:?}", response);
+    assert_eq!(response.header("Content-Type"), Some("application/problem+json"));
+    assert_eq!(response.header("Content-Length"), Some(response.body.len().to_string().as_str()));
+    assert_eq!(response.header("X-Content-Type-Options"), Some("nosniff"));
+    assert_eq!(json(&response.body)["code"], "bad_websocket_handshake");
+}
+
+// Instances sharing a database, each with its tables in a schema of its own or
+// under a prefix, don't see each other's users
+#[test]
+fn crud_with_postgres_in_a_schema_and_under_a_prefix() {
+    let Some(database_url) = common::database_url("the Postgres suite") else {
+        return;
+    };
+    let default = Server::start(&database_url);
+    let in_schema = Server::start_with(&database_url, &[("DATABASE_SCHEMA", "crud-test")]);
+    let prefixed = Server::start_with(&database_url, &[("DATABASE_TABLE_PREFIX", "tenant-a_")]);
+
+    for server in [&in_schema, &prefixed] {
+        crud_suite(server);
+        list_suite(server);
+    }
+
+    let servers = [&default, &in_schema, &prefixed];
+    for (index, server) in servers.iter().enumerate() {
+        let email = unique_email("isolated");
+        let (status, body) = server.request(
+            "POST",
+            "/users",
+            Some(&format!(r#"{{"name": "Isolated", "email": "{}"}}"#, email))
+        );
+        assert_eq!(status, 200, "{}", body);
+
+        for (other_index, other) in servers.iter().enumerate() {
+            let (status, body) = other.request("GET", &format!("/users?email={}", email), None);
+            assert_eq!(status, 200, "{}", body);
+            let found = json(&body).as_array().unwrap().len();
+            assert_eq!(found, (index == other_index) as usize, "created on {}, read on {}", index, other_index);
+        }
+    }
+}
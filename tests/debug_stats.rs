@@ -0,0 +1,68 @@
+// GET /debug/stats, a snapshot of what the server is doing, the requests being
+// answered included, with the admin endpoints.
+
+mod common;
+
+use common::{ json, Server };
+use serde_json::Value;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+#[test]
+fn the_stats_have_every_section() {
+    let vars = [("APP_ENV", "test"), ("DEPLOY_TOKEN", "an unrelated s3cret")];
+    let server = Server::start_with("memory://", &vars);
+    let (status, body) = server.request("GET", "/debug/stats", None);
+    assert_eq!(status, 200, "{}", body);
+    let stats = json(&body);
+
+    assert!(stats["uptime_seconds"].as_f64().is_some_and(|uptime| uptime > 0.0), "{}", stats);
+    assert_eq!(stats["build"]["name"], "rust_postgresql_tutorial");
+    assert!(stats["build"]["version"].is_string() && stats["build"]["profile"].is_string(), "{}", stats);
+    assert!(stats["connections"]["active"].as_u64().is_some_and(|active| active >= 1), "{}", stats);
+    assert!(stats["connections"]["max"].is_u64());
+    for field in ["threads", "busy", "queue_depth"] {
+        assert!(stats["workers"][field].is_u64(), "{}: {}", field, stats);
+    }
+    // Neither a pool nor a cache with memory://, nor a rate limit by default
+    assert_eq!(stats["pools"], serde_json::json!({}));
+    assert_eq!(stats["cache"], Value::Null);
+    assert_eq!(stats["rate_limiter"]["buckets"], Value::Null);
+    // This request, until it is answered
+    let in_flight = stats["in_flight"].as_array().unwrap();
+    assert_eq!(in_flight.len(), 1, "{}", stats);
+    assert_eq!(in_flight[0]["route"], "GET /debug/stats");
+
+    assert_eq!(stats["config"]["profile"], "test");
+    assert_eq!(stats["config"]["env"]["APP_ENV"], "test");
+    assert_eq!(stats["config"]["env"]["DATABASE_URL"], "[REDACTED]");
+    assert_eq!(stats["config"]["env"]["DEPLOY_TOKEN"], "[REDACTED]");
+    assert!(!body.contains("s3cret"), "{}", body);
+}
+
+#[test]
+fn requests_being_answered_are_listed() {
+    let server = Server::start_with("memory://", &[("APP_ENV", "test")]);
+    thread::scope(|scope| {
+        let sleeping = scope.spawn(|| {
+            server.request_with_headers("GET", "/admin/sleep?seconds=1.5", "X-Request-Id: sleepy\r\n", None).0
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let live = loop {
+            let stats = json(&server.request("GET", "/debug/stats", None).1);
+            let in_flight = stats["in_flight"].as_array().unwrap().clone();
+            let live = in_flight.into_iter().find(|request| request["route"] == "GET /admin/sleep");
+            match live {
+                Some(live) => break live,
+                None if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+                None => panic!("the sleep never showed: {}", stats),
+            }
+        };
+        assert_eq!(live["request_id"], "sleepy");
+        assert!(live["elapsed_ms"].is_u64(), "{}", live);
+        assert_eq!(sleeping.join().unwrap(), 200);
+    });
+    let stats = json(&server.request("GET", "/debug/stats", None).1);
+    assert_eq!(stats["in_flight"].as_array().unwrap().len(), 1, "{}", stats);
+}
@@ -0,0 +1,120 @@
+// ENCRYPTION_KEY: emails encrypted in the users table, the events and the stored
+// responses, still found and kept unique, and rotate-encryption-key. Runs when
+// TEST_DATABASE_URL points at a database the tests may write to, whose role may
+// create schemas.
+
+mod common;
+
+use common::{ json, Server };
+use postgres::{ Client, NoTls };
+use std::env;
+use std::process::{ Command, Output };
+
+const SCHEMA: &str = "encryption-test";
+
+// 32 bytes of 1, then of 2
+const KEY: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+const NEW_KEY: &str = "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=";
+
+fn rotate(database_url: &str, vars: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .arg("rotate-encryption-key")
+        .env("DATABASE_URL", database_url)
+        .env("DATABASE_SCHEMA", SCHEMA)
+        .envs(vars.iter().copied())
+        .output()
+        .unwrap()
+}
+
+fn stored_emails(admin: &mut Client) -> Vec<(String, Option<String>)> {
+    let rows = admin
+        .query(&format!("SELECT email, email_hash FROM \"{}\".users ORDER BY id", SCHEMA), &[])
+        .unwrap();
+    rows.iter().map(|row| (row.get(0), row.get(1))).collect()
+}
+
+// The events and the stored responses, as text
+fn stored_elsewhere(admin: &mut Client) -> String {
+    let query = format!(
+        "SELECT string_agg(payload::text, ' ') FROM \"{0}\".events_outbox
+        UNION ALL SELECT string_agg(response_body, ' ') FROM \"{0}\".idempotency_keys",
+        SCHEMA
+    );
+    let rows = admin.query(&query, &[]).unwrap();
+    rows.iter().filter_map(|row| row.get::<_, Option<String>>(0)).collect::<Vec<_>>().join(" ")
+}
+
+#[test]
+fn emails_are_encrypted_at_rest() {
+    let Some(database_url) = common::database_url("the encryption test") else {
+        return;
+    };
+    let mut admin = Client::connect(&database_url, NoTls).unwrap();
+    admin.batch_execute(&format!("DROP SCHEMA IF EXISTS \"{}\" CASCADE", SCHEMA)).unwrap();
+
+    // A user from before the key was set, stored as it is
+    let server = Server::start_with(&database_url, &[("DATABASE_SCHEMA", SCHEMA)]);
+    let grace = r#"{"name": "Grace Hopper", "email": "grace@example.com"}"#;
+    assert_eq!(server.request("POST", "/users", Some(grace)).0, 200);
+    drop(server);
+
+    let server = Server::start_with(&database_url, &[("DATABASE_SCHEMA", SCHEMA), ("ENCRYPTION_KEY", KEY)]);
+    let ada = r#"{"name": "Ada Lovelace", "email": "ada@example.com"}"#;
+    let key = "Idempotency-Key: ada\r\n";
+    let (status, body) = server.request_with_headers("POST", "/users", key, Some(ada));
+    assert_eq!(status, 200, "{}", body);
+    let path = format!("/users/{}", json(&body)["id"]);
+    let (status, replayed) = server.request_with_headers("POST", "/users", key, Some(ada));
+    assert_eq!(status, 200, "{}", replayed);
+    assert_eq!(json(&replayed), json(&body));
+
+    let (status, body) = server.request("GET", &path, None);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["email"], "ada@example.com");
+    let (_, body) = server.request("GET", "/users?email=ADA@example.com", None);
+    assert_eq!(json(&body)[0]["name"], "Ada Lovelace");
+    let (_, body) = server.request("GET", "/users?email=grace@example.com", None);
+    assert_eq!(json(&body)[0]["name"], "Grace Hopper");
+    // Taken, encrypted or not
+    let taken = r#"{"name": "Someone Else", "email": "Ada@Example.com"}"#;
+    assert_eq!(server.request("POST", "/users", Some(taken)).0, 409);
+    let (status, body) = server.request("POST", "/users", Some(grace));
+    assert_eq!(status, 409, "{}", body);
+    let renamed = r#"{"name": "Ada Lovelace", "email": "GRACE@example.com"}"#;
+    let (status, body) = server.request("PUT", &path, Some(renamed));
+    assert_eq!(status, 409, "{}", body);
+    drop(server);
+
+    let stored = stored_emails(&mut admin);
+    assert_eq!(stored[0], ("grace@example.com".to_owned(), None));
+    assert!(!stored[1].0.contains("ada"), "{:?}", stored[1]);
+    assert!(stored[1].1.is_some());
+    let elsewhere = stored_elsewhere(&mut admin);
+    assert!(!elsewhere.contains("ada@example.com") && elsewhere.contains("grace@example.com"), "{}", elsewhere);
+
+    // Every email under the new key, the plaintext one too
+    let refused = rotate(&database_url, &[("NEW_ENCRYPTION_KEY", NEW_KEY)]);
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("ENCRYPTION_KEY is not set"));
+    let rotated = rotate(&database_url, &[("ENCRYPTION_KEY", KEY), ("NEW_ENCRYPTION_KEY", NEW_KEY)]);
+    assert!(rotated.status.success(), "{}", String::from_utf8_lossy(&rotated.stderr));
+    let stored = stored_emails(&mut admin);
+    assert!(stored.iter().all(|(email, hash)| !email.contains("example.com") && hash.is_some()), "{:?}", stored);
+    let elsewhere = stored_elsewhere(&mut admin);
+    assert!(!elsewhere.contains("example.com"), "{}", elsewhere);
+
+    let server = Server::start_with(&database_url, &[("DATABASE_SCHEMA", SCHEMA), ("ENCRYPTION_KEY", NEW_KEY)]);
+    let (_, body) = server.request("GET", "/users?email=grace@example.com", None);
+    assert_eq!(json(&body)[0]["name"], "Grace Hopper");
+    let (status, body) = server.request("GET", &path, None);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["email"], "ada@example.com");
+    let (status, body) = server.request("GET", &format!("{}/export", path), None);
+    assert_eq!(status, 200, "{}", body);
+    assert!(body.contains("ada@example.com"), "{}", body);
+    drop(server);
+
+    // The old key can't read them anymore
+    let server = Server::start_with(&database_url, &[("DATABASE_SCHEMA", SCHEMA), ("ENCRYPTION_KEY", KEY)]);
+    assert_eq!(server.request("GET", &path, None).0, 500);
+}
@@ -0,0 +1,82 @@
+// The .env and .env.local of the working directory, read at startup under the
+// variables the server was started with.
+
+mod common;
+
+use common::{ json, Server };
+use serde_json::Value;
+use std::path::PathBuf;
+use std::process::Command;
+use std::{ env, fs };
+
+// A directory of its own, with those files in it
+fn dir_with(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = env::temp_dir().join(format!("env-files-test-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for (file, content) in files {
+        fs::write(dir.join(file), content).unwrap();
+    }
+    dir
+}
+
+fn env_of(server: &Server) -> Value {
+    let (status, body) = server.request("GET", "/debug/stats", None);
+    assert_eq!(status, 200, "{}", body);
+    json(&body)["config"]["env"].clone()
+}
+
+#[test]
+fn the_environment_wins_over_env_local_over_env() {
+    let env_file = "
+        # Local settings
+        DATABASE_URL=memory://
+        APP_ENV=test
+        MAINTENANCE_MESSAGE=\"Back in a minute, really\"
+        SLOW_REQUEST_MS=100
+        SLOW_QUERY_MS=100   # both
+    ";
+    let dir = dir_with("precedence", &[(".env", env_file), (".env.local", "SLOW_REQUEST_MS='200'\n")]);
+    let server = Server::start_in(&dir, &[("SLOW_QUERY_MS", "300")]);
+    let env = env_of(&server);
+    assert_eq!(env["APP_ENV"], "test");
+    assert_eq!(env["MAINTENANCE_MESSAGE"], "Back in a minute, really");
+    assert_eq!(env["SLOW_REQUEST_MS"], "200");
+    assert_eq!(env["SLOW_QUERY_MS"], "300");
+    assert_eq!(server.request("GET", "/users", None).0, 200);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn no_env_file_is_needed() {
+    let dir = dir_with("missing", &[]);
+    let server = Server::start_in(&dir, &[("DATABASE_URL", "memory://"), ("APP_ENV", "test")]);
+    assert_eq!(env_of(&server)["APP_ENV"], "test");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .env_remove("DATABASE_URL")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains(".env"), "{}", stderr);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn the_lines_that_are_not_valid_are_warned_of_and_left_out() {
+    let dir = dir_with("invalid", &[(".env", "APP_ENV=test\nnot a variable\nDATABASE_URL=memory://\nB=\"open\n")]);
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .args(["check", "--json"])
+        .env_remove("DATABASE_URL")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("WARN config: .env, line 2: expected NAME=value, left out"), "{}", stderr);
+    assert!(stderr.contains("WARN config: .env, line 4: the \" isn't closed, left out"), "{}", stderr);
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["status"], "ok");
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,116 @@
+// The 500s answer the id of their error, in X-Error-Id and in the problem, and
+// what went wrong is logged under it. Here the users table is dropped under the
+// server, or a column of it renamed, so that the repository fails: in a SQLite
+// file, and in Postgres when TEST_DATABASE_URL points at a database the tests may
+// write to, whose role may create schemas.
+
+mod common;
+
+use common::{ json, Response, Server };
+use postgres::{ Client, NoTls };
+use rusqlite::Connection;
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::time::{ Duration, Instant };
+
+fn database(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("error-ids-test-{}-{}.db", name, std::process::id()));
+    fs::remove_file(&path).ok();
+    path
+}
+
+const SCHEMA: &str = "error-ids-test";
+
+// The 500 of a user read once the table was broken
+fn failed(server: &Server, break_table: impl FnOnce()) -> Response {
+    let ada = r#"{"name": "Ada Lovelace", "email": "ada@example.com"}"#;
+    let (status, body) = server.request("POST", "/users", Some(ada));
+    assert_eq!(status, 200, "{}", body);
+    break_table();
+    let response = server.call("GET", "/users/1", "", None);
+    assert_eq!(response.status, 500, "{}", response.body);
+    assert_eq!(response.header("Content-Type"), Some("application/problem+json"));
+    response
+}
+
+// The error logged under the id, the log being JSON
+fn logged(lines: &Receiver<String>, error_id: &str) -> Value {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let line = lines.recv_timeout(left).unwrap_or_else(|_| panic!("nothing was logged as {}", error_id));
+        match serde_json::from_str::<Value>(&line) {
+            Ok(event) if event["error_id"] == error_id && event["level"] == "ERROR" => return event,
+            _ => continue,
+        }
+    }
+}
+
+#[test]
+fn prod_answers_the_id_and_logs_what_went_wrong() {
+    let path = database("prod");
+    let url = format!("sqlite://{}", path.display());
+    let (server, lines) = Server::start_capturing(&url, &[("APP_ENV", "production"), ("REQUIRE_AUTH", "false")]);
+    let response = failed(&server, || Connection::open(&path).unwrap().execute_batch("DROP TABLE users").unwrap());
+    let error_id = response.header("X-Error-Id").unwrap().to_owned();
+    let body = json(&response.body);
+    assert_eq!(body["error_id"], error_id.as_str());
+    assert_eq!(response.header("X-Request-Id"), Some(error_id.as_str()));
+    // Nothing of the query nor of the database
+    assert_eq!(body["detail"], format!("The error was logged as {}", error_id));
+    for leak in ["users", "SELECT", "table", "sqlite", path.to_str().unwrap()] {
+        assert!(!response.body.contains(leak), "{}", response.body);
+    }
+
+    let event = logged(&lines, &error_id);
+    let message = event["message"].as_str().unwrap();
+    assert!(message.starts_with(&format!("Error {}: Error fetching user: ", error_id)), "{}", message);
+    assert!(message.contains("no such table: users"), "{}", message);
+    drop(server);
+    fs::remove_file(&path).ok();
+}
+
+#[test]
+fn dev_also_answers_what_went_wrong() {
+    let path = database("dev");
+    let url = format!("sqlite://{}", path.display());
+    let (server, lines) = Server::start_capturing(&url, &[("APP_ENV", "test"), ("LOG_FORMAT", "json")]);
+    let response = failed(&server, || Connection::open(&path).unwrap().execute_batch("DROP TABLE users").unwrap());
+    let error_id = response.header("X-Error-Id").unwrap().to_owned();
+    let body = json(&response.body);
+    assert_eq!((&body["status"], &body["error_id"]), (&Value::from(500), &Value::from(error_id.as_str())));
+    let detail = body["detail"].as_str().unwrap();
+    assert!(detail.starts_with("Error fetching user: ") && detail.contains("no such table: users"), "{}", detail);
+    assert!(logged(&lines, &error_id)["message"].as_str().unwrap().contains("no such table: users"));
+    drop(server);
+    fs::remove_file(&path).ok();
+}
+
+#[test]
+fn the_sqlstate_of_postgres_is_logged() {
+    let Some(database_url) = common::database_url("the SQLSTATE test") else {
+        return;
+    };
+    let mut admin = Client::connect(&database_url, NoTls).unwrap();
+    admin.batch_execute(&format!("DROP SCHEMA IF EXISTS \"{}\" CASCADE", SCHEMA)).unwrap();
+    let vars = [("APP_ENV", "production"), ("REQUIRE_AUTH", "false"), ("MIGRATIONS_MODE", "apply")];
+    let vars = [&vars[..], &[("DATABASE_SCHEMA", SCHEMA)]].concat();
+    let (server, lines) = Server::start_capturing(&database_url, &vars);
+    let rename = format!("ALTER TABLE \"{}\".users RENAME COLUMN name TO full_name", SCHEMA);
+    let response = failed(&server, || admin.batch_execute(&rename).unwrap());
+    let error_id = response.header("X-Error-Id").unwrap().to_owned();
+    assert_eq!(json(&response.body)["error_id"], error_id.as_str());
+    for leak in ["name", "column", SCHEMA, "postgres", "localhost"] {
+        assert!(!response.body.contains(leak), "{}", response.body);
+    }
+
+    // Undefined column
+    let event = logged(&lines, &error_id);
+    assert_eq!(event["sqlstate"], "42703", "{}", event);
+    assert!(event["message"].as_str().unwrap().contains(r#"column "name" does not exist"#), "{}", event);
+    drop(server);
+    admin.batch_execute(&format!("DROP SCHEMA \"{}\" CASCADE", SCHEMA)).unwrap();
+}
@@ -0,0 +1,85 @@
+// ERROR_WEBHOOK_URL: the panics and the 500s are reported to it, here a server
+// listening in the test, each error once however many times it happened.
+
+mod common;
+
+use common::Server;
+use serde_json::Value;
+use std::io::{ BufRead, BufReader, Read, Write };
+use std::net::TcpListener;
+use std::sync::mpsc::{ self, Receiver };
+use std::thread;
+use std::time::Duration;
+
+// The reports POSTed to the webhook, answered 200
+fn webhook() -> (String, Receiver<(String, Value)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/errors", listener.local_addr().unwrap());
+    let (sender, reports) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let (mut request_line, mut length) = (String::new(), 0);
+            reader.read_line(&mut request_line).unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((_, value)) = line.to_ascii_lowercase().split_once("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            sender.send((request_line, serde_json::from_slice(&body).unwrap())).ok();
+        }
+    });
+    (url, reports)
+}
+
+#[test]
+fn each_distinct_failure_is_reported_once() {
+    let (url, reports) = webhook();
+    let vars = [("ERROR_WEBHOOK_URL", url.as_str()), ("APP_ENV", "test")];
+    let server = Server::start_with("memory://", &vars);
+    // The status of the response
+    let fail = |target: &str, request_id: &str| {
+        let mut stream = server.send("GET", target, &format!("X-Request-Id: {}\r\n", request_id), None);
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok();
+        response.split_whitespace().nth(1).map(|status| status.parse::<u16>().unwrap())
+    };
+    for attempt in 0..3 {
+        assert_eq!(fail(&format!("/admin/fail?reason=for+user+{}", attempt), "error-1"), Some(500));
+    }
+    assert_eq!(fail("/admin/fail?with=panic&reason=loudly", "panic-1"), Some(500));
+    assert_eq!(fail("/admin/fail?with=panic&reason=loudly", "panic-2"), Some(500));
+    // Still answering, the address of the failure masked
+    assert_eq!(fail("/admin/fail?reason=for+ada@example.com", "error-2"), Some(500));
+    assert_eq!(server.request("GET", "/users/42", None).0, 404);
+
+    let mut received = Vec::new();
+    while let Ok(report) = reports.recv_timeout(Duration::from_secs(2)) {
+        received.push(report);
+    }
+    let messages: Vec<&str> = received.iter().map(|(_, report)| report["message"].as_str().unwrap()).collect();
+    assert_eq!(received.len(), 3, "{:?}", messages);
+    let (request_line, error) = &received[0];
+    assert!(request_line.starts_with("POST /errors "), "{}", request_line);
+    assert_eq!(error["message"], "Failing for user 0");
+    assert_eq!((error["level"].as_str(), error["logger"].as_str()), (Some("error"), Some("error")));
+    assert_eq!(error["tags"]["route"], "GET /admin/fail");
+    assert_eq!((&error["tags"]["request_id"], &error["tags"]["error_id"]), (&"error-1".into(), &"error-1".into()));
+    assert_eq!(error["environment"], "test");
+    assert!(error["release"].as_str().unwrap().contains('@'), "{}", error);
+    assert_eq!(error["event_id"].as_str().unwrap().len(), 32);
+
+    let panic = &received[1].1;
+    assert_eq!((panic["level"].as_str(), panic["tags"]["request_id"].as_str()), (Some("fatal"), Some("panic-1")));
+    assert!(panic["message"].as_str().unwrap().contains("Failing loudly"), "{}", panic);
+    let masked = received[2].1["message"].as_str().unwrap();
+    assert!(masked.starts_with("Failing for ") && !masked.contains("ada@example.com"), "{}", masked);
+}
@@ -0,0 +1,60 @@
+// GET /users/events: the changes of the users as server-sent events, fed by the
+// one connection of the instance listening to the notifications of Postgres.
+
+mod common;
+
+use common::{ unique_email, TestDatabase };
+use postgres::{ Client, NoTls };
+use std::io::Read;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+// What the stream sent until it had the text, or failing the test after a while
+fn read_until(stream: &mut TcpStream, text: &str) -> String {
+    stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut sent = Vec::new();
+    let mut buffer = [0; 4096];
+    while !String::from_utf8_lossy(&sent).contains(text) {
+        assert!(Instant::now() < deadline, "no {} in {}", text, String::from_utf8_lossy(&sent));
+        if let Ok(read) = stream.read(&mut buffer) {
+            sent.extend_from_slice(&buffer[..read]);
+        }
+    }
+    String::from_utf8_lossy(&sent).into_owned()
+}
+
+#[test]
+fn the_streams_share_the_listening_connection() {
+    let Some(database) = TestDatabase::create("events") else {
+        return;
+    };
+    let prefix = format!("events_{}_", std::process::id());
+    let server = database.start_server(&[("DATABASE_TABLE_PREFIX", &prefix)]);
+    let mut streams: Vec<TcpStream> = (0..3).map(|_| server.send("GET", "/users/events", "", None)).collect();
+    for stream in &mut streams {
+        read_until(stream, "text/event-stream");
+    }
+
+    // The backends of the instance last asked to LISTEN, the broadcaster's alone
+    let mut client = Client::connect(&common::database_url("events").unwrap(), NoTls).unwrap();
+    let listen = format!("LISTEN {}user_changes", prefix);
+    let mut listening = || -> i64 {
+        let query = "SELECT count(*) FROM pg_stat_activity WHERE query = $1";
+        client.query_one(query, &[&listen]).unwrap().get(0)
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while listening() == 0 {
+        assert!(Instant::now() < deadline, "the broadcaster never listened");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(listening(), 1);
+
+    let email = unique_email("streamed");
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}"}}"#, email);
+    assert_eq!(server.request("POST", "/users", Some(&user)).0, 200);
+    for stream in &mut streams {
+        assert!(read_until(stream, &email).contains("event: "));
+    }
+}
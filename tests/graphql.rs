@@ -0,0 +1,191 @@
+// POST /graphql: the users read and changed with a GraphQL document, each field
+// authorized as its route is and failing on its own, with the error of what its
+// route would have answered. A document that is invalid, too deep or too complex
+// isn't executed at all, nor one longer than a body is read.
+
+mod common;
+
+use std::io::{ Read, Write };
+use std::net::TcpStream;
+
+use common::{ json, unique_email, Response, Server };
+use serde_json::{ json, Value };
+
+const ADMIN: &str = "X-Api-Key: a\r\n";
+const READER: &str = "X-Api-Key: r\r\n";
+
+fn start(vars: &[(&str, &str)]) -> Server {
+    let vars = [&[("API_KEYS", "admin:a,reader:r:reader"), ("APP_ENV", "test")], vars].concat();
+    Server::start_with("memory://", &vars)
+}
+
+fn graphql(server: &Server, headers: &str, query: &str, variables: Value) -> (u16, Value) {
+    let body = json!({ "query": query, "variables": variables }).to_string();
+    let (status, body) = server.request_with_headers("POST", "/graphql", headers, Some(&body));
+    (status, json(&body))
+}
+
+const CREATE: &str = "mutation New($input: UserInput!) { createUser(input: $input) { id name } }";
+
+fn create(server: &Server, name: &str) -> (i64, String) {
+    let email = unique_email(name);
+    let (status, body) = graphql(server, ADMIN, CREATE, json!({ "input": { "name": name, "email": email } }));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["createUser"]["name"], name, "{}", body);
+    (body["data"]["createUser"]["id"].as_i64().unwrap(), email)
+}
+
+#[test]
+fn users_are_read_and_changed_with_their_fields_in_the_order_asked() {
+    let server = start(&[]);
+    let (ada, ada_email) = create(&server, "Ada");
+    let (grace, _) = create(&server, "Grace");
+
+    let query = "query One($id: Int!) { __typename someone: user(id: $id) { email id __typename } }";
+    let body = json!({ "query": query, "variables": { "id": ada } }).to_string();
+    let (status, text) = server.request_with_headers("POST", "/graphql", ADMIN, Some(&body));
+    assert_eq!(status, 200, "{}", text);
+    let expected = format!(
+        r#"{{"data":{{"__typename":"Query","someone":{{"email":"{}","id":{},"__typename":"User"}}}}}}"#,
+        ada_email.to_lowercase(),
+        ada
+    );
+    assert_eq!(text, expected);
+
+    let (_, body) = graphql(&server, ADMIN, "{ users(limit: 1, offset: 1) { id } all: users { name } }", json!({}));
+    assert_eq!(body["data"]["users"], json!([{ "id": grace }]), "{}", body);
+    assert_eq!(body["data"]["all"], json!([{ "name": "Ada" }, { "name": "Grace" }]), "{}", body);
+    let filtered = "query By($email: String) { users(filter: { email: $email }) { id } }";
+    let (_, body) = graphql(&server, ADMIN, filtered, json!({ "email": ada_email.to_uppercase() }));
+    assert_eq!(body["data"]["users"], json!([{ "id": ada }]), "{}", body);
+
+    let update = "mutation($id: Int!, $input: UserInput!) { updateUser(id: $id, input: $input) { name } }";
+    let input = json!({ "name": "Ada Lovelace", "email": ada_email });
+    let (_, body) = graphql(&server, ADMIN, update, json!({ "id": ada, "input": input }));
+    assert_eq!(body["data"]["updateUser"]["name"], "Ada Lovelace", "{}", body);
+    let (_, body) = graphql(&server, ADMIN, &format!("mutation {{ deleteUser(id: {}) }}", grace), json!({}));
+    assert_eq!(body["data"]["deleteUser"], grace, "{}", body);
+    assert_eq!(server.request_with_headers("GET", &format!("/users/{}", grace), ADMIN, None).0, 404);
+}
+
+#[test]
+fn a_field_that_fails_is_null_with_the_error_of_its_route() {
+    let server = start(&[]);
+    let (ada, ada_email) = create(&server, "Ada");
+
+    let query = format!("{{ user(id: 999) {{ id }} found: user(id: {}) {{ id }} }}", ada);
+    let (status, body) = graphql(&server, ADMIN, &query, json!({}));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"], json!({ "user": null, "found": { "id": ada } }));
+    let error = &body["errors"][0];
+    assert_eq!((&error["path"], &error["extensions"]["code"]), (&json!(["user"]), &json!("NOT_FOUND")), "{}", body);
+    assert_eq!(error["locations"], json!([{ "line": 1, "column": 3 }]));
+
+    // The errors of the validation, as PUT /users/{id} would answer them
+    let (_, body) = graphql(&server, ADMIN, CREATE, json!({ "input": { "name": "Ada", "email": "not an email" } }));
+    let extensions = &body["errors"][0]["extensions"];
+    assert_eq!(extensions["code"], "BAD_USER_INPUT", "{}", body);
+    assert_eq!(extensions["errors"][0]["field"], "email", "{}", body);
+    let (_, body) = graphql(&server, ADMIN, CREATE, json!({ "input": { "name": "Ada", "email": ada_email } }));
+    assert_eq!(body["errors"][0]["extensions"]["code"], "CONFLICT", "{}", body);
+    let (_, body) = graphql(&server, ADMIN, CREATE, json!({ "input": { "name": "Ada", "mail": ada_email } }));
+    assert_eq!(body["errors"][0]["extensions"]["field"], "mail", "{}", body);
+
+    // A reader reads but doesn't create
+    let (_, body) = graphql(&server, READER, &query, json!({}));
+    assert_eq!(body["data"]["found"]["id"], ada, "{}", body);
+    let input = json!({ "input": { "name": "Grace", "email": unique_email("grace") } });
+    let (_, body) = graphql(&server, READER, CREATE, input);
+    assert_eq!(body["data"]["createUser"], Value::Null, "{}", body);
+    assert_eq!(body["errors"][0]["extensions"]["code"], "FORBIDDEN", "{}", body);
+    assert_eq!(body["errors"][0]["message"], "createUser needs the writer role, reader is a reader");
+}
+
+#[test]
+fn documents_that_cant_be_executed_are_refused_whole() {
+    let server = start(&[("GRAPHQL_MAX_COMPLEXITY", "50")]);
+    let refused = |query: &str, variables: Value| {
+        let (status, body) = graphql(&server, ADMIN, query, variables);
+        assert_eq!((status, body.get("data")), (400, None), "{}", body);
+        body["errors"][0].clone()
+    };
+
+    let error = refused("{\n  user(id: 1 { id } }", json!({}));
+    assert_eq!(error["extensions"]["code"], "GRAPHQL_PARSE_FAILED", "{}", error);
+    assert_eq!(error["locations"], json!([{ "line": 2, "column": 14 }]));
+    let error = refused("{ user(id: 1) { password } }", json!({}));
+    assert_eq!(error["message"], "Cannot query field \"password\" on type \"User\"");
+    assert_eq!(error["extensions"]["code"], "GRAPHQL_VALIDATION_FAILED");
+    let introspection = refused("{ __schema { types { name } } }", json!({}));
+    assert_eq!(introspection["message"], "Cannot query field \"__schema\" on type \"Query\"");
+    assert_eq!(refused("{ user { id } }", json!({}))["message"], "Field \"user\" argument \"id\" is required");
+    let error = refused("query($id: Int!) { user(id: $id) { id } }", json!({}));
+    assert_eq!(error["extensions"]["code"], "BAD_USER_INPUT", "{}", error);
+
+    // A point for the list and one for each id of its limit
+    let error = refused("{ users(limit: 49) { id } a: users(limit: 1) { id } }", json!({}));
+    assert_eq!(error["extensions"]["code"], "QUERY_TOO_COMPLEX", "{}", error);
+    assert_eq!(error["message"], "The document has a complexity of 52, over the 50 of GRAPHQL_MAX_COMPLEXITY");
+    assert_eq!(graphql(&server, ADMIN, "{ users(limit: 49) { id } }", json!({})).0, 200);
+
+    let server = start(&[("GRAPHQL_MAX_DEPTH", "1")]);
+    let (status, body) = graphql(&server, ADMIN, "{ user(id: 1) { id } }", json!({}));
+    assert_eq!((status, &body["errors"][0]["extensions"]["code"]), (400, &json!("QUERY_TOO_DEEP")), "{}", body);
+    assert_eq!(graphql(&server, ADMIN, "{ __typename }", json!({})).0, 200);
+}
+
+#[test]
+fn graphiql_is_served_when_it_is_on() {
+    let server = start(&[("GRAPHIQL", "true")]);
+    let page = server.call("GET", "/graphql", ADMIN, None);
+    assert_eq!(page.status, 200, "{}", page.body);
+    assert!(page.header("Content-Type").is_some_and(|kind| kind.starts_with("text/html")));
+    assert!(page.header("Content-Security-Policy").is_some_and(|csp| csp.contains("https://unpkg.com")));
+    assert!(page.body.contains("GraphiQL.createFetcher({ url: '/graphql' })"), "{}", page.body);
+
+    let server = start(&[]);
+    let refused = server.call("GET", "/graphql", ADMIN, None);
+    assert_eq!((refused.status, refused.header("Allow")), (405, Some("POST")), "{}", refused.body);
+}
+
+#[test]
+fn queries_are_reads_though_they_are_posted() {
+    let server = start(&[("READ_ONLY", "true")]);
+    let (status, body) = graphql(&server, ADMIN, "{ users { id } }", json!({}));
+    assert_eq!((status, &body["data"]["users"]), (200, &json!([])), "{}", body);
+    let input = json!({ "input": { "name": "Ada", "email": unique_email("ada") } });
+    assert_eq!(graphql(&server, ADMIN, CREATE, input).0, 403);
+}
+
+#[test]
+fn graphql_keeps_its_keys_whatever_the_case_of_the_api() {
+    let server = start(&[("JSON_CASE", "camelCase")]);
+    let (ada, _) = create(&server, "Ada");
+    let query = format!("{{ the_one: user(id: {}) {{ name full_id: id }} }}", ada);
+    let body = json!({ "query": query }).to_string();
+    let response = server.call("POST", "/graphql", ADMIN, Some(&body));
+    assert_eq!(response.header("Content-Type"), Some("application/graphql-response+json"));
+    let expected = format!(r#"{{"data":{{"the_one":{{"name":"Ada","full_id":{}}}}}}}"#, ada);
+    assert_eq!(response.body, expected);
+}
+
+#[test]
+fn a_document_longer_than_a_kilobyte_is_read_whole_and_one_past_the_limit_not_at_all() {
+    let server = start(&[]);
+    let (ada, _) = create(&server, "Ada");
+    let padding = " ".repeat(2048);
+    let query = format!("{{ user(id: {}) {{ {}name }} }}", ada, padding);
+    let (status, body) = graphql(&server, ADMIN, &query, json!({}));
+    assert_eq!((status, &body["data"]["user"]), (200, &json!({ "name": "Ada" })), "{}", body);
+
+    // Answered once its head is read, the body left unsent
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    let head = "POST /graphql HTTP/1.1\r\nHost: localhost\r\nX-Api-Key: a\r\nContent-Length: 1000000\r\n\r\n{";
+    stream.write_all(head.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let response = Response::parse(&response);
+    assert_eq!(response.status, 413, "{}", response.body);
+    assert_eq!(response.header("Content-Type"), Some("application/problem+json"));
+    assert_eq!(json(&response.body)["code"], "body_too_large", "{}", response.body);
+}
@@ -0,0 +1,85 @@
+// GET /health/details, each dependency checked on its own: ok, degraded or down,
+// with how long it took to answer, the whole being the worst of them.
+
+mod common;
+
+use common::{ json, Server };
+use std::env;
+use std::net::TcpListener;
+use std::time::{ Duration, Instant };
+
+#[test]
+fn the_details_are_ok_when_every_dependency_is() {
+    let server = Server::start_with("memory://", &[]);
+    let (status, body) = server.request("GET", "/health/details", None);
+    assert_eq!(status, 200, "{}", body);
+    let health = json(&body);
+    assert_eq!(health["status"], "ok");
+    let components = health["components"].as_object().unwrap();
+    // Neither a pool, the migrations with it, nor a cache with memory://
+    assert_eq!(components.keys().collect::<Vec<_>>(), ["circuit_breaker", "database"]);
+    for component in components.values() {
+        assert_eq!(component["status"], "ok", "{}", body);
+        assert!(component["latency_ms"].is_f64() && component["detail"].is_string(), "{}", body);
+    }
+    assert_eq!(health["components"]["circuit_breaker"]["detail"], "closed");
+}
+
+#[test]
+fn an_unreachable_cache_is_down() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("redis://127.0.0.1:{}", listener.local_addr().unwrap().port());
+    drop(listener);
+    let vars = [("USER_CACHE_TTL_MS", "60000"), ("CACHE_URL", url.as_str())];
+    let server = Server::start_with("memory://", &vars);
+    let (status, body) = server.request("GET", "/health/details", None);
+    assert_eq!(status, 503, "{}", body);
+    let health = json(&body);
+    assert_eq!(health["status"], "down");
+    assert_eq!(health["components"]["database"]["status"], "ok", "{}", body);
+    assert_eq!(health["components"]["cache"]["status"], "down", "{}", body);
+    assert!(health["components"]["cache"]["detail"].as_str().unwrap().contains("can't reach"), "{}", body);
+    // /health only asks the database
+    assert_eq!(server.request("GET", "/health", None).0, 200);
+}
+
+#[test]
+fn a_cache_that_doesnt_answer_is_down_after_the_probe_timeout() {
+    // Accepts the connections, never reads from them
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("redis://127.0.0.1:{}", listener.local_addr().unwrap().port());
+    let vars = [
+        ("USER_CACHE_TTL_MS", "60000"),
+        ("CACHE_URL", url.as_str()),
+        ("CACHE_TIMEOUT_MS", "5000"),
+        ("HEALTH_PROBE_TIMEOUT_MS", "200"),
+    ];
+    let server = Server::start_with("memory://", &vars);
+    let started = Instant::now();
+    let (status, body) = server.request("GET", "/health/details", None);
+    assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+    assert_eq!(status, 503, "{}", body);
+    let cache = &json(&body)["components"]["cache"];
+    assert_eq!(cache["status"], "down", "{}", body);
+    assert_eq!(cache["latency_ms"], 200.0, "{}", body);
+    assert!(cache["detail"].as_str().unwrap().starts_with("No answer within"), "{}", body);
+    drop(listener);
+}
+
+#[test]
+fn the_migrations_and_the_backup_disk_are_checked() {
+    let Some(database_url) = common::database_url("the migrations health test") else {
+        return;
+    };
+    let dir = env::temp_dir();
+    let server = Server::start_with(&database_url, &[("BACKUP_DIR", dir.to_str().unwrap())]);
+    let (status, body) = server.request("GET", "/health/details", None);
+    let health = json(&body);
+    for name in ["database", "migrations", "backup_disk", "circuit_breaker"] {
+        assert!(health["components"][name]["status"].is_string(), "{}: {}", name, body);
+    }
+    assert_eq!(health["components"]["database"]["status"], "ok", "{}", body);
+    assert_eq!(health["components"]["migrations"]["status"], "ok", "{}", body);
+    assert!(health["components"]["database"]["detail"].as_str().unwrap().contains("connections in use"), "{}", body);
+    assert_eq!(status == 200, health["status"] != "down");
+}
@@ -0,0 +1,55 @@
+// The server run in process, through the library, instead of the binary: on a
+// port of its own, answering over plain TCP, shut down through its handle.
+
+use rust_postgresql_tutorial::config::Config;
+use rust_postgresql_tutorial::repository::memory::MemoryRepository;
+use rust_postgresql_tutorial::run_server;
+use std::io::{ Read, Write };
+use std::net::{ SocketAddr, TcpStream };
+use std::sync::{ mpsc, Arc };
+use std::thread;
+use std::time::Duration;
+
+fn request(addr: SocketAddr, method: &str, target: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let head = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n", method, target);
+    // The end of the head and the body after a pause, so that they arrive apart
+    write!(stream, "{}Content-Length: {}\r\n", head, body.len()).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    write!(stream, "\r\n{}", body).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    (status, body.to_owned())
+}
+
+#[test]
+fn the_server_runs_in_process_until_its_handle_shuts_it_down() {
+    let vars = [("DATABASE_URL", "memory://"), ("BIND_ADDRESS", "127.0.0.1"), ("PORT", "0")];
+    let config = Config::from_file_and_vars(None, vars.map(|(name, value)| (name.to_owned(), value.to_owned())));
+    let server = run_server(config.unwrap(), Arc::new(MemoryRepository::default())).unwrap();
+    let addr = server.local_addr();
+    assert_ne!(addr.port(), 0);
+
+    let (status, body) = request(addr, "POST", "/users", r#"{"name":"Ada","email":"ada@example.com"}"#);
+    assert_eq!(status, 200, "{}", body);
+    let (status, body) = request(addr, "GET", "/users", "");
+    assert_eq!(status, 200);
+    assert!(body.contains("ada@example.com"), "{}", body);
+
+    // Connected before the shutdown, answered while the server drains
+    let mut open = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    server.shutdown();
+    write!(open, "GET /readyz HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    open.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+
+    let (joined, stopped) = mpsc::channel();
+    thread::spawn(move || joined.send(server.join()).unwrap());
+    assert!(stopped.recv_timeout(Duration::from_secs(10)).unwrap().is_ok());
+    assert!(TcpStream::connect(addr).is_err());
+}
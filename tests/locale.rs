@@ -0,0 +1,71 @@
+// The messages of the errors are in the language of the Accept-Language of the
+// request, English, German or French, or else in DEFAULT_LANGUAGE; their codes are
+// the same in all of them.
+
+mod common;
+
+use common::{ json, Server };
+use std::io::Read;
+
+// The head and the body of the response
+fn response(server: &Server, method: &str, target: &str, headers: &str, body: Option<&str>) -> (String, String) {
+    let mut response = String::new();
+    server.send(method, target, headers, body).read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (format!("{}\r\n", head), body.to_owned())
+}
+
+#[test]
+fn the_problems_are_in_the_language_asked_for() {
+    let server = Server::start("memory://");
+    let languages = [
+        ("de", "de", "Nicht gefunden", "Benutzer mit der ID 999 wurde nicht gefunden"),
+        ("fr-FR", "fr", "Introuvable", "Utilisateur avec l'ID 999 introuvable"),
+        ("it", "en", "Not Found", "User with ID 999 not found"),
+        ("en;q=0.3, it, de-AT;q=0.9, fr;q=0.5", "de", "Nicht gefunden", "Benutzer mit der ID 999 wurde nicht gefunden"),
+    ];
+    for (accept_language, language, title, detail) in languages {
+        let headers = format!("Accept-Language: {}\r\n", accept_language);
+        let (head, body) = response(&server, "GET", "/users/999", &headers, None);
+        assert!(head.starts_with("HTTP/1.1 404"), "{}", head);
+        assert!(head.contains(&format!("\r\nContent-Language: {}\r\n", language)), "{}", head);
+        let problem = json(&body);
+        assert_eq!((problem["title"].as_str(), problem["detail"].as_str()), (Some(title), Some(detail)));
+        assert_eq!(problem["code"], "user_not_found");
+    }
+    // Without one, in the default and saying nothing of it
+    let (head, body) = response(&server, "GET", "/users/999", "", None);
+    assert!(!head.contains("Content-Language"), "{}", head);
+    assert_eq!(json(&body)["detail"], "User with ID 999 not found");
+}
+
+#[test]
+fn the_validation_errors_are_in_the_language_asked_for() {
+    let server = Server::start("memory://");
+    let invalid = Some(r#"{"name": "", "email": "no-at"}"#);
+    let messages = [
+        ("de", ["name darf nicht leer sein", "email muss ein @ enthalten"]),
+        ("fr", ["name ne doit pas être vide", "email doit contenir un @"]),
+    ];
+    for (language, expected) in messages {
+        let headers = format!("Accept-Language: {}\r\nContent-Type: application/json\r\n", language);
+        let (head, body) = response(&server, "POST", "/users", &headers, invalid);
+        assert!(head.starts_with("HTTP/1.1 422"), "{}", head);
+        let errors = json(&body)["errors"].as_array().unwrap().clone();
+        let codes: Vec<&str> = errors.iter().map(|error| error["code"].as_str().unwrap()).collect();
+        let messages: Vec<&str> = errors.iter().map(|error| error["message"].as_str().unwrap()).collect();
+        assert_eq!(codes, ["required", "missing_at"], "{}", body);
+        assert_eq!(messages, expected);
+    }
+}
+
+#[test]
+fn the_default_language_is_configured() {
+    let server = Server::start_with("memory://", &[("DEFAULT_LANGUAGE", "fr")]);
+    for headers in ["", "Accept-Language: it, es;q=0.5\r\n"] {
+        let (_, body) = response(&server, "GET", "/users/999", headers, None);
+        assert_eq!(json(&body)["detail"], "Utilisateur avec l'ID 999 introuvable", "{}", headers);
+    }
+    let (_, body) = response(&server, "GET", "/users/999", "Accept-Language: en\r\n", None);
+    assert_eq!(json(&body)["title"], "Not Found");
+}
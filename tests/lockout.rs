@@ -0,0 +1,108 @@
+// The lockouts of the logins: LOGIN_MAX_FAILURES failed logins lock the email for
+// LOGIN_LOCKOUT_SECS with 429s, whether or not it is a user's, until an admin
+// clears it with DELETE /admin/lockouts. LOGIN_MAX_FAILURES_PER_IP lock the
+// client address for every email.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+const SECRET: &str = "an HS256 secret of at least 32 bytes";
+const ROOT: &str = "X-Api-Key: r00t\r\n";
+
+fn start(lockout_secs: &str, max_failures_per_ip: &str) -> Server {
+    let vars = [
+        ("JWT_SECRET", SECRET),
+        ("API_KEYS", "root:r00t"),
+        ("APP_ENV", "test"),
+        ("LOGIN_MAX_FAILURES", "3"),
+        ("LOGIN_MAX_FAILURES_PER_IP", max_failures_per_ip),
+        ("LOGIN_LOCKOUT_SECS", lockout_secs),
+    ];
+    Server::start_with("memory://", &vars)
+}
+
+fn create_user(server: &Server) -> String {
+    let email = unique_email("ada");
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}", "password": "correct horse battery"}}"#, email);
+    let (status, body) = server.request_with_headers("POST", "/users", ROOT, Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    email
+}
+
+// The status, the Retry-After header if any and the body
+fn log_in(server: &Server, email: &str, password: &str) -> (u16, Option<u64>, serde_json::Value) {
+    let login = format!(r#"{{"email": "{}", "password": "{}"}}"#, email, password);
+    let mut response = String::new();
+    server.send("POST", "/login", "", Some(&login)).read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    let retry_after = head.split("\r\n").find_map(|line| line.strip_prefix("Retry-After: "));
+    (status, retry_after.map(|secs| secs.parse().unwrap()), json(body))
+}
+
+#[test]
+fn failed_logins_lock_the_email_until_cleared() {
+    let server = start("300", "20");
+    let email = create_user(&server);
+
+    assert_eq!(log_in(&server, &email, "battery staple").0, 401);
+    assert_eq!(log_in(&server, &email, "battery staple").0, 401);
+    // Logging in starts over
+    assert_eq!(log_in(&server, &email, "correct horse battery").0, 200);
+    for _ in 0..3 {
+        assert_eq!(log_in(&server, &email, "battery staple").0, 401);
+    }
+    let (status, retry_after, body) = log_in(&server, &email, "correct horse battery");
+    assert_eq!(status, 429, "{}", body);
+    assert_eq!(body["code"], "login_locked");
+    assert!(retry_after.is_some_and(|secs| secs > 290 && secs <= 300), "{:?}", retry_after);
+
+    // Just the same for an email that is no user's
+    for _ in 0..3 {
+        assert_eq!(log_in(&server, "nobody@example.com", "battery staple").0, 401);
+    }
+    assert_eq!(log_in(&server, "nobody@example.com", "battery staple").0, 429);
+
+    let target = format!("/admin/lockouts?email={}", email.to_uppercase());
+    let (status, body) = server.request_with_headers("DELETE", &target, ROOT, None);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["cleared"], true);
+    assert_eq!(log_in(&server, &email, "correct horse battery").0, 200);
+    assert_eq!(server.request_with_headers("DELETE", "/admin/lockouts", ROOT, None).0, 400);
+}
+
+#[test]
+fn lockouts_expire() {
+    let server = start("1", "20");
+    let email = create_user(&server);
+    for _ in 0..3 {
+        assert_eq!(log_in(&server, &email, "battery staple").0, 401);
+    }
+    assert_eq!(log_in(&server, &email, "correct horse battery").0, 429);
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(log_in(&server, &email, "correct horse battery").0, 200);
+}
+
+#[test]
+fn failed_logins_lock_the_client_address_for_every_email() {
+    let server = start("300", "5");
+    let (ada, alan) = (create_user(&server), create_user(&server));
+
+    // Each email has a budget of its own, the address one for all of them
+    for _ in 0..2 {
+        assert_eq!(log_in(&server, &ada, "battery staple").0, 401);
+        assert_eq!(log_in(&server, &alan, "battery staple").0, 401);
+    }
+    assert_eq!(log_in(&server, &alan, "correct horse battery").0, 200);
+    assert_eq!(log_in(&server, "nobody@example.com", "battery staple").0, 401);
+    let (status, _, body) = log_in(&server, &alan, "correct horse battery");
+    assert_eq!(status, 429, "{}", body);
+
+    let (status, body) = server.request_with_headers("DELETE", "/admin/lockouts?ip=127.0.0.1", ROOT, None);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(log_in(&server, &alan, "correct horse battery").0, 200);
+}
@@ -0,0 +1,72 @@
+// LOG_FORMAT=json: every line the server logs is a JSON object, of timestamp,
+// level, target, message and the fields of the event.
+
+mod common;
+
+use common::Server;
+use serde_json::Value;
+use std::process::Command;
+use std::time::{ Duration, Instant };
+
+fn assert_schema(event: &Value) {
+    let timestamp = event["timestamp"].as_str().unwrap_or_else(|| panic!("no timestamp: {}", event));
+    assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok(), "{}", event);
+    assert!(["ERROR", "WARN", "INFO", "DEBUG", "TRACE"].contains(&event["level"].as_str().unwrap()), "{}", event);
+    assert!(event["target"].is_string() && event["message"].is_string(), "{}", event);
+}
+
+#[test]
+fn access_events_are_json_objects() {
+    let (server, lines) = Server::start_capturing("memory://", &[("LOG_FORMAT", "json")]);
+    assert_eq!(server.request_with_headers("GET", "/users/42", "User-Agent: \"quoted\"\r\n", None).0, 404);
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let event = loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let line = lines.recv_timeout(left).expect("the request wasn't logged");
+        let event: Value = serde_json::from_str(&line).unwrap_or_else(|e| panic!("{}: {}", e, line));
+        assert_schema(&event);
+        if event["target"] == "access" && event["path"] == "/users/42" {
+            break event;
+        }
+    };
+    assert_eq!(event["level"], "INFO");
+    assert_eq!(event["message"], "GET /users/42 404");
+    assert_eq!(event["method"], "GET");
+    assert_eq!(event["route"], "GET /users/{id}");
+    assert_eq!(event["status"], 404);
+    assert!(event["bytes"].as_u64().unwrap() > 0, "{}", event);
+    assert!(event["duration_ms"].as_f64().unwrap() >= 0.0, "{}", event);
+    assert_eq!(event["client_ip"], "127.0.0.1");
+    assert_eq!(event["user_agent"], "\"quoted\"");
+}
+
+#[test]
+fn error_events_are_json_objects() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .env("DATABASE_URL", "memory://")
+        .env("LOG_FORMAT", "json")
+        .env("AUTH_AUDIT", "maybe")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let line = stderr.lines().last().unwrap();
+    let event: Value = serde_json::from_str(line).unwrap_or_else(|e| panic!("{}: {}", e, line));
+    assert_schema(&event);
+    assert_eq!(event["level"], "ERROR");
+    assert_eq!(event["target"], "rust_postgresql_tutorial");
+    assert_eq!(event["message"], "Invalid auth audit config: AUTH_AUDIT must be true or false, got \"maybe\"");
+}
+
+#[test]
+fn an_unknown_format_keeps_the_server_from_starting() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .env("DATABASE_URL", "memory://")
+        .env("LOG_FORMAT", "xml")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Invalid log config: LOG_FORMAT must be plain or json"), "{}", stderr);
+}
@@ -0,0 +1,61 @@
+// ACCESS_LOG_SAMPLE_RATE, or ACCESS_LOG_SAMPLE_FILE read again after a SIGHUP:
+// only that share of the successful requests is in the access log, the errors
+// always are, and all of them are in the metrics.
+
+mod common;
+
+use common::Server;
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+// The paths in the access log up to that of the last request, to /nope, a 404,
+// but the connections closed without a request
+fn logged_paths(server: &Server, lines: &Receiver<String>) -> Vec<String> {
+    assert_eq!(server.request("GET", "/nope", None).0, 404);
+    let (deadline, mut paths) = (Instant::now() + Duration::from_secs(10), Vec::new());
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let line = lines.recv_timeout(left).unwrap_or_else(|_| panic!("/nope wasn't logged: {:?}", paths));
+        let Ok(event) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        if event["target"] == "access" {
+            let path = event["path"].as_str().unwrap().to_owned();
+            if path == "/nope" {
+                return paths;
+            }
+            if path != "-" {
+                paths.push(path);
+            }
+        }
+    }
+}
+
+#[test]
+fn the_rate_of_the_file_is_read_again_on_sighup() {
+    let path = env::temp_dir().join(format!("log-sampling-test-{}", std::process::id()));
+    fs::write(&path, "0").unwrap();
+    let vars = [("LOG_FORMAT", "json"), ("ACCESS_LOG_SAMPLE_FILE", path.to_str().unwrap())];
+    let (server, lines) = Server::start_capturing("memory://", &vars);
+
+    for _ in 0..5 {
+        assert_eq!(server.request("GET", "/livez", None).0, 200);
+    }
+    assert_eq!(server.request("GET", "/users/123456", None).0, 404);
+    assert_eq!(logged_paths(&server, &lines), ["/users/123456"]);
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    assert!(metrics.contains("\nhttp_requests_total{method=\"GET\",route=\"/livez\",status_class=\"2xx\"} 5\n"));
+    assert!(metrics.contains("\naccess_log_sampled_out_total 5\n"), "{}", metrics);
+
+    fs::write(&path, "1").unwrap();
+    server.signal(libc::SIGHUP);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(server.request("GET", "/livez", None).0, 200);
+    // The request to /metrics was answered before the SIGHUP
+    assert_eq!(logged_paths(&server, &lines), ["/livez"]);
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,99 @@
+// JWT_SECRET: POST /login trades the email and the password of a user for a token,
+// and the token does as well as a key in Authorization: Bearer until it expires.
+// A token turned away says why in the code of the 401.
+
+mod common;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use common::{ json, unique_email, Server };
+use jsonwebtoken::{ EncodingKey, Header };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+const SECRET: &str = "an HS256 secret of at least 32 bytes";
+const ROOT: &str = "X-Api-Key: r00t\r\n";
+
+fn start() -> Server {
+    Server::start_with("memory://", &[("JWT_SECRET", SECRET), ("JWT_LEEWAY_SECS", "0"), ("API_KEYS", "root:r00t")])
+}
+
+fn create_user(server: &Server, email: &str, password: &str) -> i64 {
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}", "password": "{}"}}"#, email, password);
+    let (status, body) = server.request_with_headers("POST", "/users", ROOT, Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    assert!(!body.contains("password") && !body.contains("argon2"), "{}", body);
+    json(&body)["id"].as_i64().unwrap()
+}
+
+fn log_in(server: &Server, email: &str, password: &str) -> (u16, serde_json::Value) {
+    let login = format!(r#"{{"email": "{}", "password": "{}"}}"#, email, password);
+    let (status, body) = server.request("POST", "/login", Some(&login));
+    (status, json(&body))
+}
+
+fn get_with_token(server: &Server, target: &str, token: &str) -> (u16, serde_json::Value) {
+    let headers = format!("Authorization: Bearer {}\r\n", token);
+    let (status, body) = server.request_with_headers("GET", target, &headers, None);
+    (status, json(&body))
+}
+
+// Signed with the secret of the server
+fn sign(sub: i64, iat: u64, nbf: u64, exp: u64) -> String {
+    let claims = serde_json::json!({
+        "sub": sub.to_string(), "tenant": "default", "role": "writer", "iat": iat, "nbf": nbf, "exp": exp,
+    });
+    jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+}
+
+#[test]
+fn users_log_in_for_a_token() {
+    let server = start();
+    let email = unique_email("ada");
+    let id = create_user(&server, &email, "correct horse battery");
+
+    let (status, body) = log_in(&server, &email, "correct horse battery");
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["token_type"], "Bearer");
+    assert_eq!(body["expires_in"], 3600);
+    let token = body["token"].as_str().unwrap();
+
+    let (status, user) = get_with_token(&server, &format!("/users/{}", id), token);
+    assert_eq!(status, 200, "{}", user);
+    assert_eq!(user["email"], email.as_str());
+
+    // Neither a wrong password nor an unknown email says which it was
+    for (email, password) in [(email.as_str(), "battery staple"), ("nobody@example.com", "correct horse battery")] {
+        let (status, body) = log_in(&server, email, password);
+        assert_eq!(status, 401, "{}", body);
+        assert_eq!(body["code"], "invalid_credentials");
+    }
+    assert_eq!(server.request("GET", "/users", None).0, 401);
+}
+
+#[test]
+fn tokens_that_are_expired_tampered_with_or_malformed_are_turned_away() {
+    let server = start();
+    let email = unique_email("alan");
+    let id = create_user(&server, &email, "correct horse battery");
+    let target = format!("/users/{}", id);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    assert_eq!(get_with_token(&server, &target, &sign(id, now - 120, now - 120, now + 60)).0, 200);
+    let expired = sign(id, now - 120, now - 120, now - 60);
+    assert_eq!(get_with_token(&server, &target, &expired).1["code"], "token_expired");
+    let early = sign(id, now, now + 60, now + 120);
+    assert_eq!(get_with_token(&server, &target, &early).1["code"], "token_not_yet_valid");
+
+    // The claims of another user under the signature of the token
+    let (_, body) = log_in(&server, &email, "correct horse battery");
+    let token = body["token"].as_str().unwrap();
+    let parts: Vec<&str> = token.split('.').collect();
+    let mut claims: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+    claims["sub"] = "1".into();
+    let tampered = format!("{}.{}.{}", parts[0], URL_SAFE_NO_PAD.encode(claims.to_string()), parts[2]);
+    let (status, body) = get_with_token(&server, &target, &tampered);
+    assert_eq!(status, 401, "{}", body);
+    assert_eq!(body["code"], "token_invalid_signature");
+
+    assert_eq!(get_with_token(&server, &target, "not.a.token").1["code"], "token_malformed");
+}
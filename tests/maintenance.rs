@@ -0,0 +1,83 @@
+// Maintenance mode: every request but /health, /livez, /metrics and the switch
+// is answered 503 with a problem+json body, or only the writes in read-only
+// maintenance, until it is turned off again.
+
+mod common;
+
+use common::{ json, Server };
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+const ADA: &str = r#"{"name": "Ada Lovelace", "email": "ada@example.com"}"#;
+
+// The status line and headers, and the body
+fn send(server: &Server, method: &str, target: &str, body: &str) -> (String, String) {
+    let mut response = String::new();
+    server.send(method, target, "", Some(body)).read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.to_owned(), body.to_owned())
+}
+
+fn assert_turned_away(server: &Server, method: &str, target: &str, message: &str) {
+    let (head, body) = send(server, method, target, ADA);
+    assert!(head.starts_with("HTTP/1.1 503 "), "{} {}: {}", method, target, head);
+    assert!(head.contains("\r\nContent-Type: application/problem+json"), "{}", head);
+    assert!(head.contains("\r\nRetry-After: 120"), "{}", head);
+    let body = json(&body);
+    assert_eq!((&body["status"], &body["detail"]), (&serde_json::json!(503), &serde_json::json!(message)));
+}
+
+#[test]
+fn maintenance_is_toggled_at_runtime() {
+    let server = Server::start_with("memory://", &[("APP_ENV", "test")]);
+    let on = r#"{"enabled": true, "message": "Upgrading the database", "retry_after": 120}"#;
+    let (status, body) = server.request("POST", "/admin/maintenance", Some(on));
+    assert_eq!(status, 200, "{}", body);
+
+    for (method, target) in [("GET", "/users"), ("POST", "/users"), ("GET", "/users/1"), ("GET", "/readyz")] {
+        assert_turned_away(&server, method, target, "Upgrading the database");
+    }
+    for target in ["/health", "/livez", "/metrics"] {
+        assert_eq!(server.request("GET", target, None).0, 200, "{}", target);
+    }
+    let (status, body) = server.request("GET", "/admin/maintenance", None);
+    assert_eq!(status, 200);
+    assert_eq!(json(&body)["enabled"], true);
+    assert_eq!(json(&body)["read_only"], false);
+
+    // Reads are served in read-only maintenance
+    let read_only = r#"{"enabled": true, "read_only": true, "message": "Read only", "retry_after": 120}"#;
+    assert_eq!(server.request("POST", "/admin/maintenance", Some(read_only)).0, 200);
+    assert_eq!(server.request("GET", "/users", None).0, 200);
+    assert_turned_away(&server, "POST", "/users", "Read only");
+
+    assert_eq!(server.request("POST", "/admin/maintenance", Some(r#"{"enabled": false}"#)).0, 200);
+    let (status, body) = server.request("POST", "/users", Some(ADA));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(server.request("GET", "/readyz", None).0, 200);
+}
+
+#[test]
+fn maintenance_is_set_at_startup_and_reloaded_on_sighup() {
+    let server = Server::start_with(
+        "memory://",
+        &[("MAINTENANCE", "on"), ("MAINTENANCE_MESSAGE", "Back soon"), ("MAINTENANCE_RETRY_AFTER", "120")],
+    );
+    assert_turned_away(&server, "GET", "/users", "Back soon");
+    assert_eq!(server.request("GET", "/health", None).0, 200);
+    drop(server);
+
+    let path = env::temp_dir().join(format!("maintenance-test-{}.json", std::process::id()));
+    fs::write(&path, r#"{"enabled": true, "message": "From the file", "retry_after": 120}"#).unwrap();
+    let server = Server::start_with("memory://", &[("MAINTENANCE_FILE", path.to_str().unwrap())]);
+    assert_turned_away(&server, "GET", "/users", "From the file");
+
+    fs::write(&path, r#"{"enabled": false}"#).unwrap();
+    server.signal(libc::SIGHUP);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(server.request("GET", "/users", None).0, 200);
+    fs::remove_file(&path).unwrap();
+}
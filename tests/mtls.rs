@@ -0,0 +1,185 @@
+// TLS_PORT with TLS_CLIENT_CA_PATH: HTTPS whose clients present a certificate of
+// the CAs of the bundle, the CN of which is the principal of their requests. The
+// certificates are those of a throwaway CA made for the test, and another CA's.
+
+mod common;
+
+use common::{ Response, Server };
+use openssl::asn1::Asn1Time;
+use openssl::bn::{ BigNum, MsbOption };
+use openssl::hash::MessageDigest;
+use openssl::pkey::{ PKey, Private };
+use openssl::rsa::Rsa;
+use openssl::ssl::{ SslConnector, SslMethod, SslVerifyMode };
+use openssl::x509::extension::{ BasicConstraints, SubjectAlternativeName };
+use openssl::x509::{ X509, X509NameBuilder };
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::io::{ Read, Write };
+use std::net::{ TcpListener, TcpStream };
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
+use std::time::{ Duration, Instant };
+
+struct Issued {
+    certificate: X509,
+    key: PKey<Private>,
+}
+
+// Signed by the issuer, or by itself without one
+fn issue(common_name: &str, issuer: Option<&Issued>, ca: bool) -> Issued {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", common_name).unwrap();
+    let name = name.build();
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+    builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(issuer.map_or(&name, |issuer| issuer.certificate.subject_name())).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    if ca {
+        builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+    } else {
+        let names = SubjectAlternativeName::new().ip("127.0.0.1").build(&builder.x509v3_context(None, None));
+        builder.append_extension(names.unwrap()).unwrap();
+    }
+    builder.sign(issuer.map_or(&key, |issuer| &issuer.key), MessageDigest::sha256()).unwrap();
+    Issued { certificate: builder.build(), key }
+}
+
+// The CA of the clients and of the server, and another one
+struct Pki {
+    dir: PathBuf,
+    ca: Issued,
+    other_ca: Issued,
+}
+
+impl Pki {
+    fn new(name: &str) -> Pki {
+        let dir = env::temp_dir().join(format!("mtls-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (ca, other_ca) = (issue("Test CA", None, true), issue("Other CA", None, true));
+        let server = issue("localhost", Some(&ca), false);
+        fs::write(dir.join("ca.pem"), ca.certificate.to_pem().unwrap()).unwrap();
+        fs::write(dir.join("server.pem"), server.certificate.to_pem().unwrap()).unwrap();
+        fs::write(dir.join("server.key"), server.key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        Pki { dir, ca, other_ca }
+    }
+
+    fn path(&self, file: &str) -> String {
+        self.dir.join(file).to_str().unwrap().to_owned()
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+fn start(pki: &Pki, vars: &[(&str, &str)]) -> (Server, Receiver<String>, u16) {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string();
+    let (ca, cert, key) = (pki.path("ca.pem"), pki.path("server.pem"), pki.path("server.key"));
+    let defaults = [
+        ("TLS_PORT", port.as_str()),
+        ("TLS_CERT_PATH", cert.as_str()),
+        ("TLS_KEY_PATH", key.as_str()),
+        ("TLS_CLIENT_CA_PATH", ca.as_str()),
+        ("API_KEYS", "root:r00t"),
+        ("AUTH_AUDIT", "true"),
+        ("LOG_FORMAT", "json"),
+    ];
+    let (server, lines) = Server::start_capturing("memory://", &[&defaults, vars].concat());
+    (server, lines, port.parse().unwrap())
+}
+
+// The response to the request over TLS, with the certificate if any, or None when
+// the connection was refused
+fn request(port: u16, pki: &Pki, client: Option<&Issued>, target: &str, headers: &str) -> Option<Response> {
+    let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+    connector.cert_store_mut().add_cert(pki.ca.certificate.clone()).unwrap();
+    connector.set_verify(SslVerifyMode::PEER);
+    if let Some(client) = client {
+        connector.set_certificate(&client.certificate).unwrap();
+        connector.set_private_key(&client.key).unwrap();
+    }
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut tls = connector.build().connect("localhost", stream).ok()?;
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: 0\r\n\r\n", target, headers);
+    tls.write_all(request.as_bytes()).ok()?;
+    let mut response = Vec::new();
+    tls.read_to_end(&mut response).ok();
+    let response = String::from_utf8(response).unwrap();
+    (!response.is_empty()).then(|| Response::parse(&response))
+}
+
+// The principal of the next audit event
+fn audited(lines: &Receiver<String>) -> Value {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let line = lines.recv_timeout(left).expect("no audit event");
+        match serde_json::from_str::<Value>(&line) {
+            Ok(event) if event["event_type"] == "auth_audit" => return event["principal"].clone(),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn only_the_certificates_of_the_ca_get_in() {
+    let pki = Pki::new("required");
+    let (server, lines, port) = start(&pki, &[]);
+    let valid = issue("billing-service", Some(&pki.ca), false);
+    let foreign = issue("billing-service", Some(&pki.other_ca), false);
+
+    assert!(request(port, &pki, None, "/users/1", "").is_none());
+    assert!(request(port, &pki, Some(&foreign), "/users/1", "").is_none());
+    let response = request(port, &pki, Some(&valid), "/users/1", "").expect("the valid certificate was refused");
+    assert_eq!(response.status, 404, "{:?}", response);
+    assert_eq!(audited(&lines), "billing-service");
+
+    // Plain HTTP is served as before, the key being needed there
+    assert_eq!(server.request("GET", "/users/1", None).0, 401);
+}
+
+#[test]
+fn an_optional_certificate_leaves_the_other_credentials() {
+    let pki = Pki::new("optional");
+    let (_server, lines, port) = start(&pki, &[("TLS_CLIENT_CERT", "optional"), ("TLS_CLIENT_ROLE", "reader")]);
+    let valid = issue("reporting", Some(&pki.ca), false);
+    let foreign = issue("reporting", Some(&pki.other_ca), false);
+
+    assert!(request(port, &pki, Some(&foreign), "/users/1", "X-Api-Key: r00t\r\n").is_none());
+    let response = request(port, &pki, None, "/users/1", "").unwrap();
+    assert_eq!(response.status, 401);
+    let response = request(port, &pki, None, "/users/1", "X-Api-Key: r00t\r\n").unwrap();
+    assert_eq!(response.status, 404);
+
+    // With the role of TLS_CLIENT_ROLE
+    let response = request(port, &pki, Some(&valid), "/admin/stats", "").unwrap();
+    assert_eq!(response.status, 403);
+    let principals: Vec<Value> = (0..4).map(|_| audited(&lines)).collect();
+    assert_eq!(principals, [Value::Null, "root".into(), "reporting".into(), "reporting".into()]);
+}
+
+#[test]
+fn unreadable_certificates_are_refused_at_startup() {
+    let missing = "/nonexistent/server.pem";
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .env("DATABASE_URL", "memory://")
+        .env("TLS_PORT", "0")
+        .env("TLS_CERT_PATH", missing)
+        .env("TLS_KEY_PATH", missing)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("TLS_CERT_PATH"), "{:?}", output);
+}
@@ -0,0 +1,126 @@
+// OTEL_EXPORTER_OTLP_ENDPOINT: the spans are sent to a collector as OTLP/HTTP JSON,
+// here one listening in the test, in batches and once more when shutting down.
+
+mod common;
+
+use common::Server;
+use serde_json::Value;
+use std::io::{ BufRead, BufReader, Read, Write };
+use std::net::TcpListener;
+use std::sync::mpsc::{ self, Receiver };
+use std::thread;
+use std::time::Duration;
+
+// The bodies POSTed to /v1/traces of the collector, answered 200
+fn collector() -> (String, Receiver<Value>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, exports) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let (mut request_line, mut length) = (String::new(), 0);
+            reader.read_line(&mut request_line).unwrap();
+            assert!(request_line.starts_with("POST /v1/traces "), "{}", request_line);
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((_, value)) = line.to_ascii_lowercase().split_once("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}").unwrap();
+            sender.send(serde_json::from_slice(&body).unwrap()).ok();
+        }
+    });
+    (endpoint, exports)
+}
+
+fn attribute<'a>(attributes: &'a Value, key: &str) -> &'a Value {
+    let attribute = attributes.as_array().unwrap().iter().find(|attribute| attribute["key"] == key);
+    attribute.map_or(&Value::Null, |attribute| &attribute["value"]["stringValue"])
+}
+
+fn stop(mut server: Server) {
+    server.signal(libc::SIGTERM);
+    assert!(server.wait_for_exit(Duration::from_secs(20)).is_some_and(|status| status.success()));
+}
+
+#[test]
+fn spans_are_exported_by_shutdown_at_the_latest() {
+    let (endpoint, exports) = collector();
+    let vars = [
+        ("OTEL_EXPORTER_OTLP_ENDPOINT", endpoint.as_str()),
+        ("OTEL_SERVICE_NAME", "users-api"),
+        ("APP_ENV", "staging"),
+        ("REQUIRE_AUTH", "false"),
+        // Longer than the test, only the shutdown sends them
+        ("OTEL_BSP_SCHEDULE_DELAY", "600000"),
+    ];
+    let server = Server::start_with("memory://", &vars);
+    assert_eq!(server.request_with_headers("GET", "/users/42", "X-Request-Id: otlp-1\r\n", None).0, 404);
+    stop(server);
+
+    let export = exports.recv_timeout(Duration::from_secs(10)).expect("nothing was exported");
+    let resource = &export["resourceSpans"][0]["resource"]["attributes"];
+    assert_eq!(attribute(resource, "service.name"), "users-api");
+    assert_eq!(attribute(resource, "deployment.environment"), "staging");
+    let spans: Vec<&Value> = export["resourceSpans"][0]["scopeSpans"][0]["spans"].as_array().unwrap().iter().collect();
+    let request = spans
+        .iter()
+        .find(|span| span["name"] == "request" && attribute(&span["attributes"], "request_id") == "otlp-1")
+        .unwrap_or_else(|| panic!("no request span: {:?}", spans));
+    assert_eq!(attribute(&request["attributes"], "method"), "GET");
+    assert_eq!(attribute(&request["attributes"], "route"), "GET /users/{id}");
+    assert_eq!(attribute(&request["attributes"], "db_calls"), "1");
+    assert!(attribute(&request["attributes"], "db_ms").as_str().unwrap().parse::<f64>().is_ok(), "{}", request);
+    assert_eq!((request["kind"].as_u64(), request.get("parentSpanId")), (Some(2), None));
+    assert_eq!(request["traceId"].as_str().unwrap().len(), 32);
+
+    let under = |name: &str| {
+        let span = spans.iter().find(|span| span["name"] == name && span["parentSpanId"] == request["spanId"]);
+        span.unwrap_or_else(|| panic!("no {} span under the request: {:?}", name, spans))
+    };
+    let db = under("db");
+    assert_eq!(db["traceId"], request["traceId"]);
+    assert_eq!(attribute(&db["attributes"], "statement"), "find");
+    assert_eq!(attribute(&db["attributes"], "table"), "users");
+    under("write");
+    let (start, end) = (db["startTimeUnixNano"].as_str().unwrap(), db["endTimeUnixNano"].as_str().unwrap());
+    assert!(start.parse::<u128>().unwrap() <= end.parse::<u128>().unwrap());
+}
+
+#[test]
+fn unsampled_traces_are_not_exported() {
+    let (endpoint, exports) = collector();
+    let vars = [
+        ("OTEL_EXPORTER_OTLP_ENDPOINT", endpoint.as_str()),
+        ("OTEL_TRACES_SAMPLER", "parentbased_traceidratio"),
+        ("OTEL_TRACES_SAMPLER_ARG", "0"),
+        ("OTEL_BSP_SCHEDULE_DELAY", "50"),
+    ];
+    let server = Server::start_with("memory://", &vars);
+    for _ in 0..5 {
+        assert_eq!(server.request("GET", "/users/42", None).0, 404);
+    }
+    stop(server);
+    assert!(exports.recv_timeout(Duration::from_millis(500)).is_err());
+}
+
+#[test]
+fn an_unknown_sampler_keeps_the_server_from_starting() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .env("DATABASE_URL", "memory://")
+        .env("OTEL_EXPORTER_OTLP_ENDPOINT", "http://127.0.0.1:4318")
+        .env("OTEL_TRACES_SAMPLER", "sometimes")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Invalid OTLP config: OTEL_TRACES_SAMPLER must be"), "{}", stderr);
+}
@@ -0,0 +1,91 @@
+// The accounts that aren't admins only have their own record: the record of
+// another is a 403, whether or not there is one, and GET /users is forbidden or,
+// with USER_LISTS=own, a list of just themselves. The keys have every record.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+
+const SECRET: &str = "an HS256 secret of at least 32 bytes";
+const ROOT: &str = "X-Api-Key: r00t\r\n";
+
+fn start(user_lists: &str) -> Server {
+    let vars = [("JWT_SECRET", SECRET), ("API_KEYS", "root:r00t"), ("USER_LISTS", user_lists)];
+    Server::start_with("memory://", &vars)
+}
+
+// The id of a new user, and the Authorization header of their token
+fn log_in_new_user(server: &Server) -> (i64, String) {
+    let email = unique_email("ada");
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}", "password": "correct horse battery"}}"#, email);
+    let (status, body) = server.request_with_headers("POST", "/users", ROOT, Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    let id = json(&body)["id"].as_i64().unwrap();
+    (id, log_in(server, &email))
+}
+
+fn log_in(server: &Server, email: &str) -> String {
+    let login = format!(r#"{{"email": "{}", "password": "correct horse battery"}}"#, email);
+    let (status, body) = server.request("POST", "/login", Some(&login));
+    assert_eq!(status, 200, "{}", body);
+    format!("Authorization: Bearer {}\r\n", json(&body)["token"].as_str().unwrap())
+}
+
+#[test]
+fn users_only_have_their_own_record() {
+    let server = start("forbidden");
+    let (ada, token) = log_in_new_user(&server);
+    let (alan, _) = log_in_new_user(&server);
+    let update = r#"{"name": "Ada King", "email": "ada.king@example.com"}"#;
+
+    let (status, body) = server.request_with_headers("GET", &format!("/users/{}", ada), &token, None);
+    assert_eq!(status, 200, "{}", body);
+    let (status, body) = server.request_with_headers("PUT", &format!("/users/{}", ada), &token, Some(update));
+    assert_eq!(status, 200, "{}", body);
+
+    // Another user, and no user at all, are told the same
+    for id in [alan, 2147483647] {
+        let (status, body) = server.request_with_headers("GET", &format!("/users/{}", id), &token, None);
+        assert_eq!(status, 403, "{}", body);
+        let body = json(&body);
+        assert_eq!(body["code"], "not_owner");
+        assert_eq!(body["detail"], format!("GET /users/{{id}} is only for the record of user {}", ada));
+    }
+    let target = format!("/users/{}", alan);
+    assert_eq!(server.request_with_headers("PUT", &target, &token, Some(update)).0, 403);
+    assert_eq!(server.request_with_headers("DELETE", &target, &token, None).0, 403);
+
+    let (status, body) = server.request_with_headers("GET", "/users", &token, None);
+    assert_eq!(status, 403, "{}", body);
+    assert_eq!(json(&body)["code"], "not_owner");
+
+    // Without credentials it is still a 401
+    assert_eq!(server.request("GET", &target, None).0, 401);
+
+    // The keys, and the users made admins, have every record
+    assert_eq!(server.request_with_headers("GET", &target, ROOT, None).0, 200);
+    assert_eq!(server.request_with_headers("GET", "/users", ROOT, None).0, 200);
+    let admin = r#"{"role": "admin"}"#;
+    let (status, body) = server.request_with_headers("PUT", &format!("/users/{}/role", ada), ROOT, Some(admin));
+    assert_eq!(status, 200, "{}", body);
+    let token = log_in(&server, "ada.king@example.com");
+    assert_eq!(server.request_with_headers("GET", &target, &token, None).0, 200);
+    assert_eq!(server.request_with_headers("GET", "/users", &token, None).0, 200);
+}
+
+#[test]
+fn users_can_list_just_themselves() {
+    let server = start("own");
+    let (ada, token) = log_in_new_user(&server);
+    log_in_new_user(&server);
+
+    let (status, body) = server.request_with_headers("GET", "/users", &token, None);
+    assert_eq!(status, 200, "{}", body);
+    let users = json(&body);
+    let ids: Vec<i64> = users.as_array().unwrap().iter().map(|user| user["id"].as_i64().unwrap()).collect();
+    assert_eq!(ids, [ada]);
+
+    let (status, body) = server.request_with_headers("GET", "/users", ROOT, None);
+    assert_eq!(status, 200, "{}", body);
+    assert!(json(&body).as_array().unwrap().len() >= 2, "{}", body);
+}
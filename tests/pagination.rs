@@ -0,0 +1,122 @@
+// The lists come in pages: PAGE_SIZE_DEFAULT users unless ?limit= asks for
+// others, at most PAGE_SIZE_MAX, a limit over it being lowered with
+// X-Limit-Clamped: true, and a Link to the next page with the limit the response
+// has. The history of an export is cut the same way, at EXPORT_PAGE_SIZE_MAX.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+use std::io::Read;
+use std::time::Duration;
+use std::{ env, fs, thread };
+
+struct Page {
+    head: String,
+    body: serde_json::Value,
+}
+
+impl Page {
+    fn get(server: &Server, target: &str) -> Page {
+        let mut response = String::new();
+        server.send("GET", target, "", None).read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        Page { head: format!("{}\r\n", head), body: json(body) }
+    }
+
+    fn link(&self, rel: &str) -> Option<&str> {
+        let links = self.head.split("\r\n").find_map(|header| header.strip_prefix("Link: ")).unwrap();
+        let link = links.split(", ").find_map(|link| link.strip_suffix(&format!(">; rel=\"{}\"", rel)))?;
+        link.strip_prefix('<')
+    }
+
+    fn clamped(&self) -> bool {
+        self.head.contains("\r\nX-Limit-Clamped: true\r\n")
+    }
+
+    fn len(&self) -> usize {
+        self.body.as_array().unwrap().len()
+    }
+}
+
+fn create_users(server: &Server, tag: &str, count: usize) {
+    for i in 0..count {
+        let user = format!(r#"{{"name": "{} {}", "email": "{}"}}"#, tag, i, unique_email("page"));
+        assert_eq!(server.request("POST", "/users", Some(&user)).0, 200);
+    }
+}
+
+#[test]
+fn a_limit_over_the_maximum_is_clamped_and_echoed_in_the_links() {
+    let server = Server::start_with("memory://", &[("PAGE_SIZE_DEFAULT", "3"), ("PAGE_SIZE_MAX", "4")]);
+    create_users(&server, "Paged", 10);
+
+    let first = Page::get(&server, "/users?name_contains=paged");
+    assert_eq!((first.len(), first.clamped()), (3, false));
+    assert_eq!(first.link("first"), Some("/users?name_contains=paged&limit=3"));
+    let last_id = first.body[2]["id"].as_i64().unwrap();
+    assert_eq!(first.link("next"), Some(format!("/users?name_contains=paged&limit=3&after_id={}", last_id).as_str()));
+
+    let clamped = Page::get(&server, "/users?limit=50&name_contains=paged");
+    assert_eq!((clamped.len(), clamped.clamped()), (4, true));
+    assert_eq!(clamped.link("first"), Some("/users?name_contains=paged&limit=4"));
+    let (mut next, mut names) = (clamped.link("next").map(str::to_owned), Vec::new());
+    names.extend(clamped.body.as_array().unwrap().iter().map(|user| user["name"].as_str().unwrap().to_owned()));
+    while let Some(target) = next {
+        let page = Page::get(&server, &target);
+        assert!(!page.clamped() && page.len() <= 4, "{}", page.head);
+        names.extend(page.body.as_array().unwrap().iter().map(|user| user["name"].as_str().unwrap().to_owned()));
+        next = page.link("next").map(str::to_owned);
+    }
+    assert_eq!(names, (0..10).map(|i| format!("Paged {}", i)).collect::<Vec<_>>());
+
+    for target in ["/users?limit=0", "/users?limit=-1", "/users?limit=lots", "/users?after_id=-3"] {
+        assert_eq!(server.request("GET", target, None).0, 400, "{}", target);
+    }
+}
+
+#[test]
+fn the_page_sizes_are_reloaded_on_sighup() {
+    let path = env::temp_dir().join(format!("pagination-test-{}.toml", std::process::id()));
+    fs::write(&path, "[page_size]\ndefault = 2\nmax = 5\n").unwrap();
+    let server = Server::start_with("memory://", &[("APP_ENV", "test"), ("CONFIG_FILE", path.to_str().unwrap())]);
+    create_users(&server, "Reloaded", 6);
+    assert_eq!(Page::get(&server, "/users").len(), 2);
+
+    fs::write(&path, "[page_size]\ndefault = 4\nmax = 5\n").unwrap();
+    server.signal(libc::SIGHUP);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(Page::get(&server, "/users").len(), 4);
+    assert!(Page::get(&server, "/users?limit=6").clamped());
+    fs::remove_file(&path).unwrap();
+}
+
+// The history of an export has a cap of its own, in pages like the lists
+#[test]
+fn exports_have_their_own_cap() {
+    let Some(database_url) = common::database_url("the Postgres suite") else {
+        return;
+    };
+    let vars = [("PAGE_SIZE_DEFAULT", "1"), ("PAGE_SIZE_MAX", "1"), ("EXPORT_PAGE_SIZE_MAX", "2")];
+    let server = Server::start_with(&database_url, &vars);
+    let user = format!(r#"{{"name": "Ada", "email": "{}"}}"#, unique_email("export"));
+    let (status, body) = server.request("POST", "/users", Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    let id = json(&body)["id"].as_i64().unwrap();
+    for name in ["Ada L", "Ada Lovelace"] {
+        let update = format!(r#"{{"name": "{}", "email": "{}"}}"#, name, unique_email("export"));
+        assert_eq!(server.request("PUT", &format!("/users/{}", id), Some(&update)).0, 200);
+    }
+
+    let export = Page::get(&server, &format!("/users/{}/export?limit=10", id));
+    assert!(export.clamped());
+    assert_eq!(export.body["history"].as_array().unwrap().len(), 2);
+    assert_eq!(export.body["user"]["name"], "Ada Lovelace");
+    let next = export.link("next").unwrap().to_owned();
+    assert!(next.starts_with(&format!("/users/{}/export?limit=2&after_id=", id)), "{}", next);
+    let rest = Page::get(&server, &next);
+    let event_types: Vec<&str> =
+        rest.body["history"].as_array().unwrap().iter().map(|event| event["event_type"].as_str().unwrap()).collect();
+    assert_eq!(event_types, ["user.updated"]);
+    assert!(rest.link("next").is_none());
+}
@@ -0,0 +1,54 @@
+// A handler that panics, here that of /admin/fail?with=panic, loses its request
+// only: the client is answered a 500 with the id the panic was logged under, with
+// its message and its backtrace, and the other connections are answered as usual.
+
+mod common;
+
+use common::{ json, Response, Server };
+use serde_json::Value;
+use std::io::{ Read, Write };
+use std::net::TcpStream;
+use std::sync::mpsc::Receiver;
+use std::time::{ Duration, Instant };
+
+// The panic logged, the log being JSON
+fn panic_event(lines: &Receiver<String>) -> Value {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let line = lines.recv_timeout(left).expect("no panic was logged");
+        match serde_json::from_str::<Value>(&line) {
+            Ok(event) if event["target"] == "panic" => return event,
+            _ => continue,
+        }
+    }
+}
+
+#[test]
+fn a_panic_is_answered_a_500_and_the_server_goes_on() {
+    let vars = [("APP_ENV", "test"), ("LOG_FORMAT", "json"), ("RUST_BACKTRACE", "1")];
+    let (server, lines) = Server::start_capturing("memory://", &vars);
+    // Open while the handler panics
+    let mut waiting = TcpStream::connect(server.addr()).unwrap();
+
+    let panicked = server.call("GET", "/admin/fail?with=panic&reason=loudly", "X-Request-Id: panic-1\r\n", None);
+    assert_eq!(panicked.status, 500, "{}", panicked.body);
+    assert_eq!(panicked.header("Content-Type"), Some("application/problem+json"));
+    assert_eq!(panicked.header("X-Content-Type-Options"), Some("nosniff"));
+    let body = json(&panicked.body);
+    assert_eq!((&body["status"], &body["error_id"]), (&Value::from(500), &Value::from("panic-1")), "{}", body);
+    assert!(!panicked.body.contains("loudly"), "{}", panicked.body);
+
+    let event = panic_event(&lines);
+    assert_eq!((&event["level"], &event["error_id"]), (&Value::from("ERROR"), &Value::from("panic-1")), "{}", event);
+    let message = event["message"].as_str().unwrap();
+    assert!(message.contains("Failing loudly") && message.contains("stack backtrace:"), "{}", message);
+
+    write!(waiting, "GET /livez HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    waiting.read_to_string(&mut response).unwrap();
+    assert_eq!(Response::parse(&response).status, 200, "{}", response);
+    assert_eq!(server.request("POST", "/users", Some(r#"{"name": "Ada", "email": "ada@example.com"}"#)).0, 200);
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    assert!(metrics.contains("\nrequests_panicked_total 1\n"), "{}", metrics);
+}
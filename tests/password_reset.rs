@@ -0,0 +1,152 @@
+// PASSWORD_RESET=true: POST /password-reset/request mails a link with a token,
+// printed by default, to the email if it is a user's, and POST
+// /password-reset/confirm sets the new password with it, once, ending the
+// sessions of the user. Needs TEST_DATABASE_URL.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+use std::io::Read;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+const ROOT: &str = "X-Api-Key: r00t\r\n";
+const URL: &str = "https://app.example.com/reset?token=";
+
+fn start(database_url: &str, vars: &[(&str, &str)]) -> (Server, Receiver<String>) {
+    let mut all = vec![
+        ("PASSWORD_RESET", "true"),
+        ("PASSWORD_RESET_URL", "https://app.example.com/reset?token={token}"),
+        ("SESSIONS", "true"),
+        ("API_KEYS", "root:r00t"),
+    ];
+    all.extend_from_slice(vars);
+    Server::start_capturing(database_url, &all)
+}
+
+// The token in the next link sent to the email
+fn token_sent(lines: &Receiver<String>, email: &str) -> String {
+    let prefix = format!("Mail to {} (Reset your password): Open {}", email, URL);
+    loop {
+        let line = lines.recv_timeout(Duration::from_secs(10)).expect("the link was printed");
+        if let Some((_, rest)) = line.split_once(&prefix) {
+            return rest.split_whitespace().next().unwrap().to_owned();
+        }
+    }
+}
+
+// The id and the email of a new user with the password
+fn create_user(server: &Server, password: &str) -> (i64, String) {
+    let email = unique_email("ada");
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}", "password": "{}"}}"#, email, password);
+    let (status, body) = server.request_with_headers("POST", "/users", ROOT, Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    (json(&body)["id"].as_i64().unwrap(), email)
+}
+
+// The cookie of a new session, if the password is right
+fn log_in(server: &Server, email: &str, password: &str) -> Option<String> {
+    let login = format!(r#"{{"email": "{}", "password": "{}"}}"#, email, password);
+    let mut response = String::new();
+    server.send("POST", "/session", "", Some(&login)).read_to_string(&mut response).unwrap();
+    let head = response.split("\r\n\r\n").next().unwrap();
+    let set_cookie = head.split("\r\n").find_map(|line| line.strip_prefix("Set-Cookie: "))?;
+    Some(set_cookie.split(';').next().unwrap().to_owned())
+}
+
+fn request_reset(server: &Server, email: &str) -> (u16, String) {
+    server.request("POST", "/password-reset/request", Some(&format!(r#"{{"email": "{}"}}"#, email)))
+}
+
+fn confirm(server: &Server, token: &str, new_password: &str) -> (u16, serde_json::Value) {
+    let confirmation = format!(r#"{{"token": "{}", "new_password": "{}"}}"#, token, new_password);
+    let (status, body) = server.request("POST", "/password-reset/confirm", Some(&confirmation));
+    (status, json(&body))
+}
+
+#[test]
+fn passwords_are_reset_once_and_the_sessions_end() {
+    let Some(database_url) = common::database_url("the password reset test") else {
+        return;
+    };
+    let (server, lines) = start(&database_url, &[]);
+    let (id, email) = create_user(&server, "correct horse battery");
+    let cookie = log_in(&server, &email, "correct horse battery").expect("logged in");
+    let headers = format!("Cookie: {}\r\n", cookie);
+    assert_eq!(server.request_with_headers("GET", &format!("/users/{}", id), &headers, None).0, 200);
+
+    // Whatever the case the email is given in
+    let (status, body) = request_reset(&server, &email.to_uppercase());
+    assert_eq!(status, 202, "{}", body);
+    let token = token_sent(&lines, &email);
+    assert_eq!(token.len(), 64);
+
+    let (status, body) = confirm(&server, &token, "short");
+    assert_eq!(status, 422, "{}", body);
+    let (status, body) = confirm(&server, &token, "tr0ub4dor and then some");
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!((&body["id"], &body["sessions_ended"]), (&id.into(), &1.into()));
+
+    // The session of the old password is over
+    let (status, body) = server.request_with_headers("GET", &format!("/users/{}", id), &headers, None);
+    assert_eq!(status, 401, "{}", body);
+    assert_eq!(json(&body)["code"], "invalid_session");
+    assert_eq!(log_in(&server, &email, "correct horse battery"), None);
+    assert!(log_in(&server, &email, "tr0ub4dor and then some").is_some());
+
+    let (status, body) = confirm(&server, &token, "yet another password");
+    assert_eq!((status, &body["code"]), (400, &"token_used".into()), "{}", body);
+    let (status, body) = confirm(&server, "not-a-token", "yet another password");
+    assert_eq!((status, &body["code"]), (400, &"invalid_token".into()), "{}", body);
+}
+
+#[test]
+fn emails_of_nobody_are_accepted_all_the_same() {
+    let Some(database_url) = common::database_url("the password reset test") else {
+        return;
+    };
+    let (server, lines) = start(&database_url, &[]);
+    let nobody = unique_email("nobody");
+    let (status, body) = request_reset(&server, &nobody);
+    let (_, expected) = request_reset(&server, &create_user(&server, "correct horse battery").1);
+    assert_eq!((status, body), (202, expected));
+
+    thread::sleep(Duration::from_millis(500));
+    assert!(lines.try_iter().all(|line| !line.contains(&nobody)));
+}
+
+#[test]
+fn tokens_expire() {
+    let Some(database_url) = common::database_url("the password reset test") else {
+        return;
+    };
+    let (server, lines) = start(&database_url, &[("PASSWORD_RESET_TOKEN_LIFETIME_SECS", "1")]);
+    let (_, email) = create_user(&server, "correct horse battery");
+    assert_eq!(request_reset(&server, &email).0, 202);
+    let token = token_sent(&lines, &email);
+    thread::sleep(Duration::from_millis(1500));
+
+    let (status, body) = confirm(&server, &token, "tr0ub4dor and then some");
+    assert_eq!((status, &body["code"]), (410, &"token_expired".into()), "{}", body);
+    assert!(log_in(&server, &email, "correct horse battery").is_some());
+}
+
+#[test]
+fn requests_are_limited_per_email_and_per_address() {
+    let Some(database_url) = common::database_url("the password reset test") else {
+        return;
+    };
+    let vars = [("PASSWORD_RESET_MAX_REQUESTS", "2"), ("PASSWORD_RESET_MAX_REQUESTS_PER_IP", "4")];
+    let (server, _lines) = start(&database_url, &vars);
+    let email = unique_email("ada");
+    assert_eq!(request_reset(&server, &email).0, 202);
+    assert_eq!(request_reset(&server, &email).0, 202);
+    let (status, body) = request_reset(&server, &email);
+    assert_eq!(status, 429, "{}", body);
+    assert_eq!(json(&body)["code"], "reset_rate_limited");
+
+    assert_eq!(request_reset(&server, &unique_email("alan")).0, 202);
+    assert_eq!(request_reset(&server, &unique_email("grace")).0, 202);
+    assert_eq!(request_reset(&server, &unique_email("edsger")).0, 429);
+}
@@ -0,0 +1,90 @@
+// The passwords of the users: set on POST /users, changed with the current one on
+// PUT /users/{id}/password, and never in a response. The suite runs on every
+// backend, on Postgres when TEST_DATABASE_URL is set.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+
+const SECRET: &str = "an HS256 secret of at least 32 bytes";
+const ROOT: &str = "X-Api-Key: r00t\r\n";
+
+fn start(database_url: &str) -> Server {
+    Server::start_with(database_url, &[("JWT_SECRET", SECRET), ("API_KEYS", "root:r00t")])
+}
+
+fn send(server: &Server, method: &str, target: &str, body: &str) -> (u16, serde_json::Value) {
+    let (status, body) = server.request_with_headers(method, target, ROOT, Some(body));
+    (status, json(&body))
+}
+
+fn log_in(server: &Server, email: &str, password: &str) -> u16 {
+    let login = format!(r#"{{"email": "{}", "password": "{}"}}"#, email, password);
+    server.request("POST", "/login", Some(&login)).0
+}
+
+fn passwords_suite(server: &Server) {
+    let email = unique_email("ada");
+    let user = |password: &str| {
+        format!(r#"{{"name": "Ada Lovelace", "email": "{}", "password": "{}"}}"#, email, password)
+    };
+
+    for (password, code) in [("short", "too_short"), (&*"x".repeat(129), "too_long")] {
+        let (status, body) = send(server, "POST", "/users", &user(password));
+        assert_eq!(status, 422, "{}", body);
+        assert_eq!(body["errors"][0]["field"], "password");
+        assert_eq!(body["errors"][0]["code"], code);
+    }
+    let (status, body) = send(server, "POST", "/users", &user("correct horse battery"));
+    assert_eq!(status, 200, "{}", body);
+    let id = body["id"].as_i64().unwrap();
+
+    // Not a trace of the hash in what the users are read with
+    for target in [format!("/users/{}", id), format!("/users?email={}", email)] {
+        let (status, body) = server.request_with_headers("GET", &target, ROOT, None);
+        assert_eq!(status, 200, "{}", body);
+        assert!(!body.contains("password") && !body.contains("$argon2"), "{}", body);
+    }
+
+    let (status, body) = send(server, "PUT", &format!("/users/{}", id), &user("battery staple"));
+    assert_eq!(status, 422, "{}", body);
+    assert_eq!(body["errors"][0]["code"], "not_allowed");
+
+    let target = format!("/users/{}/password", id);
+    let change = |current: &str, new: &str| {
+        format!(r#"{{"current_password": "{}", "new_password": "{}"}}"#, current, new)
+    };
+    let (status, body) = send(server, "PUT", &target, &change("battery staple", "horse battery staple"));
+    assert_eq!(status, 403, "{}", body);
+    assert_eq!(body["code"], "invalid_current_password");
+    let (status, body) = send(server, "PUT", &target, &change("correct horse battery", "short"));
+    assert_eq!(status, 422, "{}", body);
+    assert_eq!(body["errors"][0]["field"], "new_password");
+    let missing = change("a password", "another one");
+    let (status, _) = server.request_with_headers("PUT", "/users/2147483647/password", ROOT, Some(&missing));
+    assert_eq!(status, 404);
+
+    assert_eq!(log_in(server, &email, "correct horse battery"), 200);
+    let (status, body) = send(server, "PUT", &target, &change("correct horse battery", "horse battery staple"));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(log_in(server, &email, "correct horse battery"), 401);
+    assert_eq!(log_in(server, &email, "horse battery staple"), 200);
+}
+
+#[test]
+fn passwords_with_memory() {
+    passwords_suite(&start("memory://"));
+}
+
+#[test]
+fn passwords_with_sqlite() {
+    passwords_suite(&start("sqlite://:memory:"));
+}
+
+#[test]
+fn passwords_with_postgres() {
+    let Some(database_url) = common::database_url("the Postgres suite") else {
+        return;
+    };
+    passwords_suite(&start(&database_url));
+}
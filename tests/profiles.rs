@@ -0,0 +1,93 @@
+// The defaults APP_ENV changes: dev is the local one, talkative and open, prod
+// logs JSON, keeps its errors to itself, hides the admin endpoints and needs auth.
+// Without APP_ENV the admin endpoints stay hidden too. Each default is still
+// overridden by its own setting.
+
+mod common;
+
+use common::{ json, Server };
+use std::process::Command;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+// Set in prod, for the other defaults to be seen on their own
+const OPEN: (&str, &str) = ("REQUIRE_AUTH", "false");
+
+// The line logged at startup with the profile
+fn startup_line(lines: &Receiver<String>) -> String {
+    let line = lines.iter().find(|line| line.contains(" profile")).unwrap();
+    while lines.recv_timeout(Duration::from_millis(50)).is_ok() {}
+    line
+}
+
+#[test]
+fn the_profile_is_in_the_version_and_the_startup_logs() {
+    let (server, lines) = Server::start_capturing("memory://", &[("APP_ENV", "production"), OPEN]);
+    let (status, body) = server.request("GET", "/version", None);
+    assert_eq!(status, 200, "{}", body);
+    let version = json(&body);
+    assert_eq!((&version["app_env"], &version["profile"]), (&"production".into(), &"prod".into()));
+    assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+
+    // In JSON in prod, plain in dev
+    let logged: serde_json::Value = serde_json::from_str(&startup_line(&lines)).unwrap();
+    assert_eq!(logged["message"], "rust_postgresql_tutorial 0.1.0 with APP_ENV production, the prod profile");
+    let (_dev, lines) = Server::start_capturing("memory://", &[]);
+    let line = startup_line(&lines);
+    assert!(line.ends_with(" INFO rust_postgresql_tutorial: rust_postgresql_tutorial 0.1.0 with APP_ENV development, \
+        the dev profile"), "{}", line);
+
+    let (_json, lines) = Server::start_capturing("memory://", &[("LOG_FORMAT", "json")]);
+    assert!(startup_line(&lines).starts_with('{'));
+}
+
+#[test]
+fn prod_refuses_to_serve_without_auth() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .env("DATABASE_URL", "memory://")
+        .env("APP_ENV", "production")
+        .env("PORT", "0")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("APP_ENV production doesn't serve an API open to anyone"), "{}", stderr);
+
+    let with_keys = Server::start_with("memory://", &[("APP_ENV", "production"), ("API_KEYS", "ci:a-key-of-ci")]);
+    assert_eq!(with_keys.request("GET", "/users", None).0, 401);
+    assert_eq!(with_keys.request("GET", "/version", None).0, 200);
+    // Open in dev
+    let dev = Server::start("memory://");
+    assert_eq!(dev.request("GET", "/users", None).0, 200);
+}
+
+#[test]
+fn prod_hides_the_admin_endpoints_and_the_details_of_its_errors() {
+    let dev = Server::start_with("memory://", &[("APP_ENV", "development")]);
+    assert_eq!(dev.request("GET", "/debug/stats", None).0, 200);
+    let (status, body) = dev.request("GET", "/admin/fail?reason=on%20purpose", None);
+    assert_eq!((status, &json(&body)["detail"]), (500, &"Failing on purpose".into()), "{}", body);
+
+    let prod = Server::start_with("memory://", &[("APP_ENV", "production"), OPEN]);
+    assert_eq!(prod.request("GET", "/debug/stats", None).0, 404);
+    assert_eq!(prod.request("GET", "/admin/fail", None).0, 404);
+
+    let prod = Server::start_with("memory://", &[("APP_ENV", "production"), OPEN, ("ADMIN_ENDPOINTS", "true")]);
+    let (status, body) = prod.request("GET", "/admin/fail", None);
+    assert_eq!(status, 500);
+    assert!(!body.contains("Failing"), "{}", body);
+    assert!(json(&body)["error_id"].is_string(), "{}", body);
+
+    // Each default is its own setting
+    let vars = [("APP_ENV", "production"), OPEN, ("ADMIN_ENDPOINTS", "true"), ("ERROR_DETAILS", "true")];
+    let detailed = Server::start_with("memory://", &vars);
+    let (status, body) = detailed.request("GET", "/admin/fail", None);
+    assert_eq!((status, &json(&body)["detail"]), (500, &"Failing on purpose".into()), "{}", body);
+    let closed = Server::start_with("memory://", &[("APP_ENV", "development"), ("ADMIN_ENDPOINTS", "false")]);
+    assert_eq!(closed.request("GET", "/debug/stats", None).0, 404);
+
+    // Nothing destructive is reachable without APP_ENV
+    let unset = Server::start("memory://");
+    assert_eq!(unset.request("POST", "/admin/reset", None).0, 404);
+    assert_eq!(unset.request("GET", "/debug/stats", None).0, 404);
+}
@@ -0,0 +1,98 @@
+// Requests limited per client address, the one X-Forwarded-For gives when the
+// request comes through a trusted proxy, which the tests pretend to be, and per
+// API key once it authenticated.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+use std::io::Read;
+
+fn from(address: &str) -> String {
+    format!("X-Forwarded-For: {}\r\n", address)
+}
+
+// The whole response, headers included
+fn raw_request(server: &Server, method: &str, target: &str, headers: &str, body: Option<&str>) -> String {
+    let mut response = String::new();
+    server.send(method, target, headers, body).read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn a_burst_from_one_client_is_limited() {
+    let vars = [
+        ("TRUSTED_PROXIES", "127.0.0.1"),
+        ("RATE_LIMIT_PER_MINUTE", "60"),
+        ("RATE_LIMIT_BURST", "5"),
+        ("RATE_LIMIT_MUTATIONS_PER_MINUTE", "6"),
+        ("RATE_LIMIT_MUTATIONS_BURST", "2"),
+    ];
+    let server = Server::start_with("memory://", &vars);
+
+    for remaining in (0..5).rev() {
+        let response = raw_request(&server, "GET", "/users", &from("203.0.113.7"), None);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\nX-RateLimit-Limit: 5\r\n"), "{}", response);
+        assert!(response.contains(&format!("\r\nX-RateLimit-Remaining: {}\r\n", remaining)), "{}", response);
+    }
+    let response = raw_request(&server, "GET", "/users", &from("203.0.113.7"), None);
+    assert!(response.starts_with("HTTP/1.1 429 TOO MANY REQUESTS\r\n"), "{}", response);
+    assert!(response.contains("\r\nRetry-After: 1\r\n"), "{}", response);
+    assert!(response.contains("\r\nX-RateLimit-Remaining: 0\r\n"), "{}", response);
+    assert!(response.contains("\r\nX-RateLimit-Reset: 5\r\n"), "{}", response);
+    let (status, body) = server.request_with_headers("GET", "/users", &from("203.0.113.7"), None);
+    assert_eq!(status, 429);
+    assert_eq!(json(&body)["error"]["code"], "rate_limited");
+
+    // Another client, and the probes, aren't held back
+    assert_eq!(server.request_with_headers("GET", "/users", &from("203.0.113.8"), None).0, 200);
+    assert_eq!(server.request_with_headers("GET", "/health", &from("203.0.113.7"), None).0, 200);
+    assert_eq!(server.request_with_headers("GET", "/metrics", &from("203.0.113.7"), None).0, 200);
+
+    // Mutations have a stricter limit of their own
+    let client = from("203.0.113.9");
+    for _ in 0..2 {
+        let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}"}}"#, unique_email("ada"));
+        assert_eq!(server.request_with_headers("POST", "/users", &client, Some(&user)).0, 200);
+    }
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}"}}"#, unique_email("ada"));
+    let response = raw_request(&server, "POST", "/users", &client, Some(&user));
+    assert!(response.starts_with("HTTP/1.1 429 TOO MANY REQUESTS\r\n"), "{}", response);
+    assert!(response.contains("\r\nRetry-After: 10\r\n"), "{}", response);
+    assert_eq!(server.request_with_headers("GET", "/users", &client, None).0, 200);
+}
+
+#[test]
+fn forwarded_for_is_ignored_without_a_trusted_proxy() {
+    let server = Server::start_with("memory://", &[("RATE_LIMIT_PER_MINUTE", "60"), ("RATE_LIMIT_BURST", "1")]);
+    assert_eq!(server.request_with_headers("GET", "/users", &from("203.0.113.7"), None).0, 200);
+    // Still the same client
+    assert_eq!(server.request_with_headers("GET", "/users", &from("203.0.113.8"), None).0, 429);
+}
+
+#[test]
+fn the_requests_of_a_key_are_limited_from_every_address() {
+    let vars = [
+        ("TRUSTED_PROXIES", "127.0.0.1"),
+        ("API_KEYS", "ci:c,deploy:d"),
+        ("RATE_LIMIT_PER_KEY_PER_MINUTE", "60"),
+        ("RATE_LIMIT_PER_KEY_BURST", "2"),
+    ];
+    let server = Server::start_with("memory://", &vars);
+    let with_key = |key: &str, address: &str| format!("X-Api-Key: {}\r\n{}", key, from(address));
+
+    for (address, remaining) in [("203.0.113.7", 1), ("203.0.113.8", 0)] {
+        let response = raw_request(&server, "GET", "/users", &with_key("c", address), None);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains(&format!("\r\nX-RateLimit-Remaining: {}\r\n", remaining)), "{}", response);
+    }
+    let (status, body) = server.request_with_headers("GET", "/users", &with_key("c", "203.0.113.9"), None);
+    assert_eq!(status, 429, "{}", body);
+    assert_eq!(json(&body)["error"]["code"], "rate_limited");
+
+    // The other keys, and the requests refused before a key is known, aren't counted
+    assert_eq!(server.request_with_headers("GET", "/users", &with_key("d", "203.0.113.7"), None).0, 200);
+    for _ in 0..3 {
+        assert_eq!(server.request_with_headers("GET", "/users", &with_key("wrong", "203.0.113.7"), None).0, 401);
+    }
+}
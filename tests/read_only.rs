@@ -0,0 +1,66 @@
+// READ_ONLY=true, or POST /admin/read-only: the writes are answered 403 before
+// anything is done with them, the reads are served as usual.
+
+mod common;
+
+use common::{ json, Server };
+
+const ADA: &str = r#"{"name": "Ada Lovelace", "email": "ada@example.com"}"#;
+
+const WRITES: [(&str, &str); 7] = [
+    ("POST", "/users"),
+    ("POST", "/users/validate"),
+    ("PUT", "/users/1"),
+    ("PATCH", "/users/1"),
+    ("DELETE", "/users/1"),
+    ("POST", "/users/1/anonymize"),
+    ("POST", "/admin/seed"),
+];
+
+fn assert_rejected(server: &Server, method: &str, target: &str) {
+    let (status, body) = server.request(method, target, Some(ADA));
+    assert_eq!(status, 403, "{} {}: {}", method, target, body);
+    let body = json(&body);
+    assert_eq!(body["status"], 403);
+    assert!(body["detail"].as_str().unwrap().contains("read-only"), "{}", body);
+}
+
+fn assert_reads_served(server: &Server) {
+    for target in ["/users", "/users/1", "/readyz"] {
+        let (status, body) = server.request("GET", target, None);
+        assert_eq!(status, 200, "GET {}: {}", target, body);
+    }
+}
+
+#[test]
+fn writes_are_rejected_and_reads_served() {
+    let server = Server::start_with("memory://", &[("APP_ENV", "test")]);
+    assert_eq!(server.request("POST", "/users", Some(ADA)).0, 200);
+
+    let (status, body) = server.request("POST", "/admin/read-only", Some(r#"{"enabled": true}"#));
+    assert_eq!(status, 200, "{}", body);
+    for (method, target) in WRITES {
+        assert_rejected(&server, method, target);
+    }
+    assert_reads_served(&server);
+    let (_, health) = server.request("GET", "/health", None);
+    assert_eq!(json(&health)["read_only"], true);
+    assert_eq!(json(&server.request("GET", "/admin/read-only", None).1)["enabled"], true);
+
+    assert_eq!(server.request("POST", "/admin/read-only", Some(r#"{"enabled": false}"#)).0, 200);
+    let renamed = r#"{"name": "Ada King", "email": "ada@example.com"}"#;
+    assert_eq!(server.request("PUT", "/users/1", Some(renamed)).0, 200);
+    assert_eq!(json(&server.request("GET", "/health", None).1)["read_only"], false);
+}
+
+#[test]
+fn read_only_at_startup_serves_the_exempt_routes() {
+    let server = Server::start_with(
+        "memory://",
+        &[("READ_ONLY", "true"), ("READ_ONLY_EXEMPT", "POST /users/validate")],
+    );
+    assert_rejected(&server, "POST", "/users");
+    let (status, body) = server.request("POST", "/users/validate", Some(ADA));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&server.request("GET", "/health", None).1)["read_only"], true);
+}
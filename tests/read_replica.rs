@@ -0,0 +1,76 @@
+// Reads going to the replica of DATABASE_READ_URL. The replica is a second
+// database here, told apart from the primary by the rows inserted into it
+// directly. Runs when TEST_DATABASE_URL and TEST_READ_DATABASE_URL are set.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+use postgres::{ Client, NoTls };
+use std::env;
+
+const PRIMARY: &str = "X-Read-Primary: true\r\n";
+
+fn users_with_email(server: &Server, email: &str, headers: &str) -> usize {
+    let (status, body) = server.request_with_headers("GET", &format!("/users?email={}", email), headers, None);
+    assert_eq!(status, 200, "{}", body);
+    json(&body).as_array().unwrap().len()
+}
+
+#[test]
+fn reads_go_to_the_replica() {
+    let (Ok(database_url), Ok(read_url)) = (env::var("TEST_DATABASE_URL"), env::var("TEST_READ_DATABASE_URL")) else {
+        eprintln!("TEST_DATABASE_URL or TEST_READ_DATABASE_URL is not set, skipping the replica test");
+        return;
+    };
+
+    let mut replica = Client::connect(&read_url, NoTls).unwrap();
+    replica
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS users (
+                id SERIAL PRIMARY KEY,
+                name VARCHAR NOT NULL,
+                email VARCHAR UNIQUE NOT NULL,
+                anonymized_at TIMESTAMPTZ
+            );
+            ALTER TABLE users ADD COLUMN IF NOT EXISTS tenant_id VARCHAR NOT NULL DEFAULT 'default';
+            ALTER TABLE users ADD COLUMN IF NOT EXISTS email_hash VARCHAR;
+            ALTER TABLE users ADD COLUMN IF NOT EXISTS verified_at TIMESTAMPTZ;"
+        )
+        .unwrap();
+    let replica_email = unique_email("replica");
+    replica.execute("INSERT INTO users (name, email) VALUES ('Replica', $1)", &[&replica_email]).unwrap();
+
+    let server = Server::start_with(&database_url, &[("DATABASE_READ_URL", &read_url)]);
+    assert_eq!(users_with_email(&server, &replica_email, ""), 1);
+    assert_eq!(users_with_email(&server, &replica_email, PRIMARY), 0);
+
+    // Writes go to the primary, which only X-Read-Primary reads see
+    let primary_email = unique_email("primary");
+    let (status, body) = server.request(
+        "POST",
+        "/users",
+        Some(&format!(r#"{{"name": "Primary", "email": "{}"}}"#, primary_email))
+    );
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(users_with_email(&server, &primary_email, ""), 0);
+    assert_eq!(users_with_email(&server, &primary_email, PRIMARY), 1);
+}
+
+#[test]
+fn reads_fall_back_to_the_primary_without_a_replica() {
+    let Some(database_url) = common::database_url("the replica fallback test") else {
+        return;
+    };
+
+    // Nothing listens on port 1
+    let unreachable = "postgres://postgres@127.0.0.1:1/postgres";
+    let server = Server::start_with(&database_url, &[("DATABASE_READ_URL", unreachable)]);
+    let email = unique_email("fallback");
+    let (status, body) = server.request(
+        "POST",
+        "/users",
+        Some(&format!(r#"{{"name": "Fallback", "email": "{}"}}"#, email))
+    );
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(users_with_email(&server, &email, ""), 1);
+}
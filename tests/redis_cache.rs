@@ -0,0 +1,220 @@
+// CACHE_URL: the cache of GET /users/{id} kept in Redis, shared by the instances
+// using it. The tests run against a stand-in speaking the commands the server
+// sends, GET, SET with PX, DEL and SCAN.
+
+mod common;
+
+use common::{ json, Server };
+use std::collections::HashMap;
+use std::io::{ BufRead, BufReader, Read, Write };
+use std::net::{ TcpListener, TcpStream };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+type Values = Arc<Mutex<HashMap<Vec<u8>, (Vec<u8>, Instant)>>>;
+
+struct FakeRedis {
+    port: u16,
+    values: Values,
+    // The connections are closed as soon as they send something once set
+    down: Arc<AtomicBool>,
+}
+
+impl FakeRedis {
+    fn start() -> FakeRedis {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let redis = FakeRedis {
+            port: listener.local_addr().unwrap().port(),
+            values: Values::default(),
+            down: Arc::default(),
+        };
+        let (values, down) = (redis.values.clone(), redis.down.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (values, down) = (values.clone(), down.clone());
+                thread::spawn(move || serve(stream, &values, &down));
+            }
+        });
+        redis
+    }
+
+    fn url(&self) -> String {
+        format!("redis://127.0.0.1:{}", self.port)
+    }
+
+    fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> =
+            self.values.lock().unwrap().keys().map(|key| String::from_utf8_lossy(key).into_owned()).collect();
+        keys.sort();
+        keys
+    }
+}
+
+fn serve(stream: TcpStream, values: &Values, down: &AtomicBool) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    while let Some(command) = read_command(&mut reader) {
+        if down.load(Ordering::Relaxed) {
+            return;
+        }
+        let args: Vec<&[u8]> = command.iter().map(Vec::as_slice).collect();
+        let reply = match args.as_slice() {
+            [b"GET", key] => match values.lock().unwrap().get(*key) {
+                Some((value, expires)) if Instant::now() < *expires => bulk(value),
+                _ => b"$-1\r\n".to_vec(),
+            },
+            [b"SET", key, value, b"PX", ttl] => {
+                let ttl = Duration::from_millis(String::from_utf8_lossy(ttl).parse().unwrap());
+                values.lock().unwrap().insert(key.to_vec(), (value.to_vec(), Instant::now() + ttl));
+                b"+OK\r\n".to_vec()
+            }
+            [b"DEL", keys @ ..] => {
+                let mut values = values.lock().unwrap();
+                let deleted = keys.iter().filter(|key| values.remove(**key).is_some()).count();
+                format!(":{}\r\n", deleted).into_bytes()
+            }
+            [b"SCAN", b"0", b"MATCH", pattern, b"COUNT", _] => {
+                let prefix = pattern.strip_suffix(b"*").unwrap();
+                let values = values.lock().unwrap();
+                let keys: Vec<&Vec<u8>> = values.keys().filter(|key| key.starts_with(prefix)).collect();
+                let mut reply = format!("*2\r\n$1\r\n0\r\n*{}\r\n", keys.len()).into_bytes();
+                keys.into_iter().for_each(|key| reply.extend(bulk(key)));
+                reply
+            }
+            _ => b"-ERR unknown command\r\n".to_vec(),
+        };
+        if stream.write_all(&reply).is_err() {
+            return;
+        }
+    }
+}
+
+// The arguments of a command, an array of bulk strings
+fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<Vec<u8>>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok().filter(|size| *size > 0)?;
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).ok()?;
+        let length: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; length + 2];
+        reader.read_exact(&mut arg).ok()?;
+        arg.truncate(length);
+        args.push(arg);
+    }
+    Some(args)
+}
+
+fn bulk(value: &[u8]) -> Vec<u8> {
+    [format!("${}\r\n", value.len()).as_bytes(), value, b"\r\n"].concat()
+}
+
+// The status line and headers, and the body
+fn get(server: &Server, target: &str) -> (String, String) {
+    let mut response = String::new();
+    server.send("GET", target, "", None).read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.to_owned(), body.to_owned())
+}
+
+fn create(server: &Server, name: &str) -> String {
+    let user = format!(r#"{{"name": "{}", "email": "{}@example.com"}}"#, name, name.to_lowercase().replace(' ', "."));
+    let (status, body) = server.request("POST", "/users", Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    format!("/users/{}", json(&body)["id"])
+}
+
+#[test]
+fn instances_share_the_cache_and_its_invalidations() {
+    let redis = FakeRedis::start();
+    let vars = [("USER_CACHE_TTL_MS", "60000"), ("CACHE_URL", &redis.url())];
+    let (first, second) = (Server::start_with("memory://", &vars), Server::start_with("memory://", &vars));
+    // Each has a repository of its own, with a user 1 of its own
+    let path = create(&first, "Ada Lovelace");
+    assert_eq!(create(&second, "Alan Turing"), path);
+
+    let (head, body) = get(&first, &path);
+    assert!(head.contains("\r\nX-Cache: MISS"), "{}", head);
+    assert_eq!(redis.keys(), ["rust-api:user:default:1"]);
+    let (head, cached) = get(&first, &path);
+    assert!(head.contains("\r\nX-Cache: HIT"), "{}", head);
+    assert_eq!(cached, body);
+    let etag = head.lines().find(|line| line.starts_with("ETag: ")).unwrap().to_owned();
+
+    // What the first instance cached, the second answers with
+    let (head, cached) = get(&second, &path);
+    assert!(head.contains("\r\nX-Cache: HIT"), "{}", head);
+    assert!(head.contains(&etag), "{}", head);
+    assert_eq!(json(&cached)["name"], "Ada Lovelace");
+
+    // And the writes of the second invalidate it for the first
+    let renamed = r#"{"name": "Alan King", "email": "alan.king@example.com"}"#;
+    assert_eq!(second.request("PUT", &path, Some(renamed)).0, 200);
+    assert!(redis.keys().is_empty());
+    let (head, body) = get(&first, &path);
+    assert!(head.contains("\r\nX-Cache: MISS"), "{}", head);
+    assert_eq!(json(&body)["name"], "Ada Lovelace");
+}
+
+#[test]
+fn users_are_read_from_the_repository_while_redis_is_down() {
+    let redis = FakeRedis::start();
+    let server = Server::start_with("memory://", &[("USER_CACHE_TTL_MS", "60000"), ("CACHE_URL", &redis.url())]);
+    let path = create(&server, "Ada Lovelace");
+    get(&server, &path);
+    assert!(get(&server, &path).0.contains("\r\nX-Cache: HIT"));
+
+    redis.down.store(true, Ordering::Relaxed);
+    for _ in 0..3 {
+        let started = Instant::now();
+        let (head, body) = get(&server, &path);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("\r\nX-Cache: MISS"), "{}", head);
+        assert_eq!(json(&body)["name"], "Ada Lovelace");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
+
+#[test]
+fn an_unreachable_redis_is_no_cache() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let url = format!("redis://127.0.0.1:{}", port);
+    let server = Server::start_with("memory://", &[("USER_CACHE_TTL_MS", "60000"), ("CACHE_URL", &url)]);
+    let path = create(&server, "Ada Lovelace");
+
+    for _ in 0..2 {
+        let (head, body) = get(&server, &path);
+        assert!(head.contains("\r\nX-Cache: MISS"), "{}", head);
+        assert_eq!(json(&body)["name"], "Ada Lovelace");
+    }
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    assert!(metrics.contains("\nuser_cache_misses_total 2\n"), "{}", metrics);
+    assert!(!metrics.contains("user_cache_entries"), "{}", metrics);
+}
+
+#[test]
+fn the_invalidations_while_redis_is_down_are_sent_once_it_is_back() {
+    let redis = FakeRedis::start();
+    let vars = [("USER_CACHE_TTL_MS", "60000"), ("CACHE_URL", &redis.url())];
+    let (first, second) = (Server::start_with("memory://", &vars), Server::start_with("memory://", &vars));
+    let path = create(&first, "Ada Lovelace");
+    create(&second, "Alan Turing");
+    get(&first, &path);
+    assert_eq!(redis.keys(), ["rust-api:user:default:1"]);
+
+    redis.down.store(true, Ordering::Relaxed);
+    let renamed = r#"{"name": "Alan King", "email": "alan.king@example.com"}"#;
+    assert_eq!(second.request("PUT", &path, Some(renamed)).0, 200);
+    redis.down.store(false, Ordering::Relaxed);
+
+    // Sent by the second instance, with nothing else asked of it
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !redis.keys().is_empty() {
+        assert!(Instant::now() < deadline, "still cached: {:?}", redis.keys());
+        thread::sleep(Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,116 @@
+// REFRESH_TOKENS=true: POST /login also answers a refresh token, which POST
+// /token/refresh trades, once, for a new access token and the next refresh token.
+// A refresh token used twice revokes all those of its login, POST /logout revokes
+// one and DELETE /admin/users/{id}/refresh-tokens all those of a user. Needs
+// TEST_DATABASE_URL.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+
+const SECRET: &str = "an HS256 secret of at least 32 bytes";
+const ROOT: &str = "X-Api-Key: r00t\r\n";
+
+fn start(database_url: &str) -> Server {
+    let vars = [
+        ("JWT_SECRET", SECRET),
+        ("REFRESH_TOKENS", "true"),
+        ("API_KEYS", "root:r00t"),
+        ("ADMIN_ENDPOINTS", "true"),
+    ];
+    Server::start_with(database_url, &vars)
+}
+
+// The id and the email of a new user
+fn create_user(server: &Server) -> (i64, String) {
+    let email = unique_email("ada");
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}", "password": "correct horse battery"}}"#, email);
+    let (status, body) = server.request_with_headers("POST", "/users", ROOT, Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    (json(&body)["id"].as_i64().unwrap(), email)
+}
+
+// The refresh token of a login
+fn log_in(server: &Server, email: &str) -> String {
+    let login = format!(r#"{{"email": "{}", "password": "correct horse battery"}}"#, email);
+    let (status, body) = server.request("POST", "/login", Some(&login));
+    assert_eq!(status, 200, "{}", body);
+    let body = json(&body);
+    assert!(body["token"].is_string() && body["refresh_expires_in"] == 2592000, "{}", body);
+    body["refresh_token"].as_str().unwrap().to_owned()
+}
+
+fn refresh(server: &Server, token: &str) -> (u16, serde_json::Value) {
+    let refresh = format!(r#"{{"refresh_token": "{}"}}"#, token);
+    let (status, body) = server.request("POST", "/token/refresh", Some(&refresh));
+    (status, json(&body))
+}
+
+// The next refresh token, the access token along with it good for the user
+fn refreshed(server: &Server, id: i64, token: &str) -> String {
+    let (status, body) = refresh(server, token);
+    assert_eq!(status, 200, "{}", body);
+    let headers = format!("Authorization: Bearer {}\r\n", body["token"].as_str().unwrap());
+    assert_eq!(server.request_with_headers("GET", &format!("/users/{}", id), &headers, None).0, 200);
+    body["refresh_token"].as_str().unwrap().to_owned()
+}
+
+fn refused(server: &Server, token: &str) -> String {
+    let (status, body) = refresh(server, token);
+    assert_eq!(status, 401, "{}", body);
+    body["code"].as_str().unwrap().to_owned()
+}
+
+#[test]
+fn refresh_tokens_rotate_and_a_reused_one_revokes_its_login() {
+    let Some(database_url) = common::database_url("the refresh token test") else {
+        return;
+    };
+    let server = start(&database_url);
+    let (id, email) = create_user(&server);
+    let first = log_in(&server, &email);
+    let second = refreshed(&server, id, &first);
+    let third = refreshed(&server, id, &second);
+    assert_ne!(second, third);
+
+    // The other logins aren't touched
+    let other = log_in(&server, &email);
+    assert_eq!(refused(&server, &first), "refresh_token_reused");
+    assert_eq!(refused(&server, &third), "refresh_token_revoked");
+    refreshed(&server, id, &other);
+
+    assert_eq!(refused(&server, "not-a-token"), "invalid_refresh_token");
+    assert_eq!(refresh(&server, "").0 / 100, 4);
+}
+
+#[test]
+fn logging_out_and_the_admins_revoke_refresh_tokens() {
+    let Some(database_url) = common::database_url("the refresh token test") else {
+        return;
+    };
+    let server = start(&database_url);
+    let (id, email) = create_user(&server);
+
+    let token = log_in(&server, &email);
+    let logout = format!(r#"{{"refresh_token": "{}"}}"#, token);
+    let (status, body) = server.request("POST", "/logout", Some(&logout));
+    assert_eq!((status, json(&body)["logged_out"].as_bool()), (200, Some(true)), "{}", body);
+    assert_eq!(refused(&server, &token), "refresh_token_revoked");
+
+    let tokens = [log_in(&server, &email), log_in(&server, &email)];
+    let target = format!("/admin/users/{}/refresh-tokens", id);
+    assert_eq!(server.request("DELETE", &target, None).0, 401);
+    let (status, body) = server.request_with_headers("DELETE", &target, ROOT, None);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body), serde_json::json!({ "id": id, "revoked": 2 }));
+    for token in tokens {
+        assert_eq!(refused(&server, &token), "refresh_token_revoked");
+    }
+}
+
+#[test]
+fn refresh_tokens_need_the_switch() {
+    let server = Server::start_with("memory://", &[("JWT_SECRET", SECRET)]);
+    let (status, _) = server.request("POST", "/token/refresh", Some(r#"{"refresh_token": "abc"}"#));
+    assert_eq!(status, 501);
+}
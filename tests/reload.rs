@@ -0,0 +1,49 @@
+// The runtime settings read again on SIGHUP, from the environment and the config
+// file, without a restart: those that aren't valid leave the ones before in place.
+
+mod common;
+
+use common::{ json, Server };
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+use std::{ env, fs, iter, thread };
+
+// The first line logged that has the text, waiting a bit for it
+fn logged(lines: &Receiver<String>, text: &str) -> Option<String> {
+    iter::from_fn(|| lines.recv_timeout(Duration::from_secs(5)).ok()).find(|line| line.contains(text))
+}
+
+fn generation(server: &Server) -> u64 {
+    let (_, body) = server.request("GET", "/debug/stats", None);
+    json(&body)["config"]["generation"].as_u64().unwrap()
+}
+
+#[test]
+fn a_sighup_applies_the_new_rate_limit() {
+    let path = env::temp_dir().join(format!("reload-test-{}.toml", std::process::id()));
+    fs::write(&path, "[rate_limit]\nper_minute = 600\nburst = 100\n").unwrap();
+    let vars = [("APP_ENV", "test"), ("CONFIG_FILE", path.to_str().unwrap())];
+    let (server, lines) = Server::start_capturing("memory://", &vars);
+    for _ in 0..3 {
+        assert_eq!(server.request("GET", "/users", None).0, 200);
+    }
+    assert_eq!(generation(&server), 1);
+
+    fs::write(&path, "[rate_limit]\nper_minute = 1\nburst = 1\n").unwrap();
+    server.signal(libc::SIGHUP);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(server.request("GET", "/users", None).0, 200);
+    assert_eq!(server.request("GET", "/users", None).0, 429);
+    assert_eq!(generation(&server), 2);
+    assert!(logged(&lines, "RATE_LIMIT_BURST 100 -> 1, generation 2").is_some());
+
+    // Not valid, the limit stays
+    fs::write(&path, "[rate_limit]\nper_minute = \"many\"\n").unwrap();
+    server.signal(libc::SIGHUP);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(server.request("GET", "/users", None).0, 429);
+    assert_eq!(generation(&server), 2);
+    let error = logged(&lines, "Keeping the settings of generation 2").unwrap();
+    assert!(error.contains("RATE_LIMIT_PER_MINUTE must be a number"), "{}", error);
+    fs::remove_file(&path).unwrap();
+}
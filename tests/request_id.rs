@@ -0,0 +1,68 @@
+// Every request has an id, the X-Request-Id it was sent with when that is safe to
+// log, else a new one: it is in the X-Request-Id of the response and in the
+// lines logged while answering it.
+
+mod common;
+
+use common::Server;
+use serde_json::Value;
+use std::io::Read;
+use std::sync::mpsc::Receiver;
+use std::time::{ Duration, Instant };
+
+// The head and the body of the response, what was read of it when the server
+// closed the connection on what it didn't read of the request
+fn response(server: &Server, target: &str, headers: &str) -> (String, String) {
+    let mut stream = server.send("GET", target, headers, None);
+    let (mut response, mut buffer) = (Vec::new(), [0; 1024]);
+    while let Ok(read @ 1..) = stream.read(&mut buffer) {
+        response.extend_from_slice(&buffer[..read]);
+    }
+    let response = String::from_utf8(response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or_else(|| panic!("no response: {}", response));
+    (head.to_owned(), body.to_owned())
+}
+
+fn request_id(head: &str) -> &str {
+    head.lines().find_map(|line| line.strip_prefix("X-Request-Id: ")).unwrap_or_else(|| panic!("no id in {}", head))
+}
+
+// The request_id of the access event of the path, the log being JSON
+fn logged_id(lines: &Receiver<String>, path: &str) -> String {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let line = lines.recv_timeout(left).unwrap_or_else(|_| panic!("{} wasn't logged", path));
+        let event: Value = serde_json::from_str(&line).unwrap_or_else(|e| panic!("{}: {}", e, line));
+        if event["target"] == "access" && event["path"] == path {
+            return event["request_id"].as_str().unwrap_or_else(|| panic!("no request_id: {}", event)).to_owned();
+        }
+    }
+}
+
+#[test]
+fn an_id_is_generated_unless_one_was_sent() {
+    let (server, lines) = Server::start_capturing("memory://", &[("LOG_FORMAT", "json")]);
+
+    let (head, _) = response(&server, "/users/1", "");
+    let generated = request_id(&head);
+    let groups: Vec<usize> = generated.split('-').map(str::len).collect();
+    assert_eq!(groups, [8, 4, 4, 4, 12], "{}", generated);
+    assert_eq!(logged_id(&lines, "/users/1"), generated);
+    let (head, _) = response(&server, "/users/1", "");
+    assert_ne!(request_id(&head), generated);
+
+    let (head, _) = response(&server, "/users/2", "X-Request-Id: 01HZX3K8Q4W9V6T2M5N7P0R3S8\r\n");
+    assert_eq!(request_id(&head), "01HZX3K8Q4W9V6T2M5N7P0R3S8");
+    assert_eq!(logged_id(&lines, "/users/2"), "01HZX3K8Q4W9V6T2M5N7P0R3S8");
+}
+
+#[test]
+fn a_hostile_id_is_replaced() {
+    let (server, lines) = Server::start_capturing("memory://", &[("LOG_FORMAT", "json")]);
+    let hostile = "\\\"".repeat(5 * 1024);
+    let (head, _) = response(&server, "/users/3", &format!("X-Request-Id: {}\r\n", hostile));
+    let replaced = request_id(&head);
+    assert_eq!(replaced.len(), 36, "{}", replaced);
+    assert_eq!(logged_id(&lines, "/users/3"), replaced);
+}
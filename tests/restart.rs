@@ -0,0 +1,51 @@
+// REUSE_PORT=true: a new version started on the port of the old one shares it
+// until the old one is sent SIGTERM, then takes every new connection while the old
+// one drains, so that no request fails during the restart.
+
+mod common;
+
+use common::Server;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+#[test]
+fn requests_succeed_while_the_old_server_shuts_down() {
+    let mut old = Server::start_with("memory://", &[("REUSE_PORT", "true")]);
+    let port = old.port().to_string();
+    let user = r#"{"name": "Ada Lovelace", "email": "ada@example.com"}"#;
+    assert_eq!(old.request("POST", "/users", Some(user)).0, 200);
+
+    // Each has a repository of its own, the user only exists in the old one: the
+    // new one is listening once it answers 404
+    let new = Server::start_with("memory://", &[("REUSE_PORT", "true"), ("PORT", &port)]);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while new.request("GET", "/users/1", None).0 != 404 {
+        assert!(Instant::now() < deadline, "the new server didn't start listening");
+    }
+
+    let done = AtomicBool::new(false);
+    let statuses = thread::scope(|scope| {
+        // Each request on a connection of its own, the port is all it uses
+        let client = scope.spawn(|| {
+            let mut statuses = Vec::new();
+            while !done.load(Ordering::Relaxed) {
+                statuses.push(new.request("GET", "/health", None).0);
+            }
+            statuses
+        });
+
+        thread::sleep(Duration::from_millis(200));
+        old.signal(libc::SIGTERM);
+        let status = old.wait_for_exit(Duration::from_secs(5)).expect("the old server didn't exit");
+        assert!(status.success(), "{}", status);
+        thread::sleep(Duration::from_millis(200));
+        done.store(true, Ordering::Relaxed);
+        client.join().unwrap()
+    });
+
+    assert!(statuses.len() > 10, "{:?}", statuses);
+    assert!(statuses.iter().all(|status| *status == 200), "{:?}", statuses);
+    // Only the new one is left
+    assert_eq!(new.request("GET", "/users/1", None).0, 404);
+}
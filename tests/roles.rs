@@ -0,0 +1,95 @@
+// The roles: a reader only reads, a writer changes the users too, and an admin
+// has the admin endpoints, the streams and the exports as well. Whoever comes
+// short of the role of a route gets a 403 that names it.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+
+const SECRET: &str = "an HS256 secret of at least 32 bytes";
+const ADMIN: &str = "X-Api-Key: a\r\n";
+const WRITER: &str = "X-Api-Key: w\r\n";
+const READER: &str = "X-Api-Key: r\r\n";
+
+fn start() -> Server {
+    let vars = [("API_KEYS", "admin:a,writer:w:writer,reader:r:reader"), ("APP_ENV", "test"), ("JWT_SECRET", SECRET)];
+    Server::start_with("memory://", &vars)
+}
+
+fn create_user(server: &Server, headers: &str) -> (u16, serde_json::Value) {
+    let email = unique_email("ada");
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}", "password": "correct horse battery"}}"#, email);
+    let (status, body) = server.request_with_headers("POST", "/users", headers, Some(&user));
+    (status, json(&body))
+}
+
+#[test]
+fn routes_need_their_role() {
+    let server = start();
+    let (_, user) = create_user(&server, ADMIN);
+    let id = user["id"].as_i64().unwrap();
+
+    for headers in [ADMIN, WRITER, READER] {
+        assert_eq!(server.request_with_headers("GET", "/users", headers, None).0, 200, "{}", headers);
+        assert_eq!(server.request_with_headers("GET", &format!("/users/{}", id), headers, None).0, 200);
+    }
+
+    assert_eq!(create_user(&server, WRITER).0, 200);
+    let (status, body) = create_user(&server, READER);
+    assert_eq!(status, 403, "{}", body);
+    assert_eq!(body["code"], "missing_role");
+    assert_eq!(body["required_role"], "writer");
+    assert_eq!(body["detail"], "POST /users needs the writer role, reader is a reader");
+
+    let (_, doomed) = create_user(&server, WRITER);
+    let target = format!("/users/{}", doomed["id"]);
+    assert_eq!(server.request_with_headers("DELETE", &target, READER, None).0, 403);
+    assert_eq!(server.request_with_headers("DELETE", &target, WRITER, None).0, 200);
+
+    for target in ["/admin/maintenance", "/admin/api-keys"] {
+        for headers in [WRITER, READER] {
+            let (status, body) = server.request_with_headers("GET", target, headers, None);
+            assert_eq!(status, 403, "{}", body);
+            assert_eq!(json(&body)["required_role"], "admin");
+        }
+    }
+    assert_eq!(server.request_with_headers("GET", "/admin/maintenance", ADMIN, None).0, 200);
+}
+
+#[test]
+fn admins_give_users_their_role() {
+    let server = start();
+    let (_, user) = create_user(&server, ADMIN);
+    let (id, email) = (user["id"].as_i64().unwrap(), user["email"].as_str().unwrap().to_owned());
+    let target = format!("/users/{}/role", id);
+
+    let log_in = || {
+        let login = format!(r#"{{"email": "{}", "password": "correct horse battery"}}"#, email);
+        let (status, body) = server.request("POST", "/login", Some(&login));
+        assert_eq!(status, 200, "{}", body);
+        format!("Authorization: Bearer {}\r\n", json(&body)["token"].as_str().unwrap())
+    };
+
+    // New users write
+    let token = log_in();
+    assert_eq!(create_user(&server, &token).0, 200);
+    assert_eq!(server.request_with_headers("GET", "/admin/maintenance", &token, None).0, 403);
+
+    assert_eq!(server.request_with_headers("PUT", &target, WRITER, Some(r#"{"role": "admin"}"#)).0, 403);
+    let (status, _) = server.request_with_headers("PUT", &target, ADMIN, Some(r#"{"role": "owner"}"#));
+    assert_eq!(status, 400);
+    let (status, body) = server.request_with_headers("PUT", &target, ADMIN, Some(r#"{"role": "admin"}"#));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["role"], "admin");
+    let (status, _) = server.request_with_headers("PUT", "/users/2147483647/role", ADMIN, Some(r#"{"role": "admin"}"#));
+    assert_eq!(status, 404);
+
+    // The token has the role of when it was issued
+    assert_eq!(server.request_with_headers("GET", "/admin/maintenance", &token, None).0, 403);
+    assert_eq!(server.request_with_headers("GET", "/admin/maintenance", &log_in(), None).0, 200);
+
+    assert_eq!(server.request_with_headers("PUT", &target, ADMIN, Some(r#"{"role": "reader"}"#)).0, 200);
+    let (status, body) = create_user(&server, &log_in());
+    assert_eq!(status, 403, "{}", body);
+    assert_eq!(body["detail"], format!("POST /users needs the writer role, user {} is a reader", id));
+}
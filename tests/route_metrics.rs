@@ -0,0 +1,47 @@
+// /metrics has how long the requests took and how they were answered, by the
+// method and route template they matched, the others all counted as unmatched.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+
+// The value of the sample of /metrics with these name and labels
+fn sample(metrics: &str, name_and_labels: &str) -> Option<u64> {
+    let prefix = format!("{} ", name_and_labels);
+    metrics.lines().find_map(|line| line.strip_prefix(&prefix)).map(|value| value.parse().unwrap())
+}
+
+#[test]
+fn requests_are_counted_by_route_and_status_class() {
+    let server = Server::start("memory://");
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}"}}"#, unique_email("ada"));
+    let (status, body) = server.request("POST", "/users", Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    let id = json(&body)["id"].clone();
+    for target in [format!("/users/{}", id), format!("/users/{}", id), "/users/123456".to_owned()] {
+        server.request("GET", &target, None);
+    }
+    for target in ["/nope", "/users/1/nope", "/nope/42"] {
+        assert_eq!(server.request("GET", target, None).0, 404);
+    }
+
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    let get = r#"method="GET",route="/users/{id}""#;
+    assert_eq!(sample(&metrics, &format!("http_request_duration_seconds_count{{{}}}", get)), Some(3), "{}", metrics);
+    assert_eq!(sample(&metrics, &format!(r#"http_request_duration_seconds_bucket{{{},le="+Inf"}}"#, get)), Some(3));
+    let within_5s = sample(&metrics, &format!(r#"http_request_duration_seconds_bucket{{{},le="5"}}"#, get));
+    assert_eq!(within_5s, Some(3), "{}", metrics);
+    assert_eq!(sample(&metrics, &format!(r#"http_requests_total{{{},status_class="2xx"}}"#, get)), Some(2));
+    assert_eq!(sample(&metrics, &format!(r#"http_requests_total{{{},status_class="4xx"}}"#, get)), Some(1));
+    let post = r#"http_requests_total{method="POST",route="/users",status_class="2xx"}"#;
+    assert_eq!(sample(&metrics, post), Some(1), "{}", metrics);
+
+    // The raw paths are never labels
+    let unmatched = r#"http_requests_total{method="GET",route="unmatched",status_class="4xx"}"#;
+    assert_eq!(sample(&metrics, unmatched), Some(3), "{}", metrics);
+    assert!(!metrics.contains("nope") && !metrics.contains(&format!("/users/{}", id)), "{}", metrics);
+
+    let find = r#"db_operation_duration_seconds_count{operation="find"}"#;
+    assert_eq!(sample(&metrics, find), Some(3), "{}", metrics);
+    assert!(sample(&metrics, r#"db_operation_duration_seconds_count{operation="create"}"#).is_some());
+}
@@ -0,0 +1,88 @@
+// TENANCY=schema: tenants provisioned into schemas of their own, served by the same
+// pool. Runs when TEST_DATABASE_URL points at a database the tests may write to,
+// whose role may create schemas.
+
+mod common;
+
+use common::{ json, Server };
+use postgres::{ Client, NoTls };
+
+const SCHEMA: &str = "schema-tenancy-test";
+const TENANTS: [&str; 2] = ["schema-test-alpha", "schema-test-beta"];
+
+fn as_tenant(tenant: &str) -> String {
+    format!("X-Tenant-Id: {}\r\n", tenant)
+}
+
+fn count_users(admin: &mut Client, schema: &str) -> i64 {
+    admin.query_one(&format!("SELECT count(*) FROM \"{}\".users", schema), &[]).unwrap().get(0)
+}
+
+#[test]
+fn tenants_are_served_from_their_own_schemas() {
+    let Some(database_url) = common::database_url("the schema tenancy test") else {
+        return;
+    };
+    let mut admin = Client::connect(&database_url, NoTls).unwrap();
+    for schema in [SCHEMA.to_owned()].into_iter().chain(TENANTS.iter().map(|tenant| format!("tenant_{}", tenant))) {
+        admin.batch_execute(&format!("DROP SCHEMA IF EXISTS \"{}\" CASCADE", schema)).unwrap();
+    }
+    // One connection, so every request reuses the one the previous one returned
+    let server = Server::start_with(
+        &database_url,
+        &[("DATABASE_SCHEMA", SCHEMA), ("TENANCY", "schema"), ("ADMIN_ENDPOINTS", "true"), ("DB_POOL_MAX_SIZE", "1")]
+    );
+    let (alpha, beta) = (as_tenant(TENANTS[0]), as_tenant(TENANTS[1]));
+    let ada = r#"{"name": "Ada Lovelace", "email": "ada@example.com"}"#;
+
+    let (status, body) = server.request_with_headers("GET", "/users", &alpha, None);
+    assert_eq!((status, body.as_str()), (404, "Tenant schema-test-alpha not found"));
+    for tenant in TENANTS {
+        let (status, body) = server.request("POST", "/admin/tenants", Some(&format!(r#"{{"id": "{}"}}"#, tenant)));
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(json(&body)["schema"], format!("tenant_{}", tenant));
+    }
+    let again = format!(r#"{{"id": "{}"}}"#, TENANTS[0]);
+    assert_eq!(server.request("POST", "/admin/tenants", Some(&again)).0, 409);
+    assert_eq!(server.request("POST", "/admin/tenants", Some(r#"{"id": "Not A Slug"}"#)).0, 400);
+    let (_, body) = server.request("GET", "/admin/tenants", None);
+    let tenants = json(&body);
+    let ids: Vec<&str> = tenants.as_array().unwrap().iter().map(|tenant| tenant["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, TENANTS);
+
+    // The same email in both, the ids of each schema start over
+    let (status, body) = server.request_with_headers("POST", "/users", &alpha, Some(ada));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["id"], 1);
+    let (status, body) = server.request_with_headers("POST", "/users", &beta, Some(ada));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["id"], 1);
+    let grace = r#"{"name": "Grace Hopper", "email": "grace@example.com"}"#;
+    let (status, body) = server.request_with_headers("POST", "/users", &alpha, Some(grace));
+    assert_eq!(status, 200, "{}", body);
+    let path = format!("/users/{}", json(&body)["id"]);
+
+    assert_eq!(server.request_with_headers("GET", &path, &beta, None).0, 404);
+    assert_eq!(server.request_with_headers("DELETE", &path, &beta, None).0, 404);
+    let (status, body) = server.request_with_headers("GET", &path, &alpha, None);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["name"], "Grace Hopper");
+    let (_, body) = server.request_with_headers("GET", "/events", &beta, None);
+    assert_eq!(json(&body).as_array().unwrap().len(), 1);
+
+    assert_eq!(count_users(&mut admin, &format!("tenant_{}", TENANTS[0])), 2);
+    assert_eq!(count_users(&mut admin, &format!("tenant_{}", TENANTS[1])), 1);
+    assert_eq!(count_users(&mut admin, SCHEMA), 0);
+
+    // The connection went back to the main schema, where the registry is
+    let (status, body) = server.request_with_headers("GET", "/users", &as_tenant("schema-test-gamma"), None);
+    assert_eq!((status, body.as_str()), (404, "Tenant schema-test-gamma not found"));
+    assert_eq!(server.request("GET", "/admin/tenants", None).0, 200);
+
+    drop(server);
+    for tenant in TENANTS {
+        admin.batch_execute(&format!("DROP SCHEMA \"tenant_{}\" CASCADE", tenant)).unwrap();
+    }
+    // With the registry
+    admin.batch_execute(&format!("DROP SCHEMA \"{}\" CASCADE", SCHEMA)).unwrap();
+}
@@ -0,0 +1,62 @@
+// The secrets of the config never show: not when the database can't be reached at
+// startup, nor in the logs and the responses of a server whose replica can't be.
+// The second test needs TEST_DATABASE_URL.
+
+mod common;
+
+use common::Server;
+use std::env;
+use std::process::Command;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+const PASSWORD: &str = "s3cr3t-pa55word";
+
+// Everything printed until the server lets go of the lines
+fn all_lines(lines: &Receiver<String>) -> String {
+    let mut printed = Vec::new();
+    while let Ok(line) = lines.recv_timeout(Duration::from_secs(2)) {
+        printed.push(line);
+    }
+    printed.join("\n")
+}
+
+#[test]
+fn failing_to_connect_never_shows_the_password() {
+    let urls = [
+        format!("postgres://ada:{}@127.0.0.1:1/users", PASSWORD),
+        format!("host=127.0.0.1 port=1 user=ada password={} dbname=users", PASSWORD),
+        format!("postgres://ada:{}@127.0.0.1:1/users?sslmode=sometimes", PASSWORD),
+    ];
+    for url in urls {
+        let output = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+            .env("DATABASE_URL", &url)
+            .env("DB_CONNECT_MAX_ATTEMPTS", "1")
+            .env("JWT_SECRET", "an HS256 secret of at least 32 bytes")
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let (stdout, stderr) = (String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+        assert!(!stderr.is_empty());
+        assert!(!stdout.contains(PASSWORD) && !stderr.contains(PASSWORD), "{}\n{}", stdout, stderr);
+    }
+}
+
+#[test]
+fn an_unreachable_replica_never_shows_the_password() {
+    let Some(database_url) = common::database_url("the secrets test") else {
+        return;
+    };
+    let replica_url = format!("postgres://ada:{}@127.0.0.1:1/replica", PASSWORD);
+    let vars = [("DATABASE_READ_URL", replica_url.as_str()), ("APP_ENV", "production"), ("REQUIRE_AUTH", "false")];
+    let (server, lines) = Server::start_capturing(&database_url, &vars);
+    for path in ["/health", "/readyz", "/users/1", "/users?limit=1"] {
+        let (status, body) = server.request("GET", path, None);
+        assert!(status < 500 || status == 503, "{} {}", status, body);
+        assert!(!body.contains(PASSWORD), "{}", body);
+    }
+    drop(server);
+    let printed = all_lines(&lines);
+    assert!(printed.contains("Can't reach the read replica"), "{}", printed);
+    assert!(!printed.contains(PASSWORD), "{}", printed);
+}
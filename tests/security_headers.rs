@@ -0,0 +1,55 @@
+// Every response gets X-Content-Type-Options, X-Frame-Options and
+// Referrer-Policy, errors included, and Strict-Transport-Security with
+// TLS_TERMINATED_UPSTREAM=true. SECURITY_HEADER_<NAME> replaces or drops each.
+
+mod common;
+
+use common::Server;
+use std::io::Read;
+
+// The status line and the headers of the response
+fn head(server: &Server, method: &str, target: &str, headers: &str) -> String {
+    let mut response = String::new();
+    server.send(method, target, headers, None).read_to_string(&mut response).unwrap();
+    let (head, _) = response.split_once("\r\n\r\n").unwrap();
+    format!("{}\r\n", head)
+}
+
+#[test]
+fn responses_have_the_security_headers() {
+    let server = Server::start_with("memory://", &[("API_KEYS", "deploy:s3cret")]);
+    let key = "X-Api-Key: s3cret\r\n";
+    let responses = [
+        ("200 ", head(&server, "GET", "/users", key)),
+        ("404 ", head(&server, "GET", "/nothing/here", key)),
+        ("400 ", head(&server, "GET", "/users/abc", key)),
+        ("401 ", head(&server, "GET", "/users", "")),
+    ];
+    for (status, head) in responses {
+        assert!(head.starts_with(&format!("HTTP/1.1 {}", status)), "{}", head);
+        assert!(head.contains("\r\nX-Content-Type-Options: nosniff\r\n"), "{}", head);
+        assert!(head.contains("\r\nX-Frame-Options: DENY\r\n"), "{}", head);
+        assert!(head.contains("\r\nReferrer-Policy: no-referrer\r\n"), "{}", head);
+        assert!(!head.contains("Strict-Transport-Security"), "{}", head);
+        // Only on HTML
+        assert!(!head.contains("Content-Security-Policy"), "{}", head);
+    }
+}
+
+#[test]
+fn the_headers_are_configured() {
+    let vars = [
+        ("TLS_TERMINATED_UPSTREAM", "true"),
+        ("SECURITY_HEADER_X_FRAME_OPTIONS", "SAMEORIGIN"),
+        ("SECURITY_HEADER_REFERRER_POLICY", ""),
+        ("SECURITY_HEADER_STRICT_TRANSPORT_SECURITY", "max-age=600"),
+    ];
+    let server = Server::start_with("memory://", &vars);
+    for target in ["/users", "/users/0"] {
+        let head = head(&server, "GET", target, "");
+        assert!(head.contains("\r\nX-Content-Type-Options: nosniff\r\n"), "{}", head);
+        assert!(head.contains("\r\nX-Frame-Options: SAMEORIGIN\r\n"), "{}", head);
+        assert!(head.contains("\r\nStrict-Transport-Security: max-age=600\r\n"), "{}", head);
+        assert!(!head.contains("Referrer-Policy"), "{}", head);
+    }
+}
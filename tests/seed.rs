@@ -0,0 +1,113 @@
+// The users of SEED_FILE loaded at startup, in schemas of their own. Runs when
+// TEST_DATABASE_URL points at a database the tests may write to, whose role may
+// create schemas.
+
+mod common;
+
+use common::{ json, Server };
+use postgres::{ Client, NoTls };
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn seed_file(name: &str, content: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("seed-test-{}-{}.json", std::process::id(), name));
+    fs::write(&path, content).unwrap();
+    path
+}
+
+fn fresh_schema(admin: &mut Client, schema: &str) {
+    admin.batch_execute(&format!("DROP SCHEMA IF EXISTS \"{}\" CASCADE", schema)).unwrap();
+}
+
+fn count(admin: &mut Client, query: &str) -> i64 {
+    admin.query_one(query, &[]).unwrap().get(0)
+}
+
+#[test]
+fn seeding_again_changes_nothing() {
+    let Some(database_url) = common::database_url("the seed test") else {
+        return;
+    };
+    let mut admin = Client::connect(&database_url, NoTls).unwrap();
+    fresh_schema(&mut admin, "seed-test");
+    let file = seed_file(
+        "users",
+        r#"[
+            {"id": 100, "name": "Ada Lovelace", "email": "ada@example.com", "created_at": "2020-01-02T03:04:05Z"},
+            {"name": "  Grace   Hopper ", "email": "Grace@Example.com"},
+            {"name": "Alan Turing", "email": "alan@example.com"}
+        ]"#
+    );
+    let vars = [("DATABASE_SCHEMA", "seed-test"), ("SEED_FILE", file.to_str().unwrap())];
+
+    for _ in 0..2 {
+        let server = Server::start_with(&database_url, &vars);
+        let (status, body) = server.request("GET", "/users/100", None);
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(json(&body)["name"], "Ada Lovelace");
+        drop(server);
+        assert_eq!(count(&mut admin, "SELECT count(*) FROM \"seed-test\".users"), 3);
+        assert_eq!(count(&mut admin, "SELECT count(*) FROM \"seed-test\".events_outbox"), 3);
+    }
+    let grace = "SELECT count(*) FROM \"seed-test\".users WHERE name = 'Grace Hopper' AND email = 'grace@example.com'";
+    assert_eq!(count(&mut admin, grace), 1);
+    let created_at = "SELECT count(*) FROM \"seed-test\".events_outbox
+        WHERE payload->>'id' = '100' AND created_at = '2020-01-02T03:04:05Z'";
+    assert_eq!(count(&mut admin, created_at), 1);
+
+    // Upserted, the names follow the file; the new users come after the explicit id
+    let renamed = seed_file("renamed", r#"[{"name": "Countess Lovelace", "email": "ada@example.com"}]"#);
+    let server = Server::start_with(
+        &database_url,
+        &[("DATABASE_SCHEMA", "seed-test"), ("SEED_FILE", renamed.to_str().unwrap()), ("SEED_ON_CONFLICT", "upsert")]
+    );
+    let (_, body) = server.request("GET", "/users/100", None);
+    assert_eq!(json(&body)["name"], "Countess Lovelace");
+    let (status, body) = server.request("POST", "/users", Some(r#"{"name": "After Seed", "email": "after@example.com"}"#));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["id"], 101);
+
+    fs::remove_file(file).ok();
+    fs::remove_file(renamed).ok();
+}
+
+#[test]
+fn a_broken_entry_is_skipped_unless_strict() {
+    let Some(database_url) = common::database_url("the seed test") else {
+        return;
+    };
+    let mut admin = Client::connect(&database_url, NoTls).unwrap();
+    fresh_schema(&mut admin, "seed-test-broken");
+    let file = seed_file(
+        "broken",
+        r#"[
+            {"name": "Ada Lovelace", "email": "ada@example.com"},
+            {"name": "No Email"},
+            {"name": "Alan Turing", "email": "alan@example.com"}
+        ]"#
+    );
+
+    let strict = Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .args(["--seed-file", file.to_str().unwrap(), "--seed-strict"])
+        .env("DATABASE_URL", &database_url)
+        .env("DATABASE_SCHEMA", "seed-test-broken")
+        .env("PORT", "0")
+        .output()
+        .unwrap();
+    assert!(!strict.status.success());
+    let stderr = String::from_utf8_lossy(&strict.stderr);
+    assert!(stderr.contains("entry 1 of"), "{}", stderr);
+    assert!(stderr.contains("missing field `email`"), "{}", stderr);
+    assert_eq!(count(&mut admin, "SELECT count(*) FROM \"seed-test-broken\".users"), 0);
+
+    let server = Server::start_with(
+        &database_url,
+        &[("DATABASE_SCHEMA", "seed-test-broken"), ("SEED_FILE", file.to_str().unwrap())]
+    );
+    drop(server);
+    assert_eq!(count(&mut admin, "SELECT count(*) FROM \"seed-test-broken\".users"), 2);
+
+    fs::remove_file(file).ok();
+}
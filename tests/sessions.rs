@@ -0,0 +1,140 @@
+// SESSIONS=true: POST /session trades the email and the password of a user for a
+// session cookie, which does as well as a key until DELETE /session or until the
+// session ends, the changes sending its CSRF token too. Needs TEST_DATABASE_URL.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+const ROOT: &str = "X-Api-Key: r00t\r\n";
+
+fn start(database_url: &str, lifetime: &str) -> Server {
+    let vars = [
+        ("SESSIONS", "true"),
+        ("SESSION_LIFETIME_SECS", lifetime),
+        ("API_KEYS", "root:r00t"),
+        ("JWT_SECRET", "an HS256 secret of at least 32 bytes"),
+    ];
+    Server::start_with(database_url, &vars)
+}
+
+// The status, the Set-Cookie header if any and the body
+fn exchange(
+    server: &Server,
+    method: &str,
+    target: &str,
+    headers: &str,
+    body: Option<&str>
+) -> (u16, Option<String>, String) {
+    let mut response = String::new();
+    server.send(method, target, headers, body).read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    let set_cookie = head.split("\r\n").find_map(|line| line.strip_prefix("Set-Cookie: ")).map(str::to_owned);
+    (status, set_cookie, body.to_owned())
+}
+
+// The cookie of a new session of a new user, the id of the user, and the CSRF
+// token of the session
+fn log_in(server: &Server) -> (String, i64, String) {
+    let email = unique_email("ada");
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}", "password": "correct horse battery"}}"#, email);
+    let (status, body) = server.request_with_headers("POST", "/users", ROOT, Some(&user));
+    assert_eq!(status, 200, "{}", body);
+
+    let login = format!(r#"{{"email": "{}", "password": "correct horse battery"}}"#, email);
+    let (status, set_cookie, body) = exchange(server, "POST", "/session", "", Some(&login));
+    assert_eq!(status, 200, "{}", body);
+    let set_cookie = set_cookie.unwrap();
+    assert!(set_cookie.ends_with("; Path=/; HttpOnly; SameSite=Lax; Secure"), "{}", set_cookie);
+    let cookie = set_cookie.split(';').next().unwrap().to_owned();
+    let body = json(&body);
+    (cookie, body["user_id"].as_i64().unwrap(), body["csrf_token"].as_str().unwrap().to_owned())
+}
+
+#[test]
+fn sessions_last_until_logging_out() {
+    let Some(database_url) = common::database_url("the session test") else {
+        return;
+    };
+    let server = start(&database_url, "3600");
+    let (cookie, id, _) = log_in(&server);
+
+    // Among other cookies, and quoted
+    let session = cookie.strip_prefix("session=").unwrap();
+    let headers = format!("Cookie: theme=dark; session=\"{}\"\r\n", session);
+    let (status, _, body) = exchange(&server, "GET", &format!("/users/{}", id), &headers, None);
+    assert_eq!(status, 200, "{}", body);
+
+    let (status, set_cookie, body) = exchange(&server, "DELETE", "/session", &headers, None);
+    assert_eq!(status, 200, "{}", body);
+    assert!(set_cookie.unwrap().starts_with("session=; Path=/; Max-Age=0"));
+
+    let (status, set_cookie, body) = exchange(&server, "GET", &format!("/users/{}", id), &headers, None);
+    assert_eq!(status, 401, "{}", body);
+    assert_eq!(json(&body)["code"], "invalid_session");
+    assert!(set_cookie.unwrap().starts_with("session=; Path=/; Max-Age=0"));
+
+    let login = r#"{"email": "nobody@example.com", "password": "correct horse battery"}"#;
+    let (status, set_cookie, _) = exchange(&server, "POST", "/session", "", Some(login));
+    assert_eq!((status, set_cookie), (401, None));
+}
+
+#[test]
+fn sessions_end_after_their_lifetime() {
+    let Some(database_url) = common::database_url("the session test") else {
+        return;
+    };
+    let server = start(&database_url, "1");
+    let (cookie, id, _) = log_in(&server);
+    let headers = format!("Cookie: {}\r\n", cookie);
+    assert_eq!(exchange(&server, "GET", &format!("/users/{}", id), &headers, None).0, 200);
+
+    thread::sleep(Duration::from_millis(1500));
+    let (status, set_cookie, body) = exchange(&server, "GET", &format!("/users/{}", id), &headers, None);
+    assert_eq!(status, 401, "{}", body);
+    assert_eq!(json(&body)["code"], "invalid_session");
+    assert!(set_cookie.unwrap().starts_with("session=; Path=/; Max-Age=0"));
+}
+
+#[test]
+fn changes_with_the_cookie_need_the_csrf_token() {
+    let Some(database_url) = common::database_url("the session test") else {
+        return;
+    };
+    let server = start(&database_url, "3600");
+    let (cookie, id, csrf_token) = log_in(&server);
+    let target = format!("/users/{}", id);
+    let update = |headers: &str, name: &str| {
+        let user = format!(r#"{{"name": "{}", "email": "{}"}}"#, name, unique_email("ada"));
+        let (status, _, body) = exchange(&server, "PUT", &target, headers, Some(&user));
+        (status, json(&body))
+    };
+
+    let cookie = format!("Cookie: {}\r\n", cookie);
+    for headers in [cookie.clone(), format!("{}X-CSRF-Token: {}x\r\n", cookie, csrf_token)] {
+        let (status, body) = update(&headers, "Eve");
+        assert_eq!((status, body["code"].as_str()), (403, Some("invalid_csrf_token")), "{}", body);
+    }
+    // Reading needs none, and hands it out again
+    let (status, set_cookie, body) = exchange(&server, "GET", "/session/csrf", &cookie, None);
+    assert_eq!((status, json(&body)["csrf_token"].as_str()), (200, Some(csrf_token.as_str())), "{}", body);
+    assert_eq!(set_cookie.unwrap(), format!("csrf_token={}; Path=/; SameSite=Lax; Secure", csrf_token));
+    let (status, body) = update(&format!("{}X-CSRF-Token: {}\r\n", cookie, csrf_token), "Ada Lovelace");
+    assert_eq!(status, 200, "{}", body);
+
+    // Nor the keys and the tokens, which no other site can make a browser send
+    let (status, body) = update(ROOT, "Ada Lovelace");
+    assert_eq!(status, 200, "{}", body);
+    let login = format!(r#"{{"email": "{}", "password": "correct horse battery"}}"#, body["email"].as_str().unwrap());
+    let (status, login) = server.request("POST", "/login", Some(&login));
+    assert_eq!(status, 200, "{}", login);
+    let bearer = format!("Authorization: Bearer {}\r\n", json(&login)["token"].as_str().unwrap());
+    assert_eq!(update(&bearer, "Ada Lovelace").0, 200);
+
+    // Without a session there is no token
+    assert_eq!(exchange(&server, "GET", "/session/csrf", "", None).0, 401);
+}
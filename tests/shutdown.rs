@@ -0,0 +1,82 @@
+// SIGTERM and SIGINT: the server stops accepting connections and answers 503 to
+// /readyz, handles the requests of the connections it accepted already, then
+// exits.
+
+mod common;
+
+use common::{ json, Server };
+use std::io::{ Read, Write };
+use std::net::TcpStream;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+// Connected before the signal, the request sent after it
+fn connect(server: &Server) -> TcpStream {
+    let stream = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+    // Time for the accept loop to take it
+    thread::sleep(Duration::from_millis(100));
+    stream
+}
+
+fn respond(mut stream: TcpStream, method: &str, target: &str, body: &str) -> (u16, String) {
+    let request =
+        format!("{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", method, target, body.len(), body);
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.split_whitespace().nth(1).unwrap().parse().unwrap(), body.to_owned())
+}
+
+fn wait_until_refused(server: &Server) {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while TcpStream::connect(("127.0.0.1", server.port())).is_ok() {
+        assert!(Instant::now() < deadline, "still accepting connections");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn accepted_requests_are_handled_before_exiting() {
+    let mut server = Server::start("memory://");
+    let (readyz, create) = (connect(&server), connect(&server));
+
+    server.signal(libc::SIGTERM);
+    wait_until_refused(&server);
+
+    let (status, body) = respond(readyz, "GET", "/readyz", "");
+    assert_eq!(status, 503, "{}", body);
+    assert_eq!(json(&body)["draining"], true);
+    let (status, body) = respond(create, "POST", "/users", r#"{"name": "Ada", "email": "ada@example.com"}"#);
+    assert_eq!(status, 200, "{}", body);
+
+    let status = server.wait_for_exit(Duration::from_secs(2)).expect("the server didn't exit");
+    assert!(status.success(), "{}", status);
+}
+
+#[test]
+fn connections_still_open_after_the_grace_period_are_closed() {
+    let mut server = Server::start_with("memory://", &[("SHUTDOWN_GRACE_MS", "300")]);
+    let idle = connect(&server);
+
+    let signalled = Instant::now();
+    server.signal(libc::SIGINT);
+    let status = server.wait_for_exit(Duration::from_secs(3)).expect("the server didn't exit");
+    assert!(status.success(), "{}", status);
+    assert!(signalled.elapsed() >= Duration::from_millis(300));
+    drop(idle);
+}
+
+#[test]
+fn a_second_signal_exits_at_once() {
+    let mut server = Server::start("memory://");
+    let idle = connect(&server);
+
+    server.signal(libc::SIGTERM);
+    wait_until_refused(&server);
+    assert!(server.wait_for_exit(Duration::from_millis(200)).is_none());
+    server.signal(libc::SIGTERM);
+    let status = server.wait_for_exit(Duration::from_secs(1)).expect("the server didn't exit");
+    assert_eq!(status.code(), Some(1));
+    drop(idle);
+}
@@ -0,0 +1,171 @@
+// REQUEST_SIGNING=true: a request may carry, rather than a key of API_KEYS, the
+// HMAC-SHA256 of its method, target, timestamp and body with the key, in
+// X-Signature, and the timestamp, in X-Timestamp. With X-Nonce, signed too, the
+// request is taken once. The nonces kept in the database need TEST_DATABASE_URL.
+
+mod common;
+
+use common::{ json, Server };
+use hmac::{ Hmac, Mac };
+use sha2::{ Digest, Sha256 };
+use std::env;
+use std::sync::Barrier;
+use std::thread;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+fn start() -> Server {
+    Server::start_with("memory://", &[("API_KEYS", "deploy:s3cret,ci:an0ther:reader"), ("REQUEST_SIGNING", "true")])
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn mac(secret: &str, message: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(message.as_bytes());
+    mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn sign(secret: &str, method: &str, target: &str, timestamp: u64, body: &str) -> String {
+    mac(secret, &format!("{}\n{}\n{}\n{:x}", method, target, timestamp, Sha256::digest(body.as_bytes())))
+}
+
+fn headers(signature: &str, timestamp: u64) -> String {
+    format!("X-Signature: {}\r\nX-Timestamp: {}\r\n", signature, timestamp)
+}
+
+// The headers of GET /users?limit=1 signed now with the key and the nonce
+fn signed_with_nonce(secret: &str, nonce: &str) -> String {
+    let timestamp = now();
+    let message = format!("GET\n/users?limit=1\n{}\n{}\n{:x}", timestamp, nonce, Sha256::digest(b""));
+    format!("{}X-Nonce: {}\r\n", headers(&mac(secret, &message), timestamp), nonce)
+}
+
+fn nonce_test_server(database_url: &str, store: &str, max_skew: &str) -> Server {
+    let vars = [
+        ("API_KEYS", "deploy:s3cret,ci:an0ther:reader"),
+        ("REQUEST_SIGNING", "true"),
+        ("SIGNATURE_NONCE_REQUIRED", "true"),
+        ("SIGNATURE_NONCE_STORE", store),
+        ("SIGNATURE_MAX_SKEW_SECS", max_skew),
+    ];
+    Server::start_with(database_url, &vars)
+}
+
+fn status(server: &Server, headers: &str) -> (u16, Option<String>) {
+    let (status, body) = server.request_with_headers("GET", "/users?limit=1", headers, None);
+    let body = serde_json::from_str::<serde_json::Value>(&body).ok();
+    (status, body.and_then(|body| Some(body["code"].as_str()?.to_owned())))
+}
+
+// A nonce is taken once per key, again once its timestamp is too old to replay,
+// and only one of the same requests sent at once gets in
+fn nonces_are_taken_once(database_url: &str, store: &str) {
+    let server = nonce_test_server(database_url, store, "300");
+    let nonce = format!("{}-{}", store, now());
+    let headers = signed_with_nonce("s3cret", &nonce);
+    assert_eq!(status(&server, &headers), (200, None));
+    assert_eq!(status(&server, &headers), (401, Some("nonce_replayed".to_owned())));
+    assert_eq!(status(&server, &signed_with_nonce("s3cret", &nonce)).1.as_deref(), Some("nonce_replayed"));
+    assert_eq!(status(&server, &signed_with_nonce("an0ther", &nonce)), (200, None));
+    let unsigned_nonce = headers.replace("X-Nonce: ", "X-Nonce: other-");
+    assert_eq!(status(&server, &unsigned_nonce).1.as_deref(), Some("invalid_signature"));
+    let without_nonce = headers.split("X-Nonce").next().unwrap().to_owned();
+    assert_eq!(status(&server, &without_nonce).1.as_deref(), Some("invalid_signature"));
+
+    let headers = signed_with_nonce("s3cret", &format!("{}-at-once", nonce));
+    let barrier = Barrier::new(8);
+    let statuses: Vec<u16> = thread::scope(|scope| {
+        let sent: Vec<_> = (0..8)
+            .map(|_| scope.spawn(|| {
+                barrier.wait();
+                status(&server, &headers).0
+            }))
+            .collect();
+        sent.into_iter().map(|sent| sent.join().unwrap()).collect()
+    });
+    assert_eq!(statuses.iter().filter(|status| **status == 200).count(), 1, "{:?}", statuses);
+    assert_eq!(statuses.iter().filter(|status| **status == 401).count(), 7, "{:?}", statuses);
+    drop(server);
+
+    // Forgotten once the timestamp it came with is too old
+    let server = nonce_test_server(database_url, store, "1");
+    let headers = signed_with_nonce("s3cret", &format!("{}-forgotten", nonce));
+    assert_eq!(status(&server, &headers).0, 200);
+    thread::sleep(Duration::from_millis(2500));
+    assert_eq!(status(&server, &headers).1.as_deref(), Some("signature_expired"));
+    assert_eq!(status(&server, &signed_with_nonce("s3cret", &format!("{}-forgotten", nonce))).0, 200);
+}
+
+#[test]
+fn nonces_in_memory_are_taken_once() {
+    nonces_are_taken_once("memory://", "memory");
+}
+
+#[test]
+fn nonces_in_the_database_are_taken_once() {
+    let Some(database_url) = common::database_url("the signature nonce test") else {
+        return;
+    };
+    nonces_are_taken_once(&database_url, "postgres");
+}
+
+#[test]
+fn nonces_in_the_database_need_postgres() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rust_postgresql_tutorial"))
+        .env("DATABASE_URL", "memory://")
+        .env("API_KEYS", "deploy:s3cret")
+        .env("REQUEST_SIGNING", "true")
+        .env("SIGNATURE_NONCE_STORE", "postgres")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("SIGNATURE_NONCE_STORE=postgres needs"));
+}
+
+#[test]
+fn signed_requests_are_let_in() {
+    let server = start();
+    let body = r#"{"name": "Ada Lovelace", "email": "ada@example.com"}"#;
+    let timestamp = now();
+    let signature = sign("s3cret", "POST", "/users", timestamp, body);
+    let (status, created) = server.request_with_headers("POST", "/users", &headers(&signature, timestamp), Some(body));
+    assert_eq!(status, 200, "{}", created);
+
+    // The query is signed along with the path, and the role is the key's
+    let target = "/users?limit=1";
+    let signature = sign("an0ther", "GET", target, timestamp, "");
+    let (status, body) = server.request_with_headers("GET", target, &headers(&signature, timestamp), None);
+    assert_eq!(status, 200, "{}", body);
+    let signature = sign("an0ther", "DELETE", "/users/1", timestamp, "");
+    let (status, body) = server.request_with_headers("DELETE", "/users/1", &headers(&signature, timestamp), None);
+    assert_eq!((status, json(&body)["code"].as_str()), (403, Some("missing_role")), "{}", body);
+
+    // Keys still do
+    assert_eq!(server.request_with_headers("GET", "/users", "X-Api-Key: s3cret\r\n", None).0, 200);
+}
+
+#[test]
+fn tampered_and_stale_requests_are_turned_away() {
+    let server = start();
+    let body = r#"{"name": "Ada Lovelace", "email": "ada@example.com"}"#;
+    let timestamp = now();
+    let signature = sign("s3cret", "POST", "/users", timestamp, body);
+    let code = |headers: &str, body: &str| {
+        let (status, response) = server.request_with_headers("POST", "/users", headers, Some(body));
+        assert_eq!(status, 401, "{}", response);
+        json(&response)["code"].as_str().unwrap().to_owned()
+    };
+
+    let tampered = body.replace("Ada", "Eve");
+    assert_eq!(code(&headers(&signature, timestamp), &tampered), "invalid_signature");
+    assert_eq!(code(&headers(&sign("wrong", "POST", "/users", timestamp, body), timestamp), body), "invalid_signature");
+    assert_eq!(code(&headers(&signature, timestamp + 1), body), "invalid_signature");
+    assert_eq!(code(&format!("X-Signature: {}\r\n", signature), body), "invalid_signature");
+
+    let stale = timestamp - 301;
+    assert_eq!(code(&headers(&sign("s3cret", "POST", "/users", stale, body), stale), body), "signature_expired");
+    let ahead = timestamp + 301;
+    assert_eq!(code(&headers(&sign("s3cret", "POST", "/users", ahead, body), ahead), body), "signature_expired");
+}
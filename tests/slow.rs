@@ -0,0 +1,65 @@
+// The requests longer than SLOW_REQUEST_MS and the queries longer than
+// SLOW_QUERY_MS are logged at warn, each once, the sleep of GET /admin/sleep
+// standing in for a slow query.
+
+mod common;
+
+use common::Server;
+use serde_json::Value;
+use std::sync::mpsc::Receiver;
+use std::time::{ Duration, Instant };
+
+// The slow events logged up to the one until is, the log being JSON
+fn slow_events(lines: &Receiver<String>, slow: &mut Vec<Value>, until: impl Fn(&Value) -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let line = lines.recv_timeout(left).unwrap_or_else(|_| panic!("not logged, the slow ones are {:?}", slow));
+        let Ok(event) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        if event["target"] == "slow" {
+            slow.push(event.clone());
+        }
+        if until(&event) {
+            return;
+        }
+    }
+}
+
+#[test]
+fn slow_requests_and_queries_are_logged_once() {
+    let vars = [("LOG_FORMAT", "json"), ("APP_ENV", "test"), ("SLOW_REQUEST_MS", "200"), ("SLOW_QUERY_MS", "100")];
+    let (server, lines) = Server::start_capturing("memory://", &vars);
+    for _ in 0..3 {
+        assert_eq!(server.request("GET", "/users/42", None).0, 404);
+    }
+    let headers = "X-Request-Id: slow-1\r\n";
+    let (status, body) = server.request_with_headers("GET", "/admin/sleep?seconds=0.3", headers, None);
+    assert_eq!(status, 200, "{}", body);
+
+    let mut slow = Vec::new();
+    slow_events(&lines, &mut slow, |event| event["kind"] == "request");
+    // Anything logged for the fast ones would be by now
+    server.request("GET", "/livez", None);
+    slow_events(&lines, &mut slow, |event| event["target"] == "access" && event["path"] == "/livez");
+
+    assert_eq!(slow.len(), 2, "{:?}", slow);
+    let (query, request) = (&slow[0], &slow[1]);
+    assert_eq!((query["level"].as_str(), query["kind"].as_str()), (Some("WARN"), Some("query")), "{}", query);
+    assert_eq!(query["statement"], "sleep");
+    assert_eq!(query["rows"], 0);
+    assert_eq!(query["route"], "GET /admin/sleep");
+    assert_eq!(query["request_id"], "slow-1");
+    assert!(query["duration_ms"].as_f64().unwrap() >= 300.0, "{}", query);
+
+    assert_eq!((request["level"].as_str(), request["kind"].as_str()), (Some("WARN"), Some("request")), "{}", request);
+    assert_eq!(request["route"], "GET /admin/sleep");
+    assert_eq!(request["request_id"], "slow-1");
+    assert!(request["duration_ms"].as_f64().unwrap() >= 300.0, "{}", request);
+    assert!(request.get("statement").is_none(), "{}", request);
+
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    assert!(metrics.contains("slow_events_total{kind=\"request\"} 1\n"), "{}", metrics);
+    assert!(metrics.contains("slow_events_total{kind=\"query\"} 1\n"), "{}", metrics);
+}
@@ -0,0 +1,173 @@
+// The responses of the API compared against those of tests/snapshots/, so that a
+// change to their shape shows up in the diff of the pull request making it. Each
+// snapshot has the request and the whole response to it: its status line, its
+// headers by name and its body, pretty-printed when it is JSON. The server runs
+// on the in-memory backend with the same users every time. What changes from one
+// run to the next, the ids of the requests and of their traces and the timings,
+// is replaced by a placeholder; nothing else answered there has a time in it.
+//
+// After a change meant to be, `UPDATE_SNAPSHOTS=1 cargo test --test snapshots`
+// writes them again, to be reviewed and committed along with it.
+
+mod common;
+
+use common::{ Response, Server };
+use std::env;
+use std::fs;
+use std::path::{ Path, PathBuf };
+
+const KEY: &str = "X-Api-Key: s3cret\r\n";
+
+// The headers whose values are different on every request
+const VOLATILE: [&str; 4] = ["Date", "Server-Timing", "traceparent", "X-Request-Id"];
+
+const USERS: [(&str, &str); 3] =
+    [("Ada Lovelace", "ada@example.com"), ("Grace Hopper", "grace@example.com"), ("Alan Turing", "alan@example.com")];
+
+fn user(name: &str, email: &str) -> String {
+    format!(r#"{{"name": "{}", "email": "{}"}}"#, name, email)
+}
+
+// The response as it is in a snapshot, after the request it answers
+fn written(request: &str, response: &Response) -> String {
+    let mut lines = response.head.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    let mut headers: Vec<(&str, &str)> =
+        lines.filter_map(|line| line.split_once(':')).map(|(name, value)| (name, value.trim())).collect();
+    headers.sort_by_key(|(name, _)| name.to_ascii_lowercase());
+
+    let mut snapshot = format!("{}\n\n{}\n", request, status_line);
+    for (name, value) in headers {
+        let value = match VOLATILE.iter().any(|volatile| volatile.eq_ignore_ascii_case(name)) {
+            true => "[volatile]",
+            false => value,
+        };
+        snapshot.push_str(&format!("{}: {}\n", name, value));
+    }
+    match serde_json::from_str::<serde_json::Value>(&response.body) {
+        Ok(body) => snapshot.push_str(&format!("\n{}\n", serde_json::to_string_pretty(&body).unwrap())),
+        Err(_) if response.body.is_empty() => {}
+        Err(_) => snapshot.push_str(&format!("\n{}\n", response.body)),
+    }
+    snapshot
+}
+
+struct Snapshots {
+    dir: PathBuf,
+    update: bool,
+    // What differs from the snapshots, or has none
+    changed: Vec<String>,
+}
+
+impl Snapshots {
+    fn new() -> Snapshots {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots");
+        let update = env::var("UPDATE_SNAPSHOTS").is_ok_and(|update| !update.is_empty() && update != "0");
+        Snapshots { dir, update, changed: Vec::new() }
+    }
+
+    // Send the request, and compare what the server answered with the snapshot of
+    // that name
+    fn check(&mut self, name: &str, server: &Server, method: &str, target: &str, headers: &str, body: Option<&str>) {
+        let response = server.call(method, target, headers, body);
+        // Whatever the snapshot has
+        let length = response.body.len().to_string();
+        assert_eq!(response.header("Content-Length"), Some(length.as_str()), "{} {}", method, target);
+        let mut request = format!("{} {}", method, target);
+        if let Some(body) = body {
+            request.push_str(&format!("\n{}", body));
+        }
+        let actual = written(&request, &response);
+
+        let path = self.dir.join(format!("{}.snap", name));
+        let expected = fs::read_to_string(&path).ok();
+        if expected.as_deref() == Some(actual.as_str()) {
+            return;
+        }
+        if self.update {
+            fs::create_dir_all(&self.dir).unwrap();
+            fs::write(&path, &actual).unwrap();
+            return;
+        }
+        self.changed.push(match expected {
+            Some(expected) => format!("{} changed:\n{}", path.display(), diff(&expected, &actual)),
+            None => format!("{} is missing, it would be:\n{}", path.display(), actual),
+        });
+    }
+
+    fn assert_unchanged(self) {
+        if !self.changed.is_empty() {
+            panic!(
+                "{}\nRun UPDATE_SNAPSHOTS=1 cargo test --test snapshots if the changes are meant to be",
+                self.changed.join("\n")
+            );
+        }
+    }
+}
+
+// The lines taken out of expected, with a -, and those put in, with a +, from the
+// first that differs to the last
+fn diff(expected: &str, actual: &str) -> String {
+    let (expected, actual): (Vec<&str>, Vec<&str>) = (expected.lines().collect(), actual.lines().collect());
+    let same_start = expected.iter().zip(&actual).take_while(|(a, b)| a == b).count();
+    let same_end =
+        expected[same_start..].iter().rev().zip(actual[same_start..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let removed = expected[same_start..expected.len() - same_end].iter().map(|line| format!("-{}", line));
+    let added = actual[same_start..actual.len() - same_end].iter().map(|line| format!("+{}", line));
+    removed.chain(added).collect::<Vec<String>>().join("\n")
+}
+
+#[test]
+fn the_responses_are_those_of_the_snapshots() {
+    let server = Server::start_with("memory://", &[("API_KEYS", "deploy:s3cret"), ("APP_ENV", "test")]);
+    let mut snapshots = Snapshots::new();
+
+    snapshots.check("list_empty", &server, "GET", "/users", KEY, None);
+    let (name, email) = USERS[0];
+    snapshots.check("create", &server, "POST", "/users", KEY, Some(&user(name, email)));
+    for (name, email) in &USERS[1..] {
+        assert_eq!(server.request_with_headers("POST", "/users", KEY, Some(&user(name, email))).0, 200);
+    }
+
+    // The collections
+    snapshots.check("list", &server, "GET", "/users", KEY, None);
+    snapshots.check("list_page", &server, "GET", "/users?limit=2", KEY, None);
+    snapshots.check("list_last_page", &server, "GET", "/users?limit=2&after_id=2", KEY, None);
+    snapshots.check("list_by_email", &server, "GET", "/users?email=grace@example.com", KEY, None);
+
+    // One user
+    snapshots.check("get", &server, "GET", "/users/2", KEY, None);
+    snapshots.check("update", &server, "PUT", "/users/3", KEY, Some(&user("Alan M. Turing", "alan@example.com")));
+    snapshots.check("delete", &server, "DELETE", "/users/3", KEY, None);
+
+    // The errors
+    snapshots.check("error_unauthorized", &server, "GET", "/users", "", None);
+    snapshots.check("error_user_not_found", &server, "GET", "/users/99", KEY, None);
+    snapshots.check("error_invalid_id", &server, "GET", "/users/abc", KEY, None);
+    snapshots.check("error_invalid_json", &server, "POST", "/users", KEY, Some("nope"));
+    snapshots.check("error_invalid_user", &server, "POST", "/users", KEY, Some(&user("", "ada")));
+    snapshots.check("error_email_taken", &server, "POST", "/users", KEY, Some(&user("Ada", "ADA@example.com")));
+    snapshots.check("error_invalid_limit", &server, "GET", "/users?limit=0", KEY, None);
+    snapshots.check("error_export_unsupported", &server, "GET", "/users/1/export", KEY, None);
+
+    // Those answered before any handler runs
+    snapshots.check("error_unmatched_route", &server, "GET", "/nowhere", KEY, None);
+    snapshots.check("error_method_not_allowed", &server, "PATCH", "/users/1", KEY, None);
+    snapshots.check("error_bad_request_line", &server, "GET", "/users and more", KEY, None);
+    let padded = format!("{}X-Padding: {}\r\n", KEY, "a".repeat(8 * 1024));
+    snapshots.check("error_header_too_large", &server, "GET", "/users", &padded, None);
+    snapshots.check("error_request_line_too_long", &server, "GET", &format!("/{}", "a".repeat(8 * 1024)), KEY, None);
+
+    // The probes
+    snapshots.check("livez", &server, "GET", "/livez", "", None);
+    snapshots.check("health", &server, "GET", "/health", "", None);
+
+    snapshots.assert_unchanged();
+}
+
+#[test]
+fn a_diff_has_the_lines_that_changed() {
+    assert_eq!(diff("a\nb\nc\nd\n", "a\nB\nb2\nd\n"), "-b\n-c\n+B\n+b2");
+    assert_eq!(diff("a\nb\n", "a\nb\nc\n"), "+c");
+    assert_eq!(diff("a\n", "a\n"), "");
+}
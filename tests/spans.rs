@@ -0,0 +1,36 @@
+// The spans under each request time what it did: the Server-Timing of the
+// response has them by name, and /metrics the statements of the repository.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+use std::io::Read;
+
+// The names in the Server-Timing of the response, read to the end
+fn timings(server: &Server, method: &str, target: &str, body: Option<&str>) -> Vec<String> {
+    let mut stream = server.send(method, target, "", body);
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let timing = response.lines().find_map(|line| line.strip_prefix("Server-Timing: ")).expect(&response);
+    timing.split(", ").map(|metric| metric.split_once(";dur=").unwrap().0.to_owned()).collect()
+}
+
+#[test]
+fn responses_have_the_timings_of_their_spans() {
+    let server = Server::start("memory://");
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}"}}"#, unique_email("ada"));
+    let (status, body) = server.request("POST", "/users", Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    let target = format!("/users/{}", json(&body)["id"]);
+
+    assert_eq!(timings(&server, "GET", &target, None), ["db-wait", "db", "serialize"]);
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}"}}"#, unique_email("ada"));
+    assert_eq!(timings(&server, "POST", "/users", Some(&user)), ["db-wait", "parse_body", "db", "serialize"]);
+
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    let count = metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(r#"db_operation_duration_seconds_count{operation="find"} "#))
+        .expect(&metrics);
+    assert!(count.parse::<u64>().unwrap() >= 1, "{}", metrics);
+}
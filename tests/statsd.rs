@@ -0,0 +1,129 @@
+// STATSD_ADDR: the metrics of /metrics are also sent over UDP, in the DogStatsD
+// format, here to a socket of the test: the timings as the requests are answered,
+// the counters and the gauges every interval.
+
+mod common;
+
+use common::Server;
+use std::net::UdpSocket;
+use std::time::{ Duration, Instant };
+
+// A line of a datagram: its name, value, type, sample rate and tags
+#[derive(Debug)]
+struct Line {
+    name: String,
+    value: f64,
+    kind: String,
+    rate: Option<String>,
+    tags: Vec<String>,
+}
+
+fn parse(line: &str) -> Line {
+    let (name, rest) = line.split_once(':').unwrap();
+    let mut parts = rest.split('|');
+    let value = parts.next().unwrap().parse().unwrap();
+    let kind = parts.next().unwrap().to_owned();
+    let (mut rate, mut tags) = (None, Vec::new());
+    for part in parts {
+        match part.split_at(1) {
+            ("@", sample_rate) => rate = Some(sample_rate.to_owned()),
+            ("#", list) => tags = list.split(',').map(str::to_owned).collect(),
+            _ => panic!("unknown part {:?} in {:?}", part, line),
+        }
+    }
+    Line { name: name.to_owned(), value, kind, rate, tags }
+}
+
+// The lines received until done says there are enough, at most for 10 seconds,
+// after checking the size of each datagram
+fn receive(socket: &UdpSocket, max_size: usize, mut done: impl FnMut(&[Line]) -> bool) -> Vec<Line> {
+    socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let (started, mut lines) = (Instant::now(), Vec::new());
+    let mut buffer = [0; 65536];
+    while !done(&lines) {
+        assert!(started.elapsed() < Duration::from_secs(10), "not there yet: {:?}", lines);
+        if let Ok(size) = socket.recv(&mut buffer) {
+            assert!(size <= max_size, "a datagram of {} bytes", size);
+            let text = std::str::from_utf8(&buffer[..size]).unwrap();
+            lines.extend(text.lines().map(parse));
+        }
+    }
+    lines
+}
+
+fn tagged(line: &Line, tags: &[&str]) -> bool {
+    tags.iter().all(|tag| line.tags.iter().any(|each| each == tag))
+}
+
+// How much the counter of those tags grew over the lines
+fn counted(lines: &[Line], name: &str, tags: &[&str]) -> f64 {
+    lines.iter().filter(|line| line.name == name && line.kind == "c" && tagged(line, tags)).map(|line| line.value).sum()
+}
+
+#[test]
+fn the_timings_counters_and_gauges_are_sent_in_datagrams() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    let vars = [
+        ("STATSD_ADDR", addr.as_str()),
+        ("STATSD_PREFIX", "users."),
+        ("STATSD_SERVICE", "users-api"),
+        ("STATSD_TAGS", "team:core, region:eu"),
+        ("STATSD_INTERVAL_MS", "200"),
+        ("STATSD_MAX_PACKET_SIZE", "512"),
+        ("APP_ENV", "staging"),
+        ("REQUIRE_AUTH", "false"),
+    ];
+    let server = Server::start_with("memory://", &vars);
+    for _ in 0..3 {
+        assert_eq!(server.request("GET", "/users", None).0, 200);
+    }
+    assert_eq!(server.request("GET", "/users/999", None).0, 404);
+
+    let constant = ["env:staging", "service:users-api", "team:core", "region:eu"];
+    let ok = ["method:GET", "route:/users", "status_class:2xx"];
+    let not_found = ["method:GET", "route:/users/{id}", "status_class:4xx"];
+    let lines = receive(&socket, 512, |lines| {
+        counted(lines, "users.http_requests_total", &ok) >= 3.0
+            && counted(lines, "users.http_requests_total", &not_found) >= 1.0
+    });
+    assert_eq!(counted(&lines, "users.http_requests_total", &ok), 3.0);
+    assert!(lines.iter().all(|line| tagged(line, &constant)), "{:?}", lines);
+
+    let timings: Vec<&Line> = lines.iter().filter(|line| line.name == "users.http_request_duration").collect();
+    assert!(timings.iter().all(|line| line.kind == "ms" && line.rate.is_none()), "{:?}", timings);
+    assert_eq!(timings.iter().filter(|line| tagged(line, &["method:GET", "route:/users"])).count(), 3);
+    let workers = lines.iter().find(|line| line.name == "users.workers").unwrap();
+    assert!(workers.kind == "g" && workers.value > 0.0, "{:?}", workers);
+    // Nothing grew since, so the counters aren't sent again
+    let later = receive(&socket, 512, |lines| lines.iter().filter(|line| line.name == "users.workers").count() >= 2);
+    assert_eq!(counted(&later, "users.http_requests_total", &ok), 0.0);
+}
+
+#[test]
+fn the_timings_are_sampled_and_the_failures_counted() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    let vars = [("STATSD_ADDR", addr.as_str()), ("STATSD_SAMPLE_RATE", "0.5"), ("STATSD_INTERVAL_MS", "60000")];
+    let server = Server::start_with("memory://", &vars);
+    for _ in 0..40 {
+        server.request("GET", "/users", None);
+    }
+    let lines = receive(&socket, 1432, |lines| !lines.is_empty());
+    assert!(lines.iter().all(|line| line.kind == "ms" && line.rate.as_deref() == Some("0.5")), "{:?}", lines);
+
+    // Nothing listens there any more: the sends are refused, and said so once
+    drop(socket);
+    let (server, logs) = Server::start_capturing("memory://", &vars);
+    for _ in 0..20 {
+        server.request("GET", "/users", None);
+    }
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    let failures = metrics.lines().find_map(|line| line.strip_prefix("statsd_send_failures_total ")).unwrap();
+    assert!(failures.parse::<u64>().unwrap() >= 2, "{}", metrics);
+    let mut warned = 0;
+    while let Ok(line) = logs.recv_timeout(Duration::from_millis(500)) {
+        warned += line.contains("Can't send metrics to StatsD") as usize;
+    }
+    assert_eq!(warned, 1);
+}
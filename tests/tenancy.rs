@@ -0,0 +1,78 @@
+// TENANCY=column: two tenants on one server, each blind to the other's rows. Runs
+// when TEST_DATABASE_URL points at a database the tests may write to, whose role
+// may create schemas.
+
+mod common;
+
+use common::{ json, Server };
+use postgres::{ Client, NoTls };
+
+const SCHEMA: &str = "tenancy-test";
+
+fn as_tenant(tenant: &str) -> String {
+    format!("X-Tenant-Id: {}\r\n", tenant)
+}
+
+#[test]
+fn tenants_only_see_their_own_users() {
+    let Some(database_url) = common::database_url("the tenancy test") else {
+        return;
+    };
+    let mut admin = Client::connect(&database_url, NoTls).unwrap();
+    admin.batch_execute(&format!("DROP SCHEMA IF EXISTS \"{}\" CASCADE", SCHEMA)).unwrap();
+    let server = Server::start_with(&database_url, &[("DATABASE_SCHEMA", SCHEMA), ("TENANCY", "column")]);
+    let (a, b) = (as_tenant("tenant-a"), as_tenant("tenant-b"));
+    let ada = r#"{"name": "Ada Lovelace", "email": "ada@example.com"}"#;
+
+    let (status, body) = server.request("GET", "/users", None);
+    assert_eq!((status, body.as_str()), (400, "X-Tenant-Id is required"));
+    let (status, body) = server.request_with_headers("GET", "/users", &as_tenant("Tenant A"), None);
+    assert_eq!(status, 400, "{}", body);
+    // The probes are for the whole server
+    assert_eq!(server.request("GET", "/health", None).0, 200);
+
+    let (status, body) = server.request_with_headers("POST", "/users", &a, Some(ada));
+    assert_eq!(status, 200, "{}", body);
+    let id = json(&body)["id"].as_i64().unwrap();
+    let path = format!("/users/{}", id);
+
+    // To tenant-b the user doesn't exist
+    assert_eq!(server.request_with_headers("GET", &path, &b, None).0, 404);
+    let renamed = r#"{"name": "Someone Else", "email": "else@example.com"}"#;
+    assert_eq!(server.request_with_headers("PUT", &path, &b, Some(renamed)).0, 404);
+    assert_eq!(server.request_with_headers("DELETE", &path, &b, None).0, 404);
+    assert_eq!(server.request_with_headers("POST", &format!("{}/anonymize", path), &b, None).0, 404);
+    assert_eq!(server.request_with_headers("GET", &format!("{}/export", path), &b, None).0, 404);
+    let (_, body) = server.request_with_headers("GET", "/users?email=ada@example.com", &b, None);
+    assert_eq!(json(&body), serde_json::json!([]));
+    let (_, body) = server.request_with_headers("GET", "/events", &b, None);
+    assert_eq!(json(&body), serde_json::json!([]));
+
+    // The same email and idempotency key are free in tenant-b
+    let key = "Idempotency-Key: shared-key\r\n";
+    let (status, body) = server.request_with_headers("POST", "/users", &format!("{}{}", b, key), Some(ada));
+    assert_eq!(status, 200, "{}", body);
+    let other_id = json(&body)["id"].as_i64().unwrap();
+    assert_ne!(other_id, id);
+    let (status, body) = server.request_with_headers("POST", "/users", &format!("{}{}", a, key), Some(renamed));
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["name"], "Someone Else");
+    // but still taken within tenant-a
+    let (status, body) = server.request_with_headers("POST", "/users", &a, Some(ada));
+    assert_eq!(status, 409, "{}", body);
+
+    // tenant-a's user was left alone by tenant-b
+    let (status, body) = server.request_with_headers("GET", &path, &a, None);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(json(&body)["name"], "Ada Lovelace");
+    let (_, body) = server.request_with_headers("GET", "/users", &a, None);
+    assert_eq!(json(&body).as_array().unwrap().len(), 2);
+    let (_, body) = server.request_with_headers("GET", "/events", &b, None);
+    let events = json(&body);
+    assert_eq!(events.as_array().unwrap().len(), 1);
+    assert_eq!(events[0]["payload"]["id"], other_id);
+
+    assert_eq!(server.request_with_headers("DELETE", &path, &a, None).0, 200);
+    let (status, body) = server.request_with_headers("GET", &format!("/users/{}", other_id), &b, None);
+    assert_eq!(status, 200, "{}", body);
+}
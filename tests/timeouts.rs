@@ -0,0 +1,84 @@
+// The statement timeout, the route timeouts and the cancellation of abandoned
+// requests, with the pg_sleep of GET /admin/sleep standing in for a runaway
+// query. Runs when TEST_DATABASE_URL is set.
+
+mod common;
+
+use common::{ json, Server };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+fn start(statement_timeout_ms: &str) -> Option<Server> {
+    start_with(statement_timeout_ms, &[])
+}
+
+fn start_with(statement_timeout_ms: &str, more: &[(&str, &str)]) -> Option<Server> {
+    let database_url = common::database_url("the timeout tests")?;
+    // One connection, so that every request reuses the one that timed out
+    let vars = [
+        ("APP_ENV", "test"),
+        ("DB_POOL_MIN_SIZE", "1"),
+        ("DB_POOL_MAX_SIZE", "1"),
+        ("DB_STATEMENT_TIMEOUT_MS", statement_timeout_ms),
+    ];
+    Some(Server::start_with(&database_url, &[&vars[..], more].concat()))
+}
+
+#[test]
+fn statement_timeout_answers_504() {
+    let Some(server) = start("200") else {
+        return;
+    };
+
+    let (status, body) = server.request("GET", "/admin/sleep?seconds=2", None);
+    assert_eq!(status, 504, "{}", body);
+    assert_eq!(json(&body)["code"], "statement_timeout");
+
+    // The connection is still good for the next requests
+    let (status, body) = server.request("GET", "/health", None);
+    assert_eq!(status, 200, "{}", body);
+    let (status, body) = server.request("GET", "/admin/sleep?seconds=0.05", None);
+    assert_eq!(status, 200, "{}", body);
+}
+
+#[test]
+fn abandoned_requests_are_cancelled() {
+    let Some(server) = start("30000") else {
+        return;
+    };
+
+    let stream = server.send("GET", "/admin/sleep?seconds=10", "", None);
+    thread::sleep(Duration::from_millis(300));
+    drop(stream);
+
+    // The only connection is free again once the sleep was cancelled. Until then
+    // the health check finds it busy.
+    let started = Instant::now();
+    loop {
+        let (status, body) = server.request("GET", "/health", None);
+        if status == 200 {
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(3), "the sleep went on for {:?}: {}", started.elapsed(), body);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn requests_over_their_route_timeout_answer_504() {
+    let Some(server) = start_with("30000", &[("ROUTE_TIMEOUT_MS", "300")]) else {
+        return;
+    };
+
+    let started = Instant::now();
+    let (status, body) = server.request("GET", "/admin/sleep?seconds=10", None);
+    assert_eq!(status, 504, "{}", body);
+    assert_eq!(json(&body)["detail"], "GET /admin/sleep took longer than 300ms");
+    assert!(started.elapsed() < Duration::from_secs(2), "answered after {:?}", started.elapsed());
+
+    // The query was cancelled, the only connection is good for the next requests
+    let (status, body) = server.request("GET", "/admin/sleep?seconds=0.05", None);
+    assert_eq!(status, 200, "{}", body);
+    let (_, metrics) = server.request("GET", "/metrics", None);
+    assert!(metrics.contains("\nroute_timeouts_total{route=\"GET /admin/sleep\"} 1\n"), "{}", metrics);
+}
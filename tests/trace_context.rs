@@ -0,0 +1,105 @@
+// W3C Trace Context: a request sent with a valid traceparent is answered from a
+// span of the same trace, whose traceparent is in the response and in the requests
+// sent meanwhile, here to the error webhook.
+
+mod common;
+
+use common::Server;
+use std::io::{ BufRead, BufReader, Read, Write };
+use std::net::TcpListener;
+use std::sync::mpsc::{ self, Receiver };
+use std::thread;
+use std::time::Duration;
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+// The value of the header of the head, lowercase as the spec writes it
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+}
+
+fn head(server: &Server, target: &str, headers: &str) -> String {
+    let mut stream = server.send("GET", target, headers, None);
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.split_once("\r\n\r\n").unwrap().0.to_owned()
+}
+
+// The version, trace id, parent id and flags of a traceparent
+fn parts(traceparent: &str) -> Vec<&str> {
+    let parts: Vec<&str> = traceparent.split('-').collect();
+    assert_eq!(parts.iter().map(|part| part.len()).collect::<Vec<_>>(), [2, 32, 16, 2], "{}", traceparent);
+    parts
+}
+
+#[test]
+fn the_trace_of_a_valid_traceparent_is_kept() {
+    let server = Server::start("memory://");
+    let headers = format!("traceparent: {}\r\ntracestate: congo=t61rcWkgMzE\r\n", TRACEPARENT);
+    let head = head(&server, "/users/42", &headers);
+    let traceparent = header(&head, "traceparent").unwrap_or_else(|| panic!("no traceparent: {}", head));
+    let [version, trace_id, span_id, _] = parts(traceparent)[..] else { unreachable!() };
+    assert_eq!((version, trace_id), ("00", TRACE_ID));
+    assert_ne!(span_id, "00f067aa0ba902b7");
+    assert_eq!(header(&head, "tracestate"), Some("congo=t61rcWkgMzE"));
+}
+
+#[test]
+fn an_invalid_traceparent_starts_a_new_trace() {
+    let server = Server::start("memory://");
+    for headers in ["", "traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01\r\ntracestate: a=b\r\n"] {
+        let head = head(&server, "/users/42", headers);
+        let traceparent = header(&head, "traceparent").unwrap_or_else(|| panic!("no traceparent: {}", head));
+        let [version, trace_id, _, flags] = parts(traceparent)[..] else { unreachable!() };
+        assert_eq!((version, flags), ("00", "00"));
+        assert_ne!(trace_id, TRACE_ID);
+        assert_eq!(header(&head, "tracestate"), None, "{}", head);
+    }
+    let (first, second) = (head(&server, "/users/42", ""), head(&server, "/users/42", ""));
+    assert_ne!(parts(header(&first, "traceparent").unwrap())[1], parts(header(&second, "traceparent").unwrap())[1]);
+}
+
+// The heads of the requests to the webhook, answered 200
+fn webhook() -> (String, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/errors", listener.local_addr().unwrap());
+    let (sender, heads) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let (mut head, mut length) = (String::new(), 0);
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((_, value)) = line.to_ascii_lowercase().split_once("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                head.push_str(&line);
+            }
+            reader.read_exact(&mut vec![0; length]).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            sender.send(head).ok();
+        }
+    });
+    (url, heads)
+}
+
+#[test]
+fn outbound_requests_are_in_the_trace() {
+    let (url, heads) = webhook();
+    let server = Server::start_with("memory://", &[("ERROR_WEBHOOK_URL", url.as_str()), ("APP_ENV", "test")]);
+    let head = head(&server, "/admin/fail", &format!("traceparent: {}\r\n", TRACEPARENT));
+    assert!(head.starts_with("HTTP/1.1 500"), "{}", head);
+    let answered = parts(header(&head, "traceparent").unwrap());
+
+    let sent = heads.recv_timeout(Duration::from_secs(10)).expect("nothing was reported");
+    let traceparent = header(&sent, "traceparent").unwrap_or_else(|| panic!("no traceparent: {}", sent));
+    let [_, trace_id, parent_id, flags] = parts(traceparent)[..] else { unreachable!() };
+    // Under the span of the request, itself under the one of the caller
+    assert_eq!((trace_id, flags), (TRACE_ID, "01"));
+    assert_eq!(parent_id, answered[2]);
+}
@@ -0,0 +1,111 @@
+// EMAIL_VERIFICATION=true: each new user is sent a link with a token, printed by
+// default, and POST /users/verify marks them verified with it, once, until it
+// expires or another one is sent. Needs TEST_DATABASE_URL.
+
+mod common;
+
+use common::{ json, unique_email, Server };
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+const ROOT: &str = "X-Api-Key: r00t\r\n";
+const URL: &str = "https://app.example.com/verify?token=";
+
+fn start(database_url: &str, lifetime: &str) -> (Server, Receiver<String>) {
+    let vars = [
+        ("EMAIL_VERIFICATION", "true"),
+        ("VERIFICATION_URL", "https://app.example.com/verify?token={token}"),
+        ("VERIFICATION_TOKEN_LIFETIME_SECS", lifetime),
+        ("API_KEYS", "root:r00t"),
+    ];
+    Server::start_capturing(database_url, &vars)
+}
+
+// The token in the next link sent to the email
+fn token_sent(lines: &Receiver<String>, email: &str) -> String {
+    let prefix = format!("Mail to {} (Verify your email): Open {}", email, URL);
+    loop {
+        let line = lines.recv_timeout(Duration::from_secs(10)).expect("the link was printed");
+        if let Some((_, rest)) = line.split_once(&prefix) {
+            return rest.split_whitespace().next().unwrap().to_owned();
+        }
+    }
+}
+
+// The id and the email of a new user
+fn create_user(server: &Server) -> (i64, String) {
+    let email = unique_email("ada");
+    let user = format!(r#"{{"name": "Ada Lovelace", "email": "{}"}}"#, email);
+    let (status, body) = server.request_with_headers("POST", "/users", ROOT, Some(&user));
+    assert_eq!(status, 200, "{}", body);
+    (json(&body)["id"].as_i64().unwrap(), email)
+}
+
+// Without credentials, the token is enough
+fn verify(server: &Server, token: &str) -> (u16, serde_json::Value) {
+    let (status, body) = server.request("POST", "/users/verify", Some(&format!(r#"{{"token": "{}"}}"#, token)));
+    (status, json(&body))
+}
+
+fn resend(server: &Server, id: i64) -> (u16, String) {
+    server.request_with_headers("POST", &format!("/users/{}/resend-verification", id), ROOT, None)
+}
+
+#[test]
+fn users_verify_their_email_once() {
+    let Some(database_url) = common::database_url("the verification test") else {
+        return;
+    };
+    let (server, lines) = start(&database_url, "3600");
+    let (id, email) = create_user(&server);
+    let token = token_sent(&lines, &email);
+    assert_eq!(token.len(), 64);
+
+    let (status, body) = server.request_with_headers("GET", &format!("/users/{}", id), ROOT, None);
+    assert_eq!(status, 200, "{}", body);
+    assert!(json(&body).get("verified_at").is_none(), "{}", body);
+
+    let (status, body) = verify(&server, &token);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["id"], id);
+    let (_, user) = server.request_with_headers("GET", &format!("/users/{}", id), ROOT, None);
+    assert_eq!(json(&user)["verified_at"], body["verified_at"]);
+
+    let (status, body) = verify(&server, &token);
+    assert_eq!((status, &body["code"]), (400, &"token_used".into()), "{}", body);
+    let (status, body) = verify(&server, "not-a-token");
+    assert_eq!((status, &body["code"]), (400, &"invalid_token".into()), "{}", body);
+
+    let (status, body) = resend(&server, id);
+    assert_eq!(status, 409, "{}", body);
+    assert_eq!(json(&body)["code"], "already_verified");
+    assert_eq!(resend(&server, 2147483647).0, 404);
+}
+
+#[test]
+fn tokens_expire_and_are_replaced_by_the_next_one() {
+    let Some(database_url) = common::database_url("the verification test") else {
+        return;
+    };
+    let (server, lines) = start(&database_url, "1");
+    let (id, email) = create_user(&server);
+    let expired = token_sent(&lines, &email);
+    thread::sleep(Duration::from_millis(1500));
+    let (status, body) = verify(&server, &expired);
+    assert_eq!((status, &body["code"]), (410, &"token_expired".into()), "{}", body);
+
+    let (status, body) = resend(&server, id);
+    assert_eq!(status, 200, "{}", body);
+    let replaced = token_sent(&lines, &email);
+    let (status, body) = resend(&server, id);
+    assert_eq!(status, 200, "{}", body);
+    let token = token_sent(&lines, &email);
+
+    for old in [expired, replaced] {
+        let (status, body) = verify(&server, &old);
+        assert_eq!((status, &body["code"]), (400, &"invalid_token".into()), "{}", body);
+    }
+    let (status, body) = verify(&server, &token);
+    assert_eq!(status, 200, "{}", body);
+}